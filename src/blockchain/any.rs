@@ -104,6 +104,14 @@ impl Blockchain for AnyBlockchain {
     fn estimate_fee(&self, target: usize) -> Result<FeeRate, Error> {
         maybe_await!(impl_inner_method!(self, estimate_fee, target))
     }
+
+    fn broadcast_batch(&self, txs: &[Transaction]) -> Result<Vec<Txid>, Error> {
+        maybe_await!(impl_inner_method!(self, broadcast_batch, txs))
+    }
+
+    fn get_tx_confirmations(&self, txid: &Txid) -> Result<Option<u32>, Error> {
+        maybe_await!(impl_inner_method!(self, get_tx_confirmations, txid))
+    }
 }
 
 #[maybe_async]
@@ -113,6 +121,17 @@ impl GetHeight for AnyBlockchain {
     }
 }
 
+#[maybe_async]
+impl GetBlockHash for AnyBlockchain {
+    fn get_block_hash(&self, height: u32) -> Result<Option<BlockHash>, Error> {
+        maybe_await!(impl_inner_method!(self, get_block_hash, height))
+    }
+
+    fn get_block_header(&self, height: u32) -> Result<Option<BlockHeader>, Error> {
+        maybe_await!(impl_inner_method!(self, get_block_header, height))
+    }
+}
+
 #[maybe_async]
 impl GetTx for AnyBlockchain {
     fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, Error> {
@@ -147,6 +166,62 @@ impl WalletSync for AnyBlockchain {
             progress_update
         ))
     }
+
+    fn wallet_setup_with_stats<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<SyncStats, Error> {
+        maybe_await!(impl_inner_method!(
+            self,
+            wallet_setup_with_stats,
+            database,
+            progress_update
+        ))
+    }
+
+    fn wallet_sync_with_stats<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<SyncStats, Error> {
+        maybe_await!(impl_inner_method!(
+            self,
+            wallet_sync_with_stats,
+            database,
+            progress_update
+        ))
+    }
+
+    fn wallet_setup_with_budget<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+        max_requests: usize,
+    ) -> Result<SyncStats, Error> {
+        maybe_await!(impl_inner_method!(
+            self,
+            wallet_setup_with_budget,
+            database,
+            progress_update,
+            max_requests
+        ))
+    }
+
+    fn wallet_sync_with_budget<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+        max_requests: usize,
+    ) -> Result<SyncStats, Error> {
+        maybe_await!(impl_inner_method!(
+            self,
+            wallet_sync_with_budget,
+            database,
+            progress_update,
+            max_requests
+        ))
+    }
 }
 
 impl_from!(boxed electrum::ElectrumBlockchain, AnyBlockchain, Electrum, #[cfg(feature = "electrum")]);
@@ -183,6 +258,15 @@ impl_from!(boxed rpc::RpcBlockchain, AnyBlockchain, Rpc, #[cfg(feature = "rpc")]
 ///         socks5: None,
 ///         timeout: None,
 ///         stop_gap: 20,
+///         internal_stop_gap: None,
+///         sync_error_policy: Default::default(),
+///         tx_cache_max_entries: None,
+///         cert_expiry_warning_days: None,
+///         prefetch: false,
+///         validate_proofs: false,
+///         batch_size: None,
+///         concurrency: None,
+///         min_fee_rate: None,
 ///     })
 /// );
 /// # }
@@ -0,0 +1,1168 @@
+// Bitcoin Dev Kit
+//
+// Copyright (c) 2020-2022 Bitcoin Dev Kit Developers
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Async Electrum
+//!
+//! This module defines [`AsyncElectrumBlockchain`], the async counterpart of
+//! [`ElectrumBlockchain`](crate::blockchain::electrum::ElectrumBlockchain). It talks to the server
+//! with its own client built on `tokio`, so that syncing a wallet never blocks the executor, and
+//! populates the wallet's [database](crate::database::Database) with the same request loop as
+//! the blocking version.
+//!
+//! It requires the `async-electrum` feature, which enables `async-interface`.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! # use bdk::blockchain::{AsyncElectrumBlockchain, AsyncElectrumBlockchainConfig};
+//! # use bdk::blockchain::{ConfigurableBlockchain, GetHeight};
+//! # async fn example() -> Result<(), bdk::Error> {
+//! let config = AsyncElectrumBlockchainConfig {
+//!     url: "ssl://electrum.blockstream.info:60002".to_string(),
+//!     retry: 3,
+//!     timeout: Some(5),
+//!     stop_gap: 20,
+//!     internal_stop_gap: None,
+//!     sync_error_policy: Default::default(),
+//!     batch_size: None,
+//!     min_fee_rate: None,
+//! };
+//! let blockchain = AsyncElectrumBlockchain::from_config(&config)?;
+//! let height = blockchain.get_height().await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The server is reached over TCP (`tcp://`) or TLS (`ssl://`), the connection is opened by the
+//! first request. Unlike the blocking client, socks5 proxies and Unix domain sockets aren't
+//! supported.
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::{BlockHash, BlockHeader, Script, Transaction, TxOut, Txid};
+
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+use super::script_sync::Request;
+use super::*;
+use crate::database::{BatchDatabase, Database};
+use crate::error::Error;
+use crate::{BlockTime, FeeRate};
+
+/// Number of requests sent to the server in a single batch by default during sync
+const DEFAULT_BATCH_SIZE: usize = 100;
+
+/// Largest confirmation target accepted by `estimatesmartfee` in Bitcoin Core
+const MAX_FEE_ESTIMATE_TARGET: usize = 1008;
+
+/// Errors that can happen during a request to an Electrum server with an
+/// [`AsyncElectrumBlockchain`]
+#[derive(Debug)]
+pub enum AsyncElectrumError {
+    /// Error reading from or writing to the connection
+    Io(io::Error),
+    /// Invalid JSON received from the server
+    Json(serde_json::Error),
+    /// Invalid Bitcoin data returned
+    BitcoinEncoding(bitcoin::consensus::encode::Error),
+    /// Invalid hex data returned
+    Hex(bitcoin::hashes::hex::Error),
+    /// The server replied with an error
+    Protocol(Value),
+    /// The response doesn't have the expected shape (method called)
+    InvalidResponse(String),
+    /// The server didn't reply within the timeout
+    Timeout,
+    /// The url of the server is invalid, it should start with `tcp://` or `ssl://`
+    InvalidUrl(String),
+}
+
+impl fmt::Display for AsyncElectrumError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for AsyncElectrumError {}
+
+macro_rules! impl_error {
+    ( $from:ty, $to:ident ) => {
+        impl std::convert::From<$from> for AsyncElectrumError {
+            fn from(err: $from) -> Self {
+                AsyncElectrumError::$to(err)
+            }
+        }
+    };
+}
+
+impl_error!(io::Error, Io);
+impl_error!(serde_json::Error, Json);
+impl_error!(bitcoin::consensus::encode::Error, BitcoinEncoding);
+impl_error!(bitcoin::hashes::hex::Error, Hex);
+
+impl AsyncElectrumError {
+    /// Whether the connection to the server is broken, in which case the request can be retried
+    /// on a new connection
+    fn is_connection_error(&self) -> bool {
+        matches!(
+            self,
+            AsyncElectrumError::Io(_) | AsyncElectrumError::Timeout
+        )
+    }
+}
+
+/// Stream to the server, over TCP or TLS
+trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+/// Open connection to an Electrum server
+struct Connection {
+    stream: BufReader<Box<dyn AsyncStream>>,
+    /// Id of the next request
+    next_id: u64,
+}
+
+impl Connection {
+    /// Send a batch of `calls` and wait for their results, returned in the same order
+    ///
+    /// Notifications and responses to previous requests are skipped. If any of the calls fails
+    /// the batch fails, the responses to the remaining calls are ignored by the next batch.
+    async fn batch_call(
+        &mut self,
+        calls: &[(&str, Vec<Value>)],
+    ) -> Result<Vec<Value>, AsyncElectrumError> {
+        let first_id = self.next_id;
+        let mut payload = Vec::new();
+        for (id, (method, params)) in (first_id..).zip(calls) {
+            let request = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+            serde_json::to_writer(&mut payload, &request)?;
+            payload.push(b'\n');
+        }
+        self.next_id += calls.len() as u64;
+        self.stream.write_all(&payload).await?;
+        self.stream.flush().await?;
+
+        let mut results = vec![None; calls.len()];
+        let mut missing = calls.len();
+        let mut line = String::new();
+        while missing > 0 {
+            line.clear();
+            if self.stream.read_line(&mut line).await? == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            let mut response: Value = serde_json::from_str(&line)?;
+
+            let index = match response.get("id").and_then(Value::as_u64) {
+                Some(id) if id >= first_id && id - first_id < calls.len() as u64 => {
+                    (id - first_id) as usize
+                }
+                _ => {
+                    trace!("Skipping unexpected message: {}", line.trim_end());
+                    continue;
+                }
+            };
+            match response.get_mut("error").map(Value::take) {
+                Some(Value::Null) | None => {}
+                Some(error) => return Err(AsyncElectrumError::Protocol(error)),
+            }
+            if results[index].is_none() {
+                missing -= 1;
+            }
+            results[index] = Some(response.get_mut("result").map_or(Value::Null, Value::take));
+        }
+
+        Ok(results.into_iter().map(Option::unwrap).collect())
+    }
+}
+
+/// Client used by an [`AsyncElectrumBlockchain`], which connects to the server on the first
+/// request and reconnects after a connection error
+struct AsyncElectrumClient {
+    connection: Mutex<Option<Connection>>,
+    url: String,
+    /// Number of times a request failing because of the connection is retried
+    retry: u8,
+    timeout: Option<u8>,
+    rate_limiter: Option<RateLimiter>,
+}
+
+impl AsyncElectrumClient {
+    fn new(url: &str, retry: u8, timeout: Option<u8>) -> Result<Self, AsyncElectrumError> {
+        if !url.starts_with("tcp://") && !url.starts_with("ssl://") {
+            return Err(AsyncElectrumError::InvalidUrl(url.to_string()));
+        }
+
+        Ok(AsyncElectrumClient {
+            connection: Mutex::new(None),
+            url: url.to_string(),
+            retry,
+            timeout,
+            rate_limiter: None,
+        })
+    }
+
+    async fn with_timeout<T>(
+        &self,
+        future: impl Future<Output = Result<T, AsyncElectrumError>>,
+    ) -> Result<T, AsyncElectrumError> {
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(Duration::from_secs(timeout.into()), future)
+                .await
+                .map_err(|_| AsyncElectrumError::Timeout)?,
+            None => future.await,
+        }
+    }
+
+    async fn connect(&self) -> Result<Connection, AsyncElectrumError> {
+        let stream: Box<dyn AsyncStream> = match self.url.strip_prefix("ssl://") {
+            Some(address) => {
+                let server_name = rustls::ServerName::try_from(tls_host(address))
+                    .map_err(|_| AsyncElectrumError::InvalidUrl(self.url.clone()))?;
+                let tcp = TcpStream::connect(address).await?;
+                Box::new(tls_connector().connect(server_name, tcp).await?)
+            }
+            None => Box::new(TcpStream::connect(&self.url["tcp://".len()..]).await?),
+        };
+        debug!("Connected to {}", self.url);
+
+        Ok(Connection {
+            stream: BufReader::new(stream),
+            next_id: 0,
+        })
+    }
+
+    /// Send a batch of `calls` on `connection`, connecting first if needed
+    async fn connect_and_call(
+        &self,
+        connection: &mut Option<Connection>,
+        calls: &[(&str, Vec<Value>)],
+    ) -> Result<Vec<Value>, AsyncElectrumError> {
+        if connection.is_none() {
+            *connection = Some(self.connect().await?);
+        }
+        connection.as_mut().unwrap().batch_call(calls).await
+    }
+
+    /// Send a batch of `calls` and return their results, in the same order
+    async fn batch_call(&self, calls: &[(&str, Vec<Value>)]) -> Result<Vec<Value>, Error> {
+        if calls.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut connection = self.connection.lock().await;
+        let mut attempt = 0;
+        loop {
+            if let Some(limiter) = &self.rate_limiter {
+                limiter.wait_async().await;
+            }
+            let result = self
+                .with_timeout(self.connect_and_call(&mut connection, calls))
+                .await;
+
+            match result {
+                Ok(results) => return Ok(results),
+                Err(e) if e.is_connection_error() => {
+                    // the state of the connection is unknown, drop it
+                    *connection = None;
+                    if attempt >= self.retry {
+                        return Err(e.into());
+                    }
+                    attempt += 1;
+                    debug!(
+                        "Connection error: {}, retrying ({}/{})",
+                        e, attempt, self.retry
+                    );
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    async fn call(&self, method: &str, params: Vec<Value>) -> Result<Value, Error> {
+        let mut results = self.batch_call(&[(method, params)]).await?;
+        Ok(results.remove(0))
+    }
+
+    async fn block_headers_subscribe(&self) -> Result<(u32, BlockHeader), Error> {
+        let result = self.call("blockchain.headers.subscribe", vec![]).await?;
+        let height = result["height"]
+            .as_u64()
+            .ok_or_else(|| invalid_response("height"))?;
+        let header = result["hex"]
+            .as_str()
+            .ok_or_else(|| invalid_response("hex"))?;
+        Ok((height as u32, deserialize_hex(header)?))
+    }
+
+    async fn batch_block_header(&self, heights: &[u32]) -> Result<Vec<BlockHeader>, Error> {
+        let calls = heights
+            .iter()
+            .map(|height| ("blockchain.block.header", vec![json!(height)]))
+            .collect::<Vec<_>>();
+        self.batch_call(&calls)
+            .await?
+            .iter()
+            .map(|header| {
+                deserialize_hex(
+                    header
+                        .as_str()
+                        .ok_or_else(|| invalid_response("blockchain.block.header"))?,
+                )
+            })
+            .collect()
+    }
+
+    async fn batch_transaction_get(&self, txids: &[Txid]) -> Result<Vec<Transaction>, Error> {
+        let calls = txids
+            .iter()
+            .map(|txid| ("blockchain.transaction.get", vec![json!(txid.to_hex())]))
+            .collect::<Vec<_>>();
+        self.batch_call(&calls)
+            .await?
+            .iter()
+            .map(|tx| {
+                deserialize_hex(
+                    tx.as_str()
+                        .ok_or_else(|| invalid_response("blockchain.transaction.get"))?,
+                )
+            })
+            .collect()
+    }
+
+    async fn script_get_history(&self, script: &Script) -> Result<Vec<(Txid, i32)>, Error> {
+        let history = self
+            .call(
+                "blockchain.scripthash.get_history",
+                vec![json!(script_hash(script))],
+            )
+            .await?;
+        parse_history(history)
+    }
+
+    async fn batch_script_get_history(
+        &self,
+        scripts: &[&Script],
+    ) -> Result<Vec<Vec<(Txid, i32)>>, Error> {
+        let calls = scripts
+            .iter()
+            .map(|script| {
+                (
+                    "blockchain.scripthash.get_history",
+                    vec![json!(script_hash(script))],
+                )
+            })
+            .collect::<Vec<_>>();
+        self.batch_call(&calls)
+            .await?
+            .into_iter()
+            .map(parse_history)
+            .collect()
+    }
+}
+
+/// TLS connector trusting the Mozilla root certificates
+fn tls_connector() -> tokio_rustls::TlsConnector {
+    let mut root_store = rustls::RootCertStore::empty();
+    root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|anchor| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+
+    tokio_rustls::TlsConnector::from(Arc::new(config))
+}
+
+/// Electrum script hash of `script`: its sha256 hash, in reverse byte order
+fn script_hash(script: &Script) -> String {
+    let mut hash = sha256::Hash::hash(script.as_bytes()).into_inner();
+    hash.reverse();
+    hash.to_hex()
+}
+
+fn invalid_response(method: &str) -> Error {
+    AsyncElectrumError::InvalidResponse(method.to_string()).into()
+}
+
+fn deserialize_hex<T: bitcoin::consensus::Decodable>(hex: &str) -> Result<T, Error> {
+    let bytes = Vec::<u8>::from_hex(hex).map_err(AsyncElectrumError::from)?;
+    Ok(deserialize(&bytes).map_err(AsyncElectrumError::from)?)
+}
+
+/// Parse the history of a script hash into its txids and heights, which are zero or negative for
+/// unconfirmed transactions
+fn parse_history(history: Value) -> Result<Vec<(Txid, i32)>, Error> {
+    history
+        .as_array()
+        .ok_or_else(|| invalid_response("blockchain.scripthash.get_history"))?
+        .iter()
+        .map(|entry| {
+            let txid = entry["tx_hash"]
+                .as_str()
+                .ok_or_else(|| invalid_response("blockchain.scripthash.get_history"))?;
+            let height = entry["height"]
+                .as_i64()
+                .ok_or_else(|| invalid_response("blockchain.scripthash.get_history"))?;
+            Ok((
+                Txid::from_hex(txid).map_err(AsyncElectrumError::from)?,
+                height as i32,
+            ))
+        })
+        .collect()
+}
+
+/// Async Electrum backend that implements the required blockchain traits
+///
+/// Requests failing because of the connection are retried up to
+/// [`retry`](AsyncElectrumBlockchainConfig::retry) times on a new connection.
+///
+/// ## Example
+/// See the [`blockchain::async_electrum`](crate::blockchain::async_electrum) module for a usage
+/// example.
+pub struct AsyncElectrumBlockchain {
+    client: AsyncElectrumClient,
+    stop_gap: usize,
+    internal_stop_gap: Option<usize>,
+    sync_error_policy: SyncErrorPolicy,
+    ownership_resolver: Option<OwnershipResolver>,
+    batch_size: usize,
+    min_fee_rate: Option<FeeRate>,
+    shared_tx_cache: Option<SharedTxCache>,
+}
+
+impl AsyncElectrumBlockchain {
+    /// Create a blockchain connecting to the server at `url` on its first request, starting with
+    /// `tcp://` or `ssl://`
+    ///
+    /// The other settings have the same defaults as an
+    /// [`ElectrumBlockchain`](crate::blockchain::electrum::ElectrumBlockchain): no timeout, no
+    /// retry and a stop gap of 20.
+    pub fn new(url: &str) -> Result<Self, Error> {
+        Ok(AsyncElectrumBlockchain {
+            client: AsyncElectrumClient::new(url, 0, None)?,
+            stop_gap: 20,
+            internal_stop_gap: None,
+            sync_error_policy: SyncErrorPolicy::default(),
+            ownership_resolver: None,
+            batch_size: DEFAULT_BATCH_SIZE,
+            min_fee_rate: None,
+            shared_tx_cache: None,
+        })
+    }
+
+    /// Set the stop gap of the internal (change) keychain, which defaults to the stop gap of the
+    /// external one
+    pub fn with_internal_stop_gap(mut self, internal_stop_gap: usize) -> Self {
+        self.internal_stop_gap = Some(internal_stop_gap);
+        self
+    }
+
+    /// Set the policy used to handle errors while fetching the history of a script pubkey
+    pub fn with_sync_error_policy(mut self, sync_error_policy: SyncErrorPolicy) -> Self {
+        self.sync_error_policy = sync_error_policy;
+        self
+    }
+
+    /// Set a custom resolver consulted during sync to decide which outputs belong to the wallet
+    pub fn with_ownership_resolver(mut self, ownership_resolver: OwnershipResolver) -> Self {
+        self.ownership_resolver = Some(ownership_resolver);
+        self
+    }
+
+    /// Set the maximum number of requests sent to the server in a single batch during sync
+    /// (default: 100)
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Set the fee rate returned by [`Blockchain::estimate_fee`] when the server can't estimate
+    /// one (default: none)
+    pub fn with_min_fee_rate(mut self, min_fee_rate: FeeRate) -> Self {
+        self.min_fee_rate = Some(min_fee_rate);
+        self
+    }
+
+    /// Fetch the transactions of `txids` missing from `txs`, looking them up in the database and
+    /// the shared cache first
+    async fn fetch_txs<D: Database>(
+        &self,
+        database: &D,
+        txids: &[Txid],
+        txs: &mut HashMap<Txid, Transaction>,
+        stats: &mut SyncStats,
+    ) -> Result<(), Error> {
+        let mut need_fetch = vec![];
+        for txid in txids.iter().collect::<HashSet<_>>() {
+            if txs.contains_key(txid) {
+                continue;
+            } else if let Some(tx) = database.get_raw_tx(txid)? {
+                txs.insert(*txid, tx);
+            } else if let Some(tx) = self.shared_tx_cache.as_ref().and_then(|c| c.get(txid)) {
+                txs.insert(*txid, tx);
+            } else {
+                need_fetch.push(*txid);
+            }
+        }
+
+        let fetched = self.client.batch_transaction_get(&need_fetch).await?;
+        stats.txs_fetched += fetched.len();
+        if let Some(cache) = &self.shared_tx_cache {
+            fetched.iter().cloned().for_each(|tx| cache.insert(tx));
+        }
+        txs.extend(fetched.into_iter().map(|tx| (tx.txid(), tx)));
+
+        Ok(())
+    }
+}
+
+#[maybe_async]
+impl Blockchain for AsyncElectrumBlockchain {
+    fn get_capabilities(&self) -> HashSet<Capability> {
+        vec![
+            Capability::FullHistory,
+            Capability::GetAnyTx,
+            Capability::AccurateFees,
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<(), Error> {
+        maybe_await!(self.client.call(
+            "blockchain.transaction.broadcast",
+            vec![json!(serialize(tx).to_hex())]
+        ))?;
+        Ok(())
+    }
+
+    fn estimate_fee(&self, target: usize) -> Result<FeeRate, Error> {
+        // the server replies -1 when it can't estimate the fee rate for a target, but it may
+        // still have an estimate for a larger one
+        let mut target = target;
+        loop {
+            let estimate = maybe_await!(self
+                .client
+                .call("blockchain.estimatefee", vec![json!(target)]))?
+            .as_f64()
+            .ok_or_else(|| invalid_response("blockchain.estimatefee"))?;
+            if estimate > 0.0 {
+                return Ok(FeeRate::from_btc_per_kvb(estimate as f32));
+            }
+
+            debug!("No fee estimate for a target of {} blocks", target);
+            if target >= MAX_FEE_ESTIMATE_TARGET {
+                break;
+            }
+            target = (target.max(1) * 2).min(MAX_FEE_ESTIMATE_TARGET);
+        }
+
+        self.min_fee_rate.ok_or(Error::FeeRateUnavailable)
+    }
+
+    fn get_tx_confirmations(&self, txid: &Txid) -> Result<Option<u32>, Error> {
+        let tx = match maybe_await!(self.get_tx(txid))? {
+            Some(tx) => tx,
+            None => return Ok(None),
+        };
+
+        // the server only indexes transactions by script, the height of the transaction is the
+        // one in the history of its outputs
+        for txout in &tx.output {
+            let history = maybe_await!(self.client.script_get_history(&txout.script_pubkey))?;
+            if let Some((_, height)) = history.iter().find(|(entry, _)| entry == txid) {
+                if *height <= 0 {
+                    return Ok(Some(0));
+                }
+                let tip = maybe_await!(self.get_height())?;
+                return Ok(Some((tip + 1).saturating_sub(*height as u32).max(1)));
+            }
+        }
+
+        Err(Error::Generic(format!(
+            "Transaction {} is missing from the history of its outputs",
+            txid
+        )))
+    }
+}
+
+impl StatelessBlockchain for AsyncElectrumBlockchain {}
+
+#[maybe_async]
+impl GetHeight for AsyncElectrumBlockchain {
+    fn get_height(&self) -> Result<u32, Error> {
+        Ok(maybe_await!(self.client.block_headers_subscribe())?.0)
+    }
+}
+
+#[maybe_async]
+impl GetBlockHash for AsyncElectrumBlockchain {
+    fn get_block_hash(&self, height: u32) -> Result<Option<BlockHash>, Error> {
+        Ok(maybe_await!(self.get_block_header(height))?.map(|header| header.block_hash()))
+    }
+
+    fn get_block_header(&self, height: u32) -> Result<Option<BlockHeader>, Error> {
+        match maybe_await!(self.client.batch_block_header(&[height])) {
+            Ok(mut headers) => Ok(Some(headers.remove(0))),
+            // heights above the tip are rejected by the server
+            Err(e) if is_height_not_found(&e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[maybe_async]
+impl GetTx for AsyncElectrumBlockchain {
+    fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, Error> {
+        match maybe_await!(self.client.batch_transaction_get(&[*txid])) {
+            Ok(mut txs) => Ok(Some(txs.remove(0))),
+            // the server replies with an error if it doesn't know the transaction
+            Err(e) if is_tx_not_found(&e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[maybe_async]
+impl WalletSync for AsyncElectrumBlockchain {
+    fn wallet_setup<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<(), Error> {
+        maybe_await!(self.wallet_setup_with_stats(database, progress_update))?;
+        Ok(())
+    }
+
+    fn wallet_sync_with_stats<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<SyncStats, Error> {
+        maybe_await!(self.wallet_setup_with_stats(database, progress_update))
+    }
+
+    fn wallet_setup_with_stats<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<SyncStats, Error> {
+        let start = Instant::new();
+        let mut stats = SyncStats {
+            reorged_txids: maybe_await!(reorg::detect_reorgs(self, database))?,
+            ..Default::default()
+        };
+        let mut request = script_sync::start_with_ownership_resolver(
+            database,
+            script_sync::StopGap::new(self.stop_gap, self.internal_stop_gap),
+            self.ownership_resolver.clone(),
+        )?;
+        let mut block_headers = HashMap::<u32, BlockHeader>::new();
+        let mut txid_to_height = HashMap::<Txid, u32>::new();
+        let mut txs = HashMap::<Txid, Transaction>::new();
+        let chunk_size = self.batch_size.max(1);
+        let electrum_goof = || Error::Generic("electrum server misbehaving".to_string());
+
+        let batch_update = loop {
+            if progress_update.is_cancelled() {
+                return Err(Error::SyncAborted);
+            }
+            request = match request {
+                Request::Script(script_req) => {
+                    let scripts = script_req.request().take(chunk_size).collect::<Vec<_>>();
+                    stats.scripts_scanned += scripts.len();
+
+                    let histories: Vec<Result<Vec<_>, Error>> =
+                        match maybe_await!(self.client.batch_script_get_history(&scripts)) {
+                            Ok(histories) => histories.into_iter().map(Ok).collect(),
+                            Err(e) if self.sync_error_policy == SyncErrorPolicy::SkipErrored => {
+                                // the batch failed as a whole, retry one script at a time to find
+                                // out which ones are actually erroring
+                                debug!("batch history request failed: {}, retrying per script", e);
+                                let mut histories = Vec::with_capacity(scripts.len());
+                                for script in &scripts {
+                                    histories
+                                        .push(maybe_await!(self.client.script_get_history(script)));
+                                }
+                                histories
+                            }
+                            Err(e) => return Err(e),
+                        };
+                    let txids_per_script: Vec<Result<Vec<_>, Error>> = histories
+                        .into_iter()
+                        .map(|history| {
+                            Ok(history?
+                                .into_iter()
+                                .map(|(txid, height)| {
+                                    let tx_height = match height {
+                                        none if none <= 0 => None,
+                                        height => {
+                                            txid_to_height.insert(txid, height as u32);
+                                            Some(height as u32)
+                                        }
+                                    };
+                                    (txid, tx_height)
+                                })
+                                .collect())
+                        })
+                        .collect();
+
+                    script_req.satisfy_with_policy(txids_per_script, self.sync_error_policy)?
+                }
+
+                Request::Conftime(conftime_req) => {
+                    let needs_block_height = conftime_req
+                        .request()
+                        .filter_map(|txid| txid_to_height.get(txid).cloned())
+                        .filter(|height| !block_headers.contains_key(height))
+                        .collect::<HashSet<_>>()
+                        .into_iter()
+                        .take(chunk_size)
+                        .collect::<Vec<_>>();
+
+                    let new_block_headers =
+                        maybe_await!(self.client.batch_block_header(&needs_block_height))?;
+                    stats.headers_fetched += needs_block_height.len();
+                    block_headers.extend(needs_block_height.into_iter().zip(new_block_headers));
+
+                    let conftimes = conftime_req
+                        .request()
+                        .take(chunk_size)
+                        .map(|txid| {
+                            txid_to_height
+                                .get(txid)
+                                .map(|height| {
+                                    let header =
+                                        block_headers.get(height).ok_or_else(electrum_goof)?;
+                                    Result::<_, Error>::Ok(BlockTime {
+                                        height: *height,
+                                        timestamp: header.time.into(),
+                                    })
+                                })
+                                .transpose()
+                        })
+                        .collect::<Result<Vec<_>, Error>>()?;
+
+                    conftime_req.satisfy(conftimes)?
+                }
+
+                Request::Tx(tx_req) => {
+                    let needs_full = tx_req
+                        .request()
+                        .take(chunk_size)
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    maybe_await!(self.fetch_txs(&*database, &needs_full, &mut txs, &mut stats))?;
+                    let input_txids = needs_full
+                        .iter()
+                        .filter_map(|txid| txs.get(txid))
+                        .flat_map(|tx| &tx.input)
+                        .filter(|input| !input.previous_output.is_null())
+                        .map(|input| input.previous_output.txid)
+                        .collect::<Vec<_>>();
+                    maybe_await!(self.fetch_txs(&*database, &input_txids, &mut txs, &mut stats))?;
+
+                    let full_details = needs_full
+                        .iter()
+                        .map(|txid| {
+                            let tx = txs.get(txid).ok_or_else(electrum_goof)?;
+                            let prev_outputs = tx
+                                .input
+                                .iter()
+                                .map(|input| {
+                                    if input.previous_output.is_null() {
+                                        return Ok(None);
+                                    }
+                                    let prev_tx = txs
+                                        .get(&input.previous_output.txid)
+                                        .ok_or_else(electrum_goof)?;
+                                    let txout: &TxOut = prev_tx
+                                        .output
+                                        .get(input.previous_output.vout as usize)
+                                        .ok_or_else(electrum_goof)?;
+                                    Ok(Some(txout.clone()))
+                                })
+                                .collect::<Result<Vec<_>, Error>>()?;
+                            Ok((prev_outputs, tx.clone()))
+                        })
+                        .collect::<Result<Vec<_>, Error>>()?;
+
+                    tx_req.satisfy(full_details)?
+                }
+                Request::Finish(batch_update) => break batch_update,
+            };
+
+            if let Some(sync_progress) = request.sync_progress() {
+                progress_update.update_sync(&sync_progress)?;
+            }
+            if let Some(skipped_scripts) = request.skipped_scripts() {
+                stats.skipped_scripts = skipped_scripts;
+            }
+        };
+
+        database.commit_batch(batch_update)?;
+        maybe_await!(reorg::store_block_hashes(self, database))?;
+
+        stats.duration = start.elapsed();
+        Ok(stats)
+    }
+}
+
+/// Configuration for an [`AsyncElectrumBlockchain`]
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq)]
+pub struct AsyncElectrumBlockchainConfig {
+    /// URL of the Electrum server (such as ElectrumX, Esplora, BWT), starting with `ssl://` or
+    /// `tcp://` and including a port
+    ///
+    /// eg. `ssl://electrum.blockstream.info:60002`
+    pub url: String,
+    /// Request retry count
+    pub retry: u8,
+    /// Request timeout (seconds)
+    pub timeout: Option<u8>,
+    /// Stop searching addresses for transactions after finding an unused gap of this length
+    pub stop_gap: usize,
+    /// Stop gap of the internal (change) keychain, `stop_gap` is used if `None`
+    #[serde(default)]
+    pub internal_stop_gap: Option<usize>,
+    /// How to handle errors while fetching the history of a single script pubkey
+    #[serde(default)]
+    pub sync_error_policy: SyncErrorPolicy,
+    /// Maximum number of requests sent to the server in a single batch during sync, see
+    /// [`AsyncElectrumBlockchain::with_batch_size`] (default: 100)
+    #[serde(default)]
+    pub batch_size: Option<usize>,
+    /// Fee rate (sat/vB) returned by `estimate_fee` when the server has no estimate for any
+    /// target, see [`AsyncElectrumBlockchain::with_min_fee_rate`]
+    #[serde(default)]
+    pub min_fee_rate: Option<f32>,
+}
+
+impl LimitRequests for AsyncElectrumBlockchain {
+    fn with_rate_limiter(mut self, limiter: RateLimiter) -> Self {
+        self.client.rate_limiter = Some(limiter);
+        self
+    }
+}
+
+impl CacheTxs for AsyncElectrumBlockchain {
+    fn with_tx_cache(mut self, cache: SharedTxCache) -> Self {
+        self.shared_tx_cache = Some(cache);
+        self
+    }
+}
+
+impl ConfigurableBlockchain for AsyncElectrumBlockchain {
+    type Config = AsyncElectrumBlockchainConfig;
+
+    fn from_config(config: &Self::Config) -> Result<Self, Error> {
+        Ok(AsyncElectrumBlockchain {
+            client: AsyncElectrumClient::new(&config.url, config.retry, config.timeout)?,
+            stop_gap: config.stop_gap,
+            internal_stop_gap: config.internal_stop_gap,
+            sync_error_policy: config.sync_error_policy,
+            ownership_resolver: None,
+            batch_size: config.batch_size.unwrap_or(DEFAULT_BATCH_SIZE),
+            min_fee_rate: config.min_fee_rate.map(FeeRate::from_sat_per_vb),
+            shared_tx_cache: None,
+        })
+    }
+}
+
+/// Host of the `host:port` `address`, without the brackets around an IPv6 address, to check
+/// the certificate of the server against
+fn tls_host(address: &str) -> &str {
+    let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+    host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+/// Message of a protocol error returned by the server, lowercased
+///
+/// Servers report the errors of the daemon behind them as protocol errors too, so the message
+/// must be inspected to tell them apart.
+fn protocol_error_message(error: &Error) -> Option<String> {
+    let message = match error {
+        Error::AsyncElectrum(AsyncElectrumError::Protocol(Value::String(message))) => message,
+        Error::AsyncElectrum(AsyncElectrumError::Protocol(Value::Object(error))) => {
+            match error.get("message") {
+                Some(Value::String(message)) => message,
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+
+    Some(message.to_lowercase())
+}
+
+/// Whether `error` is the reply of the server to a request for a transaction it doesn't know
+fn is_tx_not_found(error: &Error) -> bool {
+    protocol_error_message(error).map_or(false, |message| {
+        message.contains("no such mempool or blockchain transaction")
+            || message.contains("transaction not found")
+    })
+}
+
+/// Whether `error` is the reply of the server to a request for the header of a block above the
+/// tip
+///
+/// ElectrumX replies that the height is out of range, Fulcrum that it's invalid and electrs that
+/// the header is missing or can't be found.
+fn is_height_not_found(error: &Error) -> bool {
+    protocol_error_message(error).map_or(false, |message| {
+        message.contains("out of range")
+            || message.contains("invalid height")
+            || message.contains("missing header")
+            || message.contains("not found")
+    })
+}
+
+#[cfg(test)]
+#[cfg(feature = "test-async-electrum")]
+mod async_electrum_test {
+    use bitcoin::Network;
+
+    use super::*;
+    use crate::database::MemoryDatabase;
+    use crate::testutils::blockchain_tests::TestClient;
+    use crate::wallet::test::get_test_wpkh;
+    use crate::wallet::{AddressIndex, Wallet};
+    use crate::SyncOptions;
+
+    fn get_blockchain(test_client: &TestClient) -> AsyncElectrumBlockchain {
+        AsyncElectrumBlockchain::new(&format!("tcp://{}", test_client.electrsd.electrum_url))
+            .unwrap()
+    }
+
+    fn get_wallet() -> Wallet<MemoryDatabase> {
+        Wallet::new(
+            get_test_wpkh(),
+            None,
+            Network::Regtest,
+            MemoryDatabase::new(),
+        )
+        .unwrap()
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn test_async_electrum_sync_simple() {
+        let mut test_client = TestClient::default();
+        let blockchain = get_blockchain(&test_client);
+        let wallet = get_wallet();
+        let address = wallet.get_address(AddressIndex::New).unwrap();
+
+        let txid = test_client.receive(testutils! {
+            @tx ( (@addr address.address) => 50_000 )
+        });
+        block_on(wallet.sync(&blockchain, SyncOptions::default())).unwrap();
+
+        assert_eq!(wallet.get_balance().unwrap(), 50_000);
+        let details = wallet.get_tx(&txid, false).unwrap().unwrap();
+        assert_eq!(details.received, 50_000);
+        assert_eq!(details.confirmation_time, None);
+
+        test_client.generate(1, None);
+        block_on(wallet.sync(&blockchain, SyncOptions::default())).unwrap();
+
+        let details = wallet.get_tx(&txid, false).unwrap().unwrap();
+        let height = block_on(blockchain.get_height()).unwrap();
+        assert_eq!(
+            details.confirmation_time.map(|time| time.height),
+            Some(height)
+        );
+    }
+
+    #[test]
+    fn test_async_electrum_sync_multiple_outputs() {
+        use crate::database::BatchOperations;
+        use crate::KeychainKind;
+
+        let mut test_client = TestClient::default();
+        let blockchain = get_blockchain(&test_client);
+        let wallet = get_wallet();
+        let address_1 = wallet.get_address(AddressIndex::New).unwrap();
+        let address_2 = wallet.get_address(AddressIndex::New).unwrap();
+
+        test_client.receive(testutils! {
+            @tx ( (@addr address_1.address) => 50_000, (@addr address_2.address) => 30_000 ) ( @confirmations 1 )
+        });
+
+        let mut database = MemoryDatabase::new();
+        for address in [&address_1, &address_2] {
+            database
+                .set_script_pubkey(
+                    &address.script_pubkey(),
+                    KeychainKind::External,
+                    address.index,
+                )
+                .unwrap();
+        }
+        let stats =
+            block_on(blockchain.wallet_setup_with_stats(&mut database, Box::new(NoopProgress)))
+                .unwrap();
+
+        let utxos = database.iter_utxos().unwrap();
+        assert_eq!(
+            utxos.iter().map(|utxo| utxo.txout.value).sum::<u64>(),
+            80_000
+        );
+        assert_eq!(utxos.len(), 2);
+        assert_eq!(database.iter_txs(false).unwrap().len(), 1);
+        assert_eq!(stats.scripts_scanned, 2);
+        assert_eq!(stats.headers_fetched, 1);
+    }
+
+    #[test]
+    fn test_async_electrum_sync_after_send() {
+        let mut test_client = TestClient::default();
+        let blockchain = get_blockchain(&test_client);
+        let wallet = get_wallet();
+        let address = wallet.get_address(AddressIndex::New).unwrap();
+        let node_addr = test_client.get_node_address(None);
+
+        test_client.receive(testutils! {
+            @tx ( (@addr address.address) => 50_000 )
+        });
+        block_on(wallet.sync(&blockchain, SyncOptions::default())).unwrap();
+
+        let mut builder = wallet.build_tx();
+        builder.add_recipient(node_addr.script_pubkey(), 25_000);
+        let (mut psbt, details) = builder.finish().unwrap();
+        assert!(wallet.sign(&mut psbt, Default::default()).unwrap());
+        block_on(blockchain.broadcast(&psbt.extract_tx())).unwrap();
+        block_on(wallet.sync(&blockchain, SyncOptions::default())).unwrap();
+
+        assert_eq!(wallet.get_balance().unwrap(), details.received);
+        assert_eq!(wallet.list_transactions(false).unwrap().len(), 2);
+        assert_eq!(wallet.list_unspent().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_async_electrum_get_block_header() {
+        use bitcoin::blockdata::constants::genesis_block;
+
+        let test_client = TestClient::default();
+        let blockchain = get_blockchain(&test_client);
+        let genesis = genesis_block(Network::Regtest);
+
+        assert_eq!(
+            block_on(blockchain.get_block_header(0)).unwrap(),
+            Some(genesis.header)
+        );
+        let tip = block_on(blockchain.get_height()).unwrap();
+        assert!(block_on(blockchain.get_block_hash(tip)).unwrap().is_some());
+        assert_eq!(block_on(blockchain.get_block_hash(tip + 1)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_async_electrum_get_tx() {
+        let mut test_client = TestClient::default();
+        let blockchain = get_blockchain(&test_client);
+        let wallet = get_wallet();
+        let address = wallet.get_address(AddressIndex::New).unwrap();
+
+        let txid = test_client.receive(testutils! {
+            @tx ( (@addr address.address) => 50_000 )
+        });
+        let tx = block_on(blockchain.get_tx(&txid)).unwrap().unwrap();
+        assert_eq!(tx.txid(), txid);
+        assert_eq!(
+            block_on(blockchain.get_tx_confirmations(&txid)).unwrap(),
+            Some(0)
+        );
+
+        let unknown = Txid::from_hex(&"00".repeat(32)).unwrap();
+        assert_eq!(block_on(blockchain.get_tx(&unknown)).unwrap(), None);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_async_electrum_invalid_url() {
+        assert!(matches!(
+            AsyncElectrumBlockchain::new("electrum.blockstream.info:50001"),
+            Err(Error::AsyncElectrum(AsyncElectrumError::InvalidUrl(_)))
+        ));
+    }
+
+    #[test]
+    fn test_tls_host() {
+        assert_eq!(
+            tls_host("electrum.blockstream.info:50002"),
+            "electrum.blockstream.info"
+        );
+        assert_eq!(tls_host("127.0.0.1:50002"), "127.0.0.1");
+        assert_eq!(tls_host("[::1]:50002"), "::1");
+        assert!(rustls::ServerName::try_from(tls_host("[::1]:50002")).is_ok());
+    }
+
+    #[test]
+    fn test_not_found_errors() {
+        let protocol = |error: Value| Error::AsyncElectrum(AsyncElectrumError::Protocol(error));
+
+        let tx_not_found = protocol(json!({
+            "code": 2,
+            "message": "daemon error: DaemonError({'code': -5, 'message': 'No such mempool or blockchain transaction. Use gettransaction for wallet transactions.'})"
+        }));
+        assert!(is_tx_not_found(&tx_not_found));
+        assert!(is_tx_not_found(&protocol(json!("transaction not found"))));
+        assert!(!is_tx_not_found(&protocol(json!({
+            "code": 1,
+            "message": "excessive resource usage"
+        }))));
+        assert!(!is_tx_not_found(&Error::AsyncElectrum(
+            AsyncElectrumError::Timeout
+        )));
+
+        assert!(is_height_not_found(&protocol(json!({
+            "code": 1,
+            "message": "height 1,000,000 out of range"
+        }))));
+        assert!(is_height_not_found(&protocol(json!("missing header"))));
+        assert!(!is_height_not_found(&protocol(json!({
+            "code": 1,
+            "message": "excessive resource usage"
+        }))));
+    }
+}
@@ -0,0 +1,552 @@
+// Bitcoin Dev Kit
+//
+// Copyright (c) 2020-2022 Bitcoin Dev Kit Developers
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Blockchain wrapper caching the transactions it fetches
+//!
+//! This module provides [`CachingBlockchain`], which wraps another [`Blockchain`] and keeps the
+//! transactions returned by [`GetTx::get_tx`] in a bounded cache, evicting the least recently used
+//! ones. Fetching the same transaction again, even across syncs, doesn't hit the backend.
+//! Everything else is forwarded to the inner backend.
+//!
+//! The cache is a [`SharedTxCache`] given to the inner backend through [`CacheTxs`]: the
+//! [Electrum](crate::blockchain::electrum) and [Esplora](crate::blockchain::esplora) backends look
+//! up the transactions and previous outputs they need while syncing in it before fetching them,
+//! and store the ones they fetch in it.
+//!
+//! The cache is cleared when a reorg is detected, either because [`GetHeight::get_height`] returns
+//! a lower height than before or because [`GetBlockHash::get_block_hash`] returns a different hash
+//! for a height it was already called for, since the transactions confirmed in the stale blocks
+//! may not be known to the backend anymore.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! # use bdk::blockchain::*;
+//! # #[cfg(all(feature = "esplora", not(feature = "async-interface")))]
+//! # {
+//! let esplora = EsploraBlockchain::new("https://blockstream.info/testnet/api", 20);
+//! // keep up to 1000 transactions around
+//! let blockchain = CachingBlockchain::new(esplora, 1_000);
+//! let height = blockchain.get_height()?;
+//! # }
+//! # Ok::<(), bdk::Error>(())
+//! ```
+
+use std::collections::HashMap;
+
+use super::*;
+
+/// Number of block hashes remembered to detect reorgs
+const MAX_BLOCK_HASHES: usize = 100;
+
+/// Blockchain that caches the transactions fetched from an inner backend
+///
+/// See [this module](crate::blockchain::caching)'s documentation for a usage example.
+#[derive(Debug)]
+pub struct CachingBlockchain<B> {
+    inner: B,
+    cache: SharedTxCache,
+}
+
+/// Bounded cache of transactions shared between a [`CachingBlockchain`] and its inner backend
+#[derive(Debug, Clone)]
+pub struct SharedTxCache(Arc<Mutex<CacheState>>);
+
+#[derive(Debug)]
+struct CacheState {
+    txs: LruTxs,
+    tip_height: Option<u32>,
+    block_hashes: BTreeMap<u32, BlockHash>,
+}
+
+impl CacheState {
+    fn clear(&mut self) {
+        self.txs = LruTxs::new(self.txs.max_entries);
+        self.block_hashes.clear();
+    }
+}
+
+impl SharedTxCache {
+    fn new(capacity: usize) -> Self {
+        SharedTxCache(Arc::new(Mutex::new(CacheState {
+            txs: LruTxs::new(Some(capacity)),
+            tip_height: None,
+            block_hashes: BTreeMap::new(),
+        })))
+    }
+
+    /// Return the number of transactions in the cache
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().txs.len()
+    }
+
+    /// Return whether the cache is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub(crate) fn get(&self, txid: &Txid) -> Option<Transaction> {
+        self.0.lock().unwrap().txs.get(txid)
+    }
+
+    pub(crate) fn insert(&self, tx: Transaction) {
+        self.0
+            .lock()
+            .unwrap()
+            .txs
+            .insert(tx.txid(), tx, &HashSet::new());
+    }
+}
+
+/// Backend that looks up the transactions it needs while syncing in a [`SharedTxCache`] before
+/// fetching them from its server, and stores the ones it fetches in it
+pub trait CacheTxs {
+    /// Look up and store the transactions needed while syncing in `cache`
+    fn with_tx_cache(self, cache: SharedTxCache) -> Self;
+}
+
+/// Configuration for a [`CachingBlockchain`]
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq)]
+pub struct CachingBlockchainConfig<C> {
+    /// Configuration of the inner backend
+    pub inner: C,
+    /// Maximum number of transactions kept in the cache
+    pub capacity: usize,
+}
+
+impl<B: CacheTxs> CachingBlockchain<B> {
+    /// Create a new instance caching up to `capacity` transactions fetched from `inner`
+    pub fn new(inner: B, capacity: usize) -> Self {
+        let cache = SharedTxCache::new(capacity);
+        CachingBlockchain {
+            inner: inner.with_tx_cache(cache.clone()),
+            cache,
+        }
+    }
+}
+
+impl<B> CachingBlockchain<B> {
+    /// Return a reference to the inner backend
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    /// Return the number of transactions in the cache
+    pub fn cached_txs(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// Return the cache shared with the inner backend
+    pub fn tx_cache(&self) -> &SharedTxCache {
+        &self.cache
+    }
+}
+
+#[maybe_async]
+impl<B: Blockchain> Blockchain for CachingBlockchain<B> {
+    fn get_capabilities(&self) -> HashSet<Capability> {
+        maybe_await!(self.inner.get_capabilities())
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<(), Error> {
+        maybe_await!(self.inner.broadcast(tx))
+    }
+
+    fn estimate_fee(&self, target: usize) -> Result<FeeRate, Error> {
+        maybe_await!(self.inner.estimate_fee(target))
+    }
+
+    fn broadcast_batch(&self, txs: &[Transaction]) -> Result<Vec<Txid>, Error> {
+        maybe_await!(self.inner.broadcast_batch(txs))
+    }
+
+    fn get_tx_confirmations(&self, txid: &Txid) -> Result<Option<u32>, Error> {
+        maybe_await!(self.inner.get_tx_confirmations(txid))
+    }
+}
+
+#[maybe_async]
+impl<B: Blockchain> GetHeight for CachingBlockchain<B> {
+    fn get_height(&self) -> Result<u32, Error> {
+        let height = maybe_await!(self.inner.get_height())?;
+
+        let mut state = self.cache.0.lock().unwrap();
+        if matches!(state.tip_height, Some(tip_height) if height < tip_height) {
+            log::debug!("Tip went back to {}, clearing the cache", height);
+            state.clear();
+        }
+        state.tip_height = Some(height);
+
+        Ok(height)
+    }
+}
+
+#[maybe_async]
+impl<B: Blockchain> GetTx for CachingBlockchain<B> {
+    fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, Error> {
+        if let Some(tx) = self.cache.get(txid) {
+            return Ok(Some(tx));
+        }
+
+        let tx = maybe_await!(self.inner.get_tx(txid))?;
+        if let Some(tx) = &tx {
+            self.cache.insert(tx.clone());
+        }
+
+        Ok(tx)
+    }
+}
+
+#[maybe_async]
+impl<B: Blockchain + GetBlockHash> GetBlockHash for CachingBlockchain<B> {
+    fn get_block_hash(&self, height: u32) -> Result<Option<BlockHash>, Error> {
+        let hash = maybe_await!(self.inner.get_block_hash(height))?;
+
+        if let Some(hash) = hash {
+            let mut state = self.cache.0.lock().unwrap();
+            if matches!(state.block_hashes.get(&height), Some(known) if *known != hash) {
+                log::debug!("Block {} changed to {}, clearing the cache", height, hash);
+                state.clear();
+            }
+            state.block_hashes.insert(height, hash);
+            if state.block_hashes.len() > MAX_BLOCK_HASHES {
+                let oldest = *state.block_hashes.keys().next().expect("not empty");
+                state.block_hashes.remove(&oldest);
+            }
+        }
+
+        Ok(hash)
+    }
+
+    fn get_block_header(&self, height: u32) -> Result<Option<BlockHeader>, Error> {
+        maybe_await!(self.inner.get_block_header(height))
+    }
+}
+
+#[maybe_async]
+impl<B: Blockchain + GetBlock> GetBlock for CachingBlockchain<B> {
+    fn get_block(&self, hash: &BlockHash) -> Result<Option<Block>, Error> {
+        maybe_await!(self.inner.get_block(hash))
+    }
+}
+
+#[maybe_async]
+impl<B: Blockchain> WalletSync for CachingBlockchain<B> {
+    fn wallet_setup<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<(), Error> {
+        maybe_await!(self.inner.wallet_setup(database, progress_update))
+    }
+
+    fn wallet_sync<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<(), Error> {
+        maybe_await!(self.inner.wallet_sync(database, progress_update))
+    }
+
+    fn wallet_setup_with_stats<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<SyncStats, Error> {
+        maybe_await!(self
+            .inner
+            .wallet_setup_with_stats(database, progress_update))
+    }
+
+    fn wallet_sync_with_stats<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<SyncStats, Error> {
+        maybe_await!(self.inner.wallet_sync_with_stats(database, progress_update))
+    }
+
+    fn wallet_setup_with_budget<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+        max_requests: usize,
+    ) -> Result<SyncStats, Error> {
+        maybe_await!(self
+            .inner
+            .wallet_setup_with_budget(database, progress_update, max_requests))
+    }
+
+    fn wallet_sync_with_budget<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+        max_requests: usize,
+    ) -> Result<SyncStats, Error> {
+        maybe_await!(self
+            .inner
+            .wallet_sync_with_budget(database, progress_update, max_requests))
+    }
+}
+
+impl<B: ConfigurableBlockchain + CacheTxs> ConfigurableBlockchain for CachingBlockchain<B> {
+    type Config = CachingBlockchainConfig<B::Config>;
+
+    fn from_config(config: &Self::Config) -> Result<Self, Error> {
+        Ok(CachingBlockchain::new(
+            B::from_config(&config.inner)?,
+            config.capacity,
+        ))
+    }
+}
+
+/// Map of transactions that evicts the least recently used ones once it holds more than
+/// `max_entries`
+#[derive(Debug, Default)]
+pub(super) struct LruTxs {
+    max_entries: Option<usize>,
+    txs: HashMap<Txid, (Transaction, u64)>,
+    lru: BTreeMap<u64, Txid>,
+    tick: u64,
+}
+
+impl LruTxs {
+    pub(super) fn new(max_entries: Option<usize>) -> Self {
+        LruTxs {
+            max_entries,
+            ..Default::default()
+        }
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.txs.len()
+    }
+
+    #[cfg(feature = "electrum")]
+    pub(super) fn max_entries(&self) -> Option<usize> {
+        self.max_entries
+    }
+
+    /// Mark `txid` as just used, returning whether it's in the cache
+    pub(super) fn touch(&mut self, txid: &Txid) -> bool {
+        match self.txs.get_mut(txid) {
+            Some((_, last_used)) => {
+                self.lru.remove(last_used);
+                self.tick += 1;
+                *last_used = self.tick;
+                self.lru.insert(self.tick, *txid);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(super) fn get(&mut self, txid: &Txid) -> Option<Transaction> {
+        if self.touch(txid) {
+            self.txs.get(txid).map(|(tx, _)| tx.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Insert a transaction, then evict the least recently used ones that are not `in_flight`
+    /// until the cache is back within its bound
+    pub(super) fn insert(&mut self, txid: Txid, tx: Transaction, in_flight: &HashSet<Txid>) {
+        self.tick += 1;
+        if let Some((_, last_used)) = self.txs.insert(txid, (tx, self.tick)) {
+            self.lru.remove(&last_used);
+        }
+        self.lru.insert(self.tick, txid);
+
+        let to_evict = match self.max_entries {
+            Some(max_entries) => self.txs.len().saturating_sub(max_entries),
+            None => return,
+        };
+        let evicted = self
+            .lru
+            .iter()
+            .filter(|(_, txid)| !in_flight.contains(*txid))
+            .take(to_evict)
+            .map(|(last_used, txid)| (*last_used, *txid))
+            .collect::<Vec<_>>();
+        for (last_used, txid) in evicted {
+            self.lru.remove(&last_used);
+            self.txs.remove(&txid);
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "async-interface")))]
+mod test {
+    use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::database::MemoryDatabase;
+
+    #[derive(Default)]
+    struct MockBlockchain {
+        height: AtomicU32,
+        get_tx_calls: AtomicUsize,
+        cache: Option<SharedTxCache>,
+    }
+
+    impl CacheTxs for MockBlockchain {
+        fn with_tx_cache(mut self, cache: SharedTxCache) -> Self {
+            self.cache = Some(cache);
+            self
+        }
+    }
+
+    impl Blockchain for MockBlockchain {
+        fn get_capabilities(&self) -> HashSet<Capability> {
+            HashSet::new()
+        }
+
+        fn broadcast(&self, _tx: &Transaction) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn estimate_fee(&self, _target: usize) -> Result<FeeRate, Error> {
+            Ok(FeeRate::default_min_relay_fee())
+        }
+    }
+
+    impl GetHeight for MockBlockchain {
+        fn get_height(&self) -> Result<u32, Error> {
+            Ok(self.height.load(Ordering::SeqCst))
+        }
+    }
+
+    impl WalletSync for MockBlockchain {
+        fn wallet_setup<D: BatchDatabase>(
+            &self,
+            database: &mut D,
+            _progress_update: Box<dyn Progress>,
+        ) -> Result<(), Error> {
+            // like the real backends, look up the transactions of the wallet missing from the
+            // database in the cache before fetching them
+            for n in 0..3 {
+                let txid = tx(n).txid();
+                if database.get_raw_tx(&txid)?.is_some() {
+                    continue;
+                }
+                let cached = self.cache.as_ref().and_then(|cache| cache.get(&txid));
+                let tx = match cached {
+                    Some(tx) => tx,
+                    None => {
+                        let tx = self.get_tx(&txid)?.expect("known transaction");
+                        if let Some(cache) = &self.cache {
+                            cache.insert(tx.clone());
+                        }
+                        tx
+                    }
+                };
+                database.set_raw_tx(&tx)?;
+            }
+            Ok(())
+        }
+    }
+
+    impl GetTx for MockBlockchain {
+        fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, Error> {
+            self.get_tx_calls.fetch_add(1, Ordering::SeqCst);
+            Ok((0..10).map(tx).find(|tx| tx.txid() == *txid))
+        }
+    }
+
+    fn tx(n: u32) -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: n,
+            input: vec![],
+            output: vec![],
+        }
+    }
+
+    fn get_tx_calls(blockchain: &CachingBlockchain<MockBlockchain>) -> usize {
+        blockchain.inner().get_tx_calls.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn test_caching_get_tx_hits_cache() {
+        let blockchain = CachingBlockchain::new(MockBlockchain::default(), 10);
+
+        assert_eq!(blockchain.get_tx(&tx(0).txid()).unwrap(), Some(tx(0)));
+        assert_eq!(blockchain.get_tx(&tx(0).txid()).unwrap(), Some(tx(0)));
+        assert_eq!(get_tx_calls(&blockchain), 1);
+
+        // unknown transactions are not cached
+        let unknown = tx(42).txid();
+        assert_eq!(blockchain.get_tx(&unknown).unwrap(), None);
+        assert_eq!(blockchain.get_tx(&unknown).unwrap(), None);
+        assert_eq!(get_tx_calls(&blockchain), 3);
+        assert_eq!(blockchain.cached_txs(), 1);
+    }
+
+    #[test]
+    fn test_caching_sync_uses_cache() {
+        let blockchain = CachingBlockchain::new(MockBlockchain::default(), 10);
+
+        // a transaction fetched before the sync isn't fetched again by it
+        blockchain.get_tx(&tx(0).txid()).unwrap();
+        blockchain
+            .wallet_setup(&mut MemoryDatabase::new(), Box::new(NoopProgress))
+            .unwrap();
+        assert_eq!(get_tx_calls(&blockchain), 3);
+        assert_eq!(blockchain.cached_txs(), 3);
+
+        // neither are the ones fetched by a previous sync, even of another wallet
+        let mut database = MemoryDatabase::new();
+        blockchain
+            .wallet_setup(&mut database, Box::new(NoopProgress))
+            .unwrap();
+        assert_eq!(get_tx_calls(&blockchain), 3);
+        assert_eq!(database.iter_raw_txs().unwrap().len(), 3);
+
+        blockchain.get_tx(&tx(2).txid()).unwrap();
+        assert_eq!(get_tx_calls(&blockchain), 3);
+    }
+
+    #[test]
+    fn test_caching_bounded() {
+        let blockchain = CachingBlockchain::new(MockBlockchain::default(), 2);
+
+        for n in 0..5 {
+            blockchain.get_tx(&tx(n).txid()).unwrap();
+            assert!(blockchain.cached_txs() <= 2);
+        }
+        assert_eq!(get_tx_calls(&blockchain), 5);
+
+        // the most recent ones are still cached, the oldest ones were evicted
+        blockchain.get_tx(&tx(4).txid()).unwrap();
+        assert_eq!(get_tx_calls(&blockchain), 5);
+        blockchain.get_tx(&tx(0).txid()).unwrap();
+        assert_eq!(get_tx_calls(&blockchain), 6);
+    }
+
+    #[test]
+    fn test_caching_cleared_on_reorg() {
+        let blockchain = CachingBlockchain::new(MockBlockchain::default(), 10);
+        blockchain.inner().height.store(100, Ordering::SeqCst);
+        assert_eq!(blockchain.get_height().unwrap(), 100);
+
+        blockchain.get_tx(&tx(0).txid()).unwrap();
+        blockchain.inner().height.store(101, Ordering::SeqCst);
+        assert_eq!(blockchain.get_height().unwrap(), 101);
+        assert_eq!(blockchain.cached_txs(), 1);
+
+        blockchain.inner().height.store(99, Ordering::SeqCst);
+        assert_eq!(blockchain.get_height().unwrap(), 99);
+        assert_eq!(blockchain.cached_txs(), 0);
+
+        blockchain.get_tx(&tx(0).txid()).unwrap();
+        assert_eq!(get_tx_calls(&blockchain), 2);
+    }
+}
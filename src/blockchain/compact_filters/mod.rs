@@ -70,7 +70,7 @@ mod sync;
 use crate::blockchain::*;
 use crate::database::{BatchDatabase, BatchOperations, DatabaseUtils};
 use crate::error::Error;
-use crate::types::{KeychainKind, LocalUtxo, TransactionDetails};
+use crate::types::{KeychainKind, LocalUtxo, TransactionDetails, TxType};
 use crate::{BlockTime, FeeRate};
 
 use peer::*;
@@ -157,6 +157,7 @@ impl CompactFiltersBlockchain {
 
         let mut inputs_sum: u64 = 0;
         let mut outputs_sum: u64 = 0;
+        let mut owned_outputs = 0;
 
         // look for our own inputs
         for (i, input) in tx.input.iter().enumerate() {
@@ -164,7 +165,7 @@ impl CompactFiltersBlockchain {
                 inputs_sum += previous_output.value;
 
                 // this output is ours, we have a path to derive it
-                if let Some((keychain, _)) =
+                if let Some((keychain, derivation_index)) =
                     database.get_path_from_script_pubkey(&previous_output.script_pubkey)?
                 {
                     outgoing += previous_output.value;
@@ -175,6 +176,8 @@ impl CompactFiltersBlockchain {
                         txout: previous_output.clone(),
                         keychain,
                         is_spent: true,
+                        derivation_index: Some(derivation_index),
+                        suspected_dust: false,
                     })?;
                 }
             }
@@ -194,8 +197,11 @@ impl CompactFiltersBlockchain {
                     txout: output.clone(),
                     keychain,
                     is_spent: false,
+                    derivation_index: Some(child),
+                    suspected_dust: false,
                 })?;
                 incoming += output.value;
+                owned_outputs += 1;
 
                 if keychain == KeychainKind::Internal
                     && (internal_max_deriv.is_none() || child > internal_max_deriv.unwrap_or(0))
@@ -217,6 +223,7 @@ impl CompactFiltersBlockchain {
                 sent: outgoing,
                 confirmation_time: BlockTime::new(height, timestamp),
                 fee: Some(inputs_sum.saturating_sub(outputs_sum)),
+                tx_type: Some(TxType::classify(tx, outgoing, owned_outputs)),
             };
 
             info!("Saving tx {}", tx.txid);
@@ -252,6 +259,16 @@ impl GetHeight for CompactFiltersBlockchain {
     }
 }
 
+impl GetBlockHash for CompactFiltersBlockchain {
+    fn get_block_hash(&self, height: u32) -> Result<Option<BlockHash>, Error> {
+        Ok(self.headers.get_block_hash(height as usize)?)
+    }
+
+    fn get_block_header(&self, height: u32) -> Result<Option<BlockHeader>, Error> {
+        Ok(self.headers.get_block_header(height as usize)?)
+    }
+}
+
 impl GetTx for CompactFiltersBlockchain {
     fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, Error> {
         Ok(self.peers[0]
@@ -23,38 +23,645 @@
 //! let blockchain = ElectrumBlockchain::from(client);
 //! # Ok::<(), bdk::Error>(())
 //! ```
+//!
+//! On Unix platforms the [`ElectrumBlockchainConfig::url`] can also point to a server listening
+//! on a Unix domain socket, using the `unix://` scheme (eg. `unix:///run/electrs/electrs.sock`).
 
 use std::collections::{HashMap, HashSet};
+#[cfg(feature = "electrum-cert-expiry")]
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+#[cfg(feature = "electrum-cert-expiry")]
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[allow(unused_imports)]
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 
-use bitcoin::{Transaction, Txid};
+use bitcoin::consensus::serialize;
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::hashes::Hash;
+use bitcoin::util::address::Payload;
+use bitcoin::{Address, BlockHash, BlockHeader, Network, Script, Transaction, TxMerkleNode, Txid};
 
-use electrum_client::{Client, ConfigBuilder, ElectrumApi, Socks5Config};
+use electrum_client::raw_client::{ElectrumSslStream, RawClient};
+use electrum_client::{
+    Batch, Call, Client, ConfigBuilder, ElectrumApi, GetBalanceRes, GetHeadersRes, GetHistoryRes,
+    GetMerkleRes, ListUnspentRes, Param, RawHeaderNotification, ScriptStatus, ServerFeaturesRes,
+    Socks5Config,
+};
 
+use super::caching::LruTxs;
 use super::script_sync::Request;
 use super::*;
 use crate::database::{BatchDatabase, Database};
+use crate::descriptor::ExtendedDescriptor;
 use crate::error::Error;
 use crate::{BlockTime, FeeRate};
 
 /// Wrapper over an Electrum Client that implements the required blockchain traits
 ///
+/// When created from an [`ElectrumBlockchainConfig`], a request failing because the connection
+/// to the server was dropped is retried on a new connection, up to
+/// [`retry`](ElectrumBlockchainConfig::retry) times and at least once. A blockchain created from
+/// an existing [`Client`] can't reconnect by itself.
+///
+/// With a [`concurrency`](ElectrumBlockchainConfig::concurrency) larger than one, every batch of
+/// script pubkeys is split among as many connections to fetch their history in parallel.
+///
 /// ## Example
 /// See the [`blockchain::electrum`](crate::blockchain::electrum) module for a usage example.
 pub struct ElectrumBlockchain {
-    client: Client,
+    client: Arc<ElectrumClient>,
     stop_gap: usize,
+    internal_stop_gap: Option<usize>,
+    sync_error_policy: SyncErrorPolicy,
+    tx_cache_max_entries: Option<usize>,
+    /// Address of the server (`host:port`) if it was reached directly over TLS
+    #[cfg(feature = "electrum-cert-expiry")]
+    tls_server: Option<String>,
+    #[cfg(feature = "electrum-cert-expiry")]
+    timeout: Option<u8>,
+    ownership_resolver: Option<OwnershipResolver>,
+    prefetch: bool,
+    validate_proofs: bool,
+    trusted_headers: Option<Arc<RwLock<HeaderChain>>>,
+    batch_size: usize,
+    min_fee_rate: Option<FeeRate>,
+    /// Clients used to fetch the history of script pubkeys in parallel, starting with `client`
+    history_clients: Vec<Arc<ElectrumClient>>,
+    shared_tx_cache: Option<SharedTxCache>,
 }
 
+/// Number of requests sent to the server in a single batch by default during sync
+const DEFAULT_BATCH_SIZE: usize = 100;
+
+/// Largest confirmation target accepted by `estimatesmartfee` in Bitcoin Core
+const MAX_FEE_ESTIMATE_TARGET: usize = 1008;
+
 impl std::convert::From<Client> for ElectrumBlockchain {
     fn from(client: Client) -> Self {
+        let client = Arc::new(ElectrumClient::new(
+            ElectrumConnection::Client(client),
+            None,
+        ));
         ElectrumBlockchain {
-            client,
+            client: Arc::clone(&client),
             stop_gap: 20,
+            internal_stop_gap: None,
+            sync_error_policy: SyncErrorPolicy::default(),
+            tx_cache_max_entries: None,
+            #[cfg(feature = "electrum-cert-expiry")]
+            tls_server: None,
+            #[cfg(feature = "electrum-cert-expiry")]
+            timeout: None,
+            ownership_resolver: None,
+            prefetch: false,
+            validate_proofs: false,
+            trusted_headers: None,
+            batch_size: DEFAULT_BATCH_SIZE,
+            min_fee_rate: None,
+            history_clients: vec![client],
+            shared_tx_cache: None,
+        }
+    }
+}
+
+impl ElectrumBlockchain {
+    /// Set the stop gap of the internal (change) keychain, which defaults to the stop gap of the
+    /// external one
+    ///
+    /// Change addresses are handed out in order, so they rarely need a gap as large as the
+    /// external ones.
+    pub fn with_internal_stop_gap(mut self, internal_stop_gap: usize) -> Self {
+        self.internal_stop_gap = Some(internal_stop_gap);
+        self
+    }
+
+    /// Set the policy used to handle errors while fetching the history of a script pubkey
+    pub fn with_sync_error_policy(mut self, sync_error_policy: SyncErrorPolicy) -> Self {
+        self.sync_error_policy = sync_error_policy;
+        self
+    }
+
+    /// Set the maximum number of transactions kept in memory while syncing
+    ///
+    /// The least recently used transactions are evicted first. Transactions needed to process the
+    /// current batch are never evicted, so the cache can temporarily hold more than `max_entries`
+    /// if a single batch needs more than that.
+    pub fn with_tx_cache_max_entries(mut self, max_entries: usize) -> Self {
+        self.tx_cache_max_entries = Some(max_entries);
+        self
+    }
+
+    /// Set a custom resolver consulted during sync to decide which outputs belong to the wallet
+    pub fn with_ownership_resolver(mut self, ownership_resolver: OwnershipResolver) -> Self {
+        self.ownership_resolver = Some(ownership_resolver);
+        self
+    }
+
+    /// Fetch the history of the next batch of script pubkeys while the current one is processed
+    ///
+    /// The next batch is requested in the background during sync, which reduces the time spent
+    /// waiting for the server. Requests sharing a connection are still sent one at a time. A batch
+    /// prefetched past the stop gap is discarded, at the cost of having requested the history of
+    /// some script pubkeys for nothing.
+    pub fn with_prefetch(mut self, prefetch: bool) -> Self {
+        self.prefetch = prefetch;
+        self
+    }
+
+    /// Set the maximum number of requests sent to the server in a single batch during sync
+    /// (default: 100)
+    ///
+    /// Some servers reject large batches, which is independent of the stop gap: the history of
+    /// the script pubkeys up to the stop gap is fetched in as many batches as needed.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Set the fee rate returned by [`Blockchain::estimate_fee`] when the server can't estimate
+    /// one (default: none)
+    ///
+    /// Servers reply `-1` when they don't have enough data for a confirmation target, in which
+    /// case larger targets are tried up to 1008 blocks. If none of them has an estimate this fee
+    /// rate is returned, usually the minimum relay fee, otherwise the estimation fails with
+    /// [`Error::FeeRateUnavailable`].
+    pub fn with_min_fee_rate(mut self, min_fee_rate: FeeRate) -> Self {
+        self.min_fee_rate = Some(min_fee_rate);
+        self
+    }
+
+    /// Set whether to check the merkle proof of every newly confirmed transaction against the
+    /// trusted header of its block during sync (default: false)
+    ///
+    /// The headers are taken from the [`HeaderChain`] set with
+    /// [`ElectrumBlockchain::with_trusted_headers`], never from the server. The sync fails with
+    /// [`Error::InvalidMerkleProof`] if the server reports a confirmation it can't prove, or with
+    /// [`Error::MissingTrustedHeader`] if the header of the block isn't trusted.
+    pub fn with_validate_proofs(mut self, validate_proofs: bool) -> Self {
+        self.validate_proofs = validate_proofs;
+        self
+    }
+
+    /// Set the headers used to check the merkle proofs returned by the server, see
+    /// [`ElectrumBlockchain::with_validate_proofs`]
+    ///
+    /// The chain is shared with the caller, who can keep extending it as new headers are verified.
+    pub fn with_trusted_headers(mut self, headers: Arc<RwLock<HeaderChain>>) -> Self {
+        self.trusted_headers = Some(headers);
+        self
+    }
+
+    /// Check that a transaction is confirmed in the block at `height`, verifying its merkle proof
+    /// against the trusted header at that height, see [`ElectrumBlockchain::with_trusted_headers`]
+    ///
+    /// Returns `false` if the transaction is unconfirmed, unknown to the server, confirmed at a
+    /// different height or if its merkle proof doesn't match the header. Fails with
+    /// [`Error::MissingTrustedHeader`] if there's no trusted header at `height`.
+    pub fn verify_tx_proof(&self, txid: &Txid, height: u32) -> Result<bool, Error> {
+        let header = trusted_header(&self.trusted_headers, height)?;
+        self.check_tx_proof(txid, height, &header)
+    }
+
+    /// Like [`ElectrumBlockchain::verify_tx_proof`], but uses an already trusted `header`
+    fn check_tx_proof(
+        &self,
+        txid: &Txid,
+        height: u32,
+        header: &BlockHeader,
+    ) -> Result<bool, Error> {
+        match self.client.transaction_get_merkle(txid, height as usize) {
+            Ok(merkle) => Ok(merkle_matches_header(txid, height, &merkle, header)),
+            Err(e) if is_not_in_block(&e) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Check the merkle proofs of `txs`, given as `(txid, height)`, against the trusted headers,
+    /// see [`ElectrumBlockchain::with_validate_proofs`]
+    ///
+    /// All the headers are looked up before the first proof is requested, so that a missing one
+    /// fails without sending any request.
+    fn check_tx_proofs(&self, txs: &[(Txid, u32)]) -> Result<(), Error> {
+        let headers = txs
+            .iter()
+            .map(|(_, height)| trusted_header(&self.trusted_headers, *height))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for ((txid, height), header) in txs.iter().zip(&headers) {
+            if !self.check_tx_proof(txid, *height, header)? {
+                return Err(Error::InvalidMerkleProof(*txid));
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the absolute fee paid by a transaction (sats)
+    ///
+    /// The fee is computed from the transaction and its previous outputs, fetched from the server.
+    /// Returns `None` if the transaction is unknown to the server or if it's a coinbase
+    /// transaction.
+    pub fn get_tx_fee(&self, txid: &Txid) -> Result<Option<u64>, Error> {
+        let tx = match self.client.transaction_get(txid) {
+            Ok(tx) => tx,
+            Err(e) if is_tx_not_found(&e) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if tx.is_coin_base() {
+            return Ok(None);
+        }
+
+        let prev_txs = self.client.batch_transaction_get(
+            tx.input
+                .iter()
+                .map(|txin| &txin.previous_output.txid)
+                .collect::<Vec<_>>(),
+        )?;
+        let mut input_value = 0;
+        for (txin, prev_tx) in tx.input.iter().zip(prev_txs) {
+            let prev_output = txin.previous_output;
+            input_value += prev_tx
+                .output
+                .get(prev_output.vout as usize)
+                .ok_or(Error::InvalidOutpoint(prev_output))?
+                .value;
+        }
+        let output_value = tx.output.iter().map(|txout| txout.value).sum::<u64>();
+
+        input_value
+            .checked_sub(output_value)
+            .map(Some)
+            .ok_or_else(|| {
+                Error::Generic(format!("Transaction {} spends more than its inputs", txid))
+            })
+    }
+
+    /// Return the `(confirmed, unconfirmed)` balance of each script pubkey in `scripts` (sats)
+    ///
+    /// The balances are fetched in batches of the size set with
+    /// [`ElectrumBlockchain::with_batch_size`], which is much cheaper than a full sync when only
+    /// the balance is needed. The unconfirmed balance is the change brought by the unconfirmed
+    /// transactions, negative when they spend more from a script pubkey than they pay to it.
+    pub fn get_balances(&self, scripts: &[Script]) -> Result<Vec<(u64, i64)>, Error> {
+        let mut balances = Vec::with_capacity(scripts.len());
+        for chunk in scripts.chunks(self.batch_size.max(1)) {
+            balances.extend(
+                self.client
+                    .batch_script_get_balance(chunk.iter())?
+                    .into_iter()
+                    .map(|balance| (balance.confirmed, balance.unconfirmed)),
+            );
+        }
+
+        Ok(balances)
+    }
+
+    /// Return the transactions in the history of `notification_script` that look like BIP47
+    /// notification transactions
+    ///
+    /// These are the transactions paying to the notification address that also carry an 80 bytes
+    /// `OP_RETURN` payload: the candidates to decode to learn the payment code of the sender.
+    pub fn find_notification_txs(
+        &self,
+        notification_script: &Script,
+    ) -> Result<Vec<Transaction>, Error> {
+        let history = self.client.script_get_history(notification_script)?;
+        let txs = self
+            .client
+            .batch_transaction_get(history.iter().map(|entry| &entry.tx_hash))?;
+
+        Ok(txs
+            .into_iter()
+            .filter(|tx| is_notification_tx(tx, notification_script))
+            .collect())
+    }
+
+    /// Return the expiry time of the TLS certificate presented by the server
+    ///
+    /// This opens a new TLS connection to the server to inspect its certificate. The certificate
+    /// isn't validated, since only its expiry is read: self-signed certificates, common among
+    /// Electrum servers, are supported. `None` is returned if the blockchain wasn't created with
+    /// [`ConfigurableBlockchain::from_config`] using an `ssl://` url, or if a socks5 proxy is in use,
+    /// since connecting directly to the server would bypass it.
+    #[cfg(feature = "electrum-cert-expiry")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "electrum-cert-expiry")))]
+    pub fn cert_expiry(&self) -> Result<Option<SystemTime>, Error> {
+        match &self.tls_server {
+            Some(server) => fetch_cert_expiry(server, self.timeout).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Return the banner the server wants to be displayed to its users
+    pub fn server_banner(&self) -> Result<String, Error> {
+        match self.raw_call("server.banner", vec![])? {
+            serde_json::Value::String(banner) => Ok(banner),
+            other => Err(Error::Generic(format!(
+                "Unexpected `server.banner` response: {}",
+                other
+            ))),
+        }
+    }
+
+    /// Return the software, protocol versions and chain of the server
+    ///
+    /// Useful to check a server before trusting it, for example that its genesis hash matches
+    /// the network of the wallet.
+    pub fn server_features(&self) -> Result<ServerFeatures, Error> {
+        let features = self.client.server_features()?;
+        Ok(ServerFeatures {
+            genesis_hash: BlockHash::from_hex(&features.genesis_hash.to_hex())?,
+            server_version: features.server_version,
+            protocol_min: features.protocol_min,
+            protocol_max: features.protocol_max,
+            hash_function: features.hash_function,
+            pruning: features.pruning,
+        })
+    }
+
+    /// Return the address the server operator accepts donations to
+    ///
+    /// `None` is returned if the server doesn't have one, or if the address isn't valid for the
+    /// network of the server, as identified by the genesis hash it reports in its features.
+    pub fn server_donation_address(&self) -> Result<Option<Address>, Error> {
+        let address = match self.raw_call("server.donation_address", vec![])? {
+            serde_json::Value::String(address) if !address.is_empty() => address,
+            _ => return Ok(None),
+        };
+        let address = match Address::from_str(&address) {
+            Ok(address) => address,
+            Err(e) => {
+                debug!("Invalid donation address `{}`: {}", address, e);
+                return Ok(None);
+            }
+        };
+
+        match self.get_network()? {
+            Some(network) if address_matches_network(&address, network) => Ok(Some(address)),
+            _ => {
+                debug!(
+                    "Ignoring donation address `{}` not valid for the server network",
+                    address
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    /// Return the current difficulty and estimated hashrate of the network, derived from the
+    /// header of the tip of the chain (see [`NetworkStats::from_header`])
+    pub fn get_network_stats(&self) -> Result<NetworkStats, Error> {
+        let tip = self.client.block_headers_subscribe()?;
+        Ok(NetworkStats::from_header(tip.height as u32, &tip.header))
+    }
+
+    /// Return whether any of the first `stop_gap` script pubkeys of `descriptor` has a history
+    ///
+    /// This quickly confirms that a descriptor, or the seed it was derived from, was entered
+    /// correctly before a full sync. A descriptor without a wildcard has a single script pubkey.
+    /// Fails if the keys of the descriptor aren't valid for `network`.
+    ///
+    /// The histories are requested in batches of up to
+    /// [`batch_size`](ElectrumBlockchain::with_batch_size) script pubkeys, stopping at the first
+    /// batch with a used script pubkey.
+    pub fn verify_descriptor_has_funds(
+        &self,
+        descriptor: &ExtendedDescriptor,
+        network: Network,
+        stop_gap: usize,
+    ) -> Result<bool, Error> {
+        let scripts = descriptor_script_pubkeys(descriptor, network, stop_gap)?;
+        for chunk in scripts.chunks(self.batch_size.max(1)) {
+            let histories = self.client.batch_script_get_history(chunk.iter())?;
+            if histories.iter().any(|history| !history.is_empty()) {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Return the script pubkeys that a sync of `database` would query, without making any request
+    /// to the server
+    ///
+    /// The script pubkeys phase of the sync is run against the transactions already in
+    /// `database`, in batches of [`batch_size`](ElectrumBlockchain::with_batch_size) script
+    /// pubkeys, which shows how far the stop gap takes the scan of each keychain.
+    pub fn plan_sync<D: BatchDatabase>(&self, database: &D) -> Result<SyncPlan, Error> {
+        script_sync::plan(
+            database,
+            script_sync::StopGap::new(self.stop_gap, self.internal_stop_gap),
+            self.batch_size,
+        )
+    }
+
+    /// Return the fee histogram of the mempool of the server
+    ///
+    /// Every `(fee_rate, vsize)` pair is the total vsize of the transactions paying at least
+    /// `fee_rate` but less than the fee rate of the previous pair, sorted by decreasing fee rate.
+    pub fn fee_histogram(&self) -> Result<Vec<(FeeRate, usize)>, Error> {
+        let histogram = self.raw_call("mempool.get_fee_histogram", vec![])?;
+        Ok(into_fee_histogram(serde_json::from_value(histogram)?))
+    }
+
+    /// Issue an arbitrary RPC call to the server and return the raw JSON result
+    ///
+    /// This is an escape hatch for the methods not wrapped by [`ElectrumBlockchain`], like the
+    /// custom extensions of some servers. The result isn't validated in any way and its format
+    /// depends entirely on the server implementation.
+    ///
+    /// Only strings, booleans and non-negative integers can be passed in `params`, other values
+    /// fail with [`Error::Generic`].
+    pub fn raw_call(
+        &self,
+        method: &str,
+        params: Vec<serde_json::Value>,
+    ) -> Result<serde_json::Value, Error> {
+        let params = params
+            .into_iter()
+            .map(into_param)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(self.client.raw_call(&(method.to_string(), params))?)
+    }
+}
+
+/// Features of an Electrum server, as returned by [`ElectrumBlockchain::server_features`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerFeatures {
+    /// Hash of the genesis block of the chain of the server
+    pub genesis_hash: BlockHash,
+    /// Name and version of the server software, eg. `ElectrumX 1.16.0`
+    pub server_version: String,
+    /// Oldest version of the protocol supported by the server
+    pub protocol_min: String,
+    /// Newest version of the protocol supported by the server
+    pub protocol_max: String,
+    /// Hash function used to index script pubkeys, `None` if the server doesn't report it
+    pub hash_function: Option<String>,
+    /// Height below which the history was pruned, `None` if the server isn't pruned
+    pub pruning: Option<i64>,
+}
+
+/// Convert a JSON value to a parameter of an Electrum RPC call
+fn into_param(value: serde_json::Value) -> Result<Param, Error> {
+    match value {
+        serde_json::Value::String(s) => Ok(Param::String(s)),
+        serde_json::Value::Bool(b) => Ok(Param::Bool(b)),
+        serde_json::Value::Number(n) if n.is_u64() => {
+            Ok(Param::Usize(n.as_u64().unwrap() as usize))
+        }
+        other => Err(Error::Generic(format!(
+            "Unsupported Electrum RPC parameter: {}",
+            other
+        ))),
+    }
+}
+
+/// Whether `address` can be used on `network`
+///
+/// Signet addresses are encoded like testnet ones, and so are the base58 regtest ones, so an
+/// [`Address`] parsed from a string never has those networks.
+fn address_matches_network(address: &Address, network: Network) -> bool {
+    let is_segwit = matches!(address.payload, Payload::WitnessProgram { .. });
+
+    match (network, address.network) {
+        (network, address_network) if network == address_network => true,
+        (Network::Signet, Network::Testnet) => true,
+        (Network::Regtest, Network::Testnet) => !is_segwit,
+        _ => false,
+    }
+}
+
+/// Compute the merkle root committed to by a merkle branch returned by the server
+fn electrum_merkle_root(txid: &Txid, merkle: &GetMerkleRes) -> Option<TxMerkleNode> {
+    // the hashes of the branch are sent in the reversed (display) byte order
+    let branch = merkle
+        .merkle
+        .iter()
+        .map(|node| {
+            let mut node = *node;
+            node.reverse();
+            TxMerkleNode::from_inner(node)
+        })
+        .collect::<Vec<_>>();
+
+    merkle_root(txid, &branch, merkle.pos)
+}
+
+/// Whether the merkle proof returned by the server leads from `txid` to the merkle root of the
+/// trusted `header` of the block at `height`
+fn merkle_matches_header(
+    txid: &Txid,
+    height: u32,
+    merkle: &GetMerkleRes,
+    header: &BlockHeader,
+) -> bool {
+    merkle.block_height == height as usize
+        && electrum_merkle_root(txid, merkle) == Some(header.merkle_root)
+}
+
+/// Certificate verifier accepting any certificate, used to read the expiry of certificates that
+/// don't chain up to a known root
+#[cfg(feature = "electrum-cert-expiry")]
+struct AcceptAnyCertificate;
+
+#[cfg(feature = "electrum-cert-expiry")]
+impl rustls::client::ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(feature = "electrum-cert-expiry")]
+fn fetch_cert_expiry(server: &str, timeout: Option<u8>) -> Result<SystemTime, Error> {
+    use std::net::ToSocketAddrs;
+
+    let tls_err = |e: rustls::Error| Error::Generic(format!("TLS error: {}", e));
+    let io_err = |e: std::io::Error| Error::Electrum(electrum_client::Error::IOError(e));
+
+    let host = server_host(server);
+    let server_name = rustls::ServerName::try_from(host)
+        .map_err(|_| Error::Generic(format!("Invalid server name `{}`", host)))?;
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate))
+        .with_no_client_auth();
+    let mut connection =
+        rustls::ClientConnection::new(Arc::new(config), server_name).map_err(tls_err)?;
+
+    let timeout = timeout.map(|t| Duration::from_secs(t.into()));
+    let mut last_err = None;
+    let mut stream = None;
+    for addr in server.to_socket_addrs().map_err(io_err)? {
+        let attempt = match timeout {
+            Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+            None => TcpStream::connect(addr),
+        };
+        match attempt {
+            Ok(s) => {
+                stream = Some(s);
+                break;
+            }
+            Err(e) => last_err = Some(e),
+        }
+    }
+    let mut stream = match (stream, last_err) {
+        (Some(stream), _) => stream,
+        (None, Some(e)) => return Err(io_err(e)),
+        (None, None) => {
+            return Err(Error::Generic(format!(
+                "`{}` didn't resolve to any address",
+                server
+            )))
         }
+    };
+    stream
+        .set_read_timeout(timeout)
+        .and_then(|_| stream.set_write_timeout(timeout))
+        .map_err(io_err)?;
+    while connection.is_handshaking() {
+        connection.complete_io(&mut stream).map_err(io_err)?;
     }
+
+    let cert = connection
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .ok_or_else(|| Error::Generic("The server didn't present a certificate".into()))?;
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert.0)
+        .map_err(|e| Error::Generic(format!("Invalid server certificate: {}", e)))?;
+    let not_after = u64::try_from(cert.validity().not_after.timestamp())
+        .map_err(|_| Error::Generic("Invalid server certificate expiry".into()))?;
+
+    Ok(UNIX_EPOCH + Duration::from_secs(not_after))
+}
+
+/// Return the host of a `host:port` server address, without the brackets around an IPv6 address
+#[cfg(feature = "electrum-cert-expiry")]
+fn server_host(server: &str) -> &str {
+    let host = match server.rsplit_once(':') {
+        // the last colon of `[::1]` is part of the address
+        Some((host, port)) if !port.ends_with(']') => host,
+        _ => server,
+    };
+    host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
 }
 
 impl Blockchain for ElectrumBlockchain {
@@ -63,6 +670,7 @@ impl Blockchain for ElectrumBlockchain {
             Capability::FullHistory,
             Capability::GetAnyTx,
             Capability::AccurateFees,
+            Capability::FeeHistogram,
         ]
         .into_iter()
         .collect()
@@ -72,10 +680,70 @@ impl Blockchain for ElectrumBlockchain {
         Ok(self.client.transaction_broadcast(tx).map(|_| ())?)
     }
 
+    fn broadcast_batch(&self, txs: &[Transaction]) -> Result<Vec<Txid>, Error> {
+        let mut broadcast = Vec::with_capacity(txs.len());
+        for group in independent_broadcast_groups(txs) {
+            // the transactions of a group don't spend each other, so a rejected one doesn't stop
+            // the others from being sent
+            let results = group
+                .iter()
+                .map(|tx| {
+                    self.client
+                        .transaction_broadcast_raw(&serialize(tx))
+                        .map(|_| ())
+                        .map_err(Error::Electrum)
+                })
+                .collect();
+            check_group_broadcast(group, results, &mut broadcast)?;
+        }
+
+        Ok(broadcast)
+    }
+
     fn estimate_fee(&self, target: usize) -> Result<FeeRate, Error> {
-        Ok(FeeRate::from_btc_per_kvb(
-            self.client.estimate_fee(target)? as f32
-        ))
+        // the server replies -1 when it can't estimate the fee rate for a target, but it may
+        // still have an estimate for a larger one
+        let mut target = target;
+        loop {
+            let estimate = self.client.estimate_fee(target)?;
+            if estimate > 0.0 {
+                return Ok(FeeRate::from_btc_per_kvb(estimate as f32));
+            }
+
+            debug!("No fee estimate for a target of {} blocks", target);
+            if target >= MAX_FEE_ESTIMATE_TARGET {
+                break;
+            }
+            target = (target.max(1) * 2).min(MAX_FEE_ESTIMATE_TARGET);
+        }
+
+        self.min_fee_rate.ok_or(Error::FeeRateUnavailable)
+    }
+
+    fn get_tx_confirmations(&self, txid: &Txid) -> Result<Option<u32>, Error> {
+        let tx = match self.client.transaction_get(txid) {
+            Ok(tx) => tx,
+            Err(e) if is_tx_not_found(&e) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        // the server only indexes transactions by script, the height of the transaction is the
+        // one in the history of its outputs
+        for txout in &tx.output {
+            let history = self.client.script_get_history(&txout.script_pubkey)?;
+            if let Some(entry) = history.iter().find(|entry| entry.tx_hash == *txid) {
+                if entry.height <= 0 {
+                    return Ok(Some(0));
+                }
+                let tip = self.get_height()?;
+                return Ok(Some((tip + 1).saturating_sub(entry.height as u32).max(1)));
+            }
+        }
+
+        Err(Error::Generic(format!(
+            "Transaction {} is missing from the history of its outputs",
+            txid
+        )))
     }
 }
 
@@ -92,6 +760,30 @@ impl GetHeight for ElectrumBlockchain {
     }
 }
 
+impl GetBlockHash for ElectrumBlockchain {
+    fn get_block_hash(&self, height: u32) -> Result<Option<BlockHash>, Error> {
+        Ok(self
+            .get_block_header(height)?
+            .map(|header| header.block_hash()))
+    }
+
+    fn get_block_header(&self, height: u32) -> Result<Option<BlockHeader>, Error> {
+        match self.client.block_header(height as usize) {
+            Ok(header) => Ok(Some(header)),
+            // heights above the tip are rejected by the server
+            Err(electrum_client::Error::Protocol(_)) => Ok(None),
+            Err(e) => Err(Error::Electrum(e)),
+        }
+    }
+
+    /// Return the network of the server, as identified by the genesis hash it reports in its
+    /// features
+    fn get_network(&self) -> Result<Option<Network>, Error> {
+        let genesis_hash = self.server_features()?.genesis_hash;
+        Ok(network_from_genesis_hash(&genesis_hash))
+    }
+}
+
 impl GetTx for ElectrumBlockchain {
     fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, Error> {
         Ok(self.client.transaction_get(txid).map(Option::Some)?)
@@ -102,29 +794,101 @@ impl WalletSync for ElectrumBlockchain {
     fn wallet_setup<D: BatchDatabase>(
         &self,
         database: &mut D,
-        _progress_update: Box<dyn Progress>,
+        progress_update: Box<dyn Progress>,
     ) -> Result<(), Error> {
-        let mut request = script_sync::start(database, self.stop_gap)?;
-        let mut block_times = HashMap::<u32, u32>::new();
+        self.wallet_setup_with_stats(database, progress_update)?;
+        Ok(())
+    }
+
+    fn wallet_sync_with_stats<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<SyncStats, Error> {
+        self.wallet_setup_with_stats(database, progress_update)
+    }
+
+    fn wallet_setup_with_stats<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<SyncStats, Error> {
+        let start = Instant::new();
+        let mut stats = SyncStats {
+            reorged_txids: reorg::detect_reorgs(self, database)?,
+            ..Default::default()
+        };
+        let mut request = script_sync::start_with_ownership_resolver(
+            database,
+            script_sync::StopGap::new(self.stop_gap, self.internal_stop_gap),
+            self.ownership_resolver.clone(),
+        )?;
+        let mut block_headers = HashMap::<u32, BlockHeader>::new();
         let mut txid_to_height = HashMap::<Txid, u32>::new();
-        let mut tx_cache = TxCache::new(database, &self.client);
-        let chunk_size = self.stop_gap;
+        let mut tx_cache = TxCache::new(
+            database,
+            &self.client,
+            self.tx_cache_max_entries,
+            self.shared_tx_cache.as_ref(),
+        );
+        let chunk_size = self.batch_size.max(1);
         // The electrum server has been inconsistent somehow in its responses during sync. For
         // example, we do a batch request of transactions and the response contains less
         // tranascations than in the request. This should never happen but we don't want to panic.
         let electrum_goof = || Error::Generic("electrum server misbehaving".to_string());
+        // the batch of script pubkeys whose history is being fetched in the background
+        let mut prefetch: Option<Prefetch> = None;
 
         let batch_update = loop {
+            if progress_update.is_cancelled() {
+                return Err(Error::SyncAborted);
+            }
             request = match request {
                 Request::Script(script_req) => {
-                    let scripts = script_req.request().take(chunk_size);
-                    let txids_per_script: Vec<Vec<_>> = self
-                        .client
-                        .batch_script_get_history(scripts)
-                        .map_err(Error::Electrum)?
+                    let chunk = script_req
+                        .request()
+                        .take(chunk_size)
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    stats.scripts_scanned += chunk.len();
+
+                    // a prefetched batch is only used if the scan went on with the same scripts,
+                    // it's discarded if the stop gap was reached or the keychain changed
+                    let prefetched = prefetch.take().filter(|prefetch| prefetch.scripts == chunk);
+                    if self.prefetch {
+                        let next = script_req
+                            .request()
+                            .skip(chunk_size)
+                            .take(chunk_size)
+                            .cloned()
+                            .collect::<Vec<_>>();
+                        if !next.is_empty() {
+                            prefetch = Some(Prefetch::start(&self.history_clients, next));
+                        }
+                    }
+                    let result = match prefetched {
+                        Some(prefetched) => prefetched.join(),
+                        None => parallel_script_get_history(&self.history_clients, &chunk),
+                    };
+
+                    let histories: Vec<Result<Vec<_>, electrum_client::Error>> = match result {
+                        Ok(histories) => histories.into_iter().map(Ok).collect(),
+                        Err(e) if self.sync_error_policy == SyncErrorPolicy::SkipErrored => {
+                            // the batch failed as a whole, retry one script at a time to find
+                            // out which ones are actually erroring
+                            debug!("batch history request failed: {}, retrying per script", e);
+                            chunk
+                                .iter()
+                                .map(|script| self.client.script_get_history(script))
+                                .collect()
+                        }
+                        Err(e) => return Err(Error::Electrum(e)),
+                    };
+                    let txids_per_script: Vec<Result<Vec<_>, Error>> = histories
                         .into_iter()
                         .map(|txs| {
-                            txs.into_iter()
+                            Ok(txs?
+                                .into_iter()
                                 .map(|tx| {
                                     let tx_height = match tx.height {
                                         none if none <= 0 => None,
@@ -135,11 +899,11 @@ impl WalletSync for ElectrumBlockchain {
                                     };
                                     (tx.tx_hash, tx_height)
                                 })
-                                .collect()
+                                .collect())
                         })
                         .collect();
 
-                    script_req.satisfy(txids_per_script)?
+                    script_req.satisfy_with_policy(txids_per_script, self.sync_error_policy)?
                 }
 
                 Request::Conftime(conftime_req) => {
@@ -148,7 +912,7 @@ impl WalletSync for ElectrumBlockchain {
                         let mut needs_block_height_iter = conftime_req
                             .request()
                             .filter_map(|txid| txid_to_height.get(txid).cloned())
-                            .filter(|height| block_times.get(height).is_none());
+                            .filter(|height| block_headers.get(height).is_none());
                         let mut needs_block_height = HashSet::new();
 
                         while needs_block_height.len() < chunk_size {
@@ -163,9 +927,10 @@ impl WalletSync for ElectrumBlockchain {
                     let new_block_headers = self
                         .client
                         .batch_block_header(needs_block_height.iter().cloned())?;
+                    stats.headers_fetched += needs_block_height.len();
 
                     for (height, header) in needs_block_height.into_iter().zip(new_block_headers) {
-                        block_times.insert(height, header.time);
+                        block_headers.insert(height, header);
                     }
 
                     let conftimes = conftime_req
@@ -175,17 +940,28 @@ impl WalletSync for ElectrumBlockchain {
                             let confirmation_time = txid_to_height
                                 .get(txid)
                                 .map(|height| {
-                                    let timestamp =
-                                        *block_times.get(height).ok_or_else(electrum_goof)?;
+                                    let header =
+                                        block_headers.get(height).ok_or_else(electrum_goof)?;
                                     Result::<_, Error>::Ok(BlockTime {
                                         height: *height,
-                                        timestamp: timestamp.into(),
+                                        timestamp: header.time.into(),
                                     })
                                 })
                                 .transpose()?;
                             Ok(confirmation_time)
                         })
-                        .collect::<Result<_, Error>>()?;
+                        .collect::<Result<Vec<_>, Error>>()?;
+
+                    if self.validate_proofs {
+                        let confirmed = conftime_req
+                            .request()
+                            .zip(&conftimes)
+                            .filter_map(|(txid, conftime)| {
+                                conftime.as_ref().map(|conftime| (*txid, conftime.height))
+                            })
+                            .collect::<Vec<_>>();
+                        self.check_tx_proofs(&confirmed)?;
+                    }
 
                     conftime_req.satisfy(conftimes)?
                 }
@@ -232,35 +1008,159 @@ impl WalletSync for ElectrumBlockchain {
                     tx_req.satisfy(full_details)?
                 }
                 Request::Finish(batch_update) => break batch_update,
+            };
+
+            if let Some(sync_progress) = request.sync_progress() {
+                progress_update.update_sync(&sync_progress)?;
+            }
+            if let Some(skipped_scripts) = request.skipped_scripts() {
+                stats.skipped_scripts = skipped_scripts;
             }
         };
+        stats.txs_fetched = tx_cache.fetched;
 
         database.commit_batch(batch_update)?;
-        Ok(())
+        reorg::store_block_hashes(self, database)?;
+
+        stats.duration = start.elapsed();
+        Ok(stats)
+    }
+}
+
+/// Background fetch of the history of a batch of script pubkeys
+///
+/// The thread is waited for when the prefetch is dropped, so that a discarded batch doesn't keep
+/// using the connections after the sync.
+struct Prefetch {
+    scripts: Vec<Script>,
+    handle:
+        Option<std::thread::JoinHandle<Result<Vec<Vec<GetHistoryRes>>, electrum_client::Error>>>,
+}
+
+impl Prefetch {
+    fn start(clients: &[Arc<ElectrumClient>], scripts: Vec<Script>) -> Self {
+        let clients = clients.to_vec();
+        let next_scripts = scripts.clone();
+        let handle =
+            std::thread::spawn(move || parallel_script_get_history(&clients, &next_scripts));
+        Prefetch {
+            scripts,
+            handle: Some(handle),
+        }
+    }
+
+    fn join(mut self) -> Result<Vec<Vec<GetHistoryRes>>, electrum_client::Error> {
+        join_request(self.handle.take().expect("only joined once"))
+    }
+}
+
+impl Drop for Prefetch {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Wait for a request sent from another thread, turning a panic of the thread into an error
+fn join_request<T>(
+    handle: std::thread::JoinHandle<Result<T, electrum_client::Error>>,
+) -> Result<T, electrum_client::Error> {
+    handle.join().unwrap_or_else(|_| {
+        Err(electrum_client::Error::Message(
+            "the request thread panicked".to_string(),
+        ))
+    })
+}
+
+/// Fetch the history of `scripts`, split in as many batches as `clients` sent at the same time
+///
+/// The histories are returned in the same order as `scripts`, as expected by
+/// `ScriptReq::satisfy`.
+fn parallel_script_get_history(
+    clients: &[Arc<ElectrumClient>],
+    scripts: &[Script],
+) -> Result<Vec<Vec<GetHistoryRes>>, electrum_client::Error> {
+    let part_size = ((scripts.len() + clients.len() - 1) / clients.len()).max(1);
+    if scripts.len() <= part_size {
+        return clients[0].batch_script_get_history(scripts.iter());
+    }
+
+    let mut parts = scripts.chunks(part_size);
+    let first = parts.next().expect("at least two parts");
+    let handles = parts
+        .zip(clients[1..].iter())
+        .map(|(part, client)| {
+            let client = Arc::clone(client);
+            let part = part.to_vec();
+            let len = part.len();
+            (
+                len,
+                std::thread::spawn(move || client.batch_script_get_history(part.iter())),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let first = (
+        first.len(),
+        clients[0].batch_script_get_history(first.iter()),
+    );
+    let rest = handles
+        .into_iter()
+        .map(|(len, handle)| (len, join_request(handle)));
+
+    let mut histories = Vec::with_capacity(scripts.len());
+    for (len, part) in std::iter::once(first).chain(rest) {
+        let part = part?;
+        // a part with a missing history would shift the histories of the next ones
+        if part.len() != len {
+            return Err(electrum_client::Error::Message(
+                "missing histories in the batch response".to_string(),
+            ));
+        }
+        histories.extend(part);
     }
+
+    Ok(histories)
 }
 
 struct TxCache<'a, 'b, D> {
     db: &'a D,
-    client: &'b Client,
-    cache: HashMap<Txid, Transaction>,
+    client: &'b ElectrumClient,
+    cache: LruTxs,
+    /// Cache shared with a [`CachingBlockchain`], looked up before fetching a transaction
+    shared: Option<&'b SharedTxCache>,
+    /// Number of transactions downloaded from the server
+    fetched: usize,
 }
 
 impl<'a, 'b, D: Database> TxCache<'a, 'b, D> {
-    fn new(db: &'a D, client: &'b Client) -> Self {
+    fn new(
+        db: &'a D,
+        client: &'b ElectrumClient,
+        max_entries: Option<usize>,
+        shared: Option<&'b SharedTxCache>,
+    ) -> Self {
         TxCache {
             db,
             client,
-            cache: HashMap::default(),
+            cache: LruTxs::new(max_entries),
+            shared,
+            fetched: 0,
         }
     }
+
+    /// Make sure all the `txids` are in the cache, evicting older transactions if needed
     fn save_txs<'c>(&mut self, txids: impl Iterator<Item = &'c Txid>) -> Result<(), Error> {
+        let in_flight = txids.cloned().collect::<HashSet<_>>();
         let mut need_fetch = vec![];
-        for txid in txids {
-            if self.cache.get(txid).is_some() {
+        for txid in &in_flight {
+            if self.cache.touch(txid) {
                 continue;
             } else if let Some(transaction) = self.db.get_raw_tx(txid)? {
-                self.cache.insert(*txid, transaction);
+                self.cache.insert(*txid, transaction, &in_flight);
+            } else if let Some(transaction) = self.shared.and_then(|shared| shared.get(txid)) {
+                self.cache.insert(*txid, transaction, &in_flight);
             } else {
                 need_fetch.push(txid);
             }
@@ -271,41 +1171,394 @@ impl<'a, 'b, D: Database> TxCache<'a, 'b, D> {
                 .client
                 .batch_transaction_get(need_fetch.clone())
                 .map_err(Error::Electrum)?;
+            self.fetched += txs.len();
             for (tx, _txid) in txs.into_iter().zip(need_fetch) {
                 debug_assert_eq!(*_txid, tx.txid());
-                self.cache.insert(tx.txid(), tx);
+                if let Some(shared) = self.shared {
+                    shared.insert(tx.clone());
+                }
+                self.cache.insert(tx.txid(), tx, &in_flight);
             }
         }
+        debug_assert!(
+            self.cache.len()
+                <= std::cmp::max(
+                    self.cache.max_entries().unwrap_or(usize::MAX),
+                    in_flight.len()
+                )
+        );
 
         Ok(())
     }
 
-    fn get(&self, txid: Txid) -> Option<Transaction> {
-        self.cache.get(&txid).map(Clone::clone)
+    fn get(&mut self, txid: Txid) -> Option<Transaction> {
+        self.cache.get(&txid)
     }
 }
 
-/// Configuration for an [`ElectrumBlockchain`]
-#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq)]
-pub struct ElectrumBlockchainConfig {
-    /// URL of the Electrum server (such as ElectrumX, Esplora, BWT) may start with `ssl://` or `tcp://` and include a port
-    ///
-    /// eg. `ssl://electrum.blockstream.info:60002`
-    pub url: String,
-    /// URL of the socks5 proxy server or a Tor service
-    pub socks5: Option<String>,
-    /// Request retry count
-    pub retry: u8,
-    /// Request timeout (seconds)
-    pub timeout: Option<u8>,
-    /// Stop searching addresses for transactions after finding an unused gap of this length
-    pub stop_gap: usize,
+/// Connection used by an [`ElectrumBlockchain`] to talk to the server
+enum ElectrumConnection {
+    /// Connection through a socks5 proxy, or made by the caller
+    Client(Client),
+    /// Plaintext connection over TCP
+    Tcp(RawClient<FailOnEof<TcpStream>>),
+    /// Connection over SSL, along with its socket to check whether the server closed it
+    Ssl(RawClient<ElectrumSslStream>, TcpStream),
+    /// Connection over a Unix domain socket
+    #[cfg(unix)]
+    Unix(RawClient<FailOnEof<UnixStream>>),
 }
 
-impl ConfigurableBlockchain for ElectrumBlockchain {
-    type Config = ElectrumBlockchainConfig;
+/// Stream failing the reads once the server closed the connection
+///
+/// electrum-client keeps reading a stream that reached its end, waiting for a response that will
+/// never come, so the end of the stream is reported as an error to fail the request instead.
+#[derive(Debug)]
+struct FailOnEof<S>(S);
+
+impl<S: Read> Read for FailOnEof<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.read(buf)? {
+            0 if !buf.is_empty() => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed by the server",
+            )),
+            read => Ok(read),
+        }
+    }
+}
+
+impl<S: Write> Write for FailOnEof<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Client used by an [`ElectrumBlockchain`], which reconnects to the server after a connection
+/// error
+///
+/// A call failing because of the connection is retried on a new connection, built from the
+/// configuration of the blockchain. Before reusing an SSL connection, its socket is checked to
+/// reconnect right away if the server closed it. Subscriptions made on the old connection are
+/// lost.
+struct ElectrumClient {
+    connection: RwLock<Arc<ElectrumConnection>>,
+    /// Configuration used to reconnect, `None` if the connection was made by the caller
+    config: Option<ElectrumBlockchainConfig>,
+    /// Held while a request is in flight: electrum-client can deadlock when a batch is sent on a
+    /// connection while another thread is waiting for a response on it
+    request: Mutex<()>,
+    rate_limiter: RwLock<Option<RateLimiter>>,
+}
+
+/// Whether `error` means that the connection to the server is broken
+fn is_connection_error(error: &electrum_client::Error) -> bool {
+    match error {
+        electrum_client::Error::IOError(_) | electrum_client::Error::SharedIOError(_) => true,
+        electrum_client::Error::AllAttemptsErrored(errors) => {
+            errors.iter().any(is_connection_error)
+        }
+        _ => false,
+    }
+}
+
+/// Message of a protocol error returned by the server, lowercased
+///
+/// Servers report the errors of the daemon behind them as protocol errors too, so the message
+/// must be inspected to tell them apart.
+fn protocol_error_message(error: &electrum_client::Error) -> Option<String> {
+    let message = match error {
+        electrum_client::Error::Protocol(serde_json::Value::String(message)) => message,
+        electrum_client::Error::Protocol(serde_json::Value::Object(error)) => {
+            match error.get("message") {
+                Some(serde_json::Value::String(message)) => message,
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+
+    Some(message.to_lowercase())
+}
+
+/// Whether `error` is the reply of the server to a request for a transaction it doesn't know
+fn is_tx_not_found(error: &electrum_client::Error) -> bool {
+    protocol_error_message(error).map_or(false, |message| {
+        message.contains("no such mempool or blockchain transaction")
+            || message.contains("transaction not found")
+    })
+}
+
+/// Whether `error` is the reply of the server to a request for the merkle proof of a transaction
+/// that isn't in the block at the requested height
+///
+/// ElectrumX and Fulcrum reply that the transaction isn't in the block, electrs that it can't
+/// find it.
+fn is_not_in_block(error: &electrum_client::Error) -> bool {
+    is_tx_not_found(error)
+        || protocol_error_message(error).map_or(false, |message| {
+            message.contains("not in block") || message.contains("not found")
+        })
+}
+
+macro_rules! impl_inner_method {
+    ( $self:expr, $name:ident $(, $args:expr)* ) => {
+        $self.call(|connection| match connection {
+            ElectrumConnection::Client(inner) => inner.$name( $($args, )* ),
+            ElectrumConnection::Tcp(inner) => inner.$name( $($args, )* ),
+            ElectrumConnection::Ssl(inner, _) => inner.$name( $($args, )* ),
+            #[cfg(unix)]
+            ElectrumConnection::Unix(inner) => inner.$name( $($args, )* ),
+        })
+    }
+}
+
+impl ElectrumApi for ElectrumClient {
+    fn raw_call(&self, call: &Call) -> Result<serde_json::Value, electrum_client::Error> {
+        impl_inner_method!(self, raw_call, call)
+    }
+
+    fn batch_call(&self, batch: &Batch) -> Result<Vec<serde_json::Value>, electrum_client::Error> {
+        impl_inner_method!(self, batch_call, batch)
+    }
+
+    fn block_headers_subscribe_raw(&self) -> Result<RawHeaderNotification, electrum_client::Error> {
+        impl_inner_method!(self, block_headers_subscribe_raw)
+    }
+
+    fn block_headers_pop_raw(
+        &self,
+    ) -> Result<Option<RawHeaderNotification>, electrum_client::Error> {
+        impl_inner_method!(self, block_headers_pop_raw)
+    }
+
+    fn block_header_raw(&self, height: usize) -> Result<Vec<u8>, electrum_client::Error> {
+        impl_inner_method!(self, block_header_raw, height)
+    }
+
+    fn block_headers(
+        &self,
+        start_height: usize,
+        count: usize,
+    ) -> Result<GetHeadersRes, electrum_client::Error> {
+        impl_inner_method!(self, block_headers, start_height, count)
+    }
+
+    fn estimate_fee(&self, number: usize) -> Result<f64, electrum_client::Error> {
+        impl_inner_method!(self, estimate_fee, number)
+    }
+
+    fn relay_fee(&self) -> Result<f64, electrum_client::Error> {
+        impl_inner_method!(self, relay_fee)
+    }
+
+    fn script_subscribe(
+        &self,
+        script: &Script,
+    ) -> Result<Option<ScriptStatus>, electrum_client::Error> {
+        impl_inner_method!(self, script_subscribe, script)
+    }
+
+    fn script_unsubscribe(&self, script: &Script) -> Result<bool, electrum_client::Error> {
+        impl_inner_method!(self, script_unsubscribe, script)
+    }
+
+    fn script_pop(&self, script: &Script) -> Result<Option<ScriptStatus>, electrum_client::Error> {
+        impl_inner_method!(self, script_pop, script)
+    }
+
+    fn script_get_balance(&self, script: &Script) -> Result<GetBalanceRes, electrum_client::Error> {
+        impl_inner_method!(self, script_get_balance, script)
+    }
+
+    fn batch_script_get_balance<'s, I>(
+        &self,
+        scripts: I,
+    ) -> Result<Vec<GetBalanceRes>, electrum_client::Error>
+    where
+        I: IntoIterator<Item = &'s Script> + Clone,
+    {
+        impl_inner_method!(self, batch_script_get_balance, scripts.clone())
+    }
+
+    fn script_get_history(
+        &self,
+        script: &Script,
+    ) -> Result<Vec<GetHistoryRes>, electrum_client::Error> {
+        impl_inner_method!(self, script_get_history, script)
+    }
+
+    fn batch_script_get_history<'s, I>(
+        &self,
+        scripts: I,
+    ) -> Result<Vec<Vec<GetHistoryRes>>, electrum_client::Error>
+    where
+        I: IntoIterator<Item = &'s Script> + Clone,
+    {
+        impl_inner_method!(self, batch_script_get_history, scripts.clone())
+    }
+
+    fn script_list_unspent(
+        &self,
+        script: &Script,
+    ) -> Result<Vec<ListUnspentRes>, electrum_client::Error> {
+        impl_inner_method!(self, script_list_unspent, script)
+    }
+
+    fn batch_script_list_unspent<'s, I>(
+        &self,
+        scripts: I,
+    ) -> Result<Vec<Vec<ListUnspentRes>>, electrum_client::Error>
+    where
+        I: IntoIterator<Item = &'s Script> + Clone,
+    {
+        impl_inner_method!(self, batch_script_list_unspent, scripts.clone())
+    }
+
+    fn transaction_get_raw(&self, txid: &Txid) -> Result<Vec<u8>, electrum_client::Error> {
+        impl_inner_method!(self, transaction_get_raw, txid)
+    }
+
+    fn batch_transaction_get_raw<'t, I>(
+        &self,
+        txids: I,
+    ) -> Result<Vec<Vec<u8>>, electrum_client::Error>
+    where
+        I: IntoIterator<Item = &'t Txid> + Clone,
+    {
+        impl_inner_method!(self, batch_transaction_get_raw, txids.clone())
+    }
+
+    fn batch_block_header_raw<I>(&self, heights: I) -> Result<Vec<Vec<u8>>, electrum_client::Error>
+    where
+        I: IntoIterator<Item = u32> + Clone,
+    {
+        impl_inner_method!(self, batch_block_header_raw, heights.clone())
+    }
+
+    fn batch_estimate_fee<I>(&self, numbers: I) -> Result<Vec<f64>, electrum_client::Error>
+    where
+        I: IntoIterator<Item = usize> + Clone,
+    {
+        impl_inner_method!(self, batch_estimate_fee, numbers.clone())
+    }
+
+    fn transaction_broadcast_raw(&self, raw_tx: &[u8]) -> Result<Txid, electrum_client::Error> {
+        impl_inner_method!(self, transaction_broadcast_raw, raw_tx)
+    }
+
+    fn transaction_get_merkle(
+        &self,
+        txid: &Txid,
+        height: usize,
+    ) -> Result<GetMerkleRes, electrum_client::Error> {
+        impl_inner_method!(self, transaction_get_merkle, txid, height)
+    }
+
+    fn server_features(&self) -> Result<ServerFeaturesRes, electrum_client::Error> {
+        impl_inner_method!(self, server_features)
+    }
+
+    fn ping(&self) -> Result<(), electrum_client::Error> {
+        impl_inner_method!(self, ping)
+    }
+
+    #[cfg(feature = "debug-calls")]
+    fn calls_made(&self) -> Result<usize, electrum_client::Error> {
+        impl_inner_method!(self, calls_made)
+    }
+}
+
+impl ElectrumClient {
+    fn new(connection: ElectrumConnection, config: Option<ElectrumBlockchainConfig>) -> Self {
+        ElectrumClient {
+            connection: RwLock::new(Arc::new(connection)),
+            config,
+            request: Mutex::new(()),
+            rate_limiter: RwLock::new(None),
+        }
+    }
+
+    fn from_config(config: &ElectrumBlockchainConfig) -> Result<Self, Error> {
+        let connection = ElectrumConnection::from_config(config)?;
+        Ok(Self::new(connection, Some(config.clone())))
+    }
+
+    /// Make a call with the current connection, reconnecting and retrying it if it fails because
+    /// of the connection
+    fn call<T, F>(&self, f: F) -> Result<T, electrum_client::Error>
+    where
+        F: Fn(&ElectrumConnection) -> Result<T, electrum_client::Error>,
+    {
+        let _request = self.request.lock().unwrap();
+        let mut connection = self.connection.read().unwrap().clone();
+        let mut reconnects = 0;
+        loop {
+            let error = if connection.is_closed() {
+                io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed by the server",
+                )
+                .into()
+            } else {
+                self.wait_rate_limiter();
+                match f(&connection) {
+                    Err(e) if is_connection_error(&e) => e,
+                    result => return result,
+                }
+            };
+            let config = match &self.config {
+                Some(config) if reconnects < self.max_reconnects() => config,
+                _ => return Err(error),
+            };
+            reconnects += 1;
+
+            warn!(
+                "Connection to the Electrum server lost ({}), reconnecting",
+                error
+            );
+            connection = match ElectrumConnection::from_config(config) {
+                Ok(connection) => Arc::new(connection),
+                Err(e) => {
+                    warn!("Couldn't reconnect to the Electrum server: {}", e);
+                    return Err(error);
+                }
+            };
+            *self.connection.write().unwrap() = Arc::clone(&connection);
+        }
+    }
+
+    /// Number of times a call failing because of the connection is retried on a new connection
+    fn max_reconnects(&self) -> u8 {
+        match &self.config {
+            None => 0,
+            // electrum-client already retries the calls made through a socks5 proxy
+            Some(config) if config.socks5.is_some() || config.url.starts_with("unix://") => 1,
+            Some(config) => config.retry.max(1),
+        }
+    }
+
+    /// Block until the rate limiter, if any, allows sending a request
+    fn wait_rate_limiter(&self) {
+        if let Some(limiter) = &*self.rate_limiter.read().unwrap() {
+            limiter.wait();
+        }
+    }
+}
+
+impl ElectrumConnection {
+    fn from_config(config: &ElectrumBlockchainConfig) -> Result<Self, Error> {
+        if let Some(path) = config.url.strip_prefix("unix://") {
+            return Self::from_unix_socket(path, config.timeout);
+        }
+        if config.socks5.is_none() {
+            return Ok(Self::connect(&config.url, config.timeout)?);
+        }
 
-    fn from_config(config: &Self::Config) -> Result<Self, Error> {
         let socks5 = config.socks5.as_ref().map(Socks5Config::new);
         let electrum_config = ConfigBuilder::new()
             .retry(config.retry)
@@ -313,22 +1566,256 @@ impl ConfigurableBlockchain for ElectrumBlockchain {
             .socks5(socks5)?
             .build();
 
-        Ok(ElectrumBlockchain {
-            client: Client::from_config(config.url.as_str(), electrum_config)?,
+        Ok(ElectrumConnection::Client(Client::from_config(
+            config.url.as_str(),
+            electrum_config,
+        )?))
+    }
+
+    /// Connect directly to the server at `url`, over SSL if it starts with `ssl://`
+    fn connect(url: &str, timeout: Option<u8>) -> Result<Self, electrum_client::Error> {
+        let timeout = timeout.map(|t| Duration::from_secs(t.into()));
+        if let Some(address) = url.strip_prefix("ssl://") {
+            let socket = connect_tcp(address, timeout)?;
+            let client = RawClient::new_ssl_from_stream(address, true, socket.try_clone()?)?;
+            Ok(ElectrumConnection::Ssl(client, socket))
+        } else {
+            let address = url.strip_prefix("tcp://").unwrap_or(url);
+            let socket = connect_tcp(address, timeout)?;
+            Ok(ElectrumConnection::Tcp(RawClient::from(FailOnEof(socket))))
+        }
+    }
+
+    /// Whether the server is known to have closed the connection
+    ///
+    /// Only the socket of an SSL connection is checked: the reads of the others fail once the
+    /// server closed the connection.
+    fn is_closed(&self) -> bool {
+        match self {
+            ElectrumConnection::Ssl(_, socket) => is_socket_closed(socket),
+            _ => false,
+        }
+    }
+
+    #[cfg(unix)]
+    fn from_unix_socket(path: &str, timeout: Option<u8>) -> Result<Self, Error> {
+        if !std::path::Path::new(path).exists() {
+            return Err(Error::Generic(format!(
+                "Electrum unix socket `{}` doesn't exist",
+                path
+            )));
+        }
+
+        let stream = UnixStream::connect(path).map_err(electrum_client::Error::IOError)?;
+        let timeout = timeout.map(|t| std::time::Duration::from_secs(t.into()));
+        stream
+            .set_read_timeout(timeout)
+            .and_then(|_| stream.set_write_timeout(timeout))
+            .map_err(electrum_client::Error::IOError)?;
+
+        Ok(ElectrumConnection::Unix(RawClient::from(FailOnEof(stream))))
+    }
+
+    #[cfg(not(unix))]
+    fn from_unix_socket(_path: &str, _timeout: Option<u8>) -> Result<Self, Error> {
+        Err(Error::Generic(
+            "Electrum unix sockets are only supported on unix platforms".to_string(),
+        ))
+    }
+}
+
+/// Open a TCP connection to `address` (`host:port`), trying every address it resolves to within
+/// `timeout`
+fn connect_tcp(
+    address: &str,
+    timeout: Option<Duration>,
+) -> Result<TcpStream, electrum_client::Error> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return Ok(TcpStream::connect(address)?),
+    };
+
+    let mut errors = vec![];
+    for addr in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(socket) => {
+                socket.set_read_timeout(Some(timeout))?;
+                socket.set_write_timeout(Some(timeout))?;
+                return Ok(socket);
+            }
+            Err(e) => errors.push(e.into()),
+        }
+    }
+    Err(electrum_client::Error::AllAttemptsErrored(errors))
+}
+
+/// Whether the peer closed `socket`, peeking at it without blocking
+fn is_socket_closed(socket: &TcpStream) -> bool {
+    if socket.set_nonblocking(true).is_err() {
+        return true;
+    }
+    let closed = match socket.peek(&mut [0u8; 1]) {
+        Ok(read) => read == 0,
+        Err(e) => !matches!(
+            e.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+        ),
+    };
+    socket.set_nonblocking(false).is_err() || closed
+}
+
+/// Configuration for an [`ElectrumBlockchain`]
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq)]
+pub struct ElectrumBlockchainConfig {
+    /// URL of the Electrum server (such as ElectrumX, Esplora, BWT) may start with `ssl://` or `tcp://` and include a port
+    ///
+    /// eg. `ssl://electrum.blockstream.info:60002`
+    ///
+    /// On Unix platforms it can also start with `unix://` followed by the path of a Unix domain
+    /// socket, eg. `unix:///run/electrs/electrs.sock`. In that case `socks5` and `retry` are ignored.
+    pub url: String,
+    /// URL of the socks5 proxy server or a Tor service
+    pub socks5: Option<String>,
+    /// Request retry count
+    pub retry: u8,
+    /// Request timeout (seconds)
+    pub timeout: Option<u8>,
+    /// Stop searching addresses for transactions after finding an unused gap of this length
+    pub stop_gap: usize,
+    /// Stop gap of the internal (change) keychain, `stop_gap` is used if `None`
+    #[serde(default)]
+    pub internal_stop_gap: Option<usize>,
+    /// How to handle errors while fetching the history of a single script pubkey
+    #[serde(default)]
+    pub sync_error_policy: SyncErrorPolicy,
+    /// Maximum number of transactions kept in memory while syncing, unbounded if `None`
+    #[serde(default)]
+    pub tx_cache_max_entries: Option<usize>,
+    /// Log a warning after connecting if the TLS certificate of the server expires within this
+    /// number of days
+    ///
+    /// Only checked for `ssl://` urls when no `socks5` proxy is used, and with the
+    /// `electrum-cert-expiry` feature. See [`ElectrumBlockchain::cert_expiry`].
+    #[serde(default)]
+    pub cert_expiry_warning_days: Option<u32>,
+    /// Fetch the history of the next batch of script pubkeys while the current one is processed,
+    /// see [`ElectrumBlockchain::with_prefetch`]
+    #[serde(default)]
+    pub prefetch: bool,
+    /// Check the merkle proof of every newly confirmed transaction against the header of its
+    /// block before storing it, see [`ElectrumBlockchain::with_validate_proofs`]
+    #[serde(default)]
+    pub validate_proofs: bool,
+    /// Maximum number of requests sent to the server in a single batch during sync, see
+    /// [`ElectrumBlockchain::with_batch_size`] (default: 100)
+    #[serde(default)]
+    pub batch_size: Option<usize>,
+    /// Number of connections opened to the server to fetch the history of script pubkeys in
+    /// parallel during sync (default: 1)
+    ///
+    /// Every batch of script pubkeys is split among the connections.
+    #[serde(default)]
+    pub concurrency: Option<u8>,
+    /// Fee rate (sat/vB) returned by `estimate_fee` when the server has no estimate for any
+    /// target, see [`ElectrumBlockchain::with_min_fee_rate`]
+    #[serde(default)]
+    pub min_fee_rate: Option<f32>,
+}
+
+impl LimitRequests for ElectrumBlockchain {
+    /// Wait for `limiter` before every request sent to the server
+    ///
+    /// The clients are shared with the clones of the blockchain, which wait for `limiter` too.
+    fn with_rate_limiter(self, limiter: RateLimiter) -> Self {
+        for client in &self.history_clients {
+            *client.rate_limiter.write().unwrap() = Some(limiter.clone());
+        }
+        self
+    }
+}
+
+impl CacheTxs for ElectrumBlockchain {
+    fn with_tx_cache(mut self, cache: SharedTxCache) -> Self {
+        self.shared_tx_cache = Some(cache);
+        self
+    }
+}
+
+impl ConfigurableBlockchain for ElectrumBlockchain {
+    type Config = ElectrumBlockchainConfig;
+
+    fn from_config(config: &Self::Config) -> Result<Self, Error> {
+        #[cfg(feature = "electrum-cert-expiry")]
+        let tls_server = match (config.url.strip_prefix("ssl://"), &config.socks5) {
+            (Some(server), None) => Some(server.to_string()),
+            _ => None,
+        };
+
+        let client = Arc::new(ElectrumClient::from_config(config)?);
+        let mut history_clients = vec![Arc::clone(&client)];
+        for _ in 1..config.concurrency.unwrap_or(1) {
+            history_clients.push(Arc::new(ElectrumClient::from_config(config)?));
+        }
+
+        let blockchain = ElectrumBlockchain {
+            client,
             stop_gap: config.stop_gap,
-        })
+            internal_stop_gap: config.internal_stop_gap,
+            sync_error_policy: config.sync_error_policy,
+            tx_cache_max_entries: config.tx_cache_max_entries,
+            #[cfg(feature = "electrum-cert-expiry")]
+            tls_server,
+            #[cfg(feature = "electrum-cert-expiry")]
+            timeout: config.timeout,
+            ownership_resolver: None,
+            prefetch: config.prefetch,
+            validate_proofs: config.validate_proofs,
+            trusted_headers: None,
+            batch_size: config.batch_size.unwrap_or(DEFAULT_BATCH_SIZE),
+            min_fee_rate: config.min_fee_rate.map(FeeRate::from_sat_per_vb),
+            history_clients,
+            shared_tx_cache: None,
+        };
+
+        #[cfg(not(feature = "electrum-cert-expiry"))]
+        if config.cert_expiry_warning_days.is_some() {
+            warn!("`cert_expiry_warning_days` requires the `electrum-cert-expiry` feature");
+        }
+        #[cfg(feature = "electrum-cert-expiry")]
+        if let Some(days) = config.cert_expiry_warning_days {
+            match blockchain.cert_expiry() {
+                Ok(Some(expiry)) => {
+                    let window = Duration::from_secs(u64::from(days) * 24 * 60 * 60);
+                    if expiry <= SystemTime::now() + window {
+                        warn!(
+                            "The TLS certificate of the Electrum server expires within {} days",
+                            days
+                        );
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Unable to check the TLS certificate expiry: {}", e),
+            }
+        }
+
+        Ok(blockchain)
     }
 }
 
 #[cfg(test)]
 #[cfg(feature = "test-electrum")]
-mod test {
+mod electrum_test {
+    use std::net::TcpListener;
     use std::sync::Arc;
 
+    use bitcoin::hashes::hex::FromHex;
+
+    use super::test::get_config;
     use super::*;
-    use crate::database::MemoryDatabase;
-    use crate::testutils::blockchain_tests::TestClient;
+    use crate::database::{BatchOperations, MemoryDatabase};
+    use crate::testutils::blockchain_tests::{RpcApi, TestClient};
     use crate::wallet::{AddressIndex, Wallet};
+    use crate::KeychainKind;
 
     crate::bdk_blockchain_tests! {
         fn test_instance(test_client: &TestClient) -> ElectrumBlockchain {
@@ -359,6 +1846,189 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_electrum_verify_descriptor_has_funds() {
+        let mut test_client = TestClient::default();
+        let blockchain =
+            ElectrumBlockchain::from(Client::new(&test_client.electrsd.electrum_url).unwrap());
+        let descriptor = |descriptor: &str| {
+            Wallet::new(descriptor, None, Network::Regtest, MemoryDatabase::new()).unwrap()
+        };
+        let funded = descriptor("wpkh(tpubDCKxNyM3bLgbEX13Mcd8mYxbVg9ajDkWXMh29hMWBurKfVmBfWAM96QVP3zaUcN51HvkZ3ar4VwP82kC8JZhhux8vFQoJintSpVBwpFvyU3/0/*)");
+        let unfunded = descriptor("wpkh(tpubD6NzVbkrYhZ4Y55A58Gv9RSNF5hy84b5AJqYy7sCcjFrkcLpPre8kmgfit6kY1Zs3BLgeypTDBZJM222guPpdz7Cup5yzaMu62u7mYGbwFL/0/*)");
+
+        let address = funded.get_address(AddressIndex::Peek(3)).unwrap();
+        test_client.receive(testutils! {
+            @tx ( (@addr address.address) => 50_000 )
+        });
+
+        let has_funds = |wallet: &Wallet<MemoryDatabase>, stop_gap: usize| {
+            blockchain
+                .verify_descriptor_has_funds(
+                    wallet.get_descriptor_for_keychain(KeychainKind::External),
+                    Network::Regtest,
+                    stop_gap,
+                )
+                .unwrap()
+        };
+        assert!(has_funds(&funded, 20));
+        assert!(!has_funds(&unfunded, 20));
+        // the funded address is past the ones checked
+        assert!(!has_funds(&funded, 3));
+    }
+
+    #[test]
+    fn test_electrum_get_balances() {
+        let mut test_client = TestClient::default();
+        let blockchain =
+            ElectrumBlockchain::from(Client::new(&test_client.electrsd.electrum_url).unwrap());
+        let wallet = Wallet::new(
+            "wpkh(tprv8ZgxMBicQKsPd3EupYiPRhaMooHKUHJxNsTfYuScep13go8QFfHdtkG9nRkFGb7busX4isf6X9dURGCoKgitaApQ6MupRhZMcELAxTBRJgS/*)",
+            None,
+            bitcoin::Network::Regtest,
+            MemoryDatabase::new(),
+        )
+        .unwrap();
+
+        let addresses = (0..3)
+            .map(|_| wallet.get_address(AddressIndex::New).unwrap().address)
+            .collect::<Vec<_>>();
+        test_client.receive(testutils! {
+            @tx ( (@addr addresses[0].clone()) => 50_000 )
+        });
+        test_client.receive(testutils! {
+            @tx ( (@addr addresses[1].clone()) => 30_000 ) ( @confirmations 1 )
+        });
+        wallet.sync(&blockchain, Default::default()).unwrap();
+
+        let scripts = addresses
+            .iter()
+            .map(|address| address.script_pubkey())
+            .collect::<Vec<_>>();
+        let balances = blockchain.get_balances(&scripts).unwrap();
+        assert_eq!(balances.len(), 3);
+        assert_eq!(balances[2], (0, 0));
+        let total = balances
+            .iter()
+            .map(|(confirmed, unconfirmed)| *confirmed as i64 + unconfirmed)
+            .sum::<i64>();
+        assert_eq!(total, wallet.get_balance().unwrap() as i64);
+
+        // spending a confirmed output in the mempool is reported as a negative delta
+        let utxo = wallet
+            .list_unspent()
+            .unwrap()
+            .into_iter()
+            .find(|utxo| utxo.txout.script_pubkey == scripts[1])
+            .unwrap();
+        let mut builder = wallet.build_tx();
+        builder
+            .add_utxo(utxo.outpoint)
+            .unwrap()
+            .manually_selected_only()
+            .drain_to(test_client.get_node_address(None).script_pubkey());
+        let (mut psbt, _) = builder.finish().unwrap();
+        assert!(wallet.sign(&mut psbt, Default::default()).unwrap());
+        blockchain.broadcast(&psbt.extract_tx()).unwrap();
+
+        let balances = blockchain.get_balances(&scripts).unwrap();
+        assert_eq!(balances[1], (30_000, -30_000));
+    }
+
+    #[test]
+    fn test_electrum_get_tx_fee() {
+        let mut test_client = TestClient::default();
+        let blockchain =
+            ElectrumBlockchain::from(Client::new(&test_client.electrsd.electrum_url).unwrap());
+        let wallet = Wallet::new(
+            "wpkh(L5EZftvrYaSudiozVRzTqLcHLNDoVn7H5HSfM9BAN6tMJX8oTWz6)",
+            None,
+            bitcoin::Network::Regtest,
+            MemoryDatabase::new(),
+        )
+        .unwrap();
+
+        let address = wallet.get_address(AddressIndex::New).unwrap();
+        test_client.receive(testutils! {
+            @tx ( (@addr address.address) => 50_000 )
+        });
+        wallet.sync(&blockchain, Default::default()).unwrap();
+
+        let mut builder = wallet.build_tx();
+        builder
+            .add_recipient(test_client.get_node_address(None).script_pubkey(), 25_000)
+            .fee_absolute(1_234);
+        let (mut psbt, details) = builder.finish().unwrap();
+        assert!(wallet.sign(&mut psbt, Default::default()).unwrap());
+        blockchain.broadcast(&psbt.extract_tx()).unwrap();
+
+        assert_eq!(blockchain.get_tx_fee(&details.txid).unwrap(), Some(1_234));
+        assert_eq!(
+            blockchain
+                .get_tx_fee(&Txid::from_hex(&"00".repeat(32)).unwrap())
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_electrum_tx_cache_max_entries() {
+        let mut test_client = TestClient::default();
+        let descriptor = "wpkh(L5EZftvrYaSudiozVRzTqLcHLNDoVn7H5HSfM9BAN6tMJX8oTWz6)";
+        let wallet = Wallet::new(
+            descriptor,
+            None,
+            bitcoin::Network::Regtest,
+            MemoryDatabase::new(),
+        )
+        .unwrap();
+        let node_addr = test_client.get_node_address(None);
+
+        // chain of transactions each spending the previous one, so that computing the fees of
+        // every tx requires its parent
+        let address = wallet.get_address(AddressIndex::New).unwrap();
+        test_client.receive(testutils! {
+            @tx ( (@addr address.address) => 500_000 )
+        });
+        let blockchain =
+            ElectrumBlockchain::from(Client::new(&test_client.electrsd.electrum_url).unwrap());
+        wallet.sync(&blockchain, Default::default()).unwrap();
+        for _ in 0..10 {
+            let mut builder = wallet.build_tx();
+            builder.add_recipient(node_addr.script_pubkey(), 10_000);
+            let (mut psbt, _) = builder.finish().unwrap();
+            assert!(wallet.sign(&mut psbt, Default::default()).unwrap());
+            blockchain.broadcast(&psbt.extract_tx()).unwrap();
+            wallet.sync(&blockchain, Default::default()).unwrap();
+        }
+
+        let bounded = Wallet::new(
+            descriptor,
+            None,
+            bitcoin::Network::Regtest,
+            MemoryDatabase::new(),
+        )
+        .unwrap();
+        let bounded_blockchain =
+            ElectrumBlockchain::from(Client::new(&test_client.electrsd.electrum_url).unwrap())
+                .with_tx_cache_max_entries(2);
+        // `TxCache` debug-asserts its bound while syncing
+        bounded
+            .sync(&bounded_blockchain, Default::default())
+            .unwrap();
+
+        let mut expected = wallet.list_transactions(false).unwrap();
+        let mut found = bounded.list_transactions(false).unwrap();
+        expected.sort_by_key(|details| details.txid);
+        found.sort_by_key(|details| details.txid);
+        assert_eq!(found.len(), 11);
+        assert_eq!(found, expected);
+        assert_eq!(
+            bounded.get_balance().unwrap(),
+            wallet.get_balance().unwrap()
+        );
+    }
+
     #[test]
     fn test_electrum_blockchain_factory_sync_wallet() {
         let (mut test_client, factory) = get_factory();
@@ -385,4 +2055,1394 @@ mod test {
 
         assert_eq!(wallet.get_balance().unwrap(), 50_000);
     }
+
+    #[test]
+    fn test_electrum_wallet_setup_with_stats() {
+        let mut test_client = TestClient::default();
+        let wallet = Wallet::new(
+            "wpkh(L5EZftvrYaSudiozVRzTqLcHLNDoVn7H5HSfM9BAN6tMJX8oTWz6)",
+            None,
+            bitcoin::Network::Regtest,
+            MemoryDatabase::new(),
+        )
+        .unwrap();
+        let address = wallet.get_address(AddressIndex::New).unwrap();
+        let txid = test_client.receive(testutils! {
+            @tx ( (@addr address.address) => 50_000 )
+        });
+        test_client.generate(1, None);
+
+        let blockchain =
+            ElectrumBlockchain::from(Client::new(&test_client.electrsd.electrum_url).unwrap());
+        let mut database = MemoryDatabase::new();
+        database
+            .set_script_pubkey(&address.script_pubkey(), KeychainKind::External, 0)
+            .unwrap();
+        let stats = blockchain
+            .wallet_setup_with_stats(&mut database, Box::new(NoopProgress))
+            .unwrap();
+
+        // the transaction is downloaded along with the ones it spends
+        let parents = blockchain
+            .get_tx(&txid)
+            .unwrap()
+            .unwrap()
+            .input
+            .iter()
+            .map(|input| input.previous_output.txid)
+            .collect::<HashSet<_>>();
+        assert_eq!(stats.scripts_scanned, 1);
+        assert_eq!(stats.txs_fetched, 1 + parents.len());
+        assert_eq!(stats.headers_fetched, 1);
+        assert_eq!(stats.bytes_transferred, None);
+        assert_eq!(database.iter_txs(false).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_electrum_server_features() {
+        let test_client = TestClient::default();
+        let blockchain =
+            ElectrumBlockchain::from(Client::new(&test_client.electrsd.electrum_url).unwrap());
+
+        let features = blockchain.server_features().unwrap();
+        assert_eq!(
+            features.genesis_hash,
+            bitcoin::blockdata::constants::genesis_block(Network::Regtest).block_hash()
+        );
+        assert!(!features.server_version.is_empty());
+        assert!(!features.protocol_max.is_empty());
+        assert_eq!(blockchain.get_network().unwrap(), Some(Network::Regtest));
+        blockchain.server_banner().unwrap();
+    }
+
+    #[test]
+    fn test_electrum_get_block_locator() {
+        let mut test_client = TestClient::default();
+        test_client.generate(30, None);
+
+        let blockchain =
+            ElectrumBlockchain::from(Client::new(&test_client.electrsd.electrum_url).unwrap());
+        let tip = blockchain.get_height().unwrap();
+        let locator = blockchain.get_block_locator(tip).unwrap();
+
+        assert_eq!(locator.len(), block_locator_heights(tip).len());
+        assert_eq!(
+            locator[0],
+            blockchain
+                .client
+                .block_header(tip as usize)
+                .unwrap()
+                .block_hash()
+        );
+        assert_eq!(
+            locator.last(),
+            Some(&bitcoin::blockdata::constants::genesis_block(Network::Regtest).block_hash())
+        );
+        assert_eq!(blockchain.get_block_hash(tip + 1).unwrap(), None);
+    }
+
+    #[test]
+    fn test_electrum_verify_tx_proof() {
+        let mut test_client = TestClient::default();
+        let wallet = Wallet::new(
+            "wpkh(L5EZftvrYaSudiozVRzTqLcHLNDoVn7H5HSfM9BAN6tMJX8oTWz6)",
+            None,
+            bitcoin::Network::Regtest,
+            MemoryDatabase::new(),
+        )
+        .unwrap();
+        let address = wallet.get_address(AddressIndex::New).unwrap();
+        let txid = test_client.receive(testutils! {
+            @tx ( (@addr address.address) => 50_000 )
+        });
+        test_client.generate(1, None);
+
+        let blockchain =
+            ElectrumBlockchain::from(Client::new(&test_client.electrsd.electrum_url).unwrap())
+                .with_validate_proofs(true);
+        let height = blockchain.get_height().unwrap();
+        let mut database = MemoryDatabase::new();
+        database
+            .set_script_pubkey(&address.script_pubkey(), KeychainKind::External, 0)
+            .unwrap();
+        // the headers of the server are never trusted
+        assert!(matches!(
+            blockchain.verify_tx_proof(&txid, height),
+            Err(Error::MissingTrustedHeader(h)) if h == height
+        ));
+        assert!(matches!(
+            blockchain.wallet_setup(&mut database, Box::new(NoopProgress)),
+            Err(Error::MissingTrustedHeader(h)) if h == height
+        ));
+        assert!(database.iter_txs(false).unwrap().is_empty());
+
+        // the headers are taken from the node instead
+        let mut headers = HeaderChain::new();
+        for height in [height - 1, height] {
+            let block_hash = test_client.get_block_hash(height as u64).unwrap();
+            headers.insert(height, test_client.get_block_header(&block_hash).unwrap());
+        }
+        let blockchain = blockchain.with_trusted_headers(Arc::new(RwLock::new(headers)));
+        assert!(blockchain.verify_tx_proof(&txid, height).unwrap());
+        assert!(!blockchain.verify_tx_proof(&txid, height - 1).unwrap());
+
+        blockchain
+            .wallet_setup(&mut database, Box::new(NoopProgress))
+            .unwrap();
+        assert_eq!(database.iter_txs(false).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_electrum_get_tx_confirmations() {
+        let (mut test_client, blockchain) = get_factory();
+        let address = test_client.get_node_address(None);
+        let txid = test_client.receive(testutils! {
+            @tx ( (@addr address) => 50_000 )
+        });
+        assert_eq!(blockchain.get_tx_confirmations(&txid).unwrap(), Some(0));
+
+        test_client.generate(3, None);
+        assert_eq!(blockchain.get_tx_confirmations(&txid).unwrap(), Some(3));
+
+        let unknown = Txid::from_hex(&"ab".repeat(32)).unwrap();
+        assert_eq!(blockchain.get_tx_confirmations(&unknown).unwrap(), None);
+    }
+
+    /// Forward the connections accepted on `listener` to the Electrum server at the address in
+    /// `target`, closing them once the server closes its side
+    fn proxy(listener: TcpListener, target: Arc<Mutex<String>>) {
+        std::thread::spawn(move || {
+            for client in listener.incoming() {
+                let client = client.unwrap();
+                let server = match TcpStream::connect(target.lock().unwrap().as_str()) {
+                    Ok(server) => server,
+                    Err(_) => continue,
+                };
+                let directions = vec![
+                    (client.try_clone().unwrap(), server.try_clone().unwrap()),
+                    (server, client),
+                ];
+                for (mut from, mut to) in directions {
+                    std::thread::spawn(move || {
+                        let _ = std::io::copy(&mut from, &mut to);
+                        let _ = to.shutdown(std::net::Shutdown::Both);
+                    });
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_electrum_reconnect_after_restart() {
+        let mut test_client = TestClient::default();
+        // electrs listens on a new port once restarted, the blockchain connects to a proxy
+        // forwarding to the current one
+        let target = Arc::new(Mutex::new(test_client.electrsd.electrum_url.clone()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("tcp://{}", listener.local_addr().unwrap());
+        proxy(listener, Arc::clone(&target));
+
+        let blockchain = ElectrumBlockchain::from_config(&get_config(&url)).unwrap();
+        let wallet = Wallet::new(
+            "wpkh(tprv8ZgxMBicQKsPd3EupYiPRhaMooHKUHJxNsTfYuScep13go8QFfHdtkG9nRkFGb7busX4isf6X9dURGCoKgitaApQ6MupRhZMcELAxTBRJgS/*)",
+            None,
+            Network::Regtest,
+            MemoryDatabase::new(),
+        )
+        .unwrap();
+
+        let address = wallet.get_address(AddressIndex::New).unwrap().address;
+        test_client.receive(testutils! {
+            @tx ( (@addr address.clone()) => 50_000 )
+        });
+        wallet.sync(&blockchain, Default::default()).unwrap();
+        assert_eq!(wallet.get_balance().unwrap(), 50_000);
+
+        test_client.electrsd.kill().unwrap();
+        let electrs_exe = std::env::var("ELECTRS_EXE")
+            .ok()
+            .or(electrsd::downloaded_exe_path())
+            .unwrap();
+        test_client.electrsd = electrsd::ElectrsD::with_conf(
+            electrs_exe,
+            &test_client.bitcoind,
+            &electrsd::Conf::default(),
+        )
+        .unwrap();
+        *target.lock().unwrap() = test_client.electrsd.electrum_url.clone();
+
+        // the connection to the killed electrs is dropped, the sync reconnects to the new one
+        test_client.receive(testutils! {
+            @tx ( (@addr address) => 30_000 )
+        });
+        wallet.sync(&blockchain, Default::default()).unwrap();
+        assert_eq!(wallet.get_balance().unwrap(), 80_000);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::time::{Duration, Instant};
+
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::consensus::serialize;
+    use bitcoin::hashes::{sha256d, Hash, HashEngine};
+    use bitcoin::TxOut;
+    use electrum_client::ToElectrumScriptHash;
+
+    use super::*;
+    use crate::database::{BatchOperations, MemoryDatabase};
+    use crate::wallet::AddressIndex;
+    use crate::{KeychainKind, Wallet};
+
+    pub(super) const GENESIS_HEADER: &str = "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c";
+
+    pub(super) fn get_config(url: &str) -> ElectrumBlockchainConfig {
+        ElectrumBlockchainConfig {
+            url: url.to_string(),
+            socks5: None,
+            retry: 0,
+            timeout: Some(5),
+            stop_gap: 20,
+            internal_stop_gap: None,
+            sync_error_policy: SyncErrorPolicy::default(),
+            tx_cache_max_entries: None,
+            cert_expiry_warning_days: None,
+            prefetch: false,
+            validate_proofs: false,
+            batch_size: None,
+            concurrency: None,
+            min_fee_rate: None,
+        }
+    }
+
+    /// Bind the listener of a mock server on a free port, returning it with the url to reach it
+    fn tcp_listener() -> (TcpListener, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("tcp://{}", listener.local_addr().unwrap());
+
+        (listener, url)
+    }
+
+    /// Reply to the requests received on `listener` with `responses`, in order
+    fn mock_server(
+        listener: TcpListener,
+        responses: Vec<(&'static str, serde_json::Value)>,
+    ) -> std::thread::JoinHandle<()> {
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+
+            for (method, result) in responses {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let request: serde_json::Value = serde_json::from_str(&line).unwrap();
+                assert_eq!(request["method"], method);
+
+                let response = serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": request["id"],
+                    "result": result,
+                });
+                writer
+                    .write_all(format!("{}\n", response).as_bytes())
+                    .unwrap();
+            }
+        })
+    }
+
+    /// The server closing the connection, like when it restarts, fails the next request instead of
+    /// hanging on it, which is then retried on a new connection
+    #[test]
+    fn test_electrum_reconnect_tcp() {
+        let (listener, url) = tcp_listener();
+        let header = |height: u32| serde_json::json!({ "height": height, "hex": GENESIS_HEADER });
+
+        let blockchain = ElectrumBlockchain::from_config(&get_config(&url)).unwrap();
+        let server = mock_server(
+            listener.try_clone().unwrap(),
+            vec![("blockchain.headers.subscribe", header(42))],
+        );
+        assert_eq!(blockchain.get_height().unwrap(), 42);
+        // the connection is dropped once the server is done
+        server.join().unwrap();
+
+        let server = mock_server(listener, vec![("blockchain.headers.subscribe", header(43))]);
+        assert_eq!(blockchain.get_height().unwrap(), 43);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_is_socket_closed() {
+        let (listener, _) = tcp_listener();
+        let socket = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (peer, _) = listener.accept().unwrap();
+        assert!(!is_socket_closed(&socket));
+
+        drop(peer);
+        let start = Instant::now();
+        while !is_socket_closed(&socket) {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_electrum_server_metadata() {
+        let (listener, url) = tcp_listener();
+
+        let features = serde_json::json!({
+            "server_version": "ElectrumX 1.16.0",
+            "genesis_hash": genesis_block(Network::Regtest).block_hash().to_hex(),
+            "protocol_min": "1.4",
+            "protocol_max": "1.4.2",
+            "hash_function": "sha256",
+            "pruning": null,
+        });
+        let server = mock_server(
+            listener,
+            vec![
+                ("server.banner", "Welcome to the regtest server".into()),
+                ("server.features", features.clone()),
+                (
+                    "server.donation_address",
+                    "bcrt1q3lncdlwq3lgcaaeyruynjnlccr0ve0kakh6ana".into(),
+                ),
+                ("server.features", features.clone()),
+                (
+                    "server.donation_address",
+                    "bc1qxlh2mnc0yqwas76gqq665qkggee5m98t8yskd8".into(),
+                ),
+                ("server.features", features),
+                ("server.donation_address", "".into()),
+            ],
+        );
+
+        let blockchain = ElectrumBlockchain::from_config(&get_config(&url)).unwrap();
+        assert_eq!(
+            blockchain.server_banner().unwrap(),
+            "Welcome to the regtest server"
+        );
+        assert_eq!(
+            blockchain.server_features().unwrap(),
+            ServerFeatures {
+                genesis_hash: genesis_block(Network::Regtest).block_hash(),
+                server_version: "ElectrumX 1.16.0".to_string(),
+                protocol_min: "1.4".to_string(),
+                protocol_max: "1.4.2".to_string(),
+                hash_function: Some("sha256".to_string()),
+                pruning: None,
+            }
+        );
+        assert_eq!(
+            blockchain.server_donation_address().unwrap(),
+            Some(Address::from_str("bcrt1q3lncdlwq3lgcaaeyruynjnlccr0ve0kakh6ana").unwrap())
+        );
+        // a mainnet address on a regtest server
+        assert_eq!(blockchain.server_donation_address().unwrap(), None);
+        // no donation address at all
+        assert_eq!(blockchain.server_donation_address().unwrap(), None);
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_electrum_fee_histogram() {
+        let (listener, url) = tcp_listener();
+        let server = mock_server(
+            listener,
+            vec![(
+                "mempool.get_fee_histogram",
+                serde_json::json!([[53.5, 102_030], [12, 110_000], [3.2, 1_200_000], [1, 4_000]]),
+            )],
+        );
+
+        let blockchain = ElectrumBlockchain::from_config(&get_config(&url)).unwrap();
+        assert!(blockchain
+            .get_capabilities()
+            .contains(&Capability::FeeHistogram));
+        // the protocol doesn't serve full blocks
+        assert!(!blockchain
+            .get_capabilities()
+            .contains(&Capability::GetBlock));
+        let histogram = blockchain.fee_histogram().unwrap();
+        assert_eq!(histogram.len(), 4);
+        assert_eq!(histogram[0], (FeeRate::from_sat_per_vb(53.5), 102_030));
+        assert_eq!(histogram[3], (FeeRate::from_sat_per_vb(1.0), 4_000));
+        assert!(histogram.windows(2).all(|pair| pair[0].0 > pair[1].0));
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_electrum_estimate_fee_unavailable() {
+        let targets = [2, 4, 8, 16, 32, 64, 128, 256, 512, 1008];
+
+        // the estimate for the next target is used
+        let (listener, url) = tcp_listener();
+        let server = mock_server(
+            listener,
+            vec![
+                ("blockchain.estimatefee", (-1).into()),
+                ("blockchain.estimatefee", 0.00002.into()),
+            ],
+        );
+        let blockchain = ElectrumBlockchain::from_config(&get_config(&url)).unwrap();
+        let fee_rate = blockchain.estimate_fee(2).unwrap();
+        assert!((fee_rate.as_sat_vb() - 2.0).abs() < 0.001);
+        server.join().unwrap();
+
+        // no estimate for any target, the minimum fee rate is used
+        let (listener, url) = tcp_listener();
+        let server = mock_server(
+            listener,
+            targets
+                .iter()
+                .map(|_| ("blockchain.estimatefee", (-1).into()))
+                .collect(),
+        );
+        let config = ElectrumBlockchainConfig {
+            min_fee_rate: Some(1.5),
+            ..get_config(&url)
+        };
+        let blockchain = ElectrumBlockchain::from_config(&config).unwrap();
+        assert_eq!(
+            blockchain.estimate_fee(2).unwrap(),
+            FeeRate::from_sat_per_vb(1.5)
+        );
+        server.join().unwrap();
+
+        // without a minimum fee rate the estimation fails
+        let (listener, url) = tcp_listener();
+        let server = mock_server(
+            listener,
+            targets
+                .iter()
+                .map(|_| ("blockchain.estimatefee", 0.into()))
+                .collect(),
+        );
+        let blockchain = ElectrumBlockchain::from_config(&get_config(&url)).unwrap();
+        assert!(matches!(
+            blockchain.estimate_fee(2),
+            Err(Error::FeeRateUnavailable)
+        ));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_electrum_get_network_testnet4() {
+        let (listener, url) = tcp_listener();
+
+        let features = serde_json::json!({
+            "server_version": "ElectrumX 1.16.0",
+            "genesis_hash": TESTNET4_GENESIS_HASH,
+            "protocol_min": "1.4",
+            "protocol_max": "1.4.2",
+            "hash_function": "sha256",
+            "pruning": null,
+        });
+        let server = mock_server(
+            listener,
+            vec![
+                ("server.features", features.clone()),
+                (
+                    "server.donation_address",
+                    "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx".into(),
+                ),
+                ("server.features", features),
+            ],
+        );
+
+        // testnet4 isn't mistaken for testnet3, so the address isn't trusted either
+        let blockchain = ElectrumBlockchain::from_config(&get_config(&url)).unwrap();
+        assert_eq!(blockchain.get_network().unwrap(), None);
+        assert_eq!(blockchain.server_donation_address().unwrap(), None);
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_electrum_raw_call() {
+        let (listener, url) = tcp_listener();
+
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let request: serde_json::Value = serde_json::from_str(&line).unwrap();
+            assert_eq!(request["method"], "server.version");
+            assert_eq!(request["params"], serde_json::json!(["bdk", "1.4"]));
+
+            let response = serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": request["id"],
+                "result": ["ElectrumX 1.16.0", "1.4"],
+            });
+            writer
+                .write_all(format!("{}\n", response).as_bytes())
+                .unwrap();
+        });
+
+        let blockchain = ElectrumBlockchain::from_config(&get_config(&url)).unwrap();
+        let result = blockchain
+            .raw_call("server.version", vec!["bdk".into(), "1.4".into()])
+            .unwrap();
+        let (server_software, protocol_version): (String, String) =
+            serde_json::from_value(result).unwrap();
+        assert_eq!(server_software, "ElectrumX 1.16.0");
+        assert_eq!(protocol_version, "1.4");
+
+        // parameters that can't be sent are rejected before reaching the server
+        assert!(matches!(
+            blockchain.raw_call("server.version", vec![serde_json::Value::Null]),
+            Err(Error::Generic(_))
+        ));
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_electrum_verify_tx_proof_tampered() {
+        let (listener, url) = tcp_listener();
+
+        // the genesis block only contains its coinbase, so the proof is an empty branch
+        let coinbase =
+            Txid::from_hex("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b")
+                .unwrap();
+        let server = mock_server(
+            listener,
+            vec![
+                (
+                    "blockchain.transaction.get_merkle",
+                    serde_json::json!({ "block_height": 0, "pos": 0, "merkle": [] }),
+                ),
+                (
+                    "blockchain.transaction.get_merkle",
+                    serde_json::json!({
+                        "block_height": 0,
+                        "pos": 0,
+                        "merkle": [coinbase.to_hex()],
+                    }),
+                ),
+            ],
+        );
+
+        // only the trusted header is used, the server is never asked for it
+        let mut headers = HeaderChain::new();
+        headers.insert(0, genesis_block(Network::Bitcoin).header);
+        let blockchain = ElectrumBlockchain::from_config(&get_config(&url))
+            .unwrap()
+            .with_trusted_headers(Arc::new(RwLock::new(headers)));
+        assert!(blockchain.verify_tx_proof(&coinbase, 0).unwrap());
+        assert!(!blockchain.verify_tx_proof(&coinbase, 0).unwrap());
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_electrum_get_tx_confirmations() {
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![Default::default()],
+            output: vec![
+                TxOut {
+                    value: 1_000,
+                    script_pubkey: Script::from(vec![0x51]),
+                },
+                TxOut {
+                    value: 2_000,
+                    script_pubkey: Script::from(vec![0x52]),
+                },
+            ],
+        };
+        let txid = tx.txid();
+        let raw_tx = serialize(&tx).to_hex();
+        let (listener, url) = tcp_listener();
+        let server = mock_server(
+            listener,
+            vec![
+                // confirmed at height 100, found in the history of the second output
+                ("blockchain.transaction.get", raw_tx.clone().into()),
+                ("blockchain.scripthash.get_history", serde_json::json!([])),
+                (
+                    "blockchain.scripthash.get_history",
+                    serde_json::json!([{ "tx_hash": txid.to_hex(), "height": 100 }]),
+                ),
+                (
+                    "blockchain.headers.subscribe",
+                    serde_json::json!({ "height": 105, "hex": GENESIS_HEADER }),
+                ),
+                // in the mempool
+                ("blockchain.transaction.get", raw_tx.into()),
+                (
+                    "blockchain.scripthash.get_history",
+                    serde_json::json!([{ "tx_hash": txid.to_hex(), "height": 0, "fee": 200 }]),
+                ),
+            ],
+        );
+
+        let blockchain = ElectrumBlockchain::from_config(&get_config(&url)).unwrap();
+        assert_eq!(blockchain.get_tx_confirmations(&txid).unwrap(), Some(6));
+        assert_eq!(blockchain.get_tx_confirmations(&txid).unwrap(), Some(0));
+        server.join().unwrap();
+
+        // the server replies with an error for unknown transactions
+        let (listener, url) = tcp_listener();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let request: serde_json::Value = serde_json::from_str(&line).unwrap();
+            assert_eq!(request["method"], "blockchain.transaction.get");
+
+            let response = serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": request["id"],
+                "error": { "code": 2, "message": "daemon error: No such mempool or blockchain transaction" },
+            });
+            writer
+                .write_all(format!("{}\n", response).as_bytes())
+                .unwrap();
+        });
+
+        let blockchain = ElectrumBlockchain::from_config(&get_config(&url)).unwrap();
+        assert_eq!(blockchain.get_tx_confirmations(&txid).unwrap(), None);
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_electrum_broadcast_batch() {
+        let tx = |previous_output: OutPoint, value: u64| Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![bitcoin::TxIn {
+                previous_output,
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: Script::from(vec![0x51]),
+            }],
+        };
+        let parent = tx(OutPoint::default(), 20_000);
+        let other = tx(OutPoint::default(), 30_000);
+        let child = tx(OutPoint::new(parent.txid(), 0), 10_000);
+
+        // the child is only sent once the parent and the other transaction were accepted
+        let (listener, url) = tcp_listener();
+        let server = mock_server(
+            listener,
+            vec![
+                (
+                    "blockchain.transaction.broadcast",
+                    parent.txid().to_hex().into(),
+                ),
+                (
+                    "blockchain.transaction.broadcast",
+                    other.txid().to_hex().into(),
+                ),
+                (
+                    "blockchain.transaction.broadcast",
+                    child.txid().to_hex().into(),
+                ),
+            ],
+        );
+
+        let blockchain = ElectrumBlockchain::from_config(&get_config(&url)).unwrap();
+        assert_eq!(
+            blockchain
+                .broadcast_batch(&[parent.clone(), other.clone(), child.clone()])
+                .unwrap(),
+            vec![parent.txid(), other.txid(), child.txid()]
+        );
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_address_matches_network() {
+        let regtest = Address::from_str("bcrt1q3lncdlwq3lgcaaeyruynjnlccr0ve0kakh6ana").unwrap();
+        let mainnet = Address::from_str("bc1qxlh2mnc0yqwas76gqq665qkggee5m98t8yskd8").unwrap();
+        let testnet_p2pkh = Address::from_str("mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn").unwrap();
+
+        assert!(address_matches_network(&regtest, Network::Regtest));
+        assert!(!address_matches_network(&regtest, Network::Testnet));
+        assert!(address_matches_network(&mainnet, Network::Bitcoin));
+        assert!(!address_matches_network(&mainnet, Network::Regtest));
+        assert!(address_matches_network(&testnet_p2pkh, Network::Testnet));
+        assert!(address_matches_network(&testnet_p2pkh, Network::Signet));
+        assert!(address_matches_network(&testnet_p2pkh, Network::Regtest));
+        assert!(!address_matches_network(&testnet_p2pkh, Network::Bitcoin));
+    }
+
+    /// Serve the history of the script pubkeys paid by `txs`, answering every request after
+    /// `latency` like a remote server
+    ///
+    /// The requests of a connection are answered in order, like Electrum servers do, but without
+    /// waiting for the previous answer to be sent.
+    fn latency_server(listener: TcpListener, latency: Duration, txs: Vec<Transaction>) {
+        let txs = Arc::new(txs);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let txs = Arc::clone(&txs);
+                let reader = BufReader::new(stream.try_clone().unwrap());
+                let (sender, receiver) = std::sync::mpsc::channel::<(Instant, serde_json::Value)>();
+
+                std::thread::spawn(move || {
+                    for line in reader.lines() {
+                        let request: serde_json::Value =
+                            serde_json::from_str(&line.unwrap()).unwrap();
+                        let result = latency_server_result(
+                            &txs,
+                            request["method"].as_str().unwrap(),
+                            &request["params"][0],
+                        );
+                        let response = serde_json::json!({
+                            "jsonrpc": "2.0",
+                            "id": request["id"],
+                            "result": result,
+                        });
+
+                        sender.send((Instant::now() + latency, response)).unwrap();
+                    }
+                });
+                std::thread::spawn(move || {
+                    for (deadline, response) in receiver {
+                        std::thread::sleep(deadline.saturating_duration_since(Instant::now()));
+                        if stream
+                            .write_all(format!("{}\n", response).as_bytes())
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    /// Like [`latency_server`], but every connection answers its requests one at a time, even
+    /// when they're sent in a batch
+    fn serial_latency_server(listener: TcpListener, latency: Duration, txs: Vec<Transaction>) {
+        let txs = Arc::new(txs);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let txs = Arc::clone(&txs);
+                std::thread::spawn(move || {
+                    let reader = BufReader::new(stream.try_clone().unwrap());
+                    for line in reader.lines() {
+                        let request: serde_json::Value =
+                            serde_json::from_str(&line.unwrap()).unwrap();
+                        let result = latency_server_result(
+                            &txs,
+                            request["method"].as_str().unwrap(),
+                            &request["params"][0],
+                        );
+                        let response = serde_json::json!({
+                            "jsonrpc": "2.0",
+                            "id": request["id"],
+                            "result": result,
+                        });
+
+                        std::thread::sleep(latency);
+                        stream
+                            .write_all(format!("{}\n", response).as_bytes())
+                            .unwrap();
+                    }
+                });
+            }
+        });
+    }
+
+    fn latency_server_result(
+        txs: &[Transaction],
+        method: &str,
+        param: &serde_json::Value,
+    ) -> serde_json::Value {
+        let param = param.as_str();
+        let scripthash =
+            |tx: &Transaction| tx.output[0].script_pubkey.to_electrum_scripthash().to_hex();
+
+        match method {
+            "blockchain.scripthash.get_history" => txs
+                .iter()
+                .filter(|tx| param == Some(scripthash(tx).as_str()))
+                .map(|tx| serde_json::json!({ "tx_hash": tx.txid(), "height": 1 }))
+                .collect(),
+            "blockchain.transaction.get" => txs
+                .iter()
+                .cloned()
+                .chain(std::iter::once(funding_tx()))
+                .find(|tx| param == Some(tx.txid().to_string().as_str()))
+                .map(|tx| serialize(&tx).to_hex())
+                .into(),
+            "blockchain.block.header" => GENESIS_HEADER.into(),
+            "blockchain.headers.subscribe" => {
+                serde_json::json!({ "height": 1, "hex": GENESIS_HEADER })
+            }
+            method => panic!("unexpected method {}", method),
+        }
+    }
+
+    /// Transaction funding the ones served by the latency servers
+    fn funding_tx() -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![bitcoin::TxIn::default()],
+            output: vec![
+                TxOut {
+                    value: 1_000_000,
+                    script_pubkey: Script::new(),
+                };
+                100
+            ],
+        }
+    }
+
+    /// Input spending the output `vout` of [`funding_tx`], so that the transactions of a test
+    /// don't conflict with each other
+    fn funding_input(vout: u32) -> bitcoin::TxIn {
+        bitcoin::TxIn {
+            previous_output: OutPoint::new(funding_tx().txid(), vout),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_electrum_prefetch() {
+        let script = |keychain: KeychainKind, index: u32| {
+            Script::from(vec![keychain as u8, (index >> 8) as u8, index as u8])
+        };
+        // one address out of five is used, so the scan goes on for several batches
+        let txs = (0..8)
+            .map(|i| Transaction {
+                version: 1,
+                lock_time: i,
+                input: vec![funding_input(i)],
+                output: vec![bitcoin::TxOut {
+                    value: 10_000,
+                    script_pubkey: script(KeychainKind::External, 3 + 5 * i),
+                }],
+            })
+            .collect::<Vec<_>>();
+        let (listener, url) = tcp_listener();
+        latency_server(listener, Duration::from_millis(100), txs.clone());
+
+        let sync = |prefetch: bool| {
+            let mut database = MemoryDatabase::new();
+            for keychain in &[KeychainKind::External, KeychainKind::Internal] {
+                for index in 0..60 {
+                    database
+                        .set_script_pubkey(&script(*keychain, index), *keychain, index)
+                        .unwrap();
+                }
+            }
+            let config = ElectrumBlockchainConfig {
+                stop_gap: 5,
+                prefetch,
+                batch_size: Some(5),
+                ..get_config(&url)
+            };
+            let blockchain = ElectrumBlockchain::from_config(&config).unwrap();
+
+            blockchain
+                .wallet_setup(&mut database, Box::new(NoopProgress))
+                .unwrap();
+
+            let mut txids = database
+                .iter_txs(false)
+                .unwrap()
+                .into_iter()
+                .map(|details| (details.txid, details.received, details.confirmation_time))
+                .collect::<Vec<_>>();
+            txids.sort_by_key(|(txid, _, _)| *txid);
+            (txids, database.iter_utxos().unwrap().len())
+        };
+
+        let (txids, utxos) = sync(false);
+        let (prefetched_txids, prefetched_utxos) = sync(true);
+        assert_eq!(txids.len(), txs.len());
+        assert_eq!(utxos, txs.len());
+        assert_eq!(prefetched_txids, txids);
+        assert_eq!(prefetched_utxos, utxos);
+    }
+
+    #[test]
+    fn test_electrum_batch_size() {
+        let script = |keychain: KeychainKind, index: u32| {
+            Script::from(vec![keychain as u8, (index >> 8) as u8, index as u8])
+        };
+        let txs = (0..4)
+            .map(|i| Transaction {
+                version: 1,
+                lock_time: i,
+                input: vec![funding_input(i)],
+                output: vec![bitcoin::TxOut {
+                    value: 10_000,
+                    script_pubkey: script(KeychainKind::External, 10 * i),
+                }],
+            })
+            .collect::<Vec<_>>();
+        let latency = Duration::from_millis(50);
+        let (listener, url) = tcp_listener();
+        latency_server(listener, latency, txs.clone());
+
+        let mut database = MemoryDatabase::new();
+        for keychain in &[KeychainKind::External, KeychainKind::Internal] {
+            for index in 0..60 {
+                database
+                    .set_script_pubkey(&script(*keychain, index), *keychain, index)
+                    .unwrap();
+            }
+        }
+        // the stop gap is larger than the number of script pubkeys of the wallet
+        let config = ElectrumBlockchainConfig {
+            stop_gap: 1_000,
+            batch_size: Some(10),
+            ..get_config(&url)
+        };
+        let blockchain = ElectrumBlockchain::from_config(&config).unwrap();
+
+        let start = std::time::Instant::now();
+        let stats = blockchain
+            .wallet_setup_with_stats(&mut database, Box::new(NoopProgress))
+            .unwrap();
+        let elapsed = start.elapsed();
+
+        assert_eq!(stats.scripts_scanned, 120);
+        assert_eq!(database.iter_txs(false).unwrap().len(), txs.len());
+        // the history of the 60 script pubkeys of each keychain is fetched in 6 batches, each one
+        // waiting for the latency of the server
+        assert!(elapsed >= latency * 12, "sync took {:?}", elapsed);
+    }
+
+    #[test]
+    fn test_electrum_concurrency() {
+        let script = |keychain: KeychainKind, index: u32| {
+            Script::from(vec![keychain as u8, (index >> 8) as u8, index as u8])
+        };
+        // the first 40 addresses of each keychain are used
+        let txs = [KeychainKind::External, KeychainKind::Internal]
+            .iter()
+            .flat_map(|keychain| (0..40).map(move |index| (*keychain, index)))
+            .enumerate()
+            .map(|(vout, (keychain, index))| Transaction {
+                version: 1,
+                lock_time: index,
+                input: vec![funding_input(vout as u32)],
+                output: vec![bitcoin::TxOut {
+                    value: 1_000 + index as u64,
+                    script_pubkey: script(keychain, index),
+                }],
+            })
+            .collect::<Vec<_>>();
+        let (listener, url) = tcp_listener();
+        serial_latency_server(listener, Duration::from_millis(2), txs.clone());
+
+        let sync = |concurrency: u8| {
+            let mut database = MemoryDatabase::new();
+            for keychain in &[KeychainKind::External, KeychainKind::Internal] {
+                for index in 0..60 {
+                    database
+                        .set_script_pubkey(&script(*keychain, index), *keychain, index)
+                        .unwrap();
+                }
+            }
+            let config = ElectrumBlockchainConfig {
+                stop_gap: 10,
+                batch_size: Some(20),
+                concurrency: Some(concurrency),
+                ..get_config(&url)
+            };
+            let blockchain = ElectrumBlockchain::from_config(&config).unwrap();
+
+            blockchain
+                .wallet_setup(&mut database, Box::new(NoopProgress))
+                .unwrap();
+
+            let mut txs = database
+                .iter_txs(false)
+                .unwrap()
+                .into_iter()
+                .map(|details| (details.txid, details.received, details.confirmation_time))
+                .collect::<Vec<_>>();
+            txs.sort_by_key(|(txid, _, _)| *txid);
+            let mut utxos = database
+                .iter_utxos()
+                .unwrap()
+                .into_iter()
+                .map(|utxo| (utxo.outpoint, utxo.keychain))
+                .collect::<Vec<_>>();
+            utxos.sort_by_key(|(outpoint, _)| *outpoint);
+            let last_indexes = [KeychainKind::External, KeychainKind::Internal]
+                .iter()
+                .map(|keychain| database.get_last_index(*keychain).unwrap())
+                .collect::<Vec<_>>();
+            (txs, utxos, last_indexes)
+        };
+
+        let (txids, utxos, last_indexes) = sync(1);
+        let (parallel_txids, parallel_utxos, parallel_last_indexes) = sync(4);
+        assert_eq!(txids.len(), txs.len());
+        assert_eq!(utxos.len(), txs.len());
+        assert_eq!(last_indexes, vec![Some(39), Some(39)]);
+        // every history is matched to the right script pubkey
+        assert_eq!(parallel_txids, txids);
+        assert_eq!(parallel_utxos, utxos);
+        assert_eq!(parallel_last_indexes, last_indexes);
+    }
+
+    #[test]
+    fn test_is_tx_not_found() {
+        let protocol = electrum_client::Error::Protocol;
+
+        assert!(is_tx_not_found(&protocol(serde_json::json!({
+            "code": 2,
+            "message": "daemon error: DaemonError({'code': -5, 'message': 'No such mempool or blockchain transaction. Use gettransaction for wallet transactions.'})",
+        }))));
+        assert!(is_tx_not_found(&protocol(serde_json::json!(
+            "transaction not found"
+        ))));
+
+        // other errors of the server are not swallowed
+        assert!(!is_tx_not_found(&protocol(serde_json::json!({
+            "code": 2,
+            "message": "daemon error: DaemonError({'code': -28, 'message': 'Loading block index...'})",
+        }))));
+        assert!(!is_tx_not_found(&protocol(serde_json::json!({
+            "code": -32600,
+            "message": "invalid request",
+        }))));
+        assert!(!is_tx_not_found(&electrum_client::Error::IOError(
+            std::io::ErrorKind::TimedOut.into()
+        )));
+    }
+
+    #[test]
+    fn test_is_not_in_block() {
+        let protocol = electrum_client::Error::Protocol;
+
+        assert!(is_not_in_block(&protocol(serde_json::json!({
+            "code": 1,
+            "message": "tx 4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b not in block at height 1",
+        }))));
+        assert!(is_not_in_block(&protocol(serde_json::json!(
+            "tx not found or is unconfirmed"
+        ))));
+
+        // other errors don't mean that the proof is invalid
+        assert!(!is_not_in_block(&protocol(serde_json::json!({
+            "code": 2,
+            "message": "daemon error: DaemonError({'code': -28, 'message': 'Loading block index...'})",
+        }))));
+        assert!(!is_not_in_block(&electrum_client::Error::IOError(
+            std::io::ErrorKind::TimedOut.into()
+        )));
+    }
+
+    fn tx(n: u32) -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: n,
+            input: vec![],
+            output: vec![],
+        }
+    }
+
+    #[test]
+    fn test_lru_txs_evicts_least_recently_used() {
+        let mut cache = LruTxs::new(Some(2));
+        let txs = (0..3).map(tx).collect::<Vec<_>>();
+        let none = HashSet::new();
+
+        cache.insert(txs[0].txid(), txs[0].clone(), &none);
+        cache.insert(txs[1].txid(), txs[1].clone(), &none);
+        assert_eq!(cache.get(&txs[0].txid()), Some(txs[0].clone()));
+
+        cache.insert(txs[2].txid(), txs[2].clone(), &none);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&txs[1].txid()), None);
+        assert_eq!(cache.get(&txs[0].txid()), Some(txs[0].clone()));
+        assert_eq!(cache.get(&txs[2].txid()), Some(txs[2].clone()));
+    }
+
+    #[test]
+    fn test_lru_txs_keeps_in_flight() {
+        let mut cache = LruTxs::new(Some(2));
+        let txs = (0..4).map(tx).collect::<Vec<_>>();
+        let in_flight = txs.iter().map(Transaction::txid).collect::<HashSet<_>>();
+
+        for tx in &txs {
+            cache.insert(tx.txid(), tx.clone(), &in_flight);
+        }
+        assert_eq!(cache.len(), 4);
+        for tx in &txs {
+            assert_eq!(cache.get(&tx.txid()), Some(tx.clone()));
+        }
+
+        // once the batch is done older entries get evicted
+        let next = tx(4);
+        cache.insert(next.txid(), next.clone(), &HashSet::new());
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&txs[3].txid()), Some(txs[3].clone()));
+        assert_eq!(cache.get(&next.txid()), Some(next));
+    }
+
+    #[test]
+    fn test_lru_txs_unbounded() {
+        let mut cache = LruTxs::new(None);
+        for n in 0..100 {
+            cache.insert(tx(n).txid(), tx(n), &HashSet::new());
+        }
+        assert_eq!(cache.len(), 100);
+        assert!(cache.get(&Txid::from_inner([0; 32])).is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "electrum-cert-expiry")]
+    fn test_server_host() {
+        assert_eq!(
+            server_host("electrum.example.com:50002"),
+            "electrum.example.com"
+        );
+        assert_eq!(server_host("127.0.0.1:50002"), "127.0.0.1");
+        assert_eq!(server_host("[::1]:50002"), "::1");
+        assert_eq!(server_host("[::1]"), "::1");
+    }
+
+    /// Self-signed certificate for `localhost`, expiring on 2049-12-31 at 23:59:59 UTC
+    #[cfg(feature = "electrum-cert-expiry")]
+    const SELF_SIGNED_CERT: &str = "3082019230820139a003020102021456b2480b63700e7bc4229c7cba6eba28317dd018300a06082a8648ce3d04030230143112301006035504030c096c6f63616c686f7374301e170d3235303130313030303030305a170d3439313233313233353935395a30143112301006035504030c096c6f63616c686f73743059301306072a8648ce3d020106082a8648ce3d03010703420004b58ae1894b1d86faa139c672e7148807b061e3c831e090237bc8a77c48e5c258c70dec2dc239f794d3b885f514f7f3fe1bfe79236db52080b948afda89aae223a3693067301d0603551d0e04160414466bd348adae2bc7c91950fed21e88c2bc01df0b301f0603551d23041830168014466bd348adae2bc7c91950fed21e88c2bc01df0b300f0603551d130101ff040530030101ff30140603551d11040d300b82096c6f63616c686f7374300a06082a8648ce3d04030203470030440220085ffa99c7104733037187c96d11756e74e9c63b74557821684abbc2a1df946f02201b8f4c7f4b4c4a9f972a7274dbcf1314f90b4f652f96e9a83209d609e567fd56";
+    /// PKCS#8 private key of [`SELF_SIGNED_CERT`]
+    #[cfg(feature = "electrum-cert-expiry")]
+    const SELF_SIGNED_KEY: &str = "308187020100301306072a8648ce3d020106082a8648ce3d030107046d306b0201010420fe3f2a4b5319da2cc28ad14a5816b8f1fee29ff5f17c4e82238c7b1f2288e981a14403420004b58ae1894b1d86faa139c672e7148807b061e3c831e090237bc8a77c48e5c258c70dec2dc239f794d3b885f514f7f3fe1bfe79236db52080b948afda89aae223";
+
+    #[test]
+    #[cfg(feature = "electrum-cert-expiry")]
+    fn test_cert_expiry_self_signed() {
+        use bitcoin::hashes::hex::FromHex;
+
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![rustls::Certificate(
+                    Vec::from_hex(SELF_SIGNED_CERT).unwrap(),
+                )],
+                rustls::PrivateKey(Vec::from_hex(SELF_SIGNED_KEY).unwrap()),
+            )
+            .unwrap();
+        let (listener, _) = tcp_listener();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut connection = rustls::ServerConnection::new(Arc::new(config)).unwrap();
+            while connection.is_handshaking() {
+                if connection.complete_io(&mut stream).is_err() {
+                    break;
+                }
+            }
+        });
+
+        // the certificate isn't signed by a trusted root, but its expiry is still read
+        let expiry = fetch_cert_expiry(&format!("localhost:{}", port), Some(10)).unwrap();
+        assert_eq!(expiry, UNIX_EPOCH + Duration::from_secs(2_524_607_999));
+    }
+
+    /// Set `BDK_ELECTRUM_NEAR_EXPIRY_SERVER` to the `host:port` of an Electrum server with a TLS
+    /// certificate expiring within 30 days to run this test
+    #[test]
+    #[cfg(feature = "electrum-cert-expiry")]
+    #[ignore]
+    fn test_cert_expiry_near_expiry() {
+        let server = std::env::var("BDK_ELECTRUM_NEAR_EXPIRY_SERVER").unwrap();
+        let blockchain = ElectrumBlockchain::from_config(&ElectrumBlockchainConfig {
+            timeout: Some(10),
+            cert_expiry_warning_days: Some(30),
+            ..get_config(&format!("ssl://{}", server))
+        })
+        .unwrap();
+
+        let expiry = blockchain.cert_expiry().unwrap().unwrap();
+        assert!(expiry <= SystemTime::now() + Duration::from_secs(30 * 24 * 60 * 60));
+    }
+
+    fn reversed(hash: &[u8]) -> [u8; 32] {
+        let mut reversed = [0; 32];
+        reversed.copy_from_slice(hash);
+        reversed.reverse();
+        reversed
+    }
+
+    #[test]
+    fn test_electrum_merkle_root() {
+        let left = Txid::hash(b"left");
+        let right = Txid::hash(b"right");
+        let mut engine = sha256d::Hash::engine();
+        engine.input(&left[..]);
+        engine.input(&right[..]);
+        let root = TxMerkleNode::from_hash(sha256d::Hash::from_engine(engine));
+
+        let proof = |pos, sibling: &Txid| GetMerkleRes {
+            block_height: 1,
+            pos,
+            merkle: vec![reversed(&sibling[..])],
+        };
+        assert_eq!(electrum_merkle_root(&left, &proof(0, &right)), Some(root));
+        assert_eq!(electrum_merkle_root(&right, &proof(1, &left)), Some(root));
+
+        // a tampered branch commits to a different root
+        let mut tampered = proof(0, &right);
+        tampered.merkle[0][0] ^= 1;
+        assert_ne!(electrum_merkle_root(&left, &tampered), Some(root));
+        // so does the right branch at the wrong position
+        assert_ne!(electrum_merkle_root(&left, &proof(1, &right)), Some(root));
+        // and a position that doesn't fit in the tree is rejected
+        assert_eq!(electrum_merkle_root(&left, &proof(2, &right)), None);
+    }
+
+    /// Set `BDK_ELECTRUM_TESTNET4_URL` to the url of a testnet4 Electrum server to run this test
+    #[test]
+    #[ignore]
+    fn test_electrum_testnet4() {
+        let url = std::env::var("BDK_ELECTRUM_TESTNET4_URL").unwrap();
+        let blockchain = ElectrumBlockchain::from_config(&ElectrumBlockchainConfig {
+            timeout: Some(10),
+            ..get_config(&url)
+        })
+        .unwrap();
+        let testnet4 = BlockHash::from_hex(TESTNET4_GENESIS_HASH).unwrap();
+        assert_eq!(blockchain.get_block_hash(0).unwrap(), Some(testnet4));
+        assert_eq!(blockchain.get_network().unwrap(), None);
+        assert!(blockchain.get_height().unwrap() > 0);
+
+        let wallet = Wallet::new(
+            "wpkh(tprv8ZgxMBicQKsPd3EupYiPRhaMooHKUHJxNsTfYuScep13go8QFfHdtkG9nRkFGb7busX4isf6X9dURGCoKgitaApQ6MupRhZMcELAxTBRJgS/*)",
+            None,
+            Network::Testnet,
+            MemoryDatabase::new(),
+        )
+        .unwrap();
+        let address = wallet.get_address(AddressIndex::New).unwrap();
+        assert!(address.to_string().starts_with("tb1"));
+        wallet.sync(&blockchain, Default::default()).unwrap();
+    }
+}
+
+#[cfg(all(test, unix))]
+mod unix_test {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixListener;
+
+    use super::test::{get_config, GENESIS_HEADER};
+    use super::*;
+
+    #[test]
+    fn test_electrum_unix_socket() {
+        let path = std::env::temp_dir().join(format!("bdk-electrum-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let request: serde_json::Value = serde_json::from_str(&line).unwrap();
+            assert_eq!(request["method"], "blockchain.headers.subscribe");
+
+            let response = serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": request["id"],
+                "result": { "height": 42, "hex": GENESIS_HEADER },
+            });
+            writer
+                .write_all(format!("{}\n", response).as_bytes())
+                .unwrap();
+        });
+
+        let blockchain =
+            ElectrumBlockchain::from_config(&get_config(&format!("unix://{}", path.display())))
+                .unwrap();
+        assert_eq!(blockchain.get_height().unwrap(), 42);
+
+        server.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// The server closing the connection, like when it restarts, fails the next request instead of
+    /// hanging on it, which is then retried on a new connection
+    #[test]
+    fn test_electrum_reconnect() {
+        let path = std::env::temp_dir().join(format!(
+            "bdk-electrum-reconnect-{}.sock",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let (closed_tx, closed_rx) = std::sync::mpsc::channel();
+
+        let server = std::thread::spawn(move || {
+            for height in &[42, 43] {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut writer = stream;
+
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let request: serde_json::Value = serde_json::from_str(&line).unwrap();
+                assert_eq!(request["method"], "blockchain.headers.subscribe");
+
+                let response = serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": request["id"],
+                    "result": { "height": height, "hex": GENESIS_HEADER },
+                });
+                writer
+                    .write_all(format!("{}\n", response).as_bytes())
+                    .unwrap();
+
+                // drop the connection, like a server restarting
+                writer.shutdown(std::net::Shutdown::Both).unwrap();
+                closed_tx.send(()).unwrap();
+            }
+        });
+
+        let blockchain =
+            ElectrumBlockchain::from_config(&get_config(&format!("unix://{}", path.display())))
+                .unwrap();
+        assert_eq!(blockchain.get_height().unwrap(), 42);
+        closed_rx.recv().unwrap();
+        // the request fails on the dropped connection and is retried on a new one
+        assert_eq!(blockchain.get_height().unwrap(), 43);
+
+        server.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_electrum_unix_socket_missing_path() {
+        let path = std::env::temp_dir().join("bdk-electrum-does-not-exist.sock");
+
+        assert!(matches!(
+            ElectrumBlockchain::from_config(&get_config(&format!("unix://{}", path.display()))),
+            Err(Error::Generic(_))
+        ));
+    }
 }
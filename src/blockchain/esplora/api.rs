@@ -1,8 +1,10 @@
 //! structs from the esplora API
 //!
 //! see: <https://github.com/Blockstream/esplora/blob/master/API.md>
+use std::collections::HashMap;
+
 use crate::BlockTime;
-use bitcoin::{OutPoint, Script, Transaction, TxIn, TxOut, Txid, Witness};
+use bitcoin::{BlockHash, OutPoint, Script, Transaction, TxIn, TxMerkleNode, TxOut, Txid, Witness};
 
 #[derive(serde::Deserialize, Clone, Debug)]
 pub struct PrevOut {
@@ -14,7 +16,7 @@ pub struct PrevOut {
 pub struct Vin {
     pub txid: Txid,
     pub vout: u32,
-    // None if coinbase
+    // None if coinbase, or if the server doesn't include it
     pub prevout: Option<PrevOut>,
     pub scriptsig: Script,
     #[serde(deserialize_with = "deserialize_witness", default)]
@@ -33,6 +35,7 @@ pub struct Vout {
 pub struct TxStatus {
     pub confirmed: bool,
     pub block_height: Option<u32>,
+    pub block_hash: Option<BlockHash>,
     pub block_time: Option<u64>,
 }
 
@@ -45,6 +48,53 @@ pub struct Tx {
     pub vout: Vec<Vout>,
     pub status: TxStatus,
     pub fee: u64,
+    pub weight: u64,
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct MerkleProof {
+    pub block_height: u32,
+    pub merkle: Vec<TxMerkleNode>,
+    pub pos: usize,
+}
+
+/// Response of the `/v1/mining/hashrate/:period` endpoint of mempool.space
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MiningHashrate {
+    pub current_hashrate: f64,
+    pub current_difficulty: f64,
+}
+
+/// Response of the `/mempool` endpoint
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct MempoolInfo {
+    /// `(feerate, vsize)` pairs, by decreasing feerate: each one is the total vsize of the
+    /// transactions paying at least `feerate` sat/vB but less than the feerate of the previous one
+    pub fee_histogram: Vec<(f32, u64)>,
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct OutSpend {
+    pub spent: bool,
+    pub txid: Option<Txid>,
+    pub vin: Option<u32>,
+    pub status: Option<TxStatus>,
+}
+
+impl OutSpend {
+    /// Txid of the spending transaction, if it's unconfirmed
+    pub fn unconfirmed_spend(&self) -> Option<Txid> {
+        match self {
+            OutSpend {
+                spent: true,
+                txid: Some(txid),
+                status,
+                ..
+            } if !status.as_ref().map(|s| s.confirmed).unwrap_or(false) => Some(*txid),
+            _ => None,
+        }
+    }
 }
 
 impl Tx {
@@ -78,26 +128,52 @@ impl Tx {
         }
     }
 
+    pub fn signals_rbf(&self) -> bool {
+        self.vin.iter().any(|vin| vin.sequence < 0xFFFFFFFE)
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.vin.iter().any(|vin| vin.is_coinbase)
+    }
+
+    pub fn vsize(&self) -> u64 {
+        (self.weight + 3) / 4
+    }
+
     pub fn confirmation_time(&self) -> Option<BlockTime> {
         match self.status {
             TxStatus {
                 confirmed: true,
                 block_height: Some(height),
                 block_time: Some(timestamp),
+                ..
             } => Some(BlockTime { timestamp, height }),
             _ => None,
         }
     }
 
-    pub fn previous_outputs(&self) -> Vec<Option<TxOut>> {
+    /// Txids of the transactions whose outputs are spent but not included in the inputs
+    pub fn missing_prevouts(&self) -> impl Iterator<Item = Txid> + '_ {
+        self.vin
+            .iter()
+            .filter(|vin| !vin.is_coinbase && vin.prevout.is_none())
+            .map(|vin| vin.txid)
+    }
+
+    /// Previous outputs spent by the inputs, looking up the ones not included by the server in
+    /// `prevout_txs`
+    pub fn previous_outputs(&self, prevout_txs: &HashMap<Txid, Transaction>) -> Vec<Option<TxOut>> {
         self.vin
             .iter()
             .cloned()
-            .map(|vin| {
-                vin.prevout.map(|po| TxOut {
+            .map(|vin| match vin.prevout {
+                Some(po) => Some(TxOut {
                     script_pubkey: po.scriptpubkey,
                     value: po.value,
-                })
+                }),
+                None => prevout_txs
+                    .get(&vin.txid)
+                    .and_then(|tx| tx.output.get(vin.vout as usize).cloned()),
             })
             .collect()
     }
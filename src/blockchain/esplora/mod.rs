@@ -17,15 +17,25 @@
 //! Please note, to configure the Esplora HTTP client correctly use one of:
 //! Blocking:  --features='esplora,ureq'
 //! Async:     --features='async-interface,esplora,reqwest' --no-default-features
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
 use std::io;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use bitcoin::consensus;
-use bitcoin::{BlockHash, Txid};
+use bitcoin::{
+    Block, BlockHash, BlockHeader, OutPoint, Script, Transaction, TxMerkleNode, TxOut, Txid,
+    Witness,
+};
 
+use crate::blockchain::{merkle_root, OwnershipResolver, SyncErrorPolicy};
+use crate::database::Database;
 use crate::error::Error;
-use crate::FeeRate;
+use crate::{BlockTime, FeeRate};
+
+pub use crate::blockchain::HeaderChain;
 
 #[cfg(feature = "reqwest")]
 mod reqwest;
@@ -41,20 +51,689 @@ pub use self::ureq::*;
 
 mod api;
 
-fn into_fee_rate(target: usize, estimates: HashMap<String, f64>) -> Result<FeeRate, Error> {
-    let fee_val = {
-        let mut pairs = estimates
-            .into_iter()
-            .filter_map(|(k, v)| Some((k.parse::<usize>().ok()?, v)))
-            .collect::<Vec<_>>();
-        pairs.sort_unstable_by_key(|(k, _)| std::cmp::Reverse(*k));
-        pairs
-            .into_iter()
-            .find(|(k, _)| k <= &target)
-            .map(|(_, v)| v)
-            .unwrap_or(1.0)
+/// Parse the fee estimates returned by the server into a map of confirmation targets (blocks) to
+/// fee rates
+fn into_fee_rates(estimates: HashMap<String, f64>) -> BTreeMap<usize, FeeRate> {
+    estimates
+        .into_iter()
+        .filter_map(|(k, v)| Some((k.parse::<usize>().ok()?, FeeRate::from_sat_per_vb(v as f32))))
+        .collect()
+}
+
+/// Estimate the fee rate for `target` from the fee rates of the targets in `estimates`
+///
+/// Without an estimate for `target` itself, the fee rate is linearly interpolated between the
+/// closest targets below and above it, or taken from the closest one if `target` is outside of
+/// the range of `estimates`. Returns [`EsploraError::MissingFeeEstimate`] if `estimates` is
+/// empty.
+fn into_fee_rate(target: usize, estimates: &BTreeMap<usize, FeeRate>) -> Result<FeeRate, Error> {
+    let lower = estimates.range(..=target).next_back();
+    let upper = estimates.range(target..).next();
+    let fee_rate = match (lower, upper) {
+        (Some((&low_target, low)), Some((&high_target, high))) if low_target != high_target => {
+            let ratio = (target - low_target) as f32 / (high_target - low_target) as f32;
+            FeeRate::from_sat_per_vb(low.as_sat_vb() + (high.as_sat_vb() - low.as_sat_vb()) * ratio)
+        }
+        (Some((_, fee_rate)), _) | (None, Some((_, fee_rate))) => *fee_rate,
+        (None, None) => return Err(EsploraError::MissingFeeEstimate.into()),
+    };
+
+    Ok(fee_rate)
+}
+
+/// Fee estimates fetched from the server, along with the time they were fetched at
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+struct CachedFeeEstimates {
+    /// Unix timestamp of the request, in seconds
+    timestamp: u64,
+    estimates: HashMap<String, f64>,
+}
+
+/// Cache of the fee estimates of the server, see [`EsploraBlockchain::with_fee_cache_ttl`]
+///
+/// Clones share the same cache.
+#[derive(Debug, Clone, Default)]
+struct FeeCache {
+    /// How long the estimates are reused for, the cache is disabled when `None`
+    ttl: Option<Duration>,
+    entry: Arc<Mutex<Option<CachedFeeEstimates>>>,
+}
+
+impl FeeCache {
+    fn is_fresh(&self, entry: &CachedFeeEstimates) -> bool {
+        match self.ttl {
+            Some(ttl) => {
+                crate::wallet::time::get_timestamp().saturating_sub(entry.timestamp) < ttl.as_secs()
+            }
+            None => false,
+        }
+    }
+
+    /// Return the cached estimates, unless they are expired
+    fn get(&self) -> Option<HashMap<String, f64>> {
+        self.entry
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|entry| self.is_fresh(entry))
+            .map(|entry| entry.estimates.clone())
+    }
+
+    fn insert(&self, estimates: &HashMap<String, f64>) {
+        if self.ttl.is_some() {
+            *self.entry.lock().unwrap() = Some(CachedFeeEstimates {
+                timestamp: crate::wallet::time::get_timestamp(),
+                estimates: estimates.clone(),
+            });
+        }
+    }
+
+    fn dump(&self) -> Result<Option<String>, Error> {
+        self.entry
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|entry| Ok(serde_json::to_string(entry)?))
+            .transpose()
+    }
+
+    /// Restore estimates returned by [`FeeCache::dump`], ignoring them if they are expired
+    fn load(&self, data: &str) -> Result<(), Error> {
+        let entry: CachedFeeEstimates = serde_json::from_str(data)?;
+        if self.is_fresh(&entry) {
+            *self.entry.lock().unwrap() = Some(entry);
+        } else {
+            log::debug!("Ignoring expired fee estimates from {}", entry.timestamp);
+        }
+
+        Ok(())
+    }
+}
+
+/// Treat as unconfirmed the transactions reported in a block that is no longer part of the best
+/// chain
+///
+/// `best_chain` maps heights to the hash of the block currently at that height, or `None` if the
+/// chain is now shorter than that. Transactions at heights missing from `best_chain` are left
+/// untouched.
+fn unconfirm_reorged_txs<'a, I>(txs: I, best_chain: &HashMap<u32, Option<BlockHash>>)
+where
+    I: IntoIterator<Item = &'a mut api::Tx>,
+{
+    for tx in txs {
+        if let api::TxStatus {
+            confirmed: true,
+            block_height: Some(height),
+            block_hash: Some(hash),
+            ..
+        } = tx.status
+        {
+            if matches!(best_chain.get(&height), Some(best) if *best != Some(hash)) {
+                log::debug!(
+                    "Tx {} was confirmed in block {} which was reorged out, treating it as unconfirmed",
+                    tx.txid,
+                    hash
+                );
+                tx.status = api::TxStatus {
+                    confirmed: false,
+                    block_height: None,
+                    block_hash: None,
+                    block_time: None,
+                };
+            }
+        }
+    }
+}
+
+/// Return the heights of the blocks confirming `txs` that aren't in `best_chain` yet
+fn missing_block_heights<'a, I>(txs: I, best_chain: &HashMap<u32, Option<BlockHash>>) -> Vec<u32>
+where
+    I: IntoIterator<Item = &'a api::Tx>,
+{
+    let mut heights = txs
+        .into_iter()
+        .filter(|tx| tx.status.confirmed && tx.status.block_hash.is_some())
+        .filter_map(|tx| tx.status.block_height)
+        .filter(|height| !best_chain.contains_key(height))
+        .collect::<Vec<_>>();
+    heights.sort_unstable();
+    heights.dedup();
+    heights
+}
+
+/// How [`EsploraBlockchain`] discovers the transactions of the wallet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncMode {
+    /// Query the history of every script pubkey of the wallet (default)
+    ///
+    /// This is the fastest way to sync, but it reveals all the addresses of the wallet to the
+    /// server.
+    AddressQuery,
+    /// Download the blocks mined since `birth_height` and look for the wallet script pubkeys
+    /// locally
+    ///
+    /// The server never learns the addresses of the wallet, at the cost of downloading every
+    /// block in the range. After the first sync only the blocks mined since the previous one are
+    /// scanned again, [`BLOCK_SCAN_CHUNK_SIZE`] blocks at a time. The transactions spent by the
+    /// wallet transactions aren't fetched either unless they belong to the wallet, so the fee of a
+    /// transaction spending the outputs of other wallets is unknown.
+    ///
+    /// Unconfirmed transactions are not discovered in this mode: the ones already in the database
+    /// are kept until they confirm.
+    BlockScan {
+        /// Height of the first block that can contain transactions of the wallet
+        birth_height: u32,
+    },
+}
+
+impl Default for SyncMode {
+    fn default() -> Self {
+        SyncMode::AddressQuery
+    }
+}
+
+/// Implementation of the Esplora API exposed by the server
+///
+/// Some implementations extend the API with additional endpoints, used when available.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EsploraFlavor {
+    /// The reference implementation by Blockstream (default)
+    Esplora,
+    /// The [mempool.space](https://mempool.space) backend, which also exposes mining statistics
+    MempoolSpace,
+}
+
+impl Default for EsploraFlavor {
+    fn default() -> Self {
+        EsploraFlavor::Esplora
+    }
+}
+
+/// Number of blocks scanned between two progress updates in [`SyncMode::BlockScan`]
+pub const BLOCK_SCAN_CHUNK_SIZE: usize = 2016;
+
+/// Number of blocks before the height of the previous sync scanned again in
+/// [`SyncMode::BlockScan`], to pick up the transactions moved by a reorg
+const BLOCK_SCAN_REORG_MARGIN: u32 = 6;
+
+/// Return the heights of the blocks to download in [`SyncMode::BlockScan`]
+fn block_scan_range<D: Database>(
+    database: &D,
+    birth_height: u32,
+    tip_height: u32,
+) -> Result<RangeInclusive<u32>, Error> {
+    let start = match database.get_sync_time()? {
+        Some(sync_time) => std::cmp::max(
+            birth_height,
+            sync_time
+                .block_time
+                .height
+                .saturating_sub(BLOCK_SCAN_REORG_MARGIN),
+        ),
+        None => birth_height,
     };
-    Ok(FeeRate::from_sat_per_vb(fee_val as f32))
+
+    Ok(start..=tip_height)
+}
+
+/// Wallet history discovered by matching the wallet script pubkeys against whole blocks, used to
+/// answer the requests of the sync in [`SyncMode::BlockScan`]
+struct BlockScanner {
+    scripts: HashSet<Script>,
+    ownership_resolver: Option<OwnershipResolver>,
+    /// Outputs owned by the wallet, to recognize the transactions spending them
+    wallet_outputs: HashMap<OutPoint, TxOut>,
+    /// Transactions related to each script pubkey and their confirmation height
+    history: HashMap<Script, BTreeMap<Txid, Option<u32>>>,
+    /// Transactions of the wallet found in the scanned blocks
+    found: HashMap<Txid, (Transaction, BlockTime)>,
+    /// Number of transactions contained in the scanned blocks
+    txs_downloaded: usize,
+}
+
+impl BlockScanner {
+    /// Create a scanner for the blocks starting at `start_height`
+    ///
+    /// The transactions in `database` confirmed before `start_height`, or not confirmed at all,
+    /// are kept in the history since they can't be found in the scanned blocks.
+    fn new<D: Database>(
+        database: &D,
+        start_height: u32,
+        ownership_resolver: Option<OwnershipResolver>,
+    ) -> Result<Self, Error> {
+        let mut scanner = BlockScanner {
+            scripts: database.iter_script_pubkeys(None)?.into_iter().collect(),
+            ownership_resolver,
+            wallet_outputs: HashMap::new(),
+            history: HashMap::new(),
+            found: HashMap::new(),
+            txs_downloaded: 0,
+        };
+
+        let txs = database.iter_txs(true)?;
+        for tx in txs
+            .iter()
+            .filter_map(|details| details.transaction.as_ref())
+        {
+            let txid = tx.txid();
+            for (vout, txout) in tx.output.iter().enumerate() {
+                if scanner.is_mine(&txout.script_pubkey) {
+                    let outpoint = OutPoint::new(txid, vout as u32);
+                    scanner.wallet_outputs.insert(outpoint, txout.clone());
+                }
+            }
+        }
+        for details in &txs {
+            let height = details.confirmation_time.as_ref().map(|time| time.height);
+            if height.map_or(true, |height| height < start_height) {
+                if let Some(tx) = &details.transaction {
+                    scanner.record(tx, height);
+                }
+            }
+        }
+
+        Ok(scanner)
+    }
+
+    fn is_mine(&self, script: &Script) -> bool {
+        self.scripts.contains(script)
+            || self
+                .ownership_resolver
+                .as_ref()
+                .and_then(|resolver| resolver.resolve(script))
+                .is_some()
+    }
+
+    /// Add `tx` to the history of the script pubkeys it pays or spends from, returning whether
+    /// it's related to the wallet at all
+    fn record(&mut self, tx: &Transaction, height: Option<u32>) -> bool {
+        let txid = tx.txid();
+        let mut related = vec![];
+        for input in &tx.input {
+            if let Some(txout) = self.wallet_outputs.get(&input.previous_output) {
+                related.push(txout.script_pubkey.clone());
+            }
+        }
+        for (vout, txout) in tx.output.iter().enumerate() {
+            if self.is_mine(&txout.script_pubkey) {
+                self.wallet_outputs
+                    .insert(OutPoint::new(txid, vout as u32), txout.clone());
+                related.push(txout.script_pubkey.clone());
+            }
+        }
+
+        for script in &related {
+            self.history
+                .entry(script.clone())
+                .or_default()
+                .insert(txid, height);
+        }
+
+        !related.is_empty()
+    }
+
+    /// Look for the transactions of the wallet in `block`, which must be scanned in height order
+    fn scan_block(&mut self, height: u32, block: Block) {
+        let block_time = BlockTime {
+            height,
+            timestamp: block.header.time as u64,
+        };
+        self.txs_downloaded += block.txdata.len();
+        for tx in block.txdata {
+            if self.record(&tx, Some(height)) {
+                self.found.insert(tx.txid(), (tx, block_time.clone()));
+            }
+        }
+    }
+
+    fn history(&self, script: &Script) -> Vec<(Txid, Option<u32>)> {
+        self.history
+            .get(script)
+            .map(|txs| txs.iter().map(|(txid, height)| (*txid, *height)).collect())
+            .unwrap_or_default()
+    }
+
+    fn confirmation_time(&self, txid: &Txid) -> Option<BlockTime> {
+        self.found.get(txid).map(|(_, time)| time.clone())
+    }
+
+    /// Return a transaction found in the scanned blocks along with the outputs it spends
+    ///
+    /// Only the outputs of the wallet are known: fetching the other ones would reveal the
+    /// transactions of the wallet to the server.
+    fn full_tx(&self, txid: &Txid) -> (Vec<Option<TxOut>>, Transaction) {
+        let (tx, _) = self.found.get(txid).expect("must be in index");
+        let previous_outputs = tx
+            .input
+            .iter()
+            .map(|input| {
+                let outpoint = &input.previous_output;
+                if outpoint.is_null() {
+                    return None;
+                }
+                self.wallet_outputs.get(outpoint).cloned()
+            })
+            .collect();
+
+        (previous_outputs, tx.clone())
+    }
+}
+
+/// Maximum number of generations walked by [`EsploraBlockchain::get_mempool_ancestors_descendants`]
+/// in each direction, matching the default package limits of Bitcoin Core
+const MAX_MEMPOOL_RELATIONS_DEPTH: usize = 25;
+
+/// An unconfirmed transaction related to another one, see [`MempoolRelations`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MempoolTx {
+    /// Transaction id
+    pub txid: Txid,
+    /// Fee paid by the transaction (sats)
+    pub fee: u64,
+    /// Virtual size of the transaction (vbytes)
+    pub vsize: u64,
+    /// Number of generations between this transaction and the one that was queried
+    pub depth: usize,
+}
+
+impl MempoolTx {
+    fn new(tx: &api::Tx, depth: usize) -> Self {
+        MempoolTx {
+            txid: tx.txid,
+            fee: tx.fee,
+            vsize: tx.vsize(),
+            depth,
+        }
+    }
+}
+
+/// Unconfirmed ancestors and descendants of a transaction, as returned by
+/// [`EsploraBlockchain::get_mempool_ancestors_descendants`]
+///
+/// This can be used to compute the feerate of the whole package before bumping the fees of a
+/// transaction.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MempoolRelations {
+    /// Unconfirmed transactions spent, directly or indirectly, by the transaction
+    pub ancestors: Vec<MempoolTx>,
+    /// Unconfirmed transactions spending, directly or indirectly, the transaction
+    pub descendants: Vec<MempoolTx>,
+}
+
+/// Spending status of a transaction output, as returned by
+/// [`EsploraBlockchain::get_output_status`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputStatus {
+    /// Whether the output is spent by a transaction known to the server, confirmed or not
+    pub spent: bool,
+    /// Id of the spending transaction
+    pub txid: Option<Txid>,
+    /// Index of the spending input in the spending transaction
+    pub vin: Option<u32>,
+}
+
+impl From<api::OutSpend> for OutputStatus {
+    fn from(outspend: api::OutSpend) -> Self {
+        OutputStatus {
+            spent: outspend.spent,
+            txid: outspend.txid,
+            vin: outspend.vin,
+        }
+    }
+}
+
+/// Record of the confirmation of a transaction, as returned by
+/// [`EsploraBlockchain::confirmation_path`]
+///
+/// It contains everything needed to audit the confirmation independently: the merkle branch can
+/// be checked against the merkle root of `header`, whose hash is `block_hash`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfirmationPath {
+    /// Transaction id
+    pub txid: Txid,
+    /// Hash of the block containing the transaction
+    pub block_hash: BlockHash,
+    /// Height and timestamp of the block containing the transaction
+    pub block_time: BlockTime,
+    /// Header of the block containing the transaction
+    pub header: BlockHeader,
+    /// Number of confirmations of the transaction at the time of the request
+    pub confirmations: u32,
+    /// Merkle branch linking the transaction to the merkle root of the block, from the leaves up
+    pub merkle_branch: Vec<TxMerkleNode>,
+    /// Position of the transaction in the block
+    pub merkle_pos: usize,
+}
+
+impl ConfirmationPath {
+    /// Assemble the path from the replies of the server, checking that they are consistent
+    ///
+    /// Returns [`Error::InvalidMerkleProof`] if `header` isn't the header of `block_hash` or if the
+    /// merkle proof doesn't lead to its merkle root at the height of the block.
+    fn new(
+        txid: Txid,
+        block_hash: BlockHash,
+        block_time: BlockTime,
+        header: BlockHeader,
+        tip_height: u32,
+        merkle_proof: api::MerkleProof,
+    ) -> Result<Self, Error> {
+        if header.block_hash() != block_hash
+            || merkle_proof.block_height != block_time.height
+            || !proof_matches_header(&txid, &merkle_proof, &header)
+        {
+            return Err(Error::InvalidMerkleProof(txid));
+        }
+
+        Ok(ConfirmationPath {
+            txid,
+            block_hash,
+            confirmations: tip_height
+                .checked_sub(block_time.height)
+                .map_or(0, |depth| depth.saturating_add(1)),
+            block_time,
+            header,
+            merkle_branch: merkle_proof.merkle,
+            merkle_pos: merkle_proof.pos,
+        })
+    }
+}
+
+/// Maximum depth of the merkle tree of a block
+///
+/// A block of 4M weight units holds at most 16,666 transactions of 240 weight units, the smallest
+/// possible, which fit in a tree of depth 15.
+const MAX_MERKLE_DEPTH: usize = 15;
+
+/// Whether the merkle proof returned by the server leads from `txid` to the merkle root of
+/// `header`
+///
+/// Proofs deeper than any block tree are rejected, see [`MAX_MERKLE_DEPTH`].
+fn proof_matches_header(txid: &Txid, proof: &api::MerkleProof, header: &BlockHeader) -> bool {
+    proof.merkle.len() <= MAX_MERKLE_DEPTH
+        && merkle_root(txid, &proof.merkle, proof.pos) == Some(header.merkle_root)
+}
+
+/// Check a merkle proof returned by the server against the trusted header at the height it
+/// claims
+fn verify_merkle_proof(
+    txid: &Txid,
+    proof: &api::MerkleProof,
+    headers: &HeaderChain,
+) -> Result<bool, EsploraError> {
+    let header = headers
+        .get(&proof.block_height)
+        .ok_or(EsploraError::HeaderHeightNotFound(proof.block_height))?;
+
+    Ok(proof_matches_header(txid, proof, header))
+}
+
+/// Whether `tx` can be proven in a merkle tree without ambiguity
+///
+/// The merkle tree of a block doesn't tell its leaves apart from its inner nodes, which are the
+/// hash of 64 bytes: a transaction whose serialization without witness is 64 bytes long could be
+/// passed off as an inner node, or the other way around, by a dishonest server.
+fn is_provable(tx: &Transaction) -> bool {
+    let mut stripped = tx.clone();
+    for txin in &mut stripped.input {
+        txin.witness = Witness::default();
+    }
+
+    consensus::serialize(&stripped).len() != 64
+}
+
+/// Group of endpoints of the Esplora API, used to aggregate the latencies of the requests in a
+/// [`LatencyReport`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum EndpointCategory {
+    /// Transactions and their status, merkle proofs and outspends (`/tx/:txid/*`)
+    Transaction,
+    /// Transaction broadcast (`POST /tx`)
+    Broadcast,
+    /// History and UTXOs of script pubkeys and addresses (`/scripthash/*` and `/address/*`)
+    ScriptHistory,
+    /// Blocks, headers and the tip of the chain (`/block/*`, `/block-height/*` and `/blocks/*`)
+    Block,
+    /// Fee estimates (`/fee-estimates`)
+    FeeEstimates,
+    /// Mempool statistics (`/mempool/*`)
+    Mempool,
+    /// Any other endpoint
+    Other,
+}
+
+impl EndpointCategory {
+    /// Category of the endpoint requested at `url`, relative to the `base_url` of the server
+    fn from_request(base_url: &str, method: &str, url: &str) -> Self {
+        let path = url.strip_prefix(base_url).unwrap_or(url);
+        match path.trim_start_matches('/').split('/').next() {
+            Some("tx") if method == "POST" => EndpointCategory::Broadcast,
+            Some("tx") => EndpointCategory::Transaction,
+            Some("scripthash") | Some("address") => EndpointCategory::ScriptHistory,
+            Some("block") | Some("block-height") | Some("blocks") => EndpointCategory::Block,
+            Some("fee-estimates") => EndpointCategory::FeeEstimates,
+            Some("mempool") => EndpointCategory::Mempool,
+            _ => EndpointCategory::Other,
+        }
+    }
+}
+
+/// Percentiles of the latencies of the requests sent to a category of endpoints
+///
+/// The latency of a request is the time until the headers of the response are received, including
+/// the failed requests. Latencies are bucketed with a precision of 1/8, every percentile is the
+/// upper bound of its bucket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EndpointLatency {
+    /// Number of requests sent
+    pub requests: u64,
+    /// Median latency
+    pub p50: Duration,
+    /// 95th percentile of the latencies
+    pub p95: Duration,
+    /// 99th percentile of the latencies
+    pub p99: Duration,
+}
+
+/// Latencies of the requests sent by an [`EsploraBlockchain`] since it was created, as returned by
+/// [`EsploraBlockchain::latency_report`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencyReport {
+    /// Latencies of every category of endpoints that received at least a request
+    pub endpoints: BTreeMap<EndpointCategory, EndpointLatency>,
+}
+
+/// Number of bits of a latency kept by [`LatencyHistogram`] after the most significant one
+const LATENCY_PRECISION_BITS: u32 = 3;
+
+/// Histogram of latencies in microseconds, with buckets of exponentially increasing width
+#[derive(Debug, Clone, Default)]
+struct LatencyHistogram {
+    buckets: BTreeMap<u32, u64>,
+    count: u64,
+}
+
+impl LatencyHistogram {
+    /// Index of the bucket of a latency of `micros`
+    fn bucket(micros: u64) -> u32 {
+        let sub_buckets = 1 << LATENCY_PRECISION_BITS;
+        if micros < sub_buckets {
+            return micros as u32;
+        }
+
+        let exp = 63 - micros.leading_zeros();
+        let sub = (micros >> (exp - LATENCY_PRECISION_BITS)) as u32 & (sub_buckets as u32 - 1);
+        (exp + 1 - LATENCY_PRECISION_BITS) * sub_buckets as u32 + sub
+    }
+
+    /// Smallest latency in microseconds that is larger than every latency of `bucket`
+    fn upper_bound(bucket: u32) -> u64 {
+        let sub_buckets = 1 << LATENCY_PRECISION_BITS;
+        if bucket < sub_buckets {
+            return bucket as u64 + 1;
+        }
+
+        let exp = bucket / sub_buckets + LATENCY_PRECISION_BITS - 1;
+        let sub = (bucket % sub_buckets) as u128;
+        let bound = (sub_buckets as u128 + sub + 1) << (exp - LATENCY_PRECISION_BITS);
+        bound.min(u64::MAX as u128) as u64
+    }
+
+    fn record(&mut self, latency: Duration) {
+        let micros = latency.as_micros().min(u64::MAX as u128) as u64;
+        *self.buckets.entry(Self::bucket(micros)).or_default() += 1;
+        self.count += 1;
+    }
+
+    /// Upper bound of the bucket of the latency at `percentile` (between 0 and 1)
+    fn percentile(&self, percentile: f64) -> Duration {
+        let rank = ((self.count as f64 * percentile).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in &self.buckets {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_micros(Self::upper_bound(*bucket));
+            }
+        }
+        Duration::default()
+    }
+}
+
+/// Latencies of the requests sent by the clones of a client, by category of endpoints
+#[derive(Debug, Default)]
+struct LatencyRecorder(Mutex<BTreeMap<EndpointCategory, LatencyHistogram>>);
+
+impl LatencyRecorder {
+    fn record(&self, category: EndpointCategory, latency: Duration) {
+        self.0
+            .lock()
+            .unwrap()
+            .entry(category)
+            .or_default()
+            .record(latency);
+    }
+
+    fn report(&self) -> LatencyReport {
+        let endpoints = self
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(category, histogram)| {
+                let latency = EndpointLatency {
+                    requests: histogram.count,
+                    p50: histogram.percentile(0.50),
+                    p95: histogram.percentile(0.95),
+                    p99: histogram.percentile(0.99),
+                };
+                (*category, latency)
+            })
+            .collect();
+
+        LatencyReport { endpoints }
+    }
 }
 
 /// Errors that can happen during a sync with [`EsploraBlockchain`]
@@ -70,7 +749,12 @@ pub enum EsploraError {
     #[cfg(feature = "reqwest")]
     Reqwest(::reqwest::Error),
     /// HTTP response error
-    HttpResponse(u16),
+    HttpResponse {
+        /// Status code of the response
+        status: u16,
+        /// Body of the response, truncated to 1 KiB
+        message: String,
+    },
     /// IO error during ureq response read
     Io(io::Error),
     /// No header found in ureq response
@@ -88,11 +772,39 @@ pub enum EsploraError {
     HeaderHeightNotFound(u32),
     /// Header hash not found
     HeaderHashNotFound(BlockHash),
+    /// The response is larger than the configured maximum size (bytes)
+    ResponseTooLarge(usize),
+    /// Invalid name or value of an HTTP header in [`EsploraBlockchainConfig::headers`] (name of
+    /// the header)
+    InvalidHeader(String),
+    /// The server didn't return any fee estimate
+    MissingFeeEstimate,
+    /// A thread sending requests in parallel panicked
+    #[cfg(feature = "ureq")]
+    RequestThreadPanicked,
 }
 
 impl fmt::Display for EsploraError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            EsploraError::HttpResponse { status, message } => {
+                write!(f, "HTTP error {}: {}", status, message)
+            }
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+/// Maximum size of the body kept in [`EsploraError::HttpResponse`], in bytes
+const MAX_ERROR_MESSAGE_BYTES: usize = 1_024;
+
+/// Build an [`EsploraError::HttpResponse`] from the body of the response, truncated to
+/// [`MAX_ERROR_MESSAGE_BYTES`]
+fn http_response_error(status: u16, body: &[u8]) -> EsploraError {
+    let body = &body[..body.len().min(MAX_ERROR_MESSAGE_BYTES)];
+    EsploraError::HttpResponse {
+        status,
+        message: String::from_utf8_lossy(body).into_owned(),
     }
 }
 
@@ -118,11 +830,70 @@ pub struct EsploraBlockchainConfig {
     /// Number of parallel requests sent to the esplora service (default: 4)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub concurrency: Option<u8>,
+    /// Number of parallel requests sent to fetch the transactions spent by the wallet
+    /// transactions, when the server doesn't include the previous outputs in its responses
+    /// (default: same as `concurrency`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prevout_concurrency: Option<u8>,
     /// Stop searching addresses for transactions after finding an unused gap of this length.
     pub stop_gap: usize,
+    /// Stop gap of the internal (change) keychain (default: same as `stop_gap`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub internal_stop_gap: Option<usize>,
     /// Socket timeout.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub timeout: Option<u64>,
+    /// How to handle errors while fetching the history of a single script pubkey
+    #[serde(default)]
+    pub sync_error_policy: SyncErrorPolicy,
+    /// Check that the blocks confirming the wallet transactions are still part of the best chain,
+    /// treating the transactions as unconfirmed otherwise (default: false)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verify_block_hashes: Option<bool>,
+    /// Check the merkle proof of every newly confirmed transaction against the trusted header of
+    /// its block before storing it, see [`EsploraBlockchain::with_validate_proofs`]
+    #[serde(default)]
+    pub validate_proofs: bool,
+    /// Maximum size of the responses read from the server, in bytes
+    ///
+    /// Reading a response larger than this is aborted with [`EsploraError::ResponseTooLarge`]
+    /// instead of buffering it whole.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_response_bytes: Option<usize>,
+    /// How to discover the transactions of the wallet (default: [`SyncMode::AddressQuery`])
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sync_mode: Option<SyncMode>,
+    /// Reuse the fee estimates of the server for this many seconds (default: no caching)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee_cache_ttl: Option<u64>,
+    /// Implementation of the API exposed by the server (default: [`EsploraFlavor::Esplora`])
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flavor: Option<EsploraFlavor>,
+    /// Number of times a request for transactions, script pubkey histories or block hashes is
+    /// retried with exponential backoff after a transport error or a 5xx response (default: 0)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry: Option<u8>,
+    /// Maximum number of requests made by a sync, see
+    /// [`EsploraBlockchain::with_max_requests_per_sync`] (default: no limit)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_requests_per_sync: Option<usize>,
+    /// HTTP headers sent verbatim with every request, like the `Authorization` or `X-Api-Key`
+    /// header required by some providers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub headers: Option<HashMap<String, String>>,
+    /// Number of responses of immutable endpoints cached and revalidated with their `ETag`, see
+    /// `EsploraBlockchain::with_response_cache` (default: no caching)
+    ///
+    /// Only used by the async client built on `reqwest`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_cache_entries: Option<usize>,
+    /// Record the requests sent to the server and their responses, to export them with
+    /// `EsploraBlockchain::export_har` (default: `false`)
+    ///
+    /// Only supported by the blocking client built on `ureq`: the client built on `reqwest`
+    /// fails to be created from a configuration enabling it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub record_har: Option<bool>,
 }
 
 impl EsploraBlockchainConfig {
@@ -133,7 +904,21 @@ impl EsploraBlockchainConfig {
             proxy: None,
             timeout: None,
             stop_gap,
+            internal_stop_gap: None,
             concurrency: None,
+            prevout_concurrency: None,
+            sync_error_policy: SyncErrorPolicy::default(),
+            verify_block_hashes: None,
+            validate_proofs: false,
+            max_response_bytes: None,
+            sync_mode: None,
+            fee_cache_ttl: None,
+            flavor: None,
+            retry: None,
+            max_requests_per_sync: None,
+            headers: None,
+            response_cache_entries: None,
+            record_har: None,
         }
     }
 }
@@ -159,10 +944,174 @@ crate::bdk_blockchain_tests! {
 
 const DEFAULT_CONCURRENT_REQUESTS: u8 = 4;
 
+/// Maximum virtual size of a block (vbytes)
+const MAX_BLOCK_VSIZE: u64 = 1_000_000;
+
+/// Total vsize of the transactions of the mempool paying a higher feerate than a transaction of
+/// `vsize` vbytes paying `fee` sats, given the `fee_histogram` of the mempool
+fn vsize_ahead(fee: u64, vsize: u64, fee_histogram: &[(f32, u64)]) -> u64 {
+    let fee_rate = fee as f32 / vsize.max(1) as f32;
+    fee_histogram
+        .iter()
+        .filter(|(bucket_fee_rate, _)| *bucket_fee_rate > fee_rate)
+        .map(|(_, bucket_vsize)| bucket_vsize)
+        .sum()
+}
+
+/// Number of blocks needed to confirm a transaction of `vsize` vbytes paying `fee` sats, given
+/// the `fee_histogram` of the mempool
+///
+/// Every transaction paying a higher feerate is assumed to be mined first, the transactions that
+/// will enter the mempool in the meantime aren't accounted for.
+fn blocks_to_confirm(fee: u64, vsize: u64, fee_histogram: &[(f32, u64)]) -> usize {
+    let vsize_ahead = vsize_ahead(fee, vsize, fee_histogram);
+
+    ((vsize_ahead + vsize + MAX_BLOCK_VSIZE - 1) / MAX_BLOCK_VSIZE) as usize
+}
+
+/// Delay before the first retry of a failed request, doubled for every following attempt
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(250);
+
+/// Longest delay between two attempts of a request
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Delay before retrying a request that already failed `attempt + 1` times
+fn retry_delay(attempt: u8) -> Duration {
+    (RETRY_BASE_DELAY * 2u32.saturating_pow(attempt.into())).min(RETRY_MAX_DELAY)
+}
+
+/// Whether a request that failed with the HTTP `status` is worth retrying
+///
+/// Only server errors are retried, a 404 in particular is a legitimate "not found" answer.
+fn is_retryable_status(status: u16) -> bool {
+    (500..600).contains(&status)
+}
+
 #[cfg(test)]
 mod test {
+    use bitcoin::hashes::Hash;
+
     use super::*;
 
+    /// Mock Esplora servers shared by the tests of the blocking clients
+    #[cfg(any(feature = "ureq", not(feature = "async-interface")))]
+    pub(super) mod mock {
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        /// Serve a single request streaming `body` without a `Content-Length` header
+        pub(crate) fn mock_server(body: Vec<u8>) -> String {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+
+            std::thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0u8; 1024];
+                let _ = stream.read(&mut request);
+
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n");
+                for chunk in body.chunks(1024) {
+                    // the client hangs up once the limit is exceeded
+                    if stream.write_all(chunk).is_err() {
+                        break;
+                    }
+                }
+            });
+
+            url
+        }
+
+        /// Answer the requests with each of the `errors` statuses and bodies, then serve `body` with a
+        /// 200, counting the requests received
+        pub(crate) fn mock_status_server(
+            errors: Vec<(u16, String)>,
+            body: Vec<u8>,
+        ) -> (String, Arc<AtomicUsize>) {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let requests = Arc::new(AtomicUsize::new(0));
+
+            let count = Arc::clone(&requests);
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    let mut request = [0u8; 1024];
+                    let _ = stream.read(&mut request);
+
+                    let n = count.fetch_add(1, Ordering::SeqCst);
+                    let (status, body) = match errors.get(n) {
+                        Some((status, message)) => (*status, message.as_bytes()),
+                        None => (200, &body[..]),
+                    };
+                    let _ = stream.write_all(
+                        format!(
+                            "HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                            status,
+                            body.len()
+                        )
+                        .as_bytes(),
+                    );
+                    let _ = stream.write_all(body);
+                }
+            });
+
+            (url, requests)
+        }
+    }
+
+    fn confirmed_tx(n: u8, height: u32, block_hash: BlockHash) -> api::Tx {
+        api::Tx {
+            txid: Txid::from_inner([n; 32]),
+            version: 1,
+            locktime: 0,
+            vin: vec![],
+            vout: vec![],
+            status: api::TxStatus {
+                confirmed: true,
+                block_height: Some(height),
+                block_hash: Some(block_hash),
+                block_time: Some(1_600_000_000),
+            },
+            fee: 0,
+            weight: 0,
+        }
+    }
+
+    #[test]
+    fn test_unconfirm_reorged_txs() {
+        let best = BlockHash::from_inner([1; 32]);
+        let stale = BlockHash::from_inner([2; 32]);
+        let mut txs = vec![
+            confirmed_tx(0, 100, best),
+            confirmed_tx(1, 101, stale),
+            confirmed_tx(2, 102, stale),
+            confirmed_tx(3, 103, stale),
+        ];
+
+        let mut best_chain = HashMap::new();
+        assert_eq!(
+            missing_block_heights(&txs, &best_chain),
+            vec![100, 101, 102, 103]
+        );
+        best_chain.insert(100, Some(best));
+        best_chain.insert(101, Some(best));
+        // the chain is now shorter than 102
+        best_chain.insert(102, None);
+        assert_eq!(missing_block_heights(&txs, &best_chain), vec![103]);
+
+        unconfirm_reorged_txs(&mut txs, &best_chain);
+
+        assert!(txs[0].confirmation_time().is_some());
+        assert!(!txs[1].status.confirmed);
+        assert_eq!(txs[1].confirmation_time(), None);
+        assert_eq!(txs[1].status.block_height, None);
+        assert!(!txs[2].status.confirmed);
+        // unknown height, left untouched
+        assert!(txs[3].confirmation_time().is_some());
+    }
+
     #[test]
     fn feerate_parsing() {
         let esplora_fees = serde_json::from_str::<HashMap<String, f64>>(
@@ -199,14 +1148,1200 @@ mod test {
 "#,
         )
         .unwrap();
+        let fee_rates = into_fee_rates(esplora_fees);
         assert_eq!(
-            into_fee_rate(6, esplora_fees.clone()).unwrap(),
+            into_fee_rate(6, &fee_rates).unwrap(),
             FeeRate::from_sat_per_vb(2.236)
         );
+        let fee_rate = into_fee_rate(26, &fee_rates).unwrap();
+        assert!(
+            fee_rate < FeeRate::from_sat_per_vb(1.015) && fee_rate > FeeRate::from_sat_per_vb(1.0),
+            "should be between the values for 25 and 144"
+        );
+
+        assert_eq!(fee_rates.len(), 28);
+        let targets = fee_rates.keys().copied().collect::<Vec<_>>();
+        assert_eq!(&targets[..3], &[1, 2, 3]);
+        assert_eq!(&targets[25..], &[144, 504, 1008]);
+        assert_eq!(fee_rates[&1], FeeRate::from_sat_per_vb(4.983));
+        assert_eq!(fee_rates[&144], FeeRate::from_sat_per_vb(1.0));
+        assert_eq!(
+            into_fee_rate(0, &fee_rates).unwrap(),
+            FeeRate::from_sat_per_vb(4.983),
+            "should be clamped to the value for 1"
+        );
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(0), Duration::from_millis(250));
+        assert_eq!(retry_delay(1), Duration::from_millis(500));
+        assert_eq!(retry_delay(6), Duration::from_secs(16));
+        assert_eq!(retry_delay(7), RETRY_MAX_DELAY);
+        assert_eq!(retry_delay(u8::MAX), RETRY_MAX_DELAY);
+    }
+
+    #[test]
+    fn test_vsize_ahead() {
+        let fee_histogram = vec![
+            (50.0, 400_000),
+            (20.0, 700_000),
+            (10.0, 900_000),
+            (5.0, 2_000_000),
+        ];
+
+        // 250 vbytes at 60 sat/vB, first in line
+        assert_eq!(vsize_ahead(15_000, 250, &fee_histogram), 0);
+        // 250 vbytes at 25 sat/vB
+        assert_eq!(vsize_ahead(6_250, 250, &fee_histogram), 400_000);
+        // 250 vbytes at 20 sat/vB, in the same bucket as the transactions paying as much
+        assert_eq!(vsize_ahead(5_000, 250, &fee_histogram), 400_000);
+        // 250 vbytes at 1 sat/vB, behind everything
+        assert_eq!(vsize_ahead(250, 250, &fee_histogram), 4_000_000);
+        assert_eq!(vsize_ahead(250, 250, &[]), 0);
+    }
+
+    #[test]
+    fn test_blocks_to_confirm() {
+        let fee_histogram = vec![
+            (50.0, 400_000),
+            (20.0, 700_000),
+            (10.0, 900_000),
+            (5.0, 2_000_000),
+        ];
+
+        // 200 vbytes at 60 sat/vB, ahead of everything else
+        assert_eq!(blocks_to_confirm(12_000, 200, &fee_histogram), 1);
+        // 200 vbytes at 15 sat/vB, behind 1.1M vbytes
+        assert_eq!(blocks_to_confirm(3_000, 200, &fee_histogram), 2);
+        // 200 vbytes at 10 sat/vB, paying as much as the next bucket
+        assert_eq!(blocks_to_confirm(2_000, 200, &fee_histogram), 2);
+        // 200 vbytes at 1 sat/vB, behind 4M vbytes
+        assert_eq!(blocks_to_confirm(200, 200, &fee_histogram), 5);
+        assert_eq!(blocks_to_confirm(200, 200, &[]), 1);
+    }
+
+    #[test]
+    fn test_fee_rate_interpolation() {
+        let fee_rates = into_fee_rates(
+            serde_json::from_str::<HashMap<String, f64>>(r#"{"6": 10.0, "12": 4.0}"#).unwrap(),
+        );
+
+        let fee_rate = into_fee_rate(10, &fee_rates).unwrap();
+        assert!(fee_rate < FeeRate::from_sat_per_vb(10.0));
+        assert!(fee_rate > FeeRate::from_sat_per_vb(4.0));
+        assert!((fee_rate.as_sat_vb() - 6.0).abs() < 0.001);
+
+        // clamped outside of the range of the buckets
+        assert_eq!(
+            into_fee_rate(2, &fee_rates).unwrap(),
+            FeeRate::from_sat_per_vb(10.0)
+        );
+        assert_eq!(
+            into_fee_rate(144, &fee_rates).unwrap(),
+            FeeRate::from_sat_per_vb(4.0)
+        );
+    }
+
+    #[test]
+    fn test_fee_rate_missing_estimate() {
+        assert!(matches!(
+            into_fee_rate(6, &BTreeMap::new()),
+            Err(Error::Esplora(e)) if matches!(*e, EsploraError::MissingFeeEstimate)
+        ));
+
+        // a single bucket is enough, even far from the target
+        let fee_rates = into_fee_rates(
+            serde_json::from_str::<HashMap<String, f64>>(r#"{"144": 2.0}"#).unwrap(),
+        );
+        assert_eq!(
+            into_fee_rate(1, &fee_rates).unwrap(),
+            FeeRate::from_sat_per_vb(2.0)
+        );
+    }
+
+    #[test]
+    fn test_verify_merkle_proof() {
+        use bitcoin::hashes::{sha256d, HashEngine};
+
+        fn parent(left: &sha256d::Hash, right: &sha256d::Hash) -> sha256d::Hash {
+            let mut engine = sha256d::Hash::engine();
+            engine.input(&left[..]);
+            engine.input(&right[..]);
+            sha256d::Hash::from_engine(engine)
+        }
+
+        let txids = (0..4u8)
+            .map(|n| Txid::from_inner([n; 32]))
+            .collect::<Vec<_>>();
+        let left = parent(&txids[0].as_hash(), &txids[1].as_hash());
+        let right = parent(&txids[2].as_hash(), &txids[3].as_hash());
+        let root = TxMerkleNode::from_hash(parent(&left, &right));
+
+        let header = BlockHeader {
+            version: 1,
+            prev_blockhash: BlockHash::default(),
+            merkle_root: root,
+            time: 1_600_000_000,
+            bits: 0x207fffff,
+            nonce: 0,
+        };
+        let mut headers = HeaderChain::new();
+        headers.insert(100, header);
+
+        // proof for the third transaction of the block
+        let proof = api::MerkleProof {
+            block_height: 100,
+            merkle: vec![
+                TxMerkleNode::from_hash(txids[3].as_hash()),
+                TxMerkleNode::from_hash(left),
+            ],
+            pos: 2,
+        };
+        assert!(verify_merkle_proof(&txids[2], &proof, &headers).unwrap());
+        // the proof doesn't hold for another transaction or another position
+        assert!(!verify_merkle_proof(&txids[3], &proof, &headers).unwrap());
+        let wrong_pos = api::MerkleProof {
+            pos: 3,
+            ..proof.clone()
+        };
+        assert!(!verify_merkle_proof(&txids[2], &wrong_pos, &headers).unwrap());
+        let out_of_range = api::MerkleProof {
+            pos: 6,
+            ..proof.clone()
+        };
+        assert!(!verify_merkle_proof(&txids[2], &out_of_range, &headers).unwrap());
+        // deeper than any block
+        let too_deep = api::MerkleProof {
+            merkle: vec![TxMerkleNode::from_hash(left); MAX_MERKLE_DEPTH + 1],
+            pos: 0,
+            ..proof.clone()
+        };
+        assert!(!verify_merkle_proof(&txids[2], &too_deep, &headers).unwrap());
+
+        // a tampered header doesn't commit to the transaction anymore
+        headers.get_mut(&100).unwrap().merkle_root = TxMerkleNode::from_hash(left);
+        assert!(!verify_merkle_proof(&txids[2], &proof, &headers).unwrap());
+
+        // the server claims a height the caller doesn't have a header for
+        let unknown_height = api::MerkleProof {
+            block_height: 101,
+            ..proof
+        };
+        assert!(matches!(
+            verify_merkle_proof(&txids[2], &unknown_height, &headers),
+            Err(EsploraError::HeaderHeightNotFound(101))
+        ));
+    }
+
+    #[test]
+    fn test_is_provable() {
+        let tx = |script_len| Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![bitcoin::TxIn {
+                witness: Witness::from_vec(vec![vec![0; 72]]),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 0,
+                script_pubkey: Script::from(vec![0x6a; script_len]),
+            }],
+        };
+
+        // 60 bytes without witness, plus the script of the output
+        assert!(is_provable(&tx(3)));
+        assert!(!is_provable(&tx(4)));
+        assert!(is_provable(&tx(5)));
+    }
+
+    #[test]
+    fn test_confirmation_path_new() {
+        use bitcoin::hashes::{sha256d, HashEngine};
+
+        let txids = [Txid::from_inner([1; 32]), Txid::from_inner([2; 32])];
+        let mut engine = sha256d::Hash::engine();
+        engine.input(&txids[0].as_hash()[..]);
+        engine.input(&txids[1].as_hash()[..]);
+        let header = BlockHeader {
+            version: 1,
+            prev_blockhash: BlockHash::default(),
+            merkle_root: TxMerkleNode::from_hash(sha256d::Hash::from_engine(engine)),
+            time: 1_600_000_000,
+            bits: 0x207fffff,
+            nonce: 0,
+        };
+        let block_time = BlockTime {
+            height: 100,
+            timestamp: 1_600_000_000,
+        };
+        let proof = api::MerkleProof {
+            block_height: 100,
+            merkle: vec![TxMerkleNode::from_hash(txids[1].as_hash())],
+            pos: 0,
+        };
+        let path = |block_hash, tip_height, proof| {
+            ConfirmationPath::new(
+                txids[0],
+                block_hash,
+                block_time.clone(),
+                header,
+                tip_height,
+                proof,
+            )
+        };
+
+        let confirmation_path = path(header.block_hash(), 102, proof.clone()).unwrap();
+        assert_eq!(confirmation_path.confirmations, 3);
+        assert_eq!(confirmation_path.merkle_pos, 0);
+        assert_eq!(
+            path(header.block_hash(), u32::MAX, proof.clone())
+                .unwrap()
+                .confirmations,
+            u32::MAX - 99
+        );
+
+        // the header isn't the one of the block
+        assert!(matches!(
+            path(BlockHash::default(), 102, proof.clone()),
+            Err(Error::InvalidMerkleProof(txid)) if txid == txids[0]
+        ));
+        // the proof is for another height or doesn't lead to the merkle root
+        let other_height = api::MerkleProof {
+            block_height: 101,
+            ..proof.clone()
+        };
+        assert!(path(header.block_hash(), 102, other_height).is_err());
+        let wrong_pos = api::MerkleProof { pos: 1, ..proof };
+        assert!(path(header.block_hash(), 102, wrong_pos).is_err());
+    }
+
+    #[test]
+    fn test_block_scan_range() {
+        use crate::database::{BatchOperations, MemoryDatabase, SyncTime};
+
+        let mut database = MemoryDatabase::new();
+        assert_eq!(block_scan_range(&database, 100, 150).unwrap(), 100..=150);
+        // long ranges are scanned in chunks
+        assert_eq!(
+            block_scan_range(&database, 0, u32::MAX).unwrap(),
+            0..=u32::MAX
+        );
+
+        // after a sync only the blocks mined since then are scanned again, with some margin
+        database
+            .set_sync_time(SyncTime {
+                block_time: BlockTime {
+                    height: 3_000,
+                    timestamp: 0,
+                },
+            })
+            .unwrap();
+        assert_eq!(
+            block_scan_range(&database, 100, 3_010).unwrap(),
+            2_994..=3_010
+        );
+    }
+
+    #[test]
+    fn test_block_scanner() {
+        use bitcoin::{TxIn, TxMerkleNode};
+
+        use crate::database::{BatchOperations, MemoryDatabase};
+        use crate::{KeychainKind, TransactionDetails};
+
+        let mine = Script::from(vec![0xaa; 22]);
+        let other = Script::from(vec![0xbb; 22]);
+        let mut database = MemoryDatabase::new();
+        database
+            .set_script_pubkey(&mine, KeychainKind::External, 0)
+            .unwrap();
+
+        let tx = |lock_time: u32, previous_output: OutPoint, script_pubkey: &Script| Transaction {
+            version: 1,
+            lock_time,
+            input: vec![TxIn {
+                previous_output,
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 10_000,
+                script_pubkey: script_pubkey.clone(),
+            }],
+        };
+        let foreign = OutPoint::new(Txid::from_inner([9; 32]), 0);
+        // history synced before the scanned range is kept
+        let old = tx(0, foreign, &mine);
+        database
+            .set_tx(&TransactionDetails {
+                txid: old.txid(),
+                transaction: Some(old.clone()),
+                confirmation_time: Some(BlockTime {
+                    height: 50,
+                    timestamp: 0,
+                }),
+                ..Default::default()
+            })
+            .unwrap();
+
+        let funding = tx(1, foreign, &mine);
+        let unrelated = tx(2, foreign, &other);
+        let spending = tx(3, OutPoint::new(funding.txid(), 0), &other);
+        let block = Block {
+            header: BlockHeader {
+                version: 1,
+                prev_blockhash: BlockHash::default(),
+                merkle_root: TxMerkleNode::default(),
+                time: 1_600_000_000,
+                bits: 0x207fffff,
+                nonce: 0,
+            },
+            txdata: vec![funding.clone(), unrelated, spending.clone()],
+        };
+
+        let mut scanner = BlockScanner::new(&database, 100, None).unwrap();
+        scanner.scan_block(100, block);
+        assert_eq!(scanner.txs_downloaded, 3);
+
+        let mut history = scanner.history(&mine);
+        history.sort();
+        let mut expected = vec![
+            (old.txid(), Some(50)),
+            (funding.txid(), Some(100)),
+            (spending.txid(), Some(100)),
+        ];
+        expected.sort();
+        assert_eq!(history, expected);
+        assert!(scanner.history(&other).is_empty());
+
+        assert_eq!(
+            scanner.confirmation_time(&spending.txid()),
+            Some(BlockTime {
+                height: 100,
+                timestamp: 1_600_000_000,
+            })
+        );
+        assert_eq!(scanner.confirmation_time(&old.txid()), None);
+
+        // the wallet outputs spent are known, the foreign ones are never fetched
+        let (previous_outputs, _) = scanner.full_tx(&spending.txid());
+        assert_eq!(previous_outputs, vec![Some(funding.output[0].clone())]);
+        let (previous_outputs, _) = scanner.full_tx(&funding.txid());
+        assert_eq!(previous_outputs, vec![None]);
+    }
+
+    #[test]
+    fn test_latency_histogram_buckets() {
+        // exact below 8 microseconds
+        for micros in 0..8 {
+            assert_eq!(LatencyHistogram::bucket(micros), micros as u32);
+            assert_eq!(LatencyHistogram::upper_bound(micros as u32), micros + 1);
+        }
+
+        // every latency is below the upper bound of its bucket and at least the previous one
+        for micros in (8..100_000).chain(vec![u32::MAX as u64, u64::MAX]) {
+            let bucket = LatencyHistogram::bucket(micros);
+            assert!(micros < LatencyHistogram::upper_bound(bucket) || micros == u64::MAX);
+            assert!(micros >= LatencyHistogram::upper_bound(bucket - 1));
+        }
+
+        // buckets are 1/8 wide
+        assert_eq!(LatencyHistogram::bucket(8), 8);
+        assert_eq!(LatencyHistogram::bucket(16), 16);
+        assert_eq!(LatencyHistogram::bucket(18), 17);
+        assert_eq!(
+            LatencyHistogram::upper_bound(LatencyHistogram::bucket(1000)),
+            1024
+        );
+    }
+
+    #[test]
+    fn test_latency_histogram_percentiles() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.percentile(0.5), Duration::default());
+
+        for _ in 0..90 {
+            histogram.record(Duration::from_micros(1000));
+        }
+        for _ in 0..9 {
+            histogram.record(Duration::from_millis(100));
+        }
+        histogram.record(Duration::from_secs(2));
+
+        assert_eq!(histogram.count, 100);
+        assert_eq!(histogram.percentile(0.50), Duration::from_micros(1024));
+        assert_eq!(histogram.percentile(0.95), Duration::from_micros(106_496));
+        assert_eq!(histogram.percentile(0.99), Duration::from_micros(106_496));
+        assert_eq!(histogram.percentile(1.0), Duration::from_micros(2_097_152));
+    }
+
+    #[test]
+    fn test_endpoint_category() {
+        let base = "https://blockstream.info/api";
+        let category = |method, path: &str| {
+            EndpointCategory::from_request(base, method, &format!("{}{}", base, path))
+        };
+
+        assert_eq!(
+            category("GET", "/tx/abcd/raw"),
+            EndpointCategory::Transaction
+        );
+        assert_eq!(
+            category("GET", "/tx/abcd/merkle-proof"),
+            EndpointCategory::Transaction
+        );
+        assert_eq!(category("POST", "/tx"), EndpointCategory::Broadcast);
+        assert_eq!(
+            category("GET", "/scripthash/abcd/txs"),
+            EndpointCategory::ScriptHistory
+        );
+        assert_eq!(
+            category("GET", "/address/bc1q/utxo"),
+            EndpointCategory::ScriptHistory
+        );
+        assert_eq!(
+            category("GET", "/blocks/tip/height"),
+            EndpointCategory::Block
+        );
+        assert_eq!(
+            category("GET", "/block-height/100"),
+            EndpointCategory::Block
+        );
+        assert_eq!(
+            category("GET", "/block/abcd/header"),
+            EndpointCategory::Block
+        );
+        assert_eq!(
+            category("GET", "/fee-estimates"),
+            EndpointCategory::FeeEstimates
+        );
+        assert_eq!(
+            category("GET", "/mempool/recent"),
+            EndpointCategory::Mempool
+        );
+        assert_eq!(
+            category("GET", "/mining/hashrate/1w"),
+            EndpointCategory::Other
+        );
+    }
+
+    #[test]
+    fn test_latency_recorder_report() {
+        let recorder = LatencyRecorder::default();
+        recorder.record(EndpointCategory::Block, Duration::from_micros(10));
+        recorder.record(EndpointCategory::Block, Duration::from_micros(20));
+        recorder.record(EndpointCategory::Broadcast, Duration::from_micros(5));
+
+        let report = recorder.report();
+        assert_eq!(report.endpoints.len(), 2);
+        assert_eq!(
+            report.endpoints[&EndpointCategory::Block],
+            EndpointLatency {
+                requests: 2,
+                p50: Duration::from_micros(11),
+                p95: Duration::from_micros(22),
+                p99: Duration::from_micros(22),
+            }
+        );
+        assert_eq!(report.endpoints[&EndpointCategory::Broadcast].requests, 1);
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "test-esplora")]
+mod esplora_test {
+    use std::sync::RwLock;
+
+    use bitcoin::hashes::hex::FromHex;
+    use bitcoin::Network;
+
+    use super::*;
+    use crate::blockchain::{
+        Blockchain, Capability, GetBlock, GetHeight, NoopProgress, WalletSync,
+    };
+    use crate::database::{BatchOperations, Database, MemoryDatabase};
+    use crate::testutils;
+    use crate::testutils::blockchain_tests::{RpcApi, TestClient};
+    use crate::wallet::AddressIndex;
+    use crate::{KeychainKind, SyncOptions, Wallet};
+
+    fn init_single_sig() -> (
+        Wallet<MemoryDatabase>,
+        EsploraBlockchain,
+        (String, Option<String>),
+        TestClient,
+    ) {
+        let test_client = TestClient::default();
+        let blockchain = EsploraBlockchain::new(
+            &format!(
+                "http://{}",
+                test_client.electrsd.esplora_url.as_ref().unwrap()
+            ),
+            20,
+        );
+        let descriptors = testutils! {
+            @descriptors ( "wpkh(Alice)" ) ( "wpkh(Alice)" ) ( @keys ( "Alice" => (@generate_xprv "/44'/0'/0'/0/*", "/44'/0'/0'/1/*") ) )
+        };
+        let wallet = Wallet::new(
+            &descriptors.0,
+            descriptors.1.as_ref(),
+            Network::Regtest,
+            MemoryDatabase::new(),
+        )
+        .unwrap();
+
+        (wallet, blockchain, descriptors, test_client)
+    }
+
+    #[test]
+    fn test_get_block() {
+        let (_, blockchain, descriptors, mut test_client) = init_single_sig();
+        assert!(blockchain
+            .get_capabilities()
+            .contains(&Capability::GetBlock));
+
+        let txid = test_client.receive(testutils! {
+            @tx ( (@external descriptors, 0) => 50_000 ) ( @confirmations 1 )
+        });
+        let block_hash = test_client.get_best_block_hash().unwrap();
+        let header = test_client.get_block_header(&block_hash).unwrap();
+
+        let block = blockchain.get_block(&block_hash).unwrap().unwrap();
+        assert_eq!(block.block_hash(), block_hash);
+        assert_eq!(block.header.merkle_root, header.merkle_root);
+        assert!(block.check_merkle_root());
+        assert!(block.txdata.iter().any(|tx| tx.txid() == txid));
+
+        let unknown = BlockHash::from_hex(&"00".repeat(32)).unwrap();
+        assert!(blockchain.get_block(&unknown).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_get_tx_fee() {
+        let (wallet, blockchain, descriptors, mut test_client) = init_single_sig();
+        let node_addr = test_client.get_node_address(None);
+
+        test_client.receive(testutils! {
+            @tx ( (@external descriptors, 0) => 50_000 )
+        });
+        wallet.sync(&blockchain, SyncOptions::default()).unwrap();
+
+        let mut builder = wallet.build_tx();
+        builder
+            .add_recipient(node_addr.script_pubkey(), 25_000)
+            .fee_absolute(1_234);
+        let (mut psbt, details) = builder.finish().unwrap();
+        assert!(wallet.sign(&mut psbt, Default::default()).unwrap());
+        blockchain.broadcast(&psbt.extract_tx()).unwrap();
+
+        assert_eq!(blockchain.get_tx_fee(&details.txid).unwrap(), Some(1_234));
+        assert_eq!(
+            blockchain
+                .get_tx_fee(&Txid::from_hex(&"00".repeat(32)).unwrap())
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_find_replaceable_tx() {
+        let (wallet, blockchain, descriptors, mut test_client) = init_single_sig();
+        let node_addr = test_client.get_node_address(None);
+
+        test_client.receive(testutils! {
+            @tx ( (@external descriptors, 0) => 50_000 )
+        });
+        wallet.sync(&blockchain, SyncOptions::default()).unwrap();
+        let utxo = wallet.list_unspent().unwrap()[0].outpoint;
+        assert_eq!(blockchain.find_replaceable_tx(&[utxo]).unwrap(), None);
+
+        let mut builder = wallet.build_tx();
+        builder
+            .add_recipient(node_addr.script_pubkey(), 25_000)
+            .enable_rbf();
+        let (mut psbt, details) = builder.finish().unwrap();
+        assert!(wallet.sign(&mut psbt, Default::default()).unwrap());
+        blockchain.broadcast(&psbt.extract_tx()).unwrap();
+
+        assert_eq!(
+            blockchain.find_replaceable_tx(&[utxo]).unwrap(),
+            Some(details.txid)
+        );
+
+        test_client.generate(1, None);
+        assert_eq!(blockchain.find_replaceable_tx(&[utxo]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_get_output_status() {
+        let (wallet, blockchain, descriptors, mut test_client) = init_single_sig();
+        let node_addr = test_client.get_node_address(None);
+
+        test_client.receive(testutils! {
+            @tx ( (@external descriptors, 0) => 50_000 )
+        });
+        wallet.sync(&blockchain, SyncOptions::default()).unwrap();
+        let utxo = wallet.list_unspent().unwrap()[0].outpoint;
+        assert_eq!(
+            blockchain.get_output_status(&utxo).unwrap(),
+            Some(OutputStatus {
+                spent: false,
+                txid: None,
+                vin: None,
+            })
+        );
+
+        let mut builder = wallet.build_tx();
+        builder.add_recipient(node_addr.script_pubkey(), 25_000);
+        let (mut psbt, details) = builder.finish().unwrap();
+        assert!(wallet.sign(&mut psbt, Default::default()).unwrap());
+        let tx = psbt.extract_tx();
+        blockchain.broadcast(&tx).unwrap();
+
+        let vin = tx
+            .input
+            .iter()
+            .position(|txin| txin.previous_output == utxo)
+            .unwrap() as u32;
+        let expected = Some(OutputStatus {
+            spent: true,
+            txid: Some(details.txid),
+            vin: Some(vin),
+        });
+        assert_eq!(blockchain.get_output_status(&utxo).unwrap(), expected);
+        test_client.generate(1, None);
+        assert_eq!(blockchain.get_output_status(&utxo).unwrap(), expected);
+
+        let missing_vout = OutPoint::new(details.txid, tx.output.len() as u32);
+        assert_eq!(blockchain.get_output_status(&missing_vout).unwrap(), None);
+        let missing_tx = OutPoint::new(Txid::from_hex(&"00".repeat(32)).unwrap(), 0);
+        assert_eq!(blockchain.get_output_status(&missing_tx).unwrap(), None);
+    }
+
+    #[test]
+    fn test_verify_tx_proof() {
+        let (wallet, blockchain, descriptors, mut test_client) = init_single_sig();
+
+        let txid = test_client.receive(testutils! {
+            @tx ( (@external descriptors, 0) => 50_000 )
+        });
+        assert!(!blockchain.verify_tx_proof(&txid, 0).unwrap());
+
+        test_client.generate(1, None);
+        let height = blockchain.get_height().unwrap();
+        // the header isn't trusted yet
+        assert!(matches!(
+            blockchain.verify_tx_proof(&txid, height),
+            Err(Error::MissingTrustedHeader(h)) if h == height
+        ));
+
+        // the headers are taken from the node, not from the Esplora server
+        let headers = Arc::new(RwLock::new(HeaderChain::new()));
+        for height in [height - 1, height] {
+            let block_hash = test_client.get_block_hash(height as u64).unwrap();
+            let header = test_client.get_block_header(&block_hash).unwrap();
+            headers.write().unwrap().insert(height, header);
+        }
+        let blockchain = blockchain.with_trusted_headers(Arc::clone(&headers));
+        assert!(blockchain.verify_tx_proof(&txid, height).unwrap());
+        assert!(!blockchain.verify_tx_proof(&txid, height - 1).unwrap());
+
+        // a header that doesn't commit to the transaction makes the proof invalid
+        headers
+            .write()
+            .unwrap()
+            .get_mut(&height)
+            .unwrap()
+            .merkle_root = TxMerkleNode::from_hash(txid.as_hash());
+        assert!(!blockchain.verify_tx_proof(&txid, height).unwrap());
+        let blockchain = blockchain.with_validate_proofs(true);
+        assert!(matches!(
+            wallet.sync(&blockchain, SyncOptions::default()),
+            Err(Error::InvalidMerkleProof(t)) if t == txid
+        ));
+        assert!(wallet.list_transactions(false).unwrap().is_empty());
+
+        let block_hash = test_client.get_block_hash(height as u64).unwrap();
+        let header = test_client.get_block_header(&block_hash).unwrap();
+        headers.write().unwrap().insert(height, header);
+        wallet.sync(&blockchain, SyncOptions::default()).unwrap();
+        assert_eq!(wallet.list_transactions(false).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_find_notification_txs() {
+        let (wallet, blockchain, descriptors, mut test_client) = init_single_sig();
+        let notification_script = test_client.get_node_address(None).script_pubkey();
+
+        test_client.receive(testutils! {
+            @tx ( (@external descriptors, 0) => 50_000 )
+        });
+        wallet.sync(&blockchain, SyncOptions::default()).unwrap();
+
+        // a plain payment to the notification address isn't a candidate
+        let mut builder = wallet.build_tx();
+        builder.add_recipient(notification_script.clone(), 10_000);
+        let (mut psbt, _) = builder.finish().unwrap();
+        assert!(wallet.sign(&mut psbt, Default::default()).unwrap());
+        blockchain.broadcast(&psbt.extract_tx()).unwrap();
+        wallet.sync(&blockchain, SyncOptions::default()).unwrap();
+
+        let mut builder = wallet.build_tx();
+        builder
+            .add_recipient(notification_script.clone(), 546)
+            .add_data(&[0x01; 80])
+            .unwrap();
+        let (mut psbt, _) = builder.finish().unwrap();
+        assert!(wallet.sign(&mut psbt, Default::default()).unwrap());
+        let notification_tx = psbt.extract_tx();
+        blockchain.broadcast(&notification_tx).unwrap();
+
+        assert_eq!(
+            blockchain
+                .find_notification_txs(&notification_script)
+                .unwrap(),
+            vec![notification_tx]
+        );
+    }
+
+    #[test]
+    fn test_estimate_time_to_confirm() {
+        let (wallet, blockchain, descriptors, mut test_client) = init_single_sig();
+        let node_addr = test_client.get_node_address(None);
+
+        test_client.receive(testutils! {
+            @tx ( (@external descriptors, 0) => 50_000 )
+        });
+        wallet.sync(&blockchain, SyncOptions::default()).unwrap();
+
+        let mut builder = wallet.build_tx();
+        builder
+            .add_recipient(node_addr.script_pubkey(), 25_000)
+            .fee_rate(FeeRate::from_sat_per_vb(10.0));
+        let (mut psbt, details) = builder.finish().unwrap();
+        assert!(wallet.sign(&mut psbt, Default::default()).unwrap());
+        blockchain.broadcast(&psbt.extract_tx()).unwrap();
+
+        // the regtest mempool is nearly empty
+        assert_eq!(
+            blockchain.estimate_time_to_confirm(&details.txid).unwrap(),
+            Some(1)
+        );
+
+        // and nothing pays more than the transaction
+        assert_eq!(
+            blockchain.get_mempool_position(&details.txid).unwrap(),
+            Some(0)
+        );
+
+        test_client.generate(1, None);
+        assert_eq!(
+            blockchain.estimate_time_to_confirm(&details.txid).unwrap(),
+            None
+        );
         assert_eq!(
-            into_fee_rate(26, esplora_fees).unwrap(),
-            FeeRate::from_sat_per_vb(1.015),
-            "should inherit from value for 25"
+            blockchain.get_mempool_position(&details.txid).unwrap(),
+            None
         );
     }
+
+    #[test]
+    fn test_sync_outpoints() {
+        let (wallet, blockchain, descriptors, mut test_client) = init_single_sig();
+        let node_addr = test_client.get_node_address(None);
+
+        test_client.receive(testutils! {
+            @tx ( (@external descriptors, 0) => 50_000 )
+        });
+        test_client.receive(testutils! {
+            @tx ( (@external descriptors, 1) => 30_000 )
+        });
+        wallet.sync(&blockchain, SyncOptions::default()).unwrap();
+        let utxos = wallet.list_unspent().unwrap();
+        assert_eq!(utxos.len(), 2);
+
+        // a database that only knows about the two utxos and is never fully synced
+        let mut database = MemoryDatabase::new();
+        for utxo in &utxos {
+            database.set_utxo(utxo).unwrap();
+        }
+
+        // spend one of them out-of-band
+        let mut builder = wallet.build_tx();
+        builder
+            .add_utxo(utxos[0].outpoint)
+            .unwrap()
+            .manually_selected_only()
+            .drain_to(node_addr.script_pubkey());
+        let (mut psbt, _) = builder.finish().unwrap();
+        assert!(wallet.sign(&mut psbt, Default::default()).unwrap());
+        blockchain.broadcast(&psbt.extract_tx()).unwrap();
+
+        let unknown = OutPoint::new(Txid::from_hex(&"00".repeat(32)).unwrap(), 0);
+        blockchain
+            .sync_outpoints(
+                &mut database,
+                &[utxos[0].outpoint, utxos[1].outpoint, unknown],
+            )
+            .unwrap();
+
+        let utxo = |outpoint| database.get_utxo(&outpoint).unwrap().unwrap();
+        assert!(utxo(utxos[0].outpoint).is_spent);
+        assert!(!utxo(utxos[1].outpoint).is_spent);
+        assert!(database.get_utxo(&unknown).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_refresh_utxo_spent_status() {
+        let (wallet, blockchain, descriptors, mut test_client) = init_single_sig();
+        let node_addr = test_client.get_node_address(None);
+
+        test_client.receive(testutils! {
+            @tx ( (@external descriptors, 0) => 50_000 )
+        });
+        test_client.receive(testutils! {
+            @tx ( (@external descriptors, 1) => 30_000 )
+        });
+        wallet.sync(&blockchain, SyncOptions::default()).unwrap();
+        let utxos = wallet.list_unspent().unwrap();
+        assert_eq!(utxos.len(), 2);
+
+        let mut database = MemoryDatabase::new();
+        for utxo in &utxos {
+            database.set_utxo(utxo).unwrap();
+        }
+        assert!(blockchain
+            .refresh_utxo_spent_status(&mut database)
+            .unwrap()
+            .is_empty());
+
+        // spend one of them out-of-band
+        let mut builder = wallet.build_tx();
+        builder
+            .add_utxo(utxos[0].outpoint)
+            .unwrap()
+            .manually_selected_only()
+            .drain_to(node_addr.script_pubkey());
+        let (mut psbt, _) = builder.finish().unwrap();
+        assert!(wallet.sign(&mut psbt, Default::default()).unwrap());
+        blockchain.broadcast(&psbt.extract_tx()).unwrap();
+
+        assert_eq!(
+            blockchain.refresh_utxo_spent_status(&mut database).unwrap(),
+            vec![utxos[0].outpoint]
+        );
+        assert!(
+            database
+                .get_utxo(&utxos[0].outpoint)
+                .unwrap()
+                .unwrap()
+                .is_spent
+        );
+        assert!(
+            !database
+                .get_utxo(&utxos[1].outpoint)
+                .unwrap()
+                .unwrap()
+                .is_spent
+        );
+        // already marked as spent, not returned again
+        assert!(blockchain
+            .refresh_utxo_spent_status(&mut database)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_find_orphaned_utxos() {
+        let (wallet, blockchain, _, mut test_client) = init_single_sig();
+
+        let address = wallet.get_address(AddressIndex::New).unwrap().address;
+        test_client.generate(1, Some(address.clone()));
+        let coinbase_txid = test_client.get_best_block_hash().unwrap();
+        let coinbase_txid = test_client.get_block(&coinbase_txid).unwrap().txdata[0].txid();
+        test_client.receive(testutils! {
+            @tx ( (@addr address) => 50_000 ) ( @confirmations 1 )
+        });
+        wallet.sync(&blockchain, SyncOptions::default()).unwrap();
+        assert_eq!(wallet.list_unspent().unwrap().len(), 2);
+        assert!(blockchain
+            .find_orphaned_utxos(&*wallet.database())
+            .unwrap()
+            .is_empty());
+
+        // the transaction goes back to the mempool, its output still exists
+        test_client.invalidate(1);
+        assert!(blockchain
+            .find_orphaned_utxos(&*wallet.database())
+            .unwrap()
+            .is_empty());
+
+        // the block paying to the wallet is replaced, and its coinbase with it, while the
+        // transaction is confirmed again at a lower height
+        test_client.invalidate(1);
+        test_client.mine_blocks(3, None);
+        assert_eq!(
+            blockchain.find_orphaned_utxos(&*wallet.database()).unwrap(),
+            vec![OutPoint::new(coinbase_txid, 0)]
+        );
+    }
+
+    #[test]
+    fn test_get_mempool_ancestors_descendants() {
+        let (wallet, blockchain, descriptors, mut test_client) = init_single_sig();
+        let node_addr = test_client.get_node_address(None);
+
+        // build an unconfirmed chain: funding -> spend -> spend of the change
+        let mut txids = vec![test_client.receive(testutils! {
+            @tx ( (@external descriptors, 0) => 50_000 )
+        })];
+        wallet.sync(&blockchain, SyncOptions::default()).unwrap();
+        for _ in 0..2 {
+            let mut builder = wallet.build_tx();
+            builder.add_recipient(node_addr.script_pubkey(), 5_000);
+            let (mut psbt, details) = builder.finish().unwrap();
+            assert!(wallet.sign(&mut psbt, Default::default()).unwrap());
+            blockchain.broadcast(&psbt.extract_tx()).unwrap();
+            wallet.sync(&blockchain, SyncOptions::default()).unwrap();
+            txids.push(details.txid);
+        }
+
+        let relations = blockchain
+            .get_mempool_ancestors_descendants(&txids[1])
+            .unwrap();
+
+        let parent = relations
+            .ancestors
+            .iter()
+            .find(|tx| tx.txid == txids[0])
+            .expect("funding tx is an ancestor");
+        assert_eq!(parent.depth, 1);
+        assert!(parent.fee > 0 && parent.vsize > 0);
+
+        assert_eq!(relations.descendants.len(), 1);
+        assert_eq!(relations.descendants[0].txid, txids[2]);
+        assert_eq!(relations.descendants[0].depth, 1);
+        assert!(relations.descendants[0].fee > 0 && relations.descendants[0].vsize > 0);
+
+        test_client.generate(1, None);
+        let relations = blockchain
+            .get_mempool_ancestors_descendants(&txids[1])
+            .unwrap();
+        assert_eq!(relations, MempoolRelations::default());
+    }
+
+    #[test]
+    fn test_confirmation_path() {
+        let (_, blockchain, descriptors, mut test_client) = init_single_sig();
+
+        let txid = test_client.receive(testutils! {
+            @tx ( (@external descriptors, 0) => 50_000 )
+        });
+        assert_eq!(blockchain.confirmation_path(&txid).unwrap(), None);
+
+        test_client.generate(3, None);
+        let path = blockchain
+            .confirmation_path(&txid)
+            .unwrap()
+            .expect("the transaction is confirmed");
+        assert_eq!(path.txid, txid);
+        assert_eq!(path.confirmations, 3);
+        assert_eq!(path.block_time.height, blockchain.get_height().unwrap() - 2);
+        assert_eq!(path.header.block_hash(), path.block_hash);
+        assert_eq!(path.header.time as u64, path.block_time.timestamp);
+
+        // the merkle branch leads from the transaction to the merkle root of the header
+        assert_eq!(
+            merkle_root(&txid, &path.merkle_branch, path.merkle_pos),
+            Some(path.header.merkle_root)
+        );
+
+        assert_eq!(
+            blockchain
+                .confirmation_path(&Txid::from_hex(&"00".repeat(32)).unwrap())
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_verify_tx_against_headers() {
+        let (_, blockchain, descriptors, mut test_client) = init_single_sig();
+
+        let txid = test_client.receive(testutils! {
+            @tx ( (@external descriptors, 0) => 50_000 )
+        });
+        let mut headers = HeaderChain::new();
+        assert!(!blockchain
+            .verify_tx_against_headers(&txid, &headers)
+            .unwrap());
+
+        test_client.generate(1, None);
+        let height = blockchain.get_height().unwrap();
+        let block_hash = test_client.get_block_hash(height as u64).unwrap();
+        headers.insert(height, test_client.get_block_header(&block_hash).unwrap());
+        assert!(blockchain
+            .verify_tx_against_headers(&txid, &headers)
+            .unwrap());
+
+        // a header that doesn't commit to the transaction makes the verification fail
+        let mut tampered = headers.clone();
+        tampered.get_mut(&height).unwrap().merkle_root = TxMerkleNode::from_hash(txid.as_hash());
+        assert!(!blockchain
+            .verify_tx_against_headers(&txid, &tampered)
+            .unwrap());
+
+        headers.clear();
+        assert!(blockchain
+            .verify_tx_against_headers(&txid, &headers)
+            .is_err());
+    }
+
+    #[test]
+    fn test_wallet_setup_with_stats() {
+        let (wallet, blockchain, descriptors, mut test_client) = init_single_sig();
+
+        test_client.receive(testutils! {
+            @tx ( (@external descriptors, 0) => 50_000 )
+        });
+        test_client.generate(1, None);
+
+        let mut database = MemoryDatabase::new();
+        let script = wallet
+            .get_address(AddressIndex::Peek(0))
+            .unwrap()
+            .script_pubkey();
+        database
+            .set_script_pubkey(&script, KeychainKind::External, 0)
+            .unwrap();
+        let stats = blockchain
+            .wallet_setup_with_stats(&mut database, Box::new(NoopProgress))
+            .unwrap();
+
+        // the server includes the previous outputs, no need to fetch the parent transactions
+        assert_eq!(stats.scripts_scanned, 1);
+        assert_eq!(stats.txs_fetched, 1);
+        // the proofs aren't validated by default, no header is needed
+        assert_eq!(stats.headers_fetched, 0);
+        assert!(stats.bytes_transferred.unwrap() > 0);
+        assert_eq!(database.iter_txs(false).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_check_proofs_header_cache() {
+        let (wallet, blockchain, descriptors, mut test_client) = init_single_sig();
+
+        // three transactions confirmed in the same block
+        for index in 0..3 {
+            test_client.receive(testutils! {
+                @tx ( (@external descriptors, index) => 50_000 )
+            });
+        }
+        test_client.generate(1, None);
+        let height = blockchain.get_height().unwrap();
+        let block_hash = test_client.get_block_hash(height as u64).unwrap();
+        let mut headers = HeaderChain::new();
+        headers.insert(height, test_client.get_block_header(&block_hash).unwrap());
+        let blockchain = blockchain
+            .with_validate_proofs(true)
+            .with_trusted_headers(Arc::new(RwLock::new(headers)));
+
+        let mut database = MemoryDatabase::new();
+        for index in 0..3 {
+            let script = wallet
+                .get_address(AddressIndex::Peek(index))
+                .unwrap()
+                .script_pubkey();
+            database
+                .set_script_pubkey(&script, KeychainKind::External, index)
+                .unwrap();
+        }
+        let stats = blockchain
+            .wallet_setup_with_stats(&mut database, Box::new(NoopProgress))
+            .unwrap();
+
+        assert_eq!(database.iter_txs(false).unwrap().len(), 3);
+        // the proofs are checked against the trusted headers
+        assert_eq!(stats.headers_fetched, 0);
+    }
+
+    #[test]
+    fn test_verify_descriptor_has_funds() {
+        let (wallet, blockchain, descriptors, mut test_client) = init_single_sig();
+        let descriptor = wallet
+            .get_descriptor_for_keychain(KeychainKind::External)
+            .clone();
+        assert!(!blockchain
+            .verify_descriptor_has_funds(&descriptor, Network::Regtest, 20)
+            .unwrap());
+
+        test_client.receive(testutils! {
+            @tx ( (@external descriptors, 3) => 50_000 )
+        });
+        assert!(blockchain
+            .verify_descriptor_has_funds(&descriptor, Network::Regtest, 20)
+            .unwrap());
+        // the funded address is past the ones checked
+        assert!(!blockchain
+            .verify_descriptor_has_funds(&descriptor, Network::Regtest, 3)
+            .unwrap());
+        // testnet keys aren't valid on mainnet
+        assert!(blockchain
+            .verify_descriptor_has_funds(&descriptor, Network::Bitcoin, 20)
+            .is_err());
+    }
+
+    #[test]
+    fn test_sync_block_scan() {
+        let (wallet, blockchain, descriptors, mut test_client) = init_single_sig();
+        let birth_height = blockchain.get_height().unwrap() + 1;
+        let node_addr = test_client.get_node_address(None);
+
+        test_client.receive(testutils! {
+            @tx ( (@external descriptors, 0) => 50_000, (@external descriptors, 3) => 30_000 )
+        });
+        test_client.generate(1, None);
+        wallet.sync(&blockchain, SyncOptions::default()).unwrap();
+
+        let mut builder = wallet.build_tx();
+        builder.add_recipient(node_addr.script_pubkey(), 60_000);
+        let (mut psbt, _) = builder.finish().unwrap();
+        assert!(wallet.sign(&mut psbt, Default::default()).unwrap());
+        blockchain.broadcast(&psbt.extract_tx()).unwrap();
+        test_client.generate(2, None);
+        wallet.sync(&blockchain, SyncOptions::default()).unwrap();
+
+        let scanning = Wallet::new(
+            &descriptors.0,
+            descriptors.1.as_ref(),
+            Network::Regtest,
+            MemoryDatabase::new(),
+        )
+        .unwrap();
+        let block_scan = EsploraBlockchain::new(
+            &format!(
+                "http://{}",
+                test_client.electrsd.esplora_url.as_ref().unwrap()
+            ),
+            20,
+        )
+        .with_sync_mode(SyncMode::BlockScan { birth_height });
+        scanning.sync(&block_scan, SyncOptions::default()).unwrap();
+
+        let mut expected = wallet.list_transactions(false).unwrap();
+        let mut found = scanning.list_transactions(false).unwrap();
+        expected.sort_by_key(|details| details.txid);
+        found.sort_by_key(|details| details.txid);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found, expected);
+        assert_eq!(
+            scanning.get_balance().unwrap(),
+            wallet.get_balance().unwrap()
+        );
+
+        // the next sync only scans the new blocks and keeps the older history
+        test_client.generate(1, None);
+        scanning.sync(&block_scan, SyncOptions::default()).unwrap();
+        assert_eq!(scanning.list_transactions(false).unwrap().len(), 2);
+    }
 }
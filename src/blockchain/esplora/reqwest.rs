@@ -11,43 +11,120 @@
 
 //! Esplora by way of `reqwest` HTTP client.
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
 use bitcoin::consensus::{deserialize, serialize};
 use bitcoin::hashes::hex::{FromHex, ToHex};
 use bitcoin::hashes::{sha256, Hash};
-use bitcoin::{BlockHeader, Script, Transaction, Txid};
+use bitcoin::{Block, BlockHash, BlockHeader, Network, OutPoint, Script, Transaction, Txid};
 
 #[allow(unused_imports)]
 use log::{debug, error, info, trace};
 
-use ::reqwest::{Client, StatusCode};
-use futures::stream::{FuturesOrdered, TryStreamExt};
+use ::reqwest::header::{HeaderMap, HeaderName, HeaderValue, ETAG, IF_NONE_MATCH};
+use ::reqwest::{Client, Response, StatusCode};
+use futures::stream::{FuturesOrdered, StreamExt};
+use serde::de::DeserializeOwned;
 
-use super::api::Tx;
+use super::api::{MempoolInfo, MerkleProof, MiningHashrate, OutSpend, Tx, TxStatus};
+use super::{
+    block_scan_range, http_response_error, is_provable, is_retryable_status, proof_matches_header,
+    verify_merkle_proof, BlockScanner, ConfirmationPath, EndpointCategory, EsploraFlavor, FeeCache,
+    HeaderChain, LatencyRecorder, LatencyReport, MempoolRelations, MempoolTx, OutputStatus,
+    SyncMode, BLOCK_SCAN_CHUNK_SIZE, MAX_ERROR_MESSAGE_BYTES, MAX_MEMPOOL_RELATIONS_DEPTH,
+};
 use crate::blockchain::esplora::EsploraError;
 use crate::blockchain::*;
-use crate::database::BatchDatabase;
+use crate::database::{BatchDatabase, BatchOperations};
+use crate::descriptor::ExtendedDescriptor;
 use crate::error::Error;
-use crate::FeeRate;
+use crate::wallet::time::Instant;
+use crate::{BlockTime, FeeRate};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct UrlClient {
     url: String,
     // We use the async client instead of the blocking one because it automatically uses `fetch`
     // when the target platform is wasm32.
     client: Client,
     concurrency: u8,
+    prevout_concurrency: Option<u8>,
+    max_response_bytes: Option<usize>,
+    retry: u8,
+    bytes_received: Arc<AtomicU64>,
+    requests_sent: Arc<AtomicUsize>,
+    latencies: Arc<LatencyRecorder>,
+    response_cache: Option<Arc<ResponseCache>>,
+    rate_limiter: Option<RateLimiter>,
+}
+
+/// Bodies of the responses of immutable endpoints, along with the `ETag` used to revalidate them
+#[derive(Debug)]
+struct ResponseCache {
+    max_entries: usize,
+    entries: Mutex<CachedResponses>,
+}
+
+#[derive(Debug, Default)]
+struct CachedResponses {
+    /// `ETag` and body of the response, by url
+    bodies: HashMap<String, (String, Vec<u8>)>,
+    /// Urls in insertion order, the oldest ones are evicted first
+    order: VecDeque<String>,
+}
+
+impl ResponseCache {
+    fn new(max_entries: usize) -> Self {
+        ResponseCache {
+            max_entries,
+            entries: Mutex::new(CachedResponses::default()),
+        }
+    }
+
+    fn get(&self, url: &str) -> Option<(String, Vec<u8>)> {
+        self.entries.lock().unwrap().bodies.get(url).cloned()
+    }
+
+    fn insert(&self, url: &str, etag: String, body: Vec<u8>) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries
+            .bodies
+            .insert(url.to_string(), (etag, body))
+            .is_none()
+        {
+            entries.order.push_back(url.to_string());
+        }
+        while entries.order.len() > self.max_entries {
+            if let Some(oldest) = entries.order.pop_front() {
+                entries.bodies.remove(&oldest);
+            }
+        }
+    }
 }
 
 /// Structure that implements the logic to sync with Esplora
 ///
 /// ## Example
 /// See the [`blockchain::esplora`](crate::blockchain::esplora) module for a usage example.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct EsploraBlockchain {
     url_client: UrlClient,
     stop_gap: usize,
+    internal_stop_gap: Option<usize>,
+    sync_error_policy: SyncErrorPolicy,
+    verify_block_hashes: bool,
+    validate_proofs: bool,
+    trusted_headers: Option<Arc<RwLock<HeaderChain>>>,
+    ownership_resolver: Option<OwnershipResolver>,
+    sync_mode: SyncMode,
+    fee_cache: FeeCache,
+    flavor: EsploraFlavor,
+    max_requests_per_sync: Option<usize>,
+    shared_tx_cache: Option<SharedTxCache>,
 }
 
 impl std::convert::From<UrlClient> for EsploraBlockchain {
@@ -55,6 +132,17 @@ impl std::convert::From<UrlClient> for EsploraBlockchain {
         EsploraBlockchain {
             url_client,
             stop_gap: 20,
+            internal_stop_gap: None,
+            sync_error_policy: SyncErrorPolicy::default(),
+            verify_block_hashes: false,
+            validate_proofs: false,
+            trusted_headers: None,
+            ownership_resolver: None,
+            sync_mode: SyncMode::default(),
+            fee_cache: FeeCache::default(),
+            flavor: EsploraFlavor::default(),
+            max_requests_per_sync: None,
+            shared_tx_cache: None,
         }
     }
 }
@@ -67,8 +155,27 @@ impl EsploraBlockchain {
                 url: base_url.to_string(),
                 client: Client::new(),
                 concurrency: super::DEFAULT_CONCURRENT_REQUESTS,
+                prevout_concurrency: None,
+                max_response_bytes: None,
+                retry: 0,
+                bytes_received: Arc::new(AtomicU64::new(0)),
+                requests_sent: Arc::new(AtomicUsize::new(0)),
+                latencies: Arc::new(LatencyRecorder::default()),
+                response_cache: None,
+                rate_limiter: None,
             },
             stop_gap,
+            internal_stop_gap: None,
+            sync_error_policy: SyncErrorPolicy::default(),
+            verify_block_hashes: false,
+            validate_proofs: false,
+            trusted_headers: None,
+            ownership_resolver: None,
+            sync_mode: SyncMode::default(),
+            fee_cache: FeeCache::default(),
+            flavor: EsploraFlavor::default(),
+            max_requests_per_sync: None,
+            shared_tx_cache: None,
         }
     }
 
@@ -77,206 +184,1360 @@ impl EsploraBlockchain {
         self.url_client.concurrency = concurrency;
         self
     }
-}
 
-#[maybe_async]
-impl Blockchain for EsploraBlockchain {
-    fn get_capabilities(&self) -> HashSet<Capability> {
-        vec![
-            Capability::FullHistory,
-            Capability::GetAnyTx,
-            Capability::AccurateFees,
-        ]
-        .into_iter()
-        .collect()
+    /// Set the number of parallel requests made to fetch the transactions spent by the wallet
+    /// transactions, when the server doesn't include the previous outputs in its responses
+    ///
+    /// Defaults to the concurrency set with [`EsploraBlockchain::with_concurrency`].
+    pub fn with_prevout_concurrency(mut self, prevout_concurrency: u8) -> Self {
+        self.url_client.prevout_concurrency = Some(prevout_concurrency);
+        self
     }
 
-    fn broadcast(&self, tx: &Transaction) -> Result<(), Error> {
-        Ok(await_or_block!(self.url_client._broadcast(tx))?)
+    /// Set the maximum size of the responses read from the server, in bytes
+    ///
+    /// Reading a response larger than this is aborted with [`EsploraError::ResponseTooLarge`].
+    pub fn with_max_response_bytes(mut self, max_response_bytes: usize) -> Self {
+        self.url_client.max_response_bytes = Some(max_response_bytes);
+        self
     }
 
-    fn estimate_fee(&self, target: usize) -> Result<FeeRate, Error> {
-        let estimates = await_or_block!(self.url_client._get_fee_estimates())?;
-        super::into_fee_rate(target, estimates)
+    /// Set how many times a request for transactions, script pubkey histories or block hashes is
+    /// retried after a transport error or a 5xx response (default: 0)
+    ///
+    /// The delay between attempts starts at 250ms and doubles after every failure, up to 30s.
+    pub fn with_retry(mut self, retry: u8) -> Self {
+        self.url_client.retry = retry;
+        self
     }
-}
 
-impl StatelessBlockchain for EsploraBlockchain {}
+    /// Cache up to `max_entries` responses of immutable endpoints: raw transactions, blocks and
+    /// block headers
+    ///
+    /// A cached response is revalidated with the `If-None-Match` header carrying its `ETag`, the
+    /// body is only downloaded again if the server doesn't reply with `304 Not Modified`.
+    /// Responses without an `ETag` aren't cached. The oldest responses are evicted first.
+    pub fn with_response_cache(mut self, max_entries: usize) -> Self {
+        self.url_client.response_cache = Some(Arc::new(ResponseCache::new(max_entries)));
+        self
+    }
 
-#[maybe_async]
-impl GetHeight for EsploraBlockchain {
-    fn get_height(&self) -> Result<u32, Error> {
-        Ok(await_or_block!(self.url_client._get_height())?)
+    /// Set the maximum number of requests made by a sync
+    ///
+    /// Once the limit is reached no other script pubkey is queried, and the sync completes the
+    /// transactions found so far without deleting any existing one. This is reported by
+    /// [`SyncStats::request_budget_exhausted`]. The requests needed to complete those transactions
+    /// and to store the hashes of the blocks confirming them are still made, so the limit can be
+    /// slightly exceeded. It doesn't apply to [`SyncMode::BlockScan`].
+    pub fn with_max_requests_per_sync(mut self, max_requests_per_sync: usize) -> Self {
+        self.max_requests_per_sync = Some(max_requests_per_sync);
+        self
     }
-}
 
-#[maybe_async]
-impl GetTx for EsploraBlockchain {
-    fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, Error> {
-        Ok(await_or_block!(self.url_client._get_tx(txid))?)
+    /// Set the stop gap of the internal (change) keychain, which defaults to the stop gap of the
+    /// external one
+    ///
+    /// Change addresses are handed out in order, so they rarely need a gap as large as the
+    /// external ones.
+    pub fn with_internal_stop_gap(mut self, internal_stop_gap: usize) -> Self {
+        self.internal_stop_gap = Some(internal_stop_gap);
+        self
     }
-}
 
-#[maybe_async]
-impl WalletSync for EsploraBlockchain {
-    fn wallet_setup<D: BatchDatabase>(
+    /// Set the policy used to handle errors while fetching the history of a script pubkey.
+    pub fn with_sync_error_policy(mut self, sync_error_policy: SyncErrorPolicy) -> Self {
+        self.sync_error_policy = sync_error_policy;
+        self
+    }
+
+    /// Set whether to check that the blocks confirming the wallet transactions are still part of
+    /// the best chain during sync (default: false)
+    ///
+    /// Transactions reported in a block that was reorged out are treated as unconfirmed. This costs
+    /// one more request for each height at which the wallet transactions are confirmed.
+    pub fn with_verify_block_hashes(mut self, verify_block_hashes: bool) -> Self {
+        self.verify_block_hashes = verify_block_hashes;
+        self
+    }
+
+    /// Set whether to check the merkle proof of every newly confirmed transaction against the
+    /// trusted header of its block during sync (default: false)
+    ///
+    /// The headers are taken from the [`HeaderChain`] set with
+    /// [`EsploraBlockchain::with_trusted_headers`], never from the server. This costs an extra
+    /// request per confirmed transaction, and makes the sync fail with
+    /// [`Error::InvalidMerkleProof`] if the server reports a confirmation it can't prove, or with
+    /// [`Error::MissingTrustedHeader`] if the header of the block isn't trusted.
+    pub fn with_validate_proofs(mut self, validate_proofs: bool) -> Self {
+        self.validate_proofs = validate_proofs;
+        self
+    }
+
+    /// Set the headers used to check the merkle proofs returned by the server, see
+    /// [`EsploraBlockchain::with_validate_proofs`]
+    ///
+    /// The chain is shared with the caller, who can keep extending it as new headers are verified.
+    pub fn with_trusted_headers(mut self, headers: Arc<RwLock<HeaderChain>>) -> Self {
+        self.trusted_headers = Some(headers);
+        self
+    }
+
+    /// Set a custom resolver consulted during sync to decide which outputs belong to the wallet
+    pub fn with_ownership_resolver(mut self, ownership_resolver: OwnershipResolver) -> Self {
+        self.ownership_resolver = Some(ownership_resolver);
+        self
+    }
+
+    /// Set how the transactions of the wallet are discovered during sync
+    pub fn with_sync_mode(mut self, sync_mode: SyncMode) -> Self {
+        self.sync_mode = sync_mode;
+        self
+    }
+
+    /// Set the implementation of the API exposed by the server
+    pub fn with_flavor(mut self, flavor: EsploraFlavor) -> Self {
+        self.flavor = flavor;
+        self
+    }
+
+    /// Reuse the fee estimates of the server for `ttl` instead of fetching them for every call to
+    /// [`Blockchain::estimate_fee`]
+    ///
+    /// The cached estimates can be persisted across restarts with
+    /// [`EsploraBlockchain::dump_fee_cache`] and [`EsploraBlockchain::load_fee_cache`].
+    pub fn with_fee_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.fee_cache.ttl = Some(ttl);
+        self
+    }
+
+    /// Serialize the cached fee estimates, along with the time they were fetched at
+    ///
+    /// Returns `None` if no estimate was cached yet.
+    pub fn dump_fee_cache(&self) -> Result<Option<String>, Error> {
+        self.fee_cache.dump()
+    }
+
+    /// Restore the fee estimates serialized with [`EsploraBlockchain::dump_fee_cache`]
+    ///
+    /// Estimates older than the TTL set with [`EsploraBlockchain::with_fee_cache_ttl`] are
+    /// ignored, as are all estimates if no TTL was set.
+    pub fn load_fee_cache(&self, data: &str) -> Result<(), Error> {
+        self.fee_cache.load(data)
+    }
+
+    /// Fetch the fee rates estimated by the server for all the confirmation targets it supports,
+    /// sorted by target (blocks)
+    ///
+    /// A single request is made, which is useful to let users pick a confirmation target. The
+    /// estimates are cached like the ones used by [`Blockchain::estimate_fee`], see
+    /// [`EsploraBlockchain::with_fee_cache_ttl`].
+    #[maybe_async]
+    pub fn fee_estimates(&self) -> Result<BTreeMap<usize, FeeRate>, Error> {
+        let estimates = match self.fee_cache.get() {
+            Some(estimates) => estimates,
+            None => {
+                let estimates = await_or_block!(self.url_client._get_fee_estimates())?;
+                self.fee_cache.insert(&estimates);
+                estimates
+            }
+        };
+
+        Ok(super::into_fee_rates(estimates))
+    }
+
+    /// Return the absolute fee paid by a transaction (sats)
+    ///
+    /// Returns `None` if the transaction is unknown to the server or if it's a coinbase
+    /// transaction.
+    #[maybe_async]
+    pub fn get_tx_fee(&self, txid: &Txid) -> Result<Option<u64>, Error> {
+        Ok(await_or_block!(self.url_client._get_tx_info(txid))?
+            .filter(|tx| !tx.is_coinbase())
+            .map(|tx| tx.fee))
+    }
+
+    /// Estimate the number of blocks until the unconfirmed transaction `txid` confirms
+    ///
+    /// The feerate of the transaction is compared to the current fee histogram of the mempool of
+    /// the server, assuming every transaction paying more is mined first. Returns `None` if the
+    /// transaction is already confirmed.
+    #[maybe_async]
+    pub fn estimate_time_to_confirm(&self, txid: &Txid) -> Result<Option<usize>, Error> {
+        let tx = await_or_block!(self.url_client._get_tx_info_no_opt(txid))?;
+        if tx.status.confirmed {
+            return Ok(None);
+        }
+        let mempool = await_or_block!(self.url_client._get_mempool_info())?;
+
+        Ok(Some(super::blocks_to_confirm(
+            tx.fee,
+            tx.vsize(),
+            &mempool.fee_histogram,
+        )))
+    }
+
+    /// Return whether any of the first `stop_gap` script pubkeys of `descriptor` has a history
+    ///
+    /// This quickly confirms that a descriptor, or the seed it was derived from, was entered
+    /// correctly before a full sync. A descriptor without a wildcard has a single script pubkey.
+    /// Fails if the keys of the descriptor aren't valid for `network`.
+    ///
+    /// The script pubkeys are queried one at a time, stopping at the first used one.
+    #[maybe_async]
+    pub fn verify_descriptor_has_funds(
+        &self,
+        descriptor: &ExtendedDescriptor,
+        network: Network,
+        stop_gap: usize,
+    ) -> Result<bool, Error> {
+        for script in descriptor_script_pubkeys(descriptor, network, stop_gap)? {
+            if !await_or_block!(self.url_client._scripthash_txs(&script, None))?.is_empty() {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Return the fee histogram of the mempool of the server
+    ///
+    /// Every `(fee_rate, vsize)` pair is the total vsize of the transactions paying at least
+    /// `fee_rate` but less than the fee rate of the previous pair, sorted by decreasing fee rate.
+    #[maybe_async]
+    pub fn fee_histogram(&self) -> Result<Vec<(FeeRate, usize)>, Error> {
+        let mempool = await_or_block!(self.url_client._get_mempool_info())?;
+        Ok(into_fee_histogram(mempool.fee_histogram))
+    }
+
+    /// Estimate the position of the unconfirmed transaction `txid` in the mempool of the server
+    ///
+    /// The position is the total vsize of the transactions paying a higher feerate, from the
+    /// current fee histogram of the mempool: `0` means that the transaction is first in line to be
+    /// mined. Returns `None` if the transaction is already confirmed.
+    #[maybe_async]
+    pub fn get_mempool_position(&self, txid: &Txid) -> Result<Option<usize>, Error> {
+        let tx = await_or_block!(self.url_client._get_tx_info_no_opt(txid))?;
+        if tx.status.confirmed {
+            return Ok(None);
+        }
+        let mempool = await_or_block!(self.url_client._get_mempool_info())?;
+
+        Ok(Some(
+            super::vsize_ahead(tx.fee, tx.vsize(), &mempool.fee_histogram) as usize,
+        ))
+    }
+
+    /// Find an unconfirmed transaction that spends any of the `outpoints` and signals RBF
+    ///
+    /// This helps locating the live transaction to replace when it's not known whether a
+    /// transaction is still in the mempool. Only explicit signaling (BIP125) is considered, a
+    /// transaction that is replaceable only because of its unconfirmed ancestors isn't returned.
+    #[maybe_async]
+    pub fn find_replaceable_tx(&self, outpoints: &[OutPoint]) -> Result<Option<Txid>, Error> {
+        for outpoint in outpoints {
+            if let Some(txid) = await_or_block!(self.url_client._get_unconfirmed_spend(outpoint))? {
+                if await_or_block!(self.url_client._get_tx_info_no_opt(&txid))?.signals_rbf() {
+                    return Ok(Some(txid));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Refresh the spent status of the wallet UTXOs at `outpoints` in `database`
+    ///
+    /// This only looks up whether each output was spent, which is much cheaper than a full sync
+    /// when the wallet just needs to check some specific coins (e.g. in cold storage) weren't
+    /// moved. Outpoints that aren't UTXOs of `database` are ignored. Spending transactions aren't
+    /// downloaded, they will be found by the next full sync.
+    #[maybe_async]
+    pub fn sync_outpoints<D: BatchDatabase>(
         &self,
         database: &mut D,
-        _progress_update: Box<dyn Progress>,
+        outpoints: &[OutPoint],
     ) -> Result<(), Error> {
+        let mut batch = database.begin_batch();
+        for outpoint in outpoints {
+            let mut utxo = match database.get_utxo(outpoint)? {
+                Some(utxo) => utxo,
+                None => {
+                    debug!("Ignoring outpoint {} not owned by the wallet", outpoint);
+                    continue;
+                }
+            };
+
+            let spent = await_or_block!(self.url_client._get_outspend(outpoint))?.spent;
+            if utxo.is_spent != spent {
+                debug!("Updating outpoint {}: spent = {}", outpoint, spent);
+                utxo.is_spent = spent;
+                batch.set_utxo(&utxo)?;
+            }
+        }
+
+        database.commit_batch(batch)
+    }
+
+    /// Refresh the spent status of all the unspent UTXOs of `database`, returning the outpoints
+    /// that were spent since the last sync
+    ///
+    /// The outspends of the transactions of the UTXOs are fetched, making at most `concurrency`
+    /// requests in parallel. Like [`EsploraBlockchain::sync_outpoints`], this is a much cheaper
+    /// alternative to a full sync for a wallet that just monitors its balance, but the spending
+    /// transactions aren't downloaded.
+    #[maybe_async]
+    pub fn refresh_utxo_spent_status<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+    ) -> Result<Vec<OutPoint>, Error> {
+        let unspent = database
+            .iter_utxos()?
+            .into_iter()
+            .filter(|utxo| !utxo.is_spent)
+            .collect::<Vec<_>>();
+        let txids = unspent
+            .iter()
+            .map(|utxo| utxo.outpoint.txid)
+            .collect::<HashSet<_>>();
+        let outspends = await_or_block!(self
+            .url_client
+            ._get_txs_outspends(txids.into_iter().collect(), self.url_client.concurrency))?;
+
+        let mut batch = database.begin_batch();
+        let mut spent = vec![];
+        for mut utxo in unspent {
+            let is_spent = outspends
+                .get(&utxo.outpoint.txid)
+                .and_then(|outspends| outspends.get(utxo.outpoint.vout as usize))
+                .map_or(false, |outspend| outspend.spent);
+            if is_spent {
+                debug!("UTXO {} was spent", utxo.outpoint);
+                utxo.is_spent = true;
+                batch.set_utxo(&utxo)?;
+                spent.push(utxo.outpoint);
+            }
+        }
+        database.commit_batch(batch)?;
+
+        Ok(spent)
+    }
+
+    /// Return the script pubkeys that a sync of `database` would query, without making any request
+    /// to the server
+    ///
+    /// The script pubkeys phase of the sync is run against the transactions already in
+    /// `database`, in batches of [`concurrency`](EsploraBlockchain::with_concurrency) script
+    /// pubkeys, which shows how far the stop gap takes the scan of each keychain. It doesn't apply
+    /// to [`SyncMode::BlockScan`], which doesn't query script pubkeys.
+    pub fn plan_sync<D: BatchDatabase>(&self, database: &D) -> Result<SyncPlan, Error> {
+        script_sync::plan(
+            database,
+            script_sync::StopGap::new(self.stop_gap, self.internal_stop_gap),
+            self.url_client.concurrency as usize,
+        )
+    }
+
+    /// Fetch the unconfirmed ancestors and descendants of a transaction, along with their fees and
+    /// virtual sizes
+    ///
+    /// The graph of unconfirmed transactions is walked for at most 25 generations in each
+    /// direction. This is useful to compute the feerate of the whole package before bumping the
+    /// fees of `txid`.
+    #[maybe_async]
+    pub fn get_mempool_ancestors_descendants(
+        &self,
+        txid: &Txid,
+    ) -> Result<MempoolRelations, Error> {
+        Ok(await_or_block!(self
+            .url_client
+            ._get_mempool_relations(txid))?)
+    }
+
+    /// Return the transactions in the history of `notification_script` that look like BIP47
+    /// notification transactions
+    ///
+    /// These are the transactions paying to the notification address that also carry an 80 bytes
+    /// `OP_RETURN` payload: the candidates to decode to learn the payment code of the sender.
+    #[maybe_async]
+    pub fn find_notification_txs(
+        &self,
+        notification_script: &Script,
+    ) -> Result<Vec<Transaction>, Error> {
+        Ok(
+            await_or_block!(self.url_client._get_script_history(notification_script))?
+                .iter()
+                .map(Tx::to_tx)
+                .filter(|tx| is_notification_tx(tx, notification_script))
+                .collect(),
+        )
+    }
+
+    /// Return whether `outpoint` is spent and, if so, by which input of which transaction
+    ///
+    /// Both confirmed and unconfirmed spends are reported, which lets a wallet notice that an
+    /// output it tracks was spent by someone else, like a co-signer, without a full sync. Returns
+    /// `None` if the transaction is unknown to the server or doesn't have output `outpoint.vout`.
+    #[maybe_async]
+    pub fn get_output_status(&self, outpoint: &OutPoint) -> Result<Option<OutputStatus>, Error> {
+        Ok(await_or_block!(self.url_client._get_output_status(outpoint))?.map(OutputStatus::from))
+    }
+
+    /// Fetch the block hash, height, time and merkle proof of a confirmed transaction, along with
+    /// its current number of confirmations
+    ///
+    /// Returns `None` if the transaction is unconfirmed or unknown to the server.
+    #[maybe_async]
+    pub fn confirmation_path(&self, txid: &Txid) -> Result<Option<ConfirmationPath>, Error> {
+        await_or_block!(self.url_client._get_confirmation_path(txid))
+    }
+
+    /// Return the confirmed wallet UTXOs whose transaction doesn't exist anymore
+    ///
+    /// After a reorg the outputs of a transaction that was only confirmed in the stale blocks, like
+    /// the coinbase of one of them, don't exist anymore. This checks the status of the
+    /// transaction of every confirmed UTXO of `database` on the server, without syncing. A
+    /// transaction confirmed again in another block or sent back to the mempool still exists, so
+    /// its outputs aren't returned: the next sync updates their confirmation time.
+    #[maybe_async]
+    pub fn find_orphaned_utxos<D: Database>(&self, database: &D) -> Result<Vec<OutPoint>, Error> {
+        let mut exists = HashMap::new();
+        let mut orphaned = vec![];
+        for utxo in database.iter_utxos()? {
+            let txid = utxo.outpoint.txid;
+            let height = match database
+                .get_tx(&txid, false)?
+                .and_then(|details| details.confirmation_time)
+            {
+                Some(confirmation_time) if !utxo.is_spent => confirmation_time.height,
+                _ => continue,
+            };
+
+            if !exists.contains_key(&txid) {
+                // the status of an unknown transaction is "unconfirmed", look it up to tell
+                // apart the ones that are actually in the mempool
+                let confirmed = matches!(
+                    await_or_block!(self.url_client._get_tx_status(&txid))?,
+                    Some(TxStatus {
+                        confirmed: true,
+                        ..
+                    })
+                );
+                let found = confirmed || await_or_block!(self.url_client._get_tx(&txid))?.is_some();
+                exists.insert(txid, found);
+            }
+            if !exists[&txid] {
+                debug!(
+                    "The transaction of UTXO {}, confirmed at height {}, doesn't exist anymore",
+                    utxo.outpoint, height
+                );
+                orphaned.push(utxo.outpoint);
+            }
+        }
+
+        Ok(orphaned)
+    }
+
+    /// Return the current difficulty and estimated hashrate of the network
+    ///
+    /// With [`EsploraFlavor::MempoolSpace`] the statistics computed by the server are used,
+    /// otherwise they're derived from the header of the tip of the chain, see
+    /// [`NetworkStats::from_header`].
+    #[maybe_async]
+    pub fn get_network_stats(&self) -> Result<NetworkStats, Error> {
+        let height = await_or_block!(self.url_client._get_height())?;
+        if self.flavor == EsploraFlavor::MempoolSpace {
+            if let Some(mining) = await_or_block!(self.url_client._get_mining_hashrate())? {
+                return Ok(NetworkStats {
+                    height,
+                    difficulty: mining.current_difficulty,
+                    hashrate: mining.current_hashrate,
+                });
+            }
+        }
+
+        let header = await_or_block!(self.url_client._get_header(height))?;
+        Ok(NetworkStats::from_header(height, &header))
+    }
+
+    /// Check that a transaction is confirmed in the block at the height given by its merkle proof,
+    /// using the trusted header from `headers` instead of the one reported by the server
+    ///
+    /// Returns `false` if the transaction is unconfirmed, unknown to the server or if its merkle
+    /// proof doesn't match the trusted header. The transaction itself is fetched as well, and
+    /// `false` is returned if its serialization without witness is 64 bytes long: such a
+    /// transaction is indistinguishable from an inner node of the merkle tree. Fails with
+    /// [`EsploraError::HeaderHeightNotFound`] if `headers` doesn't contain the height of the block
+    /// claimed by the server.
+    #[maybe_async]
+    pub fn verify_tx_against_headers(
+        &self,
+        txid: &Txid,
+        headers: &HeaderChain,
+    ) -> Result<bool, Error> {
+        Ok(await_or_block!(self
+            .url_client
+            ._verify_tx_against_headers(txid, headers))?)
+    }
+
+    /// Check that a transaction is confirmed in the block at `height`, verifying its merkle proof
+    /// against the trusted header at that height, see [`EsploraBlockchain::with_trusted_headers`]
+    ///
+    /// Returns `false` if the transaction is unconfirmed, unknown to the server, confirmed at a
+    /// different height or if its merkle proof doesn't match the header. Fails with
+    /// [`Error::MissingTrustedHeader`] if there's no trusted header at `height`.
+    #[maybe_async]
+    pub fn verify_tx_proof(&self, txid: &Txid, height: u32) -> Result<bool, Error> {
+        let header = trusted_header(&self.trusted_headers, height)?;
+        Ok(await_or_block!(self
+            .url_client
+            ._verify_tx_proof(txid, height, &header))?)
+    }
+
+    /// Return the percentiles of the latencies of the requests sent since the blockchain was
+    /// created, grouped by [`EndpointCategory`]
+    ///
+    /// The report covers the requests sent by the clones of this blockchain too, including the
+    /// failed ones.
+    pub fn latency_report(&self) -> LatencyReport {
+        self.url_client.latencies.report()
+    }
+
+    /// Number of requests the current sync can still make, `None` if there's no limit
+    ///
+    /// Only meaningful on the copy of the blockchain made for the sync, whose client counts the
+    /// requests from zero.
+    fn remaining_requests(&self) -> Option<usize> {
+        self.max_requests_per_sync.map(|max_requests| {
+            max_requests.saturating_sub(self.url_client.requests_sent.load(Ordering::Relaxed))
+        })
+    }
+
+    /// Check the merkle proofs of the transactions confirmed according to `conftimes` against the
+    /// trusted headers, see [`EsploraBlockchain::with_validate_proofs`]
+    #[maybe_async]
+    fn check_proofs(&self, conftimes: &[(Txid, Option<BlockTime>)]) -> Result<(), Error> {
+        for (txid, conftime) in conftimes {
+            let height = match conftime {
+                Some(conftime) => conftime.height,
+                None => continue,
+            };
+            let header = trusted_header(&self.trusted_headers, height)?;
+            if !await_or_block!(self.url_client._verify_tx_proof(txid, height, &header))? {
+                return Err(Error::InvalidMerkleProof(*txid));
+            }
+        }
+        Ok(())
+    }
+
+    /// Sync by downloading blocks instead of querying the history of every script pubkey, see
+    /// [`SyncMode::BlockScan`]
+    #[maybe_async]
+    fn block_scan<D: BatchDatabase>(
+        &self,
+        database: &D,
+        birth_height: u32,
+        stats: &mut SyncStats,
+        // taken mutably so that the reference is `Send` across the `await` points
+        progress: &mut dyn Progress,
+    ) -> Result<D::Batch, Error> {
+        use crate::blockchain::script_sync::Request;
+
+        let tip_height = await_or_block!(self.url_client._get_height())?;
+        let mut heights = block_scan_range(database, birth_height, tip_height)?;
+        let start_height = *heights.start();
+        let total_blocks = (tip_height as u64 + 1).saturating_sub(start_height as u64);
+        let mut scanner =
+            BlockScanner::new(database, start_height, self.ownership_resolver.clone())?;
+
+        let mut scanned_blocks = 0;
+        loop {
+            let chunk = heights
+                .by_ref()
+                .take(BLOCK_SCAN_CHUNK_SIZE)
+                .collect::<Vec<_>>();
+            if chunk.is_empty() {
+                break;
+            }
+            for batch in chunk.chunks(self.url_client.concurrency.max(1) as usize) {
+                if progress.is_cancelled() {
+                    return Err(Error::SyncAborted);
+                }
+                let futures: FuturesOrdered<_> = batch
+                    .iter()
+                    .map(|height| self.url_client._get_block_by_height(*height))
+                    .collect();
+                let blocks: Vec<Result<Block, EsploraError>> = await_or_block!(futures.collect());
+                // blocks must be scanned in order to find the transactions spending from the
+                // previous ones
+                for (height, block) in batch.iter().zip(blocks) {
+                    scanner.scan_block(*height, block?);
+                }
+            }
+            scanned_blocks += chunk.len() as u64;
+            progress.update(scanned_blocks as f32 / total_blocks as f32 * 100.0, None)?;
+        }
+
+        stats.scripts_scanned = scanner.scripts.len();
+        stats.txs_fetched = scanner.txs_downloaded;
+
+        let mut request = script_sync::start_with_ownership_resolver(
+            database,
+            script_sync::StopGap::new(self.stop_gap, self.internal_stop_gap),
+            self.ownership_resolver.clone(),
+        )?;
+        loop {
+            request = match request {
+                Request::Script(script_req) => {
+                    let histories = script_req
+                        .request()
+                        .map(|script| scanner.history(script))
+                        .collect();
+                    script_req.satisfy(histories)?
+                }
+                Request::Conftime(conftime_req) => {
+                    let conftimes = conftime_req
+                        .request()
+                        .map(|txid| (*txid, scanner.confirmation_time(txid)))
+                        .collect::<Vec<_>>();
+                    if self.validate_proofs {
+                        maybe_await!(self.check_proofs(&conftimes))?;
+                    }
+                    conftime_req.satisfy(
+                        conftimes
+                            .into_iter()
+                            .map(|(_, conftime)| conftime)
+                            .collect(),
+                    )?
+                }
+                Request::Tx(tx_req) => {
+                    let full_txs = tx_req.request().map(|txid| scanner.full_tx(txid)).collect();
+                    tx_req.satisfy(full_txs)?
+                }
+                Request::Finish(batch_update) => return Ok(batch_update),
+            }
+        }
+    }
+
+    /// Sync `database`, see [`WalletSync::wallet_setup_with_stats`]
+    #[maybe_async]
+    fn sync_with_stats<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        mut progress_update: Box<dyn Progress>,
+    ) -> Result<SyncStats, Error> {
+        let start = Instant::new();
+        let mut stats = SyncStats {
+            reorged_txids: maybe_await!(reorg::detect_reorgs(self, database))?,
+            ..Default::default()
+        };
+
+        if let SyncMode::BlockScan { birth_height } = self.sync_mode {
+            let batch_update = maybe_await!(self.block_scan(
+                database,
+                birth_height,
+                &mut stats,
+                progress_update.as_mut()
+            ))?;
+            database.commit_batch(batch_update)?;
+            maybe_await!(reorg::store_block_hashes(self, database))?;
+
+            stats.bytes_transferred = Some(self.url_client.bytes_received.load(Ordering::Relaxed));
+            stats.duration = start.elapsed();
+            return Ok(stats);
+        }
+
         use crate::blockchain::script_sync::Request;
-        let mut request = script_sync::start(database, self.stop_gap)?;
+        let mut request = script_sync::start_with_ownership_resolver(
+            database,
+            script_sync::StopGap::new(self.stop_gap, self.internal_stop_gap),
+            self.ownership_resolver.clone(),
+        )?;
         let mut tx_index: HashMap<Txid, Tx> = HashMap::new();
+        let mut prevout_txs: HashMap<Txid, Transaction> = HashMap::new();
+        let mut prevouts_fetched = 0;
+        let mut best_chain: HashMap<u32, Option<BlockHash>> = HashMap::new();
 
         let batch_update = loop {
+            if progress_update.is_cancelled() {
+                return Err(Error::SyncAborted);
+            }
             request = match request {
+                Request::Script(script_req) if self.remaining_requests() == Some(0) => {
+                    debug!("Request budget exhausted, skipping the remaining script pubkeys");
+                    stats.request_budget_exhausted = true;
+                    script_req.skip_remaining()?
+                }
                 Request::Script(script_req) => {
-                    let futures: FuturesOrdered<_> = script_req
-                        .request()
-                        .take(self.url_client.concurrency as usize)
+                    let concurrency = self.url_client.concurrency as usize;
+                    let batch_size = self
+                        .remaining_requests()
+                        .map_or(concurrency, |remaining| remaining.min(concurrency));
+                    let scripts = script_req.request().take(batch_size);
+                    stats.scripts_scanned += scripts.clone().count();
+                    let futures: FuturesOrdered<_> = scripts
                         .map(|script| async move {
-                            let mut related_txs: Vec<Tx> =
-                                self.url_client._scripthash_txs(script, None).await?;
-
-                            let n_confirmed =
-                                related_txs.iter().filter(|tx| tx.status.confirmed).count();
-                            // esplora pages on 25 confirmed transactions. If there's 25 or more we
-                            // keep requesting to see if there's more.
-                            if n_confirmed >= 25 {
-                                loop {
-                                    let new_related_txs: Vec<Tx> = self
-                                        .url_client
-                                        ._scripthash_txs(
-                                            script,
-                                            Some(related_txs.last().unwrap().txid),
-                                        )
-                                        .await?;
-                                    let n = new_related_txs.len();
-                                    related_txs.extend(new_related_txs);
-                                    // we've reached the end
-                                    if n < 25 {
-                                        break;
-                                    }
-                                }
-                            }
-                            Result::<_, Error>::Ok(related_txs)
+                            Result::<_, Error>::Ok(
+                                self.url_client._get_script_history(script).await?,
+                            )
                         })
                         .collect();
-                    let txs_per_script: Vec<Vec<Tx>> = await_or_block!(futures.try_collect())?;
+                    let mut txs_per_script: Vec<Result<Vec<Tx>, Error>> =
+                        await_or_block!(futures.collect());
+
+                    if self.verify_block_hashes {
+                        let all_txs = txs_per_script.iter().flatten().flatten();
+                        for height in super::missing_block_heights(all_txs, &best_chain) {
+                            let hash = await_or_block!(self.url_client._get_block_hash(height))?;
+                            best_chain.insert(height, hash);
+                        }
+                        super::unconfirm_reorged_txs(
+                            txs_per_script.iter_mut().flatten().flatten(),
+                            &best_chain,
+                        );
+                    }
+
                     let mut satisfaction = vec![];
 
                     for txs in txs_per_script {
-                        satisfaction.push(
-                            txs.iter()
+                        satisfaction.push(txs.map(|txs| {
+                            let txids = txs
+                                .iter()
                                 .map(|tx| (tx.txid, tx.status.block_height))
-                                .collect(),
-                        );
-                        for tx in txs {
-                            tx_index.insert(tx.txid, tx);
-                        }
+                                .collect();
+                            for tx in txs {
+                                tx_index.insert(tx.txid, tx);
+                            }
+                            txids
+                        }));
                     }
 
-                    script_req.satisfy(satisfaction)?
+                    script_req.satisfy_with_policy(satisfaction, self.sync_error_policy)?
                 }
                 Request::Conftime(conftime_req) => {
                     let conftimes = conftime_req
                         .request()
                         .map(|txid| {
-                            tx_index
+                            let conftime = tx_index
                                 .get(txid)
                                 .expect("must be in index")
-                                .confirmation_time()
+                                .confirmation_time();
+                            (*txid, conftime)
                         })
-                        .collect();
-                    conftime_req.satisfy(conftimes)?
+                        .collect::<Vec<_>>();
+                    if self.validate_proofs {
+                        maybe_await!(self.check_proofs(&conftimes))?;
+                    }
+                    conftime_req.satisfy(
+                        conftimes
+                            .into_iter()
+                            .map(|(_, conftime)| conftime)
+                            .collect(),
+                    )?
                 }
                 Request::Tx(tx_req) => {
+                    let mut missing = tx_req
+                        .request()
+                        .flat_map(|txid| {
+                            tx_index
+                                .get(txid)
+                                .expect("must be in index")
+                                .missing_prevouts()
+                        })
+                        .filter(|txid| !prevout_txs.contains_key(txid))
+                        .collect::<HashSet<_>>();
+                    if let Some(cache) = &self.shared_tx_cache {
+                        missing.retain(|txid| match cache.get(txid) {
+                            Some(tx) => {
+                                prevout_txs.insert(*txid, tx);
+                                false
+                            }
+                            None => true,
+                        });
+                    }
+                    let prevout_concurrency = self
+                        .url_client
+                        .prevout_concurrency
+                        .unwrap_or(self.url_client.concurrency);
+                    let fetched = await_or_block!(self
+                        .url_client
+                        ._get_txs(missing.into_iter().collect(), prevout_concurrency))?;
+                    if let Some(cache) = &self.shared_tx_cache {
+                        fetched.values().cloned().for_each(|tx| cache.insert(tx));
+                    }
+                    prevouts_fetched += fetched.len();
+                    prevout_txs.extend(fetched);
+
                     let full_txs = tx_req
                         .request()
                         .map(|txid| {
                             let tx = tx_index.get(txid).expect("must be in index");
-                            Ok((tx.previous_outputs(), tx.to_tx()))
+                            Ok((tx.previous_outputs(&prevout_txs), tx.to_tx()))
                         })
                         .collect::<Result<_, Error>>()?;
                     tx_req.satisfy(full_txs)?
                 }
                 Request::Finish(batch_update) => break batch_update,
+            };
+
+            if let Some(sync_progress) = request.sync_progress() {
+                progress_update.update_sync(&sync_progress)?;
+            }
+            if let Some(skipped_scripts) = request.skipped_scripts() {
+                stats.skipped_scripts = skipped_scripts;
             }
         };
 
         database.commit_batch(batch_update)?;
+        maybe_await!(reorg::store_block_hashes(self, database))?;
 
-        Ok(())
+        stats.txs_fetched = tx_index.len() + prevouts_fetched;
+        stats.bytes_transferred = Some(self.url_client.bytes_received.load(Ordering::Relaxed));
+        stats.duration = start.elapsed();
+        Ok(stats)
     }
 }
 
-impl UrlClient {
-    async fn _get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, EsploraError> {
-        let resp = self
-            .client
-            .get(&format!("{}/tx/{}/raw", self.url, txid))
-            .send()
-            .await?;
+#[maybe_async]
+impl Blockchain for EsploraBlockchain {
+    fn get_capabilities(&self) -> HashSet<Capability> {
+        vec![
+            Capability::FullHistory,
+            Capability::GetAnyTx,
+            Capability::AccurateFees,
+            Capability::GetBlock,
+            Capability::FeeHistogram,
+        ]
+        .into_iter()
+        .collect()
+    }
 
-        if let StatusCode::NOT_FOUND = resp.status() {
-            return Ok(None);
+    fn broadcast(&self, tx: &Transaction) -> Result<(), Error> {
+        Ok(await_or_block!(self.url_client._broadcast(tx))?)
+    }
+
+    fn broadcast_batch(&self, txs: &[Transaction]) -> Result<Vec<Txid>, Error> {
+        let mut broadcast = Vec::with_capacity(txs.len());
+        for group in independent_broadcast_groups(txs) {
+            for chunk in group.chunks(self.url_client.concurrency.max(1) as usize) {
+                let futures: FuturesOrdered<_> = chunk
+                    .iter()
+                    .map(|tx| async move { Ok(self.url_client._broadcast(tx).await?) })
+                    .collect();
+                let results: Vec<Result<(), Error>> = await_or_block!(futures.collect());
+                check_group_broadcast(chunk, results, &mut broadcast)?;
+            }
         }
 
-        Ok(Some(deserialize(&resp.error_for_status()?.bytes().await?)?))
+        Ok(broadcast)
     }
 
-    async fn _get_tx_no_opt(&self, txid: &Txid) -> Result<Transaction, EsploraError> {
-        match self._get_tx(txid).await {
-            Ok(Some(tx)) => Ok(tx),
-            Ok(None) => Err(EsploraError::TransactionNotFound(*txid)),
+    fn estimate_fee(&self, target: usize) -> Result<FeeRate, Error> {
+        let estimates = maybe_await!(self.fee_estimates())?;
+        super::into_fee_rate(target, &estimates)
+    }
+
+    fn get_tx_confirmations(&self, txid: &Txid) -> Result<Option<u32>, Error> {
+        let height = match await_or_block!(self.url_client._get_tx_status(txid))? {
+            None => return Ok(None),
+            Some(TxStatus {
+                confirmed: true,
+                block_height: Some(height),
+                ..
+            }) => height,
+            Some(_) => return Ok(Some(0)),
+        };
+        let tip = await_or_block!(self.url_client._get_height())?;
+
+        // the tip may come from a server lagging behind the one that replied to the status
+        Ok(Some((tip + 1).saturating_sub(height).max(1)))
+    }
+}
+
+impl StatelessBlockchain for EsploraBlockchain {}
+
+#[maybe_async]
+impl GetHeight for EsploraBlockchain {
+    fn get_height(&self) -> Result<u32, Error> {
+        Ok(await_or_block!(self.url_client._get_height())?)
+    }
+}
+
+#[maybe_async]
+impl GetTx for EsploraBlockchain {
+    fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, Error> {
+        Ok(await_or_block!(self.url_client._get_tx(txid))?)
+    }
+}
+
+#[maybe_async]
+impl GetBlock for EsploraBlockchain {
+    fn get_block(&self, hash: &BlockHash) -> Result<Option<Block>, Error> {
+        Ok(await_or_block!(self.url_client._get_block(hash))?)
+    }
+}
+
+#[maybe_async]
+impl GetBlockHash for EsploraBlockchain {
+    fn get_block_hash(&self, height: u32) -> Result<Option<BlockHash>, Error> {
+        Ok(await_or_block!(self.url_client._get_block_hash(height))?)
+    }
+
+    fn get_block_header(&self, height: u32) -> Result<Option<BlockHeader>, Error> {
+        match await_or_block!(self.url_client._get_header(height)) {
+            Ok(header) => Ok(Some(header)),
+            Err(EsploraError::HeaderHeightNotFound(_)) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[maybe_async]
+impl WalletSync for EsploraBlockchain {
+    fn wallet_setup<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<(), Error> {
+        maybe_await!(self.wallet_setup_with_stats(database, progress_update))?;
+        Ok(())
+    }
+
+    fn wallet_sync_with_stats<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<SyncStats, Error> {
+        maybe_await!(self.wallet_setup_with_stats(database, progress_update))
+    }
+
+    fn wallet_setup_with_stats<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<SyncStats, Error> {
+        // the counters of the client are shared with its clones, count the requests of this sync
+        // only on a copy of the blockchain with its own counters
+        let blockchain = EsploraBlockchain {
+            url_client: self.url_client.with_new_counters(),
+            ..self.clone()
+        };
+        maybe_await!(blockchain.sync_with_stats(database, progress_update))
+    }
+
+    fn wallet_sync_with_budget<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+        max_requests: usize,
+    ) -> Result<SyncStats, Error> {
+        maybe_await!(self.wallet_setup_with_budget(database, progress_update, max_requests))
+    }
+
+    fn wallet_setup_with_budget<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+        max_requests: usize,
+    ) -> Result<SyncStats, Error> {
+        // the budget of the sync can only be lower than the one of the blockchain
+        let max_requests = self
+            .max_requests_per_sync
+            .map_or(max_requests, |limit| limit.min(max_requests));
+        let blockchain = EsploraBlockchain {
+            url_client: self.url_client.with_new_counters(),
+            max_requests_per_sync: Some(max_requests),
+            ..self.clone()
+        };
+        maybe_await!(blockchain.sync_with_stats(database, progress_update))
+    }
+}
+
+impl CacheTxs for EsploraBlockchain {
+    fn with_tx_cache(mut self, cache: SharedTxCache) -> Self {
+        self.shared_tx_cache = Some(cache);
+        self
+    }
+}
+
+impl LimitRequests for EsploraBlockchain {
+    fn with_rate_limiter(mut self, limiter: RateLimiter) -> Self {
+        self.url_client.rate_limiter = Some(limiter);
+        self
+    }
+}
+
+impl UrlClient {
+    /// Clone of the client counting the bytes and requests from zero
+    fn with_new_counters(&self) -> Self {
+        UrlClient {
+            bytes_received: Arc::new(AtomicU64::new(0)),
+            requests_sent: Arc::new(AtomicUsize::new(0)),
+            ..self.clone()
+        }
+    }
+
+    async fn _get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, EsploraError> {
+        let url = format!("{}/tx/{}/raw", self.url, txid);
+        match self.get_immutable(&url, true).await? {
+            Some(bytes) => Ok(Some(deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn _get_tx_no_opt(&self, txid: &Txid) -> Result<Transaction, EsploraError> {
+        match self._get_tx(txid).await {
+            Ok(Some(tx)) => Ok(tx),
+            Ok(None) => Err(EsploraError::TransactionNotFound(*txid)),
             Err(e) => Err(e),
         }
     }
 
+    /// Fetch `txids`, making at most `concurrency` requests in parallel
+    async fn _get_txs(
+        &self,
+        txids: Vec<Txid>,
+        concurrency: u8,
+    ) -> Result<HashMap<Txid, Transaction>, EsploraError> {
+        let mut txs = HashMap::new();
+        for chunk in txids.chunks(concurrency.max(1) as usize) {
+            let mut futures: FuturesOrdered<_> =
+                chunk.iter().map(|txid| self._get_tx_no_opt(txid)).collect();
+
+            while let Some(tx) = futures.next().await {
+                let tx = tx?;
+                txs.insert(tx.txid(), tx);
+            }
+        }
+
+        Ok(txs)
+    }
+
+    async fn _get_tx_info(&self, txid: &Txid) -> Result<Option<Tx>, EsploraError> {
+        let resp = self.get(&format!("{}/tx/{}", self.url, txid)).await?;
+
+        if let StatusCode::NOT_FOUND = resp.status() {
+            return Ok(None);
+        }
+
+        Ok(Some(
+            self.read_json(self.error_for_status(resp).await?).await?,
+        ))
+    }
+
+    async fn _get_tx_info_no_opt(&self, txid: &Txid) -> Result<Tx, EsploraError> {
+        self._get_tx_info(txid)
+            .await?
+            .ok_or(EsploraError::TransactionNotFound(*txid))
+    }
+
+    async fn _get_mempool_info(&self) -> Result<MempoolInfo, EsploraError> {
+        let resp = self.get(&format!("{}/mempool", self.url)).await?;
+        let resp = self.error_for_status(resp).await?;
+
+        self.read_json(resp).await
+    }
+
+    async fn _get_tx_outspends(&self, txid: &Txid) -> Result<Vec<OutSpend>, EsploraError> {
+        let resp = self
+            .get(&format!("{}/tx/{}/outspends", self.url, txid))
+            .await?;
+        let resp = self.error_for_status(resp).await?;
+
+        self.read_json(resp).await
+    }
+
+    /// Fetch the outspends of `txids`, making at most `concurrency` requests in parallel
+    async fn _get_txs_outspends(
+        &self,
+        txids: Vec<Txid>,
+        concurrency: u8,
+    ) -> Result<HashMap<Txid, Vec<OutSpend>>, EsploraError> {
+        let mut outspends = HashMap::new();
+        for chunk in txids.chunks(concurrency.max(1) as usize) {
+            let mut futures: FuturesOrdered<_> = chunk
+                .iter()
+                .map(|txid| async move { (*txid, self._get_tx_outspends(txid).await) })
+                .collect();
+
+            while let Some((txid, tx_outspends)) = futures.next().await {
+                outspends.insert(txid, tx_outspends?);
+            }
+        }
+
+        Ok(outspends)
+    }
+
+    async fn _get_output_status(
+        &self,
+        outpoint: &OutPoint,
+    ) -> Result<Option<OutSpend>, EsploraError> {
+        // unlike `/outspend/:vout`, `/outspends` fails if the transaction doesn't exist
+        let resp = self
+            .get(&format!("{}/tx/{}/outspends", self.url, outpoint.txid))
+            .await?;
+
+        if let StatusCode::NOT_FOUND = resp.status() {
+            return Ok(None);
+        }
+
+        Ok(self
+            .read_json::<Vec<OutSpend>>(self.error_for_status(resp).await?)
+            .await?
+            .into_iter()
+            .nth(outpoint.vout as usize))
+    }
+
+    async fn _get_outspend(&self, outpoint: &OutPoint) -> Result<OutSpend, EsploraError> {
+        let resp = self
+            .get(&format!(
+                "{}/tx/{}/outspend/{}",
+                self.url, outpoint.txid, outpoint.vout
+            ))
+            .await?;
+        let resp = self.error_for_status(resp).await?;
+
+        self.read_json(resp).await
+    }
+
+    async fn _get_unconfirmed_spend(
+        &self,
+        outpoint: &OutPoint,
+    ) -> Result<Option<Txid>, EsploraError> {
+        Ok(self._get_outspend(outpoint).await?.unconfirmed_spend())
+    }
+
+    async fn _get_mempool_relations(&self, txid: &Txid) -> Result<MempoolRelations, EsploraError> {
+        let mut relations = MempoolRelations::default();
+        let mut seen = HashSet::new();
+        seen.insert(*txid);
+
+        let mut queue = VecDeque::new();
+        queue.push_back((self._get_tx_info_no_opt(txid).await?, 0));
+        while let Some((tx, depth)) = queue.pop_front() {
+            if depth >= MAX_MEMPOOL_RELATIONS_DEPTH {
+                continue;
+            }
+
+            for vin in tx.vin.iter().filter(|vin| !vin.is_coinbase) {
+                if !seen.insert(vin.txid) {
+                    continue;
+                }
+
+                let parent = self._get_tx_info_no_opt(&vin.txid).await?;
+                if parent.status.confirmed {
+                    continue;
+                }
+                relations.ancestors.push(MempoolTx::new(&parent, depth + 1));
+                queue.push_back((parent, depth + 1));
+            }
+        }
+
+        let mut queue = VecDeque::new();
+        queue.push_back((*txid, 0));
+        while let Some((txid, depth)) = queue.pop_front() {
+            if depth >= MAX_MEMPOOL_RELATIONS_DEPTH {
+                continue;
+            }
+
+            let outspends = self._get_tx_outspends(&txid).await?;
+            for child_txid in outspends.iter().filter_map(OutSpend::unconfirmed_spend) {
+                if !seen.insert(child_txid) {
+                    continue;
+                }
+
+                let child = self._get_tx_info_no_opt(&child_txid).await?;
+                relations
+                    .descendants
+                    .push(MempoolTx::new(&child, depth + 1));
+                queue.push_back((child_txid, depth + 1));
+            }
+        }
+
+        Ok(relations)
+    }
+
+    async fn _get_tx_status(&self, txid: &Txid) -> Result<Option<TxStatus>, EsploraError> {
+        let resp = self
+            .get(&format!("{}/tx/{}/status", self.url, txid))
+            .await?;
+
+        if let StatusCode::NOT_FOUND = resp.status() {
+            return Ok(None);
+        }
+
+        Ok(Some(
+            self.read_json(self.error_for_status(resp).await?).await?,
+        ))
+    }
+
+    async fn _get_merkle_proof(&self, txid: &Txid) -> Result<Option<MerkleProof>, EsploraError> {
+        let resp = self
+            .get(&format!("{}/tx/{}/merkle-proof", self.url, txid))
+            .await?;
+
+        if let StatusCode::NOT_FOUND = resp.status() {
+            return Ok(None);
+        }
+
+        Ok(Some(
+            self.read_json(self.error_for_status(resp).await?).await?,
+        ))
+    }
+
+    async fn _verify_tx_against_headers(
+        &self,
+        txid: &Txid,
+        headers: &HeaderChain,
+    ) -> Result<bool, EsploraError> {
+        let proof = match self._get_merkle_proof(txid).await? {
+            Some(proof) => proof,
+            None => return Ok(false),
+        };
+        match self._get_tx(txid).await? {
+            Some(tx) if tx.txid() == *txid && is_provable(&tx) => {
+                verify_merkle_proof(txid, &proof, headers)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn _verify_tx_proof(
+        &self,
+        txid: &Txid,
+        height: u32,
+        header: &BlockHeader,
+    ) -> Result<bool, EsploraError> {
+        match self._get_merkle_proof(txid).await? {
+            Some(proof) if proof.block_height == height => {
+                Ok(proof_matches_header(txid, &proof, header))
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn _get_confirmation_path(&self, txid: &Txid) -> Result<Option<ConfirmationPath>, Error> {
+        let (block_hash, block_time) = match self._get_tx_status(txid).await? {
+            Some(TxStatus {
+                confirmed: true,
+                block_hash: Some(block_hash),
+                block_height: Some(height),
+                block_time: Some(timestamp),
+            }) => (block_hash, BlockTime { height, timestamp }),
+            _ => return Ok(None),
+        };
+
+        let header = self._get_header_by_hash(&block_hash).await?;
+        let merkle_proof = match self._get_merkle_proof(txid).await? {
+            Some(merkle_proof) => merkle_proof,
+            // the transaction was reorged out in the meantime
+            None => return Ok(None),
+        };
+        let tip_height = self._get_height().await?;
+
+        ConfirmationPath::new(
+            *txid,
+            block_hash,
+            block_time,
+            header,
+            tip_height,
+            merkle_proof,
+        )
+        .map(Some)
+    }
+
+    async fn _get_block_hash(&self, block_height: u32) -> Result<Option<BlockHash>, EsploraError> {
+        let resp = self
+            .get(&format!("{}/block-height/{}", self.url, block_height))
+            .await?;
+
+        if let StatusCode::NOT_FOUND = resp.status() {
+            return Ok(None);
+        }
+
+        let hash = self.read_string(self.error_for_status(resp).await?).await?;
+        Ok(Some(BlockHash::from_hex(&hash)?))
+    }
+
+    async fn _get_block_by_height(&self, block_height: u32) -> Result<Block, EsploraError> {
+        let block_hash = self
+            ._get_block_hash(block_height)
+            .await?
+            .ok_or(EsploraError::HeaderHeightNotFound(block_height))?;
+        self._get_block(&block_hash)
+            .await?
+            .ok_or(EsploraError::HeaderHashNotFound(block_hash))
+    }
+
+    async fn _get_block(&self, block_hash: &BlockHash) -> Result<Option<Block>, EsploraError> {
+        // blocks are large, failed downloads aren't retried
+        let url = format!("{}/block/{}/raw", self.url, block_hash);
+        match self.get_immutable(&url, false).await? {
+            Some(bytes) => Ok(Some(deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
     async fn _get_header(&self, block_height: u32) -> Result<BlockHeader, EsploraError> {
         let resp = self
-            .client
             .get(&format!("{}/block-height/{}", self.url, block_height))
-            .send()
             .await?;
 
         if let StatusCode::NOT_FOUND = resp.status() {
             return Err(EsploraError::HeaderHeightNotFound(block_height));
         }
-        let bytes = resp.bytes().await?;
+        let bytes = self.read_bytes(resp).await?;
         let hash = std::str::from_utf8(&bytes)
             .map_err(|_| EsploraError::HeaderHeightNotFound(block_height))?;
 
-        let resp = self
-            .client
-            .get(&format!("{}/block/{}/header", self.url, hash))
-            .send()
-            .await?;
+        let url = format!("{}/block/{}/header", self.url, hash);
+        let bytes = self
+            .get_immutable(&url, false)
+            .await?
+            .ok_or(EsploraError::HeaderHeightNotFound(block_height))?;
 
-        let header = deserialize(&Vec::from_hex(&resp.text().await?)?)?;
+        parse_header(bytes)
+    }
+
+    async fn _get_header_by_hash(
+        &self,
+        block_hash: &BlockHash,
+    ) -> Result<BlockHeader, EsploraError> {
+        let url = format!("{}/block/{}/header", self.url, block_hash);
+        let bytes = self
+            .get_immutable(&url, false)
+            .await?
+            .ok_or(EsploraError::HeaderHashNotFound(*block_hash))?;
 
-        Ok(header)
+        parse_header(bytes)
     }
 
     async fn _broadcast(&self, transaction: &Transaction) -> Result<(), EsploraError> {
-        self.client
-            .post(&format!("{}/tx", self.url))
+        self.wait_rate_limiter().await;
+        self.requests_sent.fetch_add(1, Ordering::Relaxed);
+        let url = format!("{}/tx", self.url);
+        let start = Instant::new();
+        let resp = self
+            .client
+            .post(&url)
             .body(serialize(transaction).to_hex())
             .send()
-            .await?
-            .error_for_status()?;
+            .await;
+        self.record_latency("POST", &url, start);
+        let resp = resp?;
+        self.error_for_status(resp).await?;
 
         Ok(())
     }
 
     async fn _get_height(&self) -> Result<u32, EsploraError> {
         let req = self
-            .client
-            .get(&format!("{}/blocks/tip/height", self.url))
-            .send()
+            .get_once(&format!("{}/blocks/tip/height", self.url))
             .await?;
 
-        Ok(req.error_for_status()?.text().await?.parse()?)
+        Ok(self
+            .read_string(self.error_for_status(req).await?)
+            .await?
+            .parse()?)
     }
 
     async fn _scripthash_txs(
@@ -292,26 +1553,236 @@ impl UrlClient {
             ),
             None => format!("{}/scripthash/{}/txs", self.url, script_hash),
         };
-        Ok(self
-            .client
-            .get(url)
-            .send()
-            .await?
-            .error_for_status()?
-            .json::<Vec<Tx>>()
-            .await?)
+        let resp = self.error_for_status(self.get(&url).await?).await?;
+        self.read_json::<Vec<Tx>>(resp).await
+    }
+
+    async fn _get_script_history(&self, script: &Script) -> Result<Vec<Tx>, EsploraError> {
+        let mut related_txs = self._scripthash_txs(script, None).await?;
+
+        let n_confirmed = related_txs.iter().filter(|tx| tx.status.confirmed).count();
+        // esplora pages on 25 confirmed transactions. If there's 25 or more we
+        // keep requesting to see if there's more.
+        if n_confirmed >= 25 {
+            loop {
+                let new_related_txs = self
+                    ._scripthash_txs(script, Some(related_txs.last().unwrap().txid))
+                    .await?;
+                let n = new_related_txs.len();
+                related_txs.extend(new_related_txs);
+                // we've reached the end
+                if n < 25 {
+                    break;
+                }
+            }
+        }
+
+        Ok(related_txs)
+    }
+
+    async fn _get_mining_hashrate(&self) -> Result<Option<MiningHashrate>, EsploraError> {
+        let resp = self
+            .get_once(&format!("{}/v1/mining/hashrate/3d", self.url))
+            .await?;
+
+        if let StatusCode::NOT_FOUND = resp.status() {
+            return Ok(None);
+        }
+
+        Ok(Some(
+            self.read_json(self.error_for_status(resp).await?).await?,
+        ))
     }
 
     async fn _get_fee_estimates(&self) -> Result<HashMap<String, f64>, EsploraError> {
-        Ok(self
-            .client
-            .get(&format!("{}/fee-estimates", self.url,))
-            .send()
-            .await?
-            .error_for_status()?
-            .json::<HashMap<String, f64>>()
-            .await?)
+        let resp = self
+            .get_once(&format!("{}/fee-estimates", self.url,))
+            .await?;
+        let resp = self.error_for_status(resp).await?;
+
+        self.read_json::<HashMap<String, f64>>(resp).await
+    }
+
+    /// Send a GET request to `url`, without retrying it
+    async fn get_once(&self, url: &str) -> Result<Response, reqwest::Error> {
+        self.get_once_if_none_match(url, None).await
+    }
+
+    /// Send a GET request to `url` with an optional `If-None-Match` header, without retrying it
+    async fn get_once_if_none_match(
+        &self,
+        url: &str,
+        etag: Option<&str>,
+    ) -> Result<Response, reqwest::Error> {
+        self.wait_rate_limiter().await;
+        self.requests_sent.fetch_add(1, Ordering::Relaxed);
+        let mut request = self.client.get(url);
+        if let Some(etag) = etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        let start = Instant::new();
+        let resp = request.send().await;
+        self.record_latency("GET", url, start);
+        resp
+    }
+
+    /// Wait until the rate limiter, if any, allows sending a request
+    async fn wait_rate_limiter(&self) {
+        if let Some(limiter) = &self.rate_limiter {
+            limiter.wait_async().await;
+        }
+    }
+
+    /// Fetch the body of the immutable resource at `url`, `None` if it doesn't exist
+    ///
+    /// With a response cache, the body of a cached response is reused if the server replies that
+    /// it wasn't modified.
+    async fn get_immutable(&self, url: &str, retry: bool) -> Result<Option<Vec<u8>>, EsploraError> {
+        let cached = self
+            .response_cache
+            .as_ref()
+            .and_then(|cache| cache.get(url));
+        let etag = cached.as_ref().map(|(etag, _)| etag.as_str());
+        let resp = if retry {
+            self.get_if_none_match(url, etag).await?
+        } else {
+            self.get_once_if_none_match(url, etag).await?
+        };
+
+        match (resp.status(), cached) {
+            (StatusCode::NOT_FOUND, _) => return Ok(None),
+            (StatusCode::NOT_MODIFIED, Some((_, body))) => return Ok(Some(body)),
+            _ => {}
+        }
+
+        let etag = resp
+            .headers()
+            .get(ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(str::to_string);
+        let body = self.read_bytes(self.error_for_status(resp).await?).await?;
+        if let (Some(cache), Some(etag)) = (&self.response_cache, etag) {
+            cache.insert(url, etag, body.clone());
+        }
+
+        Ok(Some(body))
+    }
+
+    /// Record the latency of a request sent to `url` at `start`
+    fn record_latency(&self, method: &str, url: &str, start: Instant) {
+        let category = EndpointCategory::from_request(&self.url, method, url);
+        self.latencies.record(category, start.elapsed());
+    }
+
+    /// Send a GET request to `url`, retrying up to `self.retry` times after a transport error or a
+    /// 5xx response
+    async fn get(&self, url: &str) -> Result<Response, reqwest::Error> {
+        self.get_if_none_match(url, None).await
+    }
+
+    /// Like [`UrlClient::get`], with an optional `If-None-Match` header
+    async fn get_if_none_match(
+        &self,
+        url: &str,
+        etag: Option<&str>,
+    ) -> Result<Response, reqwest::Error> {
+        let mut attempt = 0;
+        loop {
+            let resp = self.get_once_if_none_match(url, etag).await;
+            let retryable = match &resp {
+                Ok(resp) => is_retryable_status(resp.status().as_u16()),
+                Err(e) => !e.is_builder(),
+            };
+            if !retryable || attempt >= self.retry {
+                return resp;
+            }
+
+            debug!("Retrying request to {} after a failed attempt", url);
+            crate::wallet::time::sleep(super::retry_delay(attempt)).await;
+            attempt += 1;
+        }
+    }
+
+    /// Fail with [`EsploraError::HttpResponse`] if the status of `resp` is a client or server
+    /// error, reading at most [`MAX_ERROR_MESSAGE_BYTES`] of the body as the message
+    async fn error_for_status(&self, resp: Response) -> Result<Response, EsploraError> {
+        let status = resp.status();
+        if !status.is_client_error() && !status.is_server_error() {
+            return Ok(resp);
+        }
+
+        // `fetch` doesn't let us read the body incrementally on wasm32
+        #[cfg(target_arch = "wasm32")]
+        let body = resp
+            .bytes()
+            .await
+            .map(|body| body.to_vec())
+            .unwrap_or_default();
+        #[cfg(not(target_arch = "wasm32"))]
+        let body = {
+            let mut resp = resp;
+            let mut body = vec![];
+            while body.len() < MAX_ERROR_MESSAGE_BYTES {
+                match resp.chunk().await {
+                    Ok(Some(chunk)) => body.extend_from_slice(&chunk),
+                    _ => break,
+                }
+            }
+            body
+        };
+
+        self.bytes_received
+            .fetch_add(body.len() as u64, Ordering::Relaxed);
+        Err(http_response_error(status.as_u16(), &body))
+    }
+
+    /// Read the body of `resp`, stopping as soon as it's larger than `max_response_bytes`
+    async fn read_bytes(&self, resp: Response) -> Result<Vec<u8>, EsploraError> {
+        let max_bytes = self.max_response_bytes.unwrap_or(usize::MAX);
+        if matches!(resp.content_length(), Some(len) if len > max_bytes as u64) {
+            return Err(EsploraError::ResponseTooLarge(max_bytes));
+        }
+
+        // `fetch` doesn't let us read the body incrementally on wasm32
+        #[cfg(target_arch = "wasm32")]
+        let buf = resp.bytes().await?.to_vec();
+        #[cfg(not(target_arch = "wasm32"))]
+        let buf = {
+            let mut resp = resp;
+            let mut buf = vec![];
+            while let Some(chunk) = resp.chunk().await? {
+                if buf.len() + chunk.len() > max_bytes {
+                    return Err(EsploraError::ResponseTooLarge(max_bytes));
+                }
+                buf.extend_from_slice(&chunk);
+            }
+            buf
+        };
+
+        if buf.len() > max_bytes {
+            return Err(EsploraError::ResponseTooLarge(max_bytes));
+        }
+
+        self.bytes_received
+            .fetch_add(buf.len() as u64, Ordering::Relaxed);
+        Ok(buf)
     }
+
+    async fn read_string(&self, resp: Response) -> Result<String, EsploraError> {
+        String::from_utf8(self.read_bytes(resp).await?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e).into())
+    }
+
+    async fn read_json<T: DeserializeOwned>(&self, resp: Response) -> Result<T, EsploraError> {
+        let bytes = self.read_bytes(resp).await?;
+        Ok(serde_json::from_slice(&bytes).map_err(io::Error::from)?)
+    }
+}
+
+/// Parse a block header from the hex encoded body of a response
+fn parse_header(body: Vec<u8>) -> Result<BlockHeader, EsploraError> {
+    let hex = String::from_utf8(body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(deserialize(&Vec::from_hex(&hex)?)?)
 }
 
 impl ConfigurableBlockchain for EsploraBlockchain {
@@ -320,10 +1791,41 @@ impl ConfigurableBlockchain for EsploraBlockchain {
     fn from_config(config: &Self::Config) -> Result<Self, Error> {
         let map_e = |e: reqwest::Error| Error::Esplora(Box::new(e.into()));
 
+        if config.record_har == Some(true) {
+            return Err(Error::Generic(
+                "Recording a HAR is only supported by the ureq client".to_string(),
+            ));
+        }
+
         let mut blockchain = EsploraBlockchain::new(config.base_url.as_str(), config.stop_gap);
+        blockchain.internal_stop_gap = config.internal_stop_gap;
         if let Some(concurrency) = config.concurrency {
             blockchain.url_client.concurrency = concurrency;
         }
+        blockchain.url_client.prevout_concurrency = config.prevout_concurrency;
+        blockchain.sync_error_policy = config.sync_error_policy;
+        if let Some(verify_block_hashes) = config.verify_block_hashes {
+            blockchain.verify_block_hashes = verify_block_hashes;
+        }
+
+        blockchain.validate_proofs = config.validate_proofs;
+        blockchain.url_client.max_response_bytes = config.max_response_bytes;
+        if let Some(sync_mode) = config.sync_mode {
+            blockchain.sync_mode = sync_mode;
+        }
+        if let Some(fee_cache_ttl) = config.fee_cache_ttl {
+            blockchain.fee_cache.ttl = Some(Duration::from_secs(fee_cache_ttl));
+        }
+        if let Some(flavor) = config.flavor {
+            blockchain.flavor = flavor;
+        }
+        if let Some(retry) = config.retry {
+            blockchain.url_client.retry = retry;
+        }
+        blockchain.max_requests_per_sync = config.max_requests_per_sync;
+        if let Some(max_entries) = config.response_cache_entries {
+            blockchain = blockchain.with_response_cache(max_entries);
+        }
         let mut builder = Client::builder();
         #[cfg(not(target_arch = "wasm32"))]
         if let Some(proxy) = &config.proxy {
@@ -335,8 +1837,224 @@ impl ConfigurableBlockchain for EsploraBlockchain {
             builder = builder.timeout(core::time::Duration::from_secs(timeout));
         }
 
+        if let Some(headers) = &config.headers {
+            let mut header_map = HeaderMap::new();
+            for (name, value) in headers {
+                let invalid =
+                    || Error::Esplora(Box::new(EsploraError::InvalidHeader(name.clone())));
+                header_map.insert(
+                    HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid())?,
+                    HeaderValue::from_str(value).map_err(|_| invalid())?,
+                );
+            }
+            builder = builder.default_headers(header_map);
+        }
+
         blockchain.url_client.client = builder.build().map_err(map_e)?;
 
         Ok(blockchain)
     }
 }
+
+#[cfg(test)]
+#[cfg(not(feature = "async-interface"))]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{channel, Receiver};
+
+    use super::*;
+    use crate::blockchain::esplora::test::mock::{mock_server, mock_status_server};
+    use crate::blockchain::esplora::EsploraBlockchainConfig;
+
+    /// Serve `tx` at `/tx/:txid/raw` with an `ETag`, replying `304 Not Modified` to the requests
+    /// carrying it in `If-None-Match`. The status of every response is sent to the receiver.
+    fn mock_etag_server(tx: Transaction) -> (String, Receiver<u16>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, receiver) = channel();
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8_lossy(&request).to_lowercase();
+                assert!(request.starts_with(&format!("get /tx/{}/raw ", tx.txid())));
+
+                let response = if request.contains("if-none-match: \"v1\"\r\n") {
+                    let _ = sender.send(304);
+                    "HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nConnection: close\r\n\r\n"
+                        .as_bytes()
+                        .to_vec()
+                } else {
+                    let _ = sender.send(200);
+                    let body = serialize(&tx);
+                    let mut response = format!(
+                        "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    )
+                    .into_bytes();
+                    response.extend(body);
+                    response
+                };
+                let _ = stream.write_all(&response);
+            }
+        });
+
+        (url, receiver)
+    }
+
+    fn tx() -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![bitcoin::TxIn::default()],
+            output: vec![bitcoin::TxOut {
+                value: 10_000,
+                script_pubkey: Script::new(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_response_cache_etag() {
+        let tx = tx();
+        let (url, statuses) = mock_etag_server(tx.clone());
+        let blockchain = EsploraBlockchain::new(&url, 20).with_response_cache(10);
+
+        assert_eq!(blockchain.get_tx(&tx.txid()).unwrap(), Some(tx.clone()));
+        assert_eq!(statuses.recv().unwrap(), 200);
+        let bytes_received = blockchain.url_client.bytes_received.load(Ordering::Relaxed);
+        assert!(bytes_received > 0);
+
+        // the second request is revalidated and the cached body reused
+        assert_eq!(blockchain.get_tx(&tx.txid()).unwrap(), Some(tx.clone()));
+        assert_eq!(statuses.recv().unwrap(), 304);
+        assert_eq!(
+            blockchain.url_client.bytes_received.load(Ordering::Relaxed),
+            bytes_received
+        );
+    }
+
+    #[test]
+    fn test_response_cache_disabled() {
+        let tx = tx();
+        let (url, statuses) = mock_etag_server(tx.clone());
+        let blockchain = EsploraBlockchain::new(&url, 20);
+
+        for _ in 0..2 {
+            assert_eq!(blockchain.get_tx(&tx.txid()).unwrap(), Some(tx.clone()));
+            assert_eq!(statuses.recv().unwrap(), 200);
+        }
+    }
+
+    #[test]
+    fn test_response_cache_eviction() {
+        let cache = ResponseCache::new(2);
+        cache.insert("a", "1".to_string(), vec![1]);
+        cache.insert("b", "2".to_string(), vec![2]);
+        // replacing an entry doesn't change its position
+        cache.insert("a", "3".to_string(), vec![3]);
+        cache.insert("c", "4".to_string(), vec![4]);
+
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(cache.get("b"), Some(("2".to_string(), vec![2])));
+        assert_eq!(cache.get("c"), Some(("4".to_string(), vec![4])));
+    }
+
+    #[test]
+    fn test_retry() {
+        let tx = tx();
+        let retrying = |url: String, retry: Option<u8>| {
+            let mut config = EsploraBlockchainConfig::new(url, 20);
+            config.retry = retry;
+            EsploraBlockchain::from_config(&config).unwrap()
+        };
+
+        let (url, requests) = mock_status_server(vec![(503, String::new()); 2], serialize(&tx));
+        assert_eq!(
+            retrying(url, Some(2)).get_tx(&tx.txid()).unwrap(),
+            Some(tx.clone())
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        // no retries by default
+        let (url, requests) = mock_status_server(vec![(503, String::new()); 2], serialize(&tx));
+        match retrying(url, None).get_tx(&tx.txid()) {
+            Err(Error::Esplora(e)) => {
+                assert!(matches!(*e, EsploraError::HttpResponse { status: 503, .. }))
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // a 404 is a valid answer and is never retried
+        let (url, requests) = mock_status_server(vec![(404, String::new())], serialize(&tx));
+        assert_eq!(retrying(url, Some(2)).get_tx(&tx.txid()).unwrap(), None);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_http_response_message() {
+        let get_error = |message: String| {
+            let (url, _) = mock_status_server(vec![(429, message)], b"101".to_vec());
+            match EsploraBlockchain::new(&url, 20).get_height() {
+                Err(Error::Esplora(e)) => *e,
+                r => panic!("unexpected result: {:?}", r),
+            }
+        };
+
+        let error = get_error("rate limited".to_string());
+        assert_eq!(error.to_string(), "HTTP error 429: rate limited");
+        match error {
+            EsploraError::HttpResponse { status, message } => {
+                assert_eq!(status, 429);
+                assert_eq!(message, "rate limited");
+            }
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        // long bodies are truncated
+        match get_error("x".repeat(4_096)) {
+            EsploraError::HttpResponse { message, .. } => assert_eq!(message.len(), 1_024),
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_max_response_bytes() {
+        let url = mock_server(vec![b'1'; 16 * 1_024 * 1_024]);
+        let blockchain = EsploraBlockchain::new(&url, 20).with_max_response_bytes(1_024);
+        match blockchain.get_height() {
+            Err(Error::Esplora(e)) => {
+                assert!(matches!(*e, EsploraError::ResponseTooLarge(1_024)))
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        let url = mock_server(b"101".to_vec());
+        let blockchain = EsploraBlockchain::new(&url, 20).with_max_response_bytes(1_024);
+        assert_eq!(blockchain.get_height().unwrap(), 101);
+    }
+
+    #[test]
+    fn test_record_har_unsupported() {
+        let mut config = EsploraBlockchainConfig::new("http://127.0.0.1:1".to_string(), 20);
+        config.record_har = Some(false);
+        assert!(EsploraBlockchain::from_config(&config).is_ok());
+
+        config.record_har = Some(true);
+        assert!(matches!(
+            EsploraBlockchain::from_config(&config),
+            Err(Error::Generic(_))
+        ));
+    }
+}
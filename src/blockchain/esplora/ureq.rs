@@ -11,43 +11,77 @@
 
 //! Esplora by way of `ureq` HTTP client.
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::io;
 use std::io::Read;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::JoinHandle;
 use std::time::Duration;
 
 #[allow(unused_imports)]
 use log::{debug, error, info, trace};
 
-use ureq::{Agent, Proxy, Response};
+use serde::de::DeserializeOwned;
+use ureq::{Agent, Proxy, Request, Response};
 
 use bitcoin::consensus::{deserialize, serialize};
 use bitcoin::hashes::hex::{FromHex, ToHex};
 use bitcoin::hashes::{sha256, Hash};
-use bitcoin::{BlockHeader, Script, Transaction, Txid};
+use bitcoin::{Block, BlockHash, BlockHeader, Network, OutPoint, Script, Transaction, Txid};
 
-use super::api::Tx;
+use super::api::{MempoolInfo, MerkleProof, MiningHashrate, OutSpend, Tx, TxStatus};
+use super::{
+    block_scan_range, http_response_error, is_provable, is_retryable_status, proof_matches_header,
+    retry_delay, verify_merkle_proof, BlockScanner, ConfirmationPath, EndpointCategory,
+    EsploraFlavor, FeeCache, HeaderChain, LatencyRecorder, LatencyReport, MempoolRelations,
+    MempoolTx, OutputStatus, SyncMode, BLOCK_SCAN_CHUNK_SIZE, MAX_ERROR_MESSAGE_BYTES,
+    MAX_MEMPOOL_RELATIONS_DEPTH,
+};
 use crate::blockchain::esplora::EsploraError;
 use crate::blockchain::*;
-use crate::database::BatchDatabase;
+use crate::database::{BatchDatabase, BatchOperations};
+use crate::descriptor::ExtendedDescriptor;
 use crate::error::Error;
-use crate::FeeRate;
+use crate::wallet::time::{get_timestamp, Instant};
+use crate::{BlockTime, FeeRate};
 
 #[derive(Debug, Clone)]
 struct UrlClient {
     url: String,
     agent: Agent,
+    max_response_bytes: Option<usize>,
+    retry: u8,
+    headers: Vec<(String, String)>,
+    // shared between the clones of the client
+    bytes_received: Arc<AtomicU64>,
+    requests_sent: Arc<AtomicUsize>,
+    latencies: Arc<LatencyRecorder>,
+    har: Option<Arc<HarRecorder>>,
+    rate_limiter: Option<RateLimiter>,
 }
 
 /// Structure that implements the logic to sync with Esplora
 ///
 /// ## Example
 /// See the [`blockchain::esplora`](crate::blockchain::esplora) module for a usage example.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct EsploraBlockchain {
     url_client: UrlClient,
     stop_gap: usize,
+    internal_stop_gap: Option<usize>,
     concurrency: u8,
+    sync_error_policy: SyncErrorPolicy,
+    verify_block_hashes: bool,
+    validate_proofs: bool,
+    trusted_headers: Option<Arc<RwLock<HeaderChain>>>,
+    ownership_resolver: Option<OwnershipResolver>,
+    prevout_concurrency: Option<u8>,
+    sync_mode: SyncMode,
+    fee_cache: FeeCache,
+    flavor: EsploraFlavor,
+    max_requests_per_sync: Option<usize>,
+    shared_tx_cache: Option<SharedTxCache>,
 }
 
 impl EsploraBlockchain {
@@ -57,9 +91,29 @@ impl EsploraBlockchain {
             url_client: UrlClient {
                 url: base_url.to_string(),
                 agent: Agent::new(),
+                max_response_bytes: None,
+                retry: 0,
+                headers: Vec::new(),
+                bytes_received: Arc::new(AtomicU64::new(0)),
+                requests_sent: Arc::new(AtomicUsize::new(0)),
+                latencies: Arc::new(LatencyRecorder::default()),
+                har: None,
+                rate_limiter: None,
             },
             concurrency: super::DEFAULT_CONCURRENT_REQUESTS,
             stop_gap,
+            internal_stop_gap: None,
+            sync_error_policy: SyncErrorPolicy::default(),
+            verify_block_hashes: false,
+            validate_proofs: false,
+            trusted_headers: None,
+            ownership_resolver: None,
+            prevout_concurrency: None,
+            sync_mode: SyncMode::default(),
+            fee_cache: FeeCache::default(),
+            flavor: EsploraFlavor::default(),
+            max_requests_per_sync: None,
+            shared_tx_cache: None,
         }
     }
 
@@ -74,233 +128,1406 @@ impl EsploraBlockchain {
         self.concurrency = concurrency;
         self
     }
-}
 
-impl Blockchain for EsploraBlockchain {
-    fn get_capabilities(&self) -> HashSet<Capability> {
-        vec![
-            Capability::FullHistory,
-            Capability::GetAnyTx,
-            Capability::AccurateFees,
-        ]
-        .into_iter()
-        .collect()
+    /// Set the number of parallel requests made to fetch the transactions spent by the wallet
+    /// transactions, when the server doesn't include the previous outputs in its responses
+    ///
+    /// Defaults to the concurrency set with [`EsploraBlockchain::with_concurrency`].
+    pub fn with_prevout_concurrency(mut self, prevout_concurrency: u8) -> Self {
+        self.prevout_concurrency = Some(prevout_concurrency);
+        self
     }
 
-    fn broadcast(&self, tx: &Transaction) -> Result<(), Error> {
-        let _txid = self.url_client._broadcast(tx)?;
-        Ok(())
+    /// Set the maximum size of the responses read from the server, in bytes
+    ///
+    /// Reading a response larger than this is aborted with [`EsploraError::ResponseTooLarge`].
+    pub fn with_max_response_bytes(mut self, max_response_bytes: usize) -> Self {
+        self.url_client.max_response_bytes = Some(max_response_bytes);
+        self
     }
 
-    fn estimate_fee(&self, target: usize) -> Result<FeeRate, Error> {
-        let estimates = self.url_client._get_fee_estimates()?;
-        super::into_fee_rate(target, estimates)
+    /// Set how many times a request for transactions, script pubkey histories or block hashes is
+    /// retried after a transport error or a 5xx response (default: 0)
+    ///
+    /// The delay between attempts starts at 250ms and doubles after every failure, up to 30s.
+    pub fn with_retry(mut self, retry: u8) -> Self {
+        self.url_client.retry = retry;
+        self
     }
-}
 
-impl StatelessBlockchain for EsploraBlockchain {}
+    /// Set whether to record the requests sent to the server and their responses, to export them
+    /// with [`EsploraBlockchain::export_har`] (default: false)
+    ///
+    /// Every request is kept in memory until the blockchain and its clones are dropped, so this
+    /// should only be enabled to debug an issue.
+    pub fn with_record_har(mut self, record_har: bool) -> Self {
+        self.url_client.har = if record_har {
+            Some(Arc::new(HarRecorder::default()))
+        } else {
+            None
+        };
+        self
+    }
 
-impl GetHeight for EsploraBlockchain {
-    fn get_height(&self) -> Result<u32, Error> {
-        Ok(self.url_client._get_height()?)
+    /// Set the maximum number of requests made by a sync
+    ///
+    /// Once the limit is reached no other script pubkey is queried, and the sync completes the
+    /// transactions found so far without deleting any existing one. This is reported by
+    /// [`SyncStats::request_budget_exhausted`]. The requests needed to complete those transactions
+    /// and to store the hashes of the blocks confirming them are still made, so the limit can be
+    /// slightly exceeded. It doesn't apply to [`SyncMode::BlockScan`].
+    pub fn with_max_requests_per_sync(mut self, max_requests_per_sync: usize) -> Self {
+        self.max_requests_per_sync = Some(max_requests_per_sync);
+        self
     }
-}
 
-impl GetTx for EsploraBlockchain {
-    fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, Error> {
-        Ok(self.url_client._get_tx(txid)?)
+    /// Set the stop gap of the internal (change) keychain, which defaults to the stop gap of the
+    /// external one
+    ///
+    /// Change addresses are handed out in order, so they rarely need a gap as large as the
+    /// external ones.
+    pub fn with_internal_stop_gap(mut self, internal_stop_gap: usize) -> Self {
+        self.internal_stop_gap = Some(internal_stop_gap);
+        self
     }
-}
 
-impl WalletSync for EsploraBlockchain {
-    fn wallet_setup<D: BatchDatabase>(
+    /// Set the policy used to handle errors while fetching the history of a script pubkey.
+    pub fn with_sync_error_policy(mut self, sync_error_policy: SyncErrorPolicy) -> Self {
+        self.sync_error_policy = sync_error_policy;
+        self
+    }
+
+    /// Set whether to check that the blocks confirming the wallet transactions are still part of
+    /// the best chain during sync (default: false)
+    ///
+    /// Transactions reported in a block that was reorged out are treated as unconfirmed. This costs
+    /// one more request for each height at which the wallet transactions are confirmed.
+    pub fn with_verify_block_hashes(mut self, verify_block_hashes: bool) -> Self {
+        self.verify_block_hashes = verify_block_hashes;
+        self
+    }
+
+    /// Set whether to check the merkle proof of every newly confirmed transaction against the
+    /// trusted header of its block during sync (default: false)
+    ///
+    /// The headers are taken from the [`HeaderChain`] set with
+    /// [`EsploraBlockchain::with_trusted_headers`], never from the server. This costs an extra
+    /// request per confirmed transaction, and makes the sync fail with
+    /// [`Error::InvalidMerkleProof`] if the server reports a confirmation it can't prove, or with
+    /// [`Error::MissingTrustedHeader`] if the header of the block isn't trusted.
+    pub fn with_validate_proofs(mut self, validate_proofs: bool) -> Self {
+        self.validate_proofs = validate_proofs;
+        self
+    }
+
+    /// Set the headers used to check the merkle proofs returned by the server, see
+    /// [`EsploraBlockchain::with_validate_proofs`]
+    ///
+    /// The chain is shared with the caller, who can keep extending it as new headers are verified.
+    pub fn with_trusted_headers(mut self, headers: Arc<RwLock<HeaderChain>>) -> Self {
+        self.trusted_headers = Some(headers);
+        self
+    }
+
+    /// Set a custom resolver consulted during sync to decide which outputs belong to the wallet
+    pub fn with_ownership_resolver(mut self, ownership_resolver: OwnershipResolver) -> Self {
+        self.ownership_resolver = Some(ownership_resolver);
+        self
+    }
+
+    /// Set how the transactions of the wallet are discovered during sync
+    pub fn with_sync_mode(mut self, sync_mode: SyncMode) -> Self {
+        self.sync_mode = sync_mode;
+        self
+    }
+
+    /// Set the implementation of the API exposed by the server
+    pub fn with_flavor(mut self, flavor: EsploraFlavor) -> Self {
+        self.flavor = flavor;
+        self
+    }
+
+    /// Reuse the fee estimates of the server for `ttl` instead of fetching them for every call to
+    /// [`Blockchain::estimate_fee`]
+    ///
+    /// The cached estimates can be persisted across restarts with
+    /// [`EsploraBlockchain::dump_fee_cache`] and [`EsploraBlockchain::load_fee_cache`].
+    pub fn with_fee_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.fee_cache.ttl = Some(ttl);
+        self
+    }
+
+    /// Serialize the cached fee estimates, along with the time they were fetched at
+    ///
+    /// Returns `None` if no estimate was cached yet.
+    pub fn dump_fee_cache(&self) -> Result<Option<String>, Error> {
+        self.fee_cache.dump()
+    }
+
+    /// Restore the fee estimates serialized with [`EsploraBlockchain::dump_fee_cache`]
+    ///
+    /// Estimates older than the TTL set with [`EsploraBlockchain::with_fee_cache_ttl`] are
+    /// ignored, as are all estimates if no TTL was set.
+    pub fn load_fee_cache(&self, data: &str) -> Result<(), Error> {
+        self.fee_cache.load(data)
+    }
+
+    /// Fetch the fee rates estimated by the server for all the confirmation targets it supports,
+    /// sorted by target (blocks)
+    ///
+    /// A single request is made, which is useful to let users pick a confirmation target. The
+    /// estimates are cached like the ones used by [`Blockchain::estimate_fee`], see
+    /// [`EsploraBlockchain::with_fee_cache_ttl`].
+    pub fn fee_estimates(&self) -> Result<BTreeMap<usize, FeeRate>, Error> {
+        let estimates = match self.fee_cache.get() {
+            Some(estimates) => estimates,
+            None => {
+                let estimates = self.url_client._get_fee_estimates()?;
+                self.fee_cache.insert(&estimates);
+                estimates
+            }
+        };
+
+        Ok(super::into_fee_rates(estimates))
+    }
+
+    /// Return the absolute fee paid by a transaction (sats)
+    ///
+    /// Returns `None` if the transaction is unknown to the server or if it's a coinbase
+    /// transaction.
+    pub fn get_tx_fee(&self, txid: &Txid) -> Result<Option<u64>, Error> {
+        Ok(self
+            .url_client
+            ._get_tx_info(txid)?
+            .filter(|tx| !tx.is_coinbase())
+            .map(|tx| tx.fee))
+    }
+
+    /// Estimate the number of blocks until the unconfirmed transaction `txid` confirms
+    ///
+    /// The feerate of the transaction is compared to the current fee histogram of the mempool of
+    /// the server, assuming every transaction paying more is mined first. Returns `None` if the
+    /// transaction is already confirmed.
+    pub fn estimate_time_to_confirm(&self, txid: &Txid) -> Result<Option<usize>, Error> {
+        let tx = self.url_client._get_tx_info_no_opt(txid)?;
+        if tx.status.confirmed {
+            return Ok(None);
+        }
+        let mempool = self.url_client._get_mempool_info()?;
+
+        Ok(Some(super::blocks_to_confirm(
+            tx.fee,
+            tx.vsize(),
+            &mempool.fee_histogram,
+        )))
+    }
+
+    /// Return whether any of the first `stop_gap` script pubkeys of `descriptor` has a history
+    ///
+    /// This quickly confirms that a descriptor, or the seed it was derived from, was entered
+    /// correctly before a full sync. A descriptor without a wildcard has a single script pubkey.
+    /// Fails if the keys of the descriptor aren't valid for `network`.
+    ///
+    /// The script pubkeys are queried one at a time, stopping at the first used one.
+    pub fn verify_descriptor_has_funds(
+        &self,
+        descriptor: &ExtendedDescriptor,
+        network: Network,
+        stop_gap: usize,
+    ) -> Result<bool, Error> {
+        for script in descriptor_script_pubkeys(descriptor, network, stop_gap)? {
+            if !self.url_client._scripthash_txs(&script, None)?.is_empty() {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Return the fee histogram of the mempool of the server
+    ///
+    /// Every `(fee_rate, vsize)` pair is the total vsize of the transactions paying at least
+    /// `fee_rate` but less than the fee rate of the previous pair, sorted by decreasing fee rate.
+    pub fn fee_histogram(&self) -> Result<Vec<(FeeRate, usize)>, Error> {
+        let mempool = self.url_client._get_mempool_info()?;
+        Ok(into_fee_histogram(mempool.fee_histogram))
+    }
+
+    /// Estimate the position of the unconfirmed transaction `txid` in the mempool of the server
+    ///
+    /// The position is the total vsize of the transactions paying a higher feerate, from the
+    /// current fee histogram of the mempool: `0` means that the transaction is first in line to be
+    /// mined. Returns `None` if the transaction is already confirmed.
+    pub fn get_mempool_position(&self, txid: &Txid) -> Result<Option<usize>, Error> {
+        let tx = self.url_client._get_tx_info_no_opt(txid)?;
+        if tx.status.confirmed {
+            return Ok(None);
+        }
+        let mempool = self.url_client._get_mempool_info()?;
+
+        Ok(Some(
+            super::vsize_ahead(tx.fee, tx.vsize(), &mempool.fee_histogram) as usize,
+        ))
+    }
+
+    /// Find an unconfirmed transaction that spends any of the `outpoints` and signals RBF
+    ///
+    /// This helps locating the live transaction to replace when it's not known whether a
+    /// transaction is still in the mempool. Only explicit signaling (BIP125) is considered, a
+    /// transaction that is replaceable only because of its unconfirmed ancestors isn't returned.
+    pub fn find_replaceable_tx(&self, outpoints: &[OutPoint]) -> Result<Option<Txid>, Error> {
+        for outpoint in outpoints {
+            if let Some(txid) = self.url_client._get_unconfirmed_spend(outpoint)? {
+                if self.url_client._get_tx_info_no_opt(&txid)?.signals_rbf() {
+                    return Ok(Some(txid));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Refresh the spent status of the wallet UTXOs at `outpoints` in `database`
+    ///
+    /// This only looks up whether each output was spent, which is much cheaper than a full sync
+    /// when the wallet just needs to check some specific coins (e.g. in cold storage) weren't
+    /// moved. Outpoints that aren't UTXOs of `database` are ignored. Spending transactions aren't
+    /// downloaded, they will be found by the next full sync.
+    pub fn sync_outpoints<D: BatchDatabase>(
         &self,
         database: &mut D,
-        _progress_update: Box<dyn Progress>,
+        outpoints: &[OutPoint],
     ) -> Result<(), Error> {
+        let mut batch = database.begin_batch();
+        for outpoint in outpoints {
+            let mut utxo = match database.get_utxo(outpoint)? {
+                Some(utxo) => utxo,
+                None => {
+                    debug!("Ignoring outpoint {} not owned by the wallet", outpoint);
+                    continue;
+                }
+            };
+
+            let spent = self.url_client._get_outspend(outpoint)?.spent;
+            if utxo.is_spent != spent {
+                debug!("Updating outpoint {}: spent = {}", outpoint, spent);
+                utxo.is_spent = spent;
+                batch.set_utxo(&utxo)?;
+            }
+        }
+
+        database.commit_batch(batch)
+    }
+
+    /// Refresh the spent status of all the unspent UTXOs of `database`, returning the outpoints
+    /// that were spent since the last sync
+    ///
+    /// The outspends of the transactions of the UTXOs are fetched, making at most `concurrency`
+    /// requests in parallel. Like [`EsploraBlockchain::sync_outpoints`], this is a much cheaper
+    /// alternative to a full sync for a wallet that just monitors its balance, but the spending
+    /// transactions aren't downloaded.
+    pub fn refresh_utxo_spent_status<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+    ) -> Result<Vec<OutPoint>, Error> {
+        let unspent = database
+            .iter_utxos()?
+            .into_iter()
+            .filter(|utxo| !utxo.is_spent)
+            .collect::<Vec<_>>();
+        let txids = unspent
+            .iter()
+            .map(|utxo| utxo.outpoint.txid)
+            .collect::<HashSet<_>>();
+        let outspends = self
+            .url_client
+            ._get_txs_outspends(txids.into_iter().collect(), self.concurrency)?;
+
+        let mut batch = database.begin_batch();
+        let mut spent = vec![];
+        for mut utxo in unspent {
+            let is_spent = outspends
+                .get(&utxo.outpoint.txid)
+                .and_then(|outspends| outspends.get(utxo.outpoint.vout as usize))
+                .map_or(false, |outspend| outspend.spent);
+            if is_spent {
+                debug!("UTXO {} was spent", utxo.outpoint);
+                utxo.is_spent = true;
+                batch.set_utxo(&utxo)?;
+                spent.push(utxo.outpoint);
+            }
+        }
+        database.commit_batch(batch)?;
+
+        Ok(spent)
+    }
+
+    /// Return the script pubkeys that a sync of `database` would query, without making any request
+    /// to the server
+    ///
+    /// The script pubkeys phase of the sync is run against the transactions already in
+    /// `database`, in batches of [`concurrency`](EsploraBlockchain::with_concurrency) script
+    /// pubkeys, which shows how far the stop gap takes the scan of each keychain. It doesn't apply
+    /// to [`SyncMode::BlockScan`], which doesn't query script pubkeys.
+    pub fn plan_sync<D: BatchDatabase>(&self, database: &D) -> Result<SyncPlan, Error> {
+        script_sync::plan(
+            database,
+            script_sync::StopGap::new(self.stop_gap, self.internal_stop_gap),
+            self.concurrency as usize,
+        )
+    }
+
+    /// Fetch the unconfirmed ancestors and descendants of a transaction, along with their fees and
+    /// virtual sizes
+    ///
+    /// The graph of unconfirmed transactions is walked for at most 25 generations in each
+    /// direction. This is useful to compute the feerate of the whole package before bumping the
+    /// fees of `txid`.
+    pub fn get_mempool_ancestors_descendants(
+        &self,
+        txid: &Txid,
+    ) -> Result<MempoolRelations, Error> {
+        Ok(self.url_client._get_mempool_relations(txid)?)
+    }
+
+    fn prevout_concurrency(&self) -> u8 {
+        self.prevout_concurrency.unwrap_or(self.concurrency)
+    }
+
+    /// Number of requests the current sync can still make, `None` if there's no limit
+    ///
+    /// Only meaningful on the copy of the blockchain made for the sync, whose client counts the
+    /// requests from zero.
+    fn remaining_requests(&self) -> Option<usize> {
+        self.max_requests_per_sync.map(|max_requests| {
+            max_requests.saturating_sub(self.url_client.requests_sent.load(Ordering::Relaxed))
+        })
+    }
+
+    /// Check the merkle proofs of the transactions confirmed according to `conftimes` against the
+    /// trusted headers, see [`EsploraBlockchain::with_validate_proofs`]
+    fn check_proofs(&self, conftimes: &[(Txid, Option<BlockTime>)]) -> Result<(), Error> {
+        for (txid, conftime) in conftimes {
+            let height = match conftime {
+                Some(conftime) => conftime.height,
+                None => continue,
+            };
+            let header = trusted_header(&self.trusted_headers, height)?;
+            if !self.url_client._verify_tx_proof(txid, height, &header)? {
+                return Err(Error::InvalidMerkleProof(*txid));
+            }
+        }
+        Ok(())
+    }
+
+    /// Sync by downloading blocks instead of querying the history of every script pubkey, see
+    /// [`SyncMode::BlockScan`]
+    fn block_scan<D: BatchDatabase>(
+        &self,
+        database: &D,
+        birth_height: u32,
+        stats: &mut SyncStats,
+        progress: &dyn Progress,
+    ) -> Result<D::Batch, Error> {
         use crate::blockchain::script_sync::Request;
-        let mut request = script_sync::start(database, self.stop_gap)?;
+
+        let tip_height = self.url_client._get_height()?;
+        let mut heights = block_scan_range(database, birth_height, tip_height)?;
+        let start_height = *heights.start();
+        let total_blocks = (tip_height as u64 + 1).saturating_sub(start_height as u64);
+        let mut scanner =
+            BlockScanner::new(database, start_height, self.ownership_resolver.clone())?;
+
+        let mut scanned_blocks = 0;
+        loop {
+            let chunk = heights
+                .by_ref()
+                .take(BLOCK_SCAN_CHUNK_SIZE)
+                .collect::<Vec<_>>();
+            if chunk.is_empty() {
+                break;
+            }
+            for batch in chunk.chunks(self.concurrency.max(1) as usize) {
+                if progress.is_cancelled() {
+                    return Err(Error::SyncAborted);
+                }
+                let handles = batch
+                    .iter()
+                    .map(|&height| {
+                        let client = self.url_client.clone();
+                        // make each request in its own thread.
+                        std::thread::spawn(move || client._get_block_by_height(height))
+                    })
+                    .collect::<Vec<_>>();
+                // blocks must be scanned in order to find the transactions spending from the
+                // previous ones
+                for (height, handle) in batch.iter().zip(handles) {
+                    scanner.scan_block(*height, join_request(handle)?);
+                }
+            }
+            scanned_blocks += chunk.len() as u64;
+            progress.update(scanned_blocks as f32 / total_blocks as f32 * 100.0, None)?;
+        }
+
+        stats.scripts_scanned = scanner.scripts.len();
+        stats.txs_fetched = scanner.txs_downloaded;
+
+        let mut request = script_sync::start_with_ownership_resolver(
+            database,
+            script_sync::StopGap::new(self.stop_gap, self.internal_stop_gap),
+            self.ownership_resolver.clone(),
+        )?;
+        loop {
+            request = match request {
+                Request::Script(script_req) => {
+                    let histories = script_req
+                        .request()
+                        .map(|script| scanner.history(script))
+                        .collect();
+                    script_req.satisfy(histories)?
+                }
+                Request::Conftime(conftime_req) => {
+                    let conftimes = conftime_req
+                        .request()
+                        .map(|txid| (*txid, scanner.confirmation_time(txid)))
+                        .collect::<Vec<_>>();
+                    if self.validate_proofs {
+                        self.check_proofs(&conftimes)?;
+                    }
+                    conftime_req.satisfy(
+                        conftimes
+                            .into_iter()
+                            .map(|(_, conftime)| conftime)
+                            .collect(),
+                    )?
+                }
+                Request::Tx(tx_req) => {
+                    let full_txs = tx_req.request().map(|txid| scanner.full_tx(txid)).collect();
+                    tx_req.satisfy(full_txs)?
+                }
+                Request::Finish(batch_update) => return Ok(batch_update),
+            }
+        }
+    }
+
+    /// Return the transactions in the history of `notification_script` that look like BIP47
+    /// notification transactions
+    ///
+    /// These are the transactions paying to the notification address that also carry an 80 bytes
+    /// `OP_RETURN` payload: the candidates to decode to learn the payment code of the sender.
+    pub fn find_notification_txs(
+        &self,
+        notification_script: &Script,
+    ) -> Result<Vec<Transaction>, Error> {
+        Ok(self
+            .url_client
+            ._get_script_history(notification_script)?
+            .iter()
+            .map(Tx::to_tx)
+            .filter(|tx| is_notification_tx(tx, notification_script))
+            .collect())
+    }
+
+    /// Return whether `outpoint` is spent and, if so, by which input of which transaction
+    ///
+    /// Both confirmed and unconfirmed spends are reported, which lets a wallet notice that an
+    /// output it tracks was spent by someone else, like a co-signer, without a full sync. Returns
+    /// `None` if the transaction is unknown to the server or doesn't have output `outpoint.vout`.
+    pub fn get_output_status(&self, outpoint: &OutPoint) -> Result<Option<OutputStatus>, Error> {
+        Ok(self
+            .url_client
+            ._get_output_status(outpoint)?
+            .map(OutputStatus::from))
+    }
+
+    /// Fetch the block hash, height, time and merkle proof of a confirmed transaction, along with
+    /// its current number of confirmations
+    ///
+    /// Returns `None` if the transaction is unconfirmed or unknown to the server.
+    pub fn confirmation_path(&self, txid: &Txid) -> Result<Option<ConfirmationPath>, Error> {
+        self.url_client._get_confirmation_path(txid)
+    }
+
+    /// Return the confirmed wallet UTXOs whose transaction doesn't exist anymore
+    ///
+    /// After a reorg the outputs of a transaction that was only confirmed in the stale blocks, like
+    /// the coinbase of one of them, don't exist anymore. This checks the status of the
+    /// transaction of every confirmed UTXO of `database` on the server, without syncing. A
+    /// transaction confirmed again in another block or sent back to the mempool still exists, so
+    /// its outputs aren't returned: the next sync updates their confirmation time.
+    pub fn find_orphaned_utxos<D: Database>(&self, database: &D) -> Result<Vec<OutPoint>, Error> {
+        let mut exists = HashMap::new();
+        let mut orphaned = vec![];
+        for utxo in database.iter_utxos()? {
+            let txid = utxo.outpoint.txid;
+            let height = match database
+                .get_tx(&txid, false)?
+                .and_then(|details| details.confirmation_time)
+            {
+                Some(confirmation_time) if !utxo.is_spent => confirmation_time.height,
+                _ => continue,
+            };
+
+            if !exists.contains_key(&txid) {
+                // the status of an unknown transaction is "unconfirmed", look it up to tell
+                // apart the ones that are actually in the mempool
+                let confirmed = matches!(
+                    self.url_client._get_tx_status(&txid)?,
+                    Some(TxStatus {
+                        confirmed: true,
+                        ..
+                    })
+                );
+                let found = confirmed || self.url_client._get_tx(&txid)?.is_some();
+                exists.insert(txid, found);
+            }
+            if !exists[&txid] {
+                debug!(
+                    "The transaction of UTXO {}, confirmed at height {}, doesn't exist anymore",
+                    utxo.outpoint, height
+                );
+                orphaned.push(utxo.outpoint);
+            }
+        }
+
+        Ok(orphaned)
+    }
+
+    /// Return the current difficulty and estimated hashrate of the network
+    ///
+    /// With [`EsploraFlavor::MempoolSpace`] the statistics computed by the server are used,
+    /// otherwise they're derived from the header of the tip of the chain, see
+    /// [`NetworkStats::from_header`].
+    pub fn get_network_stats(&self) -> Result<NetworkStats, Error> {
+        let height = self.url_client._get_height()?;
+        if self.flavor == EsploraFlavor::MempoolSpace {
+            if let Some(mining) = self.url_client._get_mining_hashrate()? {
+                return Ok(NetworkStats {
+                    height,
+                    difficulty: mining.current_difficulty,
+                    hashrate: mining.current_hashrate,
+                });
+            }
+        }
+
+        let header = self.url_client._get_header(height)?;
+        Ok(NetworkStats::from_header(height, &header))
+    }
+
+    /// Check that a transaction is confirmed in the block at the height given by its merkle proof,
+    /// using the trusted header from `headers` instead of the one reported by the server
+    ///
+    /// Returns `false` if the transaction is unconfirmed, unknown to the server or if its merkle
+    /// proof doesn't match the trusted header. The transaction itself is fetched as well, and
+    /// `false` is returned if its serialization without witness is 64 bytes long: such a
+    /// transaction is indistinguishable from an inner node of the merkle tree. Fails with
+    /// [`EsploraError::HeaderHeightNotFound`] if `headers` doesn't contain the height of the block
+    /// claimed by the server.
+    pub fn verify_tx_against_headers(
+        &self,
+        txid: &Txid,
+        headers: &HeaderChain,
+    ) -> Result<bool, Error> {
+        Ok(self.url_client._verify_tx_against_headers(txid, headers)?)
+    }
+
+    /// Check that a transaction is confirmed in the block at `height`, verifying its merkle proof
+    /// against the trusted header at that height, see [`EsploraBlockchain::with_trusted_headers`]
+    ///
+    /// Returns `false` if the transaction is unconfirmed, unknown to the server, confirmed at a
+    /// different height or if its merkle proof doesn't match the header. Fails with
+    /// [`Error::MissingTrustedHeader`] if there's no trusted header at `height`.
+    pub fn verify_tx_proof(&self, txid: &Txid, height: u32) -> Result<bool, Error> {
+        let header = trusted_header(&self.trusted_headers, height)?;
+        Ok(self.url_client._verify_tx_proof(txid, height, &header)?)
+    }
+
+    /// Return the percentiles of the latencies of the requests sent since the blockchain was
+    /// created, grouped by [`EndpointCategory`]
+    ///
+    /// The report covers the requests sent by the clones of this blockchain too, including the
+    /// failed ones.
+    pub fn latency_report(&self) -> LatencyReport {
+        self.url_client.latencies.report()
+    }
+
+    /// Export the requests recorded since the blockchain was created in the HAR (HTTP Archive)
+    /// format, see [`EsploraBlockchain::with_record_har`]
+    ///
+    /// The archive can be opened by the developer tools of the browsers and attached to bug
+    /// reports: the values of the headers carrying credentials, like `Authorization`, are
+    /// redacted. Binary bodies are encoded in base64. It contains no entries if the requests
+    /// weren't recorded.
+    pub fn export_har(&self) -> String {
+        match &self.url_client.har {
+            Some(har) => har.export(),
+            None => HarRecorder::default().export(),
+        }
+    }
+
+    /// Sync `database`, see [`WalletSync::wallet_setup_with_stats`]
+    fn sync_with_stats<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<SyncStats, Error> {
+        let start = Instant::new();
+        let mut stats = SyncStats {
+            reorged_txids: reorg::detect_reorgs(self, database)?,
+            ..Default::default()
+        };
+
+        if let SyncMode::BlockScan { birth_height } = self.sync_mode {
+            let batch_update =
+                self.block_scan(database, birth_height, &mut stats, progress_update.as_ref())?;
+            database.commit_batch(batch_update)?;
+            reorg::store_block_hashes(self, database)?;
+
+            stats.bytes_transferred = Some(self.url_client.bytes_received.load(Ordering::Relaxed));
+            stats.duration = start.elapsed();
+            return Ok(stats);
+        }
+
+        use crate::blockchain::script_sync::Request;
+        let mut request = script_sync::start_with_ownership_resolver(
+            database,
+            script_sync::StopGap::new(self.stop_gap, self.internal_stop_gap),
+            self.ownership_resolver.clone(),
+        )?;
         let mut tx_index: HashMap<Txid, Tx> = HashMap::new();
+        let mut prevout_txs: HashMap<Txid, Transaction> = HashMap::new();
+        let mut prevouts_fetched = 0;
+        let mut best_chain: HashMap<u32, Option<BlockHash>> = HashMap::new();
         let batch_update = loop {
+            if progress_update.is_cancelled() {
+                return Err(Error::SyncAborted);
+            }
             request = match request {
+                Request::Script(script_req) if self.remaining_requests() == Some(0) => {
+                    debug!("Request budget exhausted, skipping the remaining script pubkeys");
+                    stats.request_budget_exhausted = true;
+                    script_req.skip_remaining()?
+                }
                 Request::Script(script_req) => {
+                    let batch_size = self
+                        .remaining_requests()
+                        .map_or(self.concurrency as usize, |remaining| {
+                            remaining.min(self.concurrency as usize)
+                        });
                     let scripts = script_req
                         .request()
-                        .take(self.concurrency as usize)
-                        .cloned();
+                        .take(batch_size)
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    stats.scripts_scanned += scripts.len();
 
                     let mut handles = vec![];
                     for script in scripts {
                         let client = self.url_client.clone();
                         // make each request in its own thread.
                         handles.push(std::thread::spawn(move || {
-                            let mut related_txs: Vec<Tx> = client._scripthash_txs(&script, None)?;
-
-                            let n_confirmed =
-                                related_txs.iter().filter(|tx| tx.status.confirmed).count();
-                            // esplora pages on 25 confirmed transactions. If there's 25 or more we
-                            // keep requesting to see if there's more.
-                            if n_confirmed >= 25 {
-                                loop {
-                                    let new_related_txs: Vec<Tx> = client._scripthash_txs(
-                                        &script,
-                                        Some(related_txs.last().unwrap().txid),
-                                    )?;
-                                    let n = new_related_txs.len();
-                                    related_txs.extend(new_related_txs);
-                                    // we've reached the end
-                                    if n < 25 {
-                                        break;
-                                    }
-                                }
-                            }
-                            Result::<_, Error>::Ok(related_txs)
+                            Result::<_, Error>::Ok(client._get_script_history(&script)?)
                         }));
                     }
 
-                    let txs_per_script: Vec<Vec<Tx>> = handles
-                        .into_iter()
-                        .map(|handle| handle.join().unwrap())
-                        .collect::<Result<_, _>>()?;
+                    let mut txs_per_script: Vec<Result<Vec<Tx>, Error>> =
+                        handles.into_iter().map(join_request).collect();
+
+                    if self.verify_block_hashes {
+                        let all_txs = txs_per_script.iter().flatten().flatten();
+                        for height in super::missing_block_heights(all_txs, &best_chain) {
+                            best_chain.insert(height, self.url_client._get_block_hash(height)?);
+                        }
+                        super::unconfirm_reorged_txs(
+                            txs_per_script.iter_mut().flatten().flatten(),
+                            &best_chain,
+                        );
+                    }
+
                     let mut satisfaction = vec![];
 
                     for txs in txs_per_script {
-                        satisfaction.push(
-                            txs.iter()
+                        satisfaction.push(txs.map(|txs| {
+                            let txids = txs
+                                .iter()
                                 .map(|tx| (tx.txid, tx.status.block_height))
-                                .collect(),
-                        );
-                        for tx in txs {
-                            tx_index.insert(tx.txid, tx);
-                        }
+                                .collect();
+                            for tx in txs {
+                                tx_index.insert(tx.txid, tx);
+                            }
+                            txids
+                        }));
                     }
 
-                    script_req.satisfy(satisfaction)?
+                    script_req.satisfy_with_policy(satisfaction, self.sync_error_policy)?
                 }
                 Request::Conftime(conftime_req) => {
                     let conftimes = conftime_req
                         .request()
                         .map(|txid| {
-                            tx_index
+                            let conftime = tx_index
                                 .get(txid)
                                 .expect("must be in index")
-                                .confirmation_time()
+                                .confirmation_time();
+                            (*txid, conftime)
                         })
-                        .collect();
-                    conftime_req.satisfy(conftimes)?
+                        .collect::<Vec<_>>();
+                    if self.validate_proofs {
+                        self.check_proofs(&conftimes)?;
+                    }
+                    conftime_req.satisfy(
+                        conftimes
+                            .into_iter()
+                            .map(|(_, conftime)| conftime)
+                            .collect(),
+                    )?
                 }
                 Request::Tx(tx_req) => {
+                    let mut missing = tx_req
+                        .request()
+                        .flat_map(|txid| {
+                            tx_index
+                                .get(txid)
+                                .expect("must be in index")
+                                .missing_prevouts()
+                        })
+                        .filter(|txid| !prevout_txs.contains_key(txid))
+                        .collect::<HashSet<_>>();
+                    if let Some(cache) = &self.shared_tx_cache {
+                        missing.retain(|txid| match cache.get(txid) {
+                            Some(tx) => {
+                                prevout_txs.insert(*txid, tx);
+                                false
+                            }
+                            None => true,
+                        });
+                    }
+                    let fetched = self
+                        .url_client
+                        ._get_txs(missing.into_iter().collect(), self.prevout_concurrency())?;
+                    if let Some(cache) = &self.shared_tx_cache {
+                        fetched.values().cloned().for_each(|tx| cache.insert(tx));
+                    }
+                    prevouts_fetched += fetched.len();
+                    prevout_txs.extend(fetched);
+
                     let full_txs = tx_req
                         .request()
                         .map(|txid| {
                             let tx = tx_index.get(txid).expect("must be in index");
-                            Ok((tx.previous_outputs(), tx.to_tx()))
+                            Ok((tx.previous_outputs(&prevout_txs), tx.to_tx()))
                         })
                         .collect::<Result<_, Error>>()?;
                     tx_req.satisfy(full_txs)?
                 }
                 Request::Finish(batch_update) => break batch_update,
+            };
+
+            if let Some(sync_progress) = request.sync_progress() {
+                progress_update.update_sync(&sync_progress)?;
+            }
+            if let Some(skipped_scripts) = request.skipped_scripts() {
+                stats.skipped_scripts = skipped_scripts;
             }
         };
 
-        database.commit_batch(batch_update)?;
+        database.commit_batch(batch_update)?;
+        reorg::store_block_hashes(self, database)?;
+
+        stats.txs_fetched = tx_index.len() + prevouts_fetched;
+        stats.bytes_transferred = Some(self.url_client.bytes_received.load(Ordering::Relaxed));
+        stats.duration = start.elapsed();
+        Ok(stats)
+    }
+}
+
+impl Blockchain for EsploraBlockchain {
+    fn get_capabilities(&self) -> HashSet<Capability> {
+        vec![
+            Capability::FullHistory,
+            Capability::GetAnyTx,
+            Capability::AccurateFees,
+            Capability::GetBlock,
+            Capability::FeeHistogram,
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<(), Error> {
+        let _txid = self.url_client._broadcast(tx)?;
+        Ok(())
+    }
+
+    fn broadcast_batch(&self, txs: &[Transaction]) -> Result<Vec<Txid>, Error> {
+        let mut broadcast = Vec::with_capacity(txs.len());
+        for group in independent_broadcast_groups(txs) {
+            for chunk in group.chunks(self.concurrency.max(1) as usize) {
+                let handles = chunk
+                    .iter()
+                    .map(|tx| {
+                        let client = self.url_client.clone();
+                        let tx = tx.clone();
+                        std::thread::spawn(move || client._broadcast(&tx))
+                    })
+                    .collect::<Vec<_>>();
+                let results = handles
+                    .into_iter()
+                    .map(|handle| Ok(join_request(handle)?))
+                    .collect();
+                check_group_broadcast(chunk, results, &mut broadcast)?;
+            }
+        }
+
+        Ok(broadcast)
+    }
+
+    fn estimate_fee(&self, target: usize) -> Result<FeeRate, Error> {
+        let estimates = self.fee_estimates()?;
+        super::into_fee_rate(target, &estimates)
+    }
+
+    fn get_tx_confirmations(&self, txid: &Txid) -> Result<Option<u32>, Error> {
+        let height = match self.url_client._get_tx_status(txid)? {
+            None => return Ok(None),
+            Some(TxStatus {
+                confirmed: true,
+                block_height: Some(height),
+                ..
+            }) => height,
+            Some(_) => return Ok(Some(0)),
+        };
+        let tip = self.url_client._get_height()?;
+
+        // the tip may come from a server lagging behind the one that replied to the status
+        Ok(Some((tip + 1).saturating_sub(height).max(1)))
+    }
+}
+
+impl StatelessBlockchain for EsploraBlockchain {}
+
+impl GetHeight for EsploraBlockchain {
+    fn get_height(&self) -> Result<u32, Error> {
+        Ok(self.url_client._get_height()?)
+    }
+}
+
+impl GetTx for EsploraBlockchain {
+    fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, Error> {
+        Ok(self.url_client._get_tx(txid)?)
+    }
+}
+
+impl GetBlock for EsploraBlockchain {
+    fn get_block(&self, hash: &BlockHash) -> Result<Option<Block>, Error> {
+        Ok(self.url_client._get_block(hash)?)
+    }
+}
+
+impl GetBlockHash for EsploraBlockchain {
+    fn get_block_hash(&self, height: u32) -> Result<Option<BlockHash>, Error> {
+        Ok(self.url_client._get_block_hash(height)?)
+    }
+
+    fn get_block_header(&self, height: u32) -> Result<Option<BlockHeader>, Error> {
+        match self.url_client._get_header(height) {
+            Ok(header) => Ok(Some(header)),
+            Err(EsploraError::HeaderHeightNotFound(_)) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl WalletSync for EsploraBlockchain {
+    fn wallet_setup<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<(), Error> {
+        self.wallet_setup_with_stats(database, progress_update)?;
+        Ok(())
+    }
+
+    fn wallet_sync_with_stats<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<SyncStats, Error> {
+        self.wallet_setup_with_stats(database, progress_update)
+    }
+
+    fn wallet_setup_with_stats<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<SyncStats, Error> {
+        // the counters of the client are shared with its clones, count the requests of this sync
+        // only on a copy of the blockchain with its own counters
+        let blockchain = EsploraBlockchain {
+            url_client: self.url_client.with_new_counters(),
+            ..self.clone()
+        };
+        blockchain.sync_with_stats(database, progress_update)
+    }
+
+    fn wallet_sync_with_budget<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+        max_requests: usize,
+    ) -> Result<SyncStats, Error> {
+        self.wallet_setup_with_budget(database, progress_update, max_requests)
+    }
+
+    fn wallet_setup_with_budget<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+        max_requests: usize,
+    ) -> Result<SyncStats, Error> {
+        // the budget of the sync can only be lower than the one of the blockchain
+        let max_requests = self
+            .max_requests_per_sync
+            .map_or(max_requests, |limit| limit.min(max_requests));
+        let blockchain = EsploraBlockchain {
+            url_client: self.url_client.with_new_counters(),
+            max_requests_per_sync: Some(max_requests),
+            ..self.clone()
+        };
+        blockchain.sync_with_stats(database, progress_update)
+    }
+}
+
+impl CacheTxs for EsploraBlockchain {
+    fn with_tx_cache(mut self, cache: SharedTxCache) -> Self {
+        self.shared_tx_cache = Some(cache);
+        self
+    }
+}
+
+impl LimitRequests for EsploraBlockchain {
+    fn with_rate_limiter(mut self, limiter: RateLimiter) -> Self {
+        self.url_client.rate_limiter = Some(limiter);
+        self
+    }
+}
+
+impl UrlClient {
+    /// Clone of the client counting the bytes and requests from zero
+    fn with_new_counters(&self) -> Self {
+        UrlClient {
+            bytes_received: Arc::new(AtomicU64::new(0)),
+            requests_sent: Arc::new(AtomicUsize::new(0)),
+            ..self.clone()
+        }
+    }
+
+    fn _get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, EsploraError> {
+        let resp = self.get(&format!("{}/tx/{}/raw", self.url, txid));
+
+        match resp {
+            Ok(resp) => Ok(Some(deserialize(&self.read_bytes(resp)?)?)),
+            Err(ureq::Error::Status(code, resp)) => {
+                if is_status_not_found(code) {
+                    return Ok(None);
+                }
+                Err(into_http_response_error(code, resp))
+            }
+            Err(e) => Err(EsploraError::Ureq(e)),
+        }
+    }
+
+    fn _get_tx_no_opt(&self, txid: &Txid) -> Result<Transaction, EsploraError> {
+        match self._get_tx(txid) {
+            Ok(Some(tx)) => Ok(tx),
+            Ok(None) => Err(EsploraError::TransactionNotFound(*txid)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Fetch `txids`, making at most `concurrency` requests in parallel
+    fn _get_txs(
+        &self,
+        txids: Vec<Txid>,
+        concurrency: u8,
+    ) -> Result<HashMap<Txid, Transaction>, EsploraError> {
+        let mut txs = HashMap::new();
+        for chunk in txids.chunks(concurrency.max(1) as usize) {
+            let handles = chunk
+                .iter()
+                .map(|txid| {
+                    let client = self.clone();
+                    let txid = *txid;
+                    std::thread::spawn(move || client._get_tx_no_opt(&txid))
+                })
+                .collect::<Vec<_>>();
+
+            for handle in handles {
+                let tx = join_request(handle)?;
+                txs.insert(tx.txid(), tx);
+            }
+        }
+
+        Ok(txs)
+    }
+
+    fn _get_tx_info(&self, txid: &Txid) -> Result<Option<Tx>, EsploraError> {
+        let resp = self.get(&format!("{}/tx/{}", self.url, txid));
+
+        match resp {
+            Ok(resp) => Ok(Some(self.read_json(resp)?)),
+            Err(ureq::Error::Status(code, resp)) => {
+                if is_status_not_found(code) {
+                    return Ok(None);
+                }
+                Err(into_http_response_error(code, resp))
+            }
+            Err(e) => Err(EsploraError::Ureq(e)),
+        }
+    }
+
+    fn _get_tx_info_no_opt(&self, txid: &Txid) -> Result<Tx, EsploraError> {
+        self._get_tx_info(txid)?
+            .ok_or(EsploraError::TransactionNotFound(*txid))
+    }
+
+    fn _get_mempool_info(&self) -> Result<MempoolInfo, EsploraError> {
+        let resp = self.get(&format!("{}/mempool", self.url))?;
+
+        self.read_json(resp)
+    }
+
+    fn _get_tx_outspends(&self, txid: &Txid) -> Result<Vec<OutSpend>, EsploraError> {
+        let resp = self.get(&format!("{}/tx/{}/outspends", self.url, txid))?;
+
+        self.read_json(resp)
+    }
+
+    /// Fetch the outspends of `txids`, making at most `concurrency` requests in parallel
+    fn _get_txs_outspends(
+        &self,
+        txids: Vec<Txid>,
+        concurrency: u8,
+    ) -> Result<HashMap<Txid, Vec<OutSpend>>, EsploraError> {
+        let mut outspends = HashMap::new();
+        for chunk in txids.chunks(concurrency.max(1) as usize) {
+            let handles = chunk
+                .iter()
+                .map(|txid| {
+                    let client = self.clone();
+                    let txid = *txid;
+                    std::thread::spawn(move || {
+                        client
+                            ._get_tx_outspends(&txid)
+                            .map(|tx_outspends| (txid, tx_outspends))
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            for handle in handles {
+                let (txid, tx_outspends) = join_request(handle)?;
+                outspends.insert(txid, tx_outspends);
+            }
+        }
+
+        Ok(outspends)
+    }
+
+    fn _get_output_status(&self, outpoint: &OutPoint) -> Result<Option<OutSpend>, EsploraError> {
+        // unlike `/outspend/:vout`, `/outspends` fails if the transaction doesn't exist
+        let resp = self.get(&format!("{}/tx/{}/outspends", self.url, outpoint.txid));
+
+        match resp {
+            Ok(resp) => Ok(self
+                .read_json::<Vec<OutSpend>>(resp)?
+                .into_iter()
+                .nth(outpoint.vout as usize)),
+            Err(ureq::Error::Status(code, resp)) => {
+                if is_status_not_found(code) {
+                    return Ok(None);
+                }
+                Err(into_http_response_error(code, resp))
+            }
+            Err(e) => Err(EsploraError::Ureq(e)),
+        }
+    }
+
+    fn _get_outspend(&self, outpoint: &OutPoint) -> Result<OutSpend, EsploraError> {
+        let resp = self.get(&format!(
+            "{}/tx/{}/outspend/{}",
+            self.url, outpoint.txid, outpoint.vout
+        ))?;
+
+        self.read_json(resp)
+    }
+
+    fn _get_unconfirmed_spend(&self, outpoint: &OutPoint) -> Result<Option<Txid>, EsploraError> {
+        Ok(self._get_outspend(outpoint)?.unconfirmed_spend())
+    }
+
+    fn _get_mempool_relations(&self, txid: &Txid) -> Result<MempoolRelations, EsploraError> {
+        let mut relations = MempoolRelations::default();
+        let mut seen = HashSet::new();
+        seen.insert(*txid);
+
+        let mut queue = VecDeque::new();
+        queue.push_back((self._get_tx_info_no_opt(txid)?, 0));
+        while let Some((tx, depth)) = queue.pop_front() {
+            if depth >= MAX_MEMPOOL_RELATIONS_DEPTH {
+                continue;
+            }
+
+            for vin in tx.vin.iter().filter(|vin| !vin.is_coinbase) {
+                if !seen.insert(vin.txid) {
+                    continue;
+                }
+
+                let parent = self._get_tx_info_no_opt(&vin.txid)?;
+                if parent.status.confirmed {
+                    continue;
+                }
+                relations.ancestors.push(MempoolTx::new(&parent, depth + 1));
+                queue.push_back((parent, depth + 1));
+            }
+        }
+
+        let mut queue = VecDeque::new();
+        queue.push_back((*txid, 0));
+        while let Some((txid, depth)) = queue.pop_front() {
+            if depth >= MAX_MEMPOOL_RELATIONS_DEPTH {
+                continue;
+            }
+
+            let outspends = self._get_tx_outspends(&txid)?;
+            for child_txid in outspends.iter().filter_map(OutSpend::unconfirmed_spend) {
+                if !seen.insert(child_txid) {
+                    continue;
+                }
+
+                let child = self._get_tx_info_no_opt(&child_txid)?;
+                relations
+                    .descendants
+                    .push(MempoolTx::new(&child, depth + 1));
+                queue.push_back((child_txid, depth + 1));
+            }
+        }
+
+        Ok(relations)
+    }
+
+    fn _get_tx_status(&self, txid: &Txid) -> Result<Option<TxStatus>, EsploraError> {
+        let resp = self.get(&format!("{}/tx/{}/status", self.url, txid));
+
+        match resp {
+            Ok(resp) => Ok(Some(self.read_json(resp)?)),
+            Err(ureq::Error::Status(code, resp)) => {
+                if is_status_not_found(code) {
+                    return Ok(None);
+                }
+                Err(into_http_response_error(code, resp))
+            }
+            Err(e) => Err(EsploraError::Ureq(e)),
+        }
+    }
+
+    fn _get_merkle_proof(&self, txid: &Txid) -> Result<Option<MerkleProof>, EsploraError> {
+        let resp = self.get(&format!("{}/tx/{}/merkle-proof", self.url, txid));
+
+        match resp {
+            Ok(resp) => Ok(Some(self.read_json(resp)?)),
+            Err(ureq::Error::Status(code, resp)) => {
+                if is_status_not_found(code) {
+                    return Ok(None);
+                }
+                Err(into_http_response_error(code, resp))
+            }
+            Err(e) => Err(EsploraError::Ureq(e)),
+        }
+    }
+
+    fn _verify_tx_against_headers(
+        &self,
+        txid: &Txid,
+        headers: &HeaderChain,
+    ) -> Result<bool, EsploraError> {
+        let proof = match self._get_merkle_proof(txid)? {
+            Some(proof) => proof,
+            None => return Ok(false),
+        };
+        match self._get_tx(txid)? {
+            Some(tx) if tx.txid() == *txid && is_provable(&tx) => {
+                verify_merkle_proof(txid, &proof, headers)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn _verify_tx_proof(
+        &self,
+        txid: &Txid,
+        height: u32,
+        header: &BlockHeader,
+    ) -> Result<bool, EsploraError> {
+        match self._get_merkle_proof(txid)? {
+            Some(proof) if proof.block_height == height => {
+                Ok(proof_matches_header(txid, &proof, header))
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn _get_confirmation_path(&self, txid: &Txid) -> Result<Option<ConfirmationPath>, Error> {
+        let (block_hash, block_time) = match self._get_tx_status(txid)? {
+            Some(TxStatus {
+                confirmed: true,
+                block_hash: Some(block_hash),
+                block_height: Some(height),
+                block_time: Some(timestamp),
+            }) => (block_hash, BlockTime { height, timestamp }),
+            _ => return Ok(None),
+        };
+
+        let header = self._get_header_by_hash(&block_hash)?;
+        let merkle_proof = match self._get_merkle_proof(txid)? {
+            Some(merkle_proof) => merkle_proof,
+            // the transaction was reorged out in the meantime
+            None => return Ok(None),
+        };
+        let tip_height = self._get_height()?;
 
-        Ok(())
+        ConfirmationPath::new(
+            *txid,
+            block_hash,
+            block_time,
+            header,
+            tip_height,
+            merkle_proof,
+        )
+        .map(Some)
     }
-}
 
-impl UrlClient {
-    fn _get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, EsploraError> {
-        let resp = self
-            .agent
-            .get(&format!("{}/tx/{}/raw", self.url, txid))
-            .call();
+    fn _get_block_hash(&self, block_height: u32) -> Result<Option<BlockHash>, EsploraError> {
+        let resp = self.get(&format!("{}/block-height/{}", self.url, block_height));
 
         match resp {
-            Ok(resp) => Ok(Some(deserialize(&into_bytes(resp)?)?)),
-            Err(ureq::Error::Status(code, _)) => {
+            Ok(resp) => Ok(Some(BlockHash::from_hex(&self.read_string(resp)?)?)),
+            Err(ureq::Error::Status(code, resp)) => {
                 if is_status_not_found(code) {
                     return Ok(None);
                 }
-                Err(EsploraError::HttpResponse(code))
+                Err(into_http_response_error(code, resp))
             }
             Err(e) => Err(EsploraError::Ureq(e)),
         }
     }
 
-    fn _get_tx_no_opt(&self, txid: &Txid) -> Result<Transaction, EsploraError> {
-        match self._get_tx(txid) {
-            Ok(Some(tx)) => Ok(tx),
-            Ok(None) => Err(EsploraError::TransactionNotFound(*txid)),
-            Err(e) => Err(e),
+    fn _get_block_by_height(&self, block_height: u32) -> Result<Block, EsploraError> {
+        let block_hash = self
+            ._get_block_hash(block_height)?
+            .ok_or(EsploraError::HeaderHeightNotFound(block_height))?;
+        self._get_block(&block_hash)?
+            .ok_or(EsploraError::HeaderHashNotFound(block_hash))
+    }
+
+    fn _get_block(&self, block_hash: &BlockHash) -> Result<Option<Block>, EsploraError> {
+        // blocks are large, failed downloads aren't retried
+        let resp = self.get_once(&format!("{}/block/{}/raw", self.url, block_hash));
+
+        match resp {
+            Ok(resp) => Ok(Some(deserialize(&self.read_bytes(resp)?)?)),
+            Err(ureq::Error::Status(code, resp)) => {
+                if is_status_not_found(code) {
+                    return Ok(None);
+                }
+                Err(into_http_response_error(code, resp))
+            }
+            Err(e) => Err(EsploraError::Ureq(e)),
         }
     }
 
     fn _get_header(&self, block_height: u32) -> Result<BlockHeader, EsploraError> {
-        let resp = self
-            .agent
-            .get(&format!("{}/block-height/{}", self.url, block_height))
-            .call();
+        let resp = self.get(&format!("{}/block-height/{}", self.url, block_height));
 
         let bytes = match resp {
-            Ok(resp) => Ok(into_bytes(resp)?),
-            Err(ureq::Error::Status(code, _)) => Err(EsploraError::HttpResponse(code)),
+            Ok(resp) => Ok(self.read_bytes(resp)?),
+            Err(ureq::Error::Status(code, _)) if is_status_not_found(code) => {
+                Err(EsploraError::HeaderHeightNotFound(block_height))
+            }
+            Err(ureq::Error::Status(code, resp)) => Err(into_http_response_error(code, resp)),
             Err(e) => Err(EsploraError::Ureq(e)),
         }?;
 
         let hash = std::str::from_utf8(&bytes)
             .map_err(|_| EsploraError::HeaderHeightNotFound(block_height))?;
 
-        let resp = self
-            .agent
-            .get(&format!("{}/block/{}/header", self.url, hash))
-            .call();
+        let resp = self.get_once(&format!("{}/block/{}/header", self.url, hash));
 
         match resp {
-            Ok(resp) => Ok(deserialize(&Vec::from_hex(&resp.into_string()?)?)?),
-            Err(ureq::Error::Status(code, _)) => Err(EsploraError::HttpResponse(code)),
+            Ok(resp) => Ok(deserialize(&Vec::from_hex(&self.read_string(resp)?)?)?),
+            // the block was reorged out between the two requests
+            Err(ureq::Error::Status(code, _)) if is_status_not_found(code) => {
+                Err(EsploraError::HeaderHeightNotFound(block_height))
+            }
+            Err(ureq::Error::Status(code, resp)) => Err(into_http_response_error(code, resp)),
             Err(e) => Err(EsploraError::Ureq(e)),
         }
     }
 
+    fn _get_header_by_hash(&self, block_hash: &BlockHash) -> Result<BlockHeader, EsploraError> {
+        let resp = self.get_once(&format!("{}/block/{}/header", self.url, block_hash))?;
+
+        Ok(deserialize(&Vec::from_hex(&self.read_string(resp)?)?)?)
+    }
+
     fn _broadcast(&self, transaction: &Transaction) -> Result<(), EsploraError> {
-        let resp = self
-            .agent
-            .post(&format!("{}/tx", self.url))
-            .send_string(&serialize(transaction).to_hex());
+        self.wait_rate_limiter();
+        self.requests_sent.fetch_add(1, Ordering::Relaxed);
+        let url = format!("{}/tx", self.url);
+        let body = serialize(transaction).to_hex();
+        let started = get_timestamp();
+        let start = Instant::new();
+        let resp = self.request("POST", &url).send_string(&body);
+        self.record_har("POST", &url, Some(body), started, &start, &resp);
+        self.record_latency("POST", &url, start);
 
         match resp {
             Ok(_) => Ok(()), // We do not return the txid?
-            Err(ureq::Error::Status(code, _)) => Err(EsploraError::HttpResponse(code)),
+            Err(ureq::Error::Status(code, resp)) => Err(into_http_response_error(code, resp)),
             Err(e) => Err(EsploraError::Ureq(e)),
         }
     }
 
     fn _get_height(&self) -> Result<u32, EsploraError> {
-        let resp = self
-            .agent
-            .get(&format!("{}/blocks/tip/height", self.url))
-            .call();
+        let resp = self.get_once(&format!("{}/blocks/tip/height", self.url));
+
+        match resp {
+            Ok(resp) => Ok(self.read_string(resp)?.parse()?),
+            Err(ureq::Error::Status(code, resp)) => Err(into_http_response_error(code, resp)),
+            Err(e) => Err(EsploraError::Ureq(e)),
+        }
+    }
+
+    fn _get_mining_hashrate(&self) -> Result<Option<MiningHashrate>, EsploraError> {
+        let resp = self.get_once(&format!("{}/v1/mining/hashrate/3d", self.url));
 
         match resp {
-            Ok(resp) => Ok(resp.into_string()?.parse()?),
-            Err(ureq::Error::Status(code, _)) => Err(EsploraError::HttpResponse(code)),
+            Ok(resp) => Ok(Some(self.read_json(resp)?)),
+            Err(ureq::Error::Status(code, resp)) => {
+                if is_status_not_found(code) {
+                    return Ok(None);
+                }
+                Err(into_http_response_error(code, resp))
+            }
             Err(e) => Err(EsploraError::Ureq(e)),
         }
     }
 
     fn _get_fee_estimates(&self) -> Result<HashMap<String, f64>, EsploraError> {
-        let resp = self
-            .agent
-            .get(&format!("{}/fee-estimates", self.url,))
-            .call();
+        let resp = self.get_once(&format!("{}/fee-estimates", self.url,));
 
         let map = match resp {
-            Ok(resp) => {
-                let map: HashMap<String, f64> = resp.into_json()?;
-                Ok(map)
-            }
-            Err(ureq::Error::Status(code, _)) => Err(EsploraError::HttpResponse(code)),
+            Ok(resp) => self.read_json::<HashMap<String, f64>>(resp),
+            Err(ureq::Error::Status(code, resp)) => Err(into_http_response_error(code, resp)),
             Err(e) => Err(EsploraError::Ureq(e)),
         }?;
 
@@ -320,26 +1547,412 @@ impl UrlClient {
             ),
             None => format!("{}/scripthash/{}/txs", self.url, script_hash),
         };
-        Ok(self.agent.get(&url).call()?.into_json()?)
+        self.read_json(self.get(&url)?)
+    }
+
+    fn _get_script_history(&self, script: &Script) -> Result<Vec<Tx>, EsploraError> {
+        let mut related_txs = self._scripthash_txs(script, None)?;
+
+        let n_confirmed = related_txs.iter().filter(|tx| tx.status.confirmed).count();
+        // esplora pages on 25 confirmed transactions. If there's 25 or more we
+        // keep requesting to see if there's more.
+        if n_confirmed >= 25 {
+            loop {
+                let new_related_txs =
+                    self._scripthash_txs(script, Some(related_txs.last().unwrap().txid))?;
+                let n = new_related_txs.len();
+                related_txs.extend(new_related_txs);
+                // we've reached the end
+                if n < 25 {
+                    break;
+                }
+            }
+        }
+
+        Ok(related_txs)
+    }
+
+    /// Send a GET request to `url`, without retrying it
+    fn get_once(&self, url: &str) -> Result<Response, ureq::Error> {
+        self.wait_rate_limiter();
+        self.requests_sent.fetch_add(1, Ordering::Relaxed);
+        let started = get_timestamp();
+        let start = Instant::new();
+        let resp = self.request("GET", url).call();
+        self.record_har("GET", url, None, started, &start, &resp);
+        self.record_latency("GET", url, start);
+        resp
+    }
+
+    /// Block until the rate limiter, if any, allows sending a request
+    fn wait_rate_limiter(&self) {
+        if let Some(limiter) = &self.rate_limiter {
+            limiter.wait();
+        }
+    }
+
+    /// Record the request sent to `url` at `start` and its response, if the requests are recorded
+    fn record_har(
+        &self,
+        method: &str,
+        url: &str,
+        body: Option<String>,
+        started: u64,
+        start: &Instant,
+        resp: &Result<Response, ureq::Error>,
+    ) {
+        let har = match &self.har {
+            Some(har) => har,
+            None => return,
+        };
+
+        let response = match resp {
+            Ok(resp) | Err(ureq::Error::Status(_, resp)) => Ok(resp),
+            Err(e) => Err(e.to_string()),
+        };
+        har.record(HarEntry {
+            started,
+            time: start.elapsed(),
+            method: method.to_string(),
+            // the URL of the response is the one its body is recorded with
+            url: response
+                .as_ref()
+                .map_or_else(|_| url.to_string(), |resp| resp.get_url().to_string()),
+            headers: self.headers.clone(),
+            body,
+            response: response.map(|resp| HarResponse {
+                status: resp.status(),
+                status_text: resp.status_text().to_string(),
+                headers: resp
+                    .headers_names()
+                    .into_iter()
+                    .filter_map(|name| {
+                        let value = resp.header(&name)?.to_string();
+                        Some((name, value))
+                    })
+                    .collect(),
+                body: None,
+            }),
+        });
+    }
+
+    /// Record the latency of a request sent to `url` at `start`
+    fn record_latency(&self, method: &str, url: &str, start: Instant) {
+        let category = EndpointCategory::from_request(&self.url, method, url);
+        self.latencies.record(category, start.elapsed());
+    }
+
+    /// Build a request to `url` carrying the configured headers
+    fn request(&self, method: &str, url: &str) -> Request {
+        self.headers
+            .iter()
+            .fold(self.agent.request(method, url), |request, (name, value)| {
+                request.set(name, value)
+            })
+    }
+
+    /// Send a GET request to `url`, retrying up to `self.retry` times after a transport error or a
+    /// 5xx response
+    fn get(&self, url: &str) -> Result<Response, ureq::Error> {
+        let mut attempt = 0;
+        loop {
+            match self.get_once(url) {
+                Err(e) if attempt < self.retry && is_retryable(&e) => {
+                    debug!("Retrying request to {} after error: {}", url, e);
+                    std::thread::sleep(retry_delay(attempt));
+                    attempt += 1;
+                }
+                resp => return resp,
+            }
+        }
+    }
+
+    fn read_bytes(&self, resp: Response) -> Result<Vec<u8>, EsploraError> {
+        let url = self.har.as_ref().map(|_| resp.get_url().to_string());
+        let bytes = into_bytes(resp, self.max_response_bytes.unwrap_or(BYTES_LIMIT))?;
+        if let (Some(har), Some(url)) = (&self.har, url) {
+            har.record_body(&url, &bytes);
+        }
+        self.bytes_received
+            .fetch_add(bytes.len() as u64, Ordering::Relaxed);
+        Ok(bytes)
+    }
+
+    fn read_string(&self, resp: Response) -> Result<String, EsploraError> {
+        String::from_utf8(self.read_bytes(resp)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e).into())
+    }
+
+    fn read_json<T: DeserializeOwned>(&self, resp: Response) -> Result<T, EsploraError> {
+        let url = self.har.as_ref().map(|_| resp.get_url().to_string());
+        let bytes = into_bytes(resp, self.max_response_bytes.unwrap_or(usize::MAX))?;
+        if let (Some(har), Some(url)) = (&self.har, url) {
+            har.record_body(&url, &bytes);
+        }
+        self.bytes_received
+            .fetch_add(bytes.len() as u64, Ordering::Relaxed);
+        Ok(serde_json::from_slice(&bytes).map_err(io::Error::from)?)
+    }
+}
+
+/// Headers whose value is replaced with [`REDACTED`] in the HAR exports, compared ignoring the case
+const SENSITIVE_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+];
+
+/// Value of the sensitive headers in the HAR exports
+const REDACTED: &str = "[REDACTED]";
+
+/// Response to a request recorded in a [`HarEntry`]
+#[derive(Debug, Clone)]
+struct HarResponse {
+    status: u16,
+    status_text: String,
+    headers: Vec<(String, String)>,
+    /// Body of the response, once it's been read
+    body: Option<Vec<u8>>,
+}
+
+/// Request sent to the server and its response, or the error that prevented receiving it
+#[derive(Debug, Clone)]
+struct HarEntry {
+    /// Unix timestamp of the request, in seconds
+    started: u64,
+    /// Time until the headers of the response were received
+    time: Duration,
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Option<String>,
+    response: Result<HarResponse, String>,
+}
+
+/// Requests sent by the clones of a client, kept to be exported in the HAR (HTTP Archive) format
+#[derive(Debug, Default)]
+struct HarRecorder(Mutex<Vec<HarEntry>>);
+
+impl HarRecorder {
+    fn record(&self, entry: HarEntry) {
+        self.0.lock().unwrap().push(entry);
+    }
+
+    /// Attach `body` to the last response received from `url` whose body wasn't read yet
+    fn record_body(&self, url: &str, body: &[u8]) {
+        let mut entries = self.0.lock().unwrap();
+        let response = entries
+            .iter_mut()
+            .rev()
+            .filter(|entry| entry.url == url)
+            .find_map(|entry| {
+                entry
+                    .response
+                    .as_mut()
+                    .ok()
+                    .filter(|resp| resp.body.is_none())
+            });
+        if let Some(response) = response {
+            response.body = Some(body.to_vec());
+        }
+    }
+
+    /// Serialize the recorded entries as a HAR 1.2 archive, redacting the sensitive headers
+    fn export(&self) -> String {
+        let headers = |headers: &[(String, String)]| {
+            headers
+                .iter()
+                .map(|(name, value)| {
+                    let sensitive = SENSITIVE_HEADERS
+                        .iter()
+                        .any(|sensitive| name.eq_ignore_ascii_case(sensitive));
+                    serde_json::json!({
+                        "name": name,
+                        "value": if sensitive { REDACTED } else { value.as_str() },
+                    })
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let entries = self
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|entry| {
+                let time = entry.time.as_secs_f64() * 1_000.0;
+                let mut request = serde_json::json!({
+                    "method": entry.method,
+                    "url": entry.url,
+                    "httpVersion": "HTTP/1.1",
+                    "cookies": [],
+                    "headers": headers(&entry.headers),
+                    "queryString": [],
+                    "headersSize": -1,
+                    "bodySize": entry.body.as_ref().map_or(0, |body| body.len()),
+                });
+                if let Some(body) = &entry.body {
+                    request["postData"] = serde_json::json!({
+                        "mimeType": "text/plain",
+                        "text": body,
+                    });
+                }
+
+                let response = match &entry.response {
+                    Ok(response) => {
+                        let header = |name: &str| {
+                            response
+                                .headers
+                                .iter()
+                                .find(|(header, _)| header.eq_ignore_ascii_case(name))
+                                .map(|(_, value)| value.as_str())
+                        };
+                        let size = match &response.body {
+                            Some(body) => body.len(),
+                            None => header("Content-Length")
+                                .and_then(|len| len.parse().ok())
+                                .unwrap_or(0),
+                        };
+                        let mut content = serde_json::json!({
+                            "size": size,
+                            "mimeType": header("Content-Type").unwrap_or(""),
+                        });
+                        // binary bodies, like raw transactions and blocks, are encoded in base64
+                        match response.body.as_ref().map(|body| std::str::from_utf8(body)) {
+                            Some(Ok(text)) => content["text"] = text.into(),
+                            Some(Err(_)) => {
+                                content["text"] =
+                                    bitcoin::base64::encode(response.body.as_ref().unwrap()).into();
+                                content["encoding"] = "base64".into();
+                            }
+                            None => {}
+                        }
+
+                        serde_json::json!({
+                            "status": response.status,
+                            "statusText": response.status_text,
+                            "httpVersion": "HTTP/1.1",
+                            "cookies": [],
+                            "headers": headers(&response.headers),
+                            "content": content,
+                            "redirectURL": "",
+                            "headersSize": -1,
+                            "bodySize": -1,
+                        })
+                    }
+                    // no response was received, HAR viewers show a status of 0 as a failed request
+                    Err(error) => serde_json::json!({
+                        "status": 0,
+                        "statusText": "",
+                        "httpVersion": "",
+                        "cookies": [],
+                        "headers": [],
+                        "content": { "size": 0, "mimeType": "" },
+                        "redirectURL": "",
+                        "headersSize": -1,
+                        "bodySize": -1,
+                        "_error": error,
+                    }),
+                };
+
+                serde_json::json!({
+                    "startedDateTime": iso_8601(entry.started),
+                    "time": time,
+                    "request": request,
+                    "response": response,
+                    "cache": {},
+                    "timings": { "send": 0, "wait": time, "receive": 0 },
+                })
+            })
+            .collect::<Vec<_>>();
+
+        serde_json::json!({
+            "log": {
+                "version": "1.2",
+                "creator": { "name": "bdk", "version": env!("CARGO_PKG_VERSION") },
+                "entries": entries,
+            }
+        })
+        .to_string()
     }
 }
 
+/// Format a unix `timestamp` (seconds) as an ISO 8601 date and time in UTC
+fn iso_8601(timestamp: u64) -> String {
+    let (days, secs) = (timestamp / 86_400, timestamp % 86_400);
+
+    // civil date from the days since the unix epoch, see
+    // http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3_600,
+        secs % 3_600 / 60,
+        secs % 60
+    )
+}
+
+/// Wait for a request sent from another thread, turning a panic of the thread into an error
+fn join_request<T, E: From<EsploraError>>(handle: JoinHandle<Result<T, E>>) -> Result<T, E> {
+    handle
+        .join()
+        .unwrap_or_else(|_| Err(EsploraError::RequestThreadPanicked.into()))
+}
+
 fn is_status_not_found(status: u16) -> bool {
     status == 404
 }
 
-fn into_bytes(resp: Response) -> Result<Vec<u8>, io::Error> {
-    const BYTES_LIMIT: usize = 10 * 1_024 * 1_024;
+fn is_retryable(e: &ureq::Error) -> bool {
+    match e {
+        ureq::Error::Status(code, _) => is_retryable_status(*code),
+        ureq::Error::Transport(_) => true,
+    }
+}
+
+/// Build an [`EsploraError::HttpResponse`] from an error response, reading at most
+/// [`MAX_ERROR_MESSAGE_BYTES`] of its body
+fn into_http_response_error(status: u16, resp: Response) -> EsploraError {
+    let mut body = vec![];
+    let _ = resp
+        .into_reader()
+        .take(MAX_ERROR_MESSAGE_BYTES as u64)
+        .read_to_end(&mut body);
+    http_response_error(status, &body)
+}
+
+/// Maximum size of the raw and text responses when no `max_response_bytes` is configured
+const BYTES_LIMIT: usize = 10 * 1_024 * 1_024;
+
+/// Read the body of `resp`, stopping as soon as it's larger than `max_bytes`
+fn into_bytes(resp: Response, max_bytes: usize) -> Result<Vec<u8>, EsploraError> {
+    let content_length = resp
+        .header("Content-Length")
+        .and_then(|len| len.parse::<usize>().ok());
+    if matches!(content_length, Some(len) if len > max_bytes) {
+        return Err(EsploraError::ResponseTooLarge(max_bytes));
+    }
 
     let mut buf: Vec<u8> = vec![];
     resp.into_reader()
-        .take((BYTES_LIMIT + 1) as u64)
+        .take((max_bytes as u64).saturating_add(1))
         .read_to_end(&mut buf)?;
-    if buf.len() > BYTES_LIMIT {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "response too big for into_bytes",
-        ));
+    if buf.len() > max_bytes {
+        return Err(EsploraError::ResponseTooLarge(max_bytes));
     }
 
     Ok(buf)
@@ -367,15 +1980,732 @@ impl ConfigurableBlockchain for EsploraBlockchain {
             blockchain = blockchain.with_concurrency(concurrency);
         }
 
+        if let Some(internal_stop_gap) = config.internal_stop_gap {
+            blockchain = blockchain.with_internal_stop_gap(internal_stop_gap);
+        }
+
+        if let Some(prevout_concurrency) = config.prevout_concurrency {
+            blockchain = blockchain.with_prevout_concurrency(prevout_concurrency);
+        }
+
+        blockchain = blockchain.with_sync_error_policy(config.sync_error_policy);
+
+        if let Some(verify_block_hashes) = config.verify_block_hashes {
+            blockchain = blockchain.with_verify_block_hashes(verify_block_hashes);
+        }
+
+        blockchain = blockchain.with_validate_proofs(config.validate_proofs);
+
+        if let Some(max_response_bytes) = config.max_response_bytes {
+            blockchain = blockchain.with_max_response_bytes(max_response_bytes);
+        }
+
+        if let Some(sync_mode) = config.sync_mode {
+            blockchain = blockchain.with_sync_mode(sync_mode);
+        }
+
+        if let Some(fee_cache_ttl) = config.fee_cache_ttl {
+            blockchain = blockchain.with_fee_cache_ttl(Duration::from_secs(fee_cache_ttl));
+        }
+
+        if let Some(flavor) = config.flavor {
+            blockchain = blockchain.with_flavor(flavor);
+        }
+
+        if let Some(retry) = config.retry {
+            blockchain = blockchain.with_retry(retry);
+        }
+
+        if let Some(max_requests_per_sync) = config.max_requests_per_sync {
+            blockchain = blockchain.with_max_requests_per_sync(max_requests_per_sync);
+        }
+
+        if let Some(record_har) = config.record_har {
+            blockchain = blockchain.with_record_har(record_har);
+        }
+
+        for (name, value) in config.headers.iter().flatten() {
+            if !is_valid_header(name, value) {
+                return Err(Error::Esplora(Box::new(EsploraError::InvalidHeader(
+                    name.clone(),
+                ))));
+            }
+            blockchain
+                .url_client
+                .headers
+                .push((name.clone(), value.clone()));
+        }
+
         Ok(blockchain)
     }
 }
 
+/// Whether `name: value` is a valid HTTP header, which `ureq` only checks when sending a request
+fn is_valid_header(name: &str, value: &str) -> bool {
+    // see the `token` and `field-value` rules of RFC 7230
+    let is_tchar = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+    !name.is_empty()
+        && name.chars().all(is_tchar)
+        && value
+            .chars()
+            .all(|c| c == '\t' || (c >= ' ' && c != '\x7f'))
+}
+
 impl From<ureq::Error> for EsploraError {
     fn from(e: ureq::Error) -> Self {
         match e {
-            ureq::Error::Status(code, _) => EsploraError::HttpResponse(code),
+            ureq::Error::Status(code, resp) => into_http_response_error(code, resp),
             e => EsploraError::Ureq(e),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::{channel, Receiver};
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::blockchain::esplora::test::mock::{mock_server, mock_status_server};
+    use crate::blockchain::esplora::EsploraBlockchainConfig;
+    use crate::database::{Database, MemoryDatabase};
+    use crate::{KeychainKind, TransactionDetails};
+
+    /// Serve `txs` at `/tx/:txid/raw`, holding every response for a while and keeping track of
+    /// the maximum number of requests in flight at the same time
+    fn mock_tx_server(txs: Vec<Transaction>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+
+        let max = Arc::clone(&max_in_flight);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let txs = txs.clone();
+                let in_flight = Arc::clone(&in_flight);
+                let max_in_flight = Arc::clone(&max);
+                std::thread::spawn(move || {
+                    let mut request = [0u8; 1024];
+                    let n = stream.read(&mut request).unwrap();
+                    let request = String::from_utf8_lossy(&request[..n]);
+                    let path = request.split_whitespace().nth(1).unwrap();
+                    let tx = txs
+                        .iter()
+                        .find(|tx| path == format!("/tx/{}/raw", tx.txid()))
+                        .unwrap();
+
+                    let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_in_flight.fetch_max(current, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(100));
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+
+                    let body = serialize(tx);
+                    let _ = stream.write_all(
+                        format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                            body.len()
+                        )
+                        .as_bytes(),
+                    );
+                    let _ = stream.write_all(&body);
+                });
+            }
+        });
+
+        (url, max_in_flight)
+    }
+
+    /// Serve `body` to every request, sending the head of each request through the channel
+    fn mock_recording_server(body: Vec<u8>) -> (String, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, receiver) = channel();
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = [0u8; 1024];
+                let n = stream.read(&mut request).unwrap();
+                let _ = sender.send(String::from_utf8_lossy(&request[..n]).into_owned());
+
+                let _ = stream.write_all(
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    )
+                    .as_bytes(),
+                );
+                let _ = stream.write_all(&body);
+            }
+        });
+
+        (url, receiver)
+    }
+
+    fn blockchain(url: String, max_response_bytes: usize) -> EsploraBlockchain {
+        let mut config = EsploraBlockchainConfig::new(url, 20);
+        config.max_response_bytes = Some(max_response_bytes);
+        EsploraBlockchain::from_config(&config).unwrap()
+    }
+
+    #[test]
+    fn test_fee_histogram() {
+        let mempool = serde_json::json!({
+            "count": 3_412,
+            "vsize": 1_502_030,
+            "total_fee": 8_452_013,
+            "fee_histogram": [[53.5, 102_030], [12.1, 110_000], [3.2, 1_200_000], [1.0, 90_000]],
+        });
+        let (url, requests) = mock_recording_server(mempool.to_string().into_bytes());
+        let blockchain = EsploraBlockchain::new(&url, 20);
+
+        assert!(blockchain
+            .get_capabilities()
+            .contains(&Capability::FeeHistogram));
+        let histogram = blockchain.fee_histogram().unwrap();
+        assert!(requests.recv().unwrap().starts_with("GET /mempool "));
+        assert_eq!(
+            histogram,
+            vec![
+                (FeeRate::from_sat_per_vb(53.5), 102_030),
+                (FeeRate::from_sat_per_vb(12.1), 110_000),
+                (FeeRate::from_sat_per_vb(3.2), 1_200_000),
+                (FeeRate::from_sat_per_vb(1.0), 90_000),
+            ]
+        );
+        assert!(histogram.windows(2).all(|pair| pair[0].0 > pair[1].0));
+    }
+
+    #[test]
+    fn test_headers() {
+        let (url, requests) = mock_recording_server(b"101".to_vec());
+        let mut config = EsploraBlockchainConfig::new(url, 20);
+        config.headers = Some(
+            vec![
+                ("Authorization".to_string(), "Bearer s3cr3t".to_string()),
+                ("X-Api-Key".to_string(), "abc:123".to_string()),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let blockchain = EsploraBlockchain::from_config(&config).unwrap();
+
+        assert_eq!(blockchain.get_height().unwrap(), 101);
+        let request = requests.recv().unwrap();
+        assert!(request.starts_with("GET /blocks/tip/height "));
+        assert!(request.contains("\r\nAuthorization: Bearer s3cr3t\r\n"));
+        assert!(request.contains("\r\nX-Api-Key: abc:123\r\n"));
+
+        for (name, value) in &[("X Api Key", "abc"), ("", "abc"), ("X-Api-Key", "abc\r\n")] {
+            config.headers = Some(
+                vec![(name.to_string(), value.to_string())]
+                    .into_iter()
+                    .collect(),
+            );
+            match EsploraBlockchain::from_config(&config) {
+                Err(Error::Esplora(e)) => {
+                    assert!(matches!(*e, EsploraError::InvalidHeader(ref n) if n == *name))
+                }
+                r => panic!("unexpected result: {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn test_max_response_bytes() {
+        let url = mock_server(vec![b'1'; 16 * 1_024 * 1_024]);
+        match blockchain(url, 1_024).get_height() {
+            Err(Error::Esplora(e)) => {
+                assert!(matches!(*e, EsploraError::ResponseTooLarge(1_024)))
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        let url = mock_server(b"101".to_vec());
+        assert_eq!(blockchain(url, 1_024).get_height().unwrap(), 101);
+    }
+
+    #[test]
+    fn test_prevout_concurrency() {
+        let txs = (0..8)
+            .map(|lock_time| Transaction {
+                version: 1,
+                lock_time,
+                input: vec![],
+                output: vec![],
+            })
+            .collect::<Vec<_>>();
+        let (url, max_in_flight) = mock_tx_server(txs.clone());
+
+        let mut config = EsploraBlockchainConfig::new(url, 20);
+        config.concurrency = Some(8);
+        assert_eq!(
+            EsploraBlockchain::from_config(&config)
+                .unwrap()
+                .prevout_concurrency(),
+            8,
+            "defaults to the main concurrency"
+        );
+
+        config.prevout_concurrency = Some(3);
+        let blockchain = EsploraBlockchain::from_config(&config).unwrap();
+        let fetched = blockchain
+            .url_client
+            ._get_txs(
+                txs.iter().map(Transaction::txid).collect(),
+                blockchain.prevout_concurrency(),
+            )
+            .unwrap();
+
+        assert_eq!(fetched.len(), txs.len());
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_fee_cache_round_trip() {
+        let ttl = Duration::from_secs(600);
+        let url = mock_server(br#"{"1": 20.0, "6": 10.0}"#.to_vec());
+        let blockchain = EsploraBlockchain::new(&url, 20).with_fee_cache_ttl(ttl);
+        assert_eq!(blockchain.dump_fee_cache().unwrap(), None);
+        assert_eq!(
+            blockchain.estimate_fee(6).unwrap(),
+            FeeRate::from_sat_per_vb(10.0)
+        );
+        // the mock server only answers once, this comes from the cache
+        assert_eq!(
+            blockchain.estimate_fee(1).unwrap(),
+            FeeRate::from_sat_per_vb(20.0)
+        );
+        let data = blockchain.dump_fee_cache().unwrap().unwrap();
+
+        // after a restart the restored estimates are used without any request
+        let restored = EsploraBlockchain::new(&url, 20).with_fee_cache_ttl(ttl);
+        restored.load_fee_cache(&data).unwrap();
+        assert_eq!(
+            restored.estimate_fee(1).unwrap(),
+            FeeRate::from_sat_per_vb(20.0)
+        );
+
+        // expired estimates are ignored, so the request to the server fails
+        let expired = EsploraBlockchain::new(&url, 20).with_fee_cache_ttl(ttl);
+        expired
+            .load_fee_cache(r#"{"timestamp":0,"estimates":{"1":20.0}}"#)
+            .unwrap();
+        assert_eq!(expired.dump_fee_cache().unwrap(), None);
+        assert!(expired.estimate_fee(1).is_err());
+    }
+
+    #[test]
+    fn test_retry() {
+        let tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output: vec![],
+        };
+        let retrying = |url: String, retry: Option<u8>| {
+            let mut config = EsploraBlockchainConfig::new(url, 20);
+            config.retry = retry;
+            EsploraBlockchain::from_config(&config).unwrap()
+        };
+
+        let (url, requests) = mock_status_server(vec![(503, String::new()); 2], serialize(&tx));
+        assert_eq!(
+            retrying(url, Some(2)).get_tx(&tx.txid()).unwrap(),
+            Some(tx.clone())
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        // no retries by default
+        let (url, requests) = mock_status_server(vec![(503, String::new()); 2], serialize(&tx));
+        match retrying(url, None).get_tx(&tx.txid()) {
+            Err(Error::Esplora(e)) => {
+                assert!(matches!(*e, EsploraError::HttpResponse { status: 503, .. }))
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // a 404 is a valid answer and is never retried
+        let (url, requests) = mock_status_server(vec![(404, String::new())], serialize(&tx));
+        assert_eq!(retrying(url, Some(2)).get_tx(&tx.txid()).unwrap(), None);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output: vec![],
+        };
+        let (url, requests) = mock_status_server(vec![(503, String::new()); 2], serialize(&tx));
+        let blockchain = EsploraBlockchain::new(&url, 20)
+            .with_retry(2)
+            .with_rate_limiter(RateLimiter::new(20.0).unwrap());
+
+        // every attempt waits for its own token
+        let start = std::time::Instant::now();
+        assert_eq!(blockchain.get_tx(&tx.txid()).unwrap(), Some(tx.clone()));
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_http_response_message() {
+        let get_error = |message: String| {
+            let (url, _) = mock_status_server(vec![(429, message)], b"101".to_vec());
+            match EsploraBlockchain::new(&url, 20).get_height() {
+                Err(Error::Esplora(e)) => *e,
+                r => panic!("unexpected result: {:?}", r),
+            }
+        };
+
+        let error = get_error("rate limited".to_string());
+        assert_eq!(error.to_string(), "HTTP error 429: rate limited");
+        match error {
+            EsploraError::HttpResponse { status, message } => {
+                assert_eq!(status, 429);
+                assert_eq!(message, "rate limited");
+            }
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        // long bodies are truncated
+        match get_error("x".repeat(4_096)) {
+            EsploraError::HttpResponse { message, .. } => assert_eq!(message.len(), 1_024),
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_max_requests_per_sync() {
+        let tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output: vec![],
+        };
+        let database = || {
+            let mut database = MemoryDatabase::new();
+            for (i, keychain) in [KeychainKind::External, KeychainKind::Internal]
+                .iter()
+                .enumerate()
+            {
+                for index in 0..10 {
+                    database
+                        .set_script_pubkey(
+                            &Script::from(vec![i as u8, index]),
+                            *keychain,
+                            index.into(),
+                        )
+                        .unwrap();
+                }
+            }
+            database
+                .set_tx(&TransactionDetails {
+                    txid: tx.txid(),
+                    transaction: Some(tx.clone()),
+                    ..Default::default()
+                })
+                .unwrap();
+            database
+        };
+        // every script pubkey has an empty history
+        let sync = |database: &mut MemoryDatabase, max_requests_per_sync| {
+            let (url, requests) = mock_status_server(vec![], b"[]".to_vec());
+            let mut config = EsploraBlockchainConfig::new(url, 20);
+            config.max_requests_per_sync = max_requests_per_sync;
+            let stats = EsploraBlockchain::from_config(&config)
+                .unwrap()
+                .wallet_setup_with_stats(database, Box::new(NoopProgress))
+                .unwrap();
+            (stats, requests.load(Ordering::SeqCst))
+        };
+
+        let mut limited = database();
+        let (stats, requests) = sync(&mut limited, Some(3));
+        assert!(stats.request_budget_exhausted);
+        assert_eq!(stats.scripts_scanned, 3);
+        assert_eq!(requests, 3);
+        // the sync is incomplete, so the transaction that wasn't found is kept
+        assert!(limited.get_tx(&tx.txid(), false).unwrap().is_some());
+
+        let mut unlimited = database();
+        let (stats, requests) = sync(&mut unlimited, None);
+        assert!(!stats.request_budget_exhausted);
+        assert_eq!(stats.scripts_scanned, 20);
+        assert_eq!(requests, 20);
+        assert!(unlimited.get_tx(&tx.txid(), false).unwrap().is_none());
+
+        // the budget can also be given to a single sync, and is counted again for every sync
+        let (url, requests) = mock_status_server(vec![], b"[]".to_vec());
+        let blockchain =
+            EsploraBlockchain::from_config(&EsploraBlockchainConfig::new(url, 20)).unwrap();
+        for _ in 0..2 {
+            let stats = blockchain
+                .wallet_setup_with_budget(&mut database(), Box::new(NoopProgress), 3)
+                .unwrap();
+            assert!(stats.request_budget_exhausted);
+            assert_eq!(stats.scripts_scanned, 3);
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 6);
+    }
+
+    /// Set `BDK_ESPLORA_TESTNET4_URL` to the base url of a testnet4 Esplora server to run this
+    /// test
+    #[test]
+    #[ignore]
+    fn test_esplora_testnet4() {
+        use crate::wallet::AddressIndex;
+        use crate::Wallet;
+
+        let url = std::env::var("BDK_ESPLORA_TESTNET4_URL").unwrap();
+        let blockchain =
+            EsploraBlockchain::from_config(&EsploraBlockchainConfig::new(url, 20)).unwrap();
+        let testnet4 = BlockHash::from_hex(TESTNET4_GENESIS_HASH).unwrap();
+        assert_eq!(blockchain.get_block_hash(0).unwrap(), Some(testnet4));
+        assert_eq!(blockchain.get_network().unwrap(), None);
+        assert!(blockchain.get_height().unwrap() > 0);
+
+        let wallet = Wallet::new(
+            "wpkh(tprv8ZgxMBicQKsPd3EupYiPRhaMooHKUHJxNsTfYuScep13go8QFfHdtkG9nRkFGb7busX4isf6X9dURGCoKgitaApQ6MupRhZMcELAxTBRJgS/*)",
+            None,
+            Network::Testnet,
+            MemoryDatabase::new(),
+        )
+        .unwrap();
+        let address = wallet.get_address(AddressIndex::New).unwrap();
+        assert!(address.to_string().starts_with("tb1"));
+        wallet.sync(&blockchain, Default::default()).unwrap();
+    }
+
+    const CONFIRMED_TXID: &str = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+    const UNCONFIRMED_TXID: &str =
+        "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb";
+
+    /// Serve `/blocks/tip/height` right away, `/fee-estimates` after 50ms, the status of
+    /// `CONFIRMED_TXID` and `UNCONFIRMED_TXID` and a 404 for any other path
+    fn mock_routing_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = [0u8; 1024];
+                let n = stream.read(&mut request).unwrap();
+                let request = String::from_utf8_lossy(&request[..n]);
+                let path = request.split_whitespace().nth(1).unwrap();
+                let (status, body) = match path {
+                    "/blocks/tip/height" => ("200 OK", "42"),
+                    "/fee-estimates" => {
+                        std::thread::sleep(Duration::from_millis(50));
+                        ("200 OK", "{}")
+                    }
+                    _ if path == format!("/tx/{}/status", CONFIRMED_TXID) => {
+                        ("200 OK", r#"{"confirmed":true,"block_height":40}"#)
+                    }
+                    _ if path == format!("/tx/{}/status", UNCONFIRMED_TXID) => {
+                        ("200 OK", r#"{"confirmed":false}"#)
+                    }
+                    _ => ("404 Not Found", "Transaction not found"),
+                };
+
+                let _ = stream.write_all(
+                    format!(
+                        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    )
+                    .as_bytes(),
+                );
+            }
+        });
+
+        url
+    }
+
+    #[test]
+    fn test_broadcast_batch() {
+        let (url, requests) = mock_recording_server(vec![]);
+        let blockchain = EsploraBlockchain::new(&url, 20);
+
+        let tx = |previous_output: OutPoint, value: u64| Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![bitcoin::TxIn {
+                previous_output,
+                ..Default::default()
+            }],
+            output: vec![bitcoin::TxOut {
+                value,
+                script_pubkey: Script::from(vec![0x51]),
+            }],
+        };
+        let parent = tx(OutPoint::default(), 20_000);
+        let child = tx(OutPoint::new(parent.txid(), 0), 10_000);
+
+        assert_eq!(
+            blockchain
+                .broadcast_batch(&[parent.clone(), child.clone()])
+                .unwrap(),
+            vec![parent.txid(), child.txid()]
+        );
+        // the child is only sent once the parent was accepted
+        for _ in 0..2 {
+            assert!(requests.recv().unwrap().starts_with("POST /tx "));
+        }
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_get_tx_confirmations() {
+        let blockchain = EsploraBlockchain::new(&mock_routing_server(), 20);
+
+        let confirmed = Txid::from_hex(CONFIRMED_TXID).unwrap();
+        assert_eq!(
+            blockchain.get_tx_confirmations(&confirmed).unwrap(),
+            Some(3)
+        );
+        let unconfirmed = Txid::from_hex(UNCONFIRMED_TXID).unwrap();
+        assert_eq!(
+            blockchain.get_tx_confirmations(&unconfirmed).unwrap(),
+            Some(0)
+        );
+        let unknown = Txid::from_hex(&"cc".repeat(32)).unwrap();
+        assert_eq!(blockchain.get_tx_confirmations(&unknown).unwrap(), None);
+    }
+
+    #[test]
+    fn test_export_har() {
+        let mut config = EsploraBlockchainConfig::new(mock_routing_server(), 20);
+        config.headers = Some(
+            vec![
+                ("Authorization".to_string(), "Bearer s3cr3t".to_string()),
+                ("X-Client".to_string(), "bdk-test".to_string()),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        config.record_har = Some(true);
+        let blockchain = EsploraBlockchain::from_config(&config).unwrap();
+
+        assert_eq!(blockchain.get_height().unwrap(), 42);
+        let confirmed = Txid::from_hex(CONFIRMED_TXID).unwrap();
+        assert_eq!(
+            blockchain.get_tx_confirmations(&confirmed).unwrap(),
+            Some(3)
+        );
+        let unknown = Txid::from_hex(&"cc".repeat(32)).unwrap();
+        assert!(blockchain.get_tx(&unknown).unwrap().is_none());
+
+        let har = blockchain.export_har();
+        assert!(!har.contains("s3cr3t"));
+        let har: serde_json::Value = serde_json::from_str(&har).unwrap();
+        assert_eq!(har["log"]["version"], "1.2");
+        let entries = har["log"]["entries"].as_array().unwrap();
+        assert_eq!(entries.len(), 4);
+
+        let paths = entries
+            .iter()
+            .map(|entry| {
+                let url = entry["request"]["url"].as_str().unwrap();
+                url.splitn(4, '/').nth(3).unwrap().to_string()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec![
+                "blocks/tip/height".to_string(),
+                format!("tx/{}/status", CONFIRMED_TXID),
+                "blocks/tip/height".to_string(),
+                format!("tx/{}/raw", unknown),
+            ]
+        );
+
+        let entry = &entries[0];
+        assert_eq!(entry["request"]["method"], "GET");
+        let headers = entry["request"]["headers"].as_array().unwrap();
+        assert!(headers.contains(&serde_json::json!({
+            "name": "Authorization",
+            "value": "[REDACTED]",
+        })));
+        assert!(headers.contains(&serde_json::json!({
+            "name": "X-Client",
+            "value": "bdk-test",
+        })));
+        assert!(entry["startedDateTime"].as_str().unwrap().ends_with('Z'));
+        assert_eq!(entry["response"]["status"], 200);
+        assert_eq!(entry["response"]["content"]["text"], "42");
+        assert_eq!(
+            entries[1]["response"]["content"]["text"],
+            r#"{"confirmed":true,"block_height":40}"#
+        );
+        // the body of the 404 isn't read
+        assert_eq!(entries[3]["response"]["status"], 404);
+        assert!(entries[3]["response"]["content"]["text"].is_null());
+
+        // nothing is recorded by default
+        let blockchain = EsploraBlockchain::new(&mock_routing_server(), 20);
+        blockchain.get_height().unwrap();
+        let har: serde_json::Value = serde_json::from_str(&blockchain.export_har()).unwrap();
+        assert!(har["log"]["entries"].as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_iso_8601() {
+        assert_eq!(iso_8601(0), "1970-01-01T00:00:00Z");
+        assert_eq!(iso_8601(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(iso_8601(1_700_000_000), "2023-11-14T22:13:20Z");
+    }
+
+    #[test]
+    fn test_latency_report() {
+        let url = mock_routing_server();
+        let blockchain = EsploraBlockchain::new(&url, 20);
+        assert!(blockchain.latency_report().endpoints.is_empty());
+
+        for _ in 0..4 {
+            assert_eq!(blockchain.get_height().unwrap(), 42);
+        }
+        for _ in 0..3 {
+            assert!(blockchain.fee_estimates().unwrap().is_empty());
+        }
+        let txid = Txid::from_hash(Hash::hash(b"unknown"));
+        assert!(blockchain.get_tx(&txid).unwrap().is_none());
+
+        let report = blockchain.latency_report();
+        assert_eq!(
+            report.endpoints.keys().copied().collect::<Vec<_>>(),
+            vec![
+                EndpointCategory::Transaction,
+                EndpointCategory::Block,
+                EndpointCategory::FeeEstimates
+            ]
+        );
+        let block = report.endpoints[&EndpointCategory::Block];
+        let fees = report.endpoints[&EndpointCategory::FeeEstimates];
+        assert_eq!(block.requests, 4);
+        assert_eq!(fees.requests, 3);
+        assert_eq!(report.endpoints[&EndpointCategory::Transaction].requests, 1);
+
+        assert!(fees.p50 >= Duration::from_millis(50));
+        assert!(fees.p50 > block.p50);
+        assert!(fees.p50 <= fees.p95 && fees.p95 <= fees.p99);
+
+        // the clones share the same report
+        let _ = blockchain.clone().get_height().unwrap();
+        assert_eq!(
+            blockchain.latency_report().endpoints[&EndpointCategory::Block].requests,
+            5
+        );
+    }
+}
@@ -0,0 +1,435 @@
+// Bitcoin Dev Kit
+//
+// Copyright (c) 2020-2022 Bitcoin Dev Kit Developers
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Failover between multiple blockchain backends
+//!
+//! This module provides [`FailoverBlockchain`], which holds an ordered list of backends and
+//! tries each of them in turn for every call, returning the first success. If every backend
+//! fails, the call fails with [`Error::AllBackendsFailed`] and the error returned by each of
+//! them.
+//!
+//! Transactions are broadcast to every backend to maximize their propagation, and the broadcast
+//! succeeds if any of them accepts the transaction.
+//!
+//! The backends must all have the same type: backends of different types can be mixed by
+//! wrapping them in an [`AnyBlockchain`](crate::blockchain::AnyBlockchain).
+//!
+//! ## Example
+//!
+//! ```no_run
+//! # use bdk::blockchain::*;
+//! # #[cfg(all(feature = "electrum", feature = "esplora", not(feature = "async-interface")))]
+//! # {
+//! let blockstream = EsploraBlockchain::new("https://blockstream.info/api", 20);
+//! let mempool = EsploraBlockchain::new("https://mempool.space/api", 20);
+//! let local = ElectrumBlockchain::from(bdk::electrum_client::Client::new(
+//!     "tcp://localhost:50001",
+//! )?);
+//!
+//! let blockchain = FailoverBlockchain::new(vec![
+//!     AnyBlockchain::from(blockstream),
+//!     AnyBlockchain::from(mempool),
+//!     AnyBlockchain::from(local),
+//! ]);
+//! let height = blockchain.get_height()?;
+//! # }
+//! # Ok::<(), bdk::Error>(())
+//! ```
+
+use super::*;
+
+/// Call `$name` on every backend in order until one of them succeeds, or fail with
+/// [`Error::AllBackendsFailed`]
+macro_rules! try_in_order {
+    ( $self:expr, $name:ident $(, $args:expr)* ) => {{
+        let mut errors = vec![];
+        for backend in &$self.backends {
+            match maybe_await!(backend.$name( $($args, )* )) {
+                Ok(value) => return Ok(value),
+                Err(e) => {
+                    log::warn!("Backend failed in `{}`: {}", stringify!($name), e);
+                    errors.push(e);
+                }
+            }
+        }
+
+        Err(Error::AllBackendsFailed(errors))
+    }};
+}
+
+/// Blockchain that tries multiple backends in order
+///
+/// See [this module](crate::blockchain::failover)'s documentation for a usage example.
+#[derive(Debug)]
+pub struct FailoverBlockchain<B> {
+    backends: Vec<B>,
+}
+
+/// Configuration for a [`FailoverBlockchain`]
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq)]
+pub struct FailoverBlockchainConfig<C> {
+    /// Configurations of the backends, in the order they are tried
+    pub backends: Vec<C>,
+}
+
+/// [`Progress`] shared between the attempts of a sync
+#[derive(Debug, Clone)]
+struct SharedProgress(Arc<Mutex<Box<dyn Progress>>>);
+
+impl Progress for SharedProgress {
+    fn update(&self, progress: f32, message: Option<String>) -> Result<(), Error> {
+        self.0.lock().unwrap().update(progress, message)
+    }
+
+    fn update_sync(&self, progress: &SyncProgress) -> Result<(), Error> {
+        self.0.lock().unwrap().update_sync(progress)
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.0.lock().unwrap().is_cancelled()
+    }
+}
+
+impl<B> FailoverBlockchain<B> {
+    /// Create a new instance trying `backends` in order
+    pub fn new(backends: Vec<B>) -> Self {
+        FailoverBlockchain { backends }
+    }
+
+    /// Return the backends, in the order they are tried
+    pub fn backends(&self) -> &[B] {
+        &self.backends
+    }
+}
+
+#[maybe_async]
+impl<B: Blockchain> Blockchain for FailoverBlockchain<B> {
+    fn get_capabilities(&self) -> HashSet<Capability> {
+        let mut capabilities: Option<HashSet<Capability>> = None;
+        for backend in &self.backends {
+            let backend_capabilities = maybe_await!(backend.get_capabilities());
+            capabilities = Some(match capabilities {
+                Some(capabilities) => capabilities
+                    .intersection(&backend_capabilities)
+                    .cloned()
+                    .collect(),
+                None => backend_capabilities,
+            });
+        }
+
+        capabilities.unwrap_or_default()
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<(), Error> {
+        let mut errors = vec![];
+        for backend in &self.backends {
+            if let Err(e) = maybe_await!(backend.broadcast(tx)) {
+                log::warn!("Backend failed to broadcast {}: {}", tx.txid(), e);
+                errors.push(e);
+            }
+        }
+
+        if errors.len() < self.backends.len() {
+            Ok(())
+        } else {
+            Err(Error::AllBackendsFailed(errors))
+        }
+    }
+
+    fn estimate_fee(&self, target: usize) -> Result<FeeRate, Error> {
+        try_in_order!(self, estimate_fee, target)
+    }
+
+    fn get_tx_confirmations(&self, txid: &Txid) -> Result<Option<u32>, Error> {
+        try_in_order!(self, get_tx_confirmations, txid)
+    }
+}
+
+#[maybe_async]
+impl<B: Blockchain> GetHeight for FailoverBlockchain<B> {
+    fn get_height(&self) -> Result<u32, Error> {
+        try_in_order!(self, get_height)
+    }
+}
+
+#[maybe_async]
+impl<B: Blockchain> GetTx for FailoverBlockchain<B> {
+    fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, Error> {
+        try_in_order!(self, get_tx, txid)
+    }
+}
+
+#[maybe_async]
+impl<B: Blockchain + GetBlockHash> GetBlockHash for FailoverBlockchain<B> {
+    fn get_block_hash(&self, height: u32) -> Result<Option<BlockHash>, Error> {
+        try_in_order!(self, get_block_hash, height)
+    }
+
+    fn get_block_header(&self, height: u32) -> Result<Option<BlockHeader>, Error> {
+        try_in_order!(self, get_block_header, height)
+    }
+}
+
+#[maybe_async]
+impl<B: Blockchain + GetBlock> GetBlock for FailoverBlockchain<B> {
+    fn get_block(&self, hash: &BlockHash) -> Result<Option<Block>, Error> {
+        try_in_order!(self, get_block, hash)
+    }
+}
+
+#[maybe_async]
+impl<B: Blockchain> WalletSync for FailoverBlockchain<B> {
+    fn wallet_setup<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<(), Error> {
+        let progress = SharedProgress(Arc::new(Mutex::new(progress_update)));
+        try_in_order!(self, wallet_setup, database, Box::new(progress.clone()))
+    }
+
+    fn wallet_sync<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<(), Error> {
+        let progress = SharedProgress(Arc::new(Mutex::new(progress_update)));
+        try_in_order!(self, wallet_sync, database, Box::new(progress.clone()))
+    }
+
+    fn wallet_setup_with_stats<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<SyncStats, Error> {
+        let progress = SharedProgress(Arc::new(Mutex::new(progress_update)));
+        try_in_order!(
+            self,
+            wallet_setup_with_stats,
+            database,
+            Box::new(progress.clone())
+        )
+    }
+
+    fn wallet_sync_with_stats<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<SyncStats, Error> {
+        let progress = SharedProgress(Arc::new(Mutex::new(progress_update)));
+        try_in_order!(
+            self,
+            wallet_sync_with_stats,
+            database,
+            Box::new(progress.clone())
+        )
+    }
+
+    fn wallet_setup_with_budget<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+        max_requests: usize,
+    ) -> Result<SyncStats, Error> {
+        let progress = SharedProgress(Arc::new(Mutex::new(progress_update)));
+        try_in_order!(
+            self,
+            wallet_setup_with_budget,
+            database,
+            Box::new(progress.clone()),
+            max_requests
+        )
+    }
+
+    fn wallet_sync_with_budget<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+        max_requests: usize,
+    ) -> Result<SyncStats, Error> {
+        let progress = SharedProgress(Arc::new(Mutex::new(progress_update)));
+        try_in_order!(
+            self,
+            wallet_sync_with_budget,
+            database,
+            Box::new(progress.clone()),
+            max_requests
+        )
+    }
+}
+
+impl<B: ConfigurableBlockchain> ConfigurableBlockchain for FailoverBlockchain<B> {
+    type Config = FailoverBlockchainConfig<B::Config>;
+
+    fn from_config(config: &Self::Config) -> Result<Self, Error> {
+        Ok(FailoverBlockchain::new(
+            config
+                .backends
+                .iter()
+                .map(B::from_config)
+                .collect::<Result<_, _>>()?,
+        ))
+    }
+}
+
+#[cfg(all(test, not(feature = "async-interface")))]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::database::MemoryDatabase;
+
+    #[derive(Default)]
+    struct MockBlockchain {
+        fail: bool,
+        capabilities: Vec<Capability>,
+        broadcast_calls: AtomicUsize,
+        setup_calls: AtomicUsize,
+    }
+
+    impl MockBlockchain {
+        fn failing() -> Self {
+            MockBlockchain {
+                fail: true,
+                ..Default::default()
+            }
+        }
+
+        fn check(&self) -> Result<(), Error> {
+            if self.fail {
+                Err(Error::Generic("backend down".into()))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    impl Blockchain for MockBlockchain {
+        fn get_capabilities(&self) -> HashSet<Capability> {
+            self.capabilities.iter().cloned().collect()
+        }
+
+        fn broadcast(&self, _tx: &Transaction) -> Result<(), Error> {
+            self.broadcast_calls.fetch_add(1, Ordering::SeqCst);
+            self.check()
+        }
+
+        fn estimate_fee(&self, _target: usize) -> Result<FeeRate, Error> {
+            self.check()?;
+            Ok(FeeRate::from_sat_per_vb(5.0))
+        }
+    }
+
+    impl GetHeight for MockBlockchain {
+        fn get_height(&self) -> Result<u32, Error> {
+            self.check()?;
+            Ok(42)
+        }
+    }
+
+    impl GetTx for MockBlockchain {
+        fn get_tx(&self, _txid: &Txid) -> Result<Option<Transaction>, Error> {
+            self.check()?;
+            Ok(None)
+        }
+    }
+
+    impl WalletSync for MockBlockchain {
+        fn wallet_setup<D: BatchDatabase>(
+            &self,
+            _database: &mut D,
+            progress_update: Box<dyn Progress>,
+        ) -> Result<(), Error> {
+            self.setup_calls.fetch_add(1, Ordering::SeqCst);
+            progress_update.update(50.0, None)?;
+            self.check()
+        }
+    }
+
+    fn test_tx() -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output: vec![],
+        }
+    }
+
+    #[test]
+    fn test_failover_to_working_backend() {
+        let blockchain =
+            FailoverBlockchain::new(vec![MockBlockchain::failing(), MockBlockchain::default()]);
+
+        assert_eq!(blockchain.get_height().unwrap(), 42);
+        assert_eq!(
+            blockchain.estimate_fee(6).unwrap(),
+            FeeRate::from_sat_per_vb(5.0)
+        );
+        assert_eq!(blockchain.get_tx(&test_tx().txid()).unwrap(), None);
+
+        let (sender, receiver) = progress();
+        blockchain
+            .wallet_setup(&mut MemoryDatabase::new(), Box::new(sender))
+            .unwrap();
+        for backend in blockchain.backends() {
+            assert_eq!(backend.setup_calls.load(Ordering::SeqCst), 1);
+        }
+        // both attempts reported their progress
+        assert_eq!(receiver.try_iter().count(), 2);
+    }
+
+    #[test]
+    fn test_failover_all_failing() {
+        let blockchain =
+            FailoverBlockchain::new(vec![MockBlockchain::failing(), MockBlockchain::failing()]);
+
+        assert!(matches!(
+            blockchain.get_height(),
+            Err(Error::AllBackendsFailed(errors)) if errors.len() == 2
+        ));
+        assert!(matches!(
+            blockchain.broadcast(&test_tx()),
+            Err(Error::AllBackendsFailed(errors)) if errors.len() == 2
+        ));
+    }
+
+    #[test]
+    fn test_failover_broadcast_to_all() {
+        let blockchain =
+            FailoverBlockchain::new(vec![MockBlockchain::default(), MockBlockchain::failing()]);
+
+        blockchain.broadcast(&test_tx()).unwrap();
+        for backend in blockchain.backends() {
+            assert_eq!(backend.broadcast_calls.load(Ordering::SeqCst), 1);
+        }
+    }
+
+    #[test]
+    fn test_failover_capabilities_intersection() {
+        let blockchain = FailoverBlockchain::new(vec![
+            MockBlockchain {
+                capabilities: vec![Capability::FullHistory, Capability::GetAnyTx],
+                ..Default::default()
+            },
+            MockBlockchain {
+                capabilities: vec![Capability::GetAnyTx, Capability::AccurateFees],
+                ..Default::default()
+            },
+        ]);
+
+        assert_eq!(
+            blockchain.get_capabilities(),
+            vec![Capability::GetAnyTx].into_iter().collect()
+        );
+        assert!(FailoverBlockchain::<MockBlockchain>::new(vec![])
+            .get_capabilities()
+            .is_empty());
+    }
+}
@@ -16,15 +16,19 @@
 //! [Compact Filters/Neutrino](crate::blockchain::compact_filters), along with a generalized trait
 //! [`Blockchain`] that can be implemented to build customized backends.
 
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
 use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
 use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use bitcoin::{Transaction, Txid};
+use bitcoin::{Block, BlockHash, BlockHeader, OutPoint, Script, Transaction, Txid};
 
-use crate::database::BatchDatabase;
+use crate::database::{BatchDatabase, Database};
 use crate::error::Error;
+use crate::wallet::time::Instant;
 use crate::wallet::{wallet_name_from_descriptor, Wallet};
 use crate::{FeeRate, KeychainKind};
 
@@ -35,6 +39,8 @@ use crate::{FeeRate, KeychainKind};
     feature = "rpc"
 ))]
 pub mod any;
+#[cfg(any(feature = "electrum", feature = "async-electrum", feature = "esplora"))]
+mod reorg;
 mod script_sync;
 
 #[cfg(any(
@@ -53,6 +59,12 @@ pub use self::electrum::ElectrumBlockchain;
 #[cfg(feature = "electrum")]
 pub use self::electrum::ElectrumBlockchainConfig;
 
+#[cfg(feature = "async-electrum")]
+#[cfg_attr(docsrs, doc(cfg(feature = "async-electrum")))]
+pub mod async_electrum;
+#[cfg(feature = "async-electrum")]
+pub use self::async_electrum::{AsyncElectrumBlockchain, AsyncElectrumBlockchainConfig};
+
 #[cfg(feature = "rpc")]
 #[cfg_attr(docsrs, doc(cfg(feature = "rpc")))]
 pub mod rpc;
@@ -74,6 +86,29 @@ pub mod compact_filters;
 #[cfg(feature = "compact_filters")]
 pub use self::compact_filters::CompactFiltersBlockchain;
 
+#[cfg(not(any(target_arch = "wasm32", feature = "async-interface")))]
+#[cfg_attr(
+    docsrs,
+    doc(cfg(not(any(target_arch = "wasm32", feature = "async-interface"))))
+)]
+pub mod pool;
+#[cfg(not(any(target_arch = "wasm32", feature = "async-interface")))]
+pub use self::pool::{BlockchainPool, PooledBlockchain};
+
+pub mod routing;
+pub use self::routing::{CapabilityRoutingBlockchain, CapabilityRoutingBlockchainConfig};
+
+pub mod caching;
+pub use self::caching::{CacheTxs, CachingBlockchain, CachingBlockchainConfig, SharedTxCache};
+
+pub mod failover;
+pub use self::failover::{FailoverBlockchain, FailoverBlockchainConfig};
+
+pub mod rate_limited;
+pub use self::rate_limited::{
+    LimitRequests, RateLimitedBlockchain, RateLimitedBlockchainConfig, RateLimiter,
+};
+
 /// Capabilities that can be supported by a [`Blockchain`] backend
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Capability {
@@ -83,6 +118,144 @@ pub enum Capability {
     GetAnyTx,
     /// Can compute accurate fees for the transactions found during sync
     AccurateFees,
+    /// Can fetch full blocks given their hash, see [`GetBlock`]
+    GetBlock,
+    /// Can return the fee histogram of the mempool of the server, see
+    /// `ElectrumBlockchain::fee_histogram` and `EsploraBlockchain::fee_histogram`
+    FeeHistogram,
+}
+
+/// Maximum weight of a transaction relayed by Bitcoin Core nodes with the default policy
+pub const MAX_STANDARD_TX_WEIGHT: usize = 400_000;
+
+/// Hash of the genesis block of testnet4 (BIP94)
+///
+/// There's no `Network` variant for testnet4: it uses the same address and key encodings as
+/// testnet3, so wallets for testnet4 are created with [`Network::Testnet`](bitcoin::Network).
+/// The two chains only differ by their genesis block, so [`network_from_genesis_hash`] doesn't
+/// report testnet4 as testnet3: compare the hash of the genesis block of the backend with this
+/// one to recognize it, eg. with [`GetBlockHash::get_block_hash`].
+pub const TESTNET4_GENESIS_HASH: &str =
+    "00000000da84f2bafbbc53dee25a72ae507ff4914b867c565be350b0da8bf043";
+
+/// Return the network of the chain starting with the block `genesis_hash`
+///
+/// Only the exact genesis blocks of the networks of [`Network`](bitcoin::Network) are recognized.
+/// Returns `None` for any other chain, like testnet4 (see [`TESTNET4_GENESIS_HASH`]) or a custom
+/// signet.
+pub fn network_from_genesis_hash(genesis_hash: &BlockHash) -> Option<bitcoin::Network> {
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::Network;
+
+    [
+        Network::Bitcoin,
+        Network::Testnet,
+        Network::Signet,
+        Network::Regtest,
+    ]
+    .iter()
+    .copied()
+    .find(|network| &genesis_block(*network).block_hash() == genesis_hash)
+}
+
+/// Checks made by [`Blockchain::broadcast_with_policy`] before broadcasting a transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BroadcastPolicy {
+    /// Maximum weight of the transaction, defaults to [`MAX_STANDARD_TX_WEIGHT`]
+    pub max_standard_weight: usize,
+    /// Broadcast the transaction even if it's heavier than `max_standard_weight`, for example
+    /// when submitting it directly to a miner that accepts non-standard transactions
+    pub force: bool,
+}
+
+impl Default for BroadcastPolicy {
+    fn default() -> Self {
+        BroadcastPolicy {
+            max_standard_weight: MAX_STANDARD_TX_WEIGHT,
+            force: false,
+        }
+    }
+}
+
+impl BroadcastPolicy {
+    /// Check `tx` against the policy, failing with [`Error::TxTooLarge`] if it's too heavy
+    pub fn check(&self, tx: &Transaction) -> Result<(), Error> {
+        let weight = tx.weight();
+        if weight > self.max_standard_weight && !self.force {
+            return Err(Error::TxTooLarge { weight });
+        }
+
+        Ok(())
+    }
+}
+
+/// Policy used by a [`Blockchain`] backend to handle errors while fetching the history of a
+/// single script pubkey during a sync
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncErrorPolicy {
+    /// Abort the whole sync as soon as an error is encountered
+    #[default]
+    FailFast,
+    /// Log a warning and continue with the other script pubkeys
+    ///
+    /// The sync is then considered incomplete: transactions already stored in the database are
+    /// kept, even if they haven't been seen again during the sync.
+    SkipErrored,
+}
+
+/// Custom logic to decide whether a script pubkey belongs to the wallet during a sync
+///
+/// Backends syncing through script pubkeys, like [Electrum](crate::blockchain::electrum) and
+/// [Esplora](crate::blockchain::esplora), consult the resolver for every output of the wallet
+/// transactions before looking up the script pubkey in the database. Returning `Some(keychain)`
+/// claims the output for `keychain`, returning `None` falls back to the database lookup.
+///
+/// This is useful for wallets whose outputs can't all be derived from their descriptors, like
+/// some multisig setups.
+///
+/// ## Example
+///
+/// ```
+/// # use bdk::blockchain::OwnershipResolver;
+/// # use bdk::bitcoin::Script;
+/// # use bdk::KeychainKind;
+/// let cosigner_script = Script::from(vec![0x00, 0x14]);
+/// let resolver = OwnershipResolver::new(move |script: &Script| {
+///     if script == &cosigner_script {
+///         Some(KeychainKind::External)
+///     } else {
+///         None
+///     }
+/// });
+/// ```
+#[derive(Clone)]
+pub struct OwnershipResolver(Arc<ResolveFn>);
+
+/// Function wrapped by an [`OwnershipResolver`]
+type ResolveFn = dyn Fn(&Script) -> Option<KeychainKind> + Send + Sync;
+
+impl OwnershipResolver {
+    /// Create a new resolver from a function returning the keychain owning a script pubkey
+    pub fn new<F>(resolver: F) -> Self
+    where
+        F: Fn(&Script) -> Option<KeychainKind> + Send + Sync + 'static,
+    {
+        OwnershipResolver(Arc::new(resolver))
+    }
+
+    /// Return the keychain owning `script`, if the resolver claims it
+    pub fn resolve(&self, script: &Script) -> Option<KeychainKind> {
+        (self.0)(script)
+    }
+}
+
+impl fmt::Debug for OwnershipResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OwnershipResolver")
+    }
 }
 
 /// Trait that defines the actions that must be supported by a blockchain backend
@@ -94,6 +267,605 @@ pub trait Blockchain: WalletSync + GetHeight + GetTx {
     fn broadcast(&self, tx: &Transaction) -> Result<(), Error>;
     /// Estimate the fee rate required to confirm a transaction in a given `target` of blocks
     fn estimate_fee(&self, target: usize) -> Result<FeeRate, Error>;
+
+    /// Broadcast `txs` in order, returning their txids
+    ///
+    /// The transactions must be sorted so that every transaction comes after the ones it spends,
+    /// like a CPFP parent and its child. If a transaction fails to broadcast the following ones
+    /// aren't sent, and [`Error::BatchBroadcast`] reports the error along with the transactions
+    /// that were broadcast.
+    ///
+    /// The default implementation broadcasts one transaction at a time. Backends can send the
+    /// transactions that don't spend each other concurrently, in which case some of the ones
+    /// listed after a failed transaction may have been broadcast too.
+    fn broadcast_batch(&self, txs: &[Transaction]) -> Result<Vec<Txid>, Error> {
+        let mut broadcast = Vec::with_capacity(txs.len());
+        for tx in txs {
+            if let Err(e) = maybe_await!(self.broadcast(tx)) {
+                return Err(Error::BatchBroadcast {
+                    broadcast,
+                    failed: tx.txid(),
+                    error: Box::new(e),
+                });
+            }
+            broadcast.push(tx.txid());
+        }
+
+        Ok(broadcast)
+    }
+
+    /// Return the number of confirmations of a transaction
+    ///
+    /// Returns `Some(0)` for a transaction in the mempool and `None` if the transaction is unknown
+    /// to the backend. The number is computed from the height of the block that confirmed the
+    /// transaction and the current tip returned by [`GetHeight::get_height`].
+    ///
+    /// Only the backend knows where a transaction was confirmed: the default implementation can
+    /// tell unknown transactions apart with [`GetTx::get_tx`] but fails with [`Error::Generic`]
+    /// for the others, backends that track the confirmation height of transactions override it.
+    fn get_tx_confirmations(&self, txid: &Txid) -> Result<Option<u32>, Error> {
+        match maybe_await!(self.get_tx(txid))? {
+            None => Ok(None),
+            Some(_) => Err(Error::Generic(format!(
+                "The backend can't report the confirmations of transaction {}",
+                txid
+            ))),
+        }
+    }
+
+    /// Broadcast a transaction after checking it against `policy`
+    ///
+    /// Transactions heavier than [`BroadcastPolicy::max_standard_weight`], like large
+    /// consolidations, would be rejected by the nodes of the network: they fail locally with
+    /// [`Error::TxTooLarge`] instead of an opaque error from the backend, unless
+    /// [`BroadcastPolicy::force`] is set.
+    fn broadcast_with_policy(
+        &self,
+        tx: &Transaction,
+        policy: &BroadcastPolicy,
+    ) -> Result<(), Error> {
+        policy.check(tx)?;
+        maybe_await!(self.broadcast(tx))
+    }
+
+    /// Store `tx` in `database` as waiting to be broadcast
+    ///
+    /// Persisting a transaction right after signing it and broadcasting it later with
+    /// [`Blockchain::flush_pending_broadcasts`] makes it possible to resume the broadcast if the
+    /// application is interrupted in between.
+    fn persist_pending_broadcast<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        tx: &Transaction,
+    ) -> Result<(), Error> {
+        database.set_pending_tx(tx)
+    }
+
+    /// Broadcast the transactions stored with [`Blockchain::persist_pending_broadcast`], removing
+    /// them from `database` once they've been broadcast
+    ///
+    /// A transaction that fails to broadcast but is already known to the backend, because it was
+    /// broadcast right before an interruption, is considered broadcast. Otherwise the error is
+    /// returned and the transactions not broadcast yet are kept for the next call.
+    ///
+    /// Returns the txids of the transactions broadcast.
+    fn flush_pending_broadcasts<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+    ) -> Result<Vec<Txid>, Error> {
+        let mut broadcast = vec![];
+        for tx in database.iter_pending_txs()? {
+            let txid = tx.txid();
+            if let Err(e) = maybe_await!(self.broadcast(&tx)) {
+                if maybe_await!(self.get_tx(&txid))?.is_none() {
+                    return Err(e);
+                }
+            }
+
+            database.del_pending_tx(&txid)?;
+            broadcast.push(txid);
+        }
+
+        Ok(broadcast)
+    }
+
+    /// Return the wallet transactions whose number of confirmations changed since the last sync,
+    /// given the current `tip_height`
+    ///
+    /// The number of confirmations of every transaction is compared with the one it had at the
+    /// height of the last sync stored in `database`, so that a UI can only refresh the
+    /// transactions that changed. Transactions are returned together with their new number of
+    /// confirmations. If the wallet was never synced every confirmed transaction is returned.
+    ///
+    /// Syncing stores the new tip in `database`, so this must be called with the new `tip_height`
+    /// before the wallet is synced to it.
+    ///
+    /// This only reads from `database`, the backend is never contacted.
+    fn confirmation_updates<D: Database>(
+        &self,
+        database: &D,
+        tip_height: u32,
+    ) -> Result<Vec<(Txid, u32)>, Error> {
+        let last_sync_height = database
+            .get_sync_time()?
+            .map(|sync_time| sync_time.block_time.height);
+        let confirmations = |tip: u32, height: u32| tip.saturating_add(1).saturating_sub(height);
+
+        Ok(database
+            .iter_txs(false)?
+            .into_iter()
+            .filter_map(|details| {
+                let height = details.confirmation_time.as_ref()?.height;
+                let previous = last_sync_height.map_or(0, |tip| confirmations(tip, height));
+                let current = confirmations(tip_height, height);
+                if previous != current {
+                    Some((details.txid, current))
+                } else {
+                    None
+                }
+            })
+            .collect())
+    }
+
+    /// Return the lowest derivation index of `keychain` whose script pubkey has no history
+    ///
+    /// The history is the one synced to `database`, so this should be called right after a sync
+    /// to get the next address that can be handed out without reusing a previous one, even if
+    /// later addresses were already used. If every cached script pubkey has some history the
+    /// index following the last one is returned.
+    ///
+    /// This only reads from `database`, the backend is never contacted.
+    fn next_unused_index<D: Database>(
+        &self,
+        database: &D,
+        keychain: KeychainKind,
+    ) -> Result<u32, Error> {
+        let used = used_script_pubkeys(database)?;
+
+        let mut index = 0;
+        while let Some(script) = database.get_script_pubkey_from_path(keychain, index)? {
+            if !used.contains(&script) {
+                break;
+            }
+            index += 1;
+        }
+
+        Ok(index)
+    }
+
+    /// Return how close the run of unused addresses handed out by the wallet for `keychain` is to
+    /// `stop_gap`
+    ///
+    /// Once a wallet hands out `stop_gap` consecutive addresses that never receive any funds, a
+    /// sync started from scratch (e.g. when restoring from the descriptors) stops scanning before
+    /// reaching the following ones and would miss funds sent to them. Callers can use this to warn
+    /// users before it happens.
+    ///
+    /// Like [`Blockchain::next_unused_index`] this only reads the history synced to `database`.
+    fn gap_usage<D: Database>(
+        &self,
+        database: &D,
+        keychain: KeychainKind,
+        stop_gap: usize,
+    ) -> Result<GapUsage, Error> {
+        let last_used_index = used_script_pubkeys(database)?
+            .iter()
+            .filter_map(|script| database.get_path_from_script_pubkey(script).transpose())
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .filter(|(script_keychain, _)| *script_keychain == keychain)
+            .map(|(_, index)| index)
+            .max();
+        let last_revealed_index = database.get_last_index(keychain)?;
+
+        let unused_run = match (last_revealed_index, last_used_index) {
+            (None, _) => 0,
+            (Some(revealed), None) => revealed + 1,
+            (Some(revealed), Some(used)) => revealed.saturating_sub(used),
+        };
+
+        Ok(GapUsage {
+            last_used_index,
+            last_revealed_index,
+            unused_run,
+            stop_gap,
+        })
+    }
+
+    /// Return the indices of the outputs of `tx` paying to a script pubkey that already appears in
+    /// the history of the wallet
+    ///
+    /// This should be called before broadcasting `tx`, to warn the user that it reuses an address
+    /// of the wallet or one that was already paid by the wallet. Like
+    /// [`Blockchain::next_unused_index`] this only reads the history synced to `database`.
+    fn detect_address_reuse<D: Database>(
+        &self,
+        tx: &Transaction,
+        database: &D,
+    ) -> Result<Vec<usize>, Error> {
+        let used = used_script_pubkeys(database)?;
+
+        Ok(tx
+            .output
+            .iter()
+            .enumerate()
+            .filter(|(_, txout)| used.contains(&txout.script_pubkey))
+            .map(|(index, _)| index)
+            .collect())
+    }
+
+    /// Return the number and total value (sats) of the unspent outputs of the wallet for each
+    /// [`ScriptType`]
+    ///
+    /// This helps understanding the mix of address types of a wallet, for example before
+    /// consolidating or migrating to a different one. Like [`Blockchain::next_unused_index`] this
+    /// only reads the UTXOs synced to `database`.
+    fn script_type_distribution<D: Database>(
+        &self,
+        database: &D,
+    ) -> Result<HashMap<ScriptType, (usize, u64)>, Error> {
+        let mut distribution = HashMap::new();
+        for utxo in database.iter_utxos()? {
+            if utxo.is_spent {
+                continue;
+            }
+
+            let entry = distribution
+                .entry(ScriptType::from_script(&utxo.txout.script_pubkey))
+                .or_insert((0, 0));
+            entry.0 += 1;
+            entry.1 += utxo.txout.value;
+        }
+
+        Ok(distribution)
+    }
+
+    /// Return the unconfirmed transactions of the wallet that can be fee-bumped by spending one of
+    /// their outputs in a child transaction (CPFP), sorted by txid
+    ///
+    /// Every unspent output of the transaction owned by the wallet is listed, not only the change
+    /// ones. Like [`Blockchain::next_unused_index`] this only reads the UTXOs and transactions
+    /// synced to `database`.
+    fn find_cpfp_candidates<D: Database>(&self, database: &D) -> Result<Vec<CpfpCandidate>, Error> {
+        let mut candidates = BTreeMap::<Txid, Vec<OutPoint>>::new();
+        let mut confirmed = HashSet::new();
+        for utxo in database.iter_utxos()? {
+            let txid = utxo.outpoint.txid;
+            if utxo.is_spent || confirmed.contains(&txid) {
+                continue;
+            }
+
+            if !candidates.contains_key(&txid) {
+                match database.get_tx(&txid, false)? {
+                    Some(details) if details.confirmation_time.is_none() => {}
+                    _ => {
+                        confirmed.insert(txid);
+                        continue;
+                    }
+                }
+            }
+            candidates.entry(txid).or_default().push(utxo.outpoint);
+        }
+
+        Ok(candidates
+            .into_iter()
+            .map(|(txid, mut outpoints)| {
+                outpoints.sort();
+                CpfpCandidate { txid, outpoints }
+            })
+            .collect())
+    }
+}
+
+/// Type of a script pubkey, as returned by [`Blockchain::script_type_distribution`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScriptType {
+    /// Pay to public key hash
+    P2pkh,
+    /// Pay to script hash, including wrapped segwit
+    P2sh,
+    /// Pay to witness public key hash
+    P2wpkh,
+    /// Pay to witness script hash
+    P2wsh,
+    /// Pay to taproot
+    P2tr,
+    /// Any other script, like bare multisig or future witness versions
+    Other,
+}
+
+impl ScriptType {
+    /// Return the type of `script`
+    pub fn from_script(script: &Script) -> Self {
+        if script.is_p2pkh() {
+            ScriptType::P2pkh
+        } else if script.is_p2sh() {
+            ScriptType::P2sh
+        } else if script.is_v0_p2wpkh() {
+            ScriptType::P2wpkh
+        } else if script.is_v0_p2wsh() {
+            ScriptType::P2wsh
+        } else if script.is_v1_p2tr() {
+            ScriptType::P2tr
+        } else {
+            ScriptType::Other
+        }
+    }
+}
+
+/// Collect the script pubkeys that appear in the history synced to `database`
+fn used_script_pubkeys<D: Database>(database: &D) -> Result<HashSet<Script>, Error> {
+    let mut used = database
+        .iter_txs(true)?
+        .into_iter()
+        .filter_map(|details| details.transaction)
+        .flat_map(|tx| tx.output)
+        .map(|txout| txout.script_pubkey)
+        .collect::<HashSet<_>>();
+    used.extend(
+        database
+            .iter_utxos()?
+            .into_iter()
+            .map(|utxo| utxo.txout.script_pubkey),
+    );
+
+    Ok(used)
+}
+
+/// Block headers indexed by height, trusted to check the merkle proofs returned by a server
+///
+/// A server can't vouch for its own proofs: light clients should only put in here headers they
+/// have verified independently of it, for instance by checking the proof of work of a chain of
+/// headers received from multiple peers, or headers taken from their own node.
+pub type HeaderChain = BTreeMap<u32, BlockHeader>;
+
+/// Return the header of the block at `height` in the trusted `headers`
+///
+/// Fails with [`Error::MissingTrustedHeader`] if there's no such header, or no trusted headers
+/// at all.
+#[cfg(any(feature = "electrum", feature = "esplora"))]
+fn trusted_header(
+    headers: &Option<Arc<std::sync::RwLock<HeaderChain>>>,
+    height: u32,
+) -> Result<BlockHeader, Error> {
+    headers
+        .as_ref()
+        .and_then(|headers| headers.read().unwrap().get(&height).copied())
+        .ok_or(Error::MissingTrustedHeader(height))
+}
+
+/// Compute the merkle root committed to by the merkle branch of the transaction at position `pos`
+///
+/// Returns `None` if `pos` doesn't fit in a tree of the depth of the branch.
+#[cfg(any(feature = "electrum", feature = "esplora"))]
+fn merkle_root(
+    txid: &Txid,
+    branch: &[bitcoin::TxMerkleNode],
+    pos: usize,
+) -> Option<bitcoin::TxMerkleNode> {
+    use bitcoin::hashes::{sha256d, Hash, HashEngine};
+
+    if pos.checked_shr(branch.len() as u32).unwrap_or(0) != 0 {
+        return None;
+    }
+
+    let root = branch
+        .iter()
+        .enumerate()
+        .fold(txid.as_hash(), |node, (level, sibling)| {
+            let mut engine = sha256d::Hash::engine();
+            if (pos >> level) & 1 == 1 {
+                engine.input(&sibling[..]);
+                engine.input(&node[..]);
+            } else {
+                engine.input(&node[..]);
+                engine.input(&sibling[..]);
+            }
+            sha256d::Hash::from_engine(engine)
+        });
+
+    Some(bitcoin::TxMerkleNode::from_hash(root))
+}
+
+/// Size of the blinded payment code embedded in a BIP47 notification transaction
+#[cfg(any(feature = "electrum", feature = "esplora"))]
+const BIP47_PAYLOAD_LEN: usize = 80;
+
+/// Whether `tx` looks like a BIP47 notification transaction sent to `notification_script`
+///
+/// A notification transaction pays to the notification address of the recipient and carries the
+/// blinded payment code of the sender in an `OP_RETURN` output. Decoding the payload needs the
+/// private key of the notification address, so this only checks the shape of the transaction.
+#[cfg(any(feature = "electrum", feature = "esplora"))]
+fn is_notification_tx(tx: &Transaction, notification_script: &Script) -> bool {
+    use bitcoin::blockdata::script::Instruction;
+
+    let has_payload = |script: &Script| {
+        if !script.is_op_return() {
+            return false;
+        }
+        // skip the `OP_RETURN` itself
+        let mut instructions = script.instructions().skip(1);
+        match (instructions.next(), instructions.next()) {
+            (Some(Ok(Instruction::PushBytes(data))), None) => data.len() == BIP47_PAYLOAD_LEN,
+            _ => false,
+        }
+    };
+
+    tx.output
+        .iter()
+        .any(|txout| &txout.script_pubkey == notification_script)
+        && tx
+            .output
+            .iter()
+            .any(|txout| has_payload(&txout.script_pubkey))
+}
+
+/// Script pubkeys of the first `count` addresses of `descriptor`, or its single script pubkey if
+/// it doesn't have a wildcard
+///
+/// Fails if the keys of the descriptor aren't valid for `network`.
+#[cfg(any(feature = "electrum", feature = "esplora"))]
+fn descriptor_script_pubkeys(
+    descriptor: &crate::descriptor::ExtendedDescriptor,
+    network: bitcoin::Network,
+    count: usize,
+) -> Result<Vec<Script>, Error> {
+    use crate::descriptor::{into_wallet_descriptor_checked, AsDerived};
+    use miniscript::descriptor::DescriptorTrait;
+
+    let secp = bitcoin::secp256k1::Secp256k1::new();
+    let (descriptor, _) = into_wallet_descriptor_checked(descriptor.clone(), &secp, network)?;
+    if !descriptor.is_deriveable() {
+        return Ok(vec![descriptor.as_derived_fixed(&secp).script_pubkey()]);
+    }
+
+    Ok((0..count as u32)
+        .map(|index| descriptor.as_derived(index, &secp).script_pubkey())
+        .collect())
+}
+
+/// Split `txs` into consecutive groups of transactions that don't spend each other, which can be
+/// broadcast concurrently once the previous groups are broadcast
+#[cfg(any(feature = "electrum", feature = "esplora"))]
+fn independent_broadcast_groups(txs: &[Transaction]) -> Vec<&[Transaction]> {
+    let mut groups = vec![];
+    let mut start = 0;
+    let mut group_txids = HashSet::new();
+    for (i, tx) in txs.iter().enumerate() {
+        let spends_group = tx
+            .input
+            .iter()
+            .any(|txin| group_txids.contains(&txin.previous_output.txid));
+        if spends_group {
+            groups.push(&txs[start..i]);
+            start = i;
+            group_txids.clear();
+        }
+        group_txids.insert(tx.txid());
+    }
+    if start < txs.len() {
+        groups.push(&txs[start..]);
+    }
+
+    groups
+}
+
+/// Append the txids of the transactions of `group` that were broadcast to `broadcast`, given the
+/// `results` of their broadcast in the same order
+///
+/// Fails with [`Error::BatchBroadcast`] if any of them failed.
+#[cfg(any(feature = "electrum", feature = "esplora"))]
+fn check_group_broadcast(
+    group: &[Transaction],
+    results: Vec<Result<(), Error>>,
+    broadcast: &mut Vec<Txid>,
+) -> Result<(), Error> {
+    let mut failure = None;
+    for (tx, result) in group.iter().zip(results) {
+        match result {
+            Ok(()) => broadcast.push(tx.txid()),
+            Err(e) if failure.is_none() => failure = Some((tx.txid(), e)),
+            Err(_) => {}
+        }
+    }
+
+    match failure {
+        Some((failed, error)) => Err(Error::BatchBroadcast {
+            broadcast: std::mem::take(broadcast),
+            failed,
+            error: Box::new(error),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Convert the `(sat/vB, vsize)` pairs of a mempool fee histogram, sorted by decreasing fee rate
+#[cfg(any(feature = "electrum", feature = "esplora"))]
+fn into_fee_histogram(histogram: Vec<(f32, u64)>) -> Vec<(FeeRate, usize)> {
+    let mut histogram = histogram
+        .into_iter()
+        .filter(|(fee_rate, _)| fee_rate.is_finite())
+        .map(|(fee_rate, vsize)| (FeeRate::from_sat_per_vb(fee_rate), vsize as usize))
+        .collect::<Vec<_>>();
+    histogram.sort_by(|(a, _), (b, _)| b.partial_cmp(a).expect("finite fee rates"));
+    histogram
+}
+
+/// Unconfirmed wallet transaction that can be fee-bumped with a child spending its outputs, as
+/// returned by [`Blockchain::find_cpfp_candidates`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpfpCandidate {
+    /// Id of the unconfirmed transaction
+    pub txid: Txid,
+    /// Unspent outputs of the transaction owned by the wallet, that a child can spend
+    pub outpoints: Vec<OutPoint>,
+}
+
+/// Usage of the gap between the last used address and the stop gap, as returned by
+/// [`Blockchain::gap_usage`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GapUsage {
+    /// Highest derivation index with some history, `None` if no address was ever used
+    pub last_used_index: Option<u32>,
+    /// Highest derivation index handed out by the wallet, `None` if no address was handed out
+    pub last_revealed_index: Option<u32>,
+    /// Number of consecutive addresses handed out after the last used one
+    pub unused_run: u32,
+    /// Stop gap the run is compared to
+    pub stop_gap: usize,
+}
+
+impl GapUsage {
+    /// Number of addresses that can still be handed out without being used before reaching the
+    /// stop gap
+    pub fn remaining(&self) -> usize {
+        self.stop_gap.saturating_sub(self.unused_run as usize)
+    }
+
+    /// Whether the run of unused addresses reached the stop gap, in which case a sync from
+    /// scratch would not find funds sent to the following addresses
+    pub fn is_exhausted(&self) -> bool {
+        self.remaining() == 0
+    }
+}
+
+/// Mining statistics of the network, as returned by the `get_network_stats` method of the
+/// backends
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NetworkStats {
+    /// Height of the tip of the chain
+    pub height: u32,
+    /// Current difficulty, relative to the minimum difficulty of mainnet
+    pub difficulty: f64,
+    /// Estimated hashrate of the network (hashes per second)
+    pub hashrate: f64,
+}
+
+impl NetworkStats {
+    /// Derive the statistics from the `bits` field of the tip `header`
+    ///
+    /// The hashrate is estimated from the difficulty, assuming blocks are found every ten
+    /// minutes on average.
+    pub fn from_header(height: u32, header: &BlockHeader) -> Self {
+        let difficulty = difficulty_from_bits(header.bits);
+
+        NetworkStats {
+            height,
+            difficulty,
+            hashrate: difficulty * 2f64.powi(32) / 600.0,
+        }
+    }
+}
+
+/// Compute the difficulty corresponding to the compact target `bits`
+fn difficulty_from_bits(bits: u32) -> f64 {
+    // difficulty 1 corresponds to the compact target 0x1d00ffff
+    let exponent = (bits >> 24) as i32;
+    let mantissa = (bits & 0x007f_ffff) as f64;
+
+    0xffff as f64 / mantissa * 256f64.powi(0x1d - exponent)
 }
 
 /// Trait for getting the current height of the blockchain.
@@ -110,6 +882,78 @@ pub trait GetTx {
     fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, Error>;
 }
 
+#[maybe_async]
+/// Trait for getting a full block by hash
+///
+/// Backends implementing it report [`Capability::GetBlock`]. The Electrum protocol doesn't
+/// provide full blocks, so [`ElectrumBlockchain`](crate::blockchain::ElectrumBlockchain) doesn't
+/// implement it.
+pub trait GetBlock {
+    /// Fetch a block given its hash
+    ///
+    /// Returns `None` if the block is unknown to the backend.
+    fn get_block(&self, hash: &BlockHash) -> Result<Option<Block>, Error>;
+}
+
+#[maybe_async]
+/// Trait for getting the hash of a block of the best chain by height
+pub trait GetBlockHash {
+    /// Fetch the hash of the block at `height`
+    ///
+    /// Returns `None` if the best chain known to the backend is shorter than `height`.
+    fn get_block_hash(&self, height: u32) -> Result<Option<BlockHash>, Error>;
+
+    /// Fetch the header of the block at `height`
+    ///
+    /// Returns `None` if the best chain known to the backend is shorter than `height`.
+    fn get_block_header(&self, height: u32) -> Result<Option<BlockHeader>, Error>;
+
+    /// Return the network of the backend, as identified by the hash of its genesis block
+    ///
+    /// Returns `None` if the genesis block doesn't belong to a known network, see
+    /// [`network_from_genesis_hash`].
+    fn get_network(&self) -> Result<Option<bitcoin::Network>, Error> {
+        Ok(maybe_await!(self.get_block_hash(0))?
+            .as_ref()
+            .and_then(network_from_genesis_hash))
+    }
+
+    /// Build a block locator for the chain up to `tip_height`, to request headers from a peer
+    ///
+    /// The hashes start from the tip: the twelve most recent blocks are all included, then the
+    /// distance between two blocks doubles at every step down to the genesis block, which is
+    /// always the last one. This bounds the size of the locator to 43 hashes.
+    fn get_block_locator(&self, tip_height: u32) -> Result<Vec<BlockHash>, Error> {
+        let mut locator = Vec::new();
+        for height in block_locator_heights(tip_height) {
+            let hash = maybe_await!(self.get_block_hash(height))?
+                .ok_or_else(|| Error::Generic(format!("No block at height {}", height)))?;
+            locator.push(hash);
+        }
+
+        Ok(locator)
+    }
+}
+
+/// Heights of the blocks of a locator for the chain up to `tip_height`, following the spacing
+/// used by Bitcoin Core
+fn block_locator_heights(tip_height: u32) -> Vec<u32> {
+    let mut heights = Vec::new();
+    let mut height = tip_height;
+    let mut step = 1u32;
+    loop {
+        heights.push(height);
+        if height == 0 {
+            return heights;
+        }
+
+        height = height.saturating_sub(step);
+        if heights.len() > 10 {
+            step = step.saturating_mul(2);
+        }
+    }
+}
+
 /// Trait for blockchains that can sync by updating the database directly.
 #[maybe_async]
 pub trait WalletSync {
@@ -154,6 +998,123 @@ pub trait WalletSync {
     ) -> Result<(), Error> {
         maybe_await!(self.wallet_setup(database, progress_update))
     }
+
+    /// Like [`Self::wallet_setup`], but also return statistics about the work done during the
+    /// sync
+    ///
+    /// If not overridden, it defaults to calling [`Self::wallet_setup`] internally and only
+    /// reports the duration of the sync.
+    fn wallet_setup_with_stats<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<SyncStats, Error> {
+        let start = Instant::new();
+        maybe_await!(self.wallet_setup(database, progress_update))?;
+
+        Ok(SyncStats {
+            duration: start.elapsed(),
+            ..Default::default()
+        })
+    }
+
+    /// Like [`Self::wallet_sync`], but also return statistics about the work done during the
+    /// sync
+    ///
+    /// If not overridden, it defaults to calling [`Self::wallet_sync`] internally and only
+    /// reports the duration of the sync. Backends that implement [`Self::wallet_setup_with_stats`]
+    /// without distinguishing between setup and sync should forward this method to it.
+    fn wallet_sync_with_stats<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<SyncStats, Error> {
+        let start = Instant::new();
+        maybe_await!(self.wallet_sync(database, progress_update))?;
+
+        Ok(SyncStats {
+            duration: start.elapsed(),
+            ..Default::default()
+        })
+    }
+
+    /// Like [`Self::wallet_setup_with_stats`], but stop looking for the history of more script
+    /// pubkeys once `max_requests` requests were sent during the sync
+    ///
+    /// The transactions found up to that point are committed and the sync is reported by
+    /// [`SyncStats::request_budget_exhausted`]. If not overridden, the budget is ignored and it
+    /// defaults to calling [`Self::wallet_setup_with_stats`] internally.
+    fn wallet_setup_with_budget<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+        _max_requests: usize,
+    ) -> Result<SyncStats, Error> {
+        maybe_await!(self.wallet_setup_with_stats(database, progress_update))
+    }
+
+    /// Like [`Self::wallet_sync_with_stats`], with a budget of requests as in
+    /// [`Self::wallet_setup_with_budget`]
+    ///
+    /// If not overridden, the budget is ignored and it defaults to calling
+    /// [`Self::wallet_sync_with_stats`] internally.
+    fn wallet_sync_with_budget<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+        _max_requests: usize,
+    ) -> Result<SyncStats, Error> {
+        maybe_await!(self.wallet_sync_with_stats(database, progress_update))
+    }
+}
+
+/// Statistics about a sync, as returned by [`WalletSync::wallet_setup_with_stats`],
+/// [`WalletSync::wallet_sync_with_stats`] and [`Wallet::sync`](crate::wallet::Wallet::sync)
+///
+/// Useful to profile a sync or to compare different backends. Backends that don't track some of
+/// these figures leave them to zero.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncStats {
+    /// Number of script pubkeys whose history was requested
+    pub scripts_scanned: usize,
+    /// Number of transactions downloaded, including the ones spent by the wallet transactions
+    pub txs_fetched: usize,
+    /// Number of block headers requested to check the confirmation of the transactions
+    pub headers_fetched: usize,
+    /// Number of bytes received from the server, if the backend can measure it
+    pub bytes_transferred: Option<u64>,
+    /// Total duration of the sync
+    pub duration: Duration,
+    /// Whether the sync stopped early because it reached the maximum number of requests it was
+    /// allowed to make, leaving some script pubkeys unchecked
+    pub request_budget_exhausted: bool,
+    /// Number of script pubkeys whose history couldn't be fetched, because of an error skipped
+    /// with [`SyncErrorPolicy::SkipErrored`] or because the request budget was exhausted
+    ///
+    /// The sync is incomplete if this is not zero: some transactions of the wallet may be
+    /// missing from the database, and none was deleted.
+    pub skipped_scripts: usize,
+    /// Transactions that were confirmed in a block reorged out of the best chain since the
+    /// previous sync
+    ///
+    /// They are marked as unconfirmed before the script pubkeys are scanned, so their entries in
+    /// the database reflect their new confirmation time, if any, once the sync completes.
+    pub reorged_txids: Vec<Txid>,
+}
+
+/// Script pubkeys that a sync would query, as returned by the `plan_sync` method of the Electrum
+/// and Esplora backends
+///
+/// Useful to debug stop gap and derivation issues without making any request to the server.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncPlan {
+    /// Script pubkeys in the order they would be requested, with their keychain and derivation
+    /// index
+    pub scripts: Vec<(KeychainKind, u32, Script)>,
+    /// Stop gap of the external keychain
+    pub stop_gap: usize,
+    /// Stop gap of the internal keychain
+    pub internal_stop_gap: usize,
 }
 
 /// Trait for [`Blockchain`] types that can be created given a configuration
@@ -251,7 +1212,7 @@ pub trait BlockchainFactory {
         wallet: &Wallet<D>,
         override_skip_blocks: Option<u32>,
         sync_options: crate::wallet::SyncOptions,
-    ) -> Result<(), Error> {
+    ) -> Result<SyncStats, Error> {
         let blockchain = self.build_for_wallet(wallet, override_skip_blocks)?;
         wallet.sync(&blockchain, sync_options)
     }
@@ -268,14 +1229,86 @@ impl<T: StatelessBlockchain> BlockchainFactory for Arc<T> {
 /// Data sent with a progress update over a [`channel`]
 pub type ProgressData = (f32, Option<String>);
 
-/// Trait for types that can receive and process progress updates during [`WalletSync::wallet_sync`] and
-/// [`WalletSync::wallet_setup`]
-pub trait Progress: Send + 'static + core::fmt::Debug {
+/// Structured progress of the scan of a wallet's script pubkeys during a sync
+///
+/// The total number of script pubkeys to scan isn't known until the stop gap is reached on every
+/// keychain, so [`SyncProgress::scripts_total_estimate`] is a lower bound that may grow during
+/// the sync as active script pubkeys are found. It never decreases and is never lower than
+/// [`SyncProgress::scripts_scanned`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncProgress {
+    /// Number of script pubkeys whose history was fetched so far
+    pub scripts_scanned: usize,
+    /// Estimate of the number of script pubkeys to scan during the whole sync
+    pub scripts_total_estimate: usize,
+    /// Keychain of the script pubkeys being scanned
+    pub current_keychain: KeychainKind,
+    /// Time elapsed since the beginning of the sync
+    pub elapsed: Duration,
+}
+
+impl SyncProgress {
+    /// Percentage of the script pubkeys scanned, in the range 0.0 - 100.0
+    pub fn percentage(&self) -> f32 {
+        if self.scripts_total_estimate == 0 {
+            return 100.0;
+        }
+
+        (self.scripts_scanned as f32 * 100.0 / self.scripts_total_estimate as f32).min(100.0)
+    }
+
+    /// Estimate of the time left to scan the remaining script pubkeys, assuming they take as long
+    /// as the ones already scanned
+    ///
+    /// Returns `None` before the first script pubkey is scanned.
+    pub fn eta(&self) -> Option<Duration> {
+        if self.scripts_scanned == 0 {
+            return None;
+        }
+
+        let remaining = self
+            .scripts_total_estimate
+            .saturating_sub(self.scripts_scanned);
+        Some(
+            self.elapsed
+                .mul_f64(remaining as f64 / self.scripts_scanned as f64),
+        )
+    }
+}
+
+/// Trait for types that can receive and process progress updates during [`WalletSync::wallet_sync`] and
+/// [`WalletSync::wallet_setup`]
+pub trait Progress: Send + 'static + core::fmt::Debug {
     /// Send a new progress update
     ///
     /// The `progress` value should be in the range 0.0 - 100.0, and the `message` value is an
     /// optional text message that can be displayed to the user.
     fn update(&self, progress: f32, message: Option<String>) -> Result<(), Error>;
+
+    /// Send a structured update on the scan of the wallet's script pubkeys
+    ///
+    /// The Electrum and Esplora backends call this after each chunk of requests. If not
+    /// overridden, it calls [`Progress::update`] with [`SyncProgress::percentage`] and a short
+    /// description of the progress.
+    fn update_sync(&self, progress: &SyncProgress) -> Result<(), Error> {
+        self.update(
+            progress.percentage(),
+            Some(format!(
+                "Scanned {} of ~{} script pubkeys",
+                progress.scripts_scanned, progress.scripts_total_estimate
+            )),
+        )
+    }
+
+    /// Whether the sync should be interrupted
+    ///
+    /// Backends check this between each chunk of requests they send, and fail with
+    /// [`Error::SyncAborted`] without committing anything to the database if it returns `true`.
+    /// It defaults to `false`, wrap a [`Progress`] in a [`CancellableProgress`] to be able to
+    /// cancel the sync with a [`CancelToken`].
+    fn is_cancelled(&self) -> bool {
+        false
+    }
 }
 
 /// Shortcut to create a [`channel`] (pair of [`Sender`] and [`Receiver`]) that can transport [`ProgressData`]
@@ -294,6 +1327,20 @@ impl Progress for Sender<ProgressData> {
     }
 }
 
+impl<P: Progress + ?Sized> Progress for Box<P> {
+    fn update(&self, progress: f32, message: Option<String>) -> Result<(), Error> {
+        (**self).update(progress, message)
+    }
+
+    fn update_sync(&self, progress: &SyncProgress) -> Result<(), Error> {
+        (**self).update_sync(progress)
+    }
+
+    fn is_cancelled(&self) -> bool {
+        (**self).is_cancelled()
+    }
+}
+
 /// Type that implements [`Progress`] and drops every update received
 #[derive(Clone, Copy, Default, Debug)]
 pub struct NoopProgress;
@@ -307,6 +1354,10 @@ impl Progress for NoopProgress {
     fn update(&self, _progress: f32, _message: Option<String>) -> Result<(), Error> {
         Ok(())
     }
+
+    fn update_sync(&self, _progress: &SyncProgress) -> Result<(), Error> {
+        Ok(())
+    }
 }
 
 /// Type that implements [`Progress`] and logs at level `INFO` every update received
@@ -328,6 +1379,179 @@ impl Progress for LogProgress {
 
         Ok(())
     }
+
+    fn update_sync(&self, progress: &SyncProgress) -> Result<(), Error> {
+        log::info!(
+            "Sync {:.3}%: scanned {} of ~{} script pubkeys, {:?} keychain, ETA {}",
+            progress.percentage(),
+            progress.scripts_scanned,
+            progress.scripts_total_estimate,
+            progress.current_keychain,
+            progress
+                .eta()
+                .map_or_else(|| "unknown".into(), |eta| format!("{}s", eta.as_secs()))
+        );
+
+        Ok(())
+    }
+}
+
+/// Type that implements [`Progress`] and forwards at most one update per `interval` to the
+/// wrapped [`Progress`]
+///
+/// Updates received too early are coalesced: only the latest one is kept and forwarded with the
+/// next update received once `interval` has elapsed. Updates reporting completion (`100.0`) are
+/// always forwarded immediately, and the last coalesced update is forwarded when the throttle is
+/// dropped, so that the wrapped [`Progress`] always sees the final state of the sync.
+///
+/// ## Example
+///
+/// ```
+/// # use std::time::Duration;
+/// # use bdk::blockchain::*;
+/// // log at most one update per second
+/// let progress = ThrottledProgress::new(log_progress(), Duration::from_secs(1));
+/// ```
+pub struct ThrottledProgress<P: Progress> {
+    inner: P,
+    interval: Duration,
+    state: Mutex<ThrottleState>,
+}
+
+struct ThrottleState {
+    last_forwarded: Option<Instant>,
+    pending: Option<ProgressData>,
+}
+
+impl<P: Progress> ThrottledProgress<P> {
+    /// Wrap `inner`, forwarding at most one update per `interval`
+    pub fn new(inner: P, interval: Duration) -> Self {
+        ThrottledProgress {
+            inner,
+            interval,
+            state: Mutex::new(ThrottleState {
+                last_forwarded: None,
+                pending: None,
+            }),
+        }
+    }
+
+    /// Forward the latest coalesced update, if any, regardless of the interval
+    pub fn flush(&self) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        match state.pending.take() {
+            Some((progress, message)) => {
+                state.last_forwarded = Some(Instant::new());
+                self.inner.update(progress, message)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl<P: Progress> Progress for ThrottledProgress<P> {
+    fn update(&self, progress: f32, message: Option<String>) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        let elapsed = state
+            .last_forwarded
+            .as_ref()
+            .map_or(true, |last| last.elapsed() >= self.interval);
+
+        if elapsed || progress >= 100.0 {
+            state.pending = None;
+            state.last_forwarded = Some(Instant::new());
+            self.inner.update(progress, message)
+        } else {
+            state.pending = Some((progress, message));
+            Ok(())
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.inner.is_cancelled()
+    }
+}
+
+impl<P: Progress> Drop for ThrottledProgress<P> {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            log::warn!("Failed to forward the last progress update: {:?}", e);
+        }
+    }
+}
+
+impl<P: Progress> fmt::Debug for ThrottledProgress<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ThrottledProgress")
+            .field("inner", &self.inner)
+            .field("interval", &self.interval)
+            .finish()
+    }
+}
+
+/// Handle used to interrupt a running sync
+///
+/// Clones of a token share the same state, so a clone can be kept to cancel the sync from
+/// another thread while the original is passed to [`CancellableProgress`] or
+/// [`SyncOptions::cancel`](crate::wallet::SyncOptions::cancel).
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Create a new token, not cancelled
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request the interruption of the syncs using this token
+    pub fn cancel(&self) {
+        self.0.store(true, AtomicOrdering::SeqCst);
+    }
+
+    /// Whether [`CancelToken::cancel`] has been called
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(AtomicOrdering::SeqCst)
+    }
+}
+
+/// Type that implements [`Progress`], forwarding every update to the wrapped [`Progress`] and
+/// reporting the sync as cancelled once its [`CancelToken`] is
+///
+/// ## Example
+///
+/// ```
+/// # use bdk::blockchain::*;
+/// let token = CancelToken::new();
+/// let progress = CancellableProgress::new(log_progress(), token.clone());
+/// // from another thread, interrupt the sync
+/// token.cancel();
+/// assert!(progress.is_cancelled());
+/// ```
+#[derive(Debug)]
+pub struct CancellableProgress<P: Progress> {
+    inner: P,
+    token: CancelToken,
+}
+
+impl<P: Progress> CancellableProgress<P> {
+    /// Wrap `inner`, cancelling the sync once `token` is cancelled
+    pub fn new(inner: P, token: CancelToken) -> Self {
+        CancellableProgress { inner, token }
+    }
+}
+
+impl<P: Progress> Progress for CancellableProgress<P> {
+    fn update(&self, progress: f32, message: Option<String>) -> Result<(), Error> {
+        self.inner.update(progress, message)
+    }
+
+    fn update_sync(&self, progress: &SyncProgress) -> Result<(), Error> {
+        self.inner.update_sync(progress)
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled() || self.inner.is_cancelled()
+    }
 }
 
 #[maybe_async]
@@ -343,6 +1567,14 @@ impl<T: Blockchain> Blockchain for Arc<T> {
     fn estimate_fee(&self, target: usize) -> Result<FeeRate, Error> {
         maybe_await!(self.deref().estimate_fee(target))
     }
+
+    fn broadcast_batch(&self, txs: &[Transaction]) -> Result<Vec<Txid>, Error> {
+        maybe_await!(self.deref().broadcast_batch(txs))
+    }
+
+    fn get_tx_confirmations(&self, txid: &Txid) -> Result<Option<u32>, Error> {
+        maybe_await!(self.deref().get_tx_confirmations(txid))
+    }
 }
 
 #[maybe_async]
@@ -359,6 +1591,17 @@ impl<T: GetHeight> GetHeight for Arc<T> {
     }
 }
 
+#[maybe_async]
+impl<T: GetBlockHash> GetBlockHash for Arc<T> {
+    fn get_block_hash(&self, height: u32) -> Result<Option<BlockHash>, Error> {
+        maybe_await!(self.deref().get_block_hash(height))
+    }
+
+    fn get_block_header(&self, height: u32) -> Result<Option<BlockHeader>, Error> {
+        maybe_await!(self.deref().get_block_header(height))
+    }
+}
+
 #[maybe_async]
 impl<T: WalletSync> WalletSync for Arc<T> {
     fn wallet_setup<D: BatchDatabase>(
@@ -376,4 +1619,923 @@ impl<T: WalletSync> WalletSync for Arc<T> {
     ) -> Result<(), Error> {
         maybe_await!(self.deref().wallet_sync(database, progress_update))
     }
+
+    fn wallet_setup_with_stats<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<SyncStats, Error> {
+        maybe_await!(self
+            .deref()
+            .wallet_setup_with_stats(database, progress_update))
+    }
+
+    fn wallet_sync_with_stats<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<SyncStats, Error> {
+        maybe_await!(self
+            .deref()
+            .wallet_sync_with_stats(database, progress_update))
+    }
+
+    fn wallet_setup_with_budget<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+        max_requests: usize,
+    ) -> Result<SyncStats, Error> {
+        maybe_await!(self
+            .deref()
+            .wallet_setup_with_budget(database, progress_update, max_requests))
+    }
+
+    fn wallet_sync_with_budget<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+        max_requests: usize,
+    ) -> Result<SyncStats, Error> {
+        maybe_await!(self
+            .deref()
+            .wallet_sync_with_budget(database, progress_update, max_requests))
+    }
+}
+
+#[cfg(test)]
+#[cfg(all(feature = "key-value-db", not(feature = "async-interface")))]
+mod test {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use bitcoin::consensus::deserialize;
+    use bitcoin::hashes::hex::FromHex;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{TxIn, TxOut};
+
+    use super::*;
+    use crate::database::{BatchOperations, MemoryDatabase, SyncTime};
+    use crate::{BlockTime, LocalUtxo, TransactionDetails};
+
+    #[derive(Default)]
+    struct MockBlockchain {
+        broadcast: Mutex<Vec<Transaction>>,
+        fail_broadcast: bool,
+    }
+
+    impl Blockchain for MockBlockchain {
+        fn get_capabilities(&self) -> HashSet<Capability> {
+            HashSet::new()
+        }
+
+        fn broadcast(&self, tx: &Transaction) -> Result<(), Error> {
+            if self.fail_broadcast {
+                return Err(Error::Generic("broadcast failed".into()));
+            }
+            self.broadcast.lock().unwrap().push(tx.clone());
+            Ok(())
+        }
+
+        fn estimate_fee(&self, _target: usize) -> Result<FeeRate, Error> {
+            Ok(FeeRate::default_min_relay_fee())
+        }
+    }
+
+    impl GetHeight for MockBlockchain {
+        fn get_height(&self) -> Result<u32, Error> {
+            Ok(0)
+        }
+    }
+
+    impl GetTx for MockBlockchain {
+        fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, Error> {
+            Ok(self
+                .broadcast
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|tx| tx.txid() == *txid)
+                .cloned())
+        }
+    }
+
+    impl WalletSync for MockBlockchain {
+        fn wallet_setup<D: BatchDatabase>(
+            &self,
+            _database: &mut D,
+            _progress_update: Box<dyn Progress>,
+        ) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_flush_pending_broadcasts_after_restart() {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("bdk_pending_{}", time.as_nanos()));
+
+        let tx: Transaction = deserialize(&Vec::<u8>::from_hex("0100000001a15d57094aa7a21a28cb20b59aab8fc7d1149a3bdbcddba9c622e4f5f6a99ece010000006c493046022100f93bb0e7d8db7bd46e40132d1f8242026e045f03a0efe71bbb8e3f475e970d790221009337cd7f1f929f00cc6ff01f03729b069a7c21b59b1736ddfee5db5946c5da8c0121033b9b137ee87d5a812d6f506efdd37f0affa7ffc310711c06c7f3e097c9447c52ffffffff0100e1f505000000001976a9140389035a9225b3839e2bbf32d826a1e222031fd888ac00000000").unwrap()).unwrap();
+
+        let blockchain = MockBlockchain {
+            fail_broadcast: true,
+            ..Default::default()
+        };
+        let db = sled::open(&dir).unwrap();
+        {
+            let mut database = db.open_tree("wallet").unwrap();
+            blockchain
+                .persist_pending_broadcast(&mut database, &tx)
+                .unwrap();
+            // the backend is unreachable, the tx is kept for later
+            assert!(blockchain.flush_pending_broadcasts(&mut database).is_err());
+            database.flush().unwrap();
+        }
+
+        // simulate a restart by reopening the tree: reopening the whole database right after
+        // dropping it can fail while sled's flusher thread still holds its lock
+        let blockchain = MockBlockchain::default();
+        let mut database = db.open_tree("wallet").unwrap();
+        assert_eq!(database.iter_pending_txs().unwrap(), vec![tx.clone()]);
+        assert_eq!(
+            blockchain.flush_pending_broadcasts(&mut database).unwrap(),
+            vec![tx.txid()]
+        );
+        assert_eq!(*blockchain.broadcast.lock().unwrap(), vec![tx.clone()]);
+        assert!(database.iter_pending_txs().unwrap().is_empty());
+        assert!(blockchain
+            .flush_pending_broadcasts(&mut database)
+            .unwrap()
+            .is_empty());
+
+        // a tx already known to the backend is considered broadcast
+        let blockchain = MockBlockchain {
+            fail_broadcast: true,
+            ..blockchain
+        };
+        blockchain
+            .persist_pending_broadcast(&mut database, &tx)
+            .unwrap();
+        assert_eq!(
+            blockchain.flush_pending_broadcasts(&mut database).unwrap(),
+            vec![tx.txid()]
+        );
+    }
+
+    #[test]
+    fn test_confirmation_updates() {
+        let blockchain = MockBlockchain::default();
+        let mut database = MemoryDatabase::new();
+        let new_tx = |lock_time, height: Option<u32>| {
+            let transaction = Transaction {
+                version: 1,
+                lock_time,
+                input: vec![],
+                output: vec![],
+            };
+            TransactionDetails {
+                txid: transaction.txid(),
+                transaction: Some(transaction),
+                confirmation_time: height.map(|height| BlockTime {
+                    height,
+                    timestamp: 0,
+                }),
+                ..Default::default()
+            }
+        };
+        let old = new_tx(0, Some(90));
+        let recent = new_tx(1, Some(100));
+        let unconfirmed = new_tx(2, None);
+        for details in &[&old, &recent, &unconfirmed] {
+            database.set_tx(details).unwrap();
+        }
+
+        // never synced, every confirmed tx is new
+        let mut updates = blockchain.confirmation_updates(&database, 100).unwrap();
+        updates.sort();
+        let mut expected = vec![(old.txid, 11), (recent.txid, 1)];
+        expected.sort();
+        assert_eq!(updates, expected);
+
+        database
+            .set_sync_time(SyncTime {
+                block_time: BlockTime {
+                    height: 100,
+                    timestamp: 0,
+                },
+            })
+            .unwrap();
+        assert!(blockchain
+            .confirmation_updates(&database, 100)
+            .unwrap()
+            .is_empty());
+
+        // the tip advanced, a tx confirmed in the new block shows up too
+        let new = new_tx(3, Some(102));
+        database.set_tx(&new).unwrap();
+        let mut updates = blockchain.confirmation_updates(&database, 102).unwrap();
+        updates.sort();
+        let mut expected = vec![(old.txid, 13), (recent.txid, 3), (new.txid, 1)];
+        expected.sort();
+        assert_eq!(updates, expected);
+
+        assert_eq!(
+            blockchain
+                .confirmation_updates(&database, u32::MAX)
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_throttled_progress() {
+        let interval = Duration::from_millis(20);
+        let (sender, receiver) = progress();
+        let throttled = ThrottledProgress::new(sender, interval);
+
+        let start = std::time::Instant::now();
+        for i in 0..50 {
+            throttled.update(i as f32, None).unwrap();
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        let elapsed = start.elapsed();
+        drop(throttled);
+
+        let received = receiver.try_iter().map(|(p, _)| p).collect::<Vec<_>>();
+        // one update per started interval, plus the last coalesced one flushed on drop
+        let max_expected = (elapsed.as_millis() / interval.as_millis()) as usize + 2;
+        assert!(
+            received.len() > 1 && received.len() <= max_expected,
+            "received {} updates in {:?}",
+            received.len(),
+            elapsed
+        );
+        assert!(received.len() < 50);
+        assert_eq!(received[0], 0.0);
+        assert_eq!(received.last(), Some(&49.0));
+        assert!(received.windows(2).all(|w| w[0] < w[1]));
+
+        // completion is never delayed
+        let (sender, receiver) = progress();
+        let throttled = ThrottledProgress::new(sender, Duration::from_secs(60));
+        throttled.update(10.0, None).unwrap();
+        throttled.update(50.0, None).unwrap();
+        throttled.update(100.0, None).unwrap();
+        assert_eq!(
+            receiver.try_iter().map(|(p, _)| p).collect::<Vec<_>>(),
+            vec![10.0, 100.0]
+        );
+    }
+
+    #[test]
+    fn test_cancellable_progress() {
+        let token = CancelToken::new();
+        let (sender, receiver) = progress();
+        let progress: Box<dyn Progress> = Box::new(ThrottledProgress::new(
+            CancellableProgress::new(sender, token.clone()),
+            Duration::from_secs(0),
+        ));
+
+        progress.update(10.0, None).unwrap();
+        assert!(!progress.is_cancelled());
+        token.clone().cancel();
+        assert!(progress.is_cancelled());
+        assert!(token.is_cancelled());
+        assert_eq!(receiver.try_iter().count(), 1);
+        assert!(!noop_progress().is_cancelled());
+    }
+
+    #[test]
+    fn test_next_unused_index() {
+        let blockchain = MockBlockchain::default();
+        let mut database = MemoryDatabase::new();
+        let scripts = (0..5u8)
+            .map(|i| Script::from(vec![i; 22]))
+            .collect::<Vec<_>>();
+        for (i, script) in scripts.iter().enumerate() {
+            database
+                .set_script_pubkey(script, KeychainKind::External, i as u32)
+                .unwrap();
+        }
+        assert_eq!(
+            blockchain
+                .next_unused_index(&database, KeychainKind::External)
+                .unwrap(),
+            0
+        );
+
+        // the history synced uses the addresses 0, 1 and 3
+        for i in &[0, 1, 3] {
+            let transaction = Transaction {
+                version: 1,
+                lock_time: *i,
+                input: vec![],
+                output: vec![bitcoin::TxOut {
+                    value: 10_000,
+                    script_pubkey: scripts[*i as usize].clone(),
+                }],
+            };
+            database
+                .set_tx(&TransactionDetails {
+                    txid: transaction.txid(),
+                    transaction: Some(transaction),
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+
+        assert_eq!(
+            blockchain
+                .next_unused_index(&database, KeychainKind::External)
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            blockchain
+                .next_unused_index(&database, KeychainKind::Internal)
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_gap_usage() {
+        let blockchain = MockBlockchain::default();
+        let mut database = MemoryDatabase::new();
+        let scripts = (0..20u8)
+            .map(|i| Script::from(vec![i; 22]))
+            .collect::<Vec<_>>();
+        for (i, script) in scripts.iter().enumerate() {
+            database
+                .set_script_pubkey(script, KeychainKind::External, i as u32)
+                .unwrap();
+        }
+
+        let usage = blockchain
+            .gap_usage(&database, KeychainKind::External, 5)
+            .unwrap();
+        assert_eq!(usage.last_revealed_index, None);
+        assert_eq!(usage.unused_run, 0);
+        assert_eq!(usage.remaining(), 5);
+
+        // the history synced uses the addresses 0 and 3
+        for i in &[0, 3] {
+            let transaction = Transaction {
+                version: 1,
+                lock_time: *i,
+                input: vec![],
+                output: vec![bitcoin::TxOut {
+                    value: 10_000,
+                    script_pubkey: scripts[*i as usize].clone(),
+                }],
+            };
+            database
+                .set_tx(&TransactionDetails {
+                    txid: transaction.txid(),
+                    transaction: Some(transaction),
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+
+        // one address left before the gap is exhausted
+        database.set_last_index(KeychainKind::External, 7).unwrap();
+        let usage = blockchain
+            .gap_usage(&database, KeychainKind::External, 5)
+            .unwrap();
+        assert_eq!(usage.last_used_index, Some(3));
+        assert_eq!(usage.unused_run, 4);
+        assert_eq!(usage.remaining(), 1);
+        assert!(!usage.is_exhausted());
+
+        // right at the gap
+        database.set_last_index(KeychainKind::External, 8).unwrap();
+        let usage = blockchain
+            .gap_usage(&database, KeychainKind::External, 5)
+            .unwrap();
+        assert_eq!(usage.unused_run, 5);
+        assert_eq!(usage.remaining(), 0);
+        assert!(usage.is_exhausted());
+
+        // the other keychain has no history at all
+        database.set_last_index(KeychainKind::Internal, 5).unwrap();
+        let usage = blockchain
+            .gap_usage(&database, KeychainKind::Internal, 5)
+            .unwrap();
+        assert_eq!(usage.last_used_index, None);
+        assert_eq!(usage.unused_run, 6);
+        assert!(usage.is_exhausted());
+    }
+
+    #[test]
+    fn test_broadcast_with_policy() {
+        let blockchain = MockBlockchain::default();
+        let tx = |script_len: usize| Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![TxOut {
+                value: 10_000,
+                script_pubkey: Script::from(vec![0x6a; script_len]),
+            }],
+        };
+        let small_tx = tx(22);
+        let large_tx = tx(MAX_STANDARD_TX_WEIGHT / 4);
+        assert!(large_tx.weight() > MAX_STANDARD_TX_WEIGHT);
+
+        let policy = BroadcastPolicy::default();
+        blockchain
+            .broadcast_with_policy(&small_tx, &policy)
+            .unwrap();
+        assert!(matches!(
+            blockchain.broadcast_with_policy(&large_tx, &policy),
+            Err(Error::TxTooLarge { weight }) if weight == large_tx.weight()
+        ));
+        assert_eq!(
+            *blockchain.broadcast.lock().unwrap(),
+            vec![small_tx.clone()]
+        );
+
+        let policy = BroadcastPolicy {
+            force: true,
+            ..Default::default()
+        };
+        blockchain
+            .broadcast_with_policy(&large_tx, &policy)
+            .unwrap();
+        assert_eq!(
+            *blockchain.broadcast.lock().unwrap(),
+            vec![small_tx, large_tx]
+        );
+    }
+
+    #[test]
+    fn test_get_tx_confirmations_default() {
+        let blockchain = MockBlockchain::default();
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![TxOut {
+                value: 10_000,
+                script_pubkey: Script::from(vec![0x51]),
+            }],
+        };
+        assert_eq!(blockchain.get_tx_confirmations(&tx.txid()).unwrap(), None);
+
+        // the mock can't tell where the transaction was confirmed
+        blockchain.broadcast(&tx).unwrap();
+        assert!(matches!(
+            blockchain.get_tx_confirmations(&tx.txid()),
+            Err(Error::Generic(_))
+        ));
+    }
+
+    /// A transaction spending the first output of each of `parents`
+    fn spending_tx(parents: &[&Transaction], value: u64) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: parents
+                .iter()
+                .map(|parent| TxIn {
+                    previous_output: OutPoint::new(parent.txid(), 0),
+                    ..Default::default()
+                })
+                .collect(),
+            output: vec![TxOut {
+                value,
+                script_pubkey: Script::from(vec![0x51]),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_broadcast_batch_default() {
+        let parent = spending_tx(&[], 20_000);
+        let child = spending_tx(&[&parent], 10_000);
+
+        let blockchain = MockBlockchain::default();
+        assert_eq!(
+            blockchain
+                .broadcast_batch(&[parent.clone(), child.clone()])
+                .unwrap(),
+            vec![parent.txid(), child.txid()]
+        );
+        assert_eq!(
+            *blockchain.broadcast.lock().unwrap(),
+            vec![parent.clone(), child.clone()]
+        );
+
+        let blockchain = MockBlockchain {
+            fail_broadcast: true,
+            ..Default::default()
+        };
+        match blockchain.broadcast_batch(&[parent.clone(), child]) {
+            Err(Error::BatchBroadcast {
+                broadcast, failed, ..
+            }) => {
+                assert!(broadcast.is_empty());
+                assert_eq!(failed, parent.txid());
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    #[cfg(any(feature = "electrum", feature = "esplora"))]
+    fn test_independent_broadcast_groups() {
+        let parent = spending_tx(&[], 20_000);
+        let other = spending_tx(&[], 30_000);
+        let child = spending_tx(&[&parent], 10_000);
+        let grandchild = spending_tx(&[&child, &other], 5_000);
+
+        let txs = vec![parent, other, child, grandchild];
+        let groups = independent_broadcast_groups(&txs);
+        assert_eq!(groups, vec![&txs[..2], &txs[2..3], &txs[3..]]);
+        assert!(independent_broadcast_groups(&[]).is_empty());
+
+        // the transactions broadcast are reported even if another one of the group failed
+        let mut broadcast = vec![txs[0].txid()];
+        let results = vec![Err(Error::Generic("rejected".into())), Ok(())];
+        match check_group_broadcast(&txs[2..], results, &mut broadcast) {
+            Err(Error::BatchBroadcast {
+                broadcast, failed, ..
+            }) => {
+                assert_eq!(broadcast, vec![txs[0].txid(), txs[3].txid()]);
+                assert_eq!(failed, txs[2].txid());
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_network_stats_from_header() {
+        let header = |bits| BlockHeader {
+            version: 1,
+            prev_blockhash: Default::default(),
+            merkle_root: Default::default(),
+            time: 0,
+            bits,
+            nonce: 0,
+        };
+
+        // genesis block
+        let stats = NetworkStats::from_header(0, &header(0x1d00ffff));
+        assert_eq!(stats.difficulty, 1.0);
+        assert!((stats.hashrate - 7_158_278.826_666_667).abs() < 1e-6);
+
+        // block 100000
+        let stats = NetworkStats::from_header(100_000, &header(0x1b04864c));
+        assert_eq!(stats.height, 100_000);
+        assert!((stats.difficulty - 14_484.162_361_225_399).abs() < 1e-9);
+
+        // regtest
+        let stats = NetworkStats::from_header(1, &header(0x207fffff));
+        assert!((stats.difficulty - 4.656_542_373_906_925e-10).abs() < 1e-20);
+    }
+
+    #[test]
+    fn test_detect_address_reuse() {
+        let blockchain = MockBlockchain::default();
+        let mut database = MemoryDatabase::new();
+        let used = Script::from(vec![0xaa; 22]);
+        let fresh = Script::from(vec![0xbb; 22]);
+        let tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output: vec![
+                TxOut {
+                    value: 10_000,
+                    script_pubkey: fresh.clone(),
+                },
+                TxOut {
+                    value: 20_000,
+                    script_pubkey: used.clone(),
+                },
+            ],
+        };
+        assert!(blockchain
+            .detect_address_reuse(&tx, &database)
+            .unwrap()
+            .is_empty());
+
+        let previous_tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output: vec![TxOut {
+                value: 50_000,
+                script_pubkey: used,
+            }],
+        };
+        database
+            .set_tx(&TransactionDetails {
+                txid: previous_tx.txid(),
+                transaction: Some(previous_tx),
+                ..Default::default()
+            })
+            .unwrap();
+
+        assert_eq!(
+            blockchain.detect_address_reuse(&tx, &database).unwrap(),
+            vec![1]
+        );
+    }
+
+    #[test]
+    fn test_script_type_distribution() {
+        let blockchain = MockBlockchain::default();
+        let mut database = MemoryDatabase::new();
+        assert!(blockchain
+            .script_type_distribution(&database)
+            .unwrap()
+            .is_empty());
+
+        let scripts = vec![
+            (
+                [&[0x76, 0xa9, 0x14][..], &[0xaa; 20], &[0x88, 0xac]].concat(),
+                10_000,
+            ),
+            ([&[0xa9, 0x14][..], &[0xaa; 20], &[0x87]].concat(), 20_000),
+            ([&[0x00, 0x14][..], &[0xaa; 20]].concat(), 30_000),
+            ([&[0x00, 0x14][..], &[0xbb; 20]].concat(), 40_000),
+            ([&[0x00, 0x20][..], &[0xaa; 32]].concat(), 50_000),
+            ([&[0x51, 0x20][..], &[0xaa; 32]].concat(), 60_000),
+            (vec![0x6a], 70_000),
+        ];
+        for (i, (script, value)) in scripts.into_iter().enumerate() {
+            database
+                .set_utxo(&LocalUtxo {
+                    outpoint: OutPoint::new(Txid::default(), i as u32),
+                    txout: TxOut {
+                        value,
+                        script_pubkey: Script::from(script),
+                    },
+                    keychain: KeychainKind::External,
+                    is_spent: false,
+                    derivation_index: Some(i as u32),
+                    suspected_dust: false,
+                })
+                .unwrap();
+        }
+        // spent utxos are ignored
+        database
+            .set_utxo(&LocalUtxo {
+                outpoint: OutPoint::new(Txid::default(), 100),
+                txout: TxOut {
+                    value: 1_000_000,
+                    script_pubkey: Script::from([&[0x00, 0x14][..], &[0xcc; 20]].concat()),
+                },
+                keychain: KeychainKind::External,
+                is_spent: true,
+                derivation_index: Some(100),
+                suspected_dust: false,
+            })
+            .unwrap();
+
+        let distribution = blockchain.script_type_distribution(&database).unwrap();
+        assert_eq!(distribution.len(), 6);
+        assert_eq!(distribution[&ScriptType::P2pkh], (1, 10_000));
+        assert_eq!(distribution[&ScriptType::P2sh], (1, 20_000));
+        assert_eq!(distribution[&ScriptType::P2wpkh], (2, 70_000));
+        assert_eq!(distribution[&ScriptType::P2wsh], (1, 50_000));
+        assert_eq!(distribution[&ScriptType::P2tr], (1, 60_000));
+        assert_eq!(distribution[&ScriptType::Other], (1, 70_000));
+    }
+
+    #[test]
+    fn test_find_cpfp_candidates() {
+        let blockchain = MockBlockchain::default();
+        let mut database = MemoryDatabase::new();
+        assert!(blockchain
+            .find_cpfp_candidates(&database)
+            .unwrap()
+            .is_empty());
+
+        let tx = |lock_time| Transaction {
+            version: 1,
+            lock_time,
+            input: vec![],
+            output: vec![],
+        };
+        let utxo = |txid, vout, keychain, is_spent| LocalUtxo {
+            outpoint: OutPoint::new(txid, vout),
+            txout: TxOut {
+                value: 10_000,
+                script_pubkey: Script::from(vec![vout as u8; 22]),
+            },
+            keychain,
+            is_spent,
+            derivation_index: Some(vout),
+            suspected_dust: false,
+        };
+
+        // unconfirmed tx paying to the wallet, with some change
+        let unconfirmed = tx(0).txid();
+        // already confirmed tx, can't be bumped anymore
+        let confirmed = tx(1).txid();
+        database
+            .set_tx(&TransactionDetails {
+                txid: unconfirmed,
+                received: 30_000,
+                ..Default::default()
+            })
+            .unwrap();
+        database
+            .set_tx(&TransactionDetails {
+                txid: confirmed,
+                received: 10_000,
+                confirmation_time: Some(BlockTime {
+                    height: 100,
+                    timestamp: 1_600_000_000,
+                }),
+                ..Default::default()
+            })
+            .unwrap();
+        for utxo in vec![
+            utxo(unconfirmed, 2, KeychainKind::Internal, false),
+            utxo(unconfirmed, 0, KeychainKind::External, false),
+            // already spent by another child
+            utxo(unconfirmed, 1, KeychainKind::External, true),
+            utxo(confirmed, 0, KeychainKind::Internal, false),
+        ] {
+            database.set_utxo(&utxo).unwrap();
+        }
+
+        assert_eq!(
+            blockchain.find_cpfp_candidates(&database).unwrap(),
+            vec![CpfpCandidate {
+                txid: unconfirmed,
+                outpoints: vec![OutPoint::new(unconfirmed, 0), OutPoint::new(unconfirmed, 2)],
+            }]
+        );
+    }
+
+    #[test]
+    #[cfg(any(feature = "electrum", feature = "esplora"))]
+    fn test_is_notification_tx() {
+        let notification_script =
+            Script::from_hex("0014cb7d8ec2a6ae8a5e3c4c4e3e7f7f7f1e4a9e9a11").unwrap();
+        let other_script =
+            Script::from_hex("0014000102030405060708090a0b0c0d0e0f10111213").unwrap();
+        let txout = |script_pubkey: Script| TxOut {
+            value: 546,
+            script_pubkey,
+        };
+        let tx = |output: Vec<TxOut>| Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output,
+        };
+
+        let notification = tx(vec![
+            txout(notification_script.clone()),
+            txout(Script::new_op_return(&[0x01; 80])),
+        ]);
+        assert!(is_notification_tx(&notification, &notification_script));
+        assert!(!is_notification_tx(&notification, &other_script));
+
+        // a payload of the wrong size
+        let short_payload = tx(vec![
+            txout(notification_script.clone()),
+            txout(Script::new_op_return(&[0x01; 32])),
+        ]);
+        assert!(!is_notification_tx(&short_payload, &notification_script));
+
+        // a payment to the notification address without any payload
+        let payment = tx(vec![
+            txout(notification_script.clone()),
+            txout(other_script),
+        ]);
+        assert!(!is_notification_tx(&payment, &notification_script));
+    }
+
+    #[test]
+    fn test_network_from_genesis_hash() {
+        use bitcoin::blockdata::constants::genesis_block;
+        use bitcoin::Network;
+
+        for network in &[
+            Network::Bitcoin,
+            Network::Testnet,
+            Network::Signet,
+            Network::Regtest,
+        ] {
+            let genesis_hash = genesis_block(*network).block_hash();
+            assert_eq!(network_from_genesis_hash(&genesis_hash), Some(*network));
+        }
+
+        // testnet4 isn't mistaken for testnet3
+        let testnet4 = BlockHash::from_hex(TESTNET4_GENESIS_HASH).unwrap();
+        assert_eq!(network_from_genesis_hash(&testnet4), None);
+        assert_eq!(network_from_genesis_hash(&BlockHash::default()), None);
+    }
+
+    #[test]
+    #[cfg(any(feature = "electrum", feature = "esplora"))]
+    fn test_descriptor_script_pubkeys() {
+        use crate::descriptor::ExtendedDescriptor;
+        use bitcoin::Network;
+        use std::str::FromStr;
+
+        let descriptor = ExtendedDescriptor::from_str("wpkh(tpubD6NzVbkrYhZ4Y55A58Gv9RSNF5hy84b5AJqYy7sCcjFrkcLpPre8kmgfit6kY1Zs3BLgeypTDBZJM222guPpdz7Cup5yzaMu62u7mYGbwFL/0/*)").unwrap();
+        let scripts = descriptor_script_pubkeys(&descriptor, Network::Regtest, 5).unwrap();
+        assert_eq!(scripts.len(), 5);
+        assert_eq!(scripts.iter().collect::<HashSet<_>>().len(), 5);
+        assert!(descriptor_script_pubkeys(&descriptor, Network::Bitcoin, 5).is_err());
+
+        let fixed = ExtendedDescriptor::from_str(
+            "wpkh(02e96fe52ef0e22d2f131dd425ce1893073a3c6ad20e8cac36726393dfb4856a4c)",
+        )
+        .unwrap();
+        assert_eq!(
+            descriptor_script_pubkeys(&fixed, Network::Bitcoin, 5)
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    #[cfg(any(feature = "electrum", feature = "esplora"))]
+    fn test_into_fee_histogram() {
+        let histogram = into_fee_histogram(vec![
+            (5.0, 60_000),
+            (53.5, 10_000),
+            (f32::NAN, 1),
+            (1.0, 1_500_000),
+            (12.1, 25_000),
+        ]);
+
+        assert_eq!(
+            histogram,
+            vec![
+                (FeeRate::from_sat_per_vb(53.5), 10_000),
+                (FeeRate::from_sat_per_vb(12.1), 25_000),
+                (FeeRate::from_sat_per_vb(5.0), 60_000),
+                (FeeRate::from_sat_per_vb(1.0), 1_500_000),
+            ]
+        );
+        assert!(histogram.windows(2).all(|pair| pair[0].0 > pair[1].0));
+    }
+
+    #[test]
+    fn test_block_locator_heights() {
+        assert_eq!(block_locator_heights(0), vec![0]);
+        assert_eq!(block_locator_heights(5), vec![5, 4, 3, 2, 1, 0]);
+
+        for tip_height in &[100, 700_000, u32::MAX] {
+            let heights = block_locator_heights(*tip_height);
+            assert_eq!(heights[0], *tip_height);
+            assert_eq!(*heights.last().unwrap(), 0);
+            assert!(heights.len() <= 43, "{} hashes", heights.len());
+
+            let steps = heights
+                .windows(2)
+                .map(|pair| pair[0] - pair[1])
+                .collect::<Vec<_>>();
+            // dense at the tip
+            assert!(steps[..11].iter().all(|step| *step == 1));
+            // then the spacing doubles, except for the last step clamped to the genesis
+            for pair in steps[11..steps.len() - 1].windows(2) {
+                assert_eq!(pair[1], pair[0] * 2);
+            }
+            assert!(*steps.last().unwrap() <= steps[steps.len() - 2] * 2);
+        }
+    }
+
+    struct HeightHashes(u32);
+
+    impl GetBlockHash for HeightHashes {
+        fn get_block_hash(&self, height: u32) -> Result<Option<BlockHash>, Error> {
+            if height > self.0 {
+                return Ok(None);
+            }
+            Ok(Some(BlockHash::hash(&height.to_le_bytes())))
+        }
+
+        fn get_block_header(&self, _height: u32) -> Result<Option<BlockHeader>, Error> {
+            unreachable!("the locator only needs the hashes")
+        }
+    }
+
+    #[test]
+    fn test_get_block_locator() {
+        let locator = HeightHashes(1_000).get_block_locator(1_000).unwrap();
+        let heights = block_locator_heights(1_000);
+        assert_eq!(locator.len(), heights.len());
+        assert_eq!(locator[0], BlockHash::hash(&1_000u32.to_le_bytes()));
+        assert_eq!(locator[11], BlockHash::hash(&989u32.to_le_bytes()));
+        assert_eq!(
+            *locator.last().unwrap(),
+            BlockHash::hash(&0u32.to_le_bytes())
+        );
+
+        // the tip is unknown to the backend
+        assert!(HeightHashes(1_000).get_block_locator(1_001).is_err());
+    }
 }
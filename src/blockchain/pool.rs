@@ -0,0 +1,465 @@
+// Bitcoin Dev Kit
+//
+// Copyright (c) 2020-2022 Bitcoin Dev Kit Developers
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Pool of blockchain connections shared between wallets
+//!
+//! This module provides [`BlockchainPool`], a [`BlockchainFactory`] that hands out
+//! [`PooledBlockchain`] handles taken from a pool bounded by a global limit. Services syncing many
+//! wallets against the same backend can share a single pool between threads, so that wallets are
+//! synced concurrently without ever creating more than the allowed number of blockchains.
+//!
+//! The limit applies to blockchains, not to sockets: a blockchain that opens several connections
+//! on its own, like an [`ElectrumBlockchain`](crate::blockchain::electrum::ElectrumBlockchain)
+//! with a [`concurrency`](crate::blockchain::electrum::ElectrumBlockchainConfig::concurrency)
+//! larger than one, counts once. The number of sockets open at the same time is then at most
+//! `max_connections` times the number of connections held by each blockchain.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! # use bdk::blockchain::*;
+//! # #[cfg(feature = "electrum")]
+//! # {
+//! let config = ElectrumBlockchainConfig {
+//!     url: "ssl://electrum.blockstream.info:60002".into(),
+//!     socks5: None,
+//!     retry: 3,
+//!     timeout: None,
+//!     stop_gap: 20,
+//!     internal_stop_gap: None,
+//!     sync_error_policy: Default::default(),
+//!     tx_cache_max_entries: None,
+//!     cert_expiry_warning_days: None,
+//!     prefetch: false,
+//!     validate_proofs: false,
+//!     batch_size: None,
+//!     concurrency: None,
+//!     min_fee_rate: None,
+//! };
+//! // never open more than 4 connections to the server, one per blockchain
+//! let pool = BlockchainPool::<ElectrumBlockchain>::from_config(config, 4);
+//! let blockchain = pool.get()?;
+//! let height = blockchain.get_height()?;
+//! # }
+//! # Ok::<(), bdk::Error>(())
+//! ```
+
+use std::sync::{Condvar, Mutex};
+
+use super::*;
+
+type ConnectFn<B> = dyn Fn() -> Result<B, Error> + Send + Sync;
+
+struct PoolState<B> {
+    idle: Vec<B>,
+    open: usize,
+}
+
+struct PoolInner<B> {
+    connect: Box<ConnectFn<B>>,
+    max_connections: usize,
+    state: Mutex<PoolState<B>>,
+    available: Condvar,
+}
+
+/// Pool of blockchains bounded by a global limit
+///
+/// See [this module](crate::blockchain::pool)'s documentation for how the limit relates to the
+/// number of sockets open.
+///
+/// Cloning the pool is cheap and returns a new reference to the same pool.
+///
+/// See [this module](crate::blockchain::pool)'s documentation for a usage example.
+pub struct BlockchainPool<B> {
+    inner: Arc<PoolInner<B>>,
+}
+
+impl<B> Clone for BlockchainPool<B> {
+    fn clone(&self) -> Self {
+        BlockchainPool {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<B: Blockchain> BlockchainPool<B> {
+    /// Create a new pool that calls `connect` to create a new blockchain, keeping at most
+    /// `max_connections` of them alive at the same time
+    ///
+    /// Blockchains are created lazily, the first time they are needed.
+    pub fn new<F>(max_connections: usize, connect: F) -> Self
+    where
+        F: Fn() -> Result<B, Error> + Send + Sync + 'static,
+    {
+        assert!(max_connections > 0, "max_connections must be at least 1");
+
+        BlockchainPool {
+            inner: Arc::new(PoolInner {
+                connect: Box::new(connect),
+                max_connections,
+                state: Mutex::new(PoolState {
+                    idle: vec![],
+                    open: 0,
+                }),
+                available: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Create a new pool that opens every connection with [`ConfigurableBlockchain::from_config`]
+    ///
+    /// This is the way to pool blockchains that hold their own connections to the server, like
+    /// [`ElectrumBlockchain`](crate::blockchain::electrum::ElectrumBlockchain).
+    pub fn from_config(config: B::Config, max_connections: usize) -> Self
+    where
+        B: ConfigurableBlockchain,
+        B::Config: Send + Sync + 'static,
+    {
+        Self::new(max_connections, move || B::from_config(&config))
+    }
+
+    /// Create a new pool of at most `max_connections` clones of `blockchain`
+    ///
+    /// This is the way to share blockchains whose clones reuse the same underlying HTTP agent, like
+    /// [`EsploraBlockchain`](crate::blockchain::esplora::EsploraBlockchain).
+    pub fn from_shared(blockchain: B, max_connections: usize) -> Self
+    where
+        B: Clone + Send + Sync + 'static,
+    {
+        Self::new(max_connections, move || Ok(blockchain.clone()))
+    }
+
+    /// Take a blockchain from the pool, creating a new one if needed
+    ///
+    /// If `max_connections` are already in use this blocks until one of them is returned to the
+    /// pool, which happens when the [`PooledBlockchain`] is dropped.
+    pub fn get(&self) -> Result<PooledBlockchain<B>, Error> {
+        let mut state = self.inner.state.lock().unwrap();
+        loop {
+            if let Some(blockchain) = state.idle.pop() {
+                return Ok(self.wrap(blockchain));
+            }
+            if state.open < self.inner.max_connections {
+                break;
+            }
+            state = self.inner.available.wait(state).unwrap();
+        }
+
+        // reserve the slot and open the connection without holding the lock
+        state.open += 1;
+        drop(state);
+
+        match (self.inner.connect)() {
+            Ok(blockchain) => Ok(self.wrap(blockchain)),
+            Err(e) => {
+                self.inner.state.lock().unwrap().open -= 1;
+                self.inner.available.notify_one();
+                Err(e)
+            }
+        }
+    }
+
+    /// Return the maximum number of blockchains the pool will create
+    pub fn max_connections(&self) -> usize {
+        self.inner.max_connections
+    }
+
+    /// Return the number of blockchains currently alive, either idle or in use
+    pub fn open_connections(&self) -> usize {
+        self.inner.state.lock().unwrap().open
+    }
+
+    fn wrap(&self, blockchain: B) -> PooledBlockchain<B> {
+        PooledBlockchain {
+            blockchain: Some(blockchain),
+            pool: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<B: Blockchain> BlockchainFactory for BlockchainPool<B> {
+    type Inner = PooledBlockchain<B>;
+
+    fn build(
+        &self,
+        _wallet_name: &str,
+        _override_skip_blocks: Option<u32>,
+    ) -> Result<Self::Inner, Error> {
+        self.get()
+    }
+}
+
+/// Blockchain taken from a [`BlockchainPool`]
+///
+/// The underlying connection is returned to the pool when this is dropped.
+pub struct PooledBlockchain<B> {
+    blockchain: Option<B>,
+    pool: Arc<PoolInner<B>>,
+}
+
+impl<B> Deref for PooledBlockchain<B> {
+    type Target = B;
+
+    fn deref(&self) -> &Self::Target {
+        self.blockchain.as_ref().expect("only taken on drop")
+    }
+}
+
+impl<B> Drop for PooledBlockchain<B> {
+    fn drop(&mut self) {
+        if let Some(blockchain) = self.blockchain.take() {
+            self.pool.state.lock().unwrap().idle.push(blockchain);
+            self.pool.available.notify_one();
+        }
+    }
+}
+
+impl<B: Blockchain> Blockchain for PooledBlockchain<B> {
+    fn get_capabilities(&self) -> HashSet<Capability> {
+        self.deref().get_capabilities()
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<(), Error> {
+        self.deref().broadcast(tx)
+    }
+
+    fn estimate_fee(&self, target: usize) -> Result<FeeRate, Error> {
+        self.deref().estimate_fee(target)
+    }
+
+    fn broadcast_batch(&self, txs: &[Transaction]) -> Result<Vec<Txid>, Error> {
+        self.deref().broadcast_batch(txs)
+    }
+
+    fn get_tx_confirmations(&self, txid: &Txid) -> Result<Option<u32>, Error> {
+        self.deref().get_tx_confirmations(txid)
+    }
+}
+
+impl<B: GetHeight> GetHeight for PooledBlockchain<B> {
+    fn get_height(&self) -> Result<u32, Error> {
+        self.deref().get_height()
+    }
+}
+
+impl<B: GetTx> GetTx for PooledBlockchain<B> {
+    fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, Error> {
+        self.deref().get_tx(txid)
+    }
+}
+
+impl<B: GetBlockHash> GetBlockHash for PooledBlockchain<B> {
+    fn get_block_hash(&self, height: u32) -> Result<Option<BlockHash>, Error> {
+        self.deref().get_block_hash(height)
+    }
+
+    fn get_block_header(&self, height: u32) -> Result<Option<BlockHeader>, Error> {
+        self.deref().get_block_header(height)
+    }
+}
+
+impl<B: GetBlock> GetBlock for PooledBlockchain<B> {
+    fn get_block(&self, hash: &BlockHash) -> Result<Option<Block>, Error> {
+        self.deref().get_block(hash)
+    }
+}
+
+impl<B: WalletSync> WalletSync for PooledBlockchain<B> {
+    fn wallet_setup<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<(), Error> {
+        self.deref().wallet_setup(database, progress_update)
+    }
+
+    fn wallet_sync<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<(), Error> {
+        self.deref().wallet_sync(database, progress_update)
+    }
+
+    fn wallet_setup_with_stats<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<SyncStats, Error> {
+        self.deref()
+            .wallet_setup_with_stats(database, progress_update)
+    }
+
+    fn wallet_sync_with_stats<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<SyncStats, Error> {
+        self.deref()
+            .wallet_sync_with_stats(database, progress_update)
+    }
+
+    fn wallet_setup_with_budget<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+        max_requests: usize,
+    ) -> Result<SyncStats, Error> {
+        self.deref()
+            .wallet_setup_with_budget(database, progress_update, max_requests)
+    }
+
+    fn wallet_sync_with_budget<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+        max_requests: usize,
+    ) -> Result<SyncStats, Error> {
+        self.deref()
+            .wallet_sync_with_budget(database, progress_update, max_requests)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use bitcoin::Network;
+
+    use super::*;
+    use crate::database::MemoryDatabase;
+    use crate::wallet::test::get_test_wpkh;
+    use crate::wallet::{SyncOptions, Wallet};
+
+    #[derive(Clone, Default)]
+    struct Counters {
+        opened: Arc<AtomicUsize>,
+        syncing: Arc<AtomicUsize>,
+        max_syncing: Arc<AtomicUsize>,
+        synced: Arc<AtomicUsize>,
+    }
+
+    #[derive(Clone)]
+    struct MockBlockchain(Counters);
+
+    impl Blockchain for MockBlockchain {
+        fn get_capabilities(&self) -> HashSet<Capability> {
+            HashSet::new()
+        }
+
+        fn broadcast(&self, _tx: &Transaction) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn estimate_fee(&self, _target: usize) -> Result<FeeRate, Error> {
+            Ok(FeeRate::default_min_relay_fee())
+        }
+    }
+
+    impl GetHeight for MockBlockchain {
+        fn get_height(&self) -> Result<u32, Error> {
+            Ok(0)
+        }
+    }
+
+    impl GetTx for MockBlockchain {
+        fn get_tx(&self, _txid: &Txid) -> Result<Option<Transaction>, Error> {
+            Ok(None)
+        }
+    }
+
+    impl WalletSync for MockBlockchain {
+        fn wallet_setup<D: BatchDatabase>(
+            &self,
+            _database: &mut D,
+            _progress_update: Box<dyn Progress>,
+        ) -> Result<(), Error> {
+            let syncing = self.0.syncing.fetch_add(1, Ordering::SeqCst) + 1;
+            self.0.max_syncing.fetch_max(syncing, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(50));
+            self.0.syncing.fetch_sub(1, Ordering::SeqCst);
+            self.0.synced.fetch_add(1, Ordering::SeqCst);
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_pool_concurrent_sync_within_cap() {
+        let counters = Counters::default();
+        let pool = {
+            let counters = counters.clone();
+            BlockchainPool::new(3, move || {
+                counters.opened.fetch_add(1, Ordering::SeqCst);
+                Ok(MockBlockchain(counters.clone()))
+            })
+        };
+
+        let handles = (0..8)
+            .map(|_| {
+                let pool = pool.clone();
+                std::thread::spawn(move || {
+                    let wallet = Wallet::new(
+                        get_test_wpkh(),
+                        None,
+                        Network::Regtest,
+                        MemoryDatabase::new(),
+                    )
+                    .unwrap();
+                    pool.sync_wallet(&wallet, None, SyncOptions::default())
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap().unwrap();
+        }
+
+        assert_eq!(counters.synced.load(Ordering::SeqCst), 8);
+        assert!(counters.opened.load(Ordering::SeqCst) <= 3);
+        assert!(counters.max_syncing.load(Ordering::SeqCst) <= 3);
+        assert!(pool.open_connections() <= pool.max_connections());
+    }
+
+    #[test]
+    fn test_pool_reuses_connections() {
+        let counters = Counters::default();
+        let pool = BlockchainPool::from_shared(MockBlockchain(counters.clone()), 2);
+
+        let a = pool.get().unwrap();
+        let b = pool.get().unwrap();
+        assert_eq!(pool.open_connections(), 2);
+        drop(a);
+
+        let _c = pool.get().unwrap();
+        assert_eq!(pool.open_connections(), 2);
+        drop(b);
+    }
+
+    #[test]
+    fn test_pool_connect_error_frees_slot() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let pool = {
+            let attempts = Arc::clone(&attempts);
+            BlockchainPool::new(1, move || {
+                if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                    Err(Error::Generic("connection refused".into()))
+                } else {
+                    Ok(MockBlockchain(Counters::default()))
+                }
+            })
+        };
+
+        assert!(pool.get().is_err());
+        assert_eq!(pool.open_connections(), 0);
+        assert!(pool.get().is_ok());
+        assert_eq!(pool.open_connections(), 1);
+    }
+}
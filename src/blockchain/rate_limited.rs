@@ -0,0 +1,517 @@
+// Bitcoin Dev Kit
+//
+// Copyright (c) 2020-2022 Bitcoin Dev Kit Developers
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Rate limiting of the requests sent to a blockchain backend
+//!
+//! This module provides [`RateLimiter`], which bounds the number of requests sent per second with
+//! a token bucket, and [`LimitRequests`], implemented by the backends that wait for a token before
+//! every request they send to their server: [Electrum](crate::blockchain::electrum),
+//! [Esplora](crate::blockchain::esplora) and its async counterpart. Every request counts, including
+//! the ones sent while syncing a wallet, and a batch of Electrum calls counts as one request.
+//!
+//! Clones of a [`RateLimiter`] share the same bucket, so giving the same limiter to several
+//! blockchains, for instance to all the ones created by a
+//! [`BlockchainPool`](crate::blockchain::BlockchainPool), bounds the requests they send together.
+//!
+//! [`RateLimitedBlockchain`] is a shortcut to create a limiter and give it to a single backend.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! # use bdk::blockchain::*;
+//! # #[cfg(all(feature = "esplora", not(feature = "async-interface")))]
+//! # {
+//! let esplora = EsploraBlockchain::new("https://blockstream.info/testnet/api", 20);
+//! // never send more than 5 requests per second
+//! let blockchain = RateLimitedBlockchain::new(esplora, 5.0)?;
+//! let height = blockchain.get_height()?;
+//! # }
+//! # Ok::<(), bdk::Error>(())
+//! ```
+
+use super::*;
+
+/// Token bucket shared between the clones of a [`RateLimiter`]
+struct TokenBucket {
+    /// Tokens added per second
+    rate: f64,
+    /// Maximum number of tokens in the bucket
+    burst: f64,
+    /// Tokens in the bucket, negative when requests are waiting for their token, and the last
+    /// time it was refilled
+    state: Mutex<(f64, Instant)>,
+}
+
+impl fmt::Debug for TokenBucket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenBucket")
+            .field("rate", &self.rate)
+            .field("burst", &self.burst)
+            .finish()
+    }
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst: u32) -> Self {
+        let burst = f64::from(burst.max(1));
+        TokenBucket {
+            rate,
+            burst,
+            state: Mutex::new((burst, Instant::new())),
+        }
+    }
+
+    /// Take a token, returning how long to wait before it can be used
+    fn take(&self) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last_refill) = &mut *state;
+
+        *tokens = (*tokens + last_refill.elapsed().as_secs_f64() * self.rate).min(self.burst);
+        *last_refill = Instant::new();
+        *tokens -= 1.0;
+
+        if *tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-*tokens / self.rate)
+        }
+    }
+}
+
+/// Limit on the number of requests sent per second, shared between its clones
+///
+/// See [this module](crate::blockchain::rate_limited)'s documentation for more details.
+#[derive(Debug, Clone)]
+pub struct RateLimiter(Arc<TokenBucket>);
+
+impl RateLimiter {
+    /// Create a new limiter allowing `requests_per_second` requests per second
+    ///
+    /// The requests are evenly spaced, use [`RateLimiter::with_burst`] to allow some of them to be
+    /// sent at once after a pause. Fails with [`Error::InvalidRateLimit`] if
+    /// `requests_per_second` isn't a finite number greater than zero.
+    pub fn new(requests_per_second: f64) -> Result<Self, Error> {
+        if !requests_per_second.is_finite() || requests_per_second <= 0.0 {
+            return Err(Error::InvalidRateLimit(requests_per_second));
+        }
+
+        Ok(RateLimiter(Arc::new(TokenBucket::new(
+            requests_per_second,
+            1,
+        ))))
+    }
+
+    /// Allow up to `burst` requests to be sent at once after a pause
+    ///
+    /// This returns a limiter with a new bucket, so it should be called before cloning it.
+    pub fn with_burst(self, burst: u32) -> Self {
+        RateLimiter(Arc::new(TokenBucket::new(self.0.rate, burst)))
+    }
+
+    /// Return the number of requests allowed per second
+    pub fn requests_per_second(&self) -> f64 {
+        self.0.rate
+    }
+
+    /// Block the thread until a request can be sent
+    #[cfg(any(
+        feature = "electrum",
+        feature = "ureq",
+        all(test, not(feature = "async-interface"))
+    ))]
+    pub(crate) fn wait(&self) {
+        let delay = self.0.take();
+        if delay > Duration::from_secs(0) {
+            std::thread::sleep(delay);
+        }
+    }
+
+    /// Wait until a request can be sent, without blocking the thread
+    #[cfg(any(feature = "async-interface", feature = "reqwest"))]
+    pub(crate) async fn wait_async(&self) {
+        let delay = self.0.take();
+        if delay > Duration::from_secs(0) {
+            crate::wallet::time::sleep(delay).await;
+        }
+    }
+}
+
+/// Backend that waits for a [`RateLimiter`] before every request it sends to its server
+pub trait LimitRequests {
+    /// Wait for `limiter` before every request sent to the server
+    ///
+    /// The limiter is shared with the clones of the blockchain.
+    fn with_rate_limiter(self, limiter: RateLimiter) -> Self;
+}
+
+/// Blockchain that limits the number of requests per second sent by an inner backend
+///
+/// See [this module](crate::blockchain::rate_limited)'s documentation for a usage example.
+#[derive(Debug, Clone)]
+pub struct RateLimitedBlockchain<B> {
+    inner: B,
+    limiter: RateLimiter,
+}
+
+/// Configuration for a [`RateLimitedBlockchain`]
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq)]
+pub struct RateLimitedBlockchainConfig<C> {
+    /// Configuration of the inner backend
+    pub inner: C,
+    /// Maximum number of requests per second
+    pub requests_per_second: f64,
+    /// Number of requests that can be sent at once after a pause, defaults to one
+    pub burst: Option<u32>,
+}
+
+impl<B: LimitRequests> RateLimitedBlockchain<B> {
+    /// Create a new instance sending at most `requests_per_second` requests per second with
+    /// `inner`
+    ///
+    /// The requests are evenly spaced, use [`RateLimitedBlockchain::with_burst`] to allow some of
+    /// them to be sent at once after a pause. Fails with [`Error::InvalidRateLimit`] if
+    /// `requests_per_second` isn't a finite number greater than zero.
+    pub fn new(inner: B, requests_per_second: f64) -> Result<Self, Error> {
+        let limiter = RateLimiter::new(requests_per_second)?;
+        Ok(RateLimitedBlockchain {
+            inner: inner.with_rate_limiter(limiter.clone()),
+            limiter,
+        })
+    }
+
+    /// Allow up to `burst` requests to be sent at once after a pause
+    ///
+    /// This replaces the limiter of the blockchain, so it should be called before cloning it.
+    pub fn with_burst(self, burst: u32) -> Self {
+        let limiter = self.limiter.with_burst(burst);
+        RateLimitedBlockchain {
+            inner: self.inner.with_rate_limiter(limiter.clone()),
+            limiter,
+        }
+    }
+}
+
+impl<B> RateLimitedBlockchain<B> {
+    /// Return a reference to the inner backend
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    /// Return the limiter shared with the inner backend, to give it to other blockchains
+    pub fn rate_limiter(&self) -> &RateLimiter {
+        &self.limiter
+    }
+}
+
+#[maybe_async]
+impl<B: Blockchain> Blockchain for RateLimitedBlockchain<B> {
+    fn get_capabilities(&self) -> HashSet<Capability> {
+        maybe_await!(self.inner.get_capabilities())
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<(), Error> {
+        maybe_await!(self.inner.broadcast(tx))
+    }
+
+    fn estimate_fee(&self, target: usize) -> Result<FeeRate, Error> {
+        maybe_await!(self.inner.estimate_fee(target))
+    }
+
+    fn broadcast_batch(&self, txs: &[Transaction]) -> Result<Vec<Txid>, Error> {
+        maybe_await!(self.inner.broadcast_batch(txs))
+    }
+
+    fn get_tx_confirmations(&self, txid: &Txid) -> Result<Option<u32>, Error> {
+        maybe_await!(self.inner.get_tx_confirmations(txid))
+    }
+}
+
+#[maybe_async]
+impl<B: Blockchain> GetHeight for RateLimitedBlockchain<B> {
+    fn get_height(&self) -> Result<u32, Error> {
+        maybe_await!(self.inner.get_height())
+    }
+}
+
+#[maybe_async]
+impl<B: Blockchain> GetTx for RateLimitedBlockchain<B> {
+    fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, Error> {
+        maybe_await!(self.inner.get_tx(txid))
+    }
+}
+
+#[maybe_async]
+impl<B: Blockchain + GetBlockHash> GetBlockHash for RateLimitedBlockchain<B> {
+    fn get_block_hash(&self, height: u32) -> Result<Option<BlockHash>, Error> {
+        maybe_await!(self.inner.get_block_hash(height))
+    }
+
+    fn get_block_header(&self, height: u32) -> Result<Option<BlockHeader>, Error> {
+        maybe_await!(self.inner.get_block_header(height))
+    }
+}
+
+#[maybe_async]
+impl<B: Blockchain + GetBlock> GetBlock for RateLimitedBlockchain<B> {
+    fn get_block(&self, hash: &BlockHash) -> Result<Option<Block>, Error> {
+        maybe_await!(self.inner.get_block(hash))
+    }
+}
+
+#[maybe_async]
+impl<B: Blockchain> WalletSync for RateLimitedBlockchain<B> {
+    fn wallet_setup<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<(), Error> {
+        maybe_await!(self.inner.wallet_setup(database, progress_update))
+    }
+
+    fn wallet_sync<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<(), Error> {
+        maybe_await!(self.inner.wallet_sync(database, progress_update))
+    }
+
+    fn wallet_setup_with_stats<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<SyncStats, Error> {
+        maybe_await!(self
+            .inner
+            .wallet_setup_with_stats(database, progress_update))
+    }
+
+    fn wallet_sync_with_stats<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<SyncStats, Error> {
+        maybe_await!(self.inner.wallet_sync_with_stats(database, progress_update))
+    }
+
+    fn wallet_setup_with_budget<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+        max_requests: usize,
+    ) -> Result<SyncStats, Error> {
+        maybe_await!(self
+            .inner
+            .wallet_setup_with_budget(database, progress_update, max_requests))
+    }
+
+    fn wallet_sync_with_budget<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+        max_requests: usize,
+    ) -> Result<SyncStats, Error> {
+        maybe_await!(self
+            .inner
+            .wallet_sync_with_budget(database, progress_update, max_requests))
+    }
+}
+
+impl<B: ConfigurableBlockchain + LimitRequests> ConfigurableBlockchain
+    for RateLimitedBlockchain<B>
+{
+    type Config = RateLimitedBlockchainConfig<B::Config>;
+
+    fn from_config(config: &Self::Config) -> Result<Self, Error> {
+        let blockchain =
+            RateLimitedBlockchain::new(B::from_config(&config.inner)?, config.requests_per_second)?;
+        Ok(match config.burst {
+            Some(burst) => blockchain.with_burst(burst),
+            None => blockchain,
+        })
+    }
+}
+
+#[cfg(all(test, not(feature = "async-interface")))]
+mod test {
+    use super::*;
+
+    /// Blockchain waiting for its limiter before answering, like a backend before sending a
+    /// request
+    #[derive(Clone, Default)]
+    struct MockBlockchain {
+        limiter: Option<RateLimiter>,
+    }
+
+    impl MockBlockchain {
+        fn request(&self) {
+            if let Some(limiter) = &self.limiter {
+                limiter.wait();
+            }
+        }
+    }
+
+    impl LimitRequests for MockBlockchain {
+        fn with_rate_limiter(self, limiter: RateLimiter) -> Self {
+            MockBlockchain {
+                limiter: Some(limiter),
+            }
+        }
+    }
+
+    impl ConfigurableBlockchain for MockBlockchain {
+        type Config = ();
+
+        fn from_config(_config: &Self::Config) -> Result<Self, Error> {
+            Ok(MockBlockchain::default())
+        }
+    }
+
+    impl Blockchain for MockBlockchain {
+        fn get_capabilities(&self) -> HashSet<Capability> {
+            HashSet::new()
+        }
+
+        fn broadcast(&self, _tx: &Transaction) -> Result<(), Error> {
+            self.request();
+            Ok(())
+        }
+
+        fn estimate_fee(&self, _target: usize) -> Result<FeeRate, Error> {
+            self.request();
+            Ok(FeeRate::default_min_relay_fee())
+        }
+    }
+
+    impl GetHeight for MockBlockchain {
+        fn get_height(&self) -> Result<u32, Error> {
+            self.request();
+            Ok(0)
+        }
+    }
+
+    impl GetTx for MockBlockchain {
+        fn get_tx(&self, _txid: &Txid) -> Result<Option<Transaction>, Error> {
+            self.request();
+            Ok(None)
+        }
+    }
+
+    impl WalletSync for MockBlockchain {
+        fn wallet_setup<D: BatchDatabase>(
+            &self,
+            _database: &mut D,
+            _progress_update: Box<dyn Progress>,
+        ) -> Result<(), Error> {
+            // a sync sends several requests
+            for _ in 0..5 {
+                self.request();
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_rate_limited_spacing() {
+        let blockchain = RateLimitedBlockchain::new(MockBlockchain::default(), 50.0).unwrap();
+
+        let start = std::time::Instant::now();
+        for _ in 0..11 {
+            blockchain.get_height().unwrap();
+        }
+        // the first call goes through right away, the next 10 are spaced by 20ms
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_rate_limited_sync_requests() {
+        let blockchain = RateLimitedBlockchain::new(MockBlockchain::default(), 50.0).unwrap();
+        let mut database = crate::database::MemoryDatabase::new();
+
+        let start = std::time::Instant::now();
+        for _ in 0..3 {
+            blockchain
+                .wallet_setup(&mut database, Box::new(NoopProgress))
+                .unwrap();
+        }
+        // every request of the syncs takes a token, not every sync
+        assert!(start.elapsed() >= Duration::from_millis(280));
+    }
+
+    #[test]
+    fn test_rate_limited_shared_between_clones() {
+        let blockchain = RateLimitedBlockchain::new(MockBlockchain::default(), 50.0).unwrap();
+
+        let start = std::time::Instant::now();
+        let handles = (0..2)
+            .map(|_| {
+                let blockchain = blockchain.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..5 {
+                        blockchain.estimate_fee(6).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(180));
+    }
+
+    #[test]
+    fn test_rate_limiter_shared_between_blockchains() {
+        let limiter = RateLimiter::new(50.0).unwrap();
+        let blockchains = (0..2)
+            .map(|_| MockBlockchain::default().with_rate_limiter(limiter.clone()))
+            .collect::<Vec<_>>();
+
+        let start = std::time::Instant::now();
+        for _ in 0..5 {
+            for blockchain in &blockchains {
+                blockchain.get_height().unwrap();
+            }
+        }
+        assert!(start.elapsed() >= Duration::from_millis(180));
+    }
+
+    #[test]
+    fn test_rate_limited_burst() {
+        let blockchain = RateLimitedBlockchain::new(MockBlockchain::default(), 1.0)
+            .unwrap()
+            .with_burst(5);
+
+        let start = std::time::Instant::now();
+        for _ in 0..5 {
+            blockchain.get_height().unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_invalid_rate() {
+        for rate in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(matches!(
+                RateLimitedBlockchain::new(MockBlockchain::default(), rate),
+                Err(Error::InvalidRateLimit(_))
+            ));
+        }
+
+        let config = RateLimitedBlockchainConfig {
+            inner: (),
+            requests_per_second: 0.0,
+            burst: None,
+        };
+        assert!(matches!(
+            RateLimitedBlockchain::<MockBlockchain>::from_config(&config),
+            Err(Error::InvalidRateLimit(_))
+        ));
+    }
+}
@@ -0,0 +1,117 @@
+// Bitcoin Dev Kit
+//
+// Copyright (c) 2020-2022 Bitcoin Dev Kit Developers
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Detection of the reorgs affecting the confirmed transactions of a wallet
+//!
+//! The hashes of the blocks confirming the wallet transactions are stored in the database, for the
+//! last [`REORG_CHECK_DEPTH`] blocks. Before a sync they are compared with the hashes of the best
+//! chain of the backend: the transactions confirmed in a block that was reorged out are marked as
+//! unconfirmed, so that the sync fetches their new confirmation time.
+
+use std::collections::HashSet;
+
+use bitcoin::Txid;
+use log::*;
+
+use crate::blockchain::{GetBlockHash, GetHeight};
+use crate::database::{BatchDatabase, BatchOperations};
+use crate::error::Error;
+
+/// Number of blocks below the tip whose hashes are kept to detect reorgs
+pub(crate) const REORG_CHECK_DEPTH: u32 = 100;
+
+/// Compare the stored block hashes with the best chain of `blockchain`, returning the txids of the
+/// transactions confirmed in blocks that aren't part of it anymore
+///
+/// These transactions are marked as unconfirmed in the database, and the hashes of the reorged
+/// blocks are deleted.
+#[maybe_async]
+pub(crate) fn detect_reorgs<B: GetBlockHash, D: BatchDatabase>(
+    blockchain: &B,
+    database: &mut D,
+) -> Result<Vec<Txid>, Error> {
+    let mut reorged_heights = HashSet::new();
+    for (height, hash) in database.iter_block_hashes()? {
+        if maybe_await!(blockchain.get_block_hash(height))? != Some(hash) {
+            reorged_heights.insert(height);
+        }
+    }
+    if reorged_heights.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut batch = database.begin_batch();
+    let mut reorged_txids = vec![];
+    for mut details in database.iter_txs(false)? {
+        let height = details.confirmation_time.as_ref().map(|time| time.height);
+        if matches!(height, Some(height) if reorged_heights.contains(&height)) {
+            warn!(
+                "Block at height {} was reorged out, transaction {} is unconfirmed",
+                height.unwrap(),
+                details.txid
+            );
+            details.confirmation_time = None;
+            batch.set_tx(&details)?;
+            reorged_txids.push(details.txid);
+        }
+    }
+    for height in reorged_heights {
+        batch.del_block_hash(height)?;
+    }
+    database.commit_batch(batch)?;
+
+    Ok(reorged_txids)
+}
+
+/// Store the hashes of the blocks confirming the wallet transactions in the last
+/// [`REORG_CHECK_DEPTH`] blocks, forgetting the older ones
+#[maybe_async]
+pub(crate) fn store_block_hashes<B: GetHeight + GetBlockHash, D: BatchDatabase>(
+    blockchain: &B,
+    database: &mut D,
+) -> Result<(), Error> {
+    let stored_heights = database
+        .iter_block_hashes()?
+        .into_iter()
+        .map(|(height, _)| height)
+        .collect::<Vec<_>>();
+    let confirmed_heights = database
+        .iter_txs(false)?
+        .into_iter()
+        .filter_map(|details| details.confirmation_time.map(|time| time.height))
+        .collect::<HashSet<_>>();
+    // don't ask the backend for its tip if there's nothing to store or forget
+    if stored_heights.is_empty() && confirmed_heights.is_empty() {
+        return Ok(());
+    }
+
+    let min_height = maybe_await!(blockchain.get_height())?.saturating_sub(REORG_CHECK_DEPTH);
+    let mut batch = database.begin_batch();
+
+    let mut stored = HashSet::new();
+    for height in stored_heights {
+        if height <= min_height {
+            batch.del_block_hash(height)?;
+        } else {
+            stored.insert(height);
+        }
+    }
+    let confirmed_heights = confirmed_heights
+        .into_iter()
+        .filter(|height| *height > min_height)
+        .collect::<HashSet<_>>();
+    for height in confirmed_heights.difference(&stored) {
+        if let Some(hash) = maybe_await!(blockchain.get_block_hash(*height))? {
+            batch.set_block_hash(*height, &hash)?;
+        }
+    }
+
+    database.commit_batch(batch)
+}
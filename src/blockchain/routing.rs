@@ -0,0 +1,384 @@
+// Bitcoin Dev Kit
+//
+// Copyright (c) 2020-2022 Bitcoin Dev Kit Developers
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Routing of blockchain calls by capability
+//!
+//! This module provides [`CapabilityRoutingBlockchain`], a [`Blockchain`] that wraps a primary and
+//! a secondary backend. Every call is sent to the primary backend, unless it needs a
+//! [`Capability`] the primary doesn't advertise and the secondary does, in which case it falls
+//! back to the secondary. This makes it possible, for instance, to sync a wallet against an
+//! Electrum server while fetching arbitrary transactions from an Esplora instance.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! # use bdk::blockchain::*;
+//! # #[cfg(all(feature = "electrum", feature = "esplora", not(feature = "async-interface")))]
+//! # {
+//! # use bitcoin::Txid;
+//! # use std::str::FromStr;
+//! let electrum = ElectrumBlockchain::from(bdk::electrum_client::Client::new(
+//!     "ssl://electrum.blockstream.info:60002",
+//! )?);
+//! let esplora = EsploraBlockchain::new("https://blockstream.info/testnet/api", 20);
+//! let blockchain = CapabilityRoutingBlockchain::new(electrum, esplora);
+//!
+//! let txid = Txid::from_str("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b")
+//!     .unwrap();
+//! let tx = blockchain.get_tx(&txid)?;
+//! # }
+//! # Ok::<(), bdk::Error>(())
+//! ```
+
+use super::*;
+
+/// Blockchain that routes each call to the first of two backends advertising the capability it
+/// needs
+///
+/// See [this module](crate::blockchain::routing)'s documentation for a usage example.
+#[derive(Debug)]
+pub struct CapabilityRoutingBlockchain<P, S> {
+    primary: P,
+    secondary: S,
+}
+
+/// Configuration for a [`CapabilityRoutingBlockchain`]
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq)]
+pub struct CapabilityRoutingBlockchainConfig<P, S> {
+    /// Configuration of the primary backend
+    pub primary: P,
+    /// Configuration of the secondary backend, used for the calls the primary can't serve
+    pub secondary: S,
+}
+
+impl<P, S> CapabilityRoutingBlockchain<P, S> {
+    /// Create a new instance routing calls between `primary` and `secondary`
+    pub fn new(primary: P, secondary: S) -> Self {
+        CapabilityRoutingBlockchain { primary, secondary }
+    }
+
+    /// Return a reference to the primary backend
+    pub fn primary(&self) -> &P {
+        &self.primary
+    }
+
+    /// Return a reference to the secondary backend
+    pub fn secondary(&self) -> &S {
+        &self.secondary
+    }
+}
+
+impl<P: Blockchain, S: Blockchain> CapabilityRoutingBlockchain<P, S> {
+    /// Whether a call needing `capability` has to be sent to the secondary backend
+    #[maybe_async]
+    fn use_secondary(&self, capability: Capability) -> bool {
+        !maybe_await!(self.primary.get_capabilities()).contains(&capability)
+            && maybe_await!(self.secondary.get_capabilities()).contains(&capability)
+    }
+}
+
+#[maybe_async]
+impl<P: Blockchain, S: Blockchain> Blockchain for CapabilityRoutingBlockchain<P, S> {
+    fn get_capabilities(&self) -> HashSet<Capability> {
+        let mut capabilities = maybe_await!(self.primary.get_capabilities());
+        capabilities.extend(maybe_await!(self.secondary.get_capabilities()));
+        capabilities
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<(), Error> {
+        maybe_await!(self.primary.broadcast(tx))
+    }
+
+    fn estimate_fee(&self, target: usize) -> Result<FeeRate, Error> {
+        maybe_await!(self.primary.estimate_fee(target))
+    }
+
+    fn broadcast_batch(&self, txs: &[Transaction]) -> Result<Vec<Txid>, Error> {
+        maybe_await!(self.primary.broadcast_batch(txs))
+    }
+
+    fn get_tx_confirmations(&self, txid: &Txid) -> Result<Option<u32>, Error> {
+        if maybe_await!(self.use_secondary(Capability::GetAnyTx)) {
+            maybe_await!(self.secondary.get_tx_confirmations(txid))
+        } else {
+            maybe_await!(self.primary.get_tx_confirmations(txid))
+        }
+    }
+}
+
+#[maybe_async]
+impl<P: Blockchain, S: Blockchain> GetHeight for CapabilityRoutingBlockchain<P, S> {
+    fn get_height(&self) -> Result<u32, Error> {
+        maybe_await!(self.primary.get_height())
+    }
+}
+
+#[maybe_async]
+impl<P: Blockchain, S: Blockchain> GetTx for CapabilityRoutingBlockchain<P, S> {
+    fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, Error> {
+        if maybe_await!(self.use_secondary(Capability::GetAnyTx)) {
+            maybe_await!(self.secondary.get_tx(txid))
+        } else {
+            maybe_await!(self.primary.get_tx(txid))
+        }
+    }
+}
+
+#[maybe_async]
+impl<P: Blockchain + GetBlockHash, S: Blockchain> GetBlockHash
+    for CapabilityRoutingBlockchain<P, S>
+{
+    fn get_block_hash(&self, height: u32) -> Result<Option<BlockHash>, Error> {
+        maybe_await!(self.primary.get_block_hash(height))
+    }
+
+    fn get_block_header(&self, height: u32) -> Result<Option<BlockHeader>, Error> {
+        maybe_await!(self.primary.get_block_header(height))
+    }
+}
+
+#[maybe_async]
+impl<P: Blockchain + GetBlock, S: Blockchain + GetBlock> GetBlock
+    for CapabilityRoutingBlockchain<P, S>
+{
+    fn get_block(&self, hash: &BlockHash) -> Result<Option<Block>, Error> {
+        if maybe_await!(self.use_secondary(Capability::GetBlock)) {
+            maybe_await!(self.secondary.get_block(hash))
+        } else {
+            maybe_await!(self.primary.get_block(hash))
+        }
+    }
+}
+
+#[maybe_async]
+impl<P: Blockchain, S: Blockchain> WalletSync for CapabilityRoutingBlockchain<P, S> {
+    fn wallet_setup<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<(), Error> {
+        if maybe_await!(self.use_secondary(Capability::FullHistory)) {
+            maybe_await!(self.secondary.wallet_setup(database, progress_update))
+        } else {
+            maybe_await!(self.primary.wallet_setup(database, progress_update))
+        }
+    }
+
+    fn wallet_sync<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<(), Error> {
+        if maybe_await!(self.use_secondary(Capability::FullHistory)) {
+            maybe_await!(self.secondary.wallet_sync(database, progress_update))
+        } else {
+            maybe_await!(self.primary.wallet_sync(database, progress_update))
+        }
+    }
+
+    fn wallet_setup_with_stats<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<SyncStats, Error> {
+        if maybe_await!(self.use_secondary(Capability::FullHistory)) {
+            maybe_await!(self
+                .secondary
+                .wallet_setup_with_stats(database, progress_update))
+        } else {
+            maybe_await!(self
+                .primary
+                .wallet_setup_with_stats(database, progress_update))
+        }
+    }
+
+    fn wallet_sync_with_stats<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+    ) -> Result<SyncStats, Error> {
+        if maybe_await!(self.use_secondary(Capability::FullHistory)) {
+            maybe_await!(self
+                .secondary
+                .wallet_sync_with_stats(database, progress_update))
+        } else {
+            maybe_await!(self
+                .primary
+                .wallet_sync_with_stats(database, progress_update))
+        }
+    }
+
+    fn wallet_setup_with_budget<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+        max_requests: usize,
+    ) -> Result<SyncStats, Error> {
+        if maybe_await!(self.use_secondary(Capability::FullHistory)) {
+            maybe_await!(self.secondary.wallet_setup_with_budget(
+                database,
+                progress_update,
+                max_requests
+            ))
+        } else {
+            maybe_await!(self.primary.wallet_setup_with_budget(
+                database,
+                progress_update,
+                max_requests
+            ))
+        }
+    }
+
+    fn wallet_sync_with_budget<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress_update: Box<dyn Progress>,
+        max_requests: usize,
+    ) -> Result<SyncStats, Error> {
+        if maybe_await!(self.use_secondary(Capability::FullHistory)) {
+            maybe_await!(self.secondary.wallet_sync_with_budget(
+                database,
+                progress_update,
+                max_requests
+            ))
+        } else {
+            maybe_await!(self.primary.wallet_sync_with_budget(
+                database,
+                progress_update,
+                max_requests
+            ))
+        }
+    }
+}
+
+impl<P: ConfigurableBlockchain, S: ConfigurableBlockchain> ConfigurableBlockchain
+    for CapabilityRoutingBlockchain<P, S>
+{
+    type Config = CapabilityRoutingBlockchainConfig<P::Config, S::Config>;
+
+    fn from_config(config: &Self::Config) -> Result<Self, Error> {
+        Ok(CapabilityRoutingBlockchain::new(
+            P::from_config(&config.primary)?,
+            S::from_config(&config.secondary)?,
+        ))
+    }
+}
+
+#[cfg(all(test, not(feature = "async-interface")))]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use bitcoin::TxOut;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct MockBlockchain {
+        capabilities: Vec<Capability>,
+        tx: Option<Transaction>,
+        get_tx_calls: AtomicUsize,
+    }
+
+    impl Blockchain for MockBlockchain {
+        fn get_capabilities(&self) -> HashSet<Capability> {
+            self.capabilities.iter().cloned().collect()
+        }
+
+        fn broadcast(&self, _tx: &Transaction) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn estimate_fee(&self, _target: usize) -> Result<FeeRate, Error> {
+            Ok(FeeRate::default_min_relay_fee())
+        }
+    }
+
+    impl GetHeight for MockBlockchain {
+        fn get_height(&self) -> Result<u32, Error> {
+            Ok(0)
+        }
+    }
+
+    impl WalletSync for MockBlockchain {
+        fn wallet_setup<D: BatchDatabase>(
+            &self,
+            _database: &mut D,
+            _progress_update: Box<dyn Progress>,
+        ) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    impl GetTx for MockBlockchain {
+        fn get_tx(&self, _txid: &Txid) -> Result<Option<Transaction>, Error> {
+            self.get_tx_calls.fetch_add(1, Ordering::SeqCst);
+            Ok(self.tx.clone())
+        }
+    }
+
+    fn test_tx() -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output: vec![TxOut {
+                value: 50_000,
+                script_pubkey: Script::new(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_routing_get_tx_to_secondary() {
+        let primary = MockBlockchain {
+            capabilities: vec![Capability::FullHistory],
+            ..Default::default()
+        };
+        let secondary = MockBlockchain {
+            capabilities: vec![Capability::GetAnyTx],
+            tx: Some(test_tx()),
+            ..Default::default()
+        };
+        let blockchain = CapabilityRoutingBlockchain::new(primary, secondary);
+
+        let tx = blockchain.get_tx(&test_tx().txid()).unwrap();
+        assert_eq!(tx, Some(test_tx()));
+        assert_eq!(blockchain.primary().get_tx_calls.load(Ordering::SeqCst), 0);
+        assert_eq!(
+            blockchain.secondary().get_tx_calls.load(Ordering::SeqCst),
+            1
+        );
+
+        let capabilities = blockchain.get_capabilities();
+        assert!(capabilities.contains(&Capability::FullHistory));
+        assert!(capabilities.contains(&Capability::GetAnyTx));
+    }
+
+    #[test]
+    fn test_routing_prefers_primary() {
+        let primary = MockBlockchain {
+            capabilities: vec![Capability::GetAnyTx],
+            ..Default::default()
+        };
+        let secondary = MockBlockchain {
+            capabilities: vec![Capability::GetAnyTx],
+            tx: Some(test_tx()),
+            ..Default::default()
+        };
+        let blockchain = CapabilityRoutingBlockchain::new(primary, secondary);
+
+        assert_eq!(blockchain.get_tx(&test_tx().txid()).unwrap(), None);
+        assert_eq!(blockchain.primary().get_tx_calls.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            blockchain.secondary().get_tx_calls.load(Ordering::SeqCst),
+            0
+        );
+    }
+}
@@ -33,11 +33,11 @@
 
 use crate::bitcoin::consensus::deserialize;
 use crate::bitcoin::hashes::hex::ToHex;
-use crate::bitcoin::{Address, Network, OutPoint, Transaction, TxOut, Txid};
+use crate::bitcoin::{Address, Block, BlockHash, Network, OutPoint, Transaction, TxOut, Txid};
 use crate::blockchain::*;
 use crate::database::{BatchDatabase, DatabaseUtils};
 use crate::descriptor::get_checksum;
-use crate::{BlockTime, Error, FeeRate, KeychainKind, LocalUtxo, TransactionDetails};
+use crate::{BlockTime, Error, FeeRate, KeychainKind, LocalUtxo, TransactionDetails, TxType};
 use bitcoincore_rpc::json::{
     GetAddressInfoResultLabel, ImportMultiOptions, ImportMultiRequest,
     ImportMultiRequestScriptPubkey, ImportMultiRescanSince,
@@ -155,6 +155,19 @@ impl Blockchain for RpcBlockchain {
 
         Ok(FeeRate::from_sat_per_vb((sat_per_kb / 1000f64) as f32))
     }
+
+    fn get_tx_confirmations(&self, txid: &Txid) -> Result<Option<u32>, Error> {
+        match self.client.get_raw_transaction_info(txid, None) {
+            Ok(info) => Ok(Some(info.confirmations.unwrap_or(0))),
+            // RPC_INVALID_ADDRESS_OR_KEY, returned for unknown transactions
+            Err(bitcoincore_rpc::Error::JsonRpc(bitcoincore_rpc::jsonrpc::Error::Rpc(e)))
+                if e.code == -5 =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
 }
 
 impl GetTx for RpcBlockchain {
@@ -163,6 +176,43 @@ impl GetTx for RpcBlockchain {
     }
 }
 
+impl GetBlock for RpcBlockchain {
+    fn get_block(&self, hash: &BlockHash) -> Result<Option<Block>, Error> {
+        match self.client.get_block(hash) {
+            Ok(block) => Ok(Some(block)),
+            // RPC_INVALID_ADDRESS_OR_KEY, returned for unknown blocks
+            Err(bitcoincore_rpc::Error::JsonRpc(bitcoincore_rpc::jsonrpc::Error::Rpc(e)))
+                if e.code == -5 =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl GetBlockHash for RpcBlockchain {
+    fn get_block_hash(&self, height: u32) -> Result<Option<BlockHash>, Error> {
+        match self.client.get_block_hash(height as u64) {
+            Ok(hash) => Ok(Some(hash)),
+            // RPC_INVALID_PARAMETER, returned for heights above the tip
+            Err(bitcoincore_rpc::Error::JsonRpc(bitcoincore_rpc::jsonrpc::Error::Rpc(e)))
+                if e.code == -8 =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn get_block_header(&self, height: u32) -> Result<Option<BlockHeader>, Error> {
+        match self.get_block_hash(height)? {
+            Some(hash) => Ok(Some(self.client.get_block_header(&hash)?)),
+            None => Ok(None),
+        }
+    }
+}
+
 impl GetHeight for RpcBlockchain {
     fn get_height(&self) -> Result<u32, Error> {
         Ok(self.client.get_blockchain_info().map(|i| i.blocks as u32)?)
@@ -306,6 +356,7 @@ impl WalletSync for RpcBlockchain {
                 let tx: Transaction = deserialize(&tx_result.hex)?;
                 let mut received = 0u64;
                 let mut sent = 0u64;
+                let mut owned_outputs = 0;
                 for output in tx.output.iter() {
                     if let Ok(Some((kind, index))) =
                         db.get_path_from_script_pubkey(&output.script_pubkey)
@@ -314,6 +365,7 @@ impl WalletSync for RpcBlockchain {
                             indexes.insert(kind, index);
                         }
                         received += output.value;
+                        owned_outputs += 1;
                     }
                 }
 
@@ -325,6 +377,7 @@ impl WalletSync for RpcBlockchain {
                     }
                 }
 
+                let tx_type = TxType::classify(&tx, sent, owned_outputs);
                 let td = TransactionDetails {
                     transaction: Some(tx),
                     txid: tx_result.info.txid,
@@ -335,6 +388,7 @@ impl WalletSync for RpcBlockchain {
                     received,
                     sent,
                     fee: tx_result.fee.map(|f| f.as_sat().abs() as u64),
+                    tx_type: Some(tx_type),
                 };
                 debug!(
                     "saving tx: {} tx_result.fee:{:?} td.fees:{:?}",
@@ -366,6 +420,8 @@ impl WalletSync for RpcBlockchain {
                             script_pubkey: u.script_pub_key,
                         },
                         is_spent: false,
+                        derivation_index: Some(path.1),
+                        suspected_dust: false,
                     })),
                 },
             )
@@ -449,7 +505,9 @@ impl ConfigurableBlockchain for RpcBlockchain {
             });
         }
 
-        let mut capabilities: HashSet<_> = vec![Capability::FullHistory].into_iter().collect();
+        let mut capabilities: HashSet<_> = vec![Capability::FullHistory, Capability::GetBlock]
+            .into_iter()
+            .collect();
         if rpc_version >= 210_000 {
             let info: HashMap<String, Value> = client.call("getindexinfo", &[]).unwrap();
             if info.contains_key("txindex") {
@@ -602,6 +660,33 @@ mod test {
         (test_client, factory)
     }
 
+    #[test]
+    fn test_rpc_get_block() {
+        let test_client = TestClient::default();
+        let config = RpcConfig {
+            url: test_client.bitcoind.rpc_url(),
+            auth: Auth::Cookie {
+                file: test_client.bitcoind.params.cookie_file.clone(),
+            },
+            network: Network::Regtest,
+            wallet_name: "get-block".to_string(),
+            skip_blocks: None,
+        };
+        let blockchain = RpcBlockchain::from_config(&config).unwrap();
+        assert!(blockchain
+            .get_capabilities()
+            .contains(&Capability::GetBlock));
+
+        let block_hash = test_client.get_best_block_hash().unwrap();
+        let block = blockchain.get_block(&block_hash).unwrap().unwrap();
+        assert_eq!(block.block_hash(), block_hash);
+        assert!(block.check_merkle_root());
+        assert!(blockchain
+            .get_block(&BlockHash::default())
+            .unwrap()
+            .is_none());
+    }
+
     #[test]
     fn test_rpc_blockchain_factory() {
         let (_test_client, factory) = get_factory();
@@ -4,14 +4,19 @@ returns associated transactions i.e. electrum.
 */
 #![allow(dead_code)]
 use crate::{
-    database::{BatchDatabase, BatchOperations, DatabaseUtils},
+    blockchain::{OwnershipResolver, SyncErrorPolicy, SyncPlan, SyncProgress},
+    database::{BatchDatabase, BatchOperations},
     wallet::time::Instant,
-    BlockTime, Error, KeychainKind, LocalUtxo, TransactionDetails,
+    BlockTime, Error, KeychainKind, LocalUtxo, TransactionDetails, TxType,
 };
 use bitcoin::{OutPoint, Script, Transaction, TxOut, Txid};
 use log::*;
 use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 
+/// History of a script pubkey: the txids paying to or spending from it, with their confirmation
+/// height if any
+type ScriptHistory = Vec<(Txid, Option<u32>)>;
+
 /// A request for on-chain information
 pub enum Request<'a, D: BatchDatabase> {
     /// A request for transactions related to script pubkeys.
@@ -24,8 +29,82 @@ pub enum Request<'a, D: BatchDatabase> {
     Finish(D::Batch),
 }
 
+impl<'a, D: BatchDatabase> Request<'a, D> {
+    /// Progress of the scan of the script pubkeys so far, `None` once the sync is finished
+    pub fn sync_progress(&self) -> Option<SyncProgress> {
+        let state = match self {
+            Request::Script(script_req) => &script_req.state,
+            Request::Conftime(conftime_req) => &conftime_req.state,
+            Request::Tx(tx_req) => &tx_req.state,
+            Request::Finish(_) => return None,
+        };
+
+        Some(SyncProgress {
+            scripts_scanned: state.scripts_scanned,
+            scripts_total_estimate: state.scripts_total_estimate,
+            current_keychain: state.current_keychain,
+            elapsed: state.start_time.elapsed(),
+        })
+    }
+
+    /// Number of script pubkeys whose history couldn't be fetched so far, `None` once the sync is
+    /// finished
+    pub fn skipped_scripts(&self) -> Option<usize> {
+        match self {
+            Request::Script(script_req) => Some(script_req.state.skipped_scripts),
+            Request::Conftime(conftime_req) => Some(conftime_req.state.skipped_scripts),
+            Request::Tx(tx_req) => Some(tx_req.state.skipped_scripts),
+            Request::Finish(_) => None,
+        }
+    }
+}
+
+/// Number of consecutive unused script pubkeys after which the sync stops looking for
+/// transactions, for each keychain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StopGap {
+    pub external: usize,
+    pub internal: usize,
+}
+
+impl StopGap {
+    /// Use `stop_gap` for the external keychain, and `internal_stop_gap` for the internal one if
+    /// it's set
+    pub fn new(stop_gap: usize, internal_stop_gap: Option<usize>) -> Self {
+        StopGap {
+            external: stop_gap,
+            internal: internal_stop_gap.unwrap_or(stop_gap),
+        }
+    }
+
+    fn get(&self, keychain: KeychainKind) -> usize {
+        match keychain {
+            KeychainKind::External => self.external,
+            KeychainKind::Internal => self.internal,
+        }
+    }
+}
+
+impl From<usize> for StopGap {
+    fn from(stop_gap: usize) -> Self {
+        StopGap::new(stop_gap, None)
+    }
+}
+
 /// starts a sync
-pub fn start<D: BatchDatabase>(db: &D, stop_gap: usize) -> Result<Request<'_, D>, Error> {
+pub fn start<D: BatchDatabase>(
+    db: &D,
+    stop_gap: impl Into<StopGap>,
+) -> Result<Request<'_, D>, Error> {
+    start_with_ownership_resolver(db, stop_gap, None)
+}
+
+/// starts a sync, consulting `ownership_resolver` to decide which outputs belong to the wallet
+pub fn start_with_ownership_resolver<D: BatchDatabase>(
+    db: &D,
+    stop_gap: impl Into<StopGap>,
+    ownership_resolver: Option<OwnershipResolver>,
+) -> Result<Request<'_, D>, Error> {
     use rand::seq::SliceRandom;
     let mut keychains = vec![KeychainKind::Internal, KeychainKind::External];
     // shuffling improve privacy, the server doesn't know my first request is from my internal or external addresses
@@ -35,25 +114,87 @@ pub fn start<D: BatchDatabase>(db: &D, stop_gap: usize) -> Result<Request<'_, D>
         .iter_script_pubkeys(Some(keychain))?
         .into_iter()
         .collect();
-    let state = State::new(db);
+    let mut scripts_cached = HashMap::new();
+    for keychain in &keychains {
+        scripts_cached.insert(*keychain, db.iter_script_pubkeys(Some(*keychain))?.len());
+    }
+    let mut state = State::new(db);
+    state.ownership_resolver = ownership_resolver;
 
-    Ok(Request::Script(ScriptReq {
+    let mut script_req = ScriptReq {
         state,
         scripts_needed,
         script_index: 0,
-        stop_gap,
+        stop_gap: stop_gap.into(),
         keychain,
         next_keychains: keychains,
-    }))
+        scripts_cached,
+    };
+    script_req.update_total_estimate();
+
+    Ok(Request::Script(script_req))
+}
+
+/// Run the script pubkeys phase of a sync against the transactions already in `db`, without
+/// querying any server, and return the script pubkeys it would request in batches of `batch_size`
+///
+/// A script pubkey is considered used if a transaction of `db` pays to it.
+pub fn plan<D: BatchDatabase>(
+    db: &D,
+    stop_gap: impl Into<StopGap>,
+    batch_size: usize,
+) -> Result<SyncPlan, Error> {
+    let stop_gap = stop_gap.into();
+    let mut histories: HashMap<Script, ScriptHistory> = HashMap::new();
+    for details in db.iter_txs(true)? {
+        let height = details.confirmation_time.as_ref().map(|time| time.height);
+        for output in details.transaction.iter().flat_map(|tx| &tx.output) {
+            histories
+                .entry(output.script_pubkey.clone())
+                .or_default()
+                .push((details.txid, height));
+        }
+    }
+
+    let mut scripts = vec![];
+    let mut request = start(db, stop_gap)?;
+    while let Request::Script(script_req) = request {
+        let batch = script_req
+            .request()
+            .take(batch_size.max(1))
+            .cloned()
+            .collect::<Vec<_>>();
+        for (offset, script) in batch.iter().enumerate() {
+            let index = match db.get_path_from_script_pubkey(script)? {
+                Some((_, index)) => index,
+                None => (script_req.script_index + offset) as u32,
+            };
+            scripts.push((script_req.keychain, index, script.clone()));
+        }
+
+        let txids = batch
+            .iter()
+            .map(|script| histories.get(script).cloned().unwrap_or_default())
+            .collect();
+        request = script_req.satisfy(txids)?;
+    }
+
+    Ok(SyncPlan {
+        scripts,
+        stop_gap: stop_gap.external,
+        internal_stop_gap: stop_gap.internal,
+    })
 }
 
 pub struct ScriptReq<'a, D: BatchDatabase> {
     state: State<'a, D>,
     script_index: usize,
     scripts_needed: VecDeque<Script>,
-    stop_gap: usize,
+    stop_gap: StopGap,
     keychain: KeychainKind,
     next_keychains: Vec<KeychainKind>,
+    /// Number of script pubkeys cached for each of the `next_keychains`
+    scripts_cached: HashMap<KeychainKind, usize>,
 }
 
 /// The sync starts by returning script pubkeys we are interested in.
@@ -63,11 +204,35 @@ impl<'a, D: BatchDatabase> ScriptReq<'a, D> {
     }
 
     pub fn satisfy(
-        mut self,
+        self,
         // we want to know the txids assoiciated with the script and their height
-        txids: Vec<Vec<(Txid, Option<u32>)>>,
+        txids: Vec<ScriptHistory>,
+    ) -> Result<Request<'a, D>, Error> {
+        self.satisfy_histories(txids.into_iter().map(Some).collect())
+    }
+
+    /// Satisfy the request with the history of each script pubkey, or `None` for the ones whose
+    /// history couldn't be fetched
+    ///
+    /// A script pubkey without history is neither used nor unused: it's counted as skipped, and
+    /// as active for the stop gap since it may have received funds.
+    fn satisfy_histories(
+        mut self,
+        histories: Vec<Option<ScriptHistory>>,
     ) -> Result<Request<'a, D>, Error> {
-        for (txid_list, script) in txids.iter().zip(self.scripts_needed.iter()) {
+        for (history, script) in histories.iter().zip(self.scripts_needed.iter()) {
+            let txid_list = match history {
+                Some(txid_list) => txid_list,
+                None => {
+                    self.state.skipped_scripts += 1;
+                    self.state
+                        .last_active_index
+                        .insert(self.keychain, self.script_index);
+                    self.script_index += 1;
+                    self.state.scripts_scanned += 1;
+                    continue;
+                }
+            };
             debug!(
                 "found {} transactions for script pubkey {}",
                 txid_list.len(),
@@ -111,9 +276,10 @@ impl<'a, D: BatchDatabase> ScriptReq<'a, D> {
             }
 
             self.script_index += 1;
+            self.state.scripts_scanned += 1;
         }
 
-        for _ in txids {
+        for _ in histories {
             self.scripts_needed.pop_front();
         }
 
@@ -125,7 +291,7 @@ impl<'a, D: BatchDatabase> ScriptReq<'a, D> {
             .unwrap_or(0); // so no addresses active maps to 0
 
         Ok(
-            if self.script_index > last_active_index + self.stop_gap
+            if self.script_index > last_active_index + self.stop_gap.get(self.keychain)
                 || self.scripts_needed.is_empty()
             {
                 debug!(
@@ -142,15 +308,91 @@ impl<'a, D: BatchDatabase> ScriptReq<'a, D> {
                         .iter_script_pubkeys(Some(keychain))?
                         .into_iter()
                         .collect();
+                    self.update_total_estimate();
                     Request::Script(self)
                 } else {
+                    self.update_total_estimate();
                     Request::Tx(TxReq { state: self.state })
                 }
             } else {
+                self.update_total_estimate();
                 Request::Script(self)
             },
         )
     }
+
+    /// Update the estimate of the number of script pubkeys the sync will scan
+    ///
+    /// For every keychain at least the script pubkeys up to the last active one plus the stop gap
+    /// are scanned, unless fewer are cached. The estimate is the sum of these lower bounds, it can
+    /// only grow as more active script pubkeys are found.
+    fn update_total_estimate(&mut self) {
+        let last_active_index = self
+            .state
+            .last_active_index
+            .get(&self.keychain)
+            .map(|x| x + 1)
+            .unwrap_or(0);
+        let current = (self.script_index + self.scripts_needed.len())
+            .min(last_active_index + self.stop_gap.get(self.keychain) + 1)
+            .max(self.script_index);
+        let next = self
+            .next_keychains
+            .iter()
+            .map(|keychain| self.scripts_cached[keychain].min(self.stop_gap.get(*keychain) + 1))
+            .sum::<usize>();
+        let estimate = self.state.scripts_scanned - self.script_index + current + next;
+
+        self.state.scripts_total_estimate = self.state.scripts_total_estimate.max(estimate);
+        self.state.current_keychain = self.keychain;
+    }
+}
+
+impl<'a, D: BatchDatabase> ScriptReq<'a, D> {
+    /// Like [`ScriptReq::satisfy`], but takes the result of the history fetch of every script
+    /// pubkey and handles the errors according to `policy`.
+    ///
+    /// Under [`SyncErrorPolicy::SkipErrored`] the script pubkeys that errored are left
+    /// unsatisfied: they don't count towards the stop gap, and the sync is reported as incomplete
+    /// by [`Request::skipped_scripts`].
+    pub fn satisfy_with_policy(
+        self,
+        results: Vec<Result<ScriptHistory, Error>>,
+        policy: SyncErrorPolicy,
+    ) -> Result<Request<'a, D>, Error> {
+        let mut histories = Vec::with_capacity(results.len());
+        for (result, script) in results.into_iter().zip(self.scripts_needed.iter()) {
+            match result {
+                Ok(txid_list) => histories.push(Some(txid_list)),
+                Err(e) if policy == SyncErrorPolicy::SkipErrored => {
+                    warn!(
+                        "skipping script pubkey {} after failing to fetch its history: {}",
+                        script, e
+                    );
+                    histories.push(None);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.satisfy_histories(histories)
+    }
+
+    /// Stop fetching the history of script pubkeys, as if all the remaining ones were skipped
+    /// because of an error
+    ///
+    /// The transactions found so far are still completed, but no existing transaction is deleted
+    /// since it may belong to one of the skipped script pubkeys.
+    pub fn skip_remaining(mut self) -> Result<Request<'a, D>, Error> {
+        let mut skipped = self.scripts_needed.len();
+        for keychain in &self.next_keychains {
+            skipped += self.state.db.iter_script_pubkeys(Some(*keychain))?.len();
+        }
+        warn!("skipping the remaining {} script pubkeys", skipped);
+        self.state.skipped_scripts += skipped;
+
+        Ok(Request::Tx(TxReq { state: self.state }))
+    }
 }
 
 /// Then we get full transactions
@@ -177,6 +419,10 @@ impl<'a, D: BatchDatabase> TxReq<'a, D> {
                 let mut received: u64 = 0;
                 let mut inputs_sum: u64 = 0;
                 let mut outputs_sum: u64 = 0;
+                let mut owned_outputs = 0;
+                // the backend can leave out the outputs spent from other wallets, in which case
+                // the fee is unknown
+                let mut missing_prevouts = false;
 
                 for (txout, (_input_index, input)) in
                     vout.into_iter().zip(tx.input.iter().enumerate())
@@ -185,10 +431,7 @@ impl<'a, D: BatchDatabase> TxReq<'a, D> {
                         Some(txout) => txout,
                         None => {
                             // skip coinbase inputs
-                            debug_assert!(
-                                input.previous_output.is_null(),
-                                "prevout should only be missing for coinbase"
-                            );
+                            missing_prevouts |= !input.previous_output.is_null();
                             continue;
                         }
                     };
@@ -206,20 +449,22 @@ impl<'a, D: BatchDatabase> TxReq<'a, D> {
                         .map_err(VerifyError::from)?;
                     }
                     inputs_sum += txout.value;
-                    if self.state.db.is_mine(&txout.script_pubkey)? {
+                    if self.state.is_mine(&txout.script_pubkey)? {
                         sent += txout.value;
                     }
                 }
 
                 for out in &tx.output {
                     outputs_sum += out.value;
-                    if self.state.db.is_mine(&out.script_pubkey)? {
+                    if self.state.is_mine(&out.script_pubkey)? {
                         received += out.value;
+                        owned_outputs += 1;
                     }
                 }
                 // we need to saturating sub since we want coinbase txs to map to 0 fee and
                 // this subtraction will be negative for coinbase txs.
                 let fee = inputs_sum.saturating_sub(outputs_sum);
+                let tx_type = TxType::classify(&tx, sent, owned_outputs);
                 Result::<_, Error>::Ok(TransactionDetails {
                     txid: *txid,
                     transaction: Some(tx),
@@ -227,7 +472,8 @@ impl<'a, D: BatchDatabase> TxReq<'a, D> {
                     sent,
                     // we're going to fill this in later
                     confirmation_time: None,
-                    fee: Some(fee),
+                    fee: if missing_prevouts { None } else { Some(fee) },
+                    tx_type: Some(tx_type),
                 })
             })
             .collect::<Result<Vec<_>, _>>()?;
@@ -294,6 +540,16 @@ struct State<'a, D> {
     tx_missing_conftime: BTreeMap<Txid, TransactionDetails>,
     /// The start of the sync
     start_time: Instant,
+    /// Number of script pubkeys whose history couldn't be fetched
+    skipped_scripts: usize,
+    /// Custom resolution of the ownership of outputs, consulted before the database
+    ownership_resolver: Option<OwnershipResolver>,
+    /// Number of script pubkeys whose history was fetched
+    scripts_scanned: usize,
+    /// Estimate of the number of script pubkeys to scan, see [`SyncProgress`]
+    scripts_total_estimate: usize,
+    /// Keychain of the script pubkeys being scanned
+    current_keychain: KeychainKind,
 }
 
 impl<'a, D: BatchDatabase> State<'a, D> {
@@ -305,14 +561,47 @@ impl<'a, D: BatchDatabase> State<'a, D> {
             tx_needed: BTreeSet::default(),
             tx_missing_conftime: BTreeMap::default(),
             start_time: Instant::new(),
+            skipped_scripts: 0,
+            ownership_resolver: None,
+            scripts_scanned: 0,
+            scripts_total_estimate: 0,
+            current_keychain: KeychainKind::External,
         }
     }
+
+    /// Return the keychain and derivation index of `script` if it belongs to the wallet
+    ///
+    /// Scripts claimed by the ownership resolver but unknown to the database have no index.
+    fn get_path(&self, script: &Script) -> Result<Option<(KeychainKind, Option<u32>)>, Error> {
+        let path = self.db.get_path_from_script_pubkey(script)?;
+        let resolved = self
+            .ownership_resolver
+            .as_ref()
+            .and_then(|resolver| resolver.resolve(script));
+
+        Ok(match resolved {
+            Some(keychain) => Some((keychain, path.map(|(_, index)| index))),
+            None => path.map(|(keychain, index)| (keychain, Some(index))),
+        })
+    }
+
+    fn is_mine(&self, script: &Script) -> Result<bool, Error> {
+        Ok(self.get_path(script)?.is_some())
+    }
     fn into_db_update(self) -> Result<D::Batch, Error> {
         debug_assert!(self.tx_needed.is_empty() && self.tx_missing_conftime.is_empty());
         let existing_txs = self.db.iter_txs(false)?;
         let existing_txids: HashSet<Txid> = existing_txs.iter().map(|tx| tx.txid).collect();
         let finished_txs = make_txs_consistent(&self.finished_txs);
-        let observed_txids: HashSet<Txid> = finished_txs.iter().map(|tx| tx.txid).collect();
+        let mut observed_txids: HashSet<Txid> = finished_txs.iter().map(|tx| tx.txid).collect();
+        if self.skipped_scripts > 0 {
+            warn!(
+                "sync incomplete: skipped {} script pubkeys, not deleting any transaction",
+                self.skipped_scripts
+            );
+            // we can't tell if a tx is gone or if it belongs to one of the skipped scripts
+            observed_txids.extend(existing_txids.iter().cloned());
+        }
         let txids_to_delete = existing_txids.difference(&observed_txids);
         let mut batch = self.db.begin_batch();
 
@@ -355,9 +644,7 @@ impl<'a, D: BatchDatabase> State<'a, D> {
                 .as_ref()
                 .expect("transaction will always be present here");
             for (i, output) in tx.output.iter().enumerate() {
-                if let Some((keychain, _)) =
-                    self.db.get_path_from_script_pubkey(&output.script_pubkey)?
-                {
+                if let Some((keychain, derivation_index)) = self.get_path(&output.script_pubkey)? {
                     // add utxos we own from the new transactions we've seen.
                     let outpoint = OutPoint {
                         txid: finished_tx.txid,
@@ -370,6 +657,12 @@ impl<'a, D: BatchDatabase> State<'a, D> {
                         keychain,
                         // Is this UTXO in the spent_utxos set?
                         is_spent: spent_utxos.get(&outpoint).is_some(),
+                        derivation_index,
+                        // keep the flag set by previous syncs
+                        suspected_dust: self
+                            .db
+                            .get_utxo(&outpoint)?
+                            .map_or(false, |utxo| utxo.suspected_dust),
                     })?;
                 }
             }
@@ -413,3 +706,648 @@ fn make_txs_consistent(txs: &[TransactionDetails]) -> Vec<&TransactionDetails> {
         .map(|(_, tx)| tx)
         .collect()
 }
+
+#[cfg(test)]
+mod test {
+    use bitcoin::hashes::Hash;
+    use bitcoin::{Script, TxIn};
+
+    use super::*;
+    use crate::database::{Database, MemoryDatabase};
+
+    fn get_test_db() -> MemoryDatabase {
+        let mut db = MemoryDatabase::new();
+        for i in 0..2 {
+            let script = Script::from(vec![i as u8; 22]);
+            db.set_script_pubkey(&script, KeychainKind::External, i)
+                .unwrap();
+        }
+
+        db
+    }
+
+    fn run_sync(db: &MemoryDatabase, policy: SyncErrorPolicy) -> Result<(), Error> {
+        let failing_script = db
+            .get_script_pubkey_from_path(KeychainKind::External, 0)?
+            .unwrap();
+        let mut request = start(db, 20)?;
+        loop {
+            request = match request {
+                Request::Script(script_req) => {
+                    let results = script_req
+                        .request()
+                        .map(|script: &Script| {
+                            if script == &failing_script {
+                                Err(Error::Generic("history unavailable".into()))
+                            } else {
+                                Ok(vec![])
+                            }
+                        })
+                        .collect();
+                    script_req.satisfy_with_policy(results, policy)?
+                }
+                Request::Tx(tx_req) => tx_req.satisfy(vec![])?,
+                Request::Conftime(conftime_req) => conftime_req.satisfy(vec![])?,
+                Request::Finish(_) => break Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_sync_error_policy_fail_fast() {
+        let db = get_test_db();
+        assert!(matches!(
+            run_sync(&db, SyncErrorPolicy::FailFast),
+            Err(Error::Generic(_))
+        ));
+    }
+
+    #[test]
+    fn test_sync_error_policy_skip_errored() {
+        let db = get_test_db();
+        run_sync(&db, SyncErrorPolicy::SkipErrored).unwrap();
+    }
+
+    #[test]
+    fn test_sync_error_policy_skipped_script_extends_gap() {
+        let mut db = MemoryDatabase::new();
+        for i in 0..10 {
+            let script = Script::from(vec![i as u8; 22]);
+            db.set_script_pubkey(&script, KeychainKind::External, i)
+                .unwrap();
+        }
+        let failing_script = Script::from(vec![1; 22]);
+
+        let mut scanned = 0;
+        let mut skipped = 0;
+        let mut request = start(&db, 2).unwrap();
+        loop {
+            skipped = request.skipped_scripts().unwrap_or(skipped);
+            request = match request {
+                Request::Script(script_req) => {
+                    let next = script_req.request().next().cloned();
+                    match next {
+                        // the internal keychain has no script pubkeys
+                        None => script_req.satisfy(vec![]).unwrap(),
+                        Some(script) => {
+                            scanned += 1;
+                            let result = if script == failing_script {
+                                Err(Error::Generic("history unavailable".into()))
+                            } else {
+                                Ok(vec![])
+                            };
+                            script_req
+                                .satisfy_with_policy(vec![result], SyncErrorPolicy::SkipErrored)
+                                .unwrap()
+                        }
+                    }
+                }
+                Request::Tx(tx_req) => tx_req.satisfy(vec![]).unwrap(),
+                Request::Conftime(conftime_req) => conftime_req.satisfy(vec![]).unwrap(),
+                Request::Finish(_) => break,
+            }
+        }
+
+        assert_eq!(skipped, 1);
+        // the errored script pubkey may be funded, so the gap starts after it
+        assert_eq!(scanned, 5);
+    }
+
+    #[test]
+    fn test_sync_error_policy_keeps_unseen_txs() {
+        let mut db = get_test_db();
+        let tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output: vec![],
+        };
+        let txid = tx.txid();
+        db.set_tx(&TransactionDetails {
+            transaction: Some(tx),
+            txid,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let mut request = start(&db, 20).unwrap();
+        let batch = loop {
+            request = match request {
+                Request::Script(script_req) => {
+                    let results = script_req
+                        .request()
+                        .map(|_| Err(Error::Generic("history unavailable".into())))
+                        .collect();
+                    script_req
+                        .satisfy_with_policy(results, SyncErrorPolicy::SkipErrored)
+                        .unwrap()
+                }
+                Request::Tx(tx_req) => tx_req.satisfy(vec![]).unwrap(),
+                Request::Conftime(conftime_req) => conftime_req.satisfy(vec![]).unwrap(),
+                Request::Finish(batch) => break batch,
+            }
+        };
+        db.commit_batch(batch).unwrap();
+
+        assert!(
+            db.get_tx(&txid, false).unwrap().is_some(),
+            "an incomplete sync shouldn't delete transactions"
+        );
+    }
+
+    #[test]
+    fn test_utxos_carry_derivation_index() {
+        let mut db = get_test_db();
+        let script = db
+            .get_script_pubkey_from_path(KeychainKind::External, 1)
+            .unwrap()
+            .unwrap();
+        let tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value: 50_000,
+                script_pubkey: script.clone(),
+            }],
+        };
+        let txid = tx.txid();
+
+        let mut request = start(&db, 20).unwrap();
+        let batch = loop {
+            request = match request {
+                Request::Script(script_req) => {
+                    let txids = script_req
+                        .request()
+                        .map(|s| {
+                            if s == &script {
+                                vec![(txid, None)]
+                            } else {
+                                vec![]
+                            }
+                        })
+                        .collect();
+                    script_req.satisfy(txids).unwrap()
+                }
+                Request::Tx(tx_req) => tx_req.satisfy(vec![(vec![], tx.clone())]).unwrap(),
+                Request::Conftime(conftime_req) => {
+                    let conftimes = conftime_req.request().map(|_| None).collect();
+                    conftime_req.satisfy(conftimes).unwrap()
+                }
+                Request::Finish(batch) => break batch,
+            }
+        };
+        db.commit_batch(batch).unwrap();
+
+        let utxo = db.get_utxo(&OutPoint { txid, vout: 0 }).unwrap().unwrap();
+        assert_eq!(utxo.keychain, KeychainKind::External);
+        assert_eq!(utxo.derivation_index, Some(1));
+    }
+
+    #[test]
+    fn test_missing_prevouts_leave_fee_unknown() {
+        let mut db = get_test_db();
+        let script = db
+            .get_script_pubkey_from_path(KeychainKind::External, 1)
+            .unwrap()
+            .unwrap();
+        let tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::default(), 0),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 50_000,
+                script_pubkey: script.clone(),
+            }],
+        };
+        let txid = tx.txid();
+
+        let mut request = start(&db, 20).unwrap();
+        let batch = loop {
+            request = match request {
+                Request::Script(script_req) => {
+                    let txids = script_req
+                        .request()
+                        .map(|s| {
+                            if s == &script {
+                                vec![(txid, None)]
+                            } else {
+                                vec![]
+                            }
+                        })
+                        .collect();
+                    script_req.satisfy(txids).unwrap()
+                }
+                // the output spent belongs to another wallet and wasn't fetched
+                Request::Tx(tx_req) => tx_req.satisfy(vec![(vec![None], tx.clone())]).unwrap(),
+                Request::Conftime(conftime_req) => {
+                    let conftimes = conftime_req.request().map(|_| None).collect();
+                    conftime_req.satisfy(conftimes).unwrap()
+                }
+                Request::Finish(batch) => break batch,
+            }
+        };
+        db.commit_batch(batch).unwrap();
+
+        let details = db.get_tx(&txid, false).unwrap().unwrap();
+        assert_eq!(details.received, 50_000);
+        assert_eq!(details.fee, None);
+    }
+
+    #[test]
+    fn test_tx_type_classification() {
+        let mut db = get_test_db();
+        let script_0 = db
+            .get_script_pubkey_from_path(KeychainKind::External, 0)
+            .unwrap()
+            .unwrap();
+        let script_1 = db
+            .get_script_pubkey_from_path(KeychainKind::External, 1)
+            .unwrap()
+            .unwrap();
+        let foreign = TxOut {
+            value: 100_000,
+            script_pubkey: Script::from(vec![0xff; 22]),
+        };
+
+        let txout = |value, script_pubkey: &Script| TxOut {
+            value,
+            script_pubkey: script_pubkey.clone(),
+        };
+        let spend = |previous_output| TxIn {
+            previous_output,
+            ..Default::default()
+        };
+        let new_tx = |input, output| Transaction {
+            version: 1,
+            lock_time: 0,
+            input,
+            output,
+        };
+
+        let coinbase = new_tx(
+            vec![spend(OutPoint::null())],
+            vec![txout(50_000, &script_0)],
+        );
+        let receive = new_tx(
+            vec![spend(OutPoint::new(Txid::default(), 0))],
+            vec![txout(50_000, &script_1)],
+        );
+        let send = new_tx(
+            vec![spend(OutPoint::new(receive.txid(), 0))],
+            vec![foreign.clone(), txout(29_000, &script_0)],
+        );
+        let self_transfer = new_tx(
+            vec![spend(OutPoint::new(send.txid(), 1))],
+            vec![txout(28_000, &script_1)],
+        );
+        let consolidation = new_tx(
+            vec![
+                spend(OutPoint::new(coinbase.txid(), 0)),
+                spend(OutPoint::new(self_transfer.txid(), 0)),
+            ],
+            vec![txout(77_000, &script_0)],
+        );
+
+        let txs = vec![
+            (vec![None], coinbase.clone(), TxType::CoinbaseReward),
+            (vec![Some(foreign)], receive.clone(), TxType::Receive),
+            (
+                vec![Some(receive.output[0].clone())],
+                send.clone(),
+                TxType::Send,
+            ),
+            (
+                vec![Some(send.output[1].clone())],
+                self_transfer.clone(),
+                TxType::SelfTransfer,
+            ),
+            (
+                vec![
+                    Some(coinbase.output[0].clone()),
+                    Some(self_transfer.output[0].clone()),
+                ],
+                consolidation,
+                TxType::Consolidation,
+            ),
+        ];
+        let by_txid = txs
+            .iter()
+            .map(|(prevouts, tx, _)| (tx.txid(), (prevouts.clone(), tx.clone())))
+            .collect::<HashMap<_, _>>();
+
+        let mut request = start(&db, 20).unwrap();
+        let batch = loop {
+            request = match request {
+                Request::Script(script_req) => {
+                    let txids = script_req
+                        .request()
+                        .map(|script| {
+                            by_txid
+                                .values()
+                                .filter(|(prevouts, tx)| {
+                                    tx.output
+                                        .iter()
+                                        .chain(prevouts.iter().flatten())
+                                        .any(|out| &out.script_pubkey == script)
+                                })
+                                .map(|(_, tx)| (tx.txid(), None))
+                                .collect()
+                        })
+                        .collect();
+                    script_req.satisfy(txids).unwrap()
+                }
+                Request::Tx(tx_req) => {
+                    let full_txs = tx_req.request().map(|txid| by_txid[txid].clone()).collect();
+                    tx_req.satisfy(full_txs).unwrap()
+                }
+                Request::Conftime(conftime_req) => {
+                    let conftimes = conftime_req.request().map(|_| None).collect();
+                    conftime_req.satisfy(conftimes).unwrap()
+                }
+                Request::Finish(batch) => break batch,
+            }
+        };
+        db.commit_batch(batch).unwrap();
+
+        for (_, tx, expected) in txs {
+            let details = db.get_tx(&tx.txid(), false).unwrap().unwrap();
+            assert_eq!(
+                details.tx_type,
+                Some(expected),
+                "wrong type for {}",
+                tx.txid()
+            );
+        }
+    }
+
+    #[test]
+    fn test_ownership_resolver_claims_unknown_script() {
+        let mut db = get_test_db();
+        let script_0 = db
+            .get_script_pubkey_from_path(KeychainKind::External, 0)
+            .unwrap()
+            .unwrap();
+        let unknown = Script::from(vec![0xaa; 22]);
+        let tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn::default()],
+            output: vec![
+                TxOut {
+                    value: 10_000,
+                    script_pubkey: script_0.clone(),
+                },
+                TxOut {
+                    value: 20_000,
+                    script_pubkey: unknown.clone(),
+                },
+            ],
+        };
+        let txid = tx.txid();
+
+        let resolver = {
+            let unknown = unknown.clone();
+            OwnershipResolver::new(move |script: &Script| {
+                if script == &unknown {
+                    Some(KeychainKind::External)
+                } else {
+                    None
+                }
+            })
+        };
+        let mut request = start_with_ownership_resolver(&db, 20, Some(resolver)).unwrap();
+        let batch = loop {
+            request = match request {
+                Request::Script(script_req) => {
+                    let txids = script_req
+                        .request()
+                        .map(|s| {
+                            if s == &script_0 {
+                                vec![(txid, None)]
+                            } else {
+                                vec![]
+                            }
+                        })
+                        .collect();
+                    script_req.satisfy(txids).unwrap()
+                }
+                Request::Tx(tx_req) => tx_req.satisfy(vec![(vec![], tx.clone())]).unwrap(),
+                Request::Conftime(conftime_req) => {
+                    let conftimes = conftime_req.request().map(|_| None).collect();
+                    conftime_req.satisfy(conftimes).unwrap()
+                }
+                Request::Finish(batch) => break batch,
+            }
+        };
+        db.commit_batch(batch).unwrap();
+
+        let utxo = db.get_utxo(&OutPoint { txid, vout: 1 }).unwrap().unwrap();
+        assert_eq!(utxo.txout.script_pubkey, unknown);
+        assert_eq!(utxo.keychain, KeychainKind::External);
+        let details = db.get_tx(&txid, false).unwrap().unwrap();
+        assert_eq!(details.received, 30_000);
+    }
+
+    #[test]
+    fn test_stop_gap_per_keychain() {
+        let mut db = MemoryDatabase::new();
+        for (prefix, keychain) in [(0u8, KeychainKind::External), (1, KeychainKind::Internal)] {
+            for i in 0..30u8 {
+                db.set_script_pubkey(&Script::from(vec![prefix, i]), keychain, i.into())
+                    .unwrap();
+            }
+        }
+        let receive = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn::default()],
+            output: vec![
+                TxOut {
+                    value: 50_000,
+                    script_pubkey: Script::from(vec![0, 10]),
+                },
+                TxOut {
+                    value: 10_000,
+                    script_pubkey: Script::from(vec![1, 1]),
+                },
+            ],
+        };
+
+        // fetch the history of one script pubkey at a time, counting them for each keychain
+        let sync = |stop_gap: StopGap| {
+            let mut scanned = HashMap::new();
+            let mut request = start(&db, stop_gap).unwrap();
+            let batch = loop {
+                request = match request {
+                    Request::Script(script_req) => {
+                        let script = script_req.request().next().unwrap().clone();
+                        *scanned.entry(script_req.keychain).or_insert(0) += 1;
+                        let txids = if receive.output.iter().any(|o| o.script_pubkey == script) {
+                            vec![(receive.txid(), None)]
+                        } else {
+                            vec![]
+                        };
+                        script_req.satisfy(vec![txids]).unwrap()
+                    }
+                    Request::Tx(tx_req) => {
+                        let txs = tx_req
+                            .request()
+                            .map(|_| (vec![], receive.clone()))
+                            .collect();
+                        tx_req.satisfy(txs).unwrap()
+                    }
+                    Request::Conftime(conftime_req) => {
+                        let conftimes = conftime_req.request().map(|_| None).collect();
+                        conftime_req.satisfy(conftimes).unwrap()
+                    }
+                    Request::Finish(batch) => break batch,
+                }
+            };
+            (scanned, batch)
+        };
+
+        // with a small gap on both keychains the external output is never found
+        let (scanned, _) = sync(2.into());
+        assert_eq!(scanned[&KeychainKind::External], 3);
+        assert_eq!(scanned[&KeychainKind::Internal], 5);
+
+        let (scanned, batch) = sync(StopGap::new(15, Some(2)));
+        assert_eq!(scanned[&KeychainKind::External], 11 + 15 + 1);
+        assert_eq!(scanned[&KeychainKind::Internal], 2 + 2 + 1);
+        db.commit_batch(batch).unwrap();
+        assert_eq!(
+            db.get_tx(&receive.txid(), false).unwrap().unwrap().received,
+            60_000
+        );
+    }
+
+    #[test]
+    fn test_plan() {
+        let mut db = MemoryDatabase::new();
+        for (prefix, keychain) in [(0u8, KeychainKind::External), (1, KeychainKind::Internal)] {
+            for i in 0..30u8 {
+                db.set_script_pubkey(&Script::from(vec![prefix, i]), keychain, i.into())
+                    .unwrap();
+            }
+        }
+        let receive = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output: vec![
+                TxOut {
+                    value: 50_000,
+                    script_pubkey: Script::from(vec![0, 10]),
+                },
+                TxOut {
+                    value: 10_000,
+                    script_pubkey: Script::from(vec![1, 1]),
+                },
+            ],
+        };
+        db.set_tx(&TransactionDetails {
+            txid: receive.txid(),
+            transaction: Some(receive),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let plan = plan(&db, StopGap::new(15, Some(2)), 1).unwrap();
+        assert_eq!(plan.stop_gap, 15);
+        assert_eq!(plan.internal_stop_gap, 2);
+
+        // like a sync, the scan goes one script pubkey past the stop gap after the last used one
+        for (keychain, prefix, expected) in [
+            (KeychainKind::External, 0u8, 10 + 1 + 15 + 1),
+            (KeychainKind::Internal, 1, 1 + 1 + 2 + 1),
+        ] {
+            let scripts = plan
+                .scripts
+                .iter()
+                .filter(|(k, _, _)| *k == keychain)
+                .collect::<Vec<_>>();
+            assert_eq!(scripts.len(), expected);
+            for (index, (_, child, script)) in scripts.into_iter().enumerate() {
+                assert_eq!(*child, index as u32);
+                assert_eq!(script, &Script::from(vec![prefix, index as u8]));
+            }
+        }
+        // the keychains are scanned one after the other
+        assert!(
+            plan.scripts
+                .windows(2)
+                .filter(|pair| pair[0].0 != pair[1].0)
+                .count()
+                <= 1
+        );
+
+        // the stop gap is only checked after each batch
+        let plan = super::plan(&db, StopGap::new(15, Some(2)), 10).unwrap();
+        assert_eq!(plan.scripts.len(), 30 + 10);
+    }
+
+    #[test]
+    fn test_sync_progress_monotonic() {
+        let mut db = MemoryDatabase::new();
+        for (prefix, keychain) in [(0u8, KeychainKind::External), (1, KeychainKind::Internal)] {
+            for i in 0..30u8 {
+                db.set_script_pubkey(&Script::from(vec![prefix, i]), keychain, i.into())
+                    .unwrap();
+            }
+        }
+        // an active script pubkey past the initial estimate makes it grow
+        let active = [Script::from(vec![0, 4]), Script::from(vec![1, 4])];
+
+        let mut updates = vec![];
+        let mut request = start(&db, 5).unwrap();
+        updates.extend(request.sync_progress());
+        loop {
+            request = match request {
+                Request::Script(script_req) => {
+                    let txids = script_req
+                        .request()
+                        .take(2)
+                        .map(|script| {
+                            if active.contains(script) {
+                                vec![(Txid::from_slice(&[script.as_bytes()[1]; 32]).unwrap(), None)]
+                            } else {
+                                vec![]
+                            }
+                        })
+                        .collect();
+                    script_req.satisfy(txids).unwrap()
+                }
+                // the transactions aren't relevant to the progress
+                Request::Tx(_) => break,
+                _ => unreachable!(),
+            };
+            updates.extend(request.sync_progress());
+        }
+
+        assert!(updates.windows(2).all(|w| {
+            w[0].scripts_scanned <= w[1].scripts_scanned
+                && w[0].scripts_total_estimate <= w[1].scripts_total_estimate
+        }));
+        assert!(updates
+            .iter()
+            .all(|u| u.scripts_scanned <= u.scripts_total_estimate));
+
+        let first = updates.first().unwrap();
+        assert_eq!(first.scripts_scanned, 0);
+        assert_eq!(first.scripts_total_estimate, 6 + 6);
+        assert_eq!(first.eta(), None);
+
+        // 4 + 1 + 5 script pubkeys on each keychain, rounded up to the chunks of 2
+        let last = updates.last().unwrap();
+        assert_eq!(last.scripts_scanned, 2 * 12);
+        assert_eq!(last.scripts_total_estimate, 2 * 12);
+        assert_eq!(last.percentage(), 100.0);
+        assert!(updates
+            .iter()
+            .any(|u| u.current_keychain != last.current_keychain));
+    }
+}
@@ -148,6 +148,15 @@ impl BatchOperations for AnyDatabase {
     fn set_sync_time(&mut self, sync_time: SyncTime) -> Result<(), Error> {
         impl_inner_method!(AnyDatabase, self, set_sync_time, sync_time)
     }
+    fn set_pending_tx(&mut self, transaction: &Transaction) -> Result<(), Error> {
+        impl_inner_method!(AnyDatabase, self, set_pending_tx, transaction)
+    }
+    fn set_block_hash(&mut self, height: u32, hash: &BlockHash) -> Result<(), Error> {
+        impl_inner_method!(AnyDatabase, self, set_block_hash, height, hash)
+    }
+    fn set_schema_version(&mut self, version: u32) -> Result<(), Error> {
+        impl_inner_method!(AnyDatabase, self, set_schema_version, version)
+    }
 
     fn del_script_pubkey_from_path(
         &mut self,
@@ -187,6 +196,12 @@ impl BatchOperations for AnyDatabase {
     fn del_sync_time(&mut self) -> Result<Option<SyncTime>, Error> {
         impl_inner_method!(AnyDatabase, self, del_sync_time)
     }
+    fn del_pending_tx(&mut self, txid: &Txid) -> Result<Option<Transaction>, Error> {
+        impl_inner_method!(AnyDatabase, self, del_pending_tx, txid)
+    }
+    fn del_block_hash(&mut self, height: u32) -> Result<Option<BlockHash>, Error> {
+        impl_inner_method!(AnyDatabase, self, del_block_hash, height)
+    }
 }
 
 impl Database for AnyDatabase {
@@ -216,6 +231,15 @@ impl Database for AnyDatabase {
     fn iter_txs(&self, include_raw: bool) -> Result<Vec<TransactionDetails>, Error> {
         impl_inner_method!(AnyDatabase, self, iter_txs, include_raw)
     }
+    fn iter_txs_sorted(&self, include_raw: bool) -> Result<Vec<TransactionDetails>, Error> {
+        impl_inner_method!(AnyDatabase, self, iter_txs_sorted, include_raw)
+    }
+    fn iter_pending_txs(&self) -> Result<Vec<Transaction>, Error> {
+        impl_inner_method!(AnyDatabase, self, iter_pending_txs)
+    }
+    fn iter_block_hashes(&self) -> Result<Vec<(u32, BlockHash)>, Error> {
+        impl_inner_method!(AnyDatabase, self, iter_block_hashes)
+    }
 
     fn get_script_pubkey_from_path(
         &self,
@@ -251,6 +275,9 @@ impl Database for AnyDatabase {
     fn get_sync_time(&self) -> Result<Option<SyncTime>, Error> {
         impl_inner_method!(AnyDatabase, self, get_sync_time)
     }
+    fn get_schema_version(&self) -> Result<Option<u32>, Error> {
+        impl_inner_method!(AnyDatabase, self, get_schema_version)
+    }
 
     fn increment_last_index(&mut self, keychain: KeychainKind) -> Result<u32, Error> {
         impl_inner_method!(AnyDatabase, self, increment_last_index, keychain)
@@ -285,6 +312,15 @@ impl BatchOperations for AnyBatch {
     fn set_sync_time(&mut self, sync_time: SyncTime) -> Result<(), Error> {
         impl_inner_method!(AnyBatch, self, set_sync_time, sync_time)
     }
+    fn set_pending_tx(&mut self, transaction: &Transaction) -> Result<(), Error> {
+        impl_inner_method!(AnyBatch, self, set_pending_tx, transaction)
+    }
+    fn set_block_hash(&mut self, height: u32, hash: &BlockHash) -> Result<(), Error> {
+        impl_inner_method!(AnyBatch, self, set_block_hash, height, hash)
+    }
+    fn set_schema_version(&mut self, version: u32) -> Result<(), Error> {
+        impl_inner_method!(AnyBatch, self, set_schema_version, version)
+    }
 
     fn del_script_pubkey_from_path(
         &mut self,
@@ -318,6 +354,12 @@ impl BatchOperations for AnyBatch {
     fn del_sync_time(&mut self) -> Result<Option<SyncTime>, Error> {
         impl_inner_method!(AnyBatch, self, del_sync_time)
     }
+    fn del_pending_tx(&mut self, txid: &Txid) -> Result<Option<Transaction>, Error> {
+        impl_inner_method!(AnyBatch, self, del_pending_tx, txid)
+    }
+    fn del_block_hash(&mut self, height: u32) -> Result<Option<BlockHash>, Error> {
+        impl_inner_method!(AnyBatch, self, del_block_hash, height)
+    }
 }
 
 impl BatchDatabase for AnyDatabase {
@@ -0,0 +1,761 @@
+// Bitcoin Dev Kit
+//
+// Copyright (c) 2020-2022 Bitcoin Dev Kit Developers
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+use std::convert::TryInto;
+use std::fmt;
+
+use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::hash_types::{BlockHash, Txid};
+use bitcoin::hashes::{hmac, sha256, Hash, HashEngine};
+use bitcoin::{OutPoint, Script, Transaction, TxOut};
+
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+use crate::database::memory::MapKey;
+use crate::database::{BatchDatabase, BatchOperations, Database, SyncTime};
+use crate::error::Error;
+use crate::types::*;
+
+const NONCE_LEN: usize = 12;
+/// Decrypted key and value of a record
+type Entry = (Vec<u8>, Vec<u8>);
+/// Key of the record used to check the key given to [`EncryptedDatabase::new`]
+const KEY_CHECK: &[u8] = b"bdk/encrypted-database/key-check";
+
+/// Database wrapper that encrypts the data stored in an inner [`Database`]
+///
+/// Every entry is encrypted with ChaCha20-Poly1305, using a random nonce that is stored alongside
+/// the ciphertext. The keys used to look entries up (script pubkeys, outpoints, txids, ...) are
+/// replaced by their HMAC, so that the inner database only ever sees opaque records.
+///
+/// Both the encryption and the lookup keys are derived from the 32-byte key given to
+/// [`EncryptedDatabase::new`], which fails with [`Error::Decryption`] if the inner database
+/// contains data encrypted with a different key, or data that wasn't written by an
+/// `EncryptedDatabase` at all.
+///
+/// ## Storage layout and cost
+///
+/// [`Database`] has no generic key-value interface, so the records are kept in the table of
+/// [`LocalUtxo`]s of the inner database: the outpoint holds the HMAC of the key and the kind of
+/// entry (script pubkey, transaction, ...), the script pubkey of the output holds the ciphertext.
+/// The records are marked as spent, but the inner database must not be used directly: its
+/// "UTXOs" are meaningless outside of this wrapper.
+///
+/// Looking an entry up by its key costs a single read of the inner database. Listing entries
+/// (e.g. [`Database::iter_txs`]) reads every record of the inner database and decrypts the ones
+/// of the requested kind, so it gets slower as the whole wallet grows.
+///
+/// ## Example
+///
+/// ```
+/// # use bdk::database::{Database, EncryptedDatabase, MemoryDatabase};
+/// # use bdk::KeychainKind;
+/// let mut database = EncryptedDatabase::new(MemoryDatabase::new(), [42; 32])?;
+/// database.increment_last_index(KeychainKind::External)?;
+///
+/// let inner = database.into_inner();
+/// assert!(EncryptedDatabase::new(inner, [21; 32]).is_err());
+/// # Ok::<(), bdk::Error>(())
+/// ```
+pub struct EncryptedDatabase<D> {
+    inner: D,
+    cipher_key: [u8; 32],
+    lookup_key: [u8; 32],
+}
+
+impl<D: fmt::Debug> fmt::Debug for EncryptedDatabase<D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptedDatabase")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut engine = hmac::HmacEngine::<sha256::Hash>::new(key);
+    engine.input(data);
+    hmac::Hmac::<sha256::Hash>::from_engine(engine).into_inner()
+}
+
+fn key_check_outpoint() -> OutPoint {
+    OutPoint {
+        txid: Txid::default(),
+        vout: 0,
+    }
+}
+
+fn parse_u32(bytes: Vec<u8>) -> Result<u32, Error> {
+    let array: [u8; 4] = bytes
+        .as_slice()
+        .try_into()
+        .map_err(|_| Error::InvalidU32Bytes(bytes.clone()))?;
+    Ok(u32::from_be_bytes(array))
+}
+
+impl<D: Database> EncryptedDatabase<D> {
+    /// Wrap `inner`, encrypting everything stored in it with `key`
+    ///
+    /// Returns [`Error::Decryption`] if `inner` already contains data encrypted with a different
+    /// key.
+    pub fn new(inner: D, key: [u8; 32]) -> Result<Self, Error> {
+        let mut database = EncryptedDatabase {
+            inner,
+            cipher_key: hmac_sha256(&key, b"bdk/encrypted-database/cipher"),
+            lookup_key: hmac_sha256(&key, b"bdk/encrypted-database/lookup"),
+        };
+        // the key check record lives at a fixed outpoint, so that it can be found without the key
+        match database.inner.get_utxo(&key_check_outpoint())? {
+            Some(record) => match database.open(&record)? {
+                (check, _) if check == KEY_CHECK => {}
+                _ => return Err(Error::Decryption),
+            },
+            None if database.inner.iter_utxos()?.is_empty() => {
+                let record = database.seal(key_check_outpoint(), KEY_CHECK, &[])?;
+                database.inner.set_utxo(&record)?;
+            }
+            None => return Err(Error::Decryption),
+        }
+
+        Ok(database)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.inner
+            .get_utxo(&self.record_outpoint(key))?
+            .map(|record| self.open(&record).map(|(_, value)| value))
+            .transpose()
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<Entry>, Error> {
+        let mut entries = self
+            .inner
+            .iter_utxos()?
+            .iter()
+            // only decrypt the records of the right kind
+            .filter(|record| Some(record.outpoint.vout) == prefix.first().map(|b| *b as u32))
+            .map(|record| self.open(record))
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .map_or(true, |(key, _)| key.starts_with(prefix))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        // keep the order of the other databases, sorted by key
+        entries.sort();
+
+        Ok(entries)
+    }
+}
+
+impl<D> EncryptedDatabase<D> {
+    /// Return the inner database, which only contains encrypted data
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(&Key::from(self.cipher_key))
+    }
+
+    /// Outpoint of the record storing `key` in the inner database
+    ///
+    /// The `vout` is the first byte of the key, which tells the kind of entry stored in the record.
+    fn record_outpoint(&self, key: &[u8]) -> OutPoint {
+        OutPoint {
+            txid: Txid::from_inner(hmac_sha256(&self.lookup_key, key)),
+            vout: key.first().map_or(0, |b| *b as u32),
+        }
+    }
+
+    /// Encrypt a key-value pair into a record for the inner database, stored at `outpoint`
+    fn seal(&self, outpoint: OutPoint, key: &[u8], value: &[u8]) -> Result<LocalUtxo, Error> {
+        let mut plaintext = (key.len() as u32).to_be_bytes().to_vec();
+        plaintext.extend_from_slice(key);
+        plaintext.extend_from_slice(value);
+
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let ciphertext = self
+            .cipher()
+            .encrypt(
+                &Nonce::from(nonce),
+                Payload {
+                    msg: &plaintext,
+                    aad: outpoint.txid.as_ref(),
+                },
+            )
+            .map_err(|_| Error::Generic("Failed to encrypt a database entry".into()))?;
+
+        let mut data = nonce.to_vec();
+        data.extend(ciphertext);
+
+        Ok(LocalUtxo {
+            outpoint,
+            txout: TxOut {
+                value: 0,
+                script_pubkey: Script::from(data),
+            },
+            keychain: KeychainKind::External,
+            is_spent: true,
+            derivation_index: None,
+            suspected_dust: false,
+        })
+    }
+
+    /// Decrypt a record of the inner database into its key-value pair
+    fn open(&self, record: &LocalUtxo) -> Result<Entry, Error> {
+        let data = record.txout.script_pubkey.as_bytes();
+        if data.len() < NONCE_LEN {
+            return Err(Error::Decryption);
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let nonce: [u8; NONCE_LEN] = nonce.try_into().expect("NONCE_LEN bytes");
+
+        let mut plaintext = self
+            .cipher()
+            .decrypt(
+                &Nonce::from(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: record.outpoint.txid.as_ref(),
+                },
+            )
+            .map_err(|_| Error::Decryption)?;
+
+        let key_len = plaintext
+            .get(..4)
+            .map(|len| u32::from_be_bytes(len.try_into().expect("4 bytes")) as usize)
+            .filter(|key_len| 4 + key_len <= plaintext.len())
+            .ok_or(Error::Decryption)?;
+        let value = plaintext.split_off(4 + key_len);
+        let key = plaintext.split_off(4);
+
+        Ok((key, value))
+    }
+}
+
+impl<D: BatchOperations> EncryptedDatabase<D> {
+    fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        let record = self.seal(self.record_outpoint(key), key, value)?;
+        self.inner.set_utxo(&record)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let outpoint = self.record_outpoint(key);
+        self.inner
+            .del_utxo(&outpoint)?
+            .map(|record| self.open(&record).map(|(_, value)| value))
+            .transpose()
+    }
+}
+
+impl<D: BatchOperations> BatchOperations for EncryptedDatabase<D> {
+    fn set_script_pubkey(
+        &mut self,
+        script: &Script,
+        keychain: KeychainKind,
+        path: u32,
+    ) -> Result<(), Error> {
+        let key = MapKey::Path((Some(keychain), Some(path))).as_map_key();
+        self.insert(&key, &serialize(script))?;
+
+        let key = MapKey::Script(Some(script)).as_map_key();
+        let value = json!({
+            "t": keychain,
+            "p": path,
+        });
+        self.insert(&key, &serde_json::to_vec(&value)?)
+    }
+
+    fn set_utxo(&mut self, utxo: &LocalUtxo) -> Result<(), Error> {
+        let key = MapKey::Utxo(Some(&utxo.outpoint)).as_map_key();
+        self.insert(&key, &serde_json::to_vec(utxo)?)
+    }
+
+    fn set_raw_tx(&mut self, transaction: &Transaction) -> Result<(), Error> {
+        let key = MapKey::RawTx(Some(&transaction.txid())).as_map_key();
+        self.insert(&key, &serialize(transaction))
+    }
+
+    fn set_tx(&mut self, transaction: &TransactionDetails) -> Result<(), Error> {
+        let key = MapKey::Transaction(Some(&transaction.txid)).as_map_key();
+
+        // remove the raw tx from the serialized version
+        let mut value = serde_json::to_value(transaction)?;
+        value["transaction"] = serde_json::Value::Null;
+        self.insert(&key, &serde_json::to_vec(&value)?)?;
+
+        // insert the raw_tx if present
+        if let Some(ref tx) = transaction.transaction {
+            self.set_raw_tx(tx)?;
+        }
+
+        Ok(())
+    }
+
+    fn set_last_index(&mut self, keychain: KeychainKind, value: u32) -> Result<(), Error> {
+        let key = MapKey::LastIndex(keychain).as_map_key();
+        self.insert(&key, &value.to_be_bytes())
+    }
+
+    fn set_sync_time(&mut self, data: SyncTime) -> Result<(), Error> {
+        let key = MapKey::SyncTime.as_map_key();
+        self.insert(&key, &serde_json::to_vec(&data)?)
+    }
+
+    fn set_pending_tx(&mut self, transaction: &Transaction) -> Result<(), Error> {
+        let key = MapKey::PendingTx(Some(&transaction.txid())).as_map_key();
+        self.insert(&key, &serialize(transaction))
+    }
+
+    fn set_schema_version(&mut self, version: u32) -> Result<(), Error> {
+        let key = MapKey::SchemaVersion.as_map_key();
+        self.insert(&key, &version.to_be_bytes())
+    }
+
+    fn set_block_hash(&mut self, height: u32, hash: &BlockHash) -> Result<(), Error> {
+        let key = MapKey::BlockHash(Some(height)).as_map_key();
+        self.insert(&key, &serialize(hash))
+    }
+
+    fn del_script_pubkey_from_path(
+        &mut self,
+        keychain: KeychainKind,
+        path: u32,
+    ) -> Result<Option<Script>, Error> {
+        let key = MapKey::Path((Some(keychain), Some(path))).as_map_key();
+        Ok(self.remove(&key)?.map(|b| deserialize(&b)).transpose()?)
+    }
+
+    fn del_path_from_script_pubkey(
+        &mut self,
+        script: &Script,
+    ) -> Result<Option<(KeychainKind, u32)>, Error> {
+        let key = MapKey::Script(Some(script)).as_map_key();
+        self.remove(&key)?
+            .map(|b| -> Result<_, Error> {
+                let mut val: serde_json::Value = serde_json::from_slice(&b)?;
+                let st = serde_json::from_value(val["t"].take())?;
+                let path = serde_json::from_value(val["p"].take())?;
+
+                Ok((st, path))
+            })
+            .transpose()
+    }
+
+    fn del_utxo(&mut self, outpoint: &OutPoint) -> Result<Option<LocalUtxo>, Error> {
+        let key = MapKey::Utxo(Some(outpoint)).as_map_key();
+        Ok(self
+            .remove(&key)?
+            .map(|b| serde_json::from_slice(&b))
+            .transpose()?)
+    }
+
+    fn del_raw_tx(&mut self, txid: &Txid) -> Result<Option<Transaction>, Error> {
+        let key = MapKey::RawTx(Some(txid)).as_map_key();
+        Ok(self.remove(&key)?.map(|b| deserialize(&b)).transpose()?)
+    }
+
+    fn del_tx(
+        &mut self,
+        txid: &Txid,
+        include_raw: bool,
+    ) -> Result<Option<TransactionDetails>, Error> {
+        let raw_tx = if include_raw {
+            self.del_raw_tx(txid)?
+        } else {
+            None
+        };
+
+        let key = MapKey::Transaction(Some(txid)).as_map_key();
+        self.remove(&key)?
+            .map(|b| -> Result<_, Error> {
+                let mut val: TransactionDetails = serde_json::from_slice(&b)?;
+                val.transaction = raw_tx;
+
+                Ok(val)
+            })
+            .transpose()
+    }
+
+    fn del_last_index(&mut self, keychain: KeychainKind) -> Result<Option<u32>, Error> {
+        let key = MapKey::LastIndex(keychain).as_map_key();
+        self.remove(&key)?.map(parse_u32).transpose()
+    }
+
+    fn del_sync_time(&mut self) -> Result<Option<SyncTime>, Error> {
+        let key = MapKey::SyncTime.as_map_key();
+        Ok(self
+            .remove(&key)?
+            .map(|b| serde_json::from_slice(&b))
+            .transpose()?)
+    }
+
+    fn del_pending_tx(&mut self, txid: &Txid) -> Result<Option<Transaction>, Error> {
+        let key = MapKey::PendingTx(Some(txid)).as_map_key();
+        Ok(self.remove(&key)?.map(|b| deserialize(&b)).transpose()?)
+    }
+
+    fn del_block_hash(&mut self, height: u32) -> Result<Option<BlockHash>, Error> {
+        let key = MapKey::BlockHash(Some(height)).as_map_key();
+        Ok(self.remove(&key)?.map(|b| deserialize(&b)).transpose()?)
+    }
+}
+
+impl<D: Database> Database for EncryptedDatabase<D> {
+    fn check_descriptor_checksum<B: AsRef<[u8]>>(
+        &mut self,
+        keychain: KeychainKind,
+        bytes: B,
+    ) -> Result<(), Error> {
+        let key = MapKey::DescriptorChecksum(keychain).as_map_key();
+
+        match self.get(&key)? {
+            Some(val) if val == bytes.as_ref() => Ok(()),
+            Some(_) => Err(Error::ChecksumMismatch),
+            None => self.insert(&key, bytes.as_ref()),
+        }
+    }
+
+    fn iter_script_pubkeys(&self, keychain: Option<KeychainKind>) -> Result<Vec<Script>, Error> {
+        let key = MapKey::Path((keychain, None)).as_map_key();
+        self.scan_prefix(&key)?
+            .into_iter()
+            .map(|(_, v)| -> Result<_, Error> { Ok(deserialize(&v)?) })
+            .collect()
+    }
+
+    fn iter_utxos(&self) -> Result<Vec<LocalUtxo>, Error> {
+        let key = MapKey::Utxo(None).as_map_key();
+        self.scan_prefix(&key)?
+            .into_iter()
+            .map(|(_, v)| -> Result<_, Error> { Ok(serde_json::from_slice(&v)?) })
+            .collect()
+    }
+
+    fn iter_raw_txs(&self) -> Result<Vec<Transaction>, Error> {
+        let key = MapKey::RawTx(None).as_map_key();
+        self.scan_prefix(&key)?
+            .into_iter()
+            .map(|(_, v)| -> Result<_, Error> { Ok(deserialize(&v)?) })
+            .collect()
+    }
+
+    fn iter_txs(&self, include_raw: bool) -> Result<Vec<TransactionDetails>, Error> {
+        let key = MapKey::Transaction(None).as_map_key();
+        self.scan_prefix(&key)?
+            .into_iter()
+            .map(|(k, v)| -> Result<_, Error> {
+                let mut txdetails: TransactionDetails = serde_json::from_slice(&v)?;
+                if include_raw {
+                    let txid = deserialize(&k[1..])?;
+                    txdetails.transaction = self.get_raw_tx(&txid)?;
+                }
+
+                Ok(txdetails)
+            })
+            .collect()
+    }
+
+    fn iter_pending_txs(&self) -> Result<Vec<Transaction>, Error> {
+        let key = MapKey::PendingTx(None).as_map_key();
+        self.scan_prefix(&key)?
+            .into_iter()
+            .map(|(_, v)| -> Result<_, Error> { Ok(deserialize(&v)?) })
+            .collect()
+    }
+
+    fn iter_block_hashes(&self) -> Result<Vec<(u32, BlockHash)>, Error> {
+        let key = MapKey::BlockHash(None).as_map_key();
+        self.scan_prefix(&key)?
+            .into_iter()
+            .map(|(k, v)| -> Result<_, Error> {
+                let height: [u8; 4] = k[1..]
+                    .try_into()
+                    .map_err(|_| Error::InvalidU32Bytes(k[1..].to_vec()))?;
+                Ok((u32::from_be_bytes(height), deserialize(&v)?))
+            })
+            .collect()
+    }
+
+    fn get_script_pubkey_from_path(
+        &self,
+        keychain: KeychainKind,
+        path: u32,
+    ) -> Result<Option<Script>, Error> {
+        let key = MapKey::Path((Some(keychain), Some(path))).as_map_key();
+        Ok(self.get(&key)?.map(|b| deserialize(&b)).transpose()?)
+    }
+
+    fn get_path_from_script_pubkey(
+        &self,
+        script: &Script,
+    ) -> Result<Option<(KeychainKind, u32)>, Error> {
+        let key = MapKey::Script(Some(script)).as_map_key();
+        self.get(&key)?
+            .map(|b| -> Result<_, Error> {
+                let mut val: serde_json::Value = serde_json::from_slice(&b)?;
+                let st = serde_json::from_value(val["t"].take())?;
+                let path = serde_json::from_value(val["p"].take())?;
+
+                Ok((st, path))
+            })
+            .transpose()
+    }
+
+    fn get_utxo(&self, outpoint: &OutPoint) -> Result<Option<LocalUtxo>, Error> {
+        let key = MapKey::Utxo(Some(outpoint)).as_map_key();
+        Ok(self
+            .get(&key)?
+            .map(|b| serde_json::from_slice(&b))
+            .transpose()?)
+    }
+
+    fn get_raw_tx(&self, txid: &Txid) -> Result<Option<Transaction>, Error> {
+        let key = MapKey::RawTx(Some(txid)).as_map_key();
+        Ok(self.get(&key)?.map(|b| deserialize(&b)).transpose()?)
+    }
+
+    fn get_tx(&self, txid: &Txid, include_raw: bool) -> Result<Option<TransactionDetails>, Error> {
+        let key = MapKey::Transaction(Some(txid)).as_map_key();
+        self.get(&key)?
+            .map(|b| -> Result<_, Error> {
+                let mut txdetails: TransactionDetails = serde_json::from_slice(&b)?;
+                if include_raw {
+                    txdetails.transaction = self.get_raw_tx(txid)?;
+                }
+
+                Ok(txdetails)
+            })
+            .transpose()
+    }
+
+    fn get_last_index(&self, keychain: KeychainKind) -> Result<Option<u32>, Error> {
+        let key = MapKey::LastIndex(keychain).as_map_key();
+        self.get(&key)?.map(parse_u32).transpose()
+    }
+
+    fn get_sync_time(&self) -> Result<Option<SyncTime>, Error> {
+        let key = MapKey::SyncTime.as_map_key();
+        Ok(self
+            .get(&key)?
+            .map(|b| serde_json::from_slice(&b))
+            .transpose()?)
+    }
+
+    fn get_schema_version(&self) -> Result<Option<u32>, Error> {
+        let key = MapKey::SchemaVersion.as_map_key();
+        self.get(&key)?.map(parse_u32).transpose()
+    }
+
+    // inserts 0 if not present
+    fn increment_last_index(&mut self, keychain: KeychainKind) -> Result<u32, Error> {
+        let value = match self.get_last_index(keychain)? {
+            Some(value) => value + 1,
+            None => 0,
+        };
+        self.set_last_index(keychain, value)?;
+
+        Ok(value)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        #[allow(deprecated)]
+        self.inner.flush()
+    }
+}
+
+impl<D: BatchDatabase> BatchDatabase for EncryptedDatabase<D> {
+    type Batch = EncryptedDatabase<D::Batch>;
+
+    fn begin_batch(&self) -> Self::Batch {
+        EncryptedDatabase {
+            inner: self.inner.begin_batch(),
+            cipher_key: self.cipher_key,
+            lookup_key: self.lookup_key,
+        }
+    }
+
+    fn commit_batch(&mut self, batch: Self::Batch) -> Result<(), Error> {
+        self.inner.commit_batch(batch.inner)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use bitcoin::{Address, Network};
+
+    use super::EncryptedDatabase;
+    use crate::database::{BatchOperations, Database, MemoryDatabase};
+    use crate::error::Error;
+    use crate::{KeychainKind, Wallet};
+
+    fn get_tree() -> EncryptedDatabase<MemoryDatabase> {
+        EncryptedDatabase::new(MemoryDatabase::new(), [42; 32]).unwrap()
+    }
+
+    #[test]
+    fn test_script_pubkey() {
+        crate::database::test::test_script_pubkey(get_tree());
+    }
+
+    #[test]
+    fn test_batch_script_pubkey() {
+        crate::database::test::test_batch_script_pubkey(get_tree());
+    }
+
+    #[test]
+    fn test_iter_script_pubkey() {
+        crate::database::test::test_iter_script_pubkey(get_tree());
+    }
+
+    #[test]
+    fn test_del_script_pubkey() {
+        crate::database::test::test_del_script_pubkey(get_tree());
+    }
+
+    #[test]
+    fn test_utxo() {
+        crate::database::test::test_utxo(get_tree());
+    }
+
+    #[test]
+    fn test_raw_tx() {
+        crate::database::test::test_raw_tx(get_tree());
+    }
+
+    #[test]
+    fn test_tx() {
+        crate::database::test::test_tx(get_tree());
+    }
+
+    #[test]
+    fn test_list_transaction() {
+        crate::database::test::test_list_transaction(get_tree());
+    }
+
+    #[test]
+    fn test_last_index() {
+        crate::database::test::test_last_index(get_tree());
+    }
+
+    #[test]
+    fn test_sync_time() {
+        crate::database::test::test_sync_time(get_tree());
+    }
+
+    #[test]
+    fn test_pending_tx() {
+        crate::database::test::test_pending_tx(get_tree());
+    }
+
+    #[test]
+    fn test_block_hashes() {
+        crate::database::test::test_block_hashes(get_tree());
+    }
+
+    #[test]
+    fn test_migrate() {
+        crate::database::test::test_migrate(get_tree());
+    }
+
+    #[test]
+    fn test_inner_is_opaque() {
+        let script = bitcoin::Script::from(vec![0xAB; 22]);
+        let mut tree = get_tree();
+        tree.set_script_pubkey(&script, KeychainKind::External, 42)
+            .unwrap();
+
+        let inner = tree.into_inner();
+        let records = inner.iter_utxos().unwrap();
+        // the two entries of the script pubkey, plus the key check
+        assert_eq!(records.len(), 3);
+        for record in records {
+            let data = record.txout.script_pubkey.as_bytes();
+            assert!(!data.windows(script.len()).any(|w| w == script.as_bytes()));
+        }
+
+        let tree = EncryptedDatabase::new(inner, [42; 32]).unwrap();
+        assert_eq!(
+            tree.get_path_from_script_pubkey(&script).unwrap(),
+            Some((KeychainKind::External, 42))
+        );
+    }
+
+    #[test]
+    fn test_wrong_key() {
+        let mut tree = get_tree();
+        tree.set_last_index(KeychainKind::External, 7).unwrap();
+
+        let inner = tree.into_inner();
+        assert!(matches!(
+            EncryptedDatabase::new(inner, [21; 32]),
+            Err(Error::Decryption)
+        ));
+    }
+
+    #[test]
+    fn test_not_encrypted() {
+        let mut inner = MemoryDatabase::new();
+        inner
+            .set_utxo(&crate::LocalUtxo {
+                outpoint: Default::default(),
+                txout: Default::default(),
+                keychain: KeychainKind::External,
+                is_spent: false,
+                derivation_index: None,
+                suspected_dust: false,
+            })
+            .unwrap();
+        assert!(matches!(
+            EncryptedDatabase::new(inner, [42; 32]),
+            Err(Error::Decryption)
+        ));
+    }
+
+    #[test]
+    fn test_wallet_only_sees_decrypted_entries() {
+        let descriptors = testutils!(@descriptors (crate::wallet::test::get_test_wpkh()));
+        let tx_meta = testutils! {
+            @tx ( (@external descriptors, 0) => 50_000 ) ( @confirmations 1 )
+        };
+        let script_pubkey = Address::from_str(&tx_meta.output[0].to_address)
+            .unwrap()
+            .script_pubkey();
+
+        let mut tree = get_tree();
+        tree.set_script_pubkey(&script_pubkey, KeychainKind::External, 0)
+            .unwrap();
+        tree.set_last_index(KeychainKind::External, 0).unwrap();
+        let txid = crate::populate_test_db!(&mut tree, tx_meta, Some(100));
+
+        let wallet = Wallet::new(&descriptors.0, None, Network::Regtest, tree).unwrap();
+        assert_eq!(wallet.get_balance().unwrap(), 50_000);
+        let utxos = wallet.list_unspent().unwrap();
+        assert_eq!(utxos.len(), 1);
+        assert_eq!(utxos[0].outpoint.txid, txid);
+        let txs = wallet.list_transactions(false).unwrap();
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].txid, txid);
+
+        // the records stored in the inner database are all spent and worthless: reading it
+        // directly finds no transaction and no balance
+        let database = wallet.database();
+        assert!(database.inner.iter_txs(false).unwrap().is_empty());
+        assert!(database.inner.iter_raw_txs().unwrap().is_empty());
+        let records = database.inner.iter_utxos().unwrap();
+        assert!(records.len() > 1);
+        assert!(records
+            .iter()
+            .all(|record| record.is_spent && record.txout.value == 0));
+    }
+}
@@ -14,7 +14,7 @@ use std::convert::TryInto;
 use sled::{Batch, Tree};
 
 use bitcoin::consensus::encode::{deserialize, serialize};
-use bitcoin::hash_types::Txid;
+use bitcoin::hash_types::{BlockHash, Txid};
 use bitcoin::{OutPoint, Script, Transaction};
 
 use crate::database::memory::MapKey;
@@ -44,6 +44,8 @@ macro_rules! impl_batch_operations {
                 "t": utxo.txout,
                 "i": utxo.keychain,
                 "s": utxo.is_spent,
+                "d": utxo.derivation_index,
+                "u": utxo.suspected_dust,
             });
             self.insert(key, serde_json::to_vec(&value)?)$($after_insert)*;
 
@@ -83,6 +85,28 @@ macro_rules! impl_batch_operations {
             Ok(())
         }
 
+        fn set_pending_tx(&mut self, transaction: &Transaction) -> Result<(), Error> {
+            let key = MapKey::PendingTx(Some(&transaction.txid())).as_map_key();
+            let value = serialize(transaction);
+            self.insert(key, value)$($after_insert)*;
+
+            Ok(())
+        }
+
+        fn set_block_hash(&mut self, height: u32, hash: &BlockHash) -> Result<(), Error> {
+            let key = MapKey::BlockHash(Some(height)).as_map_key();
+            self.insert(key, serialize(hash))$($after_insert)*;
+
+            Ok(())
+        }
+
+        fn set_schema_version(&mut self, version: u32) -> Result<(), Error> {
+            let key = MapKey::SchemaVersion.as_map_key();
+            self.insert(key, &version.to_be_bytes())$($after_insert)*;
+
+            Ok(())
+        }
+
         fn set_sync_time(&mut self, data: SyncTime) -> Result<(), Error> {
             let key = MapKey::SyncTime.as_map_key();
             self.insert(key, serde_json::to_vec(&data)?)$($after_insert)*;
@@ -127,8 +151,10 @@ macro_rules! impl_batch_operations {
                     let txout = serde_json::from_value(val["t"].take())?;
                     let keychain = serde_json::from_value(val["i"].take())?;
                     let is_spent = val.get_mut("s").and_then(|s| s.take().as_bool()).unwrap_or(false);
+                    let derivation_index = val.get_mut("d").and_then(|d| d.take().as_u64()).map(|d| d as u32);
+                    let suspected_dust = val.get_mut("u").and_then(|u| u.take().as_bool()).unwrap_or(false);
 
-                    Ok(Some(LocalUtxo { outpoint: outpoint.clone(), txout, keychain, is_spent, }))
+                    Ok(Some(LocalUtxo { outpoint: outpoint.clone(), txout, keychain, is_spent, derivation_index, suspected_dust, }))
                 }
             }
         }
@@ -185,6 +211,22 @@ macro_rules! impl_batch_operations {
 
             Ok(res.map(|b| serde_json::from_slice(&b)).transpose()?)
         }
+
+        fn del_pending_tx(&mut self, txid: &Txid) -> Result<Option<Transaction>, Error> {
+            let key = MapKey::PendingTx(Some(txid)).as_map_key();
+            let res = self.remove(key);
+            let res = $process_delete!(res);
+
+            Ok(res.map_or(Ok(None), |x| Some(deserialize(&x)).transpose())?)
+        }
+
+        fn del_block_hash(&mut self, height: u32) -> Result<Option<BlockHash>, Error> {
+            let key = MapKey::BlockHash(Some(height)).as_map_key();
+            let res = self.remove(key);
+            let res = $process_delete!(res);
+
+            Ok(res.map_or(Ok(None), |x| Some(deserialize(&x)).transpose())?)
+        }
     }
 }
 
@@ -252,12 +294,22 @@ impl Database for Tree {
                     .get_mut("s")
                     .and_then(|s| s.take().as_bool())
                     .unwrap_or(false);
+                let derivation_index = val
+                    .get_mut("d")
+                    .and_then(|d| d.take().as_u64())
+                    .map(|d| d as u32);
+                let suspected_dust = val
+                    .get_mut("u")
+                    .and_then(|u| u.take().as_bool())
+                    .unwrap_or(false);
 
                 Ok(LocalUtxo {
                     outpoint,
                     txout,
                     keychain,
                     is_spent,
+                    derivation_index,
+                    suspected_dust,
                 })
             })
             .collect()
@@ -273,6 +325,29 @@ impl Database for Tree {
             .collect()
     }
 
+    fn iter_pending_txs(&self) -> Result<Vec<Transaction>, Error> {
+        let key = MapKey::PendingTx(None).as_map_key();
+        self.scan_prefix(key)
+            .map(|x| -> Result<_, Error> {
+                let (_, v) = x?;
+                Ok(deserialize(&v)?)
+            })
+            .collect()
+    }
+
+    fn iter_block_hashes(&self) -> Result<Vec<(u32, BlockHash)>, Error> {
+        let key = MapKey::BlockHash(None).as_map_key();
+        self.scan_prefix(key)
+            .map(|x| -> Result<_, Error> {
+                let (k, v) = x?;
+                let height: [u8; 4] = k[1..]
+                    .try_into()
+                    .map_err(|_| Error::InvalidU32Bytes(k[1..].to_vec()))?;
+                Ok((u32::from_be_bytes(height), deserialize(&v)?))
+            })
+            .collect()
+    }
+
     fn iter_txs(&self, include_raw: bool) -> Result<Vec<TransactionDetails>, Error> {
         let key = MapKey::Transaction(None).as_map_key();
         self.scan_prefix(key)
@@ -325,12 +400,22 @@ impl Database for Tree {
                     .get_mut("s")
                     .and_then(|s| s.take().as_bool())
                     .unwrap_or(false);
+                let derivation_index = val
+                    .get_mut("d")
+                    .and_then(|d| d.take().as_u64())
+                    .map(|d| d as u32);
+                let suspected_dust = val
+                    .get_mut("u")
+                    .and_then(|u| u.take().as_bool())
+                    .unwrap_or(false);
 
                 Ok(LocalUtxo {
                     outpoint: *outpoint,
                     txout,
                     keychain,
                     is_spent,
+                    derivation_index,
+                    suspected_dust,
                 })
             })
             .transpose()
@@ -377,6 +462,19 @@ impl Database for Tree {
             .transpose()?)
     }
 
+    fn get_schema_version(&self) -> Result<Option<u32>, Error> {
+        let key = MapKey::SchemaVersion.as_map_key();
+        self.get(key)?
+            .map(|b| -> Result<_, Error> {
+                let array: [u8; 4] = b
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| Error::InvalidU32Bytes(b.to_vec()))?;
+                Ok(u32::from_be_bytes(array))
+            })
+            .transpose()
+    }
+
     // inserts 0 if not present
     fn increment_last_index(&mut self, keychain: KeychainKind) -> Result<u32, Error> {
         let key = MapKey::LastIndex(keychain).as_map_key();
@@ -426,8 +524,14 @@ mod test {
     use std::sync::{Arc, Condvar, Mutex, Once};
     use std::time::{SystemTime, UNIX_EPOCH};
 
+    use bitcoin::hashes::Hash;
+    use bitcoin::Txid;
     use sled::{Db, Tree};
 
+    use crate::database::memory::MapKey;
+    use crate::database::Database;
+    use crate::TransactionDetails;
+
     static mut COUNT: usize = 0;
 
     lazy_static! {
@@ -510,4 +614,50 @@ mod test {
     fn test_sync_time() {
         crate::database::test::test_sync_time(get_tree());
     }
+
+    #[test]
+    fn test_pending_tx() {
+        crate::database::test::test_pending_tx(get_tree());
+    }
+
+    #[test]
+    fn test_block_hashes() {
+        crate::database::test::test_block_hashes(get_tree());
+    }
+
+    #[test]
+    fn test_migrate() {
+        crate::database::test::test_migrate(get_tree());
+    }
+
+    #[test]
+    fn test_old_tx_details() {
+        let tree = get_tree();
+        let txid = Txid::hash(b"old");
+        // written by a version without `tx_type`
+        let value = serde_json::json!({
+            "transaction": null,
+            "txid": txid,
+            "received": 1337,
+            "sent": 0,
+            "fee": 140,
+            "confirmation_time": null,
+        });
+        tree.insert(
+            MapKey::Transaction(Some(&txid)).as_map_key(),
+            serde_json::to_vec(&value).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            tree.get_tx(&txid, false).unwrap(),
+            Some(TransactionDetails {
+                txid,
+                received: 1337,
+                sent: 0,
+                fee: Some(140),
+                ..Default::default()
+            })
+        );
+    }
 }
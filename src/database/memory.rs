@@ -19,7 +19,7 @@ use std::collections::BTreeMap;
 use std::ops::Bound::{Excluded, Included};
 
 use bitcoin::consensus::encode::{deserialize, serialize};
-use bitcoin::hash_types::Txid;
+use bitcoin::hash_types::{BlockHash, Txid};
 use bitcoin::{OutPoint, Script, Transaction};
 
 use crate::database::{BatchDatabase, BatchOperations, ConfigurableDatabase, Database, SyncTime};
@@ -34,6 +34,9 @@ use crate::types::*;
 // deriv indexes        c{i,e} -> u32
 // descriptor checksum  d{i,e} -> vec<u8>
 // last sync time       l -> { height, timestamp }
+// pending broadcast    b<txid> -> tx
+// schema version       v -> u32
+// block hashes         h<height> -> (height, hash)
 
 pub(crate) enum MapKey<'a> {
     Path((Option<KeychainKind>, Option<u32>)),
@@ -44,6 +47,9 @@ pub(crate) enum MapKey<'a> {
     LastIndex(KeychainKind),
     SyncTime,
     DescriptorChecksum(KeychainKind),
+    PendingTx(Option<&'a Txid>),
+    SchemaVersion,
+    BlockHash(Option<u32>),
 }
 
 impl MapKey<'_> {
@@ -63,6 +69,9 @@ impl MapKey<'_> {
             MapKey::LastIndex(st) => [b"c", st.as_ref()].concat(),
             MapKey::SyncTime => b"l".to_vec(),
             MapKey::DescriptorChecksum(st) => [b"d", st.as_ref()].concat(),
+            MapKey::PendingTx(_) => b"b".to_vec(),
+            MapKey::SchemaVersion => b"v".to_vec(),
+            MapKey::BlockHash(_) => b"h".to_vec(),
         }
     }
 
@@ -73,6 +82,9 @@ impl MapKey<'_> {
             MapKey::Utxo(Some(s)) => serialize(*s),
             MapKey::RawTx(Some(s)) => serialize(*s),
             MapKey::Transaction(Some(s)) => serialize(*s),
+            MapKey::PendingTx(Some(s)) => serialize(*s),
+            // big endian, to iterate the heights in order
+            MapKey::BlockHash(Some(height)) => height.to_be_bytes().to_vec(),
             _ => vec![],
         }
     }
@@ -152,7 +164,13 @@ impl BatchOperations for MemoryDatabase {
         let key = MapKey::Utxo(Some(&utxo.outpoint)).as_map_key();
         self.map.insert(
             key,
-            Box::new((utxo.txout.clone(), utxo.keychain, utxo.is_spent)),
+            Box::new((
+                utxo.txout.clone(),
+                utxo.keychain,
+                utxo.is_spent,
+                utxo.derivation_index,
+                utxo.suspected_dust,
+            )),
         );
 
         Ok(())
@@ -191,6 +209,24 @@ impl BatchOperations for MemoryDatabase {
 
         Ok(())
     }
+    fn set_pending_tx(&mut self, transaction: &Transaction) -> Result<(), Error> {
+        let key = MapKey::PendingTx(Some(&transaction.txid())).as_map_key();
+        self.map.insert(key, Box::new(transaction.clone()));
+
+        Ok(())
+    }
+    fn set_schema_version(&mut self, version: u32) -> Result<(), Error> {
+        let key = MapKey::SchemaVersion.as_map_key();
+        self.map.insert(key, Box::new(version));
+
+        Ok(())
+    }
+    fn set_block_hash(&mut self, height: u32, hash: &BlockHash) -> Result<(), Error> {
+        let key = MapKey::BlockHash(Some(height)).as_map_key();
+        self.map.insert(key, Box::new((height, *hash)));
+
+        Ok(())
+    }
 
     fn del_script_pubkey_from_path(
         &mut self,
@@ -230,12 +266,15 @@ impl BatchOperations for MemoryDatabase {
         match res {
             None => Ok(None),
             Some(b) => {
-                let (txout, keychain, is_spent) = b.downcast_ref().cloned().unwrap();
+                let (txout, keychain, is_spent, derivation_index, suspected_dust) =
+                    b.downcast_ref().cloned().unwrap();
                 Ok(Some(LocalUtxo {
                     outpoint: *outpoint,
                     txout,
                     keychain,
                     is_spent,
+                    derivation_index,
+                    suspected_dust,
                 }))
             }
         }
@@ -289,6 +328,20 @@ impl BatchOperations for MemoryDatabase {
 
         Ok(res.map(|b| b.downcast_ref().cloned().unwrap()))
     }
+    fn del_pending_tx(&mut self, txid: &Txid) -> Result<Option<Transaction>, Error> {
+        let key = MapKey::PendingTx(Some(txid)).as_map_key();
+        let res = self.map.remove(&key);
+        self.deleted_keys.push(key);
+
+        Ok(res.map(|b| b.downcast_ref().cloned().unwrap()))
+    }
+    fn del_block_hash(&mut self, height: u32) -> Result<Option<BlockHash>, Error> {
+        let key = MapKey::BlockHash(Some(height)).as_map_key();
+        let res = self.map.remove(&key);
+        self.deleted_keys.push(key);
+
+        Ok(res.map(|b| b.downcast_ref::<(u32, BlockHash)>().unwrap().1))
+    }
 }
 
 impl Database for MemoryDatabase {
@@ -329,12 +382,15 @@ impl Database for MemoryDatabase {
             .range::<Vec<u8>, _>((Included(&key), Excluded(&after(&key))))
             .map(|(k, v)| {
                 let outpoint = deserialize(&k[1..]).unwrap();
-                let (txout, keychain, is_spent) = v.downcast_ref().cloned().unwrap();
+                let (txout, keychain, is_spent, derivation_index, suspected_dust) =
+                    v.downcast_ref().cloned().unwrap();
                 Ok(LocalUtxo {
                     outpoint,
                     txout,
                     keychain,
                     is_spent,
+                    derivation_index,
+                    suspected_dust,
                 })
             })
             .collect()
@@ -348,6 +404,22 @@ impl Database for MemoryDatabase {
             .collect()
     }
 
+    fn iter_pending_txs(&self) -> Result<Vec<Transaction>, Error> {
+        let key = MapKey::PendingTx(None).as_map_key();
+        self.map
+            .range::<Vec<u8>, _>((Included(&key), Excluded(&after(&key))))
+            .map(|(_, v)| Ok(v.downcast_ref().cloned().unwrap()))
+            .collect()
+    }
+
+    fn iter_block_hashes(&self) -> Result<Vec<(u32, BlockHash)>, Error> {
+        let key = MapKey::BlockHash(None).as_map_key();
+        self.map
+            .range::<Vec<u8>, _>((Included(&key), Excluded(&after(&key))))
+            .map(|(_, v)| Ok(*v.downcast_ref().unwrap()))
+            .collect()
+    }
+
     fn iter_txs(&self, include_raw: bool) -> Result<Vec<TransactionDetails>, Error> {
         let key = MapKey::Transaction(None).as_map_key();
         self.map
@@ -393,12 +465,15 @@ impl Database for MemoryDatabase {
     fn get_utxo(&self, outpoint: &OutPoint) -> Result<Option<LocalUtxo>, Error> {
         let key = MapKey::Utxo(Some(outpoint)).as_map_key();
         Ok(self.map.get(&key).map(|b| {
-            let (txout, keychain, is_spent) = b.downcast_ref().cloned().unwrap();
+            let (txout, keychain, is_spent, derivation_index, suspected_dust) =
+                b.downcast_ref().cloned().unwrap();
             LocalUtxo {
                 outpoint: *outpoint,
                 txout,
                 keychain,
                 is_spent,
+                derivation_index,
+                suspected_dust,
             }
         }))
     }
@@ -436,6 +511,11 @@ impl Database for MemoryDatabase {
             .map(|b| b.downcast_ref().cloned().unwrap()))
     }
 
+    fn get_schema_version(&self) -> Result<Option<u32>, Error> {
+        let key = MapKey::SchemaVersion.as_map_key();
+        Ok(self.map.get(&key).map(|b| *b.downcast_ref().unwrap()))
+    }
+
     // inserts 0 if not present
     fn increment_last_index(&mut self, keychain: KeychainKind) -> Result<u32, Error> {
         let key = MapKey::LastIndex(keychain).as_map_key();
@@ -484,16 +564,24 @@ impl ConfigurableDatabase for MemoryDatabase {
 /// Artificially insert a tx in the database, as if we had found it with a `sync`. This is a hidden
 /// macro and not a `[cfg(test)]` function so it can be called within the context of doctests which
 /// don't have `test` set.
+///
+/// The transaction has version 1 and no lock time unless they are set with the `@version` and
+/// `@locktime` options of `testutils!(@tx ...)`. Its outputs are stored as UTXOs of the external
+/// keychain, unless another keychain follows their amount, as in `(@internal descriptors, 0) =>
+/// 10_000; KeychainKind::Internal`.
 macro_rules! populate_test_db {
     ($db:expr, $tx_meta:expr, $current_height:expr$(,)?) => {{
         use std::str::FromStr;
-        use $crate::database::BatchOperations;
+        use $crate::database::{BatchOperations, Database};
         let mut db = $db;
         let tx_meta = $tx_meta;
         let current_height: Option<u32> = $current_height;
         let tx = $crate::bitcoin::Transaction {
-            version: 1,
-            lock_time: 0,
+            version: tx_meta.version.unwrap_or(1),
+            lock_time: tx_meta
+                .locktime
+                .map(|locktime| locktime as u32)
+                .unwrap_or(0),
             input: vec![],
             output: tx_meta
                 .output
@@ -520,18 +608,25 @@ macro_rules! populate_test_db {
             received: 0,
             sent: 0,
             confirmation_time,
+            tx_type: None,
         };
 
         db.set_tx(&tx_details).unwrap();
-        for (vout, out) in tx.output.iter().enumerate() {
+        for (vout, (out, out_meta)) in tx.output.iter().zip(&tx_meta.output).enumerate() {
+            let derivation_index = db
+                .get_path_from_script_pubkey(&out.script_pubkey)
+                .unwrap()
+                .map(|(_, child)| child);
             db.set_utxo(&$crate::LocalUtxo {
                 txout: out.clone(),
                 outpoint: $crate::bitcoin::OutPoint {
                     txid,
                     vout: vout as u32,
                 },
-                keychain: $crate::KeychainKind::External,
+                keychain: out_meta.keychain.unwrap_or($crate::KeychainKind::External),
                 is_spent: false,
+                derivation_index,
+                suspected_dust: false,
             })
             .unwrap();
         }
@@ -622,4 +717,111 @@ mod test {
     fn test_sync_time() {
         crate::database::test::test_sync_time(get_tree());
     }
+
+    #[test]
+    fn test_pending_tx() {
+        crate::database::test::test_pending_tx(get_tree());
+    }
+
+    #[test]
+    fn test_block_hashes() {
+        crate::database::test::test_block_hashes(get_tree());
+    }
+
+    #[test]
+    fn test_migrate() {
+        crate::database::test::test_migrate(get_tree());
+    }
+
+    #[test]
+    fn test_iter_txs_sorted() {
+        use std::cell::RefCell;
+
+        use crate::database::Database;
+        use crate::wallet::test::get_test_wpkh;
+
+        let descriptors = testutils!(@descriptors (get_test_wpkh()));
+        let db = RefCell::new(get_tree());
+
+        let old_txid = crate::populate_test_db!(
+            db.borrow_mut(),
+            testutils!(@tx ( (@external descriptors, 0) => 10_000 ) (@confirmations 10)),
+            Some(100),
+        );
+        let unconfirmed_txid = crate::populate_test_db!(
+            db.borrow_mut(),
+            testutils!(@tx ( (@external descriptors, 0) => 20_000 )),
+            Some(100),
+        );
+        let recent_txid = crate::populate_test_db!(
+            db.borrow_mut(),
+            testutils!(@tx ( (@external descriptors, 0) => 30_000 ) (@confirmations 1)),
+            Some(100),
+        );
+
+        let txids = db
+            .borrow()
+            .iter_txs_sorted(false)
+            .unwrap()
+            .into_iter()
+            .map(|tx| tx.txid)
+            .collect::<Vec<_>>();
+        assert_eq!(txids, vec![unconfirmed_txid, recent_txid, old_txid]);
+    }
+
+    #[test]
+    fn test_populate_test_db_version_lock_time() {
+        use crate::database::Database;
+        use crate::wallet::test::get_test_wpkh;
+
+        let descriptors = testutils!(@descriptors (get_test_wpkh()));
+        let mut db = get_tree();
+
+        let txid = crate::populate_test_db!(
+            &mut db,
+            testutils!(@tx ( (@external descriptors, 0) => 10_000 ) (@locktime 500_000) (@version 2)),
+            Some(100),
+        );
+        let tx = db.get_raw_tx(&txid).unwrap().unwrap();
+        assert_eq!(tx.version, 2);
+        assert_eq!(tx.lock_time, 500_000);
+
+        let txid = crate::populate_test_db!(
+            &mut db,
+            testutils!(@tx ( (@external descriptors, 0) => 10_000 )),
+            Some(100),
+        );
+        let tx = db.get_raw_tx(&txid).unwrap().unwrap();
+        assert_eq!(tx.version, 1);
+        assert_eq!(tx.lock_time, 0);
+    }
+
+    #[test]
+    fn test_populate_test_db_keychain() {
+        use crate::database::Database;
+        use crate::wallet::test::get_test_wpkh;
+        use crate::KeychainKind;
+
+        let descriptors = testutils!(@descriptors (get_test_wpkh()) (get_test_wpkh()));
+        let mut db = get_tree();
+
+        let txid = crate::populate_test_db!(
+            &mut db,
+            testutils!(@tx ( (@external descriptors, 0) => 10_000, (@internal descriptors, 0) => 20_000; KeychainKind::Internal ) (@confirmations 1)),
+            Some(100),
+        );
+
+        let mut utxos = db.iter_utxos().unwrap();
+        utxos.sort_by_key(|utxo| utxo.outpoint.vout);
+        assert_eq!(
+            utxos
+                .iter()
+                .map(|utxo| (utxo.outpoint.txid, utxo.txout.value, utxo.keychain))
+                .collect::<Vec<_>>(),
+            vec![
+                (txid, 10_000, KeychainKind::External),
+                (txid, 20_000, KeychainKind::Internal)
+            ]
+        );
+    }
 }
@@ -24,9 +24,11 @@
 //!
 //! [`Wallet`]: crate::wallet::Wallet
 
+use std::cmp::Reverse;
+
 use serde::{Deserialize, Serialize};
 
-use bitcoin::hash_types::Txid;
+use bitcoin::hash_types::{BlockHash, Txid};
 use bitcoin::{OutPoint, Script, Transaction, TxOut};
 
 use crate::error::Error;
@@ -43,6 +45,11 @@ pub(crate) mod sqlite;
 #[cfg(feature = "sqlite")]
 pub use sqlite::SqliteDatabase;
 
+#[cfg(feature = "encrypt")]
+pub(crate) mod encrypted;
+#[cfg(feature = "encrypt")]
+pub use encrypted::EncryptedDatabase;
+
 pub mod memory;
 pub use memory::MemoryDatabase;
 
@@ -77,6 +84,28 @@ pub trait BatchOperations {
     fn set_last_index(&mut self, keychain: KeychainKind, value: u32) -> Result<(), Error>;
     /// Store the sync time
     fn set_sync_time(&mut self, sync_time: SyncTime) -> Result<(), Error>;
+    /// Store a transaction that should be broadcast
+    ///
+    /// The default implementation returns an error, for databases that can't persist them.
+    fn set_pending_tx(&mut self, transaction: &Transaction) -> Result<(), Error> {
+        Err(Error::Generic(format!(
+            "Can't store the pending transaction {}: not supported by this database",
+            transaction.txid()
+        )))
+    }
+    /// Store the hash of the block at `height`, used to detect reorgs during sync
+    ///
+    /// The default implementation doesn't store anything, so reorgs aren't detected.
+    fn set_block_hash(&mut self, _height: u32, _hash: &BlockHash) -> Result<(), Error> {
+        Ok(())
+    }
+    /// Store the version of the format of the data, see [`SCHEMA_VERSION`]
+    ///
+    /// The default implementation doesn't store anything, so the data is checked for upgrades at
+    /// every sync.
+    fn set_schema_version(&mut self, _version: u32) -> Result<(), Error> {
+        Ok(())
+    }
 
     /// Delete a script_pubkey given the keychain and its child number.
     fn del_script_pubkey_from_path(
@@ -106,6 +135,18 @@ pub trait BatchOperations {
     ///
     /// Returns the removed value
     fn del_sync_time(&mut self) -> Result<Option<SyncTime>, Error>;
+    /// Delete a transaction pending broadcast given its [`Txid`]
+    ///
+    /// The default implementation doesn't store any, so there's never anything to delete.
+    fn del_pending_tx(&mut self, _txid: &Txid) -> Result<Option<Transaction>, Error> {
+        Ok(None)
+    }
+    /// Delete the hash of the block at `height`
+    ///
+    /// The default implementation always returns `None`, for databases that don't store them.
+    fn del_block_hash(&mut self, _height: u32) -> Result<Option<BlockHash>, Error> {
+        Ok(None)
+    }
 }
 
 /// Trait for reading data from a database
@@ -131,6 +172,35 @@ pub trait Database: BatchOperations {
     fn iter_raw_txs(&self) -> Result<Vec<Transaction>, Error>;
     /// Return the list of transactions metadata
     fn iter_txs(&self, include_raw: bool) -> Result<Vec<TransactionDetails>, Error>;
+    /// Return the list of transactions metadata sorted by confirmation time
+    ///
+    /// Unconfirmed transactions come first, followed by the confirmed ones from the most recent
+    /// to the oldest.
+    fn iter_txs_sorted(&self, include_raw: bool) -> Result<Vec<TransactionDetails>, Error> {
+        let mut txs = self.iter_txs(include_raw)?;
+        txs.sort_by_key(|tx| {
+            let confirmation_time = tx
+                .confirmation_time
+                .as_ref()
+                .map_or((u32::MAX, u64::MAX), |time| (time.height, time.timestamp));
+            (Reverse(confirmation_time), tx.txid)
+        });
+
+        Ok(txs)
+    }
+    /// Return the list of transactions pending broadcast
+    ///
+    /// The default implementation doesn't store any and always returns an empty list.
+    fn iter_pending_txs(&self) -> Result<Vec<Transaction>, Error> {
+        Ok(vec![])
+    }
+    /// Return the stored block hashes along with their height, sorted by height
+    ///
+    /// The default implementation always returns an empty list, for databases that don't store
+    /// them.
+    fn iter_block_hashes(&self) -> Result<Vec<(u32, BlockHash)>, Error> {
+        Ok(vec![])
+    }
 
     /// Fetch a script_pubkey given the child number of a keychain.
     fn get_script_pubkey_from_path(
@@ -153,6 +223,12 @@ pub trait Database: BatchOperations {
     fn get_last_index(&self, keychain: KeychainKind) -> Result<Option<u32>, Error>;
     /// Return the sync time, if present
     fn get_sync_time(&self) -> Result<Option<SyncTime>, Error>;
+    /// Return the version of the format of the data, if present, see [`SCHEMA_VERSION`]
+    ///
+    /// The default implementation always returns `None`, for databases that don't store it.
+    fn get_schema_version(&self) -> Result<Option<u32>, Error> {
+        Ok(None)
+    }
 
     /// Increment the last derivation index for a keychain and return it
     ///
@@ -219,6 +295,53 @@ pub(crate) trait DatabaseUtils: Database {
 
 impl<T: Database> DatabaseUtils for T {}
 
+/// Current version of the format of the data written to a [`Database`]
+///
+/// The version is stored in the database, see [`Database::get_schema_version`], and the data
+/// written by older versions is upgraded by [`migrate`] at the beginning of every sync:
+///
+/// - `0`: no version stored, the database was created before the version was tracked. The
+///   [`TransactionDetails`] of the transactions synced before [`TransactionDetails::tx_type`]
+///   existed don't have a type.
+/// - `1`: every synced transaction whose raw transaction is stored has a type.
+///
+/// New fields of the stored structures are always optional: reading old data fills them with
+/// `None` until the migration runs, and older versions of the library ignore the fields they
+/// don't know about, so they can still open a database upgraded by a newer version.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Upgrade the data written by an older version of the library to [`SCHEMA_VERSION`]
+///
+/// This is a no-op if the database is already up to date, or if it was written by a newer
+/// version of the library.
+pub(crate) fn migrate<D: BatchDatabase>(database: &mut D) -> Result<(), Error> {
+    let version = database.get_schema_version()?.unwrap_or(0);
+    if version >= SCHEMA_VERSION {
+        return Ok(());
+    }
+
+    let mut batch = database.begin_batch();
+    if version < 1 {
+        for mut details in database.iter_txs(true)? {
+            let tx = match (&details.tx_type, &details.transaction) {
+                (None, Some(tx)) => tx,
+                _ => continue,
+            };
+            let mut owned_outputs = 0;
+            for txout in &tx.output {
+                if database.is_mine(&txout.script_pubkey)? {
+                    owned_outputs += 1;
+                }
+            }
+            details.tx_type = Some(TxType::classify(tx, details.sent, owned_outputs));
+            batch.set_tx(&details)?;
+        }
+    }
+    batch.set_schema_version(SCHEMA_VERSION)?;
+
+    database.commit_batch(batch)
+}
+
 #[cfg(test)]
 pub mod test {
     use std::str::FromStr;
@@ -320,6 +443,8 @@ pub mod test {
             outpoint,
             keychain: KeychainKind::External,
             is_spent: true,
+            derivation_index: Some(42),
+            suspected_dust: true,
         };
 
         tree.set_utxo(&utxo).unwrap();
@@ -353,6 +478,7 @@ pub mod test {
                 timestamp: 123456,
                 height: 1000,
             }),
+            tx_type: Some(TxType::Receive),
         };
 
         tree.set_tx(&tx_details).unwrap();
@@ -390,6 +516,7 @@ pub mod test {
                 timestamp: 123456,
                 height: 1000,
             }),
+            tx_type: Some(TxType::Receive),
         };
 
         tree.set_tx(&tx_details).unwrap();
@@ -448,5 +575,87 @@ pub mod test {
         assert!(tree.get_sync_time().unwrap().is_none());
     }
 
+    pub fn test_pending_tx<D: Database>(mut tree: D) {
+        let hex_tx = Vec::<u8>::from_hex("0100000001a15d57094aa7a21a28cb20b59aab8fc7d1149a3bdbcddba9c622e4f5f6a99ece010000006c493046022100f93bb0e7d8db7bd46e40132d1f8242026e045f03a0efe71bbb8e3f475e970d790221009337cd7f1f929f00cc6ff01f03729b069a7c21b59b1736ddfee5db5946c5da8c0121033b9b137ee87d5a812d6f506efdd37f0affa7ffc310711c06c7f3e097c9447c52ffffffff0100e1f505000000001976a9140389035a9225b3839e2bbf32d826a1e222031fd888ac00000000").unwrap();
+        let tx: Transaction = deserialize(&hex_tx).unwrap();
+        let txid = tx.txid();
+
+        assert!(tree.iter_pending_txs().unwrap().is_empty());
+
+        tree.set_pending_tx(&tx).unwrap();
+        tree.set_pending_tx(&tx).unwrap();
+        assert_eq!(tree.iter_pending_txs().unwrap(), vec![tx.clone()]);
+        // pending txs are kept apart from the raw txs found during sync
+        assert_eq!(tree.get_raw_tx(&txid).unwrap(), None);
+
+        assert_eq!(tree.del_pending_tx(&txid).unwrap(), Some(tx));
+        assert!(tree.iter_pending_txs().unwrap().is_empty());
+        assert_eq!(tree.del_pending_tx(&txid).unwrap(), None);
+    }
+
+    pub fn test_block_hashes<D: Database>(mut tree: D) {
+        use bitcoin::hashes::Hash;
+
+        let hash = |n: u8| BlockHash::hash(&[n]);
+        assert!(tree.iter_block_hashes().unwrap().is_empty());
+
+        // heights are sorted numerically
+        tree.set_block_hash(256, &hash(2)).unwrap();
+        tree.set_block_hash(1, &hash(1)).unwrap();
+        tree.set_block_hash(1_000, &hash(3)).unwrap();
+        assert_eq!(
+            tree.iter_block_hashes().unwrap(),
+            vec![(1, hash(1)), (256, hash(2)), (1_000, hash(3))]
+        );
+
+        // the hash at a height is replaced
+        tree.set_block_hash(256, &hash(4)).unwrap();
+        assert_eq!(tree.del_block_hash(256).unwrap(), Some(hash(4)));
+        assert_eq!(tree.del_block_hash(256).unwrap(), None);
+        assert_eq!(
+            tree.iter_block_hashes().unwrap(),
+            vec![(1, hash(1)), (1_000, hash(3))]
+        );
+    }
+
+    pub fn test_migrate<D: BatchDatabase>(mut tree: D) {
+        let hex_tx = Vec::<u8>::from_hex("0100000001a15d57094aa7a21a28cb20b59aab8fc7d1149a3bdbcddba9c622e4f5f6a99ece010000006c493046022100f93bb0e7d8db7bd46e40132d1f8242026e045f03a0efe71bbb8e3f475e970d790221009337cd7f1f929f00cc6ff01f03729b069a7c21b59b1736ddfee5db5946c5da8c0121033b9b137ee87d5a812d6f506efdd37f0affa7ffc310711c06c7f3e097c9447c52ffffffff0100e1f505000000001976a9140389035a9225b3839e2bbf32d826a1e222031fd888ac00000000").unwrap();
+        let tx: Transaction = deserialize(&hex_tx).unwrap();
+        tree.set_script_pubkey(&tx.output[0].script_pubkey, KeychainKind::External, 0)
+            .unwrap();
+        // written before the schema version and the transaction type existed
+        let old_details = TransactionDetails {
+            transaction: Some(tx.clone()),
+            txid: tx.txid(),
+            received: 100_000_000,
+            sent: 0,
+            fee: Some(140),
+            confirmation_time: Some(BlockTime {
+                timestamp: 123456,
+                height: 1000,
+            }),
+            tx_type: None,
+        };
+        tree.set_tx(&old_details).unwrap();
+        assert_eq!(tree.get_schema_version().unwrap(), None);
+
+        migrate(&mut tree).unwrap();
+        assert_eq!(tree.get_schema_version().unwrap(), Some(SCHEMA_VERSION));
+        assert_eq!(
+            tree.get_tx(&tx.txid(), true).unwrap(),
+            Some(TransactionDetails {
+                tx_type: Some(TxType::Receive),
+                ..old_details.clone()
+            })
+        );
+
+        // the data of newer versions is left untouched
+        tree.set_tx(&old_details).unwrap();
+        tree.set_schema_version(SCHEMA_VERSION + 1).unwrap();
+        migrate(&mut tree).unwrap();
+        assert_eq!(tree.get_schema_version().unwrap(), Some(SCHEMA_VERSION + 1));
+        assert_eq!(tree.get_tx(&tx.txid(), true).unwrap(), Some(old_details));
+    }
+
     // TODO: more tests...
 }
@@ -10,7 +10,7 @@
 // licenses.
 
 use bitcoin::consensus::encode::{deserialize, serialize};
-use bitcoin::hash_types::Txid;
+use bitcoin::hash_types::{BlockHash, Txid};
 use bitcoin::{OutPoint, Script, Transaction, TxOut};
 
 use crate::database::{BatchDatabase, BatchOperations, Database, SyncTime};
@@ -50,7 +50,15 @@ static MIGRATIONS: &[&str] = &[
     "DELETE FROM transactions;",
     "DELETE FROM utxos;",
     "DROP INDEX idx_txid_vout;",
-    "CREATE UNIQUE INDEX idx_utxos_txid_vout ON utxos(txid, vout);"
+    "CREATE UNIQUE INDEX idx_utxos_txid_vout ON utxos(txid, vout);",
+    "ALTER TABLE utxos ADD COLUMN derivation_index INTEGER;",
+    "CREATE TABLE pending_txs (txid BLOB, raw_tx BLOB);",
+    "CREATE UNIQUE INDEX idx_pending_txs_txid ON pending_txs(txid);",
+    "ALTER TABLE transaction_details ADD COLUMN tx_type TEXT;",
+    "ALTER TABLE utxos ADD COLUMN suspected_dust BOOLEAN DEFAULT 0;",
+    "CREATE TABLE schema_version (id INTEGER PRIMARY KEY, version INTEGER);",
+    "CREATE TABLE block_hashes (height INTEGER, hash BLOB);",
+    "CREATE UNIQUE INDEX idx_block_hashes_height ON block_hashes(height);"
 ];
 
 /// Sqlite database stored on filesystem
@@ -87,6 +95,7 @@ impl SqliteDatabase {
 
         Ok(self.connection.last_insert_rowid())
     }
+    #[allow(clippy::too_many_arguments)]
     fn insert_utxo(
         &self,
         value: u64,
@@ -95,8 +104,10 @@ impl SqliteDatabase {
         txid: &[u8],
         script: &[u8],
         is_spent: bool,
+        derivation_index: Option<u32>,
+        suspected_dust: bool,
     ) -> Result<i64, Error> {
-        let mut statement = self.connection.prepare_cached("INSERT INTO utxos (value, keychain, vout, txid, script, is_spent) VALUES (:value, :keychain, :vout, :txid, :script, :is_spent) ON CONFLICT(txid, vout) DO UPDATE SET value=:value, keychain=:keychain, script=:script, is_spent=:is_spent")?;
+        let mut statement = self.connection.prepare_cached("INSERT INTO utxos (value, keychain, vout, txid, script, is_spent, derivation_index, suspected_dust) VALUES (:value, :keychain, :vout, :txid, :script, :is_spent, :derivation_index, :suspected_dust) ON CONFLICT(txid, vout) DO UPDATE SET value=:value, keychain=:keychain, script=:script, is_spent=:is_spent, derivation_index=:derivation_index, suspected_dust=:suspected_dust")?;
         statement.execute(named_params! {
             ":value": value,
             ":keychain": keychain,
@@ -104,6 +115,8 @@ impl SqliteDatabase {
             ":txid": txid,
             ":script": script,
             ":is_spent": is_spent,
+            ":derivation_index": derivation_index,
+            ":suspected_dust": suspected_dust,
         })?;
 
         Ok(self.connection.last_insert_rowid())
@@ -133,6 +146,26 @@ impl SqliteDatabase {
         Ok(())
     }
 
+    fn insert_pending_tx(&self, txid: &[u8], raw_tx: &[u8]) -> Result<i64, Error> {
+        let mut statement = self.connection.prepare_cached("INSERT INTO pending_txs (txid, raw_tx) VALUES (:txid, :raw_tx) ON CONFLICT(txid) DO UPDATE SET raw_tx=:raw_tx")?;
+        statement.execute(named_params! {
+            ":txid": txid,
+            ":raw_tx": raw_tx,
+        })?;
+
+        Ok(self.connection.last_insert_rowid())
+    }
+
+    fn insert_block_hash(&self, height: u32, hash: &[u8]) -> Result<i64, Error> {
+        let mut statement = self.connection.prepare_cached("INSERT INTO block_hashes (height, hash) VALUES (:height, :hash) ON CONFLICT(height) DO UPDATE SET hash=:hash")?;
+        statement.execute(named_params! {
+            ":height": height,
+            ":hash": hash,
+        })?;
+
+        Ok(self.connection.last_insert_rowid())
+    }
+
     fn insert_transaction_details(&self, transaction: &TransactionDetails) -> Result<i64, Error> {
         let (timestamp, height) = match &transaction.confirmation_time {
             Some(confirmation_time) => (
@@ -143,8 +176,12 @@ impl SqliteDatabase {
         };
 
         let txid: &[u8] = &transaction.txid;
+        let tx_type = transaction
+            .tx_type
+            .map(|tx_type| serde_json::to_string(&tx_type))
+            .transpose()?;
 
-        let mut statement = self.connection.prepare_cached("INSERT INTO transaction_details (txid, timestamp, received, sent, fee, height) VALUES (:txid, :timestamp, :received, :sent, :fee, :height)")?;
+        let mut statement = self.connection.prepare_cached("INSERT INTO transaction_details (txid, timestamp, received, sent, fee, height, tx_type) VALUES (:txid, :timestamp, :received, :sent, :fee, :height, :tx_type)")?;
 
         statement.execute(named_params! {
             ":txid": txid,
@@ -153,6 +190,7 @@ impl SqliteDatabase {
             ":sent": transaction.sent,
             ":fee": transaction.fee,
             ":height": height,
+            ":tx_type": tx_type,
         })?;
 
         Ok(self.connection.last_insert_rowid())
@@ -168,8 +206,12 @@ impl SqliteDatabase {
         };
 
         let txid: &[u8] = &transaction.txid;
+        let tx_type = transaction
+            .tx_type
+            .map(|tx_type| serde_json::to_string(&tx_type))
+            .transpose()?;
 
-        let mut statement = self.connection.prepare_cached("UPDATE transaction_details SET timestamp=:timestamp, received=:received, sent=:sent, fee=:fee, height=:height WHERE txid=:txid")?;
+        let mut statement = self.connection.prepare_cached("UPDATE transaction_details SET timestamp=:timestamp, received=:received, sent=:sent, fee=:fee, height=:height, tx_type=:tx_type WHERE txid=:txid")?;
 
         statement.execute(named_params! {
             ":txid": txid,
@@ -178,6 +220,7 @@ impl SqliteDatabase {
             ":sent": transaction.sent,
             ":fee": transaction.fee,
             ":height": height,
+            ":tx_type": tx_type,
         })?;
 
         Ok(())
@@ -234,6 +277,15 @@ impl SqliteDatabase {
         Ok(self.connection.last_insert_rowid())
     }
 
+    fn update_schema_version(&self, version: u32) -> Result<i64, Error> {
+        let mut statement = self.connection.prepare_cached(
+            "INSERT INTO schema_version (id, version) VALUES (0, :version) ON CONFLICT(id) DO UPDATE SET version=:version WHERE id = 0",
+        )?;
+        statement.execute(named_params! { ":version": version })?;
+
+        Ok(self.connection.last_insert_rowid())
+    }
+
     fn select_script_pubkeys(&self) -> Result<Vec<Script>, Error> {
         let mut statement = self
             .connection
@@ -302,9 +354,9 @@ impl SqliteDatabase {
     }
 
     fn select_utxos(&self) -> Result<Vec<LocalUtxo>, Error> {
-        let mut statement = self
-            .connection
-            .prepare_cached("SELECT value, keychain, vout, txid, script, is_spent FROM utxos")?;
+        let mut statement = self.connection.prepare_cached(
+            "SELECT value, keychain, vout, txid, script, is_spent, derivation_index, suspected_dust FROM utxos",
+        )?;
         let mut utxos: Vec<LocalUtxo> = vec![];
         let mut rows = statement.query([])?;
         while let Some(row) = rows.next()? {
@@ -314,6 +366,8 @@ impl SqliteDatabase {
             let txid: Vec<u8> = row.get(3)?;
             let script: Vec<u8> = row.get(4)?;
             let is_spent: bool = row.get(5)?;
+            let derivation_index: Option<u32> = row.get(6)?;
+            let suspected_dust: bool = row.get(7)?;
 
             let keychain: KeychainKind = serde_json::from_str(&keychain)?;
 
@@ -325,6 +379,8 @@ impl SqliteDatabase {
                 },
                 keychain,
                 is_spent,
+                derivation_index,
+                suspected_dust,
             })
         }
 
@@ -333,7 +389,7 @@ impl SqliteDatabase {
 
     fn select_utxo_by_outpoint(&self, txid: &[u8], vout: u32) -> Result<Option<LocalUtxo>, Error> {
         let mut statement = self.connection.prepare_cached(
-            "SELECT value, keychain, script, is_spent FROM utxos WHERE txid=:txid AND vout=:vout",
+            "SELECT value, keychain, script, is_spent, derivation_index, suspected_dust FROM utxos WHERE txid=:txid AND vout=:vout",
         )?;
         let mut rows = statement.query(named_params! {":txid": txid,":vout": vout})?;
         match rows.next()? {
@@ -344,6 +400,8 @@ impl SqliteDatabase {
                 let script: Vec<u8> = row.get(2)?;
                 let script_pubkey: Script = script.into();
                 let is_spent: bool = row.get(3)?;
+                let derivation_index: Option<u32> = row.get(4)?;
+                let suspected_dust: bool = row.get(5)?;
 
                 Ok(Some(LocalUtxo {
                     outpoint: OutPoint::new(deserialize(txid)?, vout),
@@ -353,6 +411,8 @@ impl SqliteDatabase {
                     },
                     keychain,
                     is_spent,
+                    derivation_index,
+                    suspected_dust,
                 }))
             }
             None => Ok(None),
@@ -388,8 +448,65 @@ impl SqliteDatabase {
         }
     }
 
+    fn select_pending_txs(&self) -> Result<Vec<Transaction>, Error> {
+        let mut statement = self
+            .connection
+            .prepare_cached("SELECT raw_tx FROM pending_txs")?;
+        let mut txs: Vec<Transaction> = vec![];
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let raw_tx: Vec<u8> = row.get(0)?;
+            let tx: Transaction = deserialize(&raw_tx)?;
+            txs.push(tx);
+        }
+        Ok(txs)
+    }
+
+    fn select_pending_tx_by_txid(&self, txid: &[u8]) -> Result<Option<Transaction>, Error> {
+        let mut statement = self
+            .connection
+            .prepare_cached("SELECT raw_tx FROM pending_txs WHERE txid=:txid")?;
+        let mut rows = statement.query(named_params! {":txid": txid})?;
+        match rows.next()? {
+            Some(row) => {
+                let raw_tx: Vec<u8> = row.get(0)?;
+                let tx: Transaction = deserialize(&raw_tx)?;
+                Ok(Some(tx))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn select_block_hashes(&self) -> Result<Vec<(u32, BlockHash)>, Error> {
+        let mut statement = self
+            .connection
+            .prepare_cached("SELECT height, hash FROM block_hashes ORDER BY height")?;
+        let mut hashes: Vec<(u32, BlockHash)> = vec![];
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let height: u32 = row.get(0)?;
+            let hash: Vec<u8> = row.get(1)?;
+            hashes.push((height, deserialize(&hash)?));
+        }
+        Ok(hashes)
+    }
+
+    fn select_block_hash_by_height(&self, height: u32) -> Result<Option<BlockHash>, Error> {
+        let mut statement = self
+            .connection
+            .prepare_cached("SELECT hash FROM block_hashes WHERE height=:height")?;
+        let mut rows = statement.query(named_params! {":height": height})?;
+        match rows.next()? {
+            Some(row) => {
+                let hash: Vec<u8> = row.get(0)?;
+                Ok(Some(deserialize(&hash)?))
+            }
+            None => Ok(None),
+        }
+    }
+
     fn select_transaction_details_with_raw(&self) -> Result<Vec<TransactionDetails>, Error> {
-        let mut statement = self.connection.prepare_cached("SELECT transaction_details.txid, transaction_details.timestamp, transaction_details.received, transaction_details.sent, transaction_details.fee, transaction_details.height, transactions.raw_tx FROM transaction_details, transactions WHERE transaction_details.txid = transactions.txid")?;
+        let mut statement = self.connection.prepare_cached("SELECT transaction_details.txid, transaction_details.timestamp, transaction_details.received, transaction_details.sent, transaction_details.fee, transaction_details.height, transactions.raw_tx, transaction_details.tx_type FROM transaction_details, transactions WHERE transaction_details.txid = transactions.txid")?;
         let mut transaction_details: Vec<TransactionDetails> = vec![];
         let mut rows = statement.query([])?;
         while let Some(row) = rows.next()? {
@@ -408,6 +525,8 @@ impl SqliteDatabase {
                 }
                 None => None,
             };
+            let tx_type: Option<String> = row.get(7)?;
+            let tx_type = tx_type.map(|t| serde_json::from_str(&t)).transpose()?;
 
             let confirmation_time = match (height, timestamp) {
                 (Some(height), Some(timestamp)) => Some(BlockTime { height, timestamp }),
@@ -421,6 +540,7 @@ impl SqliteDatabase {
                 sent,
                 fee,
                 confirmation_time,
+                tx_type,
             });
         }
         Ok(transaction_details)
@@ -428,7 +548,7 @@ impl SqliteDatabase {
 
     fn select_transaction_details(&self) -> Result<Vec<TransactionDetails>, Error> {
         let mut statement = self.connection.prepare_cached(
-            "SELECT txid, timestamp, received, sent, fee, height FROM transaction_details",
+            "SELECT txid, timestamp, received, sent, fee, height, tx_type FROM transaction_details",
         )?;
         let mut transaction_details: Vec<TransactionDetails> = vec![];
         let mut rows = statement.query([])?;
@@ -440,6 +560,8 @@ impl SqliteDatabase {
             let sent: u64 = row.get(3)?;
             let fee: Option<u64> = row.get(4)?;
             let height: Option<u32> = row.get(5)?;
+            let tx_type: Option<String> = row.get(6)?;
+            let tx_type = tx_type.map(|t| serde_json::from_str(&t)).transpose()?;
 
             let confirmation_time = match (height, timestamp) {
                 (Some(height), Some(timestamp)) => Some(BlockTime { height, timestamp }),
@@ -453,6 +575,7 @@ impl SqliteDatabase {
                 sent,
                 fee,
                 confirmation_time,
+                tx_type,
             });
         }
         Ok(transaction_details)
@@ -462,7 +585,7 @@ impl SqliteDatabase {
         &self,
         txid: &[u8],
     ) -> Result<Option<TransactionDetails>, Error> {
-        let mut statement = self.connection.prepare_cached("SELECT transaction_details.timestamp, transaction_details.received, transaction_details.sent, transaction_details.fee, transaction_details.height, transactions.raw_tx FROM transaction_details, transactions WHERE transaction_details.txid=transactions.txid AND transaction_details.txid=:txid")?;
+        let mut statement = self.connection.prepare_cached("SELECT transaction_details.timestamp, transaction_details.received, transaction_details.sent, transaction_details.fee, transaction_details.height, transactions.raw_tx, transaction_details.tx_type FROM transaction_details, transactions WHERE transaction_details.txid=transactions.txid AND transaction_details.txid=:txid")?;
         let mut rows = statement.query(named_params! { ":txid": txid })?;
 
         match rows.next()? {
@@ -481,6 +604,8 @@ impl SqliteDatabase {
                     }
                     None => None,
                 };
+                let tx_type: Option<String> = row.get(6)?;
+                let tx_type = tx_type.map(|t| serde_json::from_str(&t)).transpose()?;
 
                 let confirmation_time = match (height, timestamp) {
                     (Some(height), Some(timestamp)) => Some(BlockTime { height, timestamp }),
@@ -494,6 +619,7 @@ impl SqliteDatabase {
                     sent,
                     fee,
                     confirmation_time,
+                    tx_type,
                 }))
             }
             None => Ok(None),
@@ -535,6 +661,18 @@ impl SqliteDatabase {
         }
     }
 
+    fn select_schema_version(&self) -> Result<Option<u32>, Error> {
+        let mut statement = self
+            .connection
+            .prepare_cached("SELECT version FROM schema_version WHERE id = 0")?;
+        let mut rows = statement.query([])?;
+
+        match rows.next()? {
+            Some(row) => Ok(Some(row.get(0)?)),
+            None => Ok(None),
+        }
+    }
+
     fn select_checksum_by_keychain(&self, keychain: String) -> Result<Option<Vec<u8>>, Error> {
         let mut statement = self
             .connection
@@ -619,6 +757,22 @@ impl SqliteDatabase {
         statement.execute([])?;
         Ok(())
     }
+
+    fn delete_pending_tx_by_txid(&self, txid: &[u8]) -> Result<(), Error> {
+        let mut statement = self
+            .connection
+            .prepare_cached("DELETE FROM pending_txs WHERE txid=:txid")?;
+        statement.execute(named_params! {":txid": txid})?;
+        Ok(())
+    }
+
+    fn delete_block_hash_by_height(&self, height: u32) -> Result<(), Error> {
+        let mut statement = self
+            .connection
+            .prepare_cached("DELETE FROM block_hashes WHERE height=:height")?;
+        statement.execute(named_params! {":height": height})?;
+        Ok(())
+    }
 }
 
 impl BatchOperations for SqliteDatabase {
@@ -641,6 +795,8 @@ impl BatchOperations for SqliteDatabase {
             &utxo.outpoint.txid,
             utxo.txout.script_pubkey.as_bytes(),
             utxo.is_spent,
+            utxo.derivation_index,
+            utxo.suspected_dust,
         )?;
         Ok(())
     }
@@ -679,11 +835,26 @@ impl BatchOperations for SqliteDatabase {
         Ok(())
     }
 
+    fn set_pending_tx(&mut self, transaction: &Transaction) -> Result<(), Error> {
+        self.insert_pending_tx(&transaction.txid(), &serialize(transaction))?;
+        Ok(())
+    }
+
+    fn set_block_hash(&mut self, height: u32, hash: &BlockHash) -> Result<(), Error> {
+        self.insert_block_hash(height, &serialize(hash))?;
+        Ok(())
+    }
+
     fn set_sync_time(&mut self, ct: SyncTime) -> Result<(), Error> {
         self.update_sync_time(ct)?;
         Ok(())
     }
 
+    fn set_schema_version(&mut self, version: u32) -> Result<(), Error> {
+        self.update_schema_version(version)?;
+        Ok(())
+    }
+
     fn del_script_pubkey_from_path(
         &mut self,
         keychain: KeychainKind,
@@ -773,6 +944,26 @@ impl BatchOperations for SqliteDatabase {
             None => Ok(None),
         }
     }
+
+    fn del_pending_tx(&mut self, txid: &Txid) -> Result<Option<Transaction>, Error> {
+        match self.select_pending_tx_by_txid(txid)? {
+            Some(tx) => {
+                self.delete_pending_tx_by_txid(txid)?;
+                Ok(Some(tx))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn del_block_hash(&mut self, height: u32) -> Result<Option<BlockHash>, Error> {
+        match self.select_block_hash_by_height(height)? {
+            Some(hash) => {
+                self.delete_block_hash_by_height(height)?;
+                Ok(Some(hash))
+            }
+            None => Ok(None),
+        }
+    }
 }
 
 impl Database for SqliteDatabase {
@@ -816,6 +1007,14 @@ impl Database for SqliteDatabase {
         self.select_transactions()
     }
 
+    fn iter_pending_txs(&self) -> Result<Vec<Transaction>, Error> {
+        self.select_pending_txs()
+    }
+
+    fn iter_block_hashes(&self) -> Result<Vec<(u32, BlockHash)>, Error> {
+        self.select_block_hashes()
+    }
+
     fn iter_txs(&self, include_raw: bool) -> Result<Vec<TransactionDetails>, Error> {
         match include_raw {
             true => self.select_transaction_details_with_raw(),
@@ -878,6 +1077,10 @@ impl Database for SqliteDatabase {
         self.select_sync_time()
     }
 
+    fn get_schema_version(&self) -> Result<Option<u32>, Error> {
+        self.select_schema_version()
+    }
+
     fn increment_last_index(&mut self, keychain: KeychainKind) -> Result<u32, Error> {
         let keychain_string = serde_json::to_string(&keychain)?;
         match self.get_last_index(keychain)? {
@@ -1031,6 +1234,21 @@ pub mod test {
         crate::database::test::test_sync_time(get_database());
     }
 
+    #[test]
+    fn test_pending_tx() {
+        crate::database::test::test_pending_tx(get_database());
+    }
+
+    #[test]
+    fn test_block_hashes() {
+        crate::database::test::test_block_hashes(get_database());
+    }
+
+    #[test]
+    fn test_migrate() {
+        crate::database::test::test_migrate(get_database());
+    }
+
     #[test]
     fn test_txs() {
         crate::database::test::test_list_transaction(get_database());
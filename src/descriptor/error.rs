@@ -22,6 +22,8 @@ pub enum Error {
     HardenedDerivationXpub,
     /// The descriptor contains multiple keys with the same BIP32 fingerprint
     DuplicatedKeys,
+    /// Invalid BIP389 multipath descriptor, or one with an unexpected number of paths
+    InvalidMultipathDescriptor,
 
     /// Error thrown while working with [`keys`](crate::keys)
     Key(crate::keys::KeyError),
@@ -195,6 +195,71 @@ impl IntoWalletDescriptor for DescriptorTemplateOut {
     }
 }
 
+/// Expand a [BIP389] multipath descriptor into one descriptor per path
+///
+/// Every `<a;b;...>` derivation step is replaced by its n-th element in the n-th descriptor, so
+/// `wpkh(xpub/<0;1>/*)` becomes `wpkh(xpub/0/*)` and `wpkh(xpub/1/*)`. A descriptor without any
+/// multipath step is returned as is. The checksum, if present, is checked and dropped.
+///
+/// [BIP389]: https://github.com/bitcoin/bips/blob/master/bip-0389.mediawiki
+pub fn expand_multipath(descriptor: &str) -> Result<Vec<String>, DescriptorError> {
+    let descriptor = match descriptor.split_once('#') {
+        Some((descriptor, checksum)) => {
+            if get_checksum(descriptor)? != checksum {
+                return Err(DescriptorError::InvalidDescriptorChecksum);
+            }
+            descriptor
+        }
+        None => descriptor,
+    };
+
+    let mut literals = vec![];
+    let mut steps = vec![];
+    let mut rest = descriptor;
+    while let Some(start) = rest.find('<') {
+        let end = rest[start..]
+            .find('>')
+            .map(|end| start + end)
+            .ok_or(DescriptorError::InvalidMultipathDescriptor)?;
+        let step = rest[start + 1..end].split(';').collect::<Vec<_>>();
+        if step.len() < 2
+            || step
+                .iter()
+                .any(|index| index.is_empty() || index.contains('<'))
+        {
+            return Err(DescriptorError::InvalidMultipathDescriptor);
+        }
+
+        literals.push(&rest[..start]);
+        steps.push(step);
+        rest = &rest[end + 1..];
+    }
+    if rest.contains('>') {
+        return Err(DescriptorError::InvalidMultipathDescriptor);
+    }
+
+    let paths = match steps.first() {
+        Some(step) => step.len(),
+        None => return Ok(vec![descriptor.to_string()]),
+    };
+    // all the multipath steps must have the same number of elements
+    if steps.iter().any(|step| step.len() != paths) {
+        return Err(DescriptorError::InvalidMultipathDescriptor);
+    }
+
+    Ok((0..paths)
+        .map(|path| {
+            let mut expanded = String::with_capacity(descriptor.len());
+            for (literal, step) in literals.iter().zip(&steps) {
+                expanded.push_str(literal);
+                expanded.push_str(step[path]);
+            }
+            expanded.push_str(rest);
+            expanded
+        })
+        .collect())
+}
+
 /// Wrapper for `IntoWalletDescriptor` that performs additional checks on the keys contained in the
 /// descriptor
 pub(crate) fn into_wallet_descriptor_checked<T: IntoWalletDescriptor>(
@@ -673,6 +738,60 @@ mod test {
     use super::*;
     use crate::psbt::PsbtUtils;
 
+    #[test]
+    fn test_expand_multipath() {
+        let tpub = "tpubD6NzVbkrYhZ4XHndKkuB8FifXm8r5FQHwrN6oZuWCz13qb93rtgKvD4PQsqC4HP4yhV3tA2fqr2RbY5mNXfM7RxXUoeABoDtsFUq2zJq6YK";
+
+        let expanded = expand_multipath(&format!("wpkh({}/<0;1>/*)", tpub)).unwrap();
+        assert_eq!(
+            expanded,
+            vec![format!("wpkh({}/0/*)", tpub), format!("wpkh({}/1/*)", tpub)]
+        );
+
+        // every multipath step takes the element with the same position
+        let expanded = expand_multipath(&format!(
+            "wsh(multi(1,{0}/<0;1;2>/*,{0}/7/<3;4;5>/*))",
+            tpub
+        ))
+        .unwrap();
+        assert_eq!(
+            expanded[2],
+            format!("wsh(multi(1,{0}/2/*,{0}/7/5/*))", tpub)
+        );
+
+        // the checksum covers the multipath descriptor
+        let descriptor = format!("wpkh({}/<0;1>/*)", tpub);
+        let checksum = get_checksum(&descriptor).unwrap();
+        assert_eq!(
+            expand_multipath(&format!("{}#{}", descriptor, checksum)).unwrap(),
+            expand_multipath(&descriptor).unwrap()
+        );
+        assert!(matches!(
+            expand_multipath(&format!("{}#aaaaaaaa", descriptor)),
+            Err(DescriptorError::InvalidDescriptorChecksum)
+        ));
+
+        let single = format!("wpkh({}/0/*)", tpub);
+        assert_eq!(expand_multipath(&single).unwrap(), vec![single.clone()]);
+
+        for invalid in &[
+            format!("wpkh({}/<0>/*)", tpub),
+            format!("wpkh({}/<0;>/*)", tpub),
+            format!("wpkh({}/<0;1/*)", tpub),
+            format!("wpkh({}/0;1>/*)", tpub),
+            format!("wsh(multi(1,{0}/<0;1>/*,{0}/<0;1;2>/*))", tpub),
+        ] {
+            assert!(
+                matches!(
+                    expand_multipath(invalid),
+                    Err(DescriptorError::InvalidMultipathDescriptor)
+                ),
+                "{} should be invalid",
+                invalid
+            );
+        }
+    }
+
     #[test]
     fn test_derive_from_psbt_input_wpkh_wif() {
         let descriptor = Descriptor::<DescriptorPublicKey>::from_str(
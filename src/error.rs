@@ -13,7 +13,7 @@ use std::fmt;
 
 use crate::bitcoin::Network;
 use crate::{descriptor, wallet, wallet::address_validator};
-use bitcoin::OutPoint;
+use bitcoin::{OutPoint, Txid};
 
 /// Errors that can be thrown by the [`Wallet`](crate::wallet::Wallet)
 #[derive(Debug)]
@@ -30,6 +30,15 @@ pub enum Error {
     NoUtxosSelected,
     /// Output created is under the dust limit, 546 satoshis
     OutputBelowDustLimit(usize),
+    /// Data of an OP_RETURN output larger than the standardness limit of 80 bytes, see
+    /// [`TxBuilder::add_data`](crate::wallet::tx_builder::TxBuilder::add_data)
+    OpReturnTooLarge(usize),
+    /// A standard transaction can only have one OP_RETURN output, see
+    /// [`TxBuilder::add_data`](crate::wallet::tx_builder::TxBuilder::add_data)
+    MultipleOpReturn,
+    /// The nSequence of an input that isn't spent by the transaction was set, see
+    /// [`TxBuilder::set_sequence`](crate::wallet::tx_builder::TxBuilder::set_sequence)
+    InputNotSelected(OutPoint),
     /// Wallet's UTXO set is not enough to cover recipient's requested plus fee
     InsufficientFunds {
         /// Sats needed for some transaction
@@ -73,6 +82,9 @@ pub enum Error {
     Key(crate::keys::KeyError),
     /// Descriptor checksum mismatch
     ChecksumMismatch,
+    /// Data read from an encrypted database couldn't be decrypted, usually because the key is
+    /// wrong
+    Decryption,
     /// Spending policy is not compatible with this [`KeychainKind`](crate::types::KeychainKind)
     SpendingPolicyRequired(crate::types::KeychainKind),
     /// Error while extracting and manipulating policies
@@ -96,6 +108,40 @@ pub enum Error {
     ProgressUpdateError,
     /// Requested outpoint doesn't exist in the tx (vout greater than available outputs)
     InvalidOutpoint(OutPoint),
+    /// Transaction heavier than the standardness limit, it should be split into smaller
+    /// transactions (see [`BroadcastPolicy`](crate::blockchain::BroadcastPolicy))
+    TxTooLarge {
+        /// Weight of the transaction
+        weight: usize,
+    },
+    /// The merkle proof of a transaction returned by the server doesn't match the header of the
+    /// block it's reported to be confirmed in
+    InvalidMerkleProof(Txid),
+    /// The header of the block at this height isn't in the trusted [`HeaderChain`] used to check
+    /// merkle proofs
+    ///
+    /// [`HeaderChain`]: crate::blockchain::HeaderChain
+    MissingTrustedHeader(u32),
+    /// A transaction of a batch failed to broadcast, see
+    /// [`Blockchain::broadcast_batch`](crate::blockchain::Blockchain::broadcast_batch)
+    BatchBroadcast {
+        /// Txids of the transactions of the batch that were broadcast
+        broadcast: Vec<Txid>,
+        /// Txid of the first transaction that failed to broadcast
+        failed: Txid,
+        /// Error returned when broadcasting the failed transaction
+        error: Box<Error>,
+    },
+    /// Every backend of a [`FailoverBlockchain`](crate::blockchain::FailoverBlockchain) failed,
+    /// with the error returned by each of them in order
+    AllBackendsFailed(Vec<Error>),
+    /// The sync was interrupted through a [`CancelToken`](crate::blockchain::CancelToken)
+    /// before completing, nothing was committed to the database
+    SyncAborted,
+    /// The number of requests per second of a
+    /// [`RateLimiter`](crate::blockchain::rate_limited::RateLimiter) must be finite and greater
+    /// than zero
+    InvalidRateLimit(f64),
 
     /// Error related to the parsing and usage of descriptors
     Descriptor(crate::descriptor::error::Error),
@@ -128,6 +174,9 @@ pub enum Error {
     #[cfg(feature = "electrum")]
     /// Electrum client error
     Electrum(electrum_client::Error),
+    #[cfg(feature = "async-electrum")]
+    /// Async Electrum client error
+    AsyncElectrum(crate::blockchain::async_electrum::AsyncElectrumError),
     #[cfg(feature = "esplora")]
     /// Esplora client error
     Esplora(Box<crate::blockchain::esplora::EsploraError>),
@@ -193,6 +242,11 @@ impl_error!(bitcoin::util::psbt::PsbtParseError, PsbtParse);
 
 #[cfg(feature = "electrum")]
 impl_error!(electrum_client::Error, Electrum);
+#[cfg(feature = "async-electrum")]
+impl_error!(
+    crate::blockchain::async_electrum::AsyncElectrumError,
+    AsyncElectrum
+);
 #[cfg(feature = "key-value-db")]
 impl_error!(sled::Error, Sled);
 #[cfg(feature = "rpc")]
@@ -188,6 +188,7 @@ fn main() -> Result<(), bdk::Error> {
 //!
 //! * `all-keys`: all features for working with bitcoin keys
 //! * `async-interface`: async functions in bdk traits
+//! * `async-electrum`: [`async_electrum`](crate::blockchain::async_electrum) async client protocol for interacting with electrum servers, enables `async-interface`
 //! * `keys-bip39`: [BIP-39](https://github.com/bitcoin/bips/blob/master/bip-0039.mediawiki) mnemonic codes for generating deterministic keys
 //!
 //! # Internal features
@@ -197,6 +198,7 @@ fn main() -> Result<(), bdk::Error> {
 //!
 //! * `compact_filters`: [`compact_filters`](crate::blockchain::compact_filters) client protocol for interacting with the bitcoin P2P network
 //! * `electrum`: [`electrum`](crate::blockchain::electrum) client protocol for interacting with electrum servers
+//! * `electrum-cert-expiry`: check the expiry of the TLS certificate of [`electrum`](crate::blockchain::electrum) servers, see [`ElectrumBlockchain::cert_expiry`](crate::blockchain::electrum::ElectrumBlockchain::cert_expiry)
 //! * `esplora`: [`esplora`](crate::blockchain::esplora) client protocol for interacting with blockstream [electrs](https://github.com/Blockstream/electrs) servers
 //! * `key-value-db`: key value [`database`](crate::database) based on [`sled`](crate::sled) for caching blockchain data
 
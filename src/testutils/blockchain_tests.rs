@@ -296,9 +296,18 @@ impl TestClient {
         );
     }
 
-    pub fn reorg(&mut self, num_blocks: u64) {
-        self.invalidate(num_blocks);
-        self.generate(num_blocks, None);
+    /// Mine `n` blocks paying to `to`, or to a new address of the node
+    pub fn mine_blocks(&mut self, n: usize, to: Option<Address>) {
+        self.generate(n as u64, to);
+    }
+
+    /// Replace the last `depth` blocks with a competing branch of `depth + 1` blocks
+    ///
+    /// The transactions of the invalidated blocks go back to the mempool, so they're mined again
+    /// in the first block of the new branch.
+    pub fn reorg(&mut self, depth: usize) {
+        self.invalidate(depth as u64);
+        self.mine_blocks(depth + 1, None);
     }
 
     pub fn get_node_address(&self, address_type: Option<AddressType>) -> Address {
@@ -463,6 +472,64 @@ macro_rules! bdk_blockchain_tests {
                 assert_eq!(list_tx_item.confirmation_time, None, "incorrect confirmation time");
             }
 
+            // the RPC blockchain doesn't check for cancellation
+            #[cfg(not(any(feature = "test-rpc", feature = "test-rpc-legacy")))]
+            #[test]
+            fn test_sync_cancelled() {
+                use std::ops::Deref;
+                use std::sync::atomic::{AtomicBool, Ordering};
+                use crate::blockchain::Progress;
+                use crate::database::Database;
+
+                /// Cancel the sync after the first chunk of requests
+                #[derive(Debug, Default)]
+                struct CancelAfterFirstChunk(AtomicBool);
+
+                impl Progress for CancelAfterFirstChunk {
+                    fn update(&self, _progress: f32, _message: Option<String>) -> Result<(), crate::Error> {
+                        Ok(())
+                    }
+
+                    fn is_cancelled(&self) -> bool {
+                        self.0.swap(true, Ordering::SeqCst)
+                    }
+                }
+
+                let (wallet, blockchain, descriptors, mut test_client) = init_single_sig();
+                test_client.receive(testutils! {
+                    @tx ( (@external descriptors, 0) => 50_000 )
+                });
+
+                let result = wallet.sync(&blockchain, SyncOptions {
+                    progress: Some(Box::new(CancelAfterFirstChunk::default())),
+                    ..Default::default()
+                });
+                assert!(matches!(result, Err(crate::Error::SyncAborted)), "sync not aborted");
+                assert!(wallet.list_transactions(false).unwrap().is_empty(), "partial sync committed");
+                assert!(wallet.list_unspent().unwrap().is_empty(), "partial sync committed");
+                assert!(wallet.database().deref().get_sync_time().unwrap().is_none(), "sync_time updated");
+
+                wallet.sync(&blockchain, SyncOptions::default()).unwrap();
+                assert_eq!(wallet.get_balance().unwrap(), 50_000, "incorrect balance");
+            }
+
+            #[test]
+            fn test_get_block_header() {
+                use $crate::bitcoin::blockdata::constants::genesis_block;
+                use $crate::blockchain::{GetBlockHash, GetHeight};
+
+                let (_, blockchain, _, _) = init_single_sig();
+                let genesis = genesis_block(Network::Regtest);
+
+                assert_eq!(blockchain.get_block_header(0).unwrap(), Some(genesis.header));
+                assert_eq!(blockchain.get_block_hash(0).unwrap(), Some(genesis.block_hash()));
+
+                let tip = blockchain.get_height().unwrap();
+                let tip_header = blockchain.get_block_header(tip).unwrap().unwrap();
+                assert_eq!(Some(tip_header.block_hash()), blockchain.get_block_hash(tip).unwrap());
+                assert_eq!(blockchain.get_block_header(tip + 1_000).unwrap(), None);
+            }
+
             #[test]
             fn test_sync_stop_gap_20() {
                 let (wallet, blockchain, descriptors, mut test_client) = init_single_sig();
@@ -623,6 +690,70 @@ macro_rules! bdk_blockchain_tests {
                 assert_eq!(list_tx_item.confirmation_time, None, "incorrect confirmation time after invalidate");
             }
 
+            // the RPC blockchain doesn't track the hashes of the blocks confirming the transactions
+            #[cfg(not(any(feature = "esplora", feature = "test-rpc", feature = "test-rpc-legacy")))]
+            #[test]
+            fn test_sync_reorged_txids() {
+                use $crate::blockchain::{NoopProgress, WalletSync};
+                use $crate::database::{BatchOperations, Database};
+                use $crate::wallet::AddressIndex;
+
+                let (wallet, blockchain, descriptors, mut test_client) = init_single_sig();
+                let txid = test_client.receive(testutils! {
+                    @tx ( (@external descriptors, 0) => 50_000 ) ( @confirmations 1 )
+                });
+
+                let mut database = MemoryDatabase::new();
+                let script_pubkey = wallet.get_address(AddressIndex::Peek(0)).unwrap().script_pubkey();
+                database.set_script_pubkey(&script_pubkey, KeychainKind::External, 0).unwrap();
+
+                let stats = blockchain.wallet_setup_with_stats(&mut database, Box::new(NoopProgress)).unwrap();
+                assert!(stats.reorged_txids.is_empty(), "unexpected reorged txids");
+                let height = database.get_tx(&txid, false).unwrap().unwrap().confirmation_time.unwrap().height;
+
+                // the transaction is mined again at the same height, in a different block
+                test_client.reorg(1);
+
+                let stats = blockchain.wallet_setup_with_stats(&mut database, Box::new(NoopProgress)).unwrap();
+                assert_eq!(stats.reorged_txids, vec![txid], "incorrect reorged txids");
+                let confirmation_time = database.get_tx(&txid, false).unwrap().unwrap().confirmation_time;
+                assert_eq!(confirmation_time.map(|time| time.height), Some(height), "incorrect height after reorg");
+
+                let stats = blockchain.wallet_setup_with_stats(&mut database, Box::new(NoopProgress)).unwrap();
+                assert!(stats.reorged_txids.is_empty(), "reorg reported twice");
+            }
+
+            // the RPC blockchain doesn't track the hashes of the blocks confirming the transactions
+            #[cfg(not(any(feature = "esplora", feature = "test-rpc", feature = "test-rpc-legacy")))]
+            #[test]
+            fn test_sync_deep_reorg() {
+                use $crate::blockchain::{NoopProgress, WalletSync};
+                use $crate::database::{BatchOperations, Database};
+                use $crate::wallet::AddressIndex;
+
+                let (wallet, blockchain, descriptors, mut test_client) = init_single_sig();
+                let txid = test_client.receive(testutils! {
+                    @tx ( (@external descriptors, 0) => 50_000 ) ( @confirmations 1 )
+                });
+                test_client.mine_blocks(2, None);
+
+                let mut database = MemoryDatabase::new();
+                let script_pubkey = wallet.get_address(AddressIndex::Peek(0)).unwrap().script_pubkey();
+                database.set_script_pubkey(&script_pubkey, KeychainKind::External, 0).unwrap();
+
+                blockchain.wallet_setup_with_stats(&mut database, Box::new(NoopProgress)).unwrap();
+                let height = database.get_tx(&txid, false).unwrap().unwrap().confirmation_time.unwrap().height;
+
+                // the block confirming the transaction and the two above it are replaced, the
+                // transaction is mined again in the first block of the new branch
+                test_client.reorg(3);
+
+                let stats = blockchain.wallet_setup_with_stats(&mut database, Box::new(NoopProgress)).unwrap();
+                assert_eq!(stats.reorged_txids, vec![txid], "incorrect reorged txids");
+                let confirmation_time = database.get_tx(&txid, false).unwrap().unwrap().confirmation_time;
+                assert_eq!(confirmation_time.map(|time| time.height), Some(height - 2), "incorrect height after reorg");
+            }
+
             #[test]
             fn test_sync_after_send() {
                 let (wallet, blockchain, descriptors, mut test_client) = init_single_sig();
@@ -787,6 +918,50 @@ macro_rules! bdk_blockchain_tests {
                 assert_eq!(sent.fee.unwrap_or(0), details.fee.unwrap_or(0), "incorrect fees from sender");
             }
 
+            #[test]
+            fn test_sync_multipath_descriptor() {
+                use $crate::wallet::AddressIndex;
+
+                let descriptor = "wpkh(tprv8ZgxMBicQKsPd3EupYiPRhaMooHKUHJxNsTfYuScep13go8QFfHdtkG9nRkFGb7busX4isf6X9dURGCoKgitaApQ6MupRhZMcELAxTBRJgS/84'/1'/0'/<0;1>/*)";
+                let mut test_client = TestClient::default();
+                let blockchain = get_blockchain(&test_client);
+                let wallet = Wallet::new_multipath(descriptor, Network::Regtest, MemoryDatabase::new()).unwrap();
+                let node_addr = test_client.get_node_address(None);
+
+                #[cfg(any(feature = "test-rpc", feature = "test-rpc-legacy"))]
+                wallet.sync(&blockchain, SyncOptions::default()).unwrap();
+
+                let address = wallet.get_address(AddressIndex::New).unwrap().address;
+                let received_txid = test_client.receive(testutils! {
+                    @tx ( (@addr address) => 50_000 )
+                });
+                wallet.sync(&blockchain, SyncOptions::default()).unwrap();
+
+                // the change goes to the internal keychain
+                let mut builder = wallet.build_tx();
+                builder.add_recipient(node_addr.script_pubkey(), 25_000);
+                let (mut psbt, details) = builder.finish().unwrap();
+                assert!(wallet.sign(&mut psbt, Default::default()).unwrap(), "Cannot finalize transaction");
+                let sent_tx = psbt.extract_tx();
+                blockchain.broadcast(&sent_tx).unwrap();
+
+                #[cfg(feature = "rpc")]  // rpc cannot see mempool tx before importmulti
+                test_client.generate(1, Some(node_addr));
+
+                // restore the wallet from the multipath descriptor alone
+                let wallet = Wallet::new_multipath(descriptor, Network::Regtest, MemoryDatabase::new()).unwrap();
+                wallet.sync(&blockchain, SyncOptions::default()).unwrap();
+
+                let txids = wallet.list_transactions(false).unwrap().into_iter().map(|tx| tx.txid).collect::<std::collections::HashSet<_>>();
+                assert!(txids.contains(&received_txid), "receive history not discovered");
+                assert!(txids.contains(&sent_tx.txid()), "change history not discovered");
+
+                let unspent = wallet.list_unspent().unwrap();
+                assert_eq!(unspent.len(), 1, "incorrect number of unspents");
+                assert_eq!(unspent[0].keychain, KeychainKind::Internal, "change not found on the internal keychain");
+                assert_eq!(wallet.get_balance().unwrap(), details.received, "incorrect balance");
+            }
+
             #[test]
             fn test_sync_long_change_chain() {
                 let (wallet, blockchain, descriptors, mut test_client) = init_single_sig();
@@ -981,7 +1156,7 @@ macro_rules! bdk_blockchain_tests {
 
                 let mut builder = wallet.build_tx();
                 let data = [42u8;80];
-                builder.add_data(&data);
+                builder.add_data(&data).unwrap();
                 let (mut psbt, details) = builder.finish().unwrap();
 
                 let finalized = wallet.sign(&mut psbt, Default::default()).unwrap();
@@ -16,6 +16,8 @@ pub mod blockchain_tests;
 
 use bitcoin::{Address, Txid};
 
+use crate::KeychainKind;
+
 #[derive(Clone, Debug)]
 pub struct TestIncomingInput {
     pub txid: Txid,
@@ -46,6 +48,8 @@ impl TestIncomingInput {
 pub struct TestIncomingOutput {
     pub value: u64,
     pub to_address: String,
+    /// Keychain of the output, only used by `populate_test_db!` which defaults to `External`
+    pub keychain: Option<KeychainKind>,
 }
 
 impl TestIncomingOutput {
@@ -53,6 +57,7 @@ impl TestIncomingOutput {
         Self {
             value,
             to_address: to_address.to_string(),
+            keychain: None,
         }
     }
 }
@@ -64,6 +69,8 @@ pub struct TestIncomingTx {
     pub min_confirmations: Option<u64>,
     pub locktime: Option<i64>,
     pub replaceable: Option<bool>,
+    /// Version of the transaction, only used by `populate_test_db!` which defaults to 1
+    pub version: Option<i32>,
 }
 
 impl TestIncomingTx {
@@ -80,6 +87,7 @@ impl TestIncomingTx {
             min_confirmations,
             locktime,
             replaceable,
+            version: None,
         }
     }
 
@@ -121,8 +129,11 @@ macro_rules! testutils {
     ( @i $descriptors:expr, $child:expr ) => ({ testutils!(@internal $descriptors, $child) });
     ( @addr $addr:expr ) => ({ $addr });
 
-    ( @tx ( $( ( $( $addr:tt )* ) => $amount:expr ),+ ) $( ( @inputs $( ($txid:expr, $vout:expr) ),+ ) )? $( ( @locktime $locktime:expr ) )? $( ( @confirmations $confirmations:expr ) )? $( ( @replaceable $replaceable:expr ) )? ) => ({
-        let outs = vec![$( $crate::testutils::TestIncomingOutput::new($amount, testutils!( $($addr)* ))),+];
+    ( @tx ( $( ( $( $addr:tt )* ) => $amount:expr $( ; $keychain:expr )? ),+ ) $( ( @inputs $( ($txid:expr, $vout:expr) ),+ ) )? $( ( @locktime $locktime:expr ) )? $( ( @version $version:expr ) )? $( ( @confirmations $confirmations:expr ) )? $( ( @replaceable $replaceable:expr ) )? ) => ({
+        let outs = vec![$( $crate::testutils::TestIncomingOutput {
+            keychain: None::<$crate::KeychainKind>$(.or(Some($keychain)))?,
+            ..$crate::testutils::TestIncomingOutput::new($amount, testutils!( $($addr)* ))
+        }),+];
         let _ins: Vec<$crate::testutils::TestIncomingInput> = vec![];
         $(
             let _ins = vec![$( $crate::testutils::TestIncomingInput { txid: $txid, vout: $vout, sequence: None }),+];
@@ -133,7 +144,9 @@ macro_rules! testutils {
         let min_confirmations = None::<u64>$(.or(Some($confirmations)))?;
         let replaceable = None::<bool>$(.or(Some($replaceable)))?;
 
-        $crate::testutils::TestIncomingTx::new(_ins, outs, min_confirmations, locktime, replaceable)
+        let mut tx = $crate::testutils::TestIncomingTx::new(_ins, outs, min_confirmations, locktime, replaceable);
+        tx.version = None::<i32>$(.or(Some($version)))?;
+        tx
     });
 
     ( @literal $key:expr ) => ({
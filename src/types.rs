@@ -9,6 +9,7 @@
 // You may not use this file except in accordance with one or both of these
 // licenses.
 
+use std::cmp::Ordering;
 use std::convert::AsRef;
 use std::ops::Sub;
 
@@ -46,7 +47,9 @@ impl AsRef<[u8]> for KeychainKind {
 }
 
 /// Fee rate
-#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
+///
+/// Fee rates are totally ordered, so they can be compared with [`Ord::max`] and [`Ord::min`].
+#[derive(Debug, Copy, Clone)]
 // Internally stored as satoshi/vbyte
 pub struct FeeRate(f32);
 
@@ -82,6 +85,56 @@ impl FeeRate {
         self.0
     }
 
+    /// Return the value as satoshi/vbyte
+    ///
+    /// Same as [`FeeRate::as_sat_vb`], named after [`FeeRate::from_sat_per_vb`].
+    pub fn as_sat_per_vb(&self) -> f32 {
+        self.0
+    }
+
+    /// Add two fee rates, returning `None` if the result isn't finite
+    pub fn checked_add(self, other: FeeRate) -> Option<FeeRate> {
+        let rate = self.0 + other.0;
+        if rate.is_finite() {
+            Some(FeeRate(rate))
+        } else {
+            None
+        }
+    }
+
+    /// Multiply the fee rate by `factor`, returning `None` if the result isn't finite or is
+    /// negative
+    pub fn checked_mul(self, factor: f32) -> Option<FeeRate> {
+        let rate = self.0 * factor;
+        if rate.is_finite() && rate >= 0.0 {
+            Some(FeeRate(rate))
+        } else {
+            None
+        }
+    }
+
+    /// Return the fee rate to use to replace a transaction paying this fee rate
+    ///
+    /// The fee rate is increased by at least 1 sat/vbyte and rounded up to a whole number of
+    /// sat/vbyte, ignoring the tiny errors introduced by the conversions from other units. The
+    /// result is never below `min_relay`.
+    pub fn bumped(&self, min_relay: FeeRate) -> FeeRate {
+        let rate = self.0 + 1.0;
+        let rate = if rate - rate.floor() < 1e-4 {
+            rate.floor()
+        } else {
+            rate.ceil()
+        };
+
+        FeeRate(rate).max(min_relay)
+    }
+
+    /// Key that orders the fee rates like [`f32::total_cmp`], which isn't available on our MSRV
+    fn total_order_key(&self) -> i32 {
+        let bits = self.0.to_bits() as i32;
+        bits ^ ((((bits >> 31) as u32) >> 1) as i32)
+    }
+
     /// Calculate absolute fee in Satoshis using size in weight units.
     pub fn fee_wu(&self, wu: usize) -> u64 {
         self.fee_vb(wu.vbytes())
@@ -99,6 +152,26 @@ impl std::default::Default for FeeRate {
     }
 }
 
+impl PartialEq for FeeRate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for FeeRate {}
+
+impl PartialOrd for FeeRate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for FeeRate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.total_order_key().cmp(&other.total_order_key())
+    }
+}
+
 impl Sub for FeeRate {
     type Output = Self;
 
@@ -133,6 +206,21 @@ pub struct LocalUtxo {
     pub keychain: KeychainKind,
     /// Whether this UTXO is spent or not
     pub is_spent: bool,
+    /// Child number of the script pubkey in the descriptor of `keychain`
+    ///
+    /// `None` if the UTXO was stored before its derivation index was tracked, or if its script
+    /// pubkey isn't derived from one of the wallet descriptors.
+    #[serde(default)]
+    pub derivation_index: Option<u32>,
+    /// Whether this UTXO is a tiny unsolicited output, possibly sent as part of a dust attack
+    ///
+    /// Set by [`Wallet::sync`](crate::Wallet::sync) when
+    /// [`SyncOptions::dust_attack_threshold`](crate::SyncOptions::dust_attack_threshold) is set,
+    /// and cleared by the syncs without it. These UTXOs are only selected when creating a
+    /// transaction with [`TxBuilder::include_suspected_dust`](crate::TxBuilder::include_suspected_dust),
+    /// spending them could link the addresses of the wallet together.
+    #[serde(default)]
+    pub suspected_dust: bool,
 }
 
 /// A [`Utxo`] with its `satisfaction_weight`.
@@ -208,11 +296,62 @@ pub struct TransactionDetails {
     /// Fee value (sats) if available.
     /// The availability of the fee depends on the backend. It's never `None` with an Electrum
     /// Server backend, but it could be `None` with a Bitcoin RPC node without txindex that receive
-    /// funds while offline.
+    /// funds while offline, or with an Esplora backend in block scan mode for the transactions
+    /// spending the outputs of other wallets.
     pub fee: Option<u64>,
     /// If the transaction is confirmed, contains height and timestamp of the block containing the
     /// transaction, unconfirmed transaction contains `None`.
     pub confirmation_time: Option<BlockTime>,
+    /// Type of the transaction from the point of view of the wallet, set during sync
+    ///
+    /// It's `None` for transactions that haven't been synced yet, like the ones just created by
+    /// the wallet.
+    pub tx_type: Option<TxType>,
+}
+
+impl TransactionDetails {
+    /// Fee rate paid by the transaction, computed from its fee and its weight
+    ///
+    /// Returns `None` if either the fee or the transaction isn't available.
+    pub fn fee_rate(&self) -> Option<FeeRate> {
+        let fee = self.fee?;
+        let weight = self.transaction.as_ref()?.weight();
+        Some(FeeRate::from_wu(fee, weight))
+    }
+}
+
+/// Type of a wallet transaction, see [`TransactionDetails::tx_type`]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum TxType {
+    /// Doesn't spend any output of the wallet
+    Receive,
+    /// Spends outputs of the wallet and pays at least one output not owned by the wallet
+    Send,
+    /// Spends outputs of the wallet and only pays back to the wallet
+    SelfTransfer,
+    /// Spends multiple outputs of the wallet into a single output owned by the wallet
+    Consolidation,
+    /// Coinbase transaction paying to the wallet
+    CoinbaseReward,
+}
+
+impl TxType {
+    /// Classify `tx` given the value it spends from the wallet (`sent`) and the number of its
+    /// outputs owned by the wallet
+    pub(crate) fn classify(tx: &Transaction, sent: u64, owned_outputs: usize) -> Self {
+        if tx.is_coin_base() {
+            TxType::CoinbaseReward
+        } else if sent == 0 {
+            TxType::Receive
+        } else if owned_outputs < tx.output.len() {
+            TxType::Send
+        } else if tx.input.len() > 1 && tx.output.len() == 1 {
+            TxType::Consolidation
+        } else {
+            TxType::SelfTransfer
+        }
+    }
 }
 
 /// Block height and timestamp of a block
@@ -249,4 +388,100 @@ mod tests {
         const _MY_RATE: FeeRate = FeeRate::from_sat_per_vb(10.0);
         const _MIN_RELAY: FeeRate = FeeRate::default_min_relay_fee();
     }
+
+    #[test]
+    fn test_transaction_details_fee_rate() {
+        use bitcoin::{Script, TxIn, Witness};
+
+        // p2wpkh spend: 82 bytes of base data and 110 bytes of witness data (with the marker and
+        // the flag), so 438 weight units or 110 vbytes
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::default(),
+                script_sig: Script::new(),
+                sequence: 0xFFFFFFFF,
+                witness: Witness::from_vec(vec![vec![0; 72], vec![0; 33]]),
+            }],
+            output: vec![TxOut {
+                value: 50_000,
+                script_pubkey: Script::from(vec![0; 22]),
+            }],
+        };
+        assert_eq!(tx.weight(), 438);
+
+        let mut details = TransactionDetails {
+            transaction: Some(tx),
+            fee: Some(1_100),
+            ..Default::default()
+        };
+        assert_eq!(details.fee_rate(), Some(FeeRate::from_sat_per_vb(10.0)));
+
+        details.fee = None;
+        assert_eq!(details.fee_rate(), None);
+        details.fee = Some(1_100);
+        details.transaction = None;
+        assert_eq!(details.fee_rate(), None);
+    }
+
+    #[test]
+    fn test_feerate_ordering() {
+        let low = FeeRate::from_sat_per_vb(1.5);
+        let high = FeeRate::from_sat_per_vb(2.0);
+
+        assert!(low < high);
+        assert_eq!(std::cmp::max(low, high), high);
+        assert_eq!(low.min(high), low);
+        assert_eq!(
+            FeeRate::from_btc_per_kvb(0.00002),
+            FeeRate::from_btc_per_kvb(0.00002)
+        );
+    }
+
+    #[test]
+    fn test_feerate_checked_arithmetic() {
+        let rate = FeeRate::from_sat_per_vb(2.5);
+
+        assert_eq!(
+            rate.checked_add(FeeRate::from_sat_per_vb(1.0)),
+            Some(FeeRate::from_sat_per_vb(3.5))
+        );
+        let max = FeeRate::from_sat_per_vb(f32::MAX);
+        assert_eq!(max.checked_add(max), None);
+        assert_eq!(rate.checked_mul(2.0), Some(FeeRate::from_sat_per_vb(5.0)));
+        assert_eq!(rate.checked_mul(-1.0), None);
+        assert_eq!(rate.checked_mul(f32::INFINITY), None);
+        assert_eq!(rate.as_sat_per_vb(), rate.as_sat_vb());
+    }
+
+    #[test]
+    fn test_feerate_bumped() {
+        let min_relay = FeeRate::default_min_relay_fee();
+
+        // whole sat/vb rates are bumped by exactly 1 sat/vb
+        assert_eq!(
+            FeeRate::from_sat_per_vb(1.0).bumped(min_relay),
+            FeeRate::from_sat_per_vb(2.0)
+        );
+        // fractional rates are rounded up to the next whole sat/vb
+        assert_eq!(
+            FeeRate::from_sat_per_vb(1.2).bumped(min_relay),
+            FeeRate::from_sat_per_vb(3.0)
+        );
+        assert_eq!(
+            FeeRate::from_sat_per_vb(1.99).bumped(min_relay),
+            FeeRate::from_sat_per_vb(3.0)
+        );
+        // the rounding errors of the conversion from btc/kvb don't cost an extra sat/vb
+        assert_eq!(
+            FeeRate::from_btc_per_kvb(0.00002).bumped(min_relay),
+            FeeRate::from_sat_per_vb(3.0)
+        );
+        // never below the min relay fee
+        assert_eq!(
+            FeeRate::from_sat_per_vb(0.5).bumped(FeeRate::from_sat_per_vb(5.0)),
+            FeeRate::from_sat_per_vb(5.0)
+        );
+    }
 }
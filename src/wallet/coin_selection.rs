@@ -637,6 +637,8 @@ mod test {
                 },
                 keychain: KeychainKind::External,
                 is_spent: false,
+                derivation_index: None,
+                suspected_dust: false,
             }),
         }
     }
@@ -671,6 +673,7 @@ mod test {
                 height: 1,
                 timestamp: 1231006505,
             }),
+            tx_type: None,
         };
 
         let utxo2_tx_details = TransactionDetails {
@@ -683,6 +686,7 @@ mod test {
                 height: 2,
                 timestamp: 1231006505,
             }),
+            tx_type: None,
         };
 
         let utxo3_tx_details = TransactionDetails {
@@ -695,6 +699,7 @@ mod test {
                 height: 3,
                 timestamp: 1231006505,
             }),
+            tx_type: None,
         };
 
         database.set_tx(&utxo1_tx_details).unwrap();
@@ -720,6 +725,8 @@ mod test {
                     },
                     keychain: KeychainKind::External,
                     is_spent: false,
+                    derivation_index: None,
+                    suspected_dust: false,
                 }),
             });
         }
@@ -740,6 +747,8 @@ mod test {
                 },
                 keychain: KeychainKind::External,
                 is_spent: false,
+                derivation_index: None,
+                suspected_dust: false,
             }),
         };
         vec![utxo; utxos_number]
@@ -897,6 +906,7 @@ mod test {
                 height: 1,
                 timestamp: 1231006505,
             }),
+            tx_type: None,
         };
 
         let utxo2_tx_details = TransactionDetails {
@@ -909,6 +919,7 @@ mod test {
                 height: 2,
                 timestamp: 1231006505,
             }),
+            tx_type: None,
         };
 
         database.set_tx(&utxo1_tx_details).unwrap();
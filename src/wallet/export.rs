@@ -251,6 +251,7 @@ mod test {
                 timestamp: 12345678,
                 height: 5000,
             }),
+            tx_type: None,
         })
         .unwrap();
 
@@ -23,19 +23,21 @@ use std::sync::Arc;
 
 use bitcoin::secp256k1::Secp256k1;
 
+use bitcoin::blockdata::script::Instruction;
 use bitcoin::consensus::encode::serialize;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
 use bitcoin::util::{psbt, taproot};
 use bitcoin::{
     Address, EcdsaSighashType, Network, OutPoint, SchnorrSighashType, Script, Transaction, TxOut,
-    Txid, Witness,
+    Txid, VarInt, Witness,
 };
 
-use miniscript::descriptor::DescriptorTrait;
+use miniscript::descriptor::{DescriptorTrait, DescriptorType};
 use miniscript::psbt::PsbtInputSatisfier;
 use miniscript::ToPublicKey;
 
 #[allow(unused_imports)]
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 
 pub mod address_validator;
 pub mod coin_selection;
@@ -54,17 +56,20 @@ use address_validator::AddressValidator;
 use coin_selection::DefaultCoinSelectionAlgorithm;
 use signer::{SignOptions, SignerOrdering, SignersContainer, TransactionSigner};
 use tx_builder::{BumpFee, CreateTx, FeePolicy, TxBuilder, TxParams};
-use utils::{check_nlocktime, check_nsequence_rbf, After, Older, SecpCtx};
+use utils::{check_nlocktime, check_nsequence_rbf, ecdsa_signature_lengths, After, Older, SecpCtx};
 
-use crate::blockchain::{GetHeight, NoopProgress, Progress, WalletSync};
+use crate::blockchain::{
+    Blockchain, CancelToken, CancellableProgress, GetHeight, GetTx, NoopProgress, Progress,
+    SyncStats, WalletSync,
+};
 use crate::database::memory::MemoryDatabase;
 use crate::database::{AnyDatabase, BatchDatabase, BatchOperations, DatabaseUtils, SyncTime};
 use crate::descriptor::derived::AsDerived;
 use crate::descriptor::policy::BuildSatisfaction;
 use crate::descriptor::{
-    get_checksum, into_wallet_descriptor_checked, DerivedDescriptor, DerivedDescriptorMeta,
-    DescriptorMeta, DescriptorScripts, ExtendedDescriptor, ExtractPolicy, IntoWalletDescriptor,
-    Policy, XKeyUtils,
+    expand_multipath, get_checksum, into_wallet_descriptor_checked, DerivedDescriptor,
+    DerivedDescriptorMeta, DescriptorError, DescriptorMeta, DescriptorPublicKey, DescriptorScripts,
+    ExtendedDescriptor, ExtractPolicy, IntoWalletDescriptor, Policy, XKeyUtils,
 };
 use crate::error::Error;
 use crate::psbt::PsbtUtils;
@@ -73,6 +78,8 @@ use crate::testutils;
 use crate::types::*;
 
 const CACHE_ADDR_BATCH_SIZE: u32 = 100;
+/// Number of confirmations after which the outputs of a coinbase transaction can be spent
+const COINBASE_MATURITY: u32 = 100;
 
 /// A Bitcoin wallet
 ///
@@ -159,6 +166,122 @@ impl fmt::Display for AddressInfo {
     }
 }
 
+/// A spendable output of the wallet, as returned by [`Wallet::coin_control_snapshot`]
+///
+/// Contains the information that is usually displayed in a coin-control list.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoinEntry {
+    /// Location of the output
+    pub outpoint: OutPoint,
+    /// Value of the output (sats)
+    pub value: u64,
+    /// Number of confirmations, `0` if the output is unconfirmed
+    pub confirmations: u32,
+    /// Address the output was received to, `None` if the script doesn't have an address form
+    pub address: Option<Address>,
+    /// Type of keychain
+    pub keychain: KeychainKind,
+    /// Whether the output is change, meaning it was received on the internal keychain
+    pub is_change: bool,
+}
+
+/// Effect of a PSBT on the balance of the wallet, as returned by [`Wallet::psbt_balance_effect`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BalanceEffect {
+    /// Sum of the values of the inputs spending outputs of this wallet (sats)
+    pub total_in_owned: u64,
+    /// Sum of the values of the outputs sending to this wallet (sats)
+    pub total_out_owned: u64,
+    /// Fee paid by the transaction (sats)
+    pub fee: u64,
+    /// Change of the wallet balance once the transaction confirms (sats), negative when sending
+    pub net_change: i64,
+}
+
+/// Balance of the wallet, split by how safe it is to spend, as returned by
+/// [`Wallet::get_balance_detailed`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Balance {
+    /// Confirmed and mature outputs (sats)
+    pub confirmed: u64,
+    /// Unconfirmed outputs of transactions spending outputs of this wallet, like our own change
+    /// (sats)
+    pub trusted_pending: u64,
+    /// Unconfirmed outputs received from others (sats)
+    pub untrusted_pending: u64,
+    /// Coinbase outputs that don't have enough confirmations to be spent yet (sats)
+    pub immature: u64,
+}
+
+impl Balance {
+    /// Sum of all the outputs of the wallet, as returned by [`Wallet::get_balance`]
+    pub fn total(&self) -> u64 {
+        self.confirmed + self.trusted_pending + self.untrusted_pending + self.immature
+    }
+}
+
+/// Consolidation transaction planned by [`Wallet::split_consolidation`]
+///
+/// It can be created with a [`TxBuilder`] by passing the `inputs` to [`TxBuilder::add_utxos`],
+/// along with [`TxBuilder::manually_selected_only`], [`TxBuilder::drain_to`] and
+/// [`TxBuilder::fee_absolute`] with the `fee`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransactionOutline {
+    /// Outputs spent by the transaction
+    pub inputs: Vec<OutPoint>,
+    /// Value sent to the consolidation script (sats)
+    pub value: u64,
+    /// Fee paid by the transaction (sats)
+    pub fee: u64,
+    /// Estimated weight of the signed transaction
+    pub weight: usize,
+}
+
+/// Confirmation target used by [`Wallet::is_payment_final`] to decide whether a transaction pays a
+/// competitive fee
+const PAYMENT_FINALITY_TARGET: usize = 6;
+
+/// Risk assessment of accepting a payment, as returned by [`Wallet::is_payment_final`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaymentFinality {
+    /// The transaction is confirmed
+    Confirmed,
+    /// The transaction is unconfirmed, but it doesn't signal RBF, it has no unconfirmed ancestors
+    /// and it pays a competitive fee
+    LowRisk,
+    /// The transaction is unconfirmed and could be replaced or stay unconfirmed for a long time
+    AtRisk {
+        /// At least one input signals opt-in RBF (BIP125)
+        signals_rbf: bool,
+        /// At least one input spends an unconfirmed transaction
+        unconfirmed_ancestors: bool,
+        /// The feerate is below the current estimate for confirmation in 6 blocks, or the fee is
+        /// unknown
+        low_fee: bool,
+    },
+}
+
+/// Encoding of the ECDSA signatures of a transaction, as returned by
+/// [`Wallet::analyze_signature_sizes`]
+///
+/// The `r` value of a signature is encoded in 32 bytes when its most significant bit is unset
+/// ("low-R") and 33 bytes otherwise ("high-R"): the signatures produced without grinding the nonce
+/// are one byte larger half of the time, which makes the final size of a transaction differ from
+/// its estimate. Schnorr signatures have a fixed size and aren't counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SigSizeReport {
+    /// Number of low-R signatures, 71 bytes or less including the sighash type
+    pub low_r: usize,
+    /// Number of high-R signatures, 72 bytes or more including the sighash type
+    pub high_r: usize,
+    /// Number of signatures with an `s` value encoded in 33 bytes, which are non-standard since
+    /// the low-S rule of BIP146 and make the signature one more byte larger
+    pub high_s: usize,
+    /// Weight added by the high-R and high-S signatures, compared to the same transaction with
+    /// low-R and low-S signatures only (weight units)
+    pub extra_weight: usize,
+}
+
 #[derive(Debug, Default)]
 /// Options to a [`sync`].
 ///
@@ -166,6 +289,19 @@ impl fmt::Display for AddressInfo {
 pub struct SyncOptions {
     /// The progress tracker which may be informed when progress is made.
     pub progress: Option<Box<dyn Progress>>,
+    /// Flag the UTXOs worth less than this amount (in satoshi) that were received from
+    /// transactions not created by the wallet as [`suspected_dust`](LocalUtxo::suspected_dust)
+    ///
+    /// The flags are computed again on every sync: syncing without a threshold clears them.
+    pub dust_attack_threshold: Option<u64>,
+    /// Token used to interrupt the sync, which then fails with
+    /// [`Error::SyncAborted`](crate::Error::SyncAborted) leaving the database untouched
+    pub cancel: Option<CancelToken>,
+    /// Stop looking for the history of more script pubkeys once this many requests were sent
+    /// during the sync, see [`WalletSync::wallet_sync_with_budget`]
+    ///
+    /// Only the backends that count their requests, like Esplora, enforce it.
+    pub max_requests: Option<usize>,
 }
 
 impl<D> Wallet<D>
@@ -183,6 +319,22 @@ where
         Self::new(descriptor, change_descriptor, network, database)
     }
 
+    /// Create a wallet from a [BIP389] multipath descriptor describing both keychains
+    ///
+    /// The descriptor must have exactly two paths, like `wpkh(xpub/<0;1>/*)`: the first one is
+    /// used for the [`External`](KeychainKind::External) keychain and the second one for the
+    /// [`Internal`](KeychainKind::Internal) keychain. The wallet then behaves, and syncs, exactly
+    /// like one created with [`Wallet::new`] with the two expanded descriptors.
+    ///
+    /// [BIP389]: https://github.com/bitcoin/bips/blob/master/bip-0389.mediawiki
+    pub fn new_multipath(descriptor: &str, network: Network, database: D) -> Result<Self, Error> {
+        let paths = expand_multipath(descriptor)?;
+        match paths.as_slice() {
+            [external, internal] => Self::new(external, Some(internal), network, database),
+            _ => Err(DescriptorError::InvalidMultipathDescriptor.into()),
+        }
+    }
+
     /// Create a wallet.
     ///
     /// The only way this can fail is if the descriptors passed in do not match the checksums in `database`.
@@ -456,6 +608,380 @@ where
             .fold(0, |sum, i| sum + i.txout.value))
     }
 
+    /// Return the balance split into confirmed, trusted pending, untrusted pending and immature
+    /// values, see [`Balance`]
+    ///
+    /// Unconfirmed outputs are trusted when their transaction spends outputs of this wallet.
+    /// Coinbase outputs are immature until they have 100 confirmations at the height of the last
+    /// sync, or if the wallet was never synced.
+    ///
+    /// Like [`Wallet::get_balance`], the values reflect the last [`Wallet::sync`].
+    pub fn get_balance_detailed(&self) -> Result<Balance, Error> {
+        let database = self.database.borrow();
+        let tip_height = database
+            .get_sync_time()?
+            .map(|sync_time| sync_time.block_time.height);
+
+        let mut balance = Balance::default();
+        for utxo in self.list_unspent()? {
+            let value = utxo.txout.value;
+            let details = match database.get_tx(&utxo.outpoint.txid, true)? {
+                Some(details) => details,
+                None => {
+                    balance.untrusted_pending += value;
+                    continue;
+                }
+            };
+
+            let is_coinbase = details
+                .transaction
+                .as_ref()
+                .map_or(false, Transaction::is_coin_base);
+            if is_coinbase {
+                let confirmations = details
+                    .confirmation_time
+                    .zip(tip_height)
+                    .map(|(block_time, tip_height)| {
+                        (tip_height + 1).saturating_sub(block_time.height)
+                    })
+                    .unwrap_or(0);
+                if confirmations >= COINBASE_MATURITY {
+                    balance.confirmed += value;
+                } else {
+                    balance.immature += value;
+                }
+            } else if details.confirmation_time.is_some() {
+                balance.confirmed += value;
+            } else if details.sent > 0 {
+                balance.trusted_pending += value;
+            } else {
+                balance.untrusted_pending += value;
+            }
+        }
+
+        Ok(balance)
+    }
+
+    /// Return a snapshot of the spendable outputs of this wallet, suitable for a coin-control list
+    ///
+    /// The number of confirmations of every output is computed relative to `tip_height`.
+    ///
+    /// Only the UTXOs stored by the last [`Wallet::sync`] are listed.
+    pub fn coin_control_snapshot(&self, tip_height: u32) -> Result<Vec<CoinEntry>, Error> {
+        let database = self.database.borrow();
+        self.list_unspent()?
+            .into_iter()
+            .map(|utxo| {
+                let confirmations = database
+                    .get_tx(&utxo.outpoint.txid, false)?
+                    .and_then(|details| details.confirmation_time)
+                    .map(|block_time| {
+                        tip_height
+                            .saturating_add(1)
+                            .saturating_sub(block_time.height)
+                    })
+                    .unwrap_or(0);
+
+                Ok(CoinEntry {
+                    outpoint: utxo.outpoint,
+                    value: utxo.txout.value,
+                    confirmations,
+                    address: Address::from_script(&utxo.txout.script_pubkey, self.network),
+                    keychain: utxo.keychain,
+                    is_change: utxo.keychain == KeychainKind::Internal,
+                })
+            })
+            .collect()
+    }
+
+    /// Return a fingerprint of the chain state stored in the database of this wallet
+    ///
+    /// The fingerprint commits to the txids of all the transactions, to every UTXO and to the last
+    /// derivation index of both keychains, independently of the order in which they were stored.
+    /// Two wallets with identical chain state produce the same fingerprint, which helps debugging
+    /// reports of different devices disagreeing on the state of the same wallet.
+    ///
+    /// Nothing is requested from a backend: compare fingerprints taken after syncing both wallets.
+    pub fn state_fingerprint(&self) -> Result<[u8; 32], Error> {
+        let database = self.database.borrow();
+        let mut engine = sha256::Hash::engine();
+
+        let mut txids = database
+            .iter_txs(false)?
+            .into_iter()
+            .map(|details| details.txid)
+            .collect::<Vec<_>>();
+        txids.sort_unstable();
+        engine.input(&serialize(&(txids.len() as u64)));
+        for txid in txids {
+            engine.input(&serialize(&txid));
+        }
+
+        let mut utxos = database.iter_utxos()?;
+        utxos.sort_unstable_by_key(|utxo| utxo.outpoint);
+        engine.input(&serialize(&(utxos.len() as u64)));
+        for utxo in utxos {
+            engine.input(&serialize(&utxo.outpoint));
+            engine.input(&serialize(&utxo.txout));
+            engine.input(&[utxo.keychain.as_byte(), utxo.is_spent as u8]);
+        }
+
+        for keychain in &[KeychainKind::External, KeychainKind::Internal] {
+            let last_index = database.get_last_index(*keychain)?;
+            engine.input(&[last_index.is_some() as u8]);
+            engine.input(&serialize(&last_index.unwrap_or(0)));
+        }
+
+        Ok(sha256::Hash::from_engine(engine).into_inner())
+    }
+
+    /// Assess the risk of accepting the payment made by an unconfirmed transaction
+    ///
+    /// Combines whether the transaction signals RBF, whether it spends unconfirmed transactions and
+    /// how its feerate compares to the current estimate of the `blockchain`. This is meant to help
+    /// merchants decide whether to accept a zero-conf payment.
+    ///
+    /// The transaction itself is read from the internal database, which first needs to be
+    /// [`Wallet::sync`] manually: [`Error::TransactionNotFound`] is returned if `txid` isn't part of
+    /// the wallet. The parents it spends usually aren't, so their status is asked to the
+    /// `blockchain` with [`Blockchain::get_tx_confirmations`]. A parent unknown to the backend
+    /// counts as unconfirmed.
+    #[maybe_async]
+    pub fn is_payment_final<B: Blockchain>(
+        &self,
+        txid: &Txid,
+        blockchain: &B,
+    ) -> Result<PaymentFinality, Error> {
+        let details = self
+            .database
+            .borrow()
+            .get_tx(txid, true)?
+            .ok_or(Error::TransactionNotFound)?;
+        if details.confirmation_time.is_some() {
+            return Ok(PaymentFinality::Confirmed);
+        }
+        let tx = details.transaction.ok_or(Error::TransactionNotFound)?;
+
+        let signals_rbf = tx.input.iter().any(|txin| txin.sequence < 0xFFFFFFFE);
+
+        let parents = tx
+            .input
+            .iter()
+            .map(|txin| txin.previous_output.txid)
+            .collect::<HashSet<_>>();
+        let mut unconfirmed_ancestors = false;
+        for parent in parents {
+            let confirmations = maybe_await!(blockchain.get_tx_confirmations(&parent))?;
+            if confirmations.unwrap_or(0) == 0 {
+                unconfirmed_ancestors = true;
+                break;
+            }
+        }
+
+        let low_fee = match details.fee {
+            Some(fee) => {
+                let mempool_rate = maybe_await!(blockchain.estimate_fee(PAYMENT_FINALITY_TARGET))?;
+                FeeRate::from_wu(fee, tx.weight()) < mempool_rate
+            }
+            None => true,
+        };
+
+        if signals_rbf || unconfirmed_ancestors || low_fee {
+            Ok(PaymentFinality::AtRisk {
+                signals_rbf,
+                unconfirmed_ancestors,
+                low_fee,
+            })
+        } else {
+            Ok(PaymentFinality::LowRisk)
+        }
+    }
+
+    /// Report whether the ECDSA signatures of a wallet transaction use low-R or high-R encodings
+    ///
+    /// Fee estimates usually assume low-R signatures, this explains why the size and feerate of a
+    /// signed transaction differ from the ones expected when it was built. The signatures are
+    /// parsed from the script sigs and witnesses of the inputs.
+    ///
+    /// Fails with [`Error::TransactionNotFound`] if `txid` isn't stored in the database, for
+    /// example because the wallet wasn't synced since the transaction was made.
+    pub fn analyze_signature_sizes(&self, txid: &Txid) -> Result<SigSizeReport, Error> {
+        let tx = self
+            .database
+            .borrow()
+            .get_tx(txid, true)?
+            .and_then(|details| details.transaction)
+            .ok_or(Error::TransactionNotFound)?;
+
+        let mut report = SigSizeReport::default();
+        for txin in &tx.input {
+            // a byte of the script sig weighs 4 weight units, a byte of the witness only 1
+            let script_sig_pushes =
+                txin.script_sig
+                    .instructions()
+                    .filter_map(|instruction| match instruction {
+                        Ok(Instruction::PushBytes(data)) => Some((data, 4)),
+                        _ => None,
+                    });
+            let witness_items = txin.witness.iter().map(|data| (data, 1));
+
+            for (data, weight_per_byte) in script_sig_pushes.chain(witness_items) {
+                let (r_len, s_len) = match ecdsa_signature_lengths(data) {
+                    Some(lengths) => lengths,
+                    None => continue,
+                };
+                if r_len > 32 {
+                    report.high_r += 1;
+                } else {
+                    report.low_r += 1;
+                }
+                if s_len > 32 {
+                    report.high_s += 1;
+                }
+                report.extra_weight +=
+                    (r_len.saturating_sub(32) + s_len.saturating_sub(32)) * weight_per_byte;
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Compute the effect that a PSBT would have on the balance of this wallet
+    ///
+    /// Inputs and outputs are classified as owned by looking up their script pubkeys in the
+    /// database. The value of every previous output is taken from the PSBT if available, then
+    /// from the database, and finally fetched from the `blockchain`.
+    ///
+    /// This is meant to be used to show "you will send X and receive Y as change" before signing.
+    #[maybe_async]
+    pub fn psbt_balance_effect<B: GetTx>(
+        &self,
+        psbt: &psbt::PartiallySignedTransaction,
+        blockchain: &B,
+    ) -> Result<BalanceEffect, Error> {
+        let mut total_in = 0;
+        let mut total_in_owned = 0;
+        for (n, txin) in psbt.unsigned_tx.input.iter().enumerate() {
+            let prev_output = txin.previous_output;
+            let prev_txout = match psbt.get_utxo_for(n) {
+                Some(txout) => txout,
+                None => {
+                    let prev_tx = match self.database.borrow().get_raw_tx(&prev_output.txid)? {
+                        Some(tx) => tx,
+                        None => maybe_await!(blockchain.get_tx(&prev_output.txid))?
+                            .ok_or(Error::TransactionNotFound)?,
+                    };
+                    prev_tx
+                        .output
+                        .get(prev_output.vout as usize)
+                        .cloned()
+                        .ok_or(Error::InvalidOutpoint(prev_output))?
+                }
+            };
+
+            total_in += prev_txout.value;
+            if self.is_mine(&prev_txout.script_pubkey)? {
+                total_in_owned += prev_txout.value;
+            }
+        }
+
+        let mut total_out = 0;
+        let mut total_out_owned = 0;
+        for txout in &psbt.unsigned_tx.output {
+            total_out += txout.value;
+            if self.is_mine(&txout.script_pubkey)? {
+                total_out_owned += txout.value;
+            }
+        }
+
+        let fee = total_in
+            .checked_sub(total_out)
+            .ok_or_else(|| Error::Generic("The PSBT spends more than its inputs".into()))?;
+
+        Ok(BalanceEffect {
+            total_in_owned,
+            total_out_owned,
+            fee,
+            net_change: total_out_owned as i64 - total_in_owned as i64,
+        })
+    }
+
+    /// Split the consolidation of `utxos` into transactions that each weigh at most `max_weight`
+    ///
+    /// Every transaction sweeps its share of `utxos`, in order, to `to` at `fee_rate` without a
+    /// change output, so the transactions don't depend on each other and can be broadcast in any
+    /// order. Their outputs can be consolidated again once they confirm. Use
+    /// [`MAX_STANDARD_TX_WEIGHT`](crate::blockchain::MAX_STANDARD_TX_WEIGHT) as `max_weight` to get
+    /// transactions relayed by the default policy of Bitcoin Core.
+    ///
+    /// Weights are estimated from the maximum satisfaction weight of the descriptors. Fails with
+    /// [`Error::TxTooLarge`] if a single input doesn't fit in `max_weight`, and with
+    /// [`Error::InsufficientFunds`] if the inputs of a transaction can't pay for its fee and a
+    /// non-dust output.
+    pub fn split_consolidation(
+        &self,
+        utxos: &[LocalUtxo],
+        to: &Script,
+        fee_rate: FeeRate,
+        max_weight: usize,
+    ) -> Result<Vec<TransactionOutline>, Error> {
+        let output = TxOut {
+            value: 0,
+            script_pubkey: to.clone(),
+        };
+        // version, locktime and number of outputs, plus the segwit marker and flag
+        let base_weight = (4 + 4 + 1) * 4 + 2 + serialize(&output).len() * 4;
+        let tx_weight = |inputs: usize, inputs_weight: usize| {
+            base_weight + VarInt(inputs as u64).len() * 4 + inputs_weight
+        };
+        let outline = |inputs: Vec<OutPoint>, inputs_weight: usize, total: u64| {
+            let weight = tx_weight(inputs.len(), inputs_weight);
+            let fee = fee_rate.fee_wu(weight);
+            match total.checked_sub(fee) {
+                Some(value) if !value.is_dust(to) => Ok(TransactionOutline {
+                    inputs,
+                    value,
+                    fee,
+                    weight,
+                }),
+                _ => Err(Error::InsufficientFunds {
+                    needed: fee + to.dust_value().as_sat(),
+                    available: total,
+                }),
+            }
+        };
+
+        let mut outlines = Vec::new();
+        let mut inputs = Vec::new();
+        let mut inputs_weight = 0;
+        let mut total = 0;
+        for utxo in utxos {
+            let weight = coin_selection::TXIN_BASE_WEIGHT
+                + self
+                    .get_descriptor_for_keychain(utxo.keychain)
+                    .max_satisfaction_weight()?;
+            if tx_weight(1, weight) > max_weight {
+                return Err(Error::TxTooLarge {
+                    weight: tx_weight(1, weight),
+                });
+            }
+
+            if tx_weight(inputs.len() + 1, inputs_weight + weight) > max_weight {
+                outlines.push(outline(std::mem::take(&mut inputs), inputs_weight, total)?);
+                inputs_weight = 0;
+                total = 0;
+            }
+            inputs.push(utxo.outpoint);
+            inputs_weight += weight;
+            total += utxo.txout.value;
+        }
+        if !inputs.is_empty() {
+            outlines.push(outline(inputs, inputs_weight, total)?);
+        }
+
+        Ok(outlines)
+    }
+
     /// Add an external signer
     ///
     /// See [the `signer` module](signer) for an example.
@@ -747,6 +1273,7 @@ where
             params.drain_wallet,
             params.manually_selected_only,
             params.bumping_fee.is_some(), // we mandate confirmed transactions if we're bumping the fee
+            params.include_suspected_dust,
         )?;
 
         let coin_selection = coin_selection.coin_select(
@@ -770,6 +1297,23 @@ where
             })
             .collect();
 
+        for (outpoint, sequence) in &params.sequences {
+            let txin = tx
+                .input
+                .iter_mut()
+                .find(|txin| txin.previous_output == *outpoint)
+                .ok_or(Error::InputNotSelected(*outpoint))?;
+            match requirements.csv {
+                Some(csv) if !check_nsequence_rbf(*sequence, csv) => {
+                    return Err(Error::Generic(format!(
+                        "Cannot set nSequence `{}` on input `{}` given a required OP_CSV of `{}`",
+                        sequence, outpoint, csv
+                    )))
+                }
+                _ => txin.sequence = *sequence,
+            }
+        }
+
         // prepare the drain output
         let mut drain_output = {
             let script_pubkey = match params.drain_to {
@@ -827,6 +1371,7 @@ where
             received,
             sent,
             fee: Some(fee_amount),
+            tx_type: None,
         };
 
         Ok((psbt, transaction_details))
@@ -902,24 +1447,25 @@ where
                     .get_previous_output(&txin.previous_output)?
                     .ok_or(Error::UnknownUtxo)?;
 
-                let (weight, keychain) = match self
+                let (weight, keychain, derivation_index) = match self
                     .database
                     .borrow()
                     .get_path_from_script_pubkey(&txout.script_pubkey)?
                 {
-                    Some((keychain, _)) => (
+                    Some((keychain, child)) => (
                         self._get_descriptor_for_keychain(keychain)
                             .0
                             .max_satisfaction_weight()
                             .unwrap(),
                         keychain,
+                        Some(child),
                     ),
                     None => {
                         // estimate the weight based on the scriptsig/witness size present in the
                         // original transaction
                         let weight =
                             serialize(&txin.script_sig).len() * 4 + serialize(&txin.witness).len();
-                        (weight, KeychainKind::External)
+                        (weight, KeychainKind::External, None)
                     }
                 };
 
@@ -928,6 +1474,8 @@ where
                     txout,
                     keychain,
                     is_spent: true,
+                    derivation_index,
+                    suspected_dust: false,
                 };
 
                 Ok(WeightedUtxo {
@@ -1316,7 +1864,7 @@ where
 
     /// Given the options returns the list of utxos that must be used to form the
     /// transaction and any further that may be used if needed.
-    #[allow(clippy::type_complexity)]
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
     fn preselect_utxos(
         &self,
         change_policy: tx_builder::ChangeSpendPolicy,
@@ -1325,6 +1873,7 @@ where
         must_use_all_available: bool,
         manual_only: bool,
         must_only_use_confirmed_tx: bool,
+        include_suspected_dust: bool,
     ) -> Result<(Vec<WeightedUtxo>, Vec<WeightedUtxo>), Error> {
         //    must_spend <- manually selected utxos
         //    may_spend  <- all other available utxos
@@ -1365,6 +1914,7 @@ where
         may_spend.retain(|u| {
             let retain = change_policy.is_satisfied_by(&u.0)
                 && !unspendable.contains(&u.0.outpoint)
+                && (include_suspected_dust || !u.0.suspected_dust)
                 && satisfies_confirmed[i];
             i += 1;
             retain
@@ -1613,28 +2163,64 @@ where
     }
 
     /// Sync the internal database with the blockchain
+    ///
+    /// Returns the [`SyncStats`] reported by the backend. A sync that couldn't fetch the history
+    /// of some script pubkeys still succeeds, but reports them in
+    /// [`SyncStats::skipped_scripts`].
     #[maybe_async]
     pub fn sync<B: WalletSync + GetHeight>(
         &self,
         blockchain: &B,
         sync_opts: SyncOptions,
-    ) -> Result<(), Error> {
+    ) -> Result<SyncStats, Error> {
         debug!("Begin sync...");
 
-        let SyncOptions { progress } = sync_opts;
-        let progress = progress.unwrap_or_else(|| Box::new(NoopProgress));
+        let SyncOptions {
+            progress,
+            dust_attack_threshold,
+            cancel,
+            max_requests,
+        } = sync_opts;
+        let mut progress = progress.unwrap_or_else(|| Box::new(NoopProgress));
+        if let Some(token) = cancel {
+            progress = Box::new(CancellableProgress::new(progress, token));
+        }
+
+        crate::database::migrate(self.database.borrow_mut().deref_mut())?;
 
         let run_setup = self.ensure_addresses_cached(CACHE_ADDR_BATCH_SIZE)?;
 
         debug!("run_setup: {}", run_setup);
         // TODO: what if i generate an address first and cache some addresses?
         // TODO: we should sync if generating an address triggers a new batch to be stored
-        if run_setup {
-            maybe_await!(
-                blockchain.wallet_setup(self.database.borrow_mut().deref_mut(), progress,)
-            )?;
-        } else {
-            maybe_await!(blockchain.wallet_sync(self.database.borrow_mut().deref_mut(), progress,))?;
+        let mut database = self.database.borrow_mut();
+        let stats = match (run_setup, max_requests) {
+            (true, None) => {
+                maybe_await!(blockchain.wallet_setup_with_stats(database.deref_mut(), progress))?
+            }
+            (false, None) => {
+                maybe_await!(blockchain.wallet_sync_with_stats(database.deref_mut(), progress))?
+            }
+            (true, Some(max_requests)) => maybe_await!(blockchain.wallet_setup_with_budget(
+                database.deref_mut(),
+                progress,
+                max_requests
+            ))?,
+            (false, Some(max_requests)) => maybe_await!(blockchain.wallet_sync_with_budget(
+                database.deref_mut(),
+                progress,
+                max_requests
+            ))?,
+        };
+        drop(database);
+        if stats.request_budget_exhausted {
+            warn!("Incomplete sync: the request budget was exhausted");
+        }
+        if stats.skipped_scripts > 0 {
+            warn!(
+                "Incomplete sync: the history of {} script pubkeys couldn't be fetched",
+                stats.skipped_scripts
+            );
         }
 
         let sync_time = SyncTime {
@@ -1646,7 +2232,37 @@ where
         debug!("Saving `sync_time` = {:?}", sync_time);
         self.database.borrow_mut().set_sync_time(sync_time)?;
 
-        Ok(())
+        self.flag_suspected_dust(dust_attack_threshold)?;
+
+        Ok(stats)
+    }
+
+    /// Flag the UTXOs worth less than `threshold` received from others as suspected dust, clearing
+    /// the flag of all the others
+    ///
+    /// Without a threshold every flag set by a previous sync is cleared.
+    fn flag_suspected_dust(&self, threshold: Option<u64>) -> Result<(), Error> {
+        let mut database = self.database.borrow_mut();
+        let mut batch = database.begin_batch();
+
+        for mut utxo in database.iter_utxos()? {
+            // change and other outputs of our own transactions are never suspicious
+            let suspected_dust = utxo.txout.value < threshold.unwrap_or(0)
+                && database
+                    .get_tx(&utxo.outpoint.txid, false)?
+                    .map_or(false, |details| details.sent == 0);
+
+            if utxo.suspected_dust != suspected_dust {
+                debug!(
+                    "Setting `suspected_dust` = {} for {}",
+                    suspected_dust, utxo.outpoint
+                );
+                utxo.suspected_dust = suspected_dust;
+                batch.set_utxo(&utxo)?;
+            }
+        }
+
+        database.commit_batch(batch)
     }
 
     /// Return the checksum of the public descriptor associated to `keychain`
@@ -1693,10 +2309,153 @@ where
     Ok(wallet_name)
 }
 
+/// Estimate the virtual size of `psbt` once all of its inputs are signed and finalized
+///
+/// Inputs that are already finalized are counted with their actual size, while the others are
+/// assumed to be spending an output of `descriptor` and are counted with its maximum satisfaction
+/// weight. For taproot descriptors an input is assumed to be spent through the key path when the
+/// tree is empty, when it already has a key-spend signature, or when its internal key has a key
+/// origin in the PSBT (meaning a signer can recognize it). Otherwise the heaviest leaf of the tree
+/// is assumed.
+///
+/// This can be used to preview the fee of a transaction before signing it.
+pub fn estimate_vsize(
+    psbt: &psbt::PartiallySignedTransaction,
+    descriptor: &ExtendedDescriptor,
+) -> Result<usize, Error> {
+    if psbt.inputs.len() != psbt.unsigned_tx.input.len() {
+        return Err(SignerError::InputIndexOutOfRange.into());
+    }
+
+    let is_segwit_descriptor = !matches!(
+        descriptor.desc_type(),
+        DescriptorType::Bare
+            | DescriptorType::Sh
+            | DescriptorType::Pkh
+            | DescriptorType::ShSortedMulti
+    );
+
+    // Everything but the scriptSigs (including their lengths) and the witnesses
+    let mut unsigned_tx = psbt.unsigned_tx.clone();
+    for txin in &mut unsigned_tx.input {
+        txin.script_sig = Script::new();
+        txin.witness = Witness::default();
+    }
+    let mut weight = (serialize(&unsigned_tx).len() - unsigned_tx.input.len()) * 4;
+
+    let mut is_segwit = false;
+    let mut empty_witnesses = 0;
+    for psbt_input in &psbt.inputs {
+        let finalized =
+            psbt_input.final_script_sig.is_some() || psbt_input.final_script_witness.is_some();
+
+        if finalized {
+            let script_sig_len = psbt_input
+                .final_script_sig
+                .as_ref()
+                .map_or(0, |script| script.len());
+            weight += (VarInt(script_sig_len as u64).len() + script_sig_len) * 4;
+
+            match &psbt_input.final_script_witness {
+                Some(witness) if !witness.is_empty() => {
+                    is_segwit = true;
+                    weight += VarInt(witness.len() as u64).len()
+                        + witness
+                            .iter()
+                            .map(|item| VarInt(item.len() as u64).len() + item.len())
+                            .sum::<usize>();
+                }
+                _ => empty_witnesses += 1,
+            }
+        } else {
+            // The satisfaction weight includes the length of the scriptSig and, for segwit
+            // descriptors, the number of witness items
+            weight += match descriptor {
+                miniscript::Descriptor::Tr(tr) => tr_satisfaction_weight(tr, psbt_input)?,
+                _ => descriptor.max_satisfaction_weight()?,
+            };
+
+            if is_segwit_descriptor {
+                is_segwit = true;
+            } else {
+                empty_witnesses += 1;
+            }
+        }
+    }
+
+    if is_segwit {
+        // segwit marker and flag, plus the number of witness items of the non-segwit inputs
+        weight += 2 + empty_witnesses;
+    }
+
+    Ok((weight + 3) / 4)
+}
+
+fn tr_satisfaction_weight(
+    tr: &miniscript::descriptor::Tr<DescriptorPublicKey>,
+    psbt_input: &psbt::Input,
+) -> Result<usize, Error> {
+    let key_spend = tr.taptree().is_none()
+        || psbt_input.tap_key_sig.is_some()
+        || psbt_input
+            .tap_internal_key
+            .map_or(false, |key| psbt_input.tap_key_origins.contains_key(&key));
+
+    if key_spend {
+        let sig_len = match psbt_input.sighash_type {
+            None => 64,
+            Some(sighash) if sighash == SchnorrSighashType::Default.into() => 64,
+            Some(_) => 65,
+        };
+        // scriptSig length, number of witness items, signature with its length
+        return Ok(4 + 1 + 1 + sig_len);
+    }
+
+    tr.iter_scripts()
+        .filter_map(|(depth, ms)| {
+            let sat_elements = ms.max_satisfaction_witness_elements().ok()?;
+            let sat_size = ms.max_satisfaction_size().ok()?;
+            let script_size = ms.script_size();
+            let control_block_size = 33 + 32 * depth as usize;
+
+            Some(
+                4 + VarInt(sat_elements as u64 + 2).len()
+                    + sat_size
+                    + VarInt(script_size as u64).len()
+                    + script_size
+                    + VarInt(control_block_size as u64).len()
+                    + control_block_size,
+            )
+        })
+        .max()
+        .ok_or_else(|| miniscript::Error::ImpossibleSatisfaction.into())
+}
+
 /// Return a fake wallet that appears to be funded for testing.
+///
+/// The wallet has a single UTXO of 50'000 satoshis with one confirmation, see
+/// [`get_funded_wallet_with`] to fund it differently.
 pub fn get_funded_wallet(
     descriptor: &str,
 ) -> (Wallet<AnyDatabase>, (String, Option<String>), bitcoin::Txid) {
+    let (wallet, descriptors, txids) = get_funded_wallet_with(descriptor, &[(50_000, 1)]);
+    (wallet, descriptors, txids[0])
+}
+
+/// Return a fake wallet funded with one UTXO for every `(value, confirmations)` pair of `utxos`,
+/// along with the txids of the transactions creating them.
+///
+/// Every UTXO is received on its own address, in the same order as `utxos`, by a transaction
+/// confirmed `confirmations` blocks below the height 100. A UTXO with zero confirmations is
+/// unconfirmed.
+pub fn get_funded_wallet_with(
+    descriptor: &str,
+    utxos: &[(u64, u32)],
+) -> (
+    Wallet<AnyDatabase>,
+    (String, Option<String>),
+    Vec<bitcoin::Txid>,
+) {
     let descriptors = testutils!(@descriptors (descriptor));
     let wallet = Wallet::new(
         &descriptors.0,
@@ -1706,32 +2465,41 @@ pub fn get_funded_wallet(
     )
     .unwrap();
 
-    let funding_address_kix = 0;
-
-    let tx_meta = testutils! {
-            @tx ( (@external descriptors, funding_address_kix) => 50_000 ) (@confirmations 1)
-    };
+    let mut txids = Vec::with_capacity(utxos.len());
+    for (funding_address_kix, (value, confirmations)) in utxos.iter().enumerate() {
+        let funding_address_kix = funding_address_kix as u32;
+        let mut tx_meta = testutils! {
+                @tx ( (@external descriptors, funding_address_kix) => *value )
+        };
+        if *confirmations > 0 {
+            tx_meta.min_confirmations = Some(*confirmations as u64);
+        }
 
-    wallet
-        .database
-        .borrow_mut()
-        .set_script_pubkey(
-            &bitcoin::Address::from_str(&tx_meta.output.get(0).unwrap().to_address)
-                .unwrap()
-                .script_pubkey(),
-            KeychainKind::External,
-            funding_address_kix,
-        )
-        .unwrap();
-    wallet
-        .database
-        .borrow_mut()
-        .set_last_index(KeychainKind::External, funding_address_kix)
-        .unwrap();
+        wallet
+            .database
+            .borrow_mut()
+            .set_script_pubkey(
+                &bitcoin::Address::from_str(&tx_meta.output.get(0).unwrap().to_address)
+                    .unwrap()
+                    .script_pubkey(),
+                KeychainKind::External,
+                funding_address_kix,
+            )
+            .unwrap();
+        wallet
+            .database
+            .borrow_mut()
+            .set_last_index(KeychainKind::External, funding_address_kix)
+            .unwrap();
 
-    let txid = crate::populate_test_db!(wallet.database.borrow_mut(), tx_meta, Some(100));
+        txids.push(crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            tx_meta,
+            Some(100)
+        ));
+    }
 
-    (wallet, descriptors, txid)
+    (wallet, descriptors, txids)
 }
 
 #[cfg(test)]
@@ -1914,6 +2682,28 @@ pub(crate) mod test {
         };
     }
 
+    #[test]
+    fn test_get_funded_wallet_with() {
+        let (wallet, _, txids) =
+            get_funded_wallet_with(get_test_wpkh(), &[(10_000, 1), (20_000, 6), (30_000, 0)]);
+
+        assert_eq!(wallet.get_balance().unwrap(), 60_000);
+        assert_eq!(wallet.list_unspent().unwrap().len(), 3);
+
+        let heights = txids
+            .iter()
+            .map(|txid| {
+                wallet
+                    .get_tx(txid, false)
+                    .unwrap()
+                    .unwrap()
+                    .confirmation_time
+                    .map(|time| time.height)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(heights, vec![Some(99), Some(94), None]);
+    }
+
     #[test]
     #[should_panic(expected = "NoRecipients")]
     fn test_create_tx_empty_recipients() {
@@ -2084,6 +2874,62 @@ pub(crate) mod test {
         assert_eq!(psbt.unsigned_tx.input[0].sequence, 0xFFFFFFFE);
     }
 
+    #[test]
+    fn test_create_tx_set_sequence_csv() {
+        let (wallet, _, txid) = get_funded_wallet(get_test_single_sig_csv());
+        let addr = wallet.get_address(New).unwrap();
+        let mut builder = wallet.build_tx();
+        builder
+            .add_recipient(addr.script_pubkey(), 25_000)
+            .set_sequence(OutPoint::new(txid, 0), 144);
+        let (psbt, _) = builder.finish().unwrap();
+
+        assert_eq!(psbt.unsigned_tx.input[0].sequence, 144);
+    }
+
+    #[test]
+    #[should_panic(expected = "given a required OP_CSV of `6`")]
+    fn test_create_tx_set_sequence_below_csv() {
+        let (wallet, _, txid) = get_funded_wallet(get_test_single_sig_csv());
+        let addr = wallet.get_address(New).unwrap();
+        let mut builder = wallet.build_tx();
+        builder
+            .add_recipient(addr.script_pubkey(), 25_000)
+            .set_sequence(OutPoint::new(txid, 0), 3);
+        builder.finish().unwrap();
+    }
+
+    #[test]
+    fn test_create_tx_set_sequence_with_rbf() {
+        let (wallet, _, txid) = get_funded_wallet(get_test_wpkh());
+        let addr = wallet.get_address(New).unwrap();
+        let mut builder = wallet.build_tx();
+        builder
+            .add_recipient(addr.script_pubkey(), 25_000)
+            .enable_rbf()
+            .set_sequence(OutPoint::new(txid, 0), 0xFFFFFFFE);
+        let (psbt, _) = builder.finish().unwrap();
+
+        // the explicit value isn't replaced by the default RBF one
+        assert_eq!(psbt.unsigned_tx.input[0].sequence, 0xFFFFFFFE);
+    }
+
+    #[test]
+    fn test_create_tx_set_sequence_unknown_input() {
+        let (wallet, _, txid) = get_funded_wallet(get_test_wpkh());
+        let addr = wallet.get_address(New).unwrap();
+        let unknown = OutPoint::new(txid, 1);
+        let mut builder = wallet.build_tx();
+        builder
+            .add_recipient(addr.script_pubkey(), 25_000)
+            .set_sequence(unknown, 10);
+
+        assert!(matches!(
+            builder.finish(),
+            Err(Error::InputNotSelected(outpoint)) if outpoint == unknown
+        ));
+    }
+
     #[test]
     #[should_panic(expected = "Cannot enable RBF with a nSequence >= 0xFFFFFFFE")]
     fn test_create_tx_invalid_rbf_sequence() {
@@ -2298,6 +3144,49 @@ pub(crate) mod test {
         builder.finish().unwrap();
     }
 
+    #[test]
+    fn test_create_tx_add_data() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let addr = wallet.get_address(New).unwrap();
+        let data = [42u8; 80];
+        let mut builder = wallet.build_tx();
+        builder
+            .add_recipient(addr.script_pubkey(), 25_000)
+            .add_data(&data)
+            .unwrap();
+        let (psbt, _) = builder.finish().unwrap();
+
+        let op_return = psbt
+            .unsigned_tx
+            .output
+            .iter()
+            .find(|output| output.script_pubkey.is_op_return())
+            .unwrap();
+        assert_eq!(op_return.value, 0);
+        assert_eq!(op_return.script_pubkey, Script::new_op_return(&data));
+    }
+
+    #[test]
+    fn test_create_tx_add_data_too_large() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let mut builder = wallet.build_tx();
+        assert!(matches!(
+            builder.add_data(&[42u8; 81]),
+            Err(Error::OpReturnTooLarge(81))
+        ));
+    }
+
+    #[test]
+    fn test_create_tx_add_data_twice() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let mut builder = wallet.build_tx();
+        builder.add_data(b"first").unwrap();
+        assert!(matches!(
+            builder.add_data(b"second"),
+            Err(Error::MultipleOpReturn)
+        ));
+    }
+
     #[test]
     fn test_create_tx_ordering_respected() {
         let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
@@ -4191,6 +5080,49 @@ pub(crate) mod test {
         );
     }
 
+    #[test]
+    fn test_new_multipath() {
+        let descriptor = |path: &str| {
+            format!("wpkh(tprv8ZgxMBicQKsPcx5nBGsR63Pe8KnRUqmbJNENAfGftF3yuXoMMoVJJcYeUw5eVkm9WBPjWYt6HMWYJNesB5HaNVBaFc1M6dRjWSYnmewUMYy/84'/1'/0'/{}/*)", path)
+        };
+        let wallet = Wallet::new_multipath(
+            &descriptor("<0;1>"),
+            Network::Regtest,
+            MemoryDatabase::default(),
+        )
+        .unwrap();
+        let expected = Wallet::new(
+            &descriptor("0"),
+            Some(&descriptor("1")),
+            Network::Regtest,
+            MemoryDatabase::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            wallet.get_address(AddressIndex::New).unwrap(),
+            expected.get_address(AddressIndex::New).unwrap()
+        );
+        assert_eq!(
+            wallet.get_internal_address(AddressIndex::New).unwrap(),
+            expected.get_internal_address(AddressIndex::New).unwrap()
+        );
+
+        // both keychains need their own path
+        for path in &["0", "<0;1;2>"] {
+            assert!(matches!(
+                Wallet::new_multipath(
+                    &descriptor(path),
+                    Network::Regtest,
+                    MemoryDatabase::default()
+                ),
+                Err(Error::Descriptor(
+                    DescriptorError::InvalidMultipathDescriptor
+                ))
+            ));
+        }
+    }
+
     #[test]
     fn test_taproot_psbt_populate_tap_key_origins() {
         let (wallet, _, _) = get_funded_wallet(get_test_tr_single_sig_xprv());
@@ -4423,6 +5355,566 @@ pub(crate) mod test {
         );
     }
 
+    #[test]
+    fn test_get_balance_detailed() {
+        use bitcoin::TxIn;
+
+        let (wallet, descriptors, _) = get_funded_wallet(get_test_wpkh());
+        let set_tip_height = |height| {
+            wallet
+                .database
+                .borrow_mut()
+                .set_sync_time(SyncTime {
+                    block_time: BlockTime {
+                        height,
+                        timestamp: 0,
+                    },
+                })
+                .unwrap();
+        };
+        set_tip_height(100);
+
+        // change of a transaction spending from the wallet
+        let change_txid = crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@external descriptors, 0) => 20_000 )),
+            None,
+        );
+        let mut details = wallet
+            .database
+            .borrow()
+            .get_tx(&change_txid, true)
+            .unwrap()
+            .unwrap();
+        details.sent = 30_000;
+        wallet.database.borrow_mut().set_tx(&details).unwrap();
+
+        // payment received from someone else
+        crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@external descriptors, 0) => 25_000 )),
+            None,
+        );
+
+        // coinbase with 6 confirmations
+        let coinbase = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Script::new(),
+                sequence: 0xFFFFFFFF,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: 5_000_000_000,
+                script_pubkey: wallet.get_address(Peek(0)).unwrap().script_pubkey(),
+            }],
+        };
+        let coinbase_txid = coinbase.txid();
+        {
+            let mut database = wallet.database.borrow_mut();
+            database
+                .set_tx(&TransactionDetails {
+                    transaction: Some(coinbase.clone()),
+                    txid: coinbase_txid,
+                    received: 5_000_000_000,
+                    sent: 0,
+                    fee: None,
+                    confirmation_time: Some(BlockTime {
+                        height: 95,
+                        timestamp: 0,
+                    }),
+                    tx_type: Some(TxType::CoinbaseReward),
+                })
+                .unwrap();
+            database
+                .set_utxo(&LocalUtxo {
+                    outpoint: OutPoint::new(coinbase_txid, 0),
+                    txout: coinbase.output[0].clone(),
+                    keychain: KeychainKind::External,
+                    is_spent: false,
+                    derivation_index: None,
+                    suspected_dust: false,
+                })
+                .unwrap();
+        }
+
+        let balance = wallet.get_balance_detailed().unwrap();
+        assert_eq!(
+            balance,
+            Balance {
+                confirmed: 50_000,
+                trusted_pending: 20_000,
+                untrusted_pending: 25_000,
+                immature: 5_000_000_000,
+            }
+        );
+        assert_eq!(balance.total(), wallet.get_balance().unwrap());
+
+        // the coinbase has 100 confirmations at height 194
+        set_tip_height(193);
+        assert_eq!(
+            wallet.get_balance_detailed().unwrap().immature,
+            5_000_000_000
+        );
+        set_tip_height(194);
+        let balance = wallet.get_balance_detailed().unwrap();
+        assert_eq!(balance.confirmed, 5_000_050_000);
+        assert_eq!(balance.immature, 0);
+    }
+    #[test]
+    fn test_coin_control_snapshot() {
+        let (wallet, descriptors, funding_txid) = get_funded_wallet(get_test_wpkh());
+        let unconfirmed_txid = crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@external descriptors, 0) => 25_000 )),
+            None,
+        );
+
+        let mut snapshot = wallet.coin_control_snapshot(100).unwrap();
+        snapshot.sort_by_key(|entry| entry.value);
+
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[0].outpoint.txid, unconfirmed_txid);
+        assert_eq!(snapshot[0].value, 25_000);
+        assert_eq!(snapshot[0].confirmations, 0);
+        assert_eq!(snapshot[1].outpoint.txid, funding_txid);
+        assert_eq!(snapshot[1].value, 50_000);
+        // `get_funded_wallet` confirms the funding tx at height 99
+        assert_eq!(snapshot[1].confirmations, 2);
+
+        let address = wallet.get_address(Peek(0)).unwrap().address;
+        for entry in snapshot {
+            assert_eq!(entry.address, Some(address.clone()));
+            assert_eq!(entry.keychain, KeychainKind::External);
+            assert!(!entry.is_change);
+        }
+    }
+
+    #[test]
+    fn test_split_consolidation() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let to = wallet.get_address(New).unwrap().script_pubkey();
+        let utxos = (0..500u32)
+            .map(|n| LocalUtxo {
+                outpoint: OutPoint::new(Txid::hash(&n.to_le_bytes()), n % 3),
+                txout: TxOut {
+                    value: 10_000 + u64::from(n),
+                    script_pubkey: to.clone(),
+                },
+                keychain: KeychainKind::External,
+                is_spent: false,
+                derivation_index: None,
+                suspected_dust: false,
+            })
+            .collect::<Vec<_>>();
+
+        let max_weight = 40_000;
+        let fee_rate = FeeRate::from_sat_per_vb(2.0);
+        let outlines = wallet
+            .split_consolidation(&utxos, &to, fee_rate, max_weight)
+            .unwrap();
+        assert!(outlines.len() > 1);
+
+        let mut spent = HashSet::new();
+        for outline in &outlines {
+            assert!(outline.weight <= max_weight);
+            assert_eq!(outline.fee, fee_rate.fee_wu(outline.weight));
+
+            let total = outline
+                .inputs
+                .iter()
+                .map(|outpoint| {
+                    let utxo = utxos.iter().find(|u| u.outpoint == *outpoint).unwrap();
+                    utxo.txout.value
+                })
+                .sum::<u64>();
+            assert_eq!(outline.value + outline.fee, total);
+            for outpoint in &outline.inputs {
+                assert!(spent.insert(*outpoint));
+            }
+        }
+        assert_eq!(spent.len(), utxos.len());
+
+        // a single input doesn't fit
+        assert!(matches!(
+            wallet.split_consolidation(&utxos, &to, fee_rate, 200),
+            Err(Error::TxTooLarge { .. })
+        ));
+        // inputs that can't pay for their fee
+        assert!(matches!(
+            wallet.split_consolidation(
+                &utxos[..1],
+                &to,
+                FeeRate::from_sat_per_vb(1_000.0),
+                max_weight
+            ),
+            Err(Error::InsufficientFunds { .. })
+        ));
+        assert!(wallet
+            .split_consolidation(&[], &to, fee_rate, max_weight)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_state_fingerprint() {
+        // the descriptor needs a wildcard for new addresses to change the state
+        let (wallet_a, descriptors, _) = get_funded_wallet(get_test_tr_single_sig_xprv());
+        let (wallet_b, _, _) = get_funded_wallet(get_test_tr_single_sig_xprv());
+        assert_eq!(
+            wallet_a.state_fingerprint().unwrap(),
+            wallet_b.state_fingerprint().unwrap()
+        );
+
+        crate::populate_test_db!(
+            wallet_a.database.borrow_mut(),
+            testutils! (@tx ( (@external descriptors, 0) => 25_000 )),
+            None,
+        );
+        assert_ne!(
+            wallet_a.state_fingerprint().unwrap(),
+            wallet_b.state_fingerprint().unwrap()
+        );
+
+        crate::populate_test_db!(
+            wallet_b.database.borrow_mut(),
+            testutils! (@tx ( (@external descriptors, 0) => 25_000 )),
+            None,
+        );
+        assert_eq!(
+            wallet_a.state_fingerprint().unwrap(),
+            wallet_b.state_fingerprint().unwrap()
+        );
+
+        wallet_a.get_address(New).unwrap();
+        assert_ne!(
+            wallet_a.state_fingerprint().unwrap(),
+            wallet_b.state_fingerprint().unwrap()
+        );
+    }
+
+    #[cfg(not(feature = "async-interface"))]
+    struct DummyBlockchain;
+
+    #[cfg(not(feature = "async-interface"))]
+    impl GetTx for DummyBlockchain {
+        fn get_tx(&self, _txid: &Txid) -> Result<Option<Transaction>, Error> {
+            Ok(None)
+        }
+    }
+
+    #[cfg(not(feature = "async-interface"))]
+    impl Blockchain for DummyBlockchain {
+        fn get_capabilities(&self) -> HashSet<crate::blockchain::Capability> {
+            HashSet::new()
+        }
+
+        fn broadcast(&self, _tx: &Transaction) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn estimate_fee(&self, _target: usize) -> Result<FeeRate, Error> {
+            Ok(FeeRate::from_sat_per_vb(1.0))
+        }
+
+        fn get_tx_confirmations(&self, _txid: &Txid) -> Result<Option<u32>, Error> {
+            Ok(Some(1))
+        }
+    }
+
+    #[cfg(not(feature = "async-interface"))]
+    impl GetHeight for DummyBlockchain {
+        fn get_height(&self) -> Result<u32, Error> {
+            Ok(100)
+        }
+    }
+
+    #[cfg(not(feature = "async-interface"))]
+    impl WalletSync for DummyBlockchain {
+        fn wallet_setup<D: BatchDatabase>(
+            &self,
+            _database: &mut D,
+            _progress_update: Box<dyn Progress>,
+        ) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "async-interface"))]
+    fn test_sync_reports_skipped_scripts() {
+        struct IncompleteBlockchain;
+
+        impl GetHeight for IncompleteBlockchain {
+            fn get_height(&self) -> Result<u32, Error> {
+                Ok(100)
+            }
+        }
+
+        impl WalletSync for IncompleteBlockchain {
+            fn wallet_setup<D: BatchDatabase>(
+                &self,
+                _database: &mut D,
+                _progress_update: Box<dyn Progress>,
+            ) -> Result<(), Error> {
+                unreachable!("the stats are requested by `Wallet::sync`")
+            }
+
+            fn wallet_setup_with_stats<D: BatchDatabase>(
+                &self,
+                _database: &mut D,
+                _progress_update: Box<dyn Progress>,
+            ) -> Result<SyncStats, Error> {
+                Ok(SyncStats {
+                    skipped_scripts: 3,
+                    ..Default::default()
+                })
+            }
+
+            fn wallet_sync_with_stats<D: BatchDatabase>(
+                &self,
+                database: &mut D,
+                progress_update: Box<dyn Progress>,
+            ) -> Result<SyncStats, Error> {
+                self.wallet_setup_with_stats(database, progress_update)
+            }
+        }
+
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let stats = wallet
+            .sync(&IncompleteBlockchain, SyncOptions::default())
+            .unwrap();
+        assert_eq!(stats.skipped_scripts, 3);
+        // the sync is still recorded, the caller decides what to do with an incomplete one
+        assert!(wallet.database().get_sync_time().unwrap().is_some());
+    }
+
+    #[test]
+    #[cfg(not(feature = "async-interface"))]
+    fn test_sync_flags_suspected_dust() {
+        let (wallet, descriptors, funding_txid) = get_funded_wallet(get_test_wpkh());
+        let dust_txid = crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@external descriptors, 1) => 500 ) (@confirmations 1)),
+            Some(100),
+        );
+        // change of a transaction sent by the wallet, small as well
+        let change_txid = {
+            let mut database = wallet.database.borrow_mut();
+            let change_txid = crate::populate_test_db!(
+                &mut *database,
+                testutils! (@tx ( (@external descriptors, 2) => 600 ) (@confirmations 1)),
+                Some(100),
+            );
+            let mut details = database.get_tx(&change_txid, true).unwrap().unwrap();
+            details.sent = 50_000;
+            database.set_tx(&details).unwrap();
+            change_txid
+        };
+
+        wallet
+            .sync(
+                &DummyBlockchain,
+                SyncOptions {
+                    dust_attack_threshold: Some(1_000),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let is_flagged = |txid| {
+            wallet
+                .get_utxo(OutPoint { txid, vout: 0 })
+                .unwrap()
+                .unwrap()
+                .suspected_dust
+        };
+        assert!(is_flagged(dust_txid));
+        assert!(!is_flagged(change_txid));
+        assert!(!is_flagged(funding_txid));
+
+        // suspected dust is never selected automatically
+        let addr = wallet.get_address(New).unwrap();
+        let mut builder = wallet.build_tx();
+        builder.drain_to(addr.script_pubkey()).drain_wallet();
+        let (psbt, _) = builder.finish().unwrap();
+        assert_eq!(psbt.unsigned_tx.input.len(), 2);
+        assert!(psbt
+            .unsigned_tx
+            .input
+            .iter()
+            .all(|txin| txin.previous_output.txid != dust_txid));
+
+        // unless the caller opts in, to get rid of it
+        let mut builder = wallet.build_tx();
+        builder
+            .drain_to(addr.script_pubkey())
+            .drain_wallet()
+            .include_suspected_dust();
+        let (psbt, _) = builder.finish().unwrap();
+        assert_eq!(psbt.unsigned_tx.input.len(), 3);
+
+        // a sync without threshold clears the flags
+        wallet
+            .sync(&DummyBlockchain, SyncOptions::default())
+            .unwrap();
+        assert!(!is_flagged(dust_txid));
+    }
+
+    #[test]
+    #[cfg(not(feature = "async-interface"))]
+    fn test_is_payment_final() {
+        let (wallet, _, funding_txid) = get_funded_wallet(get_test_wpkh());
+        assert_eq!(
+            wallet
+                .is_payment_final(&funding_txid, &DummyBlockchain)
+                .unwrap(),
+            PaymentFinality::Confirmed
+        );
+
+        let addr = wallet.get_address(New).unwrap();
+        let mut builder = wallet.build_tx();
+        builder
+            .add_recipient(addr.script_pubkey(), 25_000)
+            .enable_rbf()
+            .fee_rate(FeeRate::from_sat_per_vb(5.0));
+        let (mut psbt, mut details) = builder.finish().unwrap();
+        assert!(wallet.sign(&mut psbt, Default::default()).unwrap());
+        details.transaction = Some(psbt.extract_tx());
+        wallet.database.borrow_mut().set_tx(&details).unwrap();
+
+        assert_eq!(
+            wallet
+                .is_payment_final(&details.txid, &DummyBlockchain)
+                .unwrap(),
+            PaymentFinality::AtRisk {
+                signals_rbf: true,
+                unconfirmed_ancestors: false,
+                low_fee: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_analyze_signature_sizes() {
+        use bitcoin::hashes::hex::FromHex;
+
+        let (wallet, _, funding_txid) = get_funded_wallet(get_test_wpkh());
+        // the funding transaction has no signature at all
+        assert_eq!(
+            wallet.analyze_signature_sizes(&funding_txid).unwrap(),
+            SigSizeReport::default()
+        );
+
+        // DER encoding of a signature followed by SIGHASH_ALL
+        let signature = |r: Vec<u8>, s: Vec<u8>| {
+            let mut der = vec![0x30, (r.len() + s.len() + 4) as u8, 0x02, r.len() as u8];
+            der.extend(r);
+            der.extend(vec![0x02, s.len() as u8]);
+            der.extend(s);
+            der.push(0x01);
+            der
+        };
+        let low = [vec![0x7f], vec![0x01; 31]].concat();
+        let high = [vec![0x00, 0x80], vec![0x01; 31]].concat();
+        let low_r = signature(low.clone(), low.clone());
+        let high_r = signature(high.clone(), low);
+        let high_r_high_s = signature(high.clone(), high);
+        assert_eq!((low_r.len(), high_r.len()), (71, 72));
+        let pubkey = Vec::<u8>::from_hex(
+            "0203e76c1c3ef19e9e4bcba2ffbd3bfb4f3f3a2bde5a8c5f7b2cbd4cc2e1b09e8d",
+        )
+        .unwrap();
+
+        let input = |script_sig: Script, witness: Vec<Vec<u8>>| bitcoin::TxIn {
+            previous_output: OutPoint::new(funding_txid, 0),
+            script_sig,
+            sequence: 0xFFFFFFFF,
+            witness: Witness::from_vec(witness),
+        };
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![
+                input(Script::new(), vec![low_r, pubkey.clone()]),
+                input(
+                    bitcoin::blockdata::script::Builder::new()
+                        .push_slice(&high_r)
+                        .push_slice(&pubkey)
+                        .into_script(),
+                    vec![],
+                ),
+                input(Script::new(), vec![high_r_high_s, pubkey]),
+            ],
+            output: vec![],
+        };
+        let txid = tx.txid();
+        wallet
+            .database
+            .borrow_mut()
+            .set_tx(&TransactionDetails {
+                transaction: Some(tx),
+                txid,
+                received: 0,
+                sent: 50_000,
+                fee: None,
+                confirmation_time: None,
+                tx_type: None,
+            })
+            .unwrap();
+
+        assert_eq!(
+            wallet.analyze_signature_sizes(&txid).unwrap(),
+            SigSizeReport {
+                low_r: 1,
+                high_r: 2,
+                high_s: 1,
+                // one byte in the script sig and two in the witness
+                extra_weight: 4 + 2,
+            }
+        );
+        assert!(matches!(
+            wallet.analyze_signature_sizes(&Txid::from_inner([0xab; 32])),
+            Err(Error::TransactionNotFound)
+        ));
+    }
+
+    #[test]
+    #[cfg(not(feature = "async-interface"))]
+    fn test_psbt_balance_effect_send() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let mut builder = wallet.build_tx();
+        builder.add_recipient(addr.script_pubkey(), 25_000);
+        let (psbt, details) = builder.finish().unwrap();
+
+        let effect = wallet.psbt_balance_effect(&psbt, &DummyBlockchain).unwrap();
+
+        assert_eq!(effect.total_in_owned, 50_000);
+        assert_eq!(effect.total_out_owned, 25_000 - details.fee.unwrap_or(0));
+        assert_eq!(effect.fee, details.fee.unwrap_or(0));
+        assert_eq!(effect.net_change, -25_000 - effect.fee as i64);
+    }
+
+    #[test]
+    #[cfg(not(feature = "async-interface"))]
+    fn test_psbt_balance_effect_send_to_self() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let addr = wallet.get_address(New).unwrap();
+        let mut builder = wallet.build_tx();
+        builder.add_recipient(addr.script_pubkey(), 25_000);
+        let (psbt, details) = builder.finish().unwrap();
+
+        let effect = wallet.psbt_balance_effect(&psbt, &DummyBlockchain).unwrap();
+
+        assert_eq!(effect.total_in_owned, 50_000);
+        assert_eq!(effect.total_out_owned, 50_000 - effect.fee);
+        assert_eq!(effect.fee, details.fee.unwrap_or(0));
+        assert_eq!(effect.net_change, -(effect.fee as i64));
+    }
+
     fn test_spend_from_wallet(wallet: Wallet<AnyDatabase>) {
         let addr = wallet.get_address(AddressIndex::New).unwrap();
 
@@ -4454,6 +5946,51 @@ pub(crate) mod test {
         test_spend_from_wallet(wallet);
     }
 
+    #[test]
+    fn test_estimate_vsize() {
+        // (descriptor, maximum overestimation in vbytes)
+        let descriptors = [
+            (get_test_wpkh(), 1),
+            (
+                "pkh(cVpPVruEDdmutPzisEsYvtST1usBR3ntr8pXSyt6D2YYqXRyPcFW)",
+                2,
+            ),
+            (
+                "sh(wpkh(cVpPVruEDdmutPzisEsYvtST1usBR3ntr8pXSyt6D2YYqXRyPcFW))",
+                1,
+            ),
+            // key spend, schnorr signatures have a fixed size
+            (get_test_tr_single_sig(), 0),
+            (get_test_tr_single_sig_xprv(), 0),
+            // script spend, the internal key is not ours
+            (get_test_tr_with_taptree(), 2),
+            (get_test_tr_with_taptree_xprv(), 2),
+        ];
+
+        for &(descriptor, max_diff) in &descriptors {
+            let (wallet, _, _) = get_funded_wallet(descriptor);
+            let addr = wallet.get_address(New).unwrap();
+            let mut builder = wallet.build_tx();
+            builder.add_recipient(addr.script_pubkey(), 25_000);
+            let (mut psbt, _) = builder.finish().unwrap();
+            let wallet_descriptor = wallet.get_descriptor_for_keychain(KeychainKind::External);
+
+            let estimated = estimate_vsize(&psbt, wallet_descriptor).unwrap();
+            assert!(wallet.sign(&mut psbt, Default::default()).unwrap());
+            let actual = psbt.clone().extract_tx().vsize();
+
+            assert!(
+                estimated >= actual && estimated - actual <= max_diff,
+                "{}: estimated {} vbytes, actual {}",
+                descriptor,
+                estimated,
+                actual
+            );
+            // finalized inputs are counted with their actual size
+            assert_eq!(estimate_vsize(&psbt, wallet_descriptor).unwrap(), actual);
+        }
+    }
+
     #[test]
     fn test_taproot_sign_derive_index_from_psbt() {
         let (wallet, _, _) = get_funded_wallet(get_test_tr_single_sig_xprv());
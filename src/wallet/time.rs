@@ -40,6 +40,24 @@ pub fn get_timestamp() -> u64 {
     (millis / 1000.0) as u64
 }
 
+/// Wait for `duration` without blocking the thread
+#[cfg(all(
+    not(target_arch = "wasm32"),
+    any(feature = "async-interface", feature = "reqwest")
+))]
+pub(crate) async fn sleep(duration: Duration) {
+    tokio::time::sleep(duration).await
+}
+/// Wait for `duration` without blocking the thread
+#[cfg(all(
+    target_arch = "wasm32",
+    any(feature = "async-interface", feature = "reqwest")
+))]
+pub(crate) async fn sleep(duration: Duration) {
+    let millis = duration.as_millis().min(u32::MAX as u128) as u32;
+    gloo_timers::future::TimeoutFuture::new(millis).await
+}
+
 #[cfg(not(target_arch = "wasm32"))]
 pub(crate) struct Instant(SystemInstant);
 #[cfg(target_arch = "wasm32")]
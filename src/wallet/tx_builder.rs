@@ -45,6 +45,8 @@ use bitcoin::util::psbt::{self, PartiallySignedTransaction as Psbt};
 use bitcoin::{OutPoint, Script, Transaction};
 
 use miniscript::descriptor::DescriptorTrait;
+use rand::seq::SliceRandom;
+use rand::RngCore;
 
 use super::coin_selection::{CoinSelectionAlgorithm, DefaultCoinSelectionAlgorithm};
 use crate::{database::BatchDatabase, Error, Utxo, Wallet};
@@ -52,6 +54,9 @@ use crate::{
     types::{FeeRate, KeychainKind, LocalUtxo, WeightedUtxo},
     TransactionDetails,
 };
+/// Maximum size of the data of a standard OP_RETURN output
+const MAX_OP_RETURN_DATA: usize = 80;
+
 /// Context in which the [`TxBuilder`] is valid
 pub trait TxBuilderContext: std::fmt::Debug + Default + Clone {}
 
@@ -141,12 +146,14 @@ pub(crate) struct TxParams {
     pub(crate) ordering: TxOrdering,
     pub(crate) locktime: Option<u32>,
     pub(crate) rbf: Option<RbfValue>,
+    pub(crate) sequences: BTreeMap<OutPoint, u32>,
     pub(crate) version: Option<Version>,
     pub(crate) change_policy: ChangeSpendPolicy,
     pub(crate) only_witness_utxo: bool,
     pub(crate) add_global_xpubs: bool,
     pub(crate) include_output_redeem_witness_script: bool,
     pub(crate) bumping_fee: Option<PreviousFee>,
+    pub(crate) include_suspected_dust: bool,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -498,6 +505,16 @@ impl<'a, D: BatchDatabase, Cs: CoinSelectionAlgorithm<D>, Ctx: TxBuilderContext>
         self
     }
 
+    /// Allow spending the UTXOs flagged as [`suspected_dust`](crate::LocalUtxo::suspected_dust)
+    ///
+    /// They are skipped by default, since spending them together with other UTXOs links the
+    /// addresses of the wallet. This is meant to get rid of them, for instance with
+    /// [`TxBuilder::drain_wallet`] to a single destination.
+    pub fn include_suspected_dust(&mut self) -> &mut Self {
+        self.params.include_suspected_dust = true;
+        self
+    }
+
     /// Choose the coin selection algorithm
     ///
     /// Overrides the [`DefaultCoinSelectionAlgorithm`](super::coin_selection::DefaultCoinSelectionAlgorithm).
@@ -543,6 +560,21 @@ impl<'a, D: BatchDatabase, Cs: CoinSelectionAlgorithm<D>, Ctx: TxBuilderContext>
         self.params.rbf = Some(RbfValue::Value(nsequence));
         self
     }
+
+    /// Set the nSequence of the input spending `outpoint`, overriding the one picked by the
+    /// wallet
+    ///
+    /// This is useful to spend an output encumbered by a relative timelock (OP_CSV) that the
+    /// wallet doesn't know about, or to only signal RBF on some inputs. The explicit value is kept
+    /// even if [`TxBuilder::enable_rbf`] is used, but it must still satisfy the OP_CSV of the
+    /// wallet descriptors, if any.
+    ///
+    /// The outpoint must be spent by the transaction, otherwise [`TxBuilder::finish`] fails with
+    /// [`Error::InputNotSelected`].
+    pub fn set_sequence(&mut self, outpoint: OutPoint, sequence: u32) -> &mut Self {
+        self.params.sequences.insert(outpoint, sequence);
+        self
+    }
 }
 
 impl<'a, D: BatchDatabase, Cs: CoinSelectionAlgorithm<D>> TxBuilder<'a, D, Cs, CreateTx> {
@@ -559,10 +591,25 @@ impl<'a, D: BatchDatabase, Cs: CoinSelectionAlgorithm<D>> TxBuilder<'a, D, Cs, C
     }
 
     /// Add data as an output, using OP_RETURN
-    pub fn add_data(&mut self, data: &[u8]) -> &mut Self {
+    ///
+    /// The output has a value of zero. To keep the transaction standard, `data` can't be longer
+    /// than 80 bytes and a transaction can only have one OP_RETURN output: otherwise this fails
+    /// with [`Error::OpReturnTooLarge`] or [`Error::MultipleOpReturn`].
+    pub fn add_data(&mut self, data: &[u8]) -> Result<&mut Self, Error> {
+        if data.len() > MAX_OP_RETURN_DATA {
+            return Err(Error::OpReturnTooLarge(data.len()));
+        }
+        if self
+            .params
+            .recipients
+            .iter()
+            .any(|(script, _)| script.is_op_return())
+        {
+            return Err(Error::MultipleOpReturn);
+        }
+
         let script = Script::new_op_return(data);
-        self.add_recipient(script, 0u64);
-        self
+        Ok(self.add_recipient(script, 0u64))
     }
 
     /// Sets the address to *drain* excess coins to.
@@ -666,7 +713,6 @@ impl TxOrdering {
         match self {
             TxOrdering::Untouched => {}
             TxOrdering::Shuffle => {
-                use rand::seq::SliceRandom;
                 #[cfg(test)]
                 use rand::SeedableRng;
 
@@ -675,7 +721,7 @@ impl TxOrdering {
                 #[cfg(test)]
                 let mut rng = rand::rngs::StdRng::seed_from_u64(0);
 
-                tx.output.shuffle(&mut rng);
+                randomize_output_order(tx, &mut rng);
             }
             TxOrdering::Bip69Lexicographic => {
                 tx.input.sort_unstable_by_key(|txin| {
@@ -688,6 +734,14 @@ impl TxOrdering {
     }
 }
 
+/// Shuffle the outputs of `tx` using `rng`, leaving the inputs untouched
+///
+/// This is what [`TxOrdering::Shuffle`] does with a thread-local RNG, exposed to make the order
+/// reproducible with a seeded one or to shuffle a transaction built outside of a [`TxBuilder`].
+pub fn randomize_output_order<R: RngCore + ?Sized>(tx: &mut Transaction, rng: &mut R) {
+    tx.output.shuffle(rng);
+}
+
 /// Transaction version
 ///
 /// Has a default value of `1`
@@ -791,6 +845,44 @@ mod test {
         assert_ne!(original_tx.output, tx.output);
     }
 
+    #[test]
+    fn test_randomize_output_order() {
+        use rand::SeedableRng;
+
+        let original_tx = ordering_test_tx!();
+        let shuffle = |seed| {
+            let mut tx = original_tx.clone();
+            randomize_output_order(&mut tx, &mut rand::rngs::StdRng::seed_from_u64(seed));
+            tx
+        };
+
+        let tx = shuffle(0);
+        assert_eq!(tx, shuffle(0));
+        assert_eq!(tx.input, original_tx.input);
+
+        let mut values = tx
+            .output
+            .iter()
+            .map(|txout| txout.value)
+            .collect::<Vec<_>>();
+        let mut original_values = original_tx
+            .output
+            .iter()
+            .map(|txout| txout.value)
+            .collect::<Vec<_>>();
+        values.sort_unstable();
+        original_values.sort_unstable();
+        assert_eq!(values, original_values);
+        assert_eq!(
+            tx.output.iter().map(|txout| txout.value).sum::<u64>(),
+            original_tx
+                .output
+                .iter()
+                .map(|txout| txout.value)
+                .sum::<u64>()
+        );
+    }
+
     #[test]
     fn test_output_ordering_bip69() {
         use std::str::FromStr;
@@ -837,6 +929,8 @@ mod test {
                 txout: Default::default(),
                 keychain: KeychainKind::External,
                 is_spent: false,
+                derivation_index: None,
+                suspected_dust: false,
             },
             LocalUtxo {
                 outpoint: OutPoint {
@@ -846,6 +940,8 @@ mod test {
                 txout: Default::default(),
                 keychain: KeychainKind::Internal,
                 is_spent: false,
+                derivation_index: None,
+                suspected_dust: false,
             },
         ]
     }
@@ -11,6 +11,7 @@
 
 use bitcoin::blockdata::script::Script;
 use bitcoin::secp256k1::{All, Secp256k1};
+use bitcoin::EcdsaSig;
 
 use miniscript::{MiniscriptKey, Satisfier, ToPublicKey};
 
@@ -56,6 +57,17 @@ impl After {
     }
 }
 
+/// Return the length of the `r` and `s` values of `data` if it's an ECDSA signature encoded in DER
+/// and followed by a sighash type, as pushed in the script sigs and witnesses
+pub(crate) fn ecdsa_signature_lengths(data: &[u8]) -> Option<(usize, usize)> {
+    EcdsaSig::from_slice(data).ok()?;
+
+    // 0x30 <length> 0x02 <r length> <r> 0x02 <s length> <s> <sighash type>
+    let r_len = *data.get(3)? as usize;
+    let s_len = *data.get(5 + r_len)? as usize;
+    Some((r_len, s_len))
+}
+
 pub(crate) fn check_nsequence_rbf(rbf: u32, csv: u32) -> bool {
     // This flag cannot be set in the nSequence when spending using OP_CSV
     if rbf & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {